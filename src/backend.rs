@@ -0,0 +1,61 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Everything Rp1PIO does ends up as one of the rp1-pio driver's ioctls. A PioBackend is what actually carries them
+// out, which is normally the kernel (IoctlBackend), but can be swapped out for testing, recording, and so on:
+//
+//     let pio = Rp1PIO::with_backend(Box::new(my_backend), Chip::new());
+
+use std::{ffi::c_void, fs::File, os::fd::{AsRawFd, OwnedFd}, path::{Path, PathBuf}};
+
+use libc::c_ulong;
+
+use crate::Error;
+
+pub trait PioBackend: Send + Sync {
+    /// # Safety
+    ///
+    /// `args` must point to the argument struct (from the kernel's rp1_pio_if.h) that `request` expects, and any
+    /// pointers inside it must be valid for the duration of the call.
+    unsafe fn ioctl(&self, request: c_ulong, args: *mut c_void) -> Result<u32, Error>;
+
+    // For error messages and the like. Doesn't have to exist.
+    fn devname(&self) -> &Path;
+}
+
+// The real thing: /dev/pio<N>.
+pub struct IoctlBackend {
+    devname: PathBuf,
+    fd: OwnedFd,
+}
+
+impl IoctlBackend {
+    pub fn open(path: impl AsRef<Path>) -> Result<IoctlBackend, Error> {
+        let devname = path.as_ref().to_path_buf();
+        Ok(IoctlBackend::from_file(File::open(&devname)?, devname))
+    }
+
+    pub(crate) fn from_file(file: File, devname: PathBuf) -> IoctlBackend {
+        IoctlBackend { devname, fd: file.into() }
+    }
+}
+
+impl PioBackend for IoctlBackend {
+    unsafe fn ioctl(&self, request: c_ulong, args: *mut c_void) -> Result<u32, Error> {
+        const NEG_EREMOTEIO: i32 = -libc::EREMOTEIO;
+        const NEG_ETIMEDOUT: i32 = -libc::ETIMEDOUT;
+        match unsafe {
+            libc::ioctl(self.fd.as_raw_fd(), request, args)
+        } {
+            NEG_EREMOTEIO   => Err(Error::RemoteIOErr),
+            NEG_ETIMEDOUT   => Err(Error::TimedOut),
+            -1              => Err(std::io::Error::last_os_error())?,
+            r@ ..-1         => Err(Error::Unknown(r)),
+            r@ 0..          => Ok(r as u32),
+        }
+    }
+
+    fn devname(&self) -> &Path {
+        &self.devname
+    }
+}
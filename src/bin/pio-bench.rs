@@ -0,0 +1,155 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Measures how fast data gets in and out of the PIO block by each route, to help pick between them (and to notice
+// when something gets slower):
+//
+//     pio-bench [-d /dev/pio0] [-n words] [-p toggle-pin] [--report]
+//
+// - put/get round trip: one word through a loopback program and back, with the blocking put() and get() ioctls.
+// - put() and get() throughput: a stream of words one ioctl at a time.
+// - sm_xfer_data() throughput, both ways, for a range of DMA buffer sizes.
+// - GPIO toggles: how fast the host can flip a pin with exec(), next to how fast an SM can do it on its own. This one
+//   only runs with -p, since it drives the pin.
+//
+// --report writes Rp1PIO::diagnostic_report() to stderr at the end, so the numbers can be filed along with the system
+// they came from.
+
+use std::{process::ExitCode, time::{Duration, Instant}};
+
+use pio_pi5_rs::{pio_clock_hz, Error, LoadedProgram, PioProgram, Rp1PIO, SmConfig, StateMachine, XferDir};
+
+struct Args {
+    device: Option<String>,
+    words: usize,
+    pin: Option<u32>,
+    report: bool,
+}
+
+const USAGE: &str = "usage: pio-bench [-d device] [-n words] [-p toggle-pin] [--report]";
+
+const BUFFER_SIZES: [u32; 5] = [256, 1024, 4096, 16384, 65536];
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args { device: None, words: 100_000, pin: None, report: false };
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-d"       => args.device = Some(argv.next().ok_or(USAGE)?),
+            "-n"       => args.words = argv.next().and_then(|n| n.parse().ok()).filter(|&n| n > 0).ok_or(USAGE)?,
+            "-p"       => args.pin = Some(argv.next().and_then(|pin| pin.parse().ok()).ok_or(USAGE)?),
+            "--report" => args.report = true,
+            _          => Err(USAGE)?,
+        }
+    }
+    Ok(args)
+}
+
+fn report(name: &str, count: usize, unit: &str, elapsed: Duration) {
+    let rate = count as f64 / elapsed.as_secs_f64();
+    println!("{name:<36} {count:>9} {unit:<7} {:>9.3} s  {rate:>12.0} {unit}/s  {:>9.3} µs/{unit}",
+             elapsed.as_secs_f64(), elapsed.as_secs_f64() * 1e6 / count as f64);
+}
+
+fn time(f: impl FnOnce() -> Result<(), Error>) -> Result<Duration, Error> {
+    let start = Instant::now();
+    f()?;
+    Ok(start.elapsed())
+}
+
+// Runs `source` on the SM at full speed, with `config` on top of the wrap, and leaves it enabled.
+fn start<'a>(sm: &StateMachine<'a>, source: &str, config: SmConfig) -> Result<LoadedProgram<'a>, Error> {
+    sm.set_enabled(false)?;
+    let program = sm.pio().load_program(&PioProgram::assemble(source)?)?;
+    let (wrap_target, wrap) = program.wrap();
+    sm.init(program.offset(), &config.set_wrap(wrap_target, wrap)?)?;
+    sm.clear_fifos()?;
+    sm.set_enabled(true)?;
+    Ok(program)
+}
+
+fn stop(sm: &StateMachine, program: LoadedProgram) -> Result<(), Error> {
+    sm.set_enabled(false)?;
+    drop(program);
+    sm.clear_fifos()
+}
+
+fn bench(args: &Args, pio: &Rp1PIO) -> Result<(), Error> {
+    let sm = pio.sm_claim_unused()?;
+    let n = args.words;
+    println!("# pio-bench {} {} SM{} (PIO clock {} Hz)", pio.devname().display(), pio.chip().name, sm.index(), pio_clock_hz());
+
+    let loopback = ".program loopback\n.wrap_target\npull block\nmov isr, osr\npush block\n.wrap\n";
+    let program = start(&sm, loopback, SmConfig::default())?;
+    report("put/get round trip", n, "word", time(|| {
+        for i in 0..n {
+            sm.put(i as u32, true)?;
+            sm.get(true)?;
+        }
+        Ok(())
+    })?);
+    stop(&sm, program)?;
+
+    let sink = ".program sink\n.wrap_target\nout null, 32\n.wrap\n";
+    let program = start(&sm, sink, SmConfig::default().set_out_shift(true, true, 32)?)?;
+    report("put() throughput", n, "word", time(|| (0..n).try_for_each(|i| sm.put(i as u32, true)))?);
+    stop(&sm, program)?;
+
+    let source = ".program source\n.wrap_target\nin null, 32\n.wrap\n";
+    let program = start(&sm, source, SmConfig::default().set_in_shift(true, true, 32)?)?;
+    report("get() throughput", n, "word", time(|| (0..n).try_for_each(|_| sm.get(true).map(|_| ())))?);
+    stop(&sm, program)?;
+
+    let data = vec![0_u32; n];
+    let bytes = size_of_val(&data[..]) as u32;
+    for (dir, name, source, config) in [
+        (XferDir::ToSm,   "to SM",   sink,   SmConfig::default().set_out_shift(true, true, 32)?),
+        (XferDir::FromSm, "from SM", source, SmConfig::default().set_in_shift(true, true, 32)?),
+    ] {
+        for buf_size in BUFFER_SIZES {
+            pio.sm_config_xfer(sm.index(), dir, buf_size, 4)?;
+            let program = start(&sm, source, config)?;
+            report(&format!("sm_xfer_data() {name}, {buf_size} byte buffers"), n, "word",
+                   time(|| pio.sm_xfer_data(sm.index(), dir, bytes, &data[0]))?);
+            stop(&sm, program)?;
+        }
+    }
+
+    if let Some(pin) = args.pin {
+        pio.pio_gpio_init(pin as u16)?;
+        let config = SmConfig::default().set_set_pins(pin, 1)?;
+        let program = start(&sm, ".program idle\n.wrap_target\nnop\n.wrap\n", config)?;
+        sm.set_consecutive_pindirs(pin, 1, true)?;
+        let (set_high, set_low) = (0xe001, 0xe000); // set pins, 1 / set pins, 0
+        report(&format!("GPIO{pin} toggles with exec()"), n, "toggle", time(|| {
+            (0..n).try_for_each(|i| sm.exec(if i % 2 == 0 { set_high } else { set_low }, false))
+        })?);
+        stop(&sm, program)?;
+        println!("{:<36} {:>9} {:<7} {:>11}  {:>12.0} toggle/s", format!("GPIO{pin} toggles from an SM"), "", "", "",
+                 pio_clock_hz() as f64);
+        sm.set_consecutive_pindirs(pin, 1, false)?;
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(usage) => { eprintln!("{usage}"); return ExitCode::from(2) },
+    };
+    let pio = match &args.device {
+        Some(device) => Rp1PIO::open_path(device),
+        None         => Rp1PIO::new(0),
+    };
+    let result = pio.and_then(|pio| {
+        let result = bench(&args, &pio);
+        if args.report {
+            eprint!("{}", pio.diagnostic_report());
+        }
+        result
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => { eprintln!("pio-bench: {e}"); ExitCode::FAILURE },
+    }
+}
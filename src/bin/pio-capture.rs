@@ -0,0 +1,146 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A logic analyzer on the Pi 5's GPIO header:
+//
+//     pio-capture [-d /dev/pio0] [-p base-pin] [-n channels] [-r rate] [-s samples] [-t trigger] [-f sr|vcd|raw] [--report] <output|->
+//
+// Rates take a k or M suffix (`-r 25M`). Triggers are `rise:PIN`, `fall:PIN` or `pattern:VALUE:COUNT`, where the
+// pattern is matched against the first COUNT channels (`pattern:0b101:3`). The format defaults to the output file's
+// extension, and to raw for stdout. --report writes Rp1PIO::diagnostic_report() to stderr at the end.
+
+use std::{fs::File, io::{BufWriter, Write}, process::ExitCode};
+
+use pio_pi5_rs::{capture::{Capture, LogicAnalyzer, Trigger}, Error, Rp1PIO};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format { Sigrok, Vcd, Raw }
+
+struct Args {
+    device: Option<String>,
+    base_pin: u32,
+    channels: u32,
+    rate: f64,
+    samples: usize,
+    trigger: Trigger,
+    format: Option<Format>,
+    report: bool,
+    output: String,
+}
+
+const USAGE: &str = "usage: pio-capture [-d device] [-p base-pin] [-n channels] [-r rate] [-s samples] \
+                     [-t rise:PIN|fall:PIN|pattern:VALUE:COUNT] [-f sr|vcd|raw] [--report] <output|->";
+
+fn number(s: &str) -> Option<u32> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = s.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn rate(s: &str) -> Option<f64> {
+    let (number, scale) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 1e3),
+        b'M'        => (&s[..s.len() - 1], 1e6),
+        _           => (s, 1.0),
+    };
+    number.parse::<f64>().ok().map(|n| n * scale)
+}
+
+fn trigger(s: &str) -> Option<Trigger> {
+    let mut parts = s.split(':');
+    let trigger = match (parts.next()?, parts.next(), parts.next()) {
+        ("none", None, None)                    => Trigger::None,
+        ("rise", Some(pin), None)               => Trigger::Rising(number(pin)?),
+        ("fall", Some(pin), None)               => Trigger::Falling(number(pin)?),
+        ("pattern", Some(value), Some(count))   => Trigger::Pattern { value: number(value)?, count: number(count)? },
+        _                                       => None?,
+    };
+    parts.next().is_none().then_some(trigger)
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args { device: None, base_pin: 0, channels: 8, rate: 1e6, samples: 100_000, trigger: Trigger::None,
+                          format: None, report: false, output: String::new() };
+    let mut output = None;
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-d" => args.device = Some(argv.next().ok_or(USAGE)?),
+            "-p" => args.base_pin = argv.next().and_then(|s| number(&s)).ok_or(USAGE)?,
+            "-n" => args.channels = argv.next().and_then(|s| number(&s)).ok_or(USAGE)?,
+            "-r" => args.rate = argv.next().and_then(|s| rate(&s)).ok_or(USAGE)?,
+            "-s" => args.samples = argv.next().and_then(|s| s.parse().ok()).ok_or(USAGE)?,
+            "-t" => args.trigger = argv.next().and_then(|s| trigger(&s)).ok_or(USAGE)?,
+            "--report" => args.report = true,
+            "-f" => args.format = Some(match argv.next().as_deref() {
+                Some("sr")  => Format::Sigrok,
+                Some("vcd") => Format::Vcd,
+                Some("raw") => Format::Raw,
+                _           => Err(USAGE)?,
+            }),
+            _ if output.is_none() && (arg == "-" || !arg.starts_with('-')) => output = Some(arg),
+            _ => Err(USAGE)?,
+        }
+    }
+    args.output = output.ok_or(USAGE)?;
+    Ok(args)
+}
+
+fn format(args: &Args) -> Format {
+    args.format.unwrap_or(match args.output.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("sr")  => Format::Sigrok,
+        Some("vcd") => Format::Vcd,
+        _           => Format::Raw,
+    })
+}
+
+fn capture(args: &Args, pio: &Rp1PIO) -> Result<Capture, Error> {
+    let la = LogicAnalyzer::new(pio.sm_claim_unused()?, args.base_pin, args.channels)?
+        .with_sample_rate(args.rate)?
+        .with_trigger(args.trigger)?;
+    eprintln!("pio-capture: {} samples of GPIO{}..{} at {:.0} Hz", args.samples, args.base_pin, args.base_pin + args.channels - 1,
+              la.sample_rate());
+    la.capture(args.samples)
+}
+
+fn write(args: &Args, capture: &Capture, out: impl Write) -> Result<(), Error> {
+    match format(args) {
+        Format::Sigrok => capture.write_sigrok(out),
+        Format::Vcd    => capture.write_vcd(out),
+        Format::Raw    => capture.write_raw(out),
+    }
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let pio = match &args.device {
+        Some(device) => Rp1PIO::open_path(device),
+        None         => Rp1PIO::new(0),
+    }.map_err(|e| e.to_string())?;
+    let capture = capture(args, &pio);
+    if args.report {
+        eprint!("{}", pio.diagnostic_report());
+    }
+    let capture = capture.map_err(|e| e.to_string())?;
+    if capture.stalled {
+        eprintln!("pio-capture: the FIFO overflowed, so there are gaps in the capture (try a lower rate)");
+    }
+    match args.output.as_str() {
+        "-"    => write(args, &capture, std::io::stdout().lock()),
+        output => write(args, &capture, BufWriter::new(File::create(output).map_err(|e| format!("{output}: {e}"))?)),
+    }.map_err(|e| format!("{}: {e}", args.output))
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(usage) => { eprintln!("{usage}"); return ExitCode::from(2) },
+    };
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => { eprintln!("pio-capture: {e}"); ExitCode::FAILURE },
+    }
+}
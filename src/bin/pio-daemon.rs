@@ -0,0 +1,78 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Shares a PIO block between processes (see the daemon module for how):
+//
+//     pio-daemon [-d /dev/pio0] [-s /run/pio0.sock] [-m mode] [-t address:port]
+//
+// Run it as whoever can open the device. The socket is created with permissions `mode` (octal, 0660 by default), so
+// put the users that should get at the PIO in the socket's group rather than giving them the device. A stale socket
+// from a previous run is replaced.
+//
+// Built with the `remote` feature, `-t` serves the PIO over TCP instead (`-t 0.0.0.0:7450`, say). Anyone who can reach
+// the port can drive the pins, so keep it on a trusted network.
+
+use std::{os::unix::{fs::PermissionsExt, net::UnixListener}, process::ExitCode};
+
+use pio_pi5_rs::{daemon::Daemon, Error, Rp1PIO};
+
+struct Args {
+    device: Option<String>,
+    socket: String,
+    mode: u32,
+    #[cfg(feature = "remote")]
+    tcp: Option<String>,
+}
+
+const USAGE: &str = "usage: pio-daemon [-d device] [-s socket] [-m mode] [-t address:port]";
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args { device: None, socket: "/run/pio0.sock".to_string(), mode: 0o660,
+                          #[cfg(feature = "remote")] tcp: None };
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-d" => args.device = Some(argv.next().ok_or(USAGE)?),
+            "-s" => args.socket = argv.next().ok_or(USAGE)?,
+            "-m" => args.mode = argv.next().and_then(|mode| u32::from_str_radix(&mode, 8).ok()).ok_or(USAGE)?,
+            #[cfg(feature = "remote")]
+            "-t" => args.tcp = Some(argv.next().ok_or(USAGE)?),
+            _    => Err(USAGE)?,
+        }
+    }
+    Ok(args)
+}
+
+fn serve(args: &Args) -> Result<(), Error> {
+    let pio = match &args.device {
+        Some(device) => Rp1PIO::open_path(device),
+        None         => Rp1PIO::new(0),
+    }?;
+    #[cfg(feature = "remote")]
+    if let Some(address) = &args.tcp {
+        return serve_tcp(pio, address);
+    }
+    let _ = std::fs::remove_file(&args.socket);
+    let listener = UnixListener::bind(&args.socket)?;
+    std::fs::set_permissions(&args.socket, std::fs::Permissions::from_mode(args.mode))?;
+    eprintln!("pio-daemon: serving {} on {}", pio.devname().display(), args.socket);
+    Daemon::new(pio).serve(&listener)
+}
+
+#[cfg(feature = "remote")]
+fn serve_tcp(pio: Rp1PIO, address: &str) -> Result<(), Error> {
+    let listener = std::net::TcpListener::bind(address)?;
+    eprintln!("pio-daemon: serving {} on tcp:{}", pio.devname().display(), listener.local_addr()?);
+    Daemon::new(pio).serve_tcp(&listener)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(usage) => { eprintln!("{usage}"); return ExitCode::from(2) },
+    };
+    match serve(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => { eprintln!("pio-daemon: {e}"); ExitCode::FAILURE },
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Dumps every PIO register with its fields decoded, for attaching to bug reports:
+//
+//     pio-dump [-d /dev/pio0] [--json] [--instructions] [--report]
+//
+// --instructions adds instruction memory, disassembled. It's write-only on the RP2040, so if it all comes back as
+// zeros (`jmp 0`) the hardware isn't letting us see it. The disassembly assumes no side-set, since that depends on
+// which SM runs the instruction. The FIFO registers are skipped since reading RXF pops the FIFO. --report also writes
+// Rp1PIO::diagnostic_report() to stderr.
+
+use std::{fmt::Write, process::ExitCode};
+
+use pio_pi5_rs::{instruction::{Instruction, SideSet}, registers::RegisterValue, Error, Rp1PIO};
+
+struct Args {
+    device: Option<String>,
+    json: bool,
+    instructions: bool,
+    report: bool,
+}
+
+const USAGE: &str = "usage: pio-dump [-d device] [--json] [--instructions] [--report]";
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args { device: None, json: false, instructions: false, report: false };
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-d"             => args.device = Some(argv.next().ok_or(USAGE)?),
+            "--json"         => args.json = true,
+            "--instructions" => args.instructions = true,
+            "--report"       => args.report = true,
+            _                => Err(USAGE)?,
+        }
+    }
+    Ok(args)
+}
+
+fn disassemble(instr: u32) -> String {
+    match Instruction::decode(instr as u16, SideSet::new(0, false, false)) {
+        Ok(instr) => instr.to_string(),
+        Err(_)    => "??".to_string(),
+    }
+}
+
+fn is_instruction(reg: &RegisterValue) -> bool {
+    reg.register.name == "INSTR_MEM{}"
+}
+
+fn text(pio: &Rp1PIO, registers: &[RegisterValue]) -> String {
+    let mut out = String::new();
+    let chip = pio.chip();
+    let _ = writeln!(out, "# pio-dump {} {} ({} SMs, {} instructions, FIFO depth {})",
+                     pio.devname().display(), chip.name, chip.sm_count, chip.instr_count, chip.fifo_depth);
+    for reg in registers {
+        let Some(value) = reg.value else { continue };
+        let _ = write!(out, "{:#05x} {:<20} {value:#010x}", reg.offset, reg.name);
+        if is_instruction(reg) {
+            let _ = write!(out, "  {}", disassemble(value));
+        }
+        out += "\n";
+        for (field, value) in reg.fields() {
+            let bits = if field.msb == field.lsb { format!("[{}]", field.lsb) } else { format!("[{}:{}]", field.msb, field.lsb) };
+            let _ = writeln!(out, "      {:<26} {bits:<7} {:<2} {value:#x}", field.name, field.access);
+        }
+    }
+    out
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\'          => { out.push('\\'); out.push(c) },
+            c if c < ' '        => { let _ = write!(out, "\\u{:04x}", c as u32); },
+            c                   => out.push(c),
+        }
+    }
+    out + "\""
+}
+
+fn json(pio: &Rp1PIO, registers: &[RegisterValue]) -> String {
+    let chip = pio.chip();
+    let mut out = format!("{{\n  \"device\": {},\n  \"chip\": {{ \"name\": {}, \"compatible\": {}, \"instr_count\": {}, \"sm_count\": {}, \"fifo_depth\": {} }},\n  \"registers\": [",
+                          json_string(&pio.devname().display().to_string()), json_string(&chip.name), json_string(&chip.compatible),
+                          chip.instr_count, chip.sm_count, chip.fifo_depth);
+    let readable = registers.iter().filter_map(|reg| reg.value.map(|value| (reg, value)));
+    for (i, (reg, value)) in readable.enumerate() {
+        let fields = reg.fields().map(|(field, value)| format!("{}: {value}", json_string(field.name))).collect::<Vec<_>>().join(", ");
+        let _ = write!(out, "{}\n    {{ \"name\": {}, \"offset\": {}, \"value\": {value}, \"fields\": {{ {fields} }}",
+                       if i == 0 { "" } else { "," }, json_string(&reg.name), reg.offset);
+        if is_instruction(reg) {
+            let _ = write!(out, ", \"disassembly\": {}", json_string(&disassemble(value)));
+        }
+        out += " }";
+    }
+    out + "\n  ]\n}\n"
+}
+
+fn dump(args: &Args) -> Result<String, Error> {
+    let pio = match &args.device {
+        Some(device) => Rp1PIO::open_path(device)?,
+        None         => Rp1PIO::new(0)?,
+    };
+    let registers = pio.read_registers(args.instructions);
+    if args.report {
+        eprint!("{}", pio.diagnostic_report());
+    }
+    let registers = registers?;
+    Ok(if args.json { json(&pio, &registers) } else { text(&pio, &registers) })
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(usage) => { eprintln!("{usage}"); return ExitCode::from(2) },
+    };
+    match dump(&args) {
+        Ok(out) => { print!("{out}"); ExitCode::SUCCESS },
+        Err(e) => { eprintln!("pio-dump: {e}"); ExitCode::FAILURE },
+    }
+}
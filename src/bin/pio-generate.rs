@@ -0,0 +1,117 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Plays a waveform out of the GPIO header, for poking at a device under test:
+//
+//     pio-generate [-d /dev/pio0] [-p base-pin] [-r rate] [-f csv|vcd|json] [--repeat n] [--report] <input|->
+//
+// Channel n of the waveform goes out on GPIO base-pin+n. Rates take a k or M suffix (`-r 25M`). The format defaults to
+// the input file's extension. When it's done the pins are left at the waveform's final levels. --report writes
+// Rp1PIO::diagnostic_report() to stderr at the end.
+
+use std::process::ExitCode;
+
+use pio_pi5_rs::{generator::{PatternGenerator, Waveform}, Error, Rp1PIO};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format { Csv, Vcd, Json }
+
+struct Args {
+    device: Option<String>,
+    base_pin: u32,
+    rate: f64,
+    format: Option<Format>,
+    repeat: usize,
+    report: bool,
+    input: String,
+}
+
+const USAGE: &str = "usage: pio-generate [-d device] [-p base-pin] [-r rate] [-f csv|vcd|json] [--repeat n] [--report] <input|->";
+
+fn rate(s: &str) -> Option<f64> {
+    let (number, scale) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 1e3),
+        b'M'        => (&s[..s.len() - 1], 1e6),
+        _           => (s, 1.0),
+    };
+    number.parse::<f64>().ok().map(|n| n * scale)
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args { device: None, base_pin: 0, rate: 1e6, format: None, repeat: 1, report: false, input: String::new() };
+    let mut input = None;
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-d"       => args.device = Some(argv.next().ok_or(USAGE)?),
+            "-p"       => args.base_pin = argv.next().and_then(|s| s.parse().ok()).ok_or(USAGE)?,
+            "-r"       => args.rate = argv.next().and_then(|s| rate(&s)).ok_or(USAGE)?,
+            "--repeat" => args.repeat = argv.next().and_then(|s| s.parse().ok()).ok_or(USAGE)?,
+            "--report" => args.report = true,
+            "-f"       => args.format = Some(match argv.next().as_deref() {
+                Some("csv")  => Format::Csv,
+                Some("vcd")  => Format::Vcd,
+                Some("json") => Format::Json,
+                _            => Err(USAGE)?,
+            }),
+            _ if input.is_none() && (arg == "-" || !arg.starts_with('-')) => input = Some(arg),
+            _ => Err(USAGE)?,
+        }
+    }
+    args.input = input.ok_or(USAGE)?;
+    Ok(args)
+}
+
+fn waveform(args: &Args) -> Result<Waveform, String> {
+    let source = match args.input.as_str() {
+        "-"   => std::io::read_to_string(std::io::stdin()),
+        input => std::fs::read_to_string(input),
+    }.map_err(|e| format!("{}: {e}", args.input))?;
+    let format = match (args.format, args.input.rsplit_once('.').map(|(_, ext)| ext)) {
+        (Some(format), _)    => format,
+        (None, Some("csv"))  => Format::Csv,
+        (None, Some("vcd"))  => Format::Vcd,
+        (None, Some("json")) => Format::Json,
+        (None, _)            => Err(format!("{}: can't tell what format it is (use -f)", args.input))?,
+    };
+    match format {
+        Format::Csv  => Waveform::from_csv(&source),
+        Format::Vcd  => Waveform::from_vcd(&source),
+        Format::Json => Waveform::from_json(&source),
+    }.map_err(|e| format!("{}: {e}", args.input))
+}
+
+fn play(args: &Args, pio: &Rp1PIO, waveform: &Waveform) -> Result<(), Error> {
+    let generator = PatternGenerator::new(pio.sm_claim_unused()?, args.base_pin, waveform.channels.len() as u32)?
+        .with_sample_rate(args.rate)?;
+    let once = waveform.samples(generator.sample_rate());
+    let samples: Vec<u32> = std::iter::repeat_n(&once[..], args.repeat).flatten().copied().collect();
+    for (n, name) in waveform.channels.iter().enumerate() {
+        eprintln!("pio-generate: GPIO{} = {name}", args.base_pin + n as u32);
+    }
+    eprintln!("pio-generate: {} samples at {:.0} Hz", samples.len(), generator.sample_rate());
+    generator.play(&samples)?;
+    generator.wait()
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(usage) => { eprintln!("{usage}"); return ExitCode::from(2) },
+    };
+    let result = waveform(&args).and_then(|waveform| {
+        let pio = match &args.device {
+            Some(device) => Rp1PIO::open_path(device),
+            None         => Rp1PIO::new(0),
+        }.map_err(|e| e.to_string())?;
+        let result = play(&args, &pio, &waveform);
+        if args.report {
+            eprint!("{}", pio.diagnostic_report());
+        }
+        result.map_err(|e| e.to_string())
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => { eprintln!("pio-generate: {e}"); ExitCode::FAILURE },
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// An interactive prompt for poking at a state machine, a command at a time:
+//
+//     pio-repl [-d /dev/pio0 | --emulator]
+//     pio> claim 0
+//     pio> load blink.pio
+//     pio> exec "set pins, 1"
+//     pio> put 0xdeadbeef
+//     pio> regs
+//
+// `help` lists the commands. With --emulator nothing touches the hardware, and `run` steps the emulator along (it
+// otherwise only moves when a blocking put or get is waiting on it).
+
+use std::{io::{BufRead, IsTerminal, Write}, process::ExitCode};
+
+use pio_pi5_rs::{emulator::{Emulator, EmulatorBackend}, instruction::{Instruction, SideSet}, proc_pio::*,
+                 Chip, Error, LoadedProgram, PioProgram, Rp1PIO, SmConfig, StateMachine};
+
+struct Args {
+    device: Option<String>,
+    emulator: bool,
+}
+
+const USAGE: &str = "usage: pio-repl [-d device | --emulator]";
+
+const HELP: &str = "\
+claim [sm]              claim an SM (any free one if sm is left out)
+unclaim                 release the claimed SM
+load <file.pio>         assemble a program, load it and init the SM to run it (not started)
+exec <instruction>      assemble an instruction and execute it on the SM
+put <value>             push a word into the TX FIFO
+get                     pop a word from the RX FIFO
+start | stop | restart  enable, disable or restart the SM
+clkdiv <div>            set the clock divider
+regs                    show the SM's registers, with the current instruction disassembled
+dis                     disassemble the loaded program
+fifo                    show the FIFO levels
+pins                    show the GPIO levels and directions the PIO block is driving
+report                  print a diagnostic report (JSON) for bug reports
+run <cycles>            run the emulator (--emulator only)
+help | quit";
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args { device: None, emulator: false };
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-d"         => args.device = Some(argv.next().ok_or(USAGE)?),
+            "--emulator" => args.emulator = true,
+            _            => Err(USAGE)?,
+        }
+    }
+    if args.device.is_some() && args.emulator { Err(USAGE)? }
+    Ok(args)
+}
+
+fn number(s: &str) -> Result<u32, String> {
+    let s = s.replace('_', "");
+    if let Some(hex) = s.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(binary) = s.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2)
+    } else if let Some(negative) = s.strip_prefix('-') {
+        negative.parse::<i32>().map(|n| n.wrapping_neg() as u32)
+    } else {
+        s.parse()
+    }.map_err(|_| format!("{s:?} isn't a number"))
+}
+
+fn side_set(config: &SmConfig) -> SideSet {
+    let (bit_count, optional, pindirs) = config.get_sideset();
+    SideSet::new(bit_count.saturating_sub(optional as u32) as u8, optional, pindirs)
+}
+
+fn disassemble(instr: u16, side_set: SideSet) -> String {
+    match Instruction::decode(instr, side_set) {
+        Ok(instr) => instr.to_string(),
+        Err(_)    => "??".to_string(),
+    }
+}
+
+struct Session<'a> {
+    pio: &'a Rp1PIO,
+    emulator: Option<EmulatorBackend>,
+    sm: Option<StateMachine<'a>>,
+    program: Option<LoadedProgram<'a>>,
+}
+
+impl<'a> Session<'a> {
+    fn sm(&self) -> Result<&StateMachine<'a>, String> {
+        self.sm.as_ref().ok_or_else(|| "no SM claimed (use `claim`)".to_string())
+    }
+
+    // Anything that isn't a command-line mistake comes back from the library as an Error.
+    fn command(&mut self, command: &str, arg: &str) -> Result<String, String> {
+        self.run(command, arg).map_err(|e| match e {
+            Failure::Usage(e) => e,
+            Failure::Pio(e)   => e.to_string(),
+        })
+    }
+
+    fn run(&mut self, command: &str, arg: &str) -> Result<String, Failure> {
+        Ok(match command {
+            "claim" => {
+                if let Some(sm) = self.sm.take() { sm.unclaim()?; }
+                let sm = if arg.is_empty() { self.pio.sm_claim_unused()? } else { self.pio.sm_claim(number(arg)? as u16)? };
+                let index = sm.index();
+                self.sm = Some(sm);
+                format!("claimed SM{index}")
+            },
+            "unclaim" => {
+                self.program = None;
+                self.sm.take().ok_or_else(|| "no SM claimed".to_string())?.unclaim()?;
+                String::new()
+            },
+            "load" => {
+                let source = std::fs::read_to_string(arg).map_err(|e| format!("{arg}: {e}"))?;
+                let program = PioProgram::assemble(&source).map_err(|e| match e {
+                    Error::Assembly { line, message } => format!("{arg}:{line}: {message}"),
+                    e => e.to_string(),
+                })?;
+                self.program = None; // Make room first
+                let sm = self.sm()?;
+                let loaded = self.pio.load_program(&program)?;
+                let (wrap_target, wrap) = loaded.wrap();
+                let mut config = SmConfig::default().set_wrap(wrap_target, wrap)?;
+                if let Some(side_set) = program.side_set() {
+                    config = config.set_sideset(side_set.bits() as u32, side_set.optional, side_set.pindirs)?;
+                }
+                sm.set_enabled(false)?;
+                sm.init(loaded.offset(), &config)?;
+                let message = format!("loaded {} instructions at {}", program.len(), loaded.offset());
+                self.program = Some(loaded);
+                message
+            },
+            "exec" => {
+                let instruction = arg.trim_matches('"');
+                let side_set = match self.program.as_ref().and_then(|program| program.program().side_set()) {
+                    Some(side_set) => format!(".side_set {}{}{}\n", side_set.count, if side_set.optional { " opt" } else { "" },
+                                              if side_set.pindirs { " pindirs" } else { "" }),
+                    None           => String::new(),
+                };
+                let program = PioProgram::assemble(&format!(".program exec\n{side_set}{instruction}\n"))
+                    .map_err(|e| match e {
+                        Error::Assembly { message, .. } => message,
+                        e => e.to_string(),
+                    })?;
+                let [instr] = program.instructions() else { Err("exec takes exactly one instruction".to_string())? };
+                self.sm()?.exec(*instr, false)?;
+                format!("{instr:#06x}")
+            },
+            "put" => {
+                self.sm()?.put(number(arg)?, true)?;
+                String::new()
+            },
+            "get" => {
+                let word = self.sm()?.get(true)?;
+                format!("{word:#010x} ({word})")
+            },
+            "start"   => { self.sm()?.set_enabled(true)?; String::new() },
+            "stop"    => { self.sm()?.set_enabled(false)?; String::new() },
+            "restart" => { self.sm()?.restart()?; String::new() },
+            "clkdiv"  => {
+                let div: f64 = arg.parse().map_err(|_| format!("{arg:?} isn't a number"))?;
+                self.sm()?.set_clkdiv(div)?;
+                String::new()
+            },
+            "regs" => {
+                let sm = self.sm()?;
+                let hw = sm.read_hw_state_machine()?;
+                let config = SmConfig::from_hw(&hw);
+                let div = config.get_clkdiv();
+                let (wrap_target, wrap) = config.get_wrap();
+                let mut out = format!("SM{} {}  pc {}  instr {:#06x}  {}\n", sm.index(), if hw.enabled { "enabled" } else { "disabled" },
+                                      hw.pc, hw.instr, disassemble(hw.instr as u16, side_set(&config)));
+                out += &format!("clkdiv {:#010x} ({}+{}/256)  execctrl {:#010x}  shiftctrl {:#010x}  pinctrl {:#010x}\n",
+                                hw.clkdiv, div.div, div.frac, hw.execctrl, hw.shiftctrl, hw.pinctrl);
+                out += &format!("wrap {wrap_target}..{wrap}{}", if hw.execctrl & PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS != 0 { "  stalled" } else { "" });
+                if let Some(emulator) = &self.emulator {
+                    let emu = emulator.emulator();
+                    let state = emu.sm(sm.index());
+                    out += &format!("\nx {:#010x}  y {:#010x}  isr {:#010x} ({} bits)  osr {:#010x} ({} bits)",
+                                    state.x, state.y, state.isr, state.isr_count, state.osr, state.osr_count);
+                }
+                out
+            },
+            "dis" => {
+                let program = self.program.as_ref().ok_or_else(|| "no program loaded (use `load`)".to_string())?;
+                let side_set = program.program().side_set().unwrap_or(SideSet::new(0, false, false));
+                program.program().instructions().iter().enumerate()
+                    .map(|(i, &instr)| format!("{:2}: {instr:04x}  {}", program.offset() as usize + i, disassemble(instr, side_set)))
+                    .collect::<Vec<_>>().join("\n")
+            },
+            "fifo" => {
+                let fifo = self.sm()?.read_hw_fifo()?;
+                format!("tx {}{}  rx {}{}", fifo.tx.level, if fifo.tx.full { " (full)" } else { "" },
+                        fifo.rx.level, if fifo.rx.full { " (full)" } else { "" })
+            },
+            "pins" => {
+                let (mut out, mut oe) = ([0], [0]);
+                self.pio.read_hw(PROC_PIO_DBG_PADOUT_OFFSET, &mut out)?;
+                self.pio.read_hw(PROC_PIO_DBG_PADOE_OFFSET, &mut oe)?;
+                format!("out {:#034b}\noe  {:#034b}", out[0], oe[0])
+            },
+            "run" => {
+                let emulator = self.emulator.as_ref().ok_or_else(|| "run only works with --emulator".to_string())?;
+                let cycles = if arg.is_empty() { 1 } else { number(arg)? };
+                let mut emu = emulator.emulator();
+                emu.run(cycles as u64);
+                format!("cycle {}", emu.cycle())
+            },
+            "report" => self.pio.diagnostic_report(),
+            "help" | "?" => HELP.to_string(),
+            _ => Err(format!("unknown command {command:?} (try `help`)"))?,
+        })
+    }
+}
+
+enum Failure {
+    Usage(String),
+    Pio(Error),
+}
+
+impl From<String> for Failure {
+    fn from(e: String) -> Failure { Failure::Usage(e) }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Failure { Failure::Pio(e) }
+}
+
+fn repl(pio: &Rp1PIO, emulator: Option<EmulatorBackend>) {
+    let mut session = Session { pio, emulator, sm: None, program: None };
+    let interactive = std::io::stdin().is_terminal();
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("pio> ");
+            let _ = std::io::stdout().flush();
+        }
+        let Some(Ok(line)) = lines.next() else { break };
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let (command, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match command {
+            ""              => continue,
+            "quit" | "exit" => break,
+            command         => match session.command(command, arg.trim()) {
+                Ok(out) if out.is_empty() => {},
+                Ok(out) => println!("{out}"),
+                Err(e) => println!("error: {e}"),
+            },
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(usage) => { eprintln!("{usage}"); return ExitCode::from(2) },
+    };
+    let (pio, emulator) = if args.emulator {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        (Ok(Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new())), Some(backend))
+    } else {
+        (match &args.device {
+            Some(device) => Rp1PIO::open_path(device),
+            None         => Rp1PIO::new(0),
+        }, None)
+    };
+    match pio {
+        Ok(pio) => { repl(&pio, emulator); ExitCode::SUCCESS },
+        Err(e) => { eprintln!("pio-repl: {e}"); ExitCode::FAILURE },
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// `top` for the PIO block: redraws every SM's state a few times a second, straight from the registers, so it works
+// on SMs claimed by other processes too.
+//
+//     pio-top [-d /dev/pio0] [-i interval-ms] [--once] [--report]
+//
+// The FDEBUG flags are sticky and pio-top never clears them, so once set they stay set until something else does.
+// --report writes Rp1PIO::diagnostic_report() to stderr on the way out (so with --once, or when something fails).
+
+use std::{process::ExitCode, time::Duration};
+
+use pio_pi5_rs::{instruction::{Instruction, SideSet}, proc_pio::*, Error, Rp1PIO, SmConfig};
+
+struct Args {
+    device: Option<String>,
+    interval: Duration,
+    once: bool,
+    report: bool,
+}
+
+const USAGE: &str = "usage: pio-top [-d device] [-i interval-ms] [--once] [--report]";
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args { device: None, interval: Duration::from_millis(250), once: false, report: false };
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-d"        => args.device = Some(argv.next().ok_or(USAGE)?),
+            "-i"        => args.interval = Duration::from_millis(argv.next().and_then(|ms| ms.parse().ok()).ok_or(USAGE)?),
+            "--once"    => args.once = true,
+            "--report"  => args.report = true,
+            _           => Err(USAGE)?,
+        }
+    }
+    Ok(args)
+}
+
+fn flag(set: bool, name: &str) -> &str {
+    if set { name } else { "" }
+}
+
+fn disassemble(instr: u16, config: &SmConfig) -> String {
+    let (bit_count, optional, pindirs) = config.get_sideset();
+    let side_set = SideSet::new(bit_count.saturating_sub(optional as u32) as u8, optional, pindirs);
+    match Instruction::decode(instr, side_set) {
+        Ok(instr) => instr.to_string(),
+        Err(_)    => "??".to_string(),
+    }
+}
+
+fn screen(pio: &Rp1PIO) -> Result<String, Error> {
+    let mut fdebug = [0];
+    pio.read_hw(PROC_PIO_FDEBUG_OFFSET, &mut fdebug)?;
+    let fdebug = fdebug[0];
+    let mut out = format!("{}  {}\n\n", pio.devname().display(), pio.chip().name);
+    out += &format!("{:<2} {:<3} {:>2} {:>4} {:<24} {:>12} {:>5} {:>5} {:<8} {}\n",
+                    "SM", "EN", "PC", "INSTR", "", "CLKDIV", "TX", "RX", "WRAP", "FLAGS");
+    for sm in 0..pio.chip().sm_count {
+        let hw = pio.read_hw_state_machine(sm)?;
+        let fifo = pio.read_hw_fifo(sm)?;
+        let config = SmConfig::from_hw(&hw);
+        let div = config.get_clkdiv();
+        let (wrap_target, wrap) = config.get_wrap();
+        let bit = |lsb: u32| fdebug & 1 << (lsb + sm as u32) != 0;
+        let flags = [
+            flag(hw.execctrl & PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS != 0, "STALLED"),
+            flag(bit(PROC_PIO_FDEBUG_TXSTALL_LSB), "TXSTALL"),
+            flag(bit(PROC_PIO_FDEBUG_TXOVER_LSB), "TXOVER"),
+            flag(bit(PROC_PIO_FDEBUG_RXUNDER_LSB), "RXUNDER"),
+            flag(bit(PROC_PIO_FDEBUG_RXSTALL_LSB), "RXSTALL"),
+        ].into_iter().filter(|f| !f.is_empty()).collect::<Vec<_>>().join(" ");
+        out += &format!("{:<2} {:<3} {:>2} {:04x} {:<24} {:>7}+{:>3}/256 {:>2}{} {:>2}{} {:>2}..{:<4} {}\n",
+                        sm, if hw.enabled { "yes" } else { "no" }, hw.pc, hw.instr, disassemble(hw.instr as u16, &config),
+                        div.div, div.frac,
+                        fifo.tx.level, if fifo.tx.full { "F" } else { " " },
+                        fifo.rx.level, if fifo.rx.full { "F" } else { " " },
+                        wrap_target, wrap, flags);
+    }
+    Ok(out)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(usage) => { eprintln!("{usage}"); return ExitCode::from(2) },
+    };
+    let pio = match &args.device {
+        Some(device) => Rp1PIO::open_path(device),
+        None         => Rp1PIO::new(0),
+    };
+    let pio = match pio {
+        Ok(pio) => pio,
+        Err(e) => { eprintln!("pio-top: {e}"); return ExitCode::FAILURE },
+    };
+    let status = loop {
+        match screen(&pio) {
+            Ok(screen) if args.once => { print!("{screen}"); break ExitCode::SUCCESS },
+            Ok(screen) => print!("\x1b[H\x1b[2J{screen}"),
+            Err(e) => { eprintln!("pio-top: {e}"); break ExitCode::FAILURE },
+        }
+        std::thread::sleep(args.interval);
+    };
+    if args.report {
+        eprint!("{}", pio.diagnostic_report());
+    }
+    status
+}
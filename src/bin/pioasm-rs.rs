@@ -0,0 +1,222 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// pioasm, without needing the C++ tool installed:
+//
+//     pioasm-rs [-o c-sdk|rust|json|hex] <input.pio|-> [<output>]
+//
+// c-sdk writes the same header pioasm does (piolib uses the same API), rust writes consts and a function returning a
+// PioProgram for each program, json is for other tools and hex is one instruction per line, like pioasm's. Output goes
+// to stdout if no output file is given.
+
+use std::{fmt::Write, process::ExitCode};
+
+use pio_pi5_rs::{instruction::Instruction, pioasm::{assemble, AssembledProgram, Assembly}, Error};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format { CSdk, Rust, Json, Hex }
+
+struct Args {
+    format: Format,
+    input: String,
+    output: Option<String>,
+}
+
+const USAGE: &str = "usage: pioasm-rs [-o c-sdk|rust|json|hex] <input.pio|-> [<output>]";
+
+fn parse_args() -> Result<Args, String> {
+    let mut format = Format::CSdk;
+    let mut files = Vec::new();
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-o" => format = match argv.next().as_deref() {
+                Some("c-sdk") => Format::CSdk,
+                Some("rust")  => Format::Rust,
+                Some("json")  => Format::Json,
+                Some("hex")   => Format::Hex,
+                _             => Err(USAGE)?,
+            },
+            "-?" | "--help" => Err(USAGE)?,
+            _ if files.len() < 2 => files.push(arg),
+            _ => Err(USAGE)?,
+        }
+    }
+    let mut files = files.into_iter();
+    Ok(Args { format, input: files.next().ok_or(USAGE)?, output: files.next() })
+}
+
+fn name(program: &AssembledProgram) -> &str {
+    if program.name.is_empty() { "program" } else { &program.name }
+}
+
+// The listing comments: each instruction disassembled, with the wrap points marked.
+fn listing(program: &AssembledProgram, comment: &str, mut word: impl FnMut(&mut String, u16)) -> String {
+    let mut out = String::new();
+    let (wrap_target, wrap) = program.program.wrap().unwrap_or((0, program.program.len() as u8 - 1));
+    let side_set = program.program.side_set().unwrap_or_default();
+    for (i, &instr) in program.program.instructions().iter().enumerate() {
+        if i == wrap_target as usize {
+            let _ = writeln!(out, "            {comment}     .wrap_target");
+        }
+        let disassembly = Instruction::decode(instr, side_set).map(|instr| instr.to_string()).unwrap_or_else(|_| format!(".word {instr:#06x}"));
+        word(&mut out, instr);
+        let _ = writeln!(out, " {comment} {i:2}: {disassembly}");
+        if i == wrap as usize {
+            let _ = writeln!(out, "            {comment}     .wrap");
+        }
+    }
+    out
+}
+
+fn code_blocks(blocks: &[(String, String)], lang: &str) -> String {
+    blocks.iter().filter(|(l, _)| l == lang).map(|(_, code)| code.as_str()).collect()
+}
+
+fn c_sdk(assembly: &Assembly) -> String {
+    let mut out = String::from("// -------------------------------------------------- //\n\
+                                // This file is autogenerated by pioasm; do not edit! //\n\
+                                // -------------------------------------------------- //\n\n\
+                                #pragma once\n\n\
+                                #if !PICO_NO_HARDWARE\n\
+                                #include \"hardware/pio.h\"\n\
+                                #endif\n\n");
+    for (define, value) in &assembly.defines {
+        let _ = writeln!(out, "#define {define} {value}");
+    }
+    out += &code_blocks(&assembly.code_blocks, "c-sdk");
+    for program in &assembly.programs {
+        let name = name(program);
+        let rule = "-".repeat(name.len());
+        let (wrap_target, wrap) = program.program.wrap().unwrap_or((0, program.program.len() as u8 - 1));
+        let _ = write!(out, "// {rule} //\n// {name} //\n// {rule} //\n\n\
+                             #define {name}_wrap_target {wrap_target}\n#define {name}_wrap {wrap}\n#define {name}_pio_version {}\n\n",
+                       program.program.pio_version());
+        for (define, value) in &program.defines {
+            let _ = writeln!(out, "#define {name}_{define} {value}");
+        }
+        for (label, offset) in &program.public_labels {
+            let _ = writeln!(out, "#define {name}_offset_{label} {offset}u");
+        }
+        if !program.defines.is_empty() || !program.public_labels.is_empty() {
+            out += "\n";
+        }
+        let _ = write!(out, "static const uint16_t {name}_program_instructions[] = {{\n{}}};\n\n",
+                       listing(program, "//", |out, instr| { let _ = write!(out, "    {instr:#06x},"); }));
+        let _ = write!(out, "#if !PICO_NO_HARDWARE\n\
+                             static const struct pio_program {name}_program = {{\n\
+                             \x20   .instructions = {name}_program_instructions,\n\
+                             \x20   .length = {},\n\
+                             \x20   .origin = {},\n\
+                             \x20   .pio_version = {},\n\
+                             #if PICO_PIO_VERSION > 0\n\
+                             \x20   .used_gpio_ranges = 0x0\n\
+                             #endif\n\
+                             }};\n\n\
+                             static inline pio_sm_config {name}_program_get_default_config(uint offset) {{\n\
+                             \x20   pio_sm_config c = pio_get_default_sm_config();\n\
+                             \x20   sm_config_set_wrap(&c, offset + {name}_wrap_target, offset + {name}_wrap);\n",
+                       program.program.len(), program.program.origin().map(|origin| origin as i32).unwrap_or(-1), program.program.pio_version());
+        if let Some(side_set) = program.program.side_set() {
+            let _ = writeln!(out, "    sm_config_set_sideset(&c, {}, {}, {});", side_set.bits(), side_set.optional, side_set.pindirs);
+        }
+        out += "    return c;\n}\n";
+        out += &code_blocks(&program.code_blocks, "c-sdk");
+        out += "#endif\n\n";
+    }
+    out
+}
+
+fn rust(assembly: &Assembly) -> String {
+    let mut out = String::from("// This file is autogenerated by pioasm-rs; do not edit!\n\n\
+                                #[allow(unused_imports)]\n\
+                                use pio_pi5_rs::{instruction::SideSet, PioProgram};\n\n");
+    for (define, value) in &assembly.defines {
+        let _ = writeln!(out, "pub const {}: i64 = {value};", define.to_ascii_uppercase());
+    }
+    out += &code_blocks(&assembly.code_blocks, "rust");
+    for program in &assembly.programs {
+        let name = name(program);
+        let upper = name.to_ascii_uppercase();
+        let (wrap_target, wrap) = program.program.wrap().unwrap_or((0, program.program.len() as u8 - 1));
+        let _ = write!(out, "\n// {name}\n\npub const {upper}_WRAP_TARGET: u8 = {wrap_target};\npub const {upper}_WRAP: u8 = {wrap};\n");
+        for (define, value) in &program.defines {
+            let _ = writeln!(out, "pub const {upper}_{}: i64 = {value};", define.to_ascii_uppercase());
+        }
+        for (label, offset) in &program.public_labels {
+            let _ = writeln!(out, "pub const {upper}_OFFSET_{}: u8 = {offset};", label.to_ascii_uppercase());
+        }
+        let _ = write!(out, "\npub const {upper}_INSTRUCTIONS: [u16; {}] = [\n{}];\n\n",
+                       program.program.len(), listing(program, "//", |out, instr| { let _ = write!(out, "    {instr:#06x},"); }));
+        let _ = write!(out, "pub fn {name}_program() -> PioProgram {{\n    PioProgram::new(&{upper}_INSTRUCTIONS, {:?})\n        .with_wrap({wrap_target}, {wrap})",
+                       program.program.origin());
+        if let Some(side_set) = program.program.side_set() {
+            let _ = write!(out, "\n        .with_side_set(SideSet::new({}, {}, {}))", side_set.count, side_set.optional, side_set.pindirs);
+        }
+        out += "\n}\n";
+        out += &code_blocks(&program.code_blocks, "rust");
+    }
+    out
+}
+
+fn json_object(entries: impl Iterator<Item = (String, String)>) -> String {
+    let entries: Vec<String> = entries.map(|(name, value)| format!("\"{name}\": {value}")).collect();
+    if entries.is_empty() { "{}".to_string() } else { format!("{{ {} }}", entries.join(", ")) }
+}
+
+fn json(assembly: &Assembly) -> String {
+    let defines = |defines: &[(String, i64)]| json_object(defines.iter().map(|(name, value)| (name.clone(), value.to_string())));
+    let programs: Vec<String> = assembly.programs.iter().map(|program| {
+        let p = &program.program;
+        let (wrap_target, wrap) = p.wrap().unwrap_or((0, p.len() as u8 - 1));
+        let side_set = p.side_set().map(|side_set| json_object([
+            ("count".to_string(), side_set.count.to_string()),
+            ("optional".to_string(), side_set.optional.to_string()),
+            ("pindirs".to_string(), side_set.pindirs.to_string()),
+        ].into_iter())).unwrap_or_else(|| "null".to_string());
+        let instructions: Vec<String> = p.instructions().iter().map(|instr| instr.to_string()).collect();
+        format!("    {{\n      \"name\": \"{}\",\n      \"instructions\": [{}],\n      \"origin\": {},\n      \"wrap_target\": {wrap_target},\n      \
+                 \"wrap\": {wrap},\n      \"side_set\": {side_set},\n      \"pio_version\": {},\n      \"public_labels\": {},\n      \"defines\": {}\n    }}",
+                name(program), instructions.join(", "), p.origin().map(|origin| origin as i32).unwrap_or(-1), p.pio_version(),
+                json_object(program.public_labels.iter().map(|(label, offset)| (label.clone(), offset.to_string()))),
+                defines(&program.defines))
+    }).collect();
+    format!("{{\n  \"defines\": {},\n  \"programs\": [\n{}\n  ]\n}}\n", defines(&assembly.defines), programs.join(",\n"))
+}
+
+fn hex(assembly: &Assembly) -> Result<String, String> {
+    let [program] = &assembly.programs[..] else { Err("hex output needs exactly one program")? };
+    Ok(program.program.instructions().iter().map(|instr| format!("{instr:04x}\n")).collect())
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let source = match args.input.as_str() {
+        "-"   => std::io::read_to_string(std::io::stdin()),
+        input => std::fs::read_to_string(input),
+    }.map_err(|e| format!("{}: {e}", args.input))?;
+    let assembly = assemble(&source).map_err(|e| match e {
+        Error::Assembly { line, message } => format!("{}:{line}: {message}", args.input),
+        e => e.to_string(),
+    })?;
+    let out = match args.format {
+        Format::CSdk => c_sdk(&assembly),
+        Format::Rust => rust(&assembly),
+        Format::Json => json(&assembly),
+        Format::Hex  => hex(&assembly)?,
+    };
+    match &args.output {
+        Some(output) => std::fs::write(output, out).map_err(|e| format!("{output}: {e}")),
+        None         => { print!("{out}"); Ok(()) },
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(usage) => { eprintln!("{usage}"); return ExitCode::from(2) },
+    };
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => { eprintln!("pioasm-rs: {e}"); ExitCode::FAILURE },
+    }
+}
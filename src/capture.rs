@@ -0,0 +1,302 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A logic analyzer: one SM sampling a run of consecutive pins every clock and pushing them into its (joined) RX FIFO,
+// with DMA pulling the words out the other end:
+//
+//     let la = LogicAnalyzer::new(pio.sm_claim_unused()?, 4, 8)?
+//         .with_sample_rate(10_000_000.0)?
+//         .with_trigger(Trigger::Rising(4))?;
+//     let capture = la.capture(1_000_000)?;
+//     capture.write_sigrok(File::create("out.sr")?)?;
+//
+// The trigger runs in the PIO program, so the first sample is taken within a couple of SM clocks of it. A pattern
+// trigger compares the first `count` channels every 4 SM clocks, so a pattern shorter than that can be missed, and
+// since the pattern goes in through the TX FIFO the RX FIFO can't take it over, leaving half the usual slack. The
+// pins aren't switched over to PIO (the SM can read any pin, whatever function it has), so capturing doesn't disturb
+// whatever is driving them.
+
+use std::io::Write;
+
+use crate::{pio_clock_hz, vcd::VcdWriter, ClkDiv, Error, PioFifoJoin, PioProgram, SmConfig, StateMachine, XferDir};
+use crate::proc_pio::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    None,
+    Rising(u32),                          // GPIO number, which doesn't have to be one of the captured pins
+    Falling(u32),
+    Pattern { value: u32, count: u32 },   // The first `count` channels equal `value` (channel 0 is bit 0)
+}
+
+pub struct LogicAnalyzer<'a> {
+    sm: StateMachine<'a>,
+    base_pin: u32,
+    pin_count: u32,
+    clkdiv: ClkDiv,
+    trigger: Trigger,
+    dma: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capture {
+    pub base_pin: u32,
+    pub pin_count: u32,
+    pub sample_rate: f64,    // What the clock divider actually gave, which may be a little off what was asked for
+    pub samples: Vec<u32>,   // Channel n is bit n
+    pub stalled: bool,       // The RX FIFO filled up at some point, so there are gaps in the samples
+}
+
+impl<'a> LogicAnalyzer<'a> {
+    pub fn new(sm: StateMachine<'a>, base_pin: u32, pin_count: u32) -> Result<LogicAnalyzer<'a>, Error> {
+        if !(1..=32).contains(&pin_count) {
+            Err(Error::ParamErr { param: "pin_count", should_be: "1..=32".to_string() })?;
+        }
+        if base_pin + pin_count > 32 {
+            Err(Error::ParamErr { param: "base_pin", should_be: format!("<= {}", 32 - pin_count) })?;
+        }
+        Ok(LogicAnalyzer { sm, base_pin, pin_count, clkdiv: ClkDiv::from((1, 0)), trigger: Trigger::None, dma: true })
+    }
+
+    pub fn with_sample_rate(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz)?;
+        Ok(self)
+    }
+
+    pub fn with_trigger(mut self, trigger: Trigger) -> Result<Self, Error> {
+        match trigger {
+            Trigger::Rising(pin) | Trigger::Falling(pin) if pin >= 32 =>
+                Err(Error::ParamErr { param: "trigger pin", should_be: "< 32".to_string() })?,
+            Trigger::Pattern { count, .. } if count == 0 || count >= self.push_threshold().min(self.pin_count + 1) =>
+                Err(Error::ParamErr { param: "trigger count", should_be: format!("1..{}", self.push_threshold().min(self.pin_count + 1)) })?,
+            _ => {},
+        }
+        self.trigger = trigger;
+        Ok(self)
+    }
+
+    // Without DMA the samples are read a word at a time, which can't keep up with much more than a few MHz.
+    pub fn with_dma(mut self, dma: bool) -> Self {
+        self.dma = dma;
+        self
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64)
+    }
+
+    pub fn into_inner(self) -> StateMachine<'a> {
+        self.sm
+    }
+
+    // As many whole samples as fit in a word.
+    fn push_threshold(&self) -> u32 {
+        32 / self.pin_count * self.pin_count
+    }
+
+    fn program(&self) -> Result<PioProgram, Error> {
+        let trigger = match self.trigger {
+            Trigger::None                => String::new(),
+            Trigger::Rising(pin)         => format!("wait 0 gpio {pin}\nwait 1 gpio {pin}"),
+            Trigger::Falling(pin)        => format!("wait 1 gpio {pin}\nwait 0 gpio {pin}"),
+            Trigger::Pattern { count, .. } => format!("pull block\nmov y, osr\ncheck:\nmov isr, null\nin pins, {count}\n\
+                                                       mov x, isr\njmp x!=y check\nmov isr, null"),
+        };
+        PioProgram::assemble(&format!(".program capture\n{trigger}\n.wrap_target\nin pins, {}\n.wrap\n", self.pin_count))
+    }
+
+    // Runs the SM until `samples` samples have come back, then stops it again. The count is rounded up to a whole
+    // number of words and the extra samples dropped.
+    pub fn capture(&self, samples: usize) -> Result<Capture, Error> {
+        let per_word = (self.push_threshold() / self.pin_count) as usize;
+        let words = samples.div_ceil(per_word);
+        let pio = self.sm.pio();
+        let program = self.program()?;
+        let loaded = pio.load_program(&program)?;
+        let (wrap_target, wrap) = loaded.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.base_pin)?
+            .set_in_shift(true, true, self.push_threshold())?
+            .set_fifo_join(if matches!(self.trigger, Trigger::Pattern { .. }) { PioFifoJoin::None } else { PioFifoJoin::Rx })?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_enabled(false)?;
+        self.sm.init(loaded.offset(), &config)?;
+        if let Trigger::Pattern { value, count } = self.trigger {
+            self.sm.put(value << (32 - count), true)?;
+        }
+        let rxstall = 1 << (PROC_PIO_FDEBUG_RXSTALL_LSB + self.sm.index() as u32);
+        pio.write_hw(PROC_PIO_FDEBUG_OFFSET, &[rxstall])?;
+
+        let mut buf = vec![0_u32; words];
+        let result = self.read(&mut buf);
+        self.sm.set_enabled(false)?;
+        result?;
+        let mut fdebug = [0];
+        pio.read_hw(PROC_PIO_FDEBUG_OFFSET, &mut fdebug)?;
+
+        let mask = if self.pin_count == 32 { !0 } else { (1 << self.pin_count) - 1 };
+        let shift = 32 - self.push_threshold();
+        let samples = buf.iter()
+            .flat_map(|word| (0..per_word).map(move |i| (word >> shift) >> (i as u32 * self.pin_count) & mask))
+            .take(samples)
+            .collect();
+        Ok(Capture { base_pin: self.base_pin, pin_count: self.pin_count, sample_rate: self.sample_rate(), samples,
+                     stalled: fdebug[0] & rxstall != 0 })
+    }
+
+    fn read(&self, buf: &mut [u32]) -> Result<(), Error> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let pio = self.sm.pio();
+        if self.dma {
+            let bytes = size_of_val(buf) as u32;
+            pio.sm_config_xfer(self.sm.index(), XferDir::FromSm, bytes.min(64 * 1024), 4)?;
+            self.sm.set_enabled(true)?;
+            pio.sm_xfer_data(self.sm.index(), XferDir::FromSm, bytes, &buf[0])?;
+        } else {
+            self.sm.set_enabled(true)?;
+            for word in buf {
+                *word = self.sm.get(true)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Capture {
+    pub fn write_vcd(&self, out: impl Write) -> Result<(), Error> {
+        let mut vcd = VcdWriter::new(out, "1 ns");
+        let wires = (0..self.pin_count)
+            .map(|n| vcd.wire("capture", &format!("gpio{}", self.base_pin + n), 1))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let ns = |sample: usize| (sample as f64 * 1e9 / self.sample_rate).round() as u64;
+        for (i, sample) in self.samples.iter().enumerate() {
+            for (n, &wire) in wires.iter().enumerate() {
+                vcd.change(ns(i), wire, (sample >> n & 1) as u64)?;
+            }
+        }
+        vcd.finish(ns(self.samples.len()))?;
+        Ok(())
+    }
+
+    // Bytes per sample in the raw and sigrok formats.
+    pub fn unit_size(&self) -> usize {
+        self.pin_count.div_ceil(8).next_power_of_two() as usize
+    }
+
+    // Each sample as unit_size() little endian bytes, which is what PulseView's "Raw binary logic data" import wants.
+    pub fn write_raw(&self, mut out: impl Write) -> Result<(), Error> {
+        out.write_all(&self.raw())?;
+        Ok(())
+    }
+
+    fn raw(&self) -> Vec<u8> {
+        self.samples.iter().flat_map(|sample| sample.to_le_bytes().into_iter().take(self.unit_size())).collect()
+    }
+
+    // A sigrok session file (what PulseView and sigrok-cli open): a zip of a version file, an ini style metadata file
+    // and the raw samples.
+    pub fn write_sigrok(&self, mut out: impl Write) -> Result<(), Error> {
+        let mut metadata = format!("[global]\nsigrok version=0.5.2\n\n[device 1]\ncapturefile=logic-1\ntotal probes={}\n\
+                                    samplerate={}\ntotal analog=0\n",
+                                   self.pin_count, samplerate_string(self.sample_rate.round() as u64));
+        for n in 0..self.pin_count {
+            metadata += &format!("probe{}=GPIO{}\n", n + 1, self.base_pin + n);
+        }
+        metadata += &format!("unitsize={}\n", self.unit_size());
+        out.write_all(&zip(&[("version", b"2"), ("metadata", metadata.as_bytes()), ("logic-1-1", &self.raw())]))?;
+        Ok(())
+    }
+}
+
+// The way libsigrok's sr_samplerate_string() spells it, since that's what reads it back.
+fn samplerate_string(hz: u64) -> String {
+    match hz {
+        hz if hz >= 1_000_000_000 && hz.is_multiple_of(1_000_000_000) => format!("{} GHz", hz / 1_000_000_000),
+        hz if hz >= 1_000_000 && hz.is_multiple_of(1_000_000)         => format!("{} MHz", hz / 1_000_000),
+        hz if hz >= 1_000 && hz.is_multiple_of(1_000)                 => format!("{} kHz", hz / 1_000),
+        hz                                                            => format!("{hz} Hz"),
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0_u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 != 0 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 })
+    })
+}
+
+// Just enough zip to hold a few uncompressed files. No zip64, so each file (and the whole thing) has to stay under
+// 4 GB.
+fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut directory = Vec::new();
+    for &(name, data) in files {
+        let offset = out.len() as u32;
+        let crc = crc32(data);
+        let common = |out: &mut Vec<u8>| {
+            out.extend_from_slice(&20_u16.to_le_bytes());               // Version needed to extract
+            out.extend_from_slice(&0_u16.to_le_bytes());                // Flags
+            out.extend_from_slice(&0_u16.to_le_bytes());                // Stored
+            out.extend_from_slice(&0_u32.to_le_bytes());                // DOS time and date
+            out.extend_from_slice(&crc.to_le_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());  // Compressed size
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());  // Uncompressed size
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&0_u16.to_le_bytes());                // Extra field length
+        };
+        out.extend_from_slice(&0x0403_4b50_u32.to_le_bytes());
+        common(&mut out);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(data);
+
+        directory.extend_from_slice(&0x0201_4b50_u32.to_le_bytes());
+        directory.extend_from_slice(&20_u16.to_le_bytes());             // Version made by
+        common(&mut directory);
+        directory.extend_from_slice(&[0; 6]);                           // Comment length, disk, internal attributes
+        directory.extend_from_slice(&0_u32.to_le_bytes());              // External attributes
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+    let directory_offset = out.len() as u32;
+    out.extend_from_slice(&directory);
+    out.extend_from_slice(&0x0605_4b50_u32.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);                                     // Disk numbers
+    out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    out.extend_from_slice(&directory_offset.to_le_bytes());
+    out.extend_from_slice(&0_u16.to_le_bytes());                        // Comment length
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    #[test]
+    fn captures_after_pattern() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        backend.emulator().set_inputs(0b101 << 4, 0b111 << 4);
+        let la = LogicAnalyzer::new(pio.sm_claim(0).unwrap(), 4, 3).unwrap()
+            .with_trigger(Trigger::Pattern { value: 0b01, count: 2 }).unwrap()
+            .with_dma(false);
+        let capture = la.capture(25).unwrap();
+        assert_eq!(capture.samples, vec![0b101; 25]);
+        assert!(!capture.stalled);
+        assert_eq!(capture.unit_size(), 1);
+    }
+
+    #[test]
+    fn sigrok_zip() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(samplerate_string(25_000_000), "25 MHz");
+        assert_eq!(samplerate_string(1_500_000), "1500 kHz");
+        let zip = zip(&[("version", b"2")]);
+        assert_eq!(&zip[..4], b"PK\x03\x04");
+        assert_eq!(zip.len(), 30 + 7 + 1 + 46 + 7 + 22);
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+use std::{path::{Path, PathBuf}, sync::LazyLock};
+
+use crate::{ClkDiv, Error};
+
+const DEFAULT_PIO_CLOCK_HZ : u32 = 200_000_000; // RP1's clk_sys on every Pi 5 so far.
+const DEVICE_TREE          : &str = "/sys/firmware/devicetree/base";
+const RP1_CLOCKS           : &str = "raspberrypi,rp1-clocks";
+const RP1_CLK_SYS          : u32 = 12; // From the kernel's dt-bindings/clock/rp1.h
+const CLK_SYS_RATE         : &str = "/sys/kernel/debug/clk/clk_sys/clk_rate"; // Needs debugfs (and usually root).
+
+// Where pio_clock_hz() got its answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    DeviceTree, // The rate the RP1 clocks node assigns to clk_sys
+    DebugFs,    // The kernel's clock tree, in debugfs
+    Nominal,    // Neither was readable, so it's DEFAULT_PIO_CLOCK_HZ
+}
+
+static PIO_CLOCK: LazyLock<(u32, ClockSource)> = LazyLock::new(|| {
+    if let Some(rate) = device_tree_clk_sys(Path::new(DEVICE_TREE)) {
+        return (rate, ClockSource::DeviceTree);
+    }
+    std::fs::read_to_string(CLK_SYS_RATE).ok()
+        .and_then(|rate| rate.trim().parse().ok())
+        .filter(|&rate| rate != 0)
+        .map(|rate| (rate, ClockSource::DebugFs))
+        .unwrap_or((DEFAULT_PIO_CLOCK_HZ, ClockSource::Nominal))
+});
+
+// The clock feeding the PIO block (and so every SM's clock divider). Read from the device tree (which is world
+// readable) or debugfs if we can, otherwise we assume the standard 200 MHz.
+pub fn pio_clock_hz() -> u32 {
+    PIO_CLOCK.0
+}
+
+// When this is ClockSource::Nominal, frequencies computed from pio_clock_hz() (and their errors) are only as good as
+// the assumption.
+pub fn pio_clock_source() -> ClockSource {
+    PIO_CLOCK.1
+}
+
+// The RP1 clocks node sets its clocks up with `assigned-clocks` (<phandle index> pairs) and `assigned-clock-rates`, so
+// clk_sys is the rate that lines up with <its own phandle, RP1_CLK_SYS>.
+fn device_tree_clk_sys(root: &Path) -> Option<u32> {
+    let node = find_compatible(root, RP1_CLOCKS)?;
+    let cells = |name: &str| std::fs::read(node.join(name)).ok()
+        .map(|bytes| bytes.chunks_exact(4).map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+                          .collect::<Vec<u32>>());
+    let phandle = cells("phandle")?.first().copied()?;
+    let (clocks, rates) = (cells("assigned-clocks")?, cells("assigned-clock-rates")?);
+    if clocks.len() != rates.len() * 2 {
+        return None; // Some other provider in there, with a different number of cells
+    }
+    clocks.chunks_exact(2).zip(rates)
+        .find(|&(clock, _)| clock == [phandle, RP1_CLK_SYS])
+        .map(|(_, rate)| rate)
+        .filter(|&rate| rate != 0)
+}
+
+// The first node (depth first) with `compatible` in its NUL separated compatible list.
+fn find_compatible(dir: &Path, compatible: &str) -> Option<PathBuf> {
+    let list = std::fs::read(dir.join("compatible")).unwrap_or_default();
+    if list.split(|&b| b == 0).any(|c| c == compatible.as_bytes()) {
+        return Some(dir.to_path_buf());
+    }
+    std::fs::read_dir(dir).ok()?.flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+        .find_map(|entry| find_compatible(&entry.path(), compatible))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frequency {
+    pub target_hz: f64,
+    pub actual_hz: f64,
+    pub nominal: bool, // actual_hz (and so the error) assumes the default PIO clock, see pio_clock_source()
+}
+
+impl Frequency {
+    pub fn error_hz(&self) -> f64 {
+        self.actual_hz - self.target_hz
+    }
+
+    pub fn error_ppm(&self) -> f64 {
+        self.error_hz() / self.target_hz * 1e6
+    }
+}
+
+impl ClkDiv {
+    // The divider (with 8 bits of fraction) that gets closest to `target_hz` from a `sys_hz` clock. When `sys_hz` is
+    // pio_clock_hz(), pio_clock_source() says whether it (and so anything worked out from the divider) is nominal.
+    pub fn for_frequency(sys_hz: f64, target_hz: f64) -> Result<ClkDiv, Error> {
+        let div = sys_hz / target_hz;
+        if !(1_f64..=65536_f64).contains(&div) {
+            Err(Error::BadDiv { div, min: 1_f64, max: 65536_f64 })?;
+        }
+        let fixed = (div * 256_f64).round() as u32; // 16.8 fixed point. 65536.0 wraps to 0, which the hardware treats as 65536.
+        Ok(ClkDiv { div: (fixed >> 8) as u16, frac: fixed as u8 })
+    }
+
+    fn divisor(&self) -> f64 {
+        let int = if self.div == 0 { 65536_f64 } else { self.div as f64 };
+        int + self.frac as f64 / 256_f64
+    }
+
+    pub fn actual_frequency(&self, sys_hz: f64) -> f64 {
+        sys_hz / self.divisor()
+    }
+
+    // How far off `target_hz` this divider lands. The divider only knows its register value, not what it was
+    // computed for, so both clocks have to be passed back in.
+    pub fn error_ppm(&self, sys_hz: f64, target_hz: f64) -> f64 {
+        (self.actual_frequency(sys_hz) - target_hz) / target_hz * 1e6
+    }
+}
+
+impl From<(u16, u8)> for ClkDiv {
+    fn from((div, frac): (u16, u8)) -> Self {
+        ClkDiv { div, frac }
+    }
+}
+
+impl std::fmt::Display for ClkDiv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.divisor()) // Always exact: at most 17 bits of integer and 8 bits of fraction.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_tree() {
+        let root = std::env::temp_dir().join(format!("pio-clock-{}", std::process::id()));
+        let node = root.join("axi/pcie@120000/rp1/clocks@c040018000");
+        std::fs::create_dir_all(&node).unwrap();
+        let cells = |cells: &[u32]| cells.iter().flat_map(|cell| cell.to_be_bytes()).collect::<Vec<u8>>();
+        std::fs::write(root.join("compatible"), b"raspberrypi,5-model-b\0brcm,bcm2712\0").unwrap();
+        std::fs::write(node.join("compatible"), b"raspberrypi,rp1-clocks\0").unwrap();
+        std::fs::write(node.join("phandle"), cells(&[7])).unwrap();
+        std::fs::write(node.join("assigned-clocks"), cells(&[7, 0, 7, RP1_CLK_SYS, 7, 13])).unwrap();
+        std::fs::write(node.join("assigned-clock-rates"), cells(&[1_000_000_000, 150_000_000, 50_000_000])).unwrap();
+        let found = device_tree_clk_sys(&root);
+        std::fs::write(node.join("assigned-clocks"), cells(&[7, 0, 7, RP1_CLK_SYS])).unwrap();
+        let mismatched = device_tree_clk_sys(&root);
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(found, Some(150_000_000));
+        assert_eq!(mismatched, None);
+        assert_eq!(device_tree_clk_sys(&root), None);
+    }
+    #[test]
+    fn for_frequency() {
+        assert_eq!(ClkDiv::for_frequency(200e6, 200e6), Ok(ClkDiv { div: 1, frac: 0 }));
+        assert_eq!(ClkDiv::for_frequency(200e6, 115_200.0), Ok(ClkDiv { div: 1736, frac: 28 })); // 1736.11
+        assert_eq!(ClkDiv::for_frequency(200e6, 200e6 / 65536.0), Ok(ClkDiv { div: 0, frac: 0 }));
+        assert_eq!(ClkDiv::for_frequency(200e6, 400e6), Err(Error::BadDiv { div: 0.5, min: 1.0, max: 65536.0 }));
+        assert!(matches!(ClkDiv::for_frequency(200e6, 3000.0), Err(Error::BadDiv { .. })));
+        assert_eq!(ClkDiv { div: 0, frac: 0 }.actual_frequency(65536.0), 1.0);
+    }
+
+    #[test]
+    fn error_ppm() {
+        let div = ClkDiv::for_frequency(200e6, 115_200.0).unwrap();
+        let actual = div.actual_frequency(200e6);
+        assert_eq!(actual, 200e6 / (1736.0 + 28.0 / 256.0));
+        assert!((div.error_ppm(200e6, 115_200.0) - 1.0).abs() < 0.01, "{}", div.error_ppm(200e6, 115_200.0));
+        assert_eq!(ClkDiv { div: 4, frac: 0 }.error_ppm(200e6, 50e6), 0.0);
+        assert_eq!(ClkDiv { div: 4, frac: 0 }.error_ppm(200e6, 40e6), 250_000.0);
+        let frequency = Frequency { target_hz: 115_200.0, actual_hz: actual, nominal: true };
+        assert_eq!(frequency.error_hz(), actual - 115_200.0);
+        assert_eq!(frequency.error_ppm(), div.error_ppm(200e6, 115_200.0));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(ClkDiv { div: 4, frac: 0 }.to_string(), "4");
+        assert_eq!(ClkDiv { div: 2, frac: 128 }.to_string(), "2.5");
+        assert_eq!(ClkDiv { div: 1736, frac: 28 }.to_string(), "1736.109375");
+        assert_eq!(ClkDiv { div: 65535, frac: 255 }.to_string(), "65535.99609375");
+        assert_eq!(ClkDiv { div: 0, frac: 0 }.to_string(), "65536");
+    }
+}
@@ -1,9 +1,14 @@
 // Copyright © 2025 David Caldwell <david@porkrind.org>
 
-use crate::{proc_pio::*, ClkDiv, Error, PioFifoJoin, PioMovStatus, GPIO_COUNT, INSTRUCTION_COUNT};
+use std::ops::Range;
+
+use crate::{pio_clock_hz, proc_pio::*, ClkDiv, Error, PioFifoJoin, PioMovStatus, PioProgram, StateMachineHw, GPIO_COUNT};
+use crate::instruction::{Instruction, MovDestination, Operation, OutDestination, SetDestination, SideSet};
 
 #[repr(C)]
-#[derive(Clone,Copy)]
+#[derive(Clone,Copy,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(into = "DecodedSmConfig", try_from = "DecodedSmConfig"))]
 pub struct SmConfig {
     clkdiv:     u32,
     execctrl:   u32,
@@ -21,10 +26,24 @@ impl Default for SmConfig {
     }
 }
 
+// All of an SM's pin mappings in one go, for SmConfig::configure_pins().
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PinGroups {
+    pub out: Range<u32>,
+    pub set: Range<u32>,
+    pub in_base: u32,
+    pub sideset_base: u32,
+    pub jmp_pin: u32,
+}
+
 fn bool_to_u32(b: bool) -> u32 {
     b.into()
 }
 
+fn field(reg: u32, bits: u32, lsb: u32) -> u32 {
+    (reg & bits) >> lsb
+}
+
 macro_rules! valid_params_if {
     [ $test:expr, $param:expr, $should_be:expr] => {
         if $test { Ok(()) }
@@ -36,10 +55,45 @@ macro_rules! valid_params_if {
     };
 }
 
+// Starting points for common jobs, roughly what piolib's example `*_program_init()` helpers do. The wrap still has
+// to be set to match wherever the program got loaded.
+impl SmConfig {
+    // Shifts words out LSB first on `pin`, one bit per `out` at an SM clock of `hz`. `pin` is also mapped for `set`.
+    pub fn for_tx_serializer(pin: u32, hz: f64) -> Result<Self, Error> {
+        SmConfig::default()
+            .set_out_pins(pin, 1)?
+            .set_set_pins(pin, 1)?
+            .set_out_shift(true, true, 32)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_hz(hz)
+    }
+
+    // Shifts `pin` into words LSB first, one bit per `in` at an SM clock of `hz`. `pin` is also the `jmp pin`.
+    pub fn for_rx_sampler(pin: u32, hz: f64) -> Result<Self, Error> {
+        SmConfig::default()
+            .set_in_pins(pin)?
+            .set_jmp_pin(pin)?
+            .set_in_shift(true, true, 32)?
+            .set_fifo_join(PioFifoJoin::Rx)?
+            .set_clkdiv_hz(hz)
+    }
+
+    // For the canonical ws2812 program from the pico-examples: 1 side-set pin, 24 bit GRB words MSB first (so put
+    // `rgb << 8`), and 10 SM cycles per bit at 800 kHz.
+    pub fn for_ws2812(pin: u32) -> Result<Self, Error> {
+        SmConfig::default()
+            .set_sideset(1, false, false)?
+            .set_sideset_pins(pin)?
+            .set_out_shift(false, true, 24)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_hz(800_000.0 * 10.0)
+    }
+}
+
 impl SmConfig {
     pub fn set_out_pins(mut self, out_base: u32, out_count: u32) -> Result<Self, Error> {
         valid_params_if!(out_base < GPIO_COUNT as u32, "out_base", format!("< {GPIO_COUNT}"))?;
-        valid_params_if!(out_base < GPIO_COUNT as u32, "out_base")?;
+        valid_params_if!(out_count <= 32, "out_count")?;
         self.pinctrl = (self.pinctrl & !(PROC_PIO_SM0_PINCTRL_OUT_BASE_BITS | PROC_PIO_SM0_PINCTRL_OUT_COUNT_BITS)) |
                        (out_base << PROC_PIO_SM0_PINCTRL_OUT_BASE_LSB) |
                        (out_count << PROC_PIO_SM0_PINCTRL_OUT_COUNT_LSB);
@@ -69,6 +123,18 @@ impl SmConfig {
         Ok(self)
     }
 
+    // Sets all the pin mappings at once. The groups can overlap, as in a UART TX with out, set and side-set all on the
+    // one pin: side-set wins when it and out or set write a shared pin in the same cycle.
+    pub fn configure_pins(self, pins: PinGroups) -> Result<Self, Error> {
+        valid_params_if!(pins.out.start <= pins.out.end, "out", format!("an ascending range, not {:?}", pins.out))?;
+        valid_params_if!(pins.set.start <= pins.set.end, "set", format!("an ascending range, not {:?}", pins.set))?;
+        self.set_out_pins(pins.out.start, pins.out.len() as u32)?
+            .set_set_pins(pins.set.start, pins.set.len() as u32)?
+            .set_in_pins(pins.in_base)?
+            .set_sideset_pins(pins.sideset_base)?
+            .set_jmp_pin(pins.jmp_pin)
+    }
+
     pub fn set_sideset(mut self, bit_count: u32, optional: bool, pindirs: bool) -> Result<Self, Error> {
         valid_params_if!(bit_count <= 5, "bit_count")?;
         valid_params_if!(!optional || bit_count >= 1, "option,bit_count")?;
@@ -92,9 +158,16 @@ impl SmConfig {
         self.set_clkdiv_int_frac(div.try_into()?)
     }
 
+    // Picks the closest divider to `target_hz`. get_clkdiv_hz() reports what was actually achieved.
+    pub fn set_clkdiv_hz(self, target_hz: f64) -> Result<Self, Error> {
+        self.set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, target_hz)?)
+    }
+
     pub fn set_wrap(mut self, wrap_target: u32, wrap: u32) -> Result<Self, Error> {
-        valid_params_if!(wrap        < INSTRUCTION_COUNT as u32, "wrap",        format!("< {INSTRUCTION_COUNT}"))?;
-        valid_params_if!(wrap_target < INSTRUCTION_COUNT as u32, "wrap_target", format!("< {INSTRUCTION_COUNT}"))?;
+        // This is only the register field's limit. The chip's actual instruction memory size gets checked at init().
+        const WRAP_LIMIT: u32 = (PROC_PIO_SM0_EXECCTRL_WRAP_TOP_BITS >> PROC_PIO_SM0_EXECCTRL_WRAP_TOP_LSB) + 1;
+        valid_params_if!(wrap        < WRAP_LIMIT, "wrap",        format!("< {WRAP_LIMIT}"))?;
+        valid_params_if!(wrap_target < WRAP_LIMIT, "wrap_target", format!("< {WRAP_LIMIT}"))?;
         self.execctrl = (self.execctrl & !(PROC_PIO_SM0_EXECCTRL_WRAP_TOP_BITS | PROC_PIO_SM0_EXECCTRL_WRAP_BOTTOM_BITS)) |
                         (wrap_target << PROC_PIO_SM0_EXECCTRL_WRAP_BOTTOM_LSB) |
                         (wrap << PROC_PIO_SM0_EXECCTRL_WRAP_TOP_LSB);
@@ -134,7 +207,11 @@ impl SmConfig {
 
     pub fn set_fifo_join(mut self, join: PioFifoJoin) -> Result<Self, Error> {
         self.shiftctrl = (self.shiftctrl & !(PROC_PIO_SM0_SHIFTCTRL_FJOIN_TX_BITS | PROC_PIO_SM0_SHIFTCTRL_FJOIN_RX_BITS)) |
-                         ((join as u32) << PROC_PIO_SM0_SHIFTCTRL_FJOIN_TX_LSB);
+                         match join {
+                             PioFifoJoin::None => 0,
+                             PioFifoJoin::Tx   => PROC_PIO_SM0_SHIFTCTRL_FJOIN_TX_BITS,
+                             PioFifoJoin::Rx   => PROC_PIO_SM0_SHIFTCTRL_FJOIN_RX_BITS,
+                         };
         Ok(self)
     }
 
@@ -156,3 +233,461 @@ impl SmConfig {
         Ok(self)
     }
 }
+
+impl SmConfig {
+    // (clkdiv, execctrl, shiftctrl, pinctrl), in register order.
+    pub fn from_raw((clkdiv, execctrl, shiftctrl, pinctrl): (u32, u32, u32, u32)) -> SmConfig {
+        SmConfig { clkdiv, execctrl, shiftctrl, pinctrl }
+    }
+
+    pub fn to_raw(&self) -> (u32, u32, u32, u32) {
+        (self.clkdiv, self.execctrl, self.shiftctrl, self.pinctrl)
+    }
+
+    pub fn from_hw(hw: &StateMachineHw) -> SmConfig {
+        // EXEC_STALLED is read-only status, not configuration.
+        SmConfig::from_raw((hw.clkdiv, hw.execctrl & !PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS, hw.shiftctrl, hw.pinctrl))
+    }
+
+    pub fn get_out_pins(&self) -> (u32, u32) {
+        (field(self.pinctrl, PROC_PIO_SM0_PINCTRL_OUT_BASE_BITS, PROC_PIO_SM0_PINCTRL_OUT_BASE_LSB),
+         field(self.pinctrl, PROC_PIO_SM0_PINCTRL_OUT_COUNT_BITS, PROC_PIO_SM0_PINCTRL_OUT_COUNT_LSB))
+    }
+
+    pub fn get_set_pins(&self) -> (u32, u32) {
+        (field(self.pinctrl, PROC_PIO_SM0_PINCTRL_SET_BASE_BITS, PROC_PIO_SM0_PINCTRL_SET_BASE_LSB),
+         field(self.pinctrl, PROC_PIO_SM0_PINCTRL_SET_COUNT_BITS, PROC_PIO_SM0_PINCTRL_SET_COUNT_LSB))
+    }
+
+    pub fn get_in_pins(&self) -> u32 {
+        field(self.pinctrl, PROC_PIO_SM0_PINCTRL_IN_BASE_BITS, PROC_PIO_SM0_PINCTRL_IN_BASE_LSB)
+    }
+
+    pub fn get_sideset_pins(&self) -> u32 {
+        field(self.pinctrl, PROC_PIO_SM0_PINCTRL_SIDESET_BASE_BITS, PROC_PIO_SM0_PINCTRL_SIDESET_BASE_LSB)
+    }
+
+    // (bit_count, optional, pindirs)
+    pub fn get_sideset(&self) -> (u32, bool, bool) {
+        (field(self.pinctrl, PROC_PIO_SM0_PINCTRL_SIDESET_COUNT_BITS, PROC_PIO_SM0_PINCTRL_SIDESET_COUNT_LSB),
+         self.execctrl & PROC_PIO_SM0_EXECCTRL_SIDE_EN_BITS != 0,
+         self.execctrl & PROC_PIO_SM0_EXECCTRL_SIDE_PINDIR_BITS != 0)
+    }
+
+    // Mask of the GPIOs this config lets the SM drive via out, set and side-set (which wrap around at 32).
+    pub fn driven_pins(&self) -> u32 {
+        fn pin_range(base: u32, count: u32) -> u32 {
+            let mask = if count >= 32 { !0 } else { (1_u32 << count) - 1 };
+            mask.rotate_left(base)
+        }
+        let (out_base, out_count) = self.get_out_pins();
+        let (set_base, set_count) = self.get_set_pins();
+        let (sideset_bits, optional, _) = self.get_sideset();
+        pin_range(out_base, out_count) |
+        pin_range(set_base, set_count) |
+        pin_range(self.get_sideset_pins(), sideset_bits.saturating_sub(optional as u32))
+    }
+
+    pub fn get_clkdiv(&self) -> ClkDiv {
+        ClkDiv { div:  field(self.clkdiv, PROC_PIO_SM0_CLKDIV_INT_BITS, PROC_PIO_SM0_CLKDIV_INT_LSB) as u16,
+                 frac: field(self.clkdiv, PROC_PIO_SM0_CLKDIV_FRAC_BITS, PROC_PIO_SM0_CLKDIV_FRAC_LSB) as u8 }
+    }
+
+    pub fn get_clkdiv_hz(&self) -> f64 {
+        self.get_clkdiv().actual_frequency(pio_clock_hz() as f64)
+    }
+
+    // (wrap_target, wrap)
+    pub fn get_wrap(&self) -> (u32, u32) {
+        (field(self.execctrl, PROC_PIO_SM0_EXECCTRL_WRAP_BOTTOM_BITS, PROC_PIO_SM0_EXECCTRL_WRAP_BOTTOM_LSB),
+         field(self.execctrl, PROC_PIO_SM0_EXECCTRL_WRAP_TOP_BITS, PROC_PIO_SM0_EXECCTRL_WRAP_TOP_LSB))
+    }
+
+    pub fn get_jmp_pin(&self) -> u32 {
+        field(self.execctrl, PROC_PIO_SM0_EXECCTRL_JMP_PIN_BITS, PROC_PIO_SM0_EXECCTRL_JMP_PIN_LSB)
+    }
+
+    // (shift_right, autopush, push_threshold). A threshold of 32 is stored as 0 in the register.
+    pub fn get_in_shift(&self) -> (bool, bool, u32) {
+        (self.shiftctrl & PROC_PIO_SM0_SHIFTCTRL_IN_SHIFTDIR_BITS != 0,
+         self.shiftctrl & PROC_PIO_SM0_SHIFTCTRL_AUTOPUSH_BITS != 0,
+         match field(self.shiftctrl, PROC_PIO_SM0_SHIFTCTRL_PUSH_THRESH_BITS, PROC_PIO_SM0_SHIFTCTRL_PUSH_THRESH_LSB) {
+             0 => 32,
+             n => n,
+         })
+    }
+
+    // (shift_right, autopull, pull_threshold)
+    pub fn get_out_shift(&self) -> (bool, bool, u32) {
+        (self.shiftctrl & PROC_PIO_SM0_SHIFTCTRL_OUT_SHIFTDIR_BITS != 0,
+         self.shiftctrl & PROC_PIO_SM0_SHIFTCTRL_AUTOPULL_BITS != 0,
+         match field(self.shiftctrl, PROC_PIO_SM0_SHIFTCTRL_PULL_THRESH_BITS, PROC_PIO_SM0_SHIFTCTRL_PULL_THRESH_LSB) {
+             0 => 32,
+             n => n,
+         })
+    }
+
+    // Both join bits can only get set via from_raw()/from_hw(), and isn't a valid configuration.
+    pub fn get_fifo_join(&self) -> Result<PioFifoJoin, Error> {
+        match (self.shiftctrl & PROC_PIO_SM0_SHIFTCTRL_FJOIN_TX_BITS != 0, self.shiftctrl & PROC_PIO_SM0_SHIFTCTRL_FJOIN_RX_BITS != 0) {
+            (false, false) => Ok(PioFifoJoin::None),
+            (true,  false) => Ok(PioFifoJoin::Tx),
+            (false, true)  => Ok(PioFifoJoin::Rx),
+            (true,  true)  => Err(Error::ParamErr { param: "fifo_join", should_be: "at most one of FJOIN_TX and FJOIN_RX".to_string() }),
+        }
+    }
+
+    // (sticky, has_enable_pin, enable_pin_index)
+    pub fn get_out_special(&self) -> (bool, bool, u32) {
+        (self.execctrl & PROC_PIO_SM0_EXECCTRL_OUT_STICKY_BITS != 0,
+         self.execctrl & PROC_PIO_SM0_EXECCTRL_INLINE_OUT_EN_BITS != 0,
+         field(self.execctrl, PROC_PIO_SM0_EXECCTRL_OUT_EN_SEL_BITS, PROC_PIO_SM0_EXECCTRL_OUT_EN_SEL_LSB))
+    }
+
+    // (status_sel, status_n)
+    pub fn get_mov_status(&self) -> (PioMovStatus, u32) {
+        (match field(self.execctrl, PROC_PIO_SM0_EXECCTRL_STATUS_SEL_BITS, PROC_PIO_SM0_EXECCTRL_STATUS_SEL_LSB) {
+             0 => PioMovStatus::TxLessThan,
+             _ => PioMovStatus::RxLessThan,
+         },
+         field(self.execctrl, PROC_PIO_SM0_EXECCTRL_STATUS_N_BITS, PROC_PIO_SM0_EXECCTRL_STATUS_N_LSB))
+    }
+}
+
+impl SmConfig {
+    // Cross checks the config against what `program` (loaded at `offset`) actually does. These mismatches don't
+    // produce errors from the kernel, the program just silently misbehaves.
+    pub fn validate_for(&self, program: &PioProgram, offset: u16) -> Result<(), Error> {
+        let start = offset as u32;
+        let range = start..start + program.len() as u32;
+        let (wrap_target, wrap) = self.get_wrap();
+        valid_params_if!(range.contains(&wrap_target), "wrap_target", format!("in {range:?}, where the program is loaded"))?;
+        valid_params_if!(range.contains(&wrap),        "wrap",        format!("in {range:?}, where the program is loaded"))?;
+        if let Some((program_wrap_target, program_wrap)) = program.wrap() {
+            valid_params_if!(wrap_target == start + program_wrap_target as u32, "wrap_target", format!("{} to match the program", start + program_wrap_target as u32))?;
+            valid_params_if!(wrap        == start + program_wrap as u32,        "wrap",        format!("{} to match the program", start + program_wrap as u32))?;
+        }
+
+        let (sideset_bits, optional, pindirs) = self.get_sideset();
+        let side_set = match program.side_set() {
+            Some(side_set) => {
+                valid_params_if!(sideset_bits == side_set.bits() as u32 && optional == side_set.optional && pindirs == side_set.pindirs,
+                                 "sideset", format!("({}, {}, {}) to match the program", side_set.bits(), side_set.optional, side_set.pindirs))?;
+                side_set
+            },
+            None => SideSet::new(sideset_bits.saturating_sub(optional as u32) as u8, optional, pindirs),
+        };
+
+        let (_, out_count) = self.get_out_pins();
+        let (_, set_count) = self.get_set_pins();
+        for (i, instr) in program.instructions().iter().enumerate() {
+            let decoded = Instruction::decode(*instr, side_set)?;
+            match decoded.operation {
+                Operation::Jmp { address, .. } =>
+                    valid_params_if!((address as usize) < program.len(), "jmp address", format!("inside the program (instruction {i}: `{decoded}`)"))?,
+                Operation::Out { destination: OutDestination::Pins | OutDestination::Pindirs, bit_count } =>
+                    valid_params_if!(out_count >= bit_count as u32, "out_count", format!(">= {bit_count} (instruction {i}: `{decoded}`)"))?,
+                Operation::Mov { destination: MovDestination::Pins, .. } =>
+                    valid_params_if!(out_count >= 1, "out_count", format!(">= 1 (instruction {i}: `{decoded}`)"))?,
+                Operation::Set { destination: SetDestination::Pins | SetDestination::Pindirs, data } => {
+                    let needed = (u8::BITS - data.leading_zeros()).max(1);
+                    valid_params_if!(set_count >= needed, "set_count", format!(">= {needed} (instruction {i}: `{decoded}`)"))?
+                },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for SmConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (out_base, out_count)                       = self.get_out_pins();
+        let (set_base, set_count)                       = self.get_set_pins();
+        let (sideset_count, sideset_opt, sideset_pindirs) = self.get_sideset();
+        let (wrap_target, wrap)                         = self.get_wrap();
+        let (in_shift_right, autopush, push_threshold)  = self.get_in_shift();
+        let (out_shift_right, autopull, pull_threshold) = self.get_out_shift();
+        let (out_sticky, inline_out_en, out_en_sel)     = self.get_out_special();
+        let (status_sel, status_n)                      = self.get_mov_status();
+        f.debug_struct("SmConfig")
+            .field("clkdiv",          &self.get_clkdiv())
+            .field("out_pins",        &(out_base..out_base + out_count))
+            .field("set_pins",        &(set_base..set_base + set_count))
+            .field("in_base",         &self.get_in_pins())
+            .field("sideset_base",    &self.get_sideset_pins())
+            .field("sideset_count",   &sideset_count)
+            .field("sideset_opt",     &sideset_opt)
+            .field("sideset_pindirs", &sideset_pindirs)
+            .field("jmp_pin",         &self.get_jmp_pin())
+            .field("wrap",            &(wrap_target..=wrap))
+            .field("in_shift_right",  &in_shift_right)
+            .field("autopush",        &autopush)
+            .field("push_threshold",  &push_threshold)
+            .field("out_shift_right", &out_shift_right)
+            .field("autopull",        &autopull)
+            .field("pull_threshold",  &pull_threshold)
+            .field("fifo_join",       &self.get_fifo_join())
+            .field("out_sticky",      &out_sticky)
+            .field("inline_out_en",   &inline_out_en)
+            .field("out_en_sel",      &out_en_sel)
+            .field("status_sel",      &status_sel)
+            .field("status_n",        &status_n)
+            .finish()
+    }
+}
+
+// SmConfig's serialized form: the decoded fields rather than the four raw registers, so that config files are
+// readable and editable. Deserializing goes through the normal setters so everything gets validated.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct DecodedSmConfig {
+    clkdiv_int:       u16,
+    clkdiv_frac:      u8,
+    out_base:         u32,
+    out_count:        u32,
+    set_base:         u32,
+    set_count:        u32,
+    in_base:          u32,
+    sideset_base:     u32,
+    sideset_bits:     u32,
+    sideset_optional: bool,
+    sideset_pindirs:  bool,
+    jmp_pin:          u32,
+    wrap_target:      u32,
+    wrap:             u32,
+    in_shift_right:   bool,
+    autopush:         bool,
+    push_threshold:   u32,
+    out_shift_right:  bool,
+    autopull:         bool,
+    pull_threshold:   u32,
+    fjoin_tx:         bool,
+    fjoin_rx:         bool,
+    out_sticky:       bool,
+    inline_out_en:    bool,
+    out_en_sel:       u32,
+    status_sel:       PioMovStatus,
+    status_n:         u32,
+}
+
+#[cfg(feature = "serde")]
+impl From<SmConfig> for DecodedSmConfig {
+    fn from(config: SmConfig) -> Self {
+        let clkdiv                                          = config.get_clkdiv();
+        let (out_base, out_count)                           = config.get_out_pins();
+        let (set_base, set_count)                           = config.get_set_pins();
+        let (sideset_bits, sideset_optional, sideset_pindirs) = config.get_sideset();
+        let (wrap_target, wrap)                             = config.get_wrap();
+        let (in_shift_right, autopush, push_threshold)      = config.get_in_shift();
+        let (out_shift_right, autopull, pull_threshold)     = config.get_out_shift();
+        let (out_sticky, inline_out_en, out_en_sel)         = config.get_out_special();
+        let (status_sel, status_n)                          = config.get_mov_status();
+        DecodedSmConfig {
+            clkdiv_int: clkdiv.div, clkdiv_frac: clkdiv.frac,
+            out_base, out_count, set_base, set_count,
+            in_base: config.get_in_pins(),
+            sideset_base: config.get_sideset_pins(),
+            sideset_bits, sideset_optional, sideset_pindirs,
+            jmp_pin: config.get_jmp_pin(),
+            wrap_target, wrap,
+            in_shift_right, autopush, push_threshold,
+            out_shift_right, autopull, pull_threshold,
+            fjoin_tx: config.shiftctrl & PROC_PIO_SM0_SHIFTCTRL_FJOIN_TX_BITS != 0,
+            fjoin_rx: config.shiftctrl & PROC_PIO_SM0_SHIFTCTRL_FJOIN_RX_BITS != 0,
+            out_sticky, inline_out_en, out_en_sel,
+            status_sel, status_n,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<DecodedSmConfig> for SmConfig {
+    type Error = Error;
+
+    fn try_from(d: DecodedSmConfig) -> Result<Self, Self::Error> {
+        let join = match (d.fjoin_tx, d.fjoin_rx) {
+            (false, false) => PioFifoJoin::None,
+            (true,  false) => PioFifoJoin::Tx,
+            (false, true)  => PioFifoJoin::Rx,
+            (true,  true)  => Err(Error::ParamErr { param: "fjoin_tx,fjoin_rx", should_be: "not both set".to_string() })?,
+        };
+        SmConfig { clkdiv: 0, execctrl: 0, shiftctrl: 0, pinctrl: 0 }
+            .set_clkdiv_int_frac(ClkDiv { div: d.clkdiv_int, frac: d.clkdiv_frac })?
+            .set_out_pins(d.out_base, d.out_count)?
+            .set_set_pins(d.set_base, d.set_count)?
+            .set_in_pins(d.in_base)?
+            .set_sideset_pins(d.sideset_base)?
+            .set_sideset(d.sideset_bits, d.sideset_optional, d.sideset_pindirs)?
+            .set_jmp_pin(d.jmp_pin)?
+            .set_wrap(d.wrap_target, d.wrap)?
+            .set_in_shift(d.in_shift_right, d.autopush, d.push_threshold)?
+            .set_out_shift(d.out_shift_right, d.autopull, d.pull_threshold)?
+            .set_fifo_join(join)?
+            .set_out_special(d.out_sticky, d.inline_out_en, d.out_en_sel)?
+            .set_mov_status(d.status_sel, d.status_n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configure_pins() {
+        // drivers::uart's TX: out, set and side-set all on the one pin
+        let pins = PinGroups { out: 5..6, set: 5..6, in_base: 5, sideset_base: 5, jmp_pin: 5 };
+        let config = SmConfig::default().set_sideset(2, true, false).unwrap().configure_pins(pins.clone()).unwrap();
+        assert_eq!((config.get_out_pins(), config.get_set_pins()), ((5, 1), (5, 1)));
+        assert_eq!((config.get_in_pins(), config.get_sideset_pins(), config.get_jmp_pin()), (5, 5, 5));
+        assert_eq!(config.driven_pins(), 1 << 5);
+        // Doesn't care whether the side-set count comes first
+        let later = SmConfig::default().configure_pins(pins).unwrap().set_sideset(2, true, false).unwrap();
+        assert_eq!(later, config);
+
+        let pins = PinGroups { out: 0..4, set: 4..6, in_base: 10, sideset_base: 6, jmp_pin: 11 };
+        let config = SmConfig::default().set_sideset(1, false, false).unwrap().configure_pins(pins).unwrap();
+        assert_eq!(config.driven_pins(), 0b111_1111);
+        #[allow(clippy::reversed_empty_ranges)]
+        let backwards = PinGroups { out: 4..0, ..PinGroups::default() };
+        assert!(SmConfig::default().configure_pins(backwards).is_err());
+        assert!(SmConfig::default().configure_pins(PinGroups { set: 0..6, ..PinGroups::default() }).is_err());
+    }
+    #[test]
+    fn fifo_join() {
+        let bits = PROC_PIO_SM0_SHIFTCTRL_FJOIN_TX_BITS | PROC_PIO_SM0_SHIFTCTRL_FJOIN_RX_BITS;
+        for (join, expected) in [(PioFifoJoin::None, 0),
+                                 (PioFifoJoin::Tx,   PROC_PIO_SM0_SHIFTCTRL_FJOIN_TX_BITS),
+                                 (PioFifoJoin::Rx,   PROC_PIO_SM0_SHIFTCTRL_FJOIN_RX_BITS)] {
+            // Whatever it was joined before
+            for before in [PioFifoJoin::None, PioFifoJoin::Tx, PioFifoJoin::Rx] {
+                let config = SmConfig::default().set_fifo_join(before).unwrap().set_fifo_join(join).unwrap();
+                assert_eq!(config.get_fifo_join().unwrap(), join);
+                assert_eq!(config.shiftctrl & bits, expected, "{before:?} -> {join:?}");
+                assert_eq!(config.shiftctrl & !bits, SmConfig::default().shiftctrl & !bits);
+            }
+        }
+        let both = SmConfig { shiftctrl: bits, ..SmConfig::default() };
+        assert!(both.get_fifo_join().is_err());
+    }
+    #[test]
+    fn clkdiv_hz() {
+        let sys = pio_clock_hz() as f64;
+        let div = |hz: f64| SmConfig::default().set_clkdiv_hz(hz).map(|config| config.get_clkdiv());
+        assert_eq!(div(sys), Ok(ClkDiv { div: 1, frac: 0 }));
+        assert_eq!(div(sys / 4.0), Ok(ClkDiv { div: 4, frac: 0 }));
+        assert_eq!(div(sys / 2.5), Ok(ClkDiv { div: 2, frac: 128 }));
+        assert_eq!(SmConfig::default().set_clkdiv_hz(sys / 2.5).unwrap().get_clkdiv_hz(), sys / 2.5);
+        // To the nearest 1/256th
+        assert_eq!(div(sys / (3.0 + 0.7 / 256.0)), Ok(ClkDiv { div: 3, frac: 1 }));
+        assert_eq!(div(sys / (3.0 + 0.3 / 256.0)), Ok(ClkDiv { div: 3, frac: 0 }));
+        assert_eq!(div(sys / (3.0 + 255.7 / 256.0)), Ok(ClkDiv { div: 4, frac: 0 }));
+        // The slowest is a divider of 65536, which the register holds as 0.
+        assert_eq!(div(sys / 65536.0), Ok(ClkDiv { div: 0, frac: 0 }));
+        assert_eq!(div(sys * 2.0), Err(Error::BadDiv { div: 0.5, min: 1.0, max: 65536.0 }));
+        assert!(matches!(div(sys / 65537.0), Err(Error::BadDiv { .. })));
+        assert!(matches!(div(sys / 65536.5), Err(Error::BadDiv { .. })));
+    }
+    #[test]
+    fn validate_for() {
+        // jmp 1; out pins, 2; mov pins, x; set pins, 3 side 0
+        let program = PioProgram::new(&[0x0001, 0x6002, 0xa001, 0xf003], None)
+            .with_side_set(SideSet::new(1, true, false))
+            .with_wrap(1, 3);
+        let config = SmConfig::default().set_wrap(11, 13).unwrap()
+            .set_out_pins(0, 2).unwrap()
+            .set_set_pins(2, 2).unwrap()
+            .set_sideset(2, true, false).unwrap();
+        config.validate_for(&program, 10).unwrap();
+
+        let rejected = |config: SmConfig, program: &PioProgram| match config.validate_for(program, 10) {
+            Err(Error::ParamErr { param, .. }) => param,
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(rejected(config.set_wrap(9, 13).unwrap(), &program.clone().with_wrap(0, 3)), "wrap_target");
+        assert_eq!(rejected(config.set_wrap(10, 14).unwrap(), &program.clone().with_wrap(0, 3)), "wrap");
+        assert_eq!(rejected(config.set_wrap(10, 13).unwrap(), &program), "wrap_target"); // Not the program's
+        assert_eq!(rejected(config.set_wrap(11, 12).unwrap(), &program), "wrap");
+        assert_eq!(rejected(config.set_sideset(1, false, false).unwrap(), &program), "sideset");
+        assert_eq!(rejected(config.set_out_pins(0, 1).unwrap(), &program), "out_count");
+        let mov = PioProgram::new(&[0xa001], None);
+        SmConfig::default().set_wrap(10, 10).unwrap().set_out_pins(0, 1).unwrap().validate_for(&mov, 10).unwrap();
+        assert_eq!(rejected(SmConfig::default().set_wrap(10, 10).unwrap(), &mov), "out_count");
+        assert_eq!(rejected(config.set_set_pins(2, 1).unwrap(), &program), "set_count");
+        let side_set = SideSet::new(1, true, false);
+        let jumps_out = PioProgram::new(&[0x0004, 0x6002, 0xa001, 0xf003], None).with_side_set(side_set);
+        assert_eq!(rejected(config.set_wrap(10, 13).unwrap(), &jumps_out), "jmp address");
+        let bad = PioProgram::new(&[0x2060, 0x6002, 0xa001, 0xf003], None).with_side_set(side_set); // wait with source 3
+        assert_eq!(rejected(config.set_wrap(10, 13).unwrap(), &bad), "instr");
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let config = SmConfig::default().set_clkdiv_int_frac(ClkDiv { div: 1736, frac: 28 }).unwrap()
+            .set_out_pins(4, 2).unwrap()
+            .set_set_pins(6, 3).unwrap()
+            .set_in_pins(9).unwrap()
+            .set_sideset_pins(10).unwrap()
+            .set_sideset(2, true, true).unwrap()
+            .set_jmp_pin(12).unwrap()
+            .set_wrap(3, 7).unwrap()
+            .set_in_shift(false, true, 8).unwrap()
+            .set_out_shift(true, true, 24).unwrap()
+            .set_fifo_join(PioFifoJoin::Rx).unwrap()
+            .set_out_special(true, true, 1).unwrap()
+            .set_mov_status(PioMovStatus::RxLessThan, 2).unwrap();
+        let json = serde_json::to_value(config).unwrap();
+        assert_eq!((&json["out_base"], &json["fjoin_rx"], &json["clkdiv_frac"]), (&4.into(), &true.into(), &28.into()));
+        assert_eq!(serde_json::from_value::<SmConfig>(json.clone()).unwrap(), config);
+        let default = serde_json::to_string(&SmConfig::default()).unwrap();
+        assert_eq!(serde_json::from_str::<SmConfig>(&default).unwrap(), SmConfig::default());
+
+        // Everything goes back through the setters
+        for (field, value) in [("fjoin_tx", true.into()), ("out_count", 33.into()), ("set_count", 6.into()),
+                               ("jmp_pin", 28.into()), ("wrap", 32.into()), ("push_threshold", 33.into()),
+                               ("status_sel", "Sideways".into()), ("clkdiv_frac", 256.into())] {
+            let mut invalid = json.clone();
+            invalid[field] = value;
+            assert!(serde_json::from_value::<SmConfig>(invalid).is_err(), "{field}");
+        }
+        let mut missing = json.clone();
+        missing.as_object_mut().unwrap().remove("wrap");
+        assert!(serde_json::from_value::<SmConfig>(missing).is_err());
+    }
+    #[test]
+    fn presets() {
+        let clkdiv = |hz: f64| {
+            let div = ClkDiv::for_frequency(pio_clock_hz() as f64, hz).unwrap();
+            (div.div as u32) << PROC_PIO_SM0_CLKDIV_INT_LSB | (div.frac as u32) << PROC_PIO_SM0_CLKDIV_FRAC_LSB
+        };
+        let (_, default_execctrl, _, _) = SmConfig::default().to_raw();
+        let shift_right = PROC_PIO_SM0_SHIFTCTRL_IN_SHIFTDIR_BITS | PROC_PIO_SM0_SHIFTCTRL_OUT_SHIFTDIR_BITS;
+
+        // Thresholds of 32 are stored as 0
+        assert_eq!(SmConfig::for_tx_serializer(5, 1e6).unwrap().to_raw(),
+                   (clkdiv(1e6),
+                    default_execctrl,
+                    shift_right | PROC_PIO_SM0_SHIFTCTRL_AUTOPULL_BITS | PROC_PIO_SM0_SHIFTCTRL_FJOIN_TX_BITS,
+                    5 << PROC_PIO_SM0_PINCTRL_OUT_BASE_LSB | 1 << PROC_PIO_SM0_PINCTRL_OUT_COUNT_LSB |
+                    5 << PROC_PIO_SM0_PINCTRL_SET_BASE_LSB | 1 << PROC_PIO_SM0_PINCTRL_SET_COUNT_LSB));
+        assert_eq!(SmConfig::for_rx_sampler(7, 2.5e6).unwrap().to_raw(),
+                   (clkdiv(2.5e6),
+                    default_execctrl | 7 << PROC_PIO_SM0_EXECCTRL_JMP_PIN_LSB,
+                    shift_right | PROC_PIO_SM0_SHIFTCTRL_AUTOPUSH_BITS | PROC_PIO_SM0_SHIFTCTRL_FJOIN_RX_BITS,
+                    7 << PROC_PIO_SM0_PINCTRL_IN_BASE_LSB));
+        let ws2812 = SmConfig::for_ws2812(18).unwrap();
+        assert_eq!(ws2812.to_raw(),
+                   (clkdiv(8e6),
+                    default_execctrl,
+                    PROC_PIO_SM0_SHIFTCTRL_IN_SHIFTDIR_BITS | PROC_PIO_SM0_SHIFTCTRL_AUTOPULL_BITS |
+                    24 << PROC_PIO_SM0_SHIFTCTRL_PULL_THRESH_LSB | PROC_PIO_SM0_SHIFTCTRL_FJOIN_TX_BITS,
+                    18 << PROC_PIO_SM0_PINCTRL_SIDESET_BASE_LSB | 1 << PROC_PIO_SM0_PINCTRL_SIDESET_COUNT_LSB));
+        if pio_clock_hz() == 200_000_000 {
+            assert_eq!(ws2812.get_clkdiv(), ClkDiv { div: 25, frac: 0 });
+        }
+
+        assert!(SmConfig::for_tx_serializer(28, 1e6).is_err());
+        assert!(SmConfig::for_rx_sampler(0, 1.0).is_err());
+        assert!(SmConfig::for_ws2812(28).is_err());
+    }
+}
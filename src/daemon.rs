@@ -0,0 +1,516 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Sharing one PIO between processes. pio-daemon holds /dev/pio0 open and hands out its SMs and instruction memory to
+// clients over a Unix socket, and a DaemonClient is a PioBackend that talks to it, so everything else works as usual:
+//
+//     let client = DaemonClient::connect("/run/pio0.sock")?;
+//     let chip = client.chip().clone();
+//     let pio = Rp1PIO::with_backend(Box::new(client), chip);
+//
+// The daemon keeps track of which client claimed which SMs and loaded which programs. A client can only touch SMs it
+// has claimed and only remove programs it added, and when it disconnects its SMs are stopped and unclaimed and its
+// programs removed. Things that would reach around that are refused with EPERM: CLEAR_INSTR_MEM, WRITE_HW, and
+// READ_HW of another client's TX or RX FIFO (reading RXF pops it).
+//
+// Pins belong to the first client to use them, either with a GPIO ioctl or by setting up one of its SMs to drive them
+// (its out, set and side-set pins, or the mask given to set_pins_with_mask() and friends), until it disconnects. GPIO
+// ioctls on another client's pins, and SM configs that would drive them, get EPERM too.
+//
+// The protocol is the ioctls themselves, one per line, in the same hex as the traces in record.rs. The daemon starts
+// with a greeting describing the chip:
+//
+//     # pio-daemon v1 <name> <compatible> <instr_count> <sm_count> <fifo_depth>
+//
+// and then for each ioctl the client sends `<request> <args> <buffer>` (the buffer being what WRITE_HW or a to-SM
+// XFER_DATA points at, or `-`) and gets back `<args after> <buffer> <result>` (the buffer being what READ_HW or a
+// from-SM XFER_DATA filled in, and the args keeping the client's own pointer). Requests are answered in order, so a
+// client blocked in a get() holds up the rest of its ioctls; use another connection (and another Rp1PIO) for anything
+// that needs to run alongside.
+//
+// With the `remote` feature the same protocol can be served over TCP, which makes a Pi 5 into a networked instrument
+// that a bench PC can load programs into and stream FIFO data from:
+//
+//     let client = DaemonClient::connect_tcp("pi5.local:7450")?;
+//
+// There's no authentication or encryption, so only listen on a network you trust (or tunnel it over ssh).
+
+use std::{ffi::c_void, io::{BufRead, BufReader, Read, Write}, os::unix::net::{UnixListener, UnixStream},
+          path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+
+#[cfg(feature = "remote")]
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use libc::c_ulong;
+
+use crate::{lock, proc_pio::*, record::{hex, unhex, Outcome}, Chip, Error, PioBackend, Rp1PIO, GPIOS_MASK, GPIO_COUNT};
+use crate::ioctl::*;
+
+const GREETING: &str = "# pio-daemon v1";
+
+// Nobody has a legitimate reason to move more than this in one ioctl, and the daemon allocates it up front.
+const MAX_BUFFER: usize = 16 << 20;
+
+fn protocol_error(message: impl Into<String>) -> Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into()).into()
+}
+
+fn errno<T>(errno: i32) -> Result<T, Error> {
+    Err(std::io::Error::from_raw_os_error(errno))?
+}
+
+// Which SMs a request affects, for deciding whether a client is allowed to make it.
+enum Scope {
+    Anyone,
+    Sm,        // `sm` is the first field
+    Mask,      // so is `mask`
+    Gpio,      // and `gpio`
+    ReadHw,
+    Claim,
+    Unclaim,
+    AddProgram,
+    RemoveProgram,
+    Nobody,
+}
+
+fn scope(request: c_ulong) -> Scope {
+    match request {
+        PIO_IOC_SM_CLAIM                                          => Scope::Claim,
+        PIO_IOC_SM_UNCLAIM                                        => Scope::Unclaim,
+        PIO_IOC_ADD_PROGRAM                                       => Scope::AddProgram,
+        PIO_IOC_REMOVE_PROGRAM                                    => Scope::RemoveProgram,
+        PIO_IOC_CLEAR_INSTR_MEM | PIO_IOC_WRITE_HW                => Scope::Nobody,
+        PIO_IOC_SM_SET_ENABLED | PIO_IOC_SM_RESTART |
+        PIO_IOC_SM_CLKDIV_RESTART | PIO_IOC_SM_ENABLE_SYNC        => Scope::Mask,
+        PIO_IOC_SM_INIT | PIO_IOC_SM_SET_CONFIG | PIO_IOC_SM_EXEC | PIO_IOC_SM_CLEAR_FIFOS | PIO_IOC_SM_SET_CLKDIV |
+        PIO_IOC_SM_SET_PINS | PIO_IOC_SM_SET_PINDIRS | PIO_IOC_SM_PUT | PIO_IOC_SM_GET | PIO_IOC_SM_SET_DMACTRL |
+        PIO_IOC_SM_DRAIN_TX | PIO_IOC_SM_CONFIG_XFER | PIO_IOC_SM_CONFIG_XFER32 |
+        PIO_IOC_SM_XFER_DATA | PIO_IOC_SM_XFER_DATA32             => Scope::Sm,
+        PIO_IOC_GPIO_INIT | PIO_IOC_GPIO_SET_FUNCTION | PIO_IOC_GPIO_SET_PULLS | PIO_IOC_GPIO_SET_OUTOVER |
+        PIO_IOC_GPIO_SET_INOVER | PIO_IOC_GPIO_SET_OEOVER | PIO_IOC_GPIO_SET_INPUT_ENABLED |
+        PIO_IOC_GPIO_SET_DRIVE_STRENGTH                           => Scope::Gpio,
+        PIO_IOC_READ_HW                                           => Scope::ReadHw,
+        _                                                         => Scope::Anyone,
+    }
+}
+
+// The pins an SM request would have the SM drive.
+unsafe fn sm_pins(request: c_ulong, args: *const c_void) -> u32 {
+    unsafe {
+        match request {
+            PIO_IOC_SM_INIT        => (*(args as *const SmInitArgs)).config.driven_pins() & GPIOS_MASK,
+            PIO_IOC_SM_SET_CONFIG  => (*(args as *const SmSetConfigArgs)).config.driven_pins() & GPIOS_MASK,
+            PIO_IOC_SM_SET_PINS    => (*(args as *const SmSetPinsArgs)).mask & GPIOS_MASK,
+            PIO_IOC_SM_SET_PINDIRS => (*(args as *const SmSetPindirsArgs)).mask & GPIOS_MASK,
+            _                      => 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Claims {
+    sms: Vec<Option<u64>>,            // Which client claimed each SM
+    programs: Vec<(u64, u16, u16)>,   // (client, offset, length)
+    pins: Vec<Option<u64>>,           // Which client is using each GPIO
+}
+
+impl Claims {
+    // Makes the call and then gives the client all of `pins`, unless another client already has any of them (in which
+    // case there's no call) or the call fails.
+    fn take_pins(&mut self, client: u64, pins: u32, call: impl FnOnce() -> Result<u32, Error>) -> Result<u32, Error> {
+        let wanted = |pin: usize| pins & 1 << pin != 0;
+        if self.pins.iter().enumerate().any(|(pin, owner)| wanted(pin) && owner.is_some_and(|owner| owner != client)) {
+            errno(libc::EPERM)?
+        }
+        let result = call()?;
+        for (pin, owner) in self.pins.iter_mut().enumerate() {
+            if wanted(pin) { *owner = Some(client) }
+        }
+        Ok(result)
+    }
+}
+
+pub struct Daemon {
+    pio: Rp1PIO,
+    claims: Mutex<Claims>,
+    next_client: AtomicU64,
+}
+
+impl Daemon {
+    pub fn new(pio: Rp1PIO) -> Daemon {
+        let claims = Claims { sms: vec![None; pio.chip().sm_count as usize], programs: Vec::new(),
+                              pins: vec![None; GPIO_COUNT] };
+        Daemon { pio, claims: Mutex::new(claims), next_client: AtomicU64::new(0) }
+    }
+
+    pub fn pio(&self) -> &Rp1PIO {
+        &self.pio
+    }
+
+    // Accepts clients forever, each on its own thread.
+    pub fn serve(&self, listener: &UnixListener) -> Result<(), Error> {
+        self.serve_incoming(listener.incoming(), UnixStream::try_clone)
+    }
+
+    // Like serve(), but for clients on the network.
+    #[cfg(feature = "remote")]
+    pub fn serve_tcp(&self, listener: &TcpListener) -> Result<(), Error> {
+        self.serve_incoming(listener.incoming().map(|stream| {
+            let stream = stream?;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        }), TcpStream::try_clone)
+    }
+
+    fn serve_incoming<S: Read + Write + Send>(&self, incoming: impl Iterator<Item = std::io::Result<S>>,
+                                             try_clone: fn(&S) -> std::io::Result<S>) -> Result<(), Error> {
+        std::thread::scope(|scope| {
+            for stream in incoming {
+                let stream = stream?;
+                scope.spawn(move || {
+                    if let Ok(reader) = try_clone(&stream) {
+                        let _ = self.serve_client(reader, stream);
+                    }
+                });
+            }
+            Ok(())
+        })
+    }
+
+    // Answers one client's requests until it goes away, then cleans up after it.
+    pub fn serve_client(&self, reader: impl Read, mut writer: impl Write) -> Result<(), Error> {
+        let client = self.next_client.fetch_add(1, Ordering::Relaxed);
+        let result = (|| {
+            let chip = self.pio.chip();
+            writeln!(writer, "{GREETING} {} {} {} {} {}", chip.name, chip.compatible, chip.instr_count, chip.sm_count, chip.fifo_depth)?;
+            writer.flush()?;
+            for line in BufReader::new(reader).lines() {
+                let reply = self.request(client, &line?);
+                writeln!(writer, "{reply}")?;
+                writer.flush()?;
+            }
+            Ok(())
+        })();
+        self.disconnect(client);
+        result
+    }
+
+    fn request(&self, client: u64, line: &str) -> String {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match fields[..] {
+            [request, args, buffer] => c_ulong::from_str_radix(request.trim_start_matches("0x"), 16).ok()
+                .and_then(|request| Some((request, unhex(args).ok()?, unhex(buffer).ok()?))),
+            _ => None,
+        };
+        let Some((request, mut args, buffer)) = parsed else { return format!("- - {}", Outcome::Errno(libc::EINVAL)) };
+        let (result, buffer) = self.ioctl(client, request, &mut args, buffer);
+        format!("{} {} {}", hex(&args), hex(&buffer), Outcome::of(&result))
+    }
+
+    fn ioctl(&self, client: u64, request: c_ulong, args: &mut [u8], buffer: Vec<u8>) -> (Result<u32, Error>, Vec<u8>) {
+        if request_name(request) == "UNKNOWN" || args.len() != args_size(request) {
+            return (errno(libc::EINVAL), Vec::new());
+        }
+        // Somewhere suitably aligned for the args struct.
+        let mut aligned = vec![0_u64; args.len().div_ceil(8)];
+        let ptr = aligned.as_mut_ptr() as *mut c_void;
+        unsafe { std::ptr::copy_nonoverlapping(args.as_ptr(), ptr as *mut u8, args.len()) };
+        let first = args.get(..2).map(|b| u16::from_ne_bytes([b[0], b[1]])).unwrap_or(0);
+
+        let mut data = Vec::new();
+        let mut returned = args.len();
+        if let Some((_, len, output)) = unsafe { args_buffer(request, ptr) } {
+            returned = POINTER_OFFSET; // The rest is our own pointer, which is nobody else's business
+            if len > MAX_BUFFER || !output && buffer.len() != len {
+                return (errno(libc::EINVAL), Vec::new());
+            }
+            data = if output { vec![0; len] } else { buffer };
+            let data_ptr = data.as_mut_ptr() as usize;
+            unsafe { std::ptr::copy_nonoverlapping(data_ptr.to_ne_bytes().as_ptr(), (ptr as *mut u8).add(POINTER_OFFSET), size_of::<usize>()) };
+        }
+
+        let result = self.checked_ioctl(client, request, first, ptr);
+        unsafe { std::ptr::copy_nonoverlapping(ptr as *const u8, args.as_mut_ptr(), returned) };
+        let output = unsafe { args_buffer(request, ptr) }.is_some_and(|(_, _, output)| output);
+        (result, if output { data } else { Vec::new() })
+    }
+
+    fn checked_ioctl(&self, client: u64, request: c_ulong, first: u16, args: *mut c_void) -> Result<u32, Error> {
+        let owns = |claims: &Claims, mask: u16| (0..claims.sms.len()).filter(|sm| mask & 1 << sm != 0)
+            .all(|sm| claims.sms[sm] == Some(client));
+        let call = || unsafe { self.pio.backend().ioctl(request, args) };
+        match scope(request) {
+            Scope::Anyone => call(),
+            Scope::Nobody => errno(libc::EPERM),
+            Scope::Sm => {
+                let mut claims = lock(&self.claims);
+                if first as usize >= claims.sms.len() || !owns(&claims, 1 << first) { errno(libc::EPERM)? }
+                match unsafe { sm_pins(request, args) } {
+                    0    => { drop(claims); call() }, // Don't hold everyone up while a get() or put() blocks
+                    pins => claims.take_pins(client, pins, call),
+                }
+            },
+            Scope::Gpio => {
+                let pins = if (first as usize) < GPIO_COUNT { 1 << first } else { 0 };
+                lock(&self.claims).take_pins(client, pins, call)
+            },
+            Scope::ReadHw => {
+                let hw = unsafe { &*(args as *const AccessHwArgs) };
+                let (start, end) = (hw.addr & 0x0fff_ffff, (hw.addr & 0x0fff_ffff).saturating_add(hw.len));
+                let claims = lock(&self.claims);
+                let fifos = |sm: usize| [PROC_PIO_TXF0_OFFSET, PROC_PIO_RXF0_OFFSET].map(|fifo| fifo + sm as u32 * 4);
+                if (0..claims.sms.len()).filter(|&sm| claims.sms[sm] != Some(client)).flat_map(fifos)
+                                        .any(|fifo| fifo < end && start < fifo + 4) {
+                    errno(libc::EPERM)?
+                }
+                drop(claims);
+                call()
+            },
+            Scope::Mask => {
+                if !owns(&lock(&self.claims), first) { errno(libc::EPERM)? }
+                call()
+            },
+            Scope::Claim => {
+                let mut claims = lock(&self.claims);
+                if claims.sms.iter().enumerate().any(|(sm, owner)| first & 1 << sm != 0 && owner.is_some()) { errno(libc::EBUSY)? }
+                let result = call()?;
+                let mask = if first == 0 { 1 << result } else { first }; // A mask of 0 claims any SM and returns it
+                for (sm, owner) in claims.sms.iter_mut().enumerate() {
+                    if mask & 1 << sm != 0 { *owner = Some(client) }
+                }
+                Ok(result)
+            },
+            Scope::Unclaim => {
+                let mut claims = lock(&self.claims);
+                if !owns(&claims, first) { errno(libc::EPERM)? }
+                let result = call()?;
+                for (sm, owner) in claims.sms.iter_mut().enumerate() {
+                    if first & 1 << sm != 0 { *owner = None }
+                }
+                Ok(result)
+            },
+            Scope::AddProgram => {
+                let length = first;
+                let offset = call()?;
+                lock(&self.claims).programs.push((client, offset as u16, length));
+                Ok(offset)
+            },
+            Scope::RemoveProgram => {
+                let origin = unsafe { (*(args as *const RemoveProgramArgs)).origin };
+                let mut claims = lock(&self.claims);
+                let Some(index) = claims.programs.iter().position(|&program| program == (client, origin, first)) else { errno(libc::EPERM)? };
+                let result = call()?;
+                claims.programs.remove(index);
+                Ok(result)
+            },
+        }
+    }
+
+    // Stops and unclaims the client's SMs and removes its programs.
+    fn disconnect(&self, client: u64) {
+        let mut claims = lock(&self.claims);
+        let mask = claims.sms.iter().enumerate().filter(|(_, owner)| **owner == Some(client)).fold(0, |mask, (sm, _)| mask | 1 << sm);
+        if mask != 0 {
+            let _ = self.pio.sm_set_enabled_mask(mask, false);
+            let mut args = SmClaimArgs { mask };
+            let _ = unsafe { self.pio.backend().ioctl(PIO_IOC_SM_UNCLAIM, &mut args as *mut _ as *mut c_void) };
+            claims.sms.iter_mut().filter(|owner| **owner == Some(client)).for_each(|owner| *owner = None);
+        }
+        for &(_, origin, num_instrs) in claims.programs.iter().filter(|&&(owner, ..)| owner == client) {
+            let mut args = RemoveProgramArgs { num_instrs, origin };
+            let _ = unsafe { self.pio.backend().ioctl(PIO_IOC_REMOVE_PROGRAM, &mut args as *mut _ as *mut c_void) };
+        }
+        claims.programs.retain(|&(owner, ..)| owner != client);
+        claims.pins.iter_mut().filter(|owner| **owner == Some(client)).for_each(|owner| *owner = None);
+    }
+}
+
+struct Connection {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
+}
+
+// The client end: a PioBackend that sends every ioctl to a pio-daemon.
+pub struct DaemonClient {
+    devname: PathBuf,
+    chip: Chip,
+    conn: Mutex<Connection>,
+}
+
+impl DaemonClient {
+    pub fn connect(path: impl AsRef<Path>) -> Result<DaemonClient, Error> {
+        let stream = UnixStream::connect(path.as_ref())?;
+        DaemonClient::from_streams(Box::new(stream.try_clone()?), Box::new(stream), path.as_ref().to_path_buf())
+    }
+
+    #[cfg(feature = "remote")]
+    pub fn connect_tcp(addr: impl ToSocketAddrs) -> Result<DaemonClient, Error> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let devname = PathBuf::from(format!("tcp:{}", stream.peer_addr()?));
+        DaemonClient::from_streams(Box::new(stream.try_clone()?), Box::new(stream), devname)
+    }
+
+    pub(crate) fn from_streams(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>, devname: PathBuf) -> Result<DaemonClient, Error> {
+        let mut reader = BufReader::new(reader);
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting)?;
+        let fields: Vec<&str> = greeting.strip_prefix(GREETING).unwrap_or_default().split_whitespace().collect();
+        let [name, compatible, instr_count, sm_count, fifo_depth] = fields[..] else { Err(protocol_error(format!("not a pio-daemon: {greeting:?}")))? };
+        let number = |n: &str| n.parse().map_err(|_| protocol_error(format!("bad greeting {greeting:?}")));
+        let chip = Chip { name: name.to_string(), compatible: compatible.to_string(),
+                          instr_count: number(instr_count)?, sm_count: number(sm_count)?, fifo_depth: number(fifo_depth)? };
+        Ok(DaemonClient { devname, chip, conn: Mutex::new(Connection { reader, writer }) })
+    }
+
+    // What the daemon's PIO block is, to pass to Rp1PIO::with_backend().
+    pub fn chip(&self) -> &Chip {
+        &self.chip
+    }
+}
+
+impl PioBackend for DaemonClient {
+    unsafe fn ioctl(&self, request: c_ulong, args: *mut c_void) -> Result<u32, Error> {
+        unsafe {
+            let bytes = args_bytes(request, args);
+            let buffer = args_buffer(request, args);
+            let input = match buffer {
+                Some((data, len, false)) => std::slice::from_raw_parts(data, len),
+                _                        => &[],
+            };
+            let mut conn = lock(&self.conn);
+            writeln!(conn.writer, "{request:#x} {} {}", hex(bytes), hex(input))?;
+            conn.writer.flush()?;
+            let mut line = String::new();
+            if conn.reader.read_line(&mut line)? == 0 {
+                Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted))?;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [after, output, outcome] = fields[..] else { Err(protocol_error(format!("bad reply {line:?}")))? };
+            let outcome: Outcome = outcome.parse().map_err(protocol_error)?;
+            // Copy back whatever the kernel wrote, leaving our pointer alone.
+            let after = unhex(after).map_err(protocol_error)?;
+            for (i, (byte, &new)) in bytes.iter_mut().zip(&after).enumerate() {
+                if buffer.is_none() || i < POINTER_OFFSET {
+                    *byte = new;
+                }
+            }
+            if let Some((data, len, true)) = buffer {
+                let output = unhex(output).map_err(protocol_error)?;
+                std::ptr::copy_nonoverlapping(output.as_ptr(), data, len.min(output.len()));
+            }
+            outcome.result()
+        }
+    }
+
+    fn devname(&self) -> &Path {
+        &self.devname
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockPio, PioProgram};
+
+    fn client(daemon: &'static Daemon) -> Rp1PIO {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || daemon.serve_client(theirs.try_clone().unwrap(), theirs));
+        let client = DaemonClient::from_streams(Box::new(ours.try_clone().unwrap()), Box::new(ours), PathBuf::from("test")).unwrap();
+        let chip = client.chip().clone();
+        Rp1PIO::with_backend(Box::new(client), chip)
+    }
+
+    #[test]
+    fn arbitration() {
+        let daemon: &'static Daemon = Box::leak(Box::new(Daemon::new(Rp1PIO::with_backend(Box::new(MockPio::new()), Chip::new()))));
+        let (a, b) = (client(daemon), client(daemon));
+        let sm = a.sm_claim(0).unwrap();
+        let offset = a.add_program(&PioProgram::new(&[0xa042], None)).unwrap();
+        sm.put(1234, true).unwrap();
+        let mut fifo = [0];
+        a.read_hw(0, &mut fifo).unwrap();
+        assert!(b.sm_claim(0).is_err());
+        assert!(b.sm(0).put(1, false).is_err());
+        assert!(b.remove_program(&PioProgram::new(&[0xa042], None), Some(offset)).is_err());
+        assert!(b.clear_instruction_memory().is_err());
+
+        // Nobody else gets to pop a's RX FIFO, but everything else is readable.
+        a.read_hw(PROC_PIO_RXF0_OFFSET, &mut fifo).unwrap();
+        assert!(b.read_hw(PROC_PIO_RXF0_OFFSET, &mut fifo).is_err());
+        assert!(b.read_hw(PROC_PIO_CTRL_OFFSET, &mut [0; 16]).is_err()); // Runs into the FIFOs
+        b.read_hw(PROC_PIO_CTRL_OFFSET, &mut fifo).unwrap();
+
+        // Pins belong to whoever drives them first.
+        sm.init(offset, &crate::SmConfig::default().set_out_pins(4, 2).unwrap()).unwrap();
+        a.pio_gpio_init(4).unwrap();
+        assert!(b.gpio_set_oeover(5, 3).is_err());
+        assert!(b.gpio_set_function(4, crate::gpio::Function::SIO).is_err());
+        b.gpio_set_outover(10, 2).unwrap();
+        assert!(sm.set_pins_with_mask(0, 1 << 10).is_err());
+        assert!(a.gpio_init(10).is_err());
+        drop(a);
+        // Once a's gone its SM is free again.
+        let mut tries = 0;
+        while b.sm_claim(0).is_err() {
+            tries += 1;
+            assert!(tries < 1000);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(b.can_add_program_at_offset(&PioProgram::new(&[0xa042], None), Some(offset)).unwrap());
+        b.pio_gpio_init(4).unwrap();
+    }
+
+    #[test]
+    fn claim_unused() {
+        let mock = MockPio::new();
+        let daemon: &'static Daemon = Box::leak(Box::new(Daemon::new(mock.pio())));
+        let (a, b) = (client(daemon), client(daemon));
+        let sm = a.sm_claim_unused().unwrap();
+        sm.put(1, true).unwrap();
+        assert!(b.sm(sm.index()).put(1, false).is_err());
+
+        // Pins only get taken by ioctls that work.
+        mock.fail_next("SM_INIT", libc::EIO);
+        assert!(sm.init(0, &crate::SmConfig::default().set_out_pins(6, 1).unwrap()).is_err());
+        mock.fail_next("GPIO_INIT", libc::EIO);
+        assert!(a.gpio_init(7).is_err());
+        b.gpio_init(6).unwrap();
+        b.gpio_init(7).unwrap();
+
+        drop(a);
+        let mut tries = 0;
+        while mock.is_claimed(0) {
+            tries += 1;
+            assert!(tries < 1000);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn pointers_stay_home() {
+        let daemon = Daemon::new(Rp1PIO::with_backend(Box::new(MockPio::new()), Chip::new()));
+        let args = AccessHwArgs { addr: 0xf000_0000 | PROC_PIO_CTRL_OFFSET, len: 4,
+                                  data: 0x1234_5678 as *const c_void };
+        let args = unsafe { std::slice::from_raw_parts(&args as *const _ as *const u8, size_of::<AccessHwArgs>()) };
+        let reply = daemon.request(0, &format!("{PIO_IOC_READ_HW:#x} {} -", hex(args)));
+        let after = unhex(reply.split_whitespace().next().unwrap()).unwrap();
+        assert_eq!(after, args); // Not the daemon's buffer
+    }
+
+    #[cfg(feature = "remote")]
+    #[test]
+    fn over_tcp() {
+        let daemon: &'static Daemon = Box::leak(Box::new(Daemon::new(Rp1PIO::with_backend(Box::new(MockPio::new()), Chip::new()))));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || daemon.serve_tcp(&listener));
+        let client = DaemonClient::connect_tcp(addr).unwrap();
+        assert_eq!(client.devname(), Path::new(&format!("tcp:{addr}")));
+        let chip = client.chip().clone();
+        let pio = Rp1PIO::with_backend(Box::new(client), chip);
+        let sm = pio.sm_claim(1).unwrap();
+        pio.add_program(&PioProgram::new(&[0xa042], None)).unwrap();
+        sm.put(5678, true).unwrap();
+    }
+}
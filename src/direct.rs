@@ -0,0 +1,210 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Talking to the PIO registers directly instead of going through the kernel (and the RP1 firmware behind it). put(),
+// get(), the FIFO state and register reads become a memory access or two instead of an ioctl round trip. Everything
+// else (programs, claiming, config, GPIOs) still goes through `inner`, so the kernel keeps track of who owns what:
+//
+//     let backend = DirectBackend::open(Box::new(IoctlBackend::open("/dev/pio0")?))?;
+//     let pio = Rp1PIO::with_backend(Box::new(backend), Chip::new());
+//
+// This needs root (for /dev/mem) and bypasses the driver's locking, so two processes using the same SM directly will
+// trample each other. Only built with the `unsafe-direct` feature.
+
+use std::{ffi::c_void, fs::OpenOptions, os::fd::AsRawFd, path::Path, time::{Duration, Instant}};
+
+use libc::c_ulong;
+
+use crate::{proc_pio::*, Chip, Error, PioBackend};
+use crate::ioctl::*;
+
+// Where the RP1's PIO block shows up in the Pi 5's physical address space: RP1's peripherals are at the start of its
+// PCIe BAR1 (0x1f_0000_0000) and the PIO is 0x178000 into them.
+pub const RP1_PIO_PHYS_ADDR: u64 = 0x1f_0017_8000;
+const MAP_SIZE: usize = 0x1000;
+
+pub struct DirectBackend {
+    inner: Box<dyn PioBackend>,
+    regs: *mut u32,
+    timeout: Duration,
+    sm_count: u16,
+}
+
+// The mapping is just memory and all access to it is volatile, one register at a time.
+unsafe impl Send for DirectBackend {}
+unsafe impl Sync for DirectBackend {}
+
+impl DirectBackend {
+    // Maps the registers through /dev/mem.
+    pub fn open(inner: Box<dyn PioBackend>) -> Result<DirectBackend, Error> {
+        DirectBackend::with_mapping(inner, "/dev/mem", RP1_PIO_PHYS_ADDR)
+    }
+
+    // Maps the registers from `offset` in `path`, which could be /dev/mem, the RP1's PCIe resource file
+    // (/sys/bus/pci/devices/.../resource1, with an offset of 0x178000) or anything else with the same layout.
+    pub fn with_mapping(inner: Box<dyn PioBackend>, path: impl AsRef<Path>, offset: u64) -> Result<DirectBackend, Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let regs = unsafe {
+            libc::mmap(std::ptr::null_mut(), MAP_SIZE, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, file.as_raw_fd(), offset as libc::off_t)
+        };
+        if regs == libc::MAP_FAILED {
+            Err(std::io::Error::last_os_error())?;
+        }
+        let mut backend = DirectBackend { inner, regs: regs as *mut u32, timeout: Duration::from_secs(1), sm_count: 0 };
+        // The block says how many SMs it has, the same as Rp1PIO::discover_chip_config() reads it.
+        backend.sm_count = match (backend.read(PROC_PIO_DBG_CFGINFO_OFFSET) & PROC_PIO_DBG_CFGINFO_SM_COUNT_BITS)
+                                 >> PROC_PIO_DBG_CFGINFO_SM_COUNT_LSB {
+            0 => Chip::new().sm_count,
+            n => n as u16,
+        };
+        Ok(backend)
+    }
+
+    // How long blocking put()s and get()s spin before giving up with Error::TimedOut.
+    pub fn with_timeout(mut self, timeout: Duration) -> DirectBackend {
+        self.timeout = timeout;
+        self
+    }
+
+    fn read(&self, offset: u32) -> u32 {
+        unsafe { self.regs.add(offset as usize / 4).read_volatile() }
+    }
+
+    fn write(&self, offset: u32, value: u32) {
+        unsafe { self.regs.add(offset as usize / 4).write_volatile(value) }
+    }
+
+    fn fstat(&self, lsb: u32, sm: u16) -> bool {
+        self.read(PROC_PIO_FSTAT_OFFSET) & 1 << (lsb + sm as u32) != 0
+    }
+
+    fn wait_while(&self, mut busy: impl FnMut() -> bool) -> Result<(), Error> {
+        let start = Instant::now();
+        while busy() {
+            if start.elapsed() > self.timeout {
+                Err(Error::TimedOut)?;
+            }
+            std::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    fn check_sm(&self, sm: u16) -> Result<u16, Error> {
+        if sm < self.sm_count { Ok(sm) } else { Err(std::io::Error::from_raw_os_error(libc::EINVAL))? }
+    }
+}
+
+impl Drop for DirectBackend {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.regs as *mut c_void, MAP_SIZE) };
+    }
+}
+
+impl PioBackend for DirectBackend {
+    unsafe fn ioctl(&self, request: c_ulong, args: *mut c_void) -> Result<u32, Error> {
+        match request {
+            PIO_IOC_SM_PUT => {
+                let args = unsafe { &*(args as *const SmPutArgs) };
+                let sm = self.check_sm(args.sm)?;
+                if args.blocking != 0 {
+                    self.wait_while(|| self.fstat(PROC_PIO_FSTAT_TXFULL_LSB, sm))?;
+                }
+                self.write(PROC_PIO_TXF0_OFFSET + sm as u32 * 4, args.data);
+                Ok(0)
+            },
+            PIO_IOC_SM_GET => {
+                let args = unsafe { &mut *(args as *mut SmGetArgs) };
+                let sm = self.check_sm(args.sm)?;
+                if args.blocking != 0 {
+                    self.wait_while(|| self.fstat(PROC_PIO_FSTAT_RXEMPTY_LSB, sm))?;
+                }
+                args.data = if self.fstat(PROC_PIO_FSTAT_RXEMPTY_LSB, sm) { 0 } else { self.read(PROC_PIO_RXF0_OFFSET + sm as u32 * 4) };
+                Ok(0)
+            },
+            PIO_IOC_SM_FIFO_STATE => {
+                let args = unsafe { &mut *(args as *mut SmFifoStateArgs) };
+                let sm = self.check_sm(args.sm)?;
+                let shift = sm as u32 * 8 + if args.tx != 0 { 0 } else { 4 };
+                let level = (self.read(PROC_PIO_FLEVEL_OFFSET) >> shift & 0xf) + (self.read(PROC_PIO_FLEVEL2_OFFSET) >> shift & 1) * 16;
+                let (empty_lsb, full_lsb) = if args.tx != 0 { (PROC_PIO_FSTAT_TXEMPTY_LSB, PROC_PIO_FSTAT_TXFULL_LSB) }
+                                            else            { (PROC_PIO_FSTAT_RXEMPTY_LSB, PROC_PIO_FSTAT_RXFULL_LSB) };
+                args.level = level as u16;
+                args.empty = self.fstat(empty_lsb, sm) as u8;
+                args.full = self.fstat(full_lsb, sm) as u8;
+                Ok(0)
+            },
+            PIO_IOC_READ_HW => {
+                let args = unsafe { &*(args as *const AccessHwArgs) };
+                let offset = args.addr & 0x0fff_ffff;
+                if offset as usize + args.len as usize > MAP_SIZE || offset % 4 != 0 {
+                    return unsafe { self.inner.ioctl(request, args as *const AccessHwArgs as *mut c_void) };
+                }
+                let data = unsafe { std::slice::from_raw_parts_mut(args.data as *mut u32, args.len as usize / 4) };
+                for (i, word) in data.iter_mut().enumerate() {
+                    *word = self.read(offset + i as u32 * 4);
+                }
+                Ok(0)
+            },
+            _ => unsafe { self.inner.ioctl(request, args) },
+        }
+    }
+
+    fn devname(&self) -> &Path {
+        self.inner.devname()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockPio, Rp1PIO};
+
+    // A plain file stands in for the registers.
+    fn backend(name: &str) -> (DirectBackend, std::path::PathBuf) {
+        backend_with(name, &[0; MAP_SIZE])
+    }
+
+    fn backend_with(name: &str, regs: &[u8]) -> (DirectBackend, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("pio-direct-{}-{name}", std::process::id()));
+        std::fs::write(&path, regs).unwrap();
+        let backend = DirectBackend::with_mapping(Box::new(MockPio::new()), &path, 0).unwrap().with_timeout(Duration::from_millis(10));
+        (backend, path)
+    }
+
+    #[test]
+    fn fifos_through_memory() {
+        let (backend, path) = backend("fifos");
+        backend.write(PROC_PIO_RXF0_OFFSET + 4, 0x5678);
+        backend.write(PROC_PIO_FLEVEL_OFFSET, 3 << 12 | 2 << 8);
+        backend.write(PROC_PIO_FSTAT_OFFSET, 1 << PROC_PIO_FSTAT_RXEMPTY_LSB); // SM0 empty, SM1 not
+        let regs = backend.regs as usize;
+        let pio = Rp1PIO::with_backend(Box::new(backend), Chip::new());
+        let (sm0, sm1) = (pio.sm(0), pio.sm(1));
+        sm1.put(0x1234, true).unwrap();
+        assert_eq!(unsafe { (regs as *const u32).add(PROC_PIO_TXF1_OFFSET as usize / 4).read_volatile() }, 0x1234);
+        assert_eq!(sm1.get(true).unwrap(), 0x5678);
+        assert_eq!(sm1.get_tx_fifo_level().unwrap(), 2);
+        assert_eq!(sm1.get_rx_fifo_level().unwrap(), 3);
+        assert!(matches!(sm0.get(true), Err(Error::TimedOut)));
+        assert!(sm0.is_rx_fifo_empty().unwrap());
+        drop(pio);
+        std::fs::remove_file(path).unwrap();
+    }
+    #[test]
+    fn sm_count_from_cfginfo() {
+        let (backend, path) = backend("default");
+        assert_eq!(backend.sm_count, 4); // Nothing in DBG_CFGINFO
+        drop(backend);
+        std::fs::remove_file(path).unwrap();
+
+        let mut regs = vec![0; MAP_SIZE];
+        let offset = PROC_PIO_DBG_CFGINFO_OFFSET as usize;
+        regs[offset..offset + 4].copy_from_slice(&(2_u32 << PROC_PIO_DBG_CFGINFO_SM_COUNT_LSB).to_ne_bytes());
+        let (backend, path) = backend_with("two-sms", &regs);
+        let pio = Rp1PIO::with_backend(Box::new(backend), Chip::new());
+        pio.sm(1).put(1, false).unwrap();
+        assert_eq!(pio.sm(2).put(1, false).unwrap_err().errno(), Some(libc::EINVAL));
+        drop(pio);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Finding the PIO devices the kernel has made available, and what they are.
+
+use std::path::{Path, PathBuf};
+
+use crate::{Chip, DeviceId, Error, Rp1PIO};
+
+#[derive(Debug, Clone)]
+pub struct PioDevice {
+    pub index: usize,
+    pub devname: PathBuf,
+    pub sysfs: Option<PathBuf>,     // The device's directory under /sys/class
+    pub compatible: Option<String>, // From the devicetree node, eg: "raspberrypi,rp1-pio"
+    pub driver: Option<String>,     // eg: "rp1-pio"
+    pub chip: Chip,
+}
+
+fn sysfs_dir(name: &str) -> Option<PathBuf> {
+    // The rp1-pio driver registers its devices in the "piodev" class, but don't depend on that.
+    let preferred = Path::new("/sys/class/piodev").join(name);
+    if preferred.exists() {
+        return Some(preferred);
+    }
+    std::fs::read_dir("/sys/class").ok()?
+        .filter_map(|class| class.ok())
+        .map(|class| class.path().join(name))
+        .find(|dir| dir.join("dev").exists())
+}
+
+fn compatible(sysfs: &Path) -> Option<String> {
+    // A list of NUL terminated strings, most specific first.
+    let raw = std::fs::read(sysfs.join("device/of_node/compatible")).ok()?;
+    raw.split(|&b| b == 0).find(|s| !s.is_empty()).map(|s| String::from_utf8_lossy(s).into_owned())
+}
+
+fn driver(sysfs: &Path) -> Option<String> {
+    let link = std::fs::read_link(sysfs.join("device/driver")).ok()?;
+    Some(link.file_name()?.to_string_lossy().into_owned())
+}
+
+impl Chip {
+    // The defaults, plus whatever the devicetree has to say about the device.
+    pub(crate) fn for_device(id: DeviceId) -> Chip {
+        let mut chip = Chip::new();
+        if let DeviceId::CharDevice(rdev) = id {
+            let sysfs = PathBuf::from(format!("/sys/dev/char/{}:{}", libc::major(rdev), libc::minor(rdev)));
+            if let Some(compatible) = compatible(&sysfs) {
+                chip.compatible = compatible;
+            }
+        }
+        chip
+    }
+}
+
+impl Rp1PIO {
+    // Every /dev/pio<N> on the system, sorted by index. Doesn't open or reserve anything.
+    pub fn enumerate() -> Result<Vec<PioDevice>, Error> {
+        Rp1PIO::enumerate_in(Path::new("/dev"))
+    }
+
+    fn enumerate_in(dev: &Path) -> Result<Vec<PioDevice>, Error> {
+        let mut devices = vec![];
+        for entry in std::fs::read_dir(dev)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(index) = name.strip_prefix("pio").and_then(|n| n.parse::<usize>().ok()) else { continue };
+            let sysfs = sysfs_dir(&name);
+            let compatible = sysfs.as_deref().and_then(compatible);
+            let mut chip = Chip::new();
+            if let Some(ref compatible) = compatible {
+                chip.compatible = compatible.clone();
+            }
+            devices.push(PioDevice { index, devname: entry.path(), driver: sysfs.as_deref().and_then(driver), sysfs, compatible, chip });
+        }
+        devices.sort_by_key(|d| d.index);
+        Ok(devices)
+    }
+
+    // `dev`/pio<index>, if it's there. If not, BadPIOInstance says what the highest index is, and with no PIO
+    // devices at all it's just not found.
+    pub(crate) fn devname_in(dev: &Path, index: usize) -> Result<PathBuf, Error> {
+        let devname = dev.join(format!("pio{index}"));
+        if devname.exists() {
+            return Ok(devname);
+        }
+        match Rp1PIO::enumerate_in(dev).ok().and_then(|devices| devices.last().map(|device| device.index)) {
+            Some(max) => Err(Error::BadPIOInstance { index, max }),
+            None      => {
+                let message = format!("no PIO devices in {}", dev.display());
+                Err(std::io::Error::new(std::io::ErrorKind::NotFound, message))?
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devname() {
+        let dev = std::env::temp_dir().join(format!("pio-dev-{}", std::process::id()));
+        std::fs::create_dir_all(&dev).unwrap();
+        std::fs::write(dev.join("null"), b"").unwrap();
+        let none = Rp1PIO::devname_in(&dev, 0);
+        let enumerated_none = Rp1PIO::enumerate_in(&dev).unwrap().len();
+        std::fs::write(dev.join("pio0"), b"").unwrap();
+        std::fs::write(dev.join("pio1"), b"").unwrap();
+        let found = Rp1PIO::devname_in(&dev, 1);
+        let past_the_end = Rp1PIO::devname_in(&dev, 2);
+        std::fs::remove_dir_all(&dev).unwrap();
+        assert!(matches!(none, Err(Error::IOError(ref e)) if e.kind() == std::io::ErrorKind::NotFound), "{none:?}");
+        assert_eq!(enumerated_none, 0);
+        assert_eq!(found.unwrap(), dev.join("pio1"));
+        assert_eq!(past_the_end, Err(Error::BadPIOInstance { index: 2, max: 1 }));
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Peripherals built out of a state machine and a PIO program, each taking a claimed StateMachine and giving it back
+// with into_inner(). Where there's an embedded-hal trait for the peripheral, it's implemented behind the
+// `embedded-hal` feature (and the async versions behind `embedded-hal-async` and `embedded-io-async`).
+
+pub mod apa102;
+#[cfg(any(feature = "embedded-hal-async", feature = "embedded-io-async"))]
+pub mod asynch;
+pub mod camera;
+pub mod can;
+pub mod dmx;
+pub mod ds18b20;
+pub mod duty_cycle;
+pub mod edge_capture;
+pub mod frequency_counter;
+pub mod hc165;
+pub mod hc595;
+pub mod hc_sr04;
+pub mod hd44780;
+pub mod hx711;
+pub mod i2c;
+pub mod i2s;
+pub mod ir;
+pub mod jtag;
+pub mod led;
+pub mod manchester;
+pub mod mdio;
+pub mod multi_uart;
+pub mod onewire;
+pub mod ook;
+pub mod parallel_bus;
+pub mod parallel_dac;
+pub mod ppm;
+pub mod pps;
+pub mod pulse_counter;
+pub mod quadrature;
+pub mod rc_pwm;
+pub mod sbus;
+pub mod sdcard;
+pub mod servo;
+pub mod seven_segment;
+pub mod sigma_delta;
+pub mod spi;
+pub mod stepper;
+pub mod tm1637;
+pub mod touch;
+pub mod uart;
+pub mod wiegand;
+pub mod ws2812;
@@ -0,0 +1,180 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// APA102 ("DotStar") and SK9822 strips, which take a clock alongside the data and so don't care about timing the way
+// WS2812s do. This is the apa102_mini program from the pico-examples, a transmit-only SPI at 2 cycles per bit:
+//
+//     let mut strip = Apa102::new(pio.sm_claim_unused()?, 10, 11)?   // data, clock
+//         .with_frequency(8_000_000.0)?
+//         .with_brightness(8)?;
+//     strip.write(&[RGB8::new(255, 0, 0), RGB8::new(0, 0, 255)])?;
+//
+// Each frame goes out as 32 zero bits, a word per LED (0b111, 5 bits of global brightness, then blue, green and red),
+// and an end frame. Every LED delays the data by half a clock as it passes it on, so the end frame is there to supply
+// the extra n/2 clocks that get the data to the far end of the strip.
+
+use super::led::{self, LedStrip, RGB8};
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 2.0;
+
+const PROGRAM: &str = "
+    .program apa102_mini
+    .side_set 1
+        out pins, 1   side 0   ; Stall here when no data (still asserts clock low)
+        nop           side 1
+";
+
+// How many zero words it takes to clock the data through `leds` LEDs: n/2 bits, rounded up.
+fn end_frame_words(leds: usize) -> usize {
+    leds.div_ceil(64)
+}
+
+pub struct Apa102<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    data: u32,
+    clock: u32,
+    clkdiv: ClkDiv,
+    brightness: u8,
+    words: Vec<u32>,
+}
+
+impl<'a> Apa102<'a> {
+    // Starts out at 4 MHz and full brightness.
+    pub fn new(sm: StateMachine<'a>, data: u32, clock: u32) -> Result<Apa102<'a>, Error> {
+        for pin in [data, clock] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, 4_000_000.0 * CYCLES_PER_BIT)?;
+        let mut strip = Apa102 { sm, program: None, data, clock, clkdiv, brightness: 31, words: Vec::new() };
+        strip.setup()?;
+        Ok(strip)
+    }
+
+    pub fn with_frequency(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz * CYCLES_PER_BIT)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    // The strip's own 5 bit global brightness, applied on top of the 8 bit colours.
+    pub fn with_brightness(mut self, brightness: u8) -> Result<Self, Error> {
+        self.set_brightness(brightness)?;
+        Ok(self)
+    }
+
+    pub fn set_brightness(&mut self, brightness: u8) -> Result<(), Error> {
+        if brightness > 31 {
+            Err(Error::ParamErr { param: "brightness", should_be: "0..=31".to_string() })?;
+        }
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    // The clock rate actually achievable with the SM's clock divider.
+    pub fn frequency(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64) / CYCLES_PER_BIT
+    }
+
+    pub fn write(&mut self, pixels: &[RGB8]) -> Result<(), Error> {
+        let header = 0xe0 | self.brightness as u32;
+        self.words.clear();
+        self.words.push(0);
+        self.words.extend(pixels.iter().map(|p| u32::from_be_bytes([header as u8, p.b, p.g, p.r])));
+        self.words.extend(std::iter::repeat_n(0, end_frame_words(pixels.len())));
+        led::queue(&self.sm, &self.words)
+    }
+
+    // Waits for the last frame to be clocked out.
+    pub fn flush(&self) -> Result<(), Error> {
+        while !self.sm.is_tx_fifo_empty()? {
+            std::thread::sleep(std::time::Duration::from_micros(10));
+        }
+        std::thread::sleep(std::time::Duration::from_secs_f64(32.0 / self.frequency()));
+        Ok(())
+    }
+
+    // Stops the SM, leaving the clock low.
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.flush()?;
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        if self.program.is_some() {
+            self.flush()?;
+        }
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.data, 1)?
+            .set_sideset(1, false, false)?
+            .set_sideset_pins(self.clock)?
+            .set_out_shift(false, true, 32)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        let pins = 1 << self.data | 1 << self.clock;
+        self.sm.set_pins_with_mask(0, pins)?;
+        self.sm.set_pindirs_with_mask(pins, pins)?;
+        for pin in [self.data, self.clock] {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+impl LedStrip for Apa102<'_> {
+    fn write(&mut self, pixels: &[RGB8]) -> Result<(), Error> {
+        Apa102::write(self, pixels)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Apa102::flush(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    #[test]
+    fn frames() {
+        assert_eq!(end_frame_words(0), 0);
+        assert_eq!(end_frame_words(64), 1);
+        assert_eq!(end_frame_words(65), 2);
+
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut strip = Apa102::new(pio.sm_claim(0).unwrap(), 10, 11).unwrap().with_brightness(3).unwrap();
+        assert!(strip.set_brightness(32).is_err());
+        strip.write(&[RGB8::new(1, 2, 3), RGB8::new(4, 5, 6)]).unwrap();
+        // Sample the data on each rising clock edge.
+        let mut emu = backend.emulator();
+        let (mut bits, mut clock) = (Vec::new(), false);
+        while bits.len() < 4 * 32 {
+            emu.step();
+            let pins = emu.pins();
+            if !clock && pins & 1 << 11 != 0 {
+                bits.push(pins & 1 << 10 != 0);
+            }
+            clock = pins & 1 << 11 != 0;
+        }
+        let words: Vec<u32> = bits.chunks(32).map(|word| word.iter().fold(0, |w, &bit| w << 1 | bit as u32)).collect();
+        assert_eq!(words, [0, 0xe3030201, 0xe3060504, 0]);
+    }
+}
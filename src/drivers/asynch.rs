@@ -0,0 +1,110 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// What the async trait impls (behind the `embedded-hal-async` and `embedded-io-async` features) are built from. None
+// of it depends on a particular runtime.
+//
+// The kernel driver has no way to tell us when a FIFO level changes, so the drivers poll: when one can't make any
+// progress it yields, waking itself straight away so the executor gets to run everything else before coming back.
+// That keeps other tasks moving but it's still a busy wait, so it's no cheaper on the CPU than the blocking calls.
+//
+// Delay sleeps on a timer thread instead, which works under any executor:
+//
+//     let mut delay = Delay;
+//     delay.delay_ms(10).await;
+
+use std::{future::Future, pin::Pin, task::{Context, Poll}};
+#[cfg(feature = "embedded-hal-async")]
+use std::{sync::{Arc, Mutex}, task::Waker, time::{Duration, Instant}};
+
+#[cfg(feature = "embedded-hal-async")]
+use crate::lock;
+
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+pub(crate) fn yield_now() -> impl Future<Output = ()> {
+    YieldNow(false)
+}
+
+#[cfg(feature = "embedded-hal-async")]
+struct Sleep {
+    deadline: Instant,
+    waker: Option<Arc<Mutex<Waker>>>,
+}
+
+#[cfg(feature = "embedded-hal-async")]
+impl Future for Sleep {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        match &self.waker {
+            Some(waker) => lock(waker).clone_from(cx.waker()),
+            None        => {
+                let waker = Arc::new(Mutex::new(cx.waker().clone()));
+                let (deadline, timer_waker) = (self.deadline, waker.clone());
+                std::thread::spawn(move || {
+                    std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                    lock(&timer_waker).wake_by_ref();
+                });
+                self.waker = Some(waker);
+            },
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(feature = "embedded-hal-async")]
+pub(crate) fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    Sleep { deadline: Instant::now() + duration, waker: None }
+}
+
+#[cfg(feature = "embedded-hal-async")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Delay;
+
+#[cfg(feature = "embedded-hal-async")]
+impl embedded_hal_async::delay::DelayNs for Delay {
+    async fn delay_ns(&mut self, ns: u32) {
+        sleep(Duration::from_nanos(ns as u64)).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{future::Future, pin::pin, task::{Context, Poll, Waker}};
+
+    // Polls `future` to completion, calling `idle` whenever it's pending (to run the emulator, say).
+    pub(crate) fn block_on<T>(future: impl Future<Output = T>, mut idle: impl FnMut()) -> T {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(value) => return value,
+                Poll::Pending      => idle(),
+            }
+        }
+    }
+
+    #[cfg(feature = "embedded-hal-async")]
+    #[test]
+    fn sleeps() {
+        use std::time::{Duration, Instant};
+        let start = Instant::now();
+        block_on(super::sleep(Duration::from_millis(20)), || std::thread::sleep(Duration::from_millis(1)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Frames from a camera with an 8 bit parallel (DVP) output, like the OV7670 and OV2640, with PCLK, VSYNC and HREF:
+//
+//     // D0 (with D1-D7 on 9-15), PCLK, VSYNC, HREF, width, height
+//     let mut camera = Camera::new(pio.sm_claim_unused()?, 8, 16, 17, 18, 320, 240)?;
+//     let frame = camera.capture()?;
+//     let pixels = frame.rgb565();        // Or frame.line(0), or frame.data
+//
+// The sensor needs setting up separately, over its SCCB (I2C) bus, and a clock on its XCLK pin, and it has to be
+// sending frames of the size given here. The SM takes the byte on D0-D7 at each rising PCLK while HREF is high,
+// starting with the first line after VSYNC falls and stopping after `height` lines of `width` pixels, which is what the
+// OV7670 does by default. Each capture() waits for the start of the next frame, so frames always come out whole, and
+// the ones in between are skipped.
+//
+// The bytes stream out of the RX FIFO with sm_xfer_data() straight into the frame, a few lines to a transfer. The SM
+// looks at PCLK at the full PIO clock, so it keeps up with pixel clocks to about 25 MHz, but if the transfers can't
+// keep up the FIFO fills and bytes are lost. The frame comes back with `stalled` set when that happens, and the SM is
+// restarted to get back in step with the camera for the next one.
+
+use crate::{proc_pio::*, Error, LoadedProgram, PioProgram, SmConfig, StateMachine, XferDir};
+
+// A frame of `height` lines of `width` pixels, `bytes_per_pixel` bytes each, in the order the camera sent them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub bytes_per_pixel: usize,
+    pub data: Vec<u8>,
+    pub stalled: bool, // The RX FIFO filled up, so bytes are missing and the rest are in the wrong places
+}
+
+impl Frame {
+    pub fn line(&self, y: usize) -> &[u8] {
+        let len = self.width * self.bytes_per_pixel;
+        &self.data[y * len..(y + 1) * len]
+    }
+
+    // Pairs of bytes as RGB565 pixels, high byte first, which is how the OV7670 sends them.
+    pub fn rgb565(&self) -> Vec<u16> {
+        self.data.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect()
+    }
+}
+
+pub struct Camera<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    data: u32,
+    pclk: u32,
+    vsync: u32,
+    href: u32,
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    dma: bool,
+    words: Vec<u32>,
+}
+
+impl<'a> Camera<'a> {
+    // `data` is D0, with D1-D7 on the pins after it. Pixels are 2 bytes (RGB565 or YUV422) unless
+    // with_bytes_per_pixel() says otherwise.
+    pub fn new(sm: StateMachine<'a>, data: u32, pclk: u32, vsync: u32, href: u32, width: usize, height: usize)
+               -> Result<Camera<'a>, Error> {
+        for pin in [data, data + 7, pclk, vsync, href] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let mut camera = Camera { sm, program: None, data, pclk, vsync, href, width, height, bytes_per_pixel: 2,
+                                  dma: true, words: Vec::new() };
+        camera.check_size()?;
+        camera.setup()?;
+        Ok(camera)
+    }
+
+    // 1 for raw Bayer or just the Y of YUV, say.
+    pub fn with_bytes_per_pixel(mut self, bytes_per_pixel: usize) -> Result<Self, Error> {
+        if !(1..=4).contains(&bytes_per_pixel) {
+            Err(Error::ParamErr { param: "bytes_per_pixel", should_be: "1..=4".to_string() })?;
+        }
+        self.bytes_per_pixel = bytes_per_pixel;
+        self.check_size()?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    // Without DMA the frame is read a word at a time, which only keeps up with the slowest pixel clocks.
+    pub fn with_dma(mut self, dma: bool) -> Self {
+        self.dma = dma;
+        self
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Waits for the next frame to start and reads the whole of it.
+    pub fn capture(&mut self) -> Result<Frame, Error> {
+        let pio = self.sm.pio();
+        let line_bytes = self.width * self.bytes_per_pixel;
+        let rxstall = 1 << (PROC_PIO_FDEBUG_RXSTALL_LSB + self.sm.index() as u32);
+        pio.write_hw(PROC_PIO_FDEBUG_OFFSET, &[rxstall])?;
+        self.sm.put(self.height as u32 - 1, true)?;
+        self.sm.put(line_bytes as u32 - 1, true)?;
+        self.words.clear();
+        self.words.resize(line_bytes * self.height / 4, 0);
+        if self.dma {
+            // Whole lines to a transfer buffer, as many as fit in 64 KiB
+            let bytes = size_of_val(&self.words[..]) as u32;
+            let buffer = (64 * 1024 / line_bytes).clamp(1, self.height) * line_bytes;
+            pio.sm_config_xfer(self.sm.index(), XferDir::FromSm, buffer.min(bytes as usize) as u32, 4)?;
+            pio.sm_xfer_data(self.sm.index(), XferDir::FromSm, bytes, &self.words[0])?;
+        } else {
+            for word in self.words.iter_mut() {
+                *word = self.sm.get(true)?;
+            }
+        }
+        let mut fdebug = [0];
+        pio.read_hw(PROC_PIO_FDEBUG_OFFSET, &mut fdebug)?;
+        let stalled = fdebug[0] & rxstall != 0;
+        if stalled {
+            self.setup()?;
+        }
+        Ok(Frame { width: self.width, height: self.height, bytes_per_pixel: self.bytes_per_pixel,
+                   data: self.words.iter().flat_map(|word| word.to_le_bytes()).collect(), stalled })
+    }
+
+    // The SM pushes whole words, so a frame that isn't a whole number of them would run into the next.
+    fn check_size(&self) -> Result<(), Error> {
+        let bytes = self.width * self.height * self.bytes_per_pixel;
+        if bytes == 0 || !bytes.is_multiple_of(4) {
+            Err(Error::ParamErr { param: "width", should_be: "a frame that's a multiple of 4 bytes".to_string() })?;
+        }
+        Ok(())
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&format!("
+            .program camera
+            .wrap_target
+                pull                        ; Lines, less 1
+                mov y, osr
+                pull                        ; Bytes a line, less 1
+                wait 1 gpio {vsync}
+                wait 0 gpio {vsync}
+            line:
+                mov x, osr
+                wait 1 gpio {href}
+            byte:
+                wait 1 gpio {pclk}
+                in pins, 8
+                wait 0 gpio {pclk}
+                jmp x-- byte
+                wait 0 gpio {href}
+                jmp y-- line
+            .wrap
+        ", vsync = self.vsync, href = self.href, pclk = self.pclk))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.data)?
+            .set_in_shift(true, true, 32)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const D0: u32 = 8;
+    const PCLK: u32 = 16;
+    const VSYNC: u32 = 17;
+    const HREF: u32 = 18;
+    const WIDTH: usize = 4;
+    const HEIGHT: usize = 3;
+    const PCLK_CYCLES: u64 = 40; // 5 MHz
+
+    // Sends frames over and over: VSYNC for a line, a blank line, then each line with HREF high, its bytes counting
+    // up from the frame number times 0x40 plus the line times 0x10. Lines take twice as long as their bytes.
+    #[derive(Debug, Default)]
+    struct Sensor;
+
+    impl Peripheral for Sensor {
+        fn step(&mut self, _pins: u32, now: u64) -> (u32, u32) {
+            let line_clocks = (WIDTH * 2 * 2) as u64;
+            let frame_clocks = line_clocks * (HEIGHT as u64 + 2);
+            let clock = now / PCLK_CYCLES;
+            let (frame, line, byte) = (clock / frame_clocks, clock % frame_clocks / line_clocks, clock % line_clocks);
+            let pclk = now % PCLK_CYCLES >= PCLK_CYCLES / 2;
+            let vsync = line == 0;
+            let href = line >= 2 && byte < line_clocks / 2;
+            let data = (frame * 0x40 + (line.saturating_sub(2)) * 0x10 + byte) as u32 & 0xff;
+            let levels = data << D0 | (pclk as u32) << PCLK | (vsync as u32) << VSYNC | (href as u32) << HREF;
+            (levels, 0xff << D0 | 1 << PCLK | 1 << VSYNC | 1 << HREF)
+        }
+    }
+
+    #[test]
+    fn captures() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(10_000_000);
+        backend.emulator().attach(Arc::new(Mutex::new(Sensor)));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut camera = Camera::new(pio.sm_claim(0).unwrap(), D0, PCLK, VSYNC, HREF, WIDTH, HEIGHT).unwrap()
+            .with_dma(false);
+        backend.emulator().run(PCLK_CYCLES * 20); // Part way into frame 0, which gets skipped
+        let frame = camera.capture().unwrap();
+        assert!(!frame.stalled);
+        assert_eq!(frame.line(0), [0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47]);
+        assert_eq!(frame.line(2), [0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67]);
+        assert_eq!(frame.rgb565()[..2], [0x4041, 0x4243]);
+
+        let frame = camera.capture().unwrap();
+        assert_eq!(frame.line(1)[0], 0x90);
+        assert!(camera.into_inner().is_ok());
+        assert!(Camera::new(pio.sm_claim(1).unwrap(), D0, PCLK, VSYNC, HREF, 3, 3).is_err()); // 18 bytes
+    }
+}
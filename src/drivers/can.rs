@@ -0,0 +1,718 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// CAN 2.0B (classic CAN, with standard and extended IDs) on any two pins, with a transceiver (an SN65HVD230, MCP2551 or
+// the like) between them and the bus, the way can2040 does it on the RP2040:
+//
+//     let mut can = Can::new(pio.sm_claim_unused()?, pio.sm_claim_unused()?, 5, 6, 500_000)?   // RX, TX
+//         .with_ack(pio.sm_claim_unused()?)?;
+//     can.send(&Frame::new(Id::Standard(0x123), &[1, 2, 3])?)?;
+//     loop {
+//         let frame = can.receive()?;
+//         println!("{:?} {:02x?}", frame.id(), frame.data());
+//     }
+//
+// The RX SM samples each bit 3/4 of the way through, hard syncing to the start of frame and resyncing to every
+// recessive to dominant edge after it, and hands the samples over 8 to a word until the bus goes quiet. The CPU does
+// the rest (unstuffing, the fields and the CRC), and frames that fail any of it are dropped and counted in errors().
+//
+// The TX SM waits for 11 recessive bits, then sends the stuffed frame up to the CRC delimiter, checking each recessive
+// bit at the sample point. If the bus is dominant it's lost arbitration, and it stops there and then. send() watches
+// its own frame come back through the RX SM, which says whether anything ACKed it, and goes again after losing. It
+// doesn't go again when nothing ACKed it, giving Error::NoAck instead, and it never sends an error frame.
+//
+// A node is meant to ACK everyone else's good frames too, which with_ack() does with a third SM. It keeps the last 32
+// samples and pulls the bus dominant for the ACK slot when they're what the CPU says they'll be at the CRC delimiter.
+// The CPU can only say once it has everything up to the CRC, and that comes through the RX FIFO up to 7 bits late,
+// which leaves it at least 9 bit times (18 µs at 500 kbit/s). Something has to be waiting in receive() or send() for
+// that to work, and from a busy userspace it won't always at the higher bit rates; frames it's too late for just go
+// without this node's ACK. The ACK SM drives the TX pin as well, so it doesn't go with pin conflict detection.
+//
+// The three programs take 31 of the PIO's 32 instructions (23 without with_ack()).
+
+use std::{collections::VecDeque, fmt};
+
+use crate::{pio_clock_hz, ClkDiv, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 32.0;
+const CRC_BITS: usize = 15;
+// Recessive bits in a row that end a frame or an error, after which a dominant one is a start of frame
+const IDLE_BITS: u32 = 7;
+// 32 recessive samples, which the ACK SM never sees since the RX SM stops sampling after 13
+const DISARMED: u32 = !0;
+
+// Where the RX program's `idle` and the TX program's `lost` are.
+const RX_IDLE: u16 = 1;
+const TX_LOST: u32 = 9;
+
+const MOV_X_NOT_NULL: u16 = 0xa02b; // mov x, ~null
+
+// Samples 24 cycles into each bit and counts recessive ones in Y. Sampling stops after 13 of them in a row (enough for
+// the word with the ACK slot to be pushed, however many of the CRC's last bits were recessive) until the next start of
+// frame. IRQ `flag` is set at each sample for the ACK SM.
+fn rx_program(flag: u16) -> String {
+    format!("
+    .program can_rx
+    recessive:
+        jmp y-- watch
+    idle:
+        wait 0 pin 0        [23]    ; Start of frame: hard sync, then on to the sample point
+    .wrap_target
+    sample:
+        in pins, 1
+        irq set {flag}
+        jmp pin recessive
+        set y, 12           [28]
+    .wrap
+    watch:
+        set x, 12
+    watch_1:
+        jmp pin watch_2
+        jmp sample          [21]    ; Recessive to dominant: resync to it
+    watch_2:
+        jmp x-- watch_1
+        jmp sample
+    ")
+}
+
+// The CPU sends how many bits there are, less one, then the bits, padded at the front with recessive ones to fill
+// the last word.
+const TX_PROGRAM: &str = "
+    .program can_tx
+    .wrap_target
+        out x, 32
+    idle:
+        set y, 21
+    idle_1:
+        jmp pin idle_2
+        jmp idle
+    idle_2:
+        jmp y-- idle_1      [14]    ; 11 recessive bits
+    bit:
+        out y, 1
+        mov pins, y
+        jmp !y dominant     [21]
+        jmp pin recessive           ; Still recessive at the sample point?
+    lost:
+        jmp lost                    ; Until the CPU starts it again
+    dominant:
+        jmp x-- bit         [7]
+    recessive:
+        jmp x-- bit         [6]
+    .wrap
+";
+
+// Takes in the RX SM's samples too and ACKs when the last 32 of them match X, which the CPU sets through the TX FIFO.
+fn ack_program(flag: u16) -> String {
+    format!("
+    .program can_ack
+    .wrap_target
+    start:
+        pull noblock
+        mov x, osr
+        wait 1 irq {flag}
+        in pins, 1
+        mov y, isr
+        jmp x!=y start      [2]
+        set pins, 0         [31]    ; The ACK slot, starting 8 cycles after the CRC delimiter's sample point
+        set pins, 1
+    .wrap
+    ")
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Pio(crate::Error),
+    NoAck, // Nothing on the bus ACKed the frame
+}
+
+impl From<crate::Error> for Error {
+    fn from(error: crate::Error) -> Self {
+        Error::Pio(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Pio(error) => write!(f, "{error}"),
+            Error::NoAck      => write!(f, "Nothing ACKed the frame"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Pio(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Id {
+    Standard(u16), // 11 bits
+    Extended(u32), // 29 bits
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    id: Id,
+    remote: bool,
+    dlc: u8,
+    data: [u8; 8],
+}
+
+impl Frame {
+    pub fn new(id: Id, data: &[u8]) -> Result<Frame, Error> {
+        if data.len() > 8 {
+            Err(crate::Error::ParamErr { param: "data", should_be: "up to 8 bytes".to_string() })?;
+        }
+        let mut frame = Frame { id: check_id(id)?, remote: false, dlc: data.len() as u8, data: [0; 8] };
+        frame.data[..data.len()].copy_from_slice(data);
+        Ok(frame)
+    }
+
+    // A remote frame, asking whoever sends `id` for `dlc` bytes of it.
+    pub fn remote(id: Id, dlc: u8) -> Result<Frame, Error> {
+        if dlc > 8 {
+            Err(crate::Error::ParamErr { param: "dlc", should_be: "up to 8".to_string() })?;
+        }
+        Ok(Frame { id: check_id(id)?, remote: true, dlc, data: [0; 8] })
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.remote
+    }
+
+    // As it was sent, which can be up to 15 (9 and up all mean 8 bytes).
+    pub fn dlc(&self) -> u8 {
+        self.dlc
+    }
+
+    // Always empty for a remote frame.
+    pub fn data(&self) -> &[u8] {
+        let len = if self.remote { 0 } else { (self.dlc as usize).min(8) };
+        &self.data[..len]
+    }
+
+    // From the start of frame to the end of the CRC, before stuffing.
+    fn bits(&self) -> Vec<bool> {
+        let mut bits = vec![false];
+        match self.id {
+            Id::Standard(id) => {
+                push_bits(&mut bits, id as u32, 11);
+                push_bits(&mut bits, (self.remote as u32) << 2, 3); // RTR, IDE, r0
+            },
+            Id::Extended(id) => {
+                push_bits(&mut bits, id >> 18, 11);
+                push_bits(&mut bits, 0b11, 2);                      // SRR, IDE
+                push_bits(&mut bits, id & 0x3ffff, 18);
+                push_bits(&mut bits, (self.remote as u32) << 2, 3); // RTR, r1, r0
+            },
+        }
+        push_bits(&mut bits, self.dlc as u32, 4);
+        for &byte in self.data() {
+            push_bits(&mut bits, byte as u32, 8);
+        }
+        let crc = crc(&bits);
+        push_bits(&mut bits, crc as u32, CRC_BITS as u32);
+        bits
+    }
+
+    // The other way, if the CRC's right. There have to be exactly frame_len() bits.
+    fn parse(bits: &[bool]) -> Option<Frame> {
+        let crc_at = bits.len() - CRC_BITS;
+        if crc(&bits[..crc_at]) as u32 != value(&bits[crc_at..]) {
+            return None;
+        }
+        let (id, header) = match bits[13] {
+            false => (Id::Standard(value(&bits[1..12]) as u16), 19),
+            true  => (Id::Extended(value(&bits[1..12]) << 18 | value(&bits[14..32])), 39),
+        };
+        let mut frame = Frame { id, remote: bits[header - 7], dlc: value(&bits[header - 4..header]) as u8,
+                                data: [0; 8] };
+        for (byte, bits) in frame.data.iter_mut().zip(bits[header..crc_at].chunks(8)) {
+            *byte = value(bits) as u8;
+        }
+        Some(frame)
+    }
+}
+
+fn check_id(id: Id) -> Result<Id, Error> {
+    let (n, max) = match id {
+        Id::Standard(n) => (n as u32, 0x7ff),
+        Id::Extended(n) => (n, 0x1fff_ffff),
+    };
+    if n > max {
+        Err(crate::Error::ParamErr { param: "id", should_be: format!("up to {max:#x}") })?;
+    }
+    Ok(id)
+}
+
+fn push_bits(bits: &mut Vec<bool>, value: u32, len: u32) {
+    bits.extend((0..len).rev().map(|bit| value >> bit & 1 != 0));
+}
+
+fn value(bits: &[bool]) -> u32 {
+    bits.iter().fold(0, |value, &bit| value << 1 | bit as u32)
+}
+
+// CRC-15/CAN, over everything before it.
+fn crc(bits: &[bool]) -> u16 {
+    bits.iter().fold(0, |crc, &bit| {
+        let feedback = bit != (crc >> 14 & 1 != 0);
+        (crc << 1 & 0x7fff) ^ if feedback { 0x4599 } else { 0 }
+    })
+}
+
+// A stuff bit after every 5 the same, carrying on from `run` (the last level and how many of it there were in a row).
+fn stuff(bits: &[bool], mut run: (bool, u32)) -> Vec<bool> {
+    let mut stuffed = vec![];
+    for &bit in bits {
+        if run.1 == 5 {
+            stuffed.push(!run.0);
+            run = (!run.0, 1);
+        }
+        stuffed.push(bit);
+        run = if bit == run.0 { (bit, run.1 + 1) } else { (bit, 1) };
+    }
+    if run.1 == 5 {
+        stuffed.push(!run.0);
+    }
+    stuffed
+}
+
+// How many bits a frame has up to the end of the CRC (before stuffing), once enough of it's in to tell.
+fn frame_len(bits: &[bool]) -> Option<usize> {
+    let header = if *bits.get(13)? { 39 } else { 19 };
+    if bits.len() < header {
+        return None;
+    }
+    let bytes = if bits[header - 7] { 0 } else { (value(&bits[header - 4..header]) as usize).min(8) };
+    Some(header + bytes * 8 + CRC_BITS)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Skip(u32), // Recessive bits so far
+    Idle,
+    Frame,
+    Ack(Frame),
+}
+
+enum Decoded {
+    Data,               // Everything up to the CRC is in
+    Frame(Frame, bool), // Up to the ACK slot, and whether it was ACKed
+    Error,
+}
+
+#[derive(Debug)]
+struct Decoder {
+    state: State,
+    bits: Vec<bool>,  // Unstuffed, from the start of frame
+    run: (bool, u32), // The last level and how many of it in a row, stuff bits and all
+    recent: u32,      // The last 32 samples, the latest at the bottom
+}
+
+impl Decoder {
+    fn sample(&mut self, level: bool) -> Option<Decoded> {
+        self.recent = self.recent << 1 | level as u32;
+        match self.state {
+            State::Skip(n) => self.state = match level {
+                true if n + 1 >= IDLE_BITS => State::Idle,
+                true                       => State::Skip(n + 1),
+                false                      => State::Skip(0),
+            },
+            State::Idle => if !level {
+                (self.state, self.bits, self.run) = (State::Frame, vec![false], (false, 1));
+            },
+            State::Frame if frame_len(&self.bits) == Some(self.bits.len()) && self.run.1 < 5 => { // The CRC delimiter
+                let Some(frame) = level.then(|| Frame::parse(&self.bits)).flatten() else {
+                    self.state = State::Skip(0);
+                    return Some(Decoded::Error);
+                };
+                self.state = State::Ack(frame);
+            },
+            State::Frame if self.run.1 == 5 => {
+                if level == self.run.0 {
+                    self.state = State::Skip(0);
+                    return Some(Decoded::Error);
+                }
+                self.run = (level, 1);
+            },
+            State::Frame => {
+                self.run = if level == self.run.0 { (level, self.run.1 + 1) } else { (level, 1) };
+                self.bits.push(level);
+                if frame_len(&self.bits) == Some(self.bits.len() + CRC_BITS) {
+                    return Some(Decoded::Data);
+                }
+            },
+            State::Ack(frame) => {
+                self.state = State::Skip(0);
+                return Some(Decoded::Frame(frame, !level));
+            },
+        }
+        None
+    }
+}
+
+struct Acker<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    armed: bool,
+}
+
+pub struct Can<'a> {
+    rx: StateMachine<'a>,
+    tx: StateMachine<'a>,
+    rx_program: Option<LoadedProgram<'a>>,
+    tx_program: Option<LoadedProgram<'a>>,
+    acker: Option<Acker<'a>>,
+    rx_pin: u32,
+    tx_pin: u32,
+    clkdiv: ClkDiv,
+    decoder: Decoder,
+    received: VecDeque<Frame>,
+    sending: Option<Vec<bool>>, // Our own frame's bits, so it isn't received or ACKed
+    sent: Option<bool>,         // It's come back, and whether it was ACKed
+    errors: u32,
+}
+
+impl<'a> Can<'a> {
+    // `bitrate` is in bits/s: 10 k to 1 M.
+    pub fn new(rx: StateMachine<'a>, tx: StateMachine<'a>, rx_pin: u32, tx_pin: u32, bitrate: u32)
+               -> Result<Can<'a>, Error> {
+        let pio = rx.pio();
+        for pin in [rx_pin, tx_pin] {
+            pio.check_gpio(pin as u16)?;
+        }
+        if !(10_000..=1_000_000).contains(&bitrate) {
+            Err(crate::Error::ParamErr { param: "bitrate", should_be: "10 k to 1 M".to_string() })?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, bitrate as f64 * CYCLES_PER_BIT)?;
+        let decoder = Decoder { state: State::Idle, bits: vec![], run: (true, 0), recent: 0 };
+        let mut can = Can { rx, tx, rx_program: None, tx_program: None, acker: None, rx_pin, tx_pin, clkdiv, decoder,
+                            received: VecDeque::new(), sending: None, sent: None, errors: 0 };
+        can.setup()?;
+        Ok(can)
+    }
+
+    // ACKs other nodes' frames from `sm`.
+    pub fn with_ack(mut self, sm: StateMachine<'a>) -> Result<Self, Error> {
+        let program = sm.pio().load_program(&PioProgram::assemble(&ack_program(self.rx.index()))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_set_pins(self.tx_pin, 1)?
+            .set_in_pins(self.rx_pin)?
+            .set_in_shift(false, false, 32)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        sm.set_enabled(false)?;
+        sm.init(program.offset(), &config)?;
+        sm.exec(MOV_X_NOT_NULL, false)?; // DISARMED
+        sm.set_enabled(true)?;
+        self.acker = Some(Acker { sm, program: Some(program), armed: false });
+        Ok(self)
+    }
+
+    // The RX and TX SMs, then the ACK one if there was one.
+    pub fn into_inner(mut self) -> Result<(StateMachine<'a>, StateMachine<'a>, Option<StateMachine<'a>>), Error> {
+        self.rx.set_enabled(false)?;
+        self.tx.set_enabled(false)?;
+        (self.rx_program, self.tx_program) = (None, None);
+        let acker = self.acker.take().map(|mut acker| -> Result<_, Error> {
+            acker.sm.set_enabled(false)?;
+            acker.program = None;
+            Ok(acker.sm)
+        }).transpose()?;
+        Ok((self.rx, self.tx, acker))
+    }
+
+    // Frames dropped for a bad CRC, stuffing or form.
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    // Waits until the frame's gone out, trying again for as long as it loses arbitration. Frames received meanwhile are
+    // kept for receive().
+    pub fn send(&mut self, frame: &Frame) -> Result<(), Error> {
+        let bits = frame.bits();
+        let mut stuffed = stuff(&bits, (true, 0));
+        stuffed.push(true); // CRC delimiter
+        let words = stuffed.len().div_ceil(32);
+        let padded: Vec<bool> = std::iter::repeat_n(true, words * 32 - stuffed.len()).chain(stuffed).collect();
+        self.sending = Some(bits);
+        let acked = loop {
+            self.tx.put((words * 32 - 1) as u32, true)?;
+            for word in padded.chunks(32) {
+                self.tx.put(value(word), true)?;
+            }
+            let acked = loop {
+                let word = self.rx.get(true)?;
+                let others = self.word(word)?;
+                if let Some(acked) = self.sent.take() {
+                    break Some(acked);
+                }
+                if others && self.lost()? {
+                    break None;
+                }
+            };
+            match acked {
+                Some(acked) => break acked,
+                None        => self.start_tx()?,
+            }
+        };
+        self.sending = None;
+        if !acked {
+            Err(Error::NoAck)?;
+        }
+        Ok(())
+    }
+
+    // Waits for the next good frame from another node.
+    pub fn receive(&mut self) -> Result<Frame, Error> {
+        loop {
+            if let Some(frame) = self.received.pop_front() {
+                return Ok(frame);
+            }
+            let word = self.rx.get(true)?;
+            self.word(word)?;
+        }
+    }
+
+    // Returns None instead of waiting when nothing's been received.
+    pub fn try_receive(&mut self) -> Result<Option<Frame>, Error> {
+        while self.received.is_empty() && !self.rx.is_rx_fifo_empty()? {
+            let word = self.rx.get(false)?;
+            self.word(word)?;
+        }
+        Ok(self.received.pop_front())
+    }
+
+    // Takes in 8 samples, and says whether anyone else's frame (or an error) ended in them.
+    fn word(&mut self, word: u32) -> Result<bool, Error> {
+        let mut others = false;
+        for bit in (0..8).rev() {
+            match self.decoder.sample(word >> bit & 1 != 0) {
+                Some(Decoded::Data) => self.arm()?,
+                Some(Decoded::Frame(frame, acked)) => {
+                    self.disarm()?;
+                    if self.sending.as_ref() == Some(&self.decoder.bits) {
+                        self.sent = Some(acked);
+                    } else {
+                        self.received.push_back(frame);
+                        others = true;
+                    }
+                },
+                Some(Decoded::Error) => {
+                    self.disarm()?;
+                    self.errors += 1;
+                    others = true;
+                },
+                None => {},
+            }
+        }
+        Ok(others)
+    }
+
+    // Everything up to the CRC is in, so the ACK SM can be told what the last 32 samples will be at the CRC delimiter,
+    // unless it's our own frame.
+    fn arm(&mut self) -> Result<(), Error> {
+        let Some(acker) = &mut self.acker else { return Ok(()) };
+        let bits = &self.decoder.bits;
+        if self.sending.as_ref().is_some_and(|sending| sending.starts_with(bits)) {
+            return Ok(());
+        }
+        let mut crc_bits = vec![];
+        push_bits(&mut crc_bits, crc(bits) as u32, CRC_BITS as u32);
+        let pattern = stuff(&crc_bits, self.decoder.run).into_iter()
+            .chain([true])
+            .fold(self.decoder.recent, |pattern, bit| pattern << 1 | bit as u32);
+        acker.sm.put(pattern, false)?;
+        acker.armed = true;
+        Ok(())
+    }
+
+    fn disarm(&mut self) -> Result<(), Error> {
+        if let Some(acker) = &mut self.acker && acker.armed {
+            acker.sm.put(DISARMED, false)?;
+            acker.armed = false;
+        }
+        Ok(())
+    }
+
+    fn lost(&self) -> Result<bool, Error> {
+        let offset = self.tx_program.as_ref().map(|program| program.offset() as u32);
+        Ok(offset.map(|offset| offset + TX_LOST) == Some(self.tx.read_hw_state_machine()?.pc))
+    }
+
+    // From the top, forgetting whatever it was sending.
+    fn start_tx(&self) -> Result<(), Error> {
+        let Some(program) = &self.tx_program else { return Ok(()) };
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.tx_pin, 1)?
+            .set_jmp_pin(self.rx_pin)?
+            .set_out_shift(false, true, 32)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.tx.set_enabled(false)?;
+        self.tx.init(program.offset(), &config)?;
+        self.tx.set_enabled(true)?;
+        Ok(())
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.rx.pio();
+        self.rx.set_enabled(false)?;
+        self.tx.set_enabled(false)?;
+        (self.rx_program, self.tx_program) = (None, None); // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&rx_program(self.rx.index()))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.rx_pin)?
+            .set_jmp_pin(self.rx_pin)?
+            .set_in_shift(false, true, 8)?
+            .set_fifo_join(PioFifoJoin::Rx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.rx.set_consecutive_pindirs(self.rx_pin, 1, false)?;
+        pio.pio_gpio_init(self.rx_pin as u16)?;
+        self.rx.init(program.offset() + RX_IDLE, &config)?;
+        self.rx_program = Some(program);
+
+        self.tx_program = Some(pio.load_program(&PioProgram::assemble(TX_PROGRAM)?)?);
+        self.tx.set_pins_with_mask(1 << self.tx_pin, 1 << self.tx_pin)?; // Recessive
+        self.tx.set_consecutive_pindirs(self.tx_pin, 1, true)?;
+        pio.pio_gpio_init(self.tx_pin as u16)?;
+        self.start_tx()?;
+        self.rx.set_enabled(true)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const RX: u32 = 5;
+    const TX: u32 = 6;
+    const BITRATE: u32 = 625_000;
+    const BIT: u64 = 320; // Cycles
+
+    // Another node, wired-ANDed with TX onto RX. It ACKs at `ack_bit` after each start of frame that isn't its own,
+    // and sends `frame` (stuffed, up to the CRC delimiter) at `send_at` or along with the next start of frame.
+    #[derive(Debug, Default)]
+    struct Node {
+        bus: bool,
+        last_dominant: u64,
+        dominant: Vec<(u64, u64)>,
+        ack_bit: Option<u64>,
+        frame: Vec<bool>,
+        send_at: Option<u64>,
+        own_ack: Option<u64>, // When to look at its own frame's ACK slot
+        acked: Option<bool>,
+        starts: u32,
+    }
+
+    impl Node {
+        fn send(&mut self, now: u64) {
+            let frame = std::mem::take(&mut self.frame);
+            for (n, &bit) in frame.iter().enumerate() {
+                if !bit {
+                    self.dominant.push((now + n as u64 * BIT, now + (n as u64 + 1) * BIT));
+                }
+            }
+            self.own_ack = Some(now + frame.len() as u64 * BIT + BIT * 3 / 4);
+            self.send_at = None;
+        }
+    }
+
+    impl Peripheral for Node {
+        fn step(&mut self, pins: u32, now: u64) -> (u32, u32) {
+            if self.send_at == Some(now) {
+                self.send(now);
+            }
+            let held = self.dominant.iter().any(|&(start, end)| (start..end).contains(&now));
+            let bus = pins >> TX & 1 != 0 && !held;
+            if self.bus && !bus && now - self.last_dominant > 10 * BIT {
+                self.starts += 1;
+                if !self.frame.is_empty() && self.send_at.is_none() {
+                    self.send(now);
+                } else if let (Some(ack), None) = (self.ack_bit, self.own_ack) {
+                    self.dominant.push((now + ack * BIT, now + (ack + 1) * BIT));
+                }
+            }
+            if self.own_ack == Some(now) {
+                (self.acked, self.own_ack) = (Some(!bus), None);
+            }
+            if !bus {
+                self.last_dominant = now;
+            }
+            self.bus = bus;
+            ((bus as u32) << RX, 1 << RX)
+        }
+    }
+
+    fn stuffed(frame: &Frame) -> Vec<bool> {
+        let mut bits = stuff(&frame.bits(), (true, 0));
+        bits.push(true);
+        bits
+    }
+
+    #[test]
+    fn frames() {
+        let frame = Frame::new(Id::Extended(0x1234567), &[0xff, 0, 0x5a]).unwrap();
+        let bits = frame.bits();
+        assert_eq!(frame_len(&bits), Some(bits.len()));
+        assert_eq!(Frame::parse(&bits), Some(frame));
+        assert!(stuffed(&frame).windows(6).all(|bits| bits.iter().any(|&bit| bit != bits[0])));
+        assert!(Frame::new(Id::Standard(0x800), &[]).is_err());
+        assert!(Frame::new(Id::Standard(1), &[0; 9]).is_err());
+
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(10_000_000);
+        let node = Arc::new(Mutex::new(Node { bus: true, ..Node::default() }));
+        backend.emulator().attach(node.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut can = Can::new(pio.sm_claim(0).unwrap(), pio.sm_claim(1).unwrap(), RX, TX, BITRATE).unwrap()
+            .with_ack(pio.sm_claim(2).unwrap()).unwrap();
+
+        // Sent, and ACKed or not.
+        let ours = Frame::new(Id::Standard(0x123), &[1, 2, 3]).unwrap();
+        node.lock().unwrap().ack_bit = Some(stuffed(&ours).len() as u64);
+        can.send(&ours).unwrap();
+        node.lock().unwrap().ack_bit = None;
+        assert!(matches!(can.send(&ours), Err(Error::NoAck)));
+
+        // Received, and ACKed.
+        let theirs = Frame::new(Id::Extended(0x1abcdef), &[0xff; 8]).unwrap();
+        {
+            let mut node = node.lock().unwrap();
+            node.frame = stuffed(&theirs);
+            node.send_at = Some(backend.emulator().cycle() + 20 * BIT);
+        }
+        assert_eq!(can.receive().unwrap(), theirs);
+        assert_eq!(node.lock().unwrap().acked, Some(true));
+
+        // Loses arbitration to a higher priority frame, then goes again after it.
+        let urgent = Frame::remote(Id::Standard(0x0ff), 2).unwrap();
+        {
+            let mut node = node.lock().unwrap();
+            (node.frame, node.ack_bit, node.starts) = (stuffed(&urgent), Some(stuffed(&ours).len() as u64), 0);
+        }
+        can.send(&ours).unwrap();
+        assert_eq!(node.lock().unwrap().starts, 2);
+        assert_eq!(can.receive().unwrap(), urgent);
+        assert_eq!(can.errors(), 0);
+    }
+}
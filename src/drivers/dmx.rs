@@ -0,0 +1,487 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// DMX512 input, for a Pi that's a fixture or a DMX analyzer. Put an RS-485 transceiver between the pin and the bus:
+//
+//     let mut dmx = dmx::Rx::new(pio.sm_claim_unused()?, 15)?;
+//     loop {
+//         let packet = dmx.receive()?;
+//         if packet.start_code == 0 && packet.timing_ok() {
+//             set_dimmer(dmx.universe()[ADDRESS - 1]);
+//         }
+//     }
+//
+// A packet is a break (the line held low for at least 88µs), a mark after the break of at least 8µs, then a start code
+// and up to 512 slots at 250 kbaud, 8N2. The SM receives the bytes like uart::Rx and, whenever a byte has no stop bit,
+// times how long the line stays low and then high, so every packet comes with its break and mark lengths. A packet
+// ends at the next break, or sooner when its length says so (512 slots, or an RDM message's length byte). Packets with
+// a framing error anywhere in them are dropped and counted. universe() keeps the slots from the last packet with a null
+// start code and good timing.
+//
+// It's all one word per byte through the RX FIFO, which holds 16 of them, so at full speed something has to be
+// receiving at least every 700µs or so.
+//
+// For RDM, give it a second SM with with_responder() and answer the messages meant for you:
+//
+//     let mut dmx = dmx::Rx::new(pio.sm_claim_unused()?, 15)?.with_responder(pio.sm_claim_unused()?, 14, 16)?;  // tx, enable
+//     if let Some(request) = dmx.receive()?.rdm() && request.is_for(MY_UID) {
+//         dmx.respond(&request.response(MY_UID, rdm::RESPONSE_ACK, vec![]))?;
+//     }
+//
+// Responses go out with a break, so DISC_UNIQUE_BRANCH (which is answered without one) isn't covered.
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+const BAUD: f64 = 250_000.0;
+const CYCLES_PER_BIT: f64 = 8.0;
+
+pub const MIN_BREAK_US: u32 = 88;
+pub const MIN_MAB_US: u32 = 8;
+
+const RX_PROGRAM: &str = "
+    .program dmx_rx
+    .wrap_target
+    start:
+        wait 0 pin 0                ; Start bit
+        set x, 7            [10]    ; Then on to the middle of the first data bit
+    bitloop:
+        in pins, 1
+        jmp x-- bitloop     [6]
+        in pins, 1                  ; The first stop bit
+        push
+        jmp pin start
+        mov x, ~null                ; No stop bit, so count how long the line stays low...
+    brk:
+        jmp pin brk_end
+        jmp x-- brk
+    brk_end:
+        mov isr, x
+        push
+        mov x, ~null                ; ...then how long it's high
+    mab:
+        jmp pin mab_high
+        mov isr, x
+        push
+        set x, 7            [7]     ; That was the start code's start bit
+        jmp bitloop
+    mab_high:
+        jmp x-- mab
+    .wrap
+";
+
+// The CPU does the framing and this just sends bits, 4µs each, holding the enable pin high while it does. A word with
+// the bit count comes first, then the bits, padded at the start to whole words with 1s (which leave the line idle).
+const TX_PROGRAM: &str = "
+    .program dmx_tx
+    .side_set 1 opt
+    .wrap_target
+        out x, 32           side 0          ; Bit count - 1, with the bus let go until it comes
+    bitloop:
+        out pins, 1         side 1 [6]
+        jmp x-- bitloop
+    .wrap
+";
+
+// A 176µs break and a 12µs mark, at 4µs a bit.
+const TX_BREAK_BITS: usize = 44;
+const TX_MAB_BITS: usize = 3;
+
+// Cycles the SM spends in a break before it starts counting: the start bit, 8 data bits, the middle of the first stop
+// bit and 4 instructions.
+const BREAK_CYCLES: u64 = 80;
+const MAB_CYCLES: u64 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub start_code: u8,
+    pub slots: Vec<u8>,
+    pub break_us: u32,
+    pub mab_us: u32,
+}
+
+impl Packet {
+    pub fn timing_ok(&self) -> bool {
+        self.break_us >= MIN_BREAK_US && self.mab_us >= MIN_MAB_US
+    }
+
+    // The RDM message in the packet, if it is one and it's intact.
+    pub fn rdm(&self) -> Option<rdm::Message> {
+        (self.start_code == rdm::START_CODE).then(|| rdm::Message::parse(&self.slots))?
+    }
+}
+
+// A packet on its way in, start code first.
+struct Partial {
+    bytes: Vec<u8>,
+    break_us: u32,
+    mab_us: u32,
+}
+
+impl Partial {
+    // Whether there can't be any more to it.
+    fn complete(&self) -> bool {
+        match self.bytes[..] {
+            [rdm::START_CODE, _, length, ..] => self.bytes.len() >= length as usize + 2,
+            _                                => self.bytes.len() > 512,
+        }
+    }
+
+    fn finish(self) -> Option<Packet> {
+        let (&start_code, slots) = self.bytes.split_first()?;
+        Some(Packet { start_code, slots: slots.to_vec(), break_us: self.break_us, mab_us: self.mab_us })
+    }
+}
+
+pub struct Rx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    clkdiv: ClkDiv,
+    responder: Option<Responder<'a>>,
+    universe: [u8; 512],
+    framing_errors: u32,
+    timing: Option<(bool, Vec<u32>)>, // After a byte with no stop bit: whether it was a break, and the counts so far
+    partial: Option<Partial>,
+}
+
+struct Responder<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+}
+
+impl<'a> Rx<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<Rx<'a>, Error> {
+        let pio = sm.pio();
+        pio.check_gpio(pin as u16)?;
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, BAUD * CYCLES_PER_BIT)?;
+        let program = pio.load_program(&PioProgram::assemble(RX_PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(pin)?
+            .set_jmp_pin(pin)?
+            .set_in_shift(true, false, 32)?
+            .set_fifo_join(PioFifoJoin::Rx)?
+            .set_clkdiv_int_frac(clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        sm.set_enabled(false)?;
+        sm.set_consecutive_pindirs(pin, 1, false)?;
+        pio.pio_gpio_init(pin as u16)?;
+        sm.init(program.offset(), &config)?;
+        sm.clear_fifos()?;
+        sm.set_enabled(true)?;
+        Ok(Rx { sm, program: Some(program), clkdiv, responder: None, universe: [0; 512], framing_errors: 0,
+                timing: None, partial: None })
+    }
+
+    // Sends RDM responses from `tx`, driving `enable` (the transceiver's DE, and ~RE if they're tied together) high
+    // while it does. The response is up to 32 bits of idle, then a 176µs break and a 12µs mark, then the message at
+    // 8N2.
+    pub fn with_responder(mut self, sm: StateMachine<'a>, tx: u32, enable: u32) -> Result<Self, Error> {
+        let pio = sm.pio();
+        for pin in [tx, enable] {
+            pio.check_gpio(pin as u16)?;
+        }
+        let program = pio.load_program(&PioProgram::assemble(TX_PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(tx, 1)?
+            .set_sideset(2, true, false)?
+            .set_sideset_pins(enable)?
+            .set_out_shift(true, true, 32)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        sm.set_enabled(false)?;
+        let pins = 1 << tx | 1 << enable;
+        sm.set_pins_with_mask(1 << tx, pins)?;
+        sm.set_pindirs_with_mask(pins, pins)?;
+        for pin in [tx, enable] {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        sm.init(program.offset(), &config)?;
+        sm.set_enabled(true)?;
+        self.responder = Some(Responder { sm, program: Some(program) });
+        Ok(self)
+    }
+
+    // Waits for the next packet.
+    pub fn receive(&mut self) -> Result<Packet, Error> {
+        loop {
+            if let Some(packet) = self.word(self.sm.get(true)?) {
+                return Ok(packet);
+            }
+        }
+    }
+
+    // Returns None instead of waiting when no packet has finished.
+    pub fn try_receive(&mut self) -> Result<Option<Packet>, Error> {
+        while !self.sm.is_rx_fifo_empty()? {
+            if let Some(packet) = self.word(self.sm.get(false)?) {
+                return Ok(Some(packet));
+            }
+        }
+        Ok(None)
+    }
+
+    // The slots from the last packet with a null start code and good timing. Slot 1 is universe()[0].
+    pub fn universe(&self) -> &[u8; 512] {
+        &self.universe
+    }
+
+    // Bytes that came without a stop bit (but weren't breaks), each of which lost a packet.
+    pub fn framing_errors(&self) -> u32 {
+        self.framing_errors
+    }
+
+    pub fn respond(&self, message: &rdm::Message) -> Result<(), Error> {
+        let Some(responder) = &self.responder else {
+            Err(Error::ParamErr { param: "self", should_be: "set up with with_responder()".to_string() })?
+        };
+        let mut bits = vec![false; TX_BREAK_BITS];
+        bits.extend([true; TX_MAB_BITS]);
+        for byte in message.to_bytes() {
+            bits.push(false);
+            bits.extend((0..8).map(|i| byte >> i & 1 != 0));
+            bits.extend([true, true]);
+        }
+        let words = bits.len().div_ceil(32);
+        let mut padded = vec![true; words * 32 - bits.len()];
+        padded.extend(bits);
+        responder.sm.put(words as u32 * 32 - 1, true)?;
+        for word in padded.chunks(32) {
+            responder.sm.put(word.iter().rev().fold(0, |word, &bit| word << 1 | bit as u32), true)?;
+        }
+        Ok(())
+    }
+
+    // Gives back the receiver's SM, and the responder's if there is one.
+    pub fn into_inner(mut self) -> Result<(StateMachine<'a>, Option<StateMachine<'a>>), Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        let responder = self.responder.take().map(|mut responder| -> Result<_, Error> {
+            responder.sm.set_enabled(false)?;
+            responder.program = None;
+            Ok(responder.sm)
+        }).transpose()?;
+        Ok((self.sm, responder))
+    }
+
+    fn us(&self, cycles: u64) -> u32 {
+        (cycles as f64 * 1e6 / self.clkdiv.actual_frequency(pio_clock_hz() as f64)).round() as u32
+    }
+
+    fn word(&mut self, word: u32) -> Option<Packet> {
+        if let Some((is_break, counts)) = &mut self.timing {
+            counts.push(!word);
+            if counts.len() < 2 {
+                return None;
+            }
+            let (is_break, brk, mab) = (*is_break, counts[0], counts[1]);
+            self.timing = None;
+            if is_break {
+                self.partial = Some(Partial { bytes: Vec::new(), break_us: self.us(BREAK_CYCLES + 2 * brk as u64),
+                                              mab_us: self.us(MAB_CYCLES + 2 * mab as u64) });
+            }
+            return None;
+        }
+        let frame = word >> 23; // 8 data bits and a stop bit, shifted in from the top
+        if frame & 0x100 == 0 {
+            // A break ends the packet before it, a framing error loses it. Either way the SM times the line next.
+            self.timing = Some((frame == 0, Vec::new()));
+            let partial = self.partial.take();
+            if frame != 0 {
+                self.framing_errors += 1;
+                return None;
+            }
+            return self.finish(partial?);
+        }
+        let partial = self.partial.as_mut()?; // Nothing to do until the next break
+        partial.bytes.push(frame as u8);
+        if !partial.complete() {
+            return None;
+        }
+        let partial = self.partial.take()?;
+        self.finish(partial)
+    }
+
+    fn finish(&mut self, partial: Partial) -> Option<Packet> {
+        let packet = partial.finish()?;
+        if packet.start_code == 0 && packet.timing_ok() {
+            self.universe[..packet.slots.len()].copy_from_slice(&packet.slots);
+        }
+        Some(packet)
+    }
+}
+
+// Just enough of RDM (ANSI E1.20) to pick out requests and answer them.
+pub mod rdm {
+    pub const START_CODE: u8 = 0xcc;
+    pub const SUB_START_CODE: u8 = 0x01;
+
+    pub const DISCOVERY_COMMAND: u8 = 0x10;
+    pub const GET_COMMAND: u8 = 0x20;
+    pub const SET_COMMAND: u8 = 0x30;
+
+    pub const RESPONSE_ACK: u8 = 0x00;
+    pub const RESPONSE_ACK_TIMER: u8 = 0x01;
+    pub const RESPONSE_NACK_REASON: u8 = 0x02;
+    pub const RESPONSE_ACK_OVERFLOW: u8 = 0x03;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Uid {
+        pub manufacturer: u16,
+        pub device: u32,
+    }
+
+    impl Uid {
+        pub const BROADCAST: Uid = Uid { manufacturer: 0xffff, device: 0xffff_ffff };
+
+        pub const fn new(manufacturer: u16, device: u32) -> Uid {
+            Uid { manufacturer, device }
+        }
+
+        fn from_bytes(bytes: &[u8]) -> Uid {
+            Uid::new(u16::from_be_bytes([bytes[0], bytes[1]]), u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]))
+        }
+
+        fn to_bytes(self) -> impl Iterator<Item = u8> {
+            self.manufacturer.to_be_bytes().into_iter().chain(self.device.to_be_bytes())
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Message {
+        pub destination: Uid,
+        pub source: Uid,
+        pub transaction: u8,
+        pub port_or_response: u8, // The port ID in a request, the response type in a response
+        pub message_count: u8,
+        pub sub_device: u16,
+        pub command_class: u8,
+        pub pid: u16,
+        pub data: Vec<u8>,
+    }
+
+    impl Message {
+        // Parses a packet's slots (everything after the start code). None if it's not an intact RDM message.
+        pub fn parse(slots: &[u8]) -> Option<Message> {
+            let &[SUB_START_CODE, length, ..] = slots else { return None };
+            let length = length as usize;
+            if length < 24 || slots.len() < length + 1 || slots[22] as usize != length - 24 {
+                return None;
+            }
+            let checksum = slots[..length - 1].iter().fold(START_CODE as u16, |sum, &b| sum.wrapping_add(b as u16));
+            if checksum != u16::from_be_bytes([slots[length - 1], slots[length]]) {
+                return None;
+            }
+            Some(Message {
+                destination: Uid::from_bytes(&slots[2..8]),
+                source: Uid::from_bytes(&slots[8..14]),
+                transaction: slots[14],
+                port_or_response: slots[15],
+                message_count: slots[16],
+                sub_device: u16::from_be_bytes([slots[17], slots[18]]),
+                command_class: slots[19],
+                pid: u16::from_be_bytes([slots[20], slots[21]]),
+                data: slots[23..length - 1].to_vec(),
+            })
+        }
+
+        // The whole message, start code to checksum.
+        pub fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes = vec![START_CODE, SUB_START_CODE, 24 + self.data.len() as u8];
+            bytes.extend(self.destination.to_bytes().chain(self.source.to_bytes()));
+            bytes.extend([self.transaction, self.port_or_response, self.message_count]);
+            bytes.extend(self.sub_device.to_be_bytes());
+            bytes.push(self.command_class);
+            bytes.extend(self.pid.to_be_bytes());
+            bytes.push(self.data.len() as u8);
+            bytes.extend(&self.data);
+            let checksum = bytes.iter().fold(0_u16, |sum, &b| sum.wrapping_add(b as u16));
+            bytes.extend(checksum.to_be_bytes());
+            bytes
+        }
+
+        // Addressed to `uid`, or to everyone, or to every device from its manufacturer.
+        pub fn is_for(&self, uid: Uid) -> bool {
+            self.destination == uid || self.destination == Uid::BROADCAST
+                || self.destination == Uid::new(uid.manufacturer, 0xffff_ffff)
+        }
+
+        // The response to this request, from `source`.
+        pub fn response(&self, source: Uid, response_type: u8, data: Vec<u8>) -> Message {
+            Message { destination: self.source, source, transaction: self.transaction, port_or_response: response_type,
+                      message_count: 0, sub_device: self.sub_device, command_class: self.command_class + 1,
+                      pid: self.pid, data }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    const PIN: u32 = 15;
+
+    // Holds the line at `level` for `us` microseconds (200 clocks each).
+    fn hold(backend: &EmulatorBackend, level: bool, us: u64) {
+        let mut emu = backend.emulator();
+        emu.set_input(PIN, level);
+        emu.run(us * 200);
+    }
+
+    // A break, a mark, then the bytes at 4µs a bit, 8N2.
+    fn send(backend: &EmulatorBackend, break_us: u64, mab_us: u64, bytes: &[u8]) {
+        hold(backend, false, break_us);
+        hold(backend, true, mab_us);
+        for &byte in bytes {
+            hold(backend, false, 4);
+            for i in 0..8 {
+                hold(backend, byte >> i & 1 != 0, 4);
+            }
+            hold(backend, true, 8);
+        }
+    }
+
+    #[test]
+    fn packets_and_timing() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut dmx = Rx::new(pio.sm_claim(0).unwrap(), PIN).unwrap();
+        hold(&backend, true, 100);
+        let mut packets = Vec::new();
+        // Too short on both counts the second time. Each packet comes out at the break after it.
+        for (break_us, mab_us, bytes) in [(100, 12, &[0, 1, 2, 3][..]), (60, 4, &[0, 9]), (120, 20, &[0x17, 0xff]), (100, 12, &[0])] {
+            send(&backend, break_us, mab_us, bytes);
+            packets.extend(std::iter::from_fn(|| dmx.try_receive().unwrap()));
+        }
+        let timing: Vec<_> = packets.iter().map(|packet| (packet.start_code, packet.slots.clone(), packet.timing_ok())).collect();
+        assert_eq!(timing, [(0, vec![1, 2, 3], true), (0, vec![9], false), (0x17, vec![0xff], true)]);
+        assert!(packets[0].break_us.abs_diff(100) <= 2 && packets[0].mab_us.abs_diff(12) <= 2, "{packets:?}");
+        assert!(packets[1].break_us.abs_diff(60) <= 2 && packets[1].mab_us.abs_diff(4) <= 2, "{packets:?}");
+        assert_eq!(dmx.universe()[..4], [1, 2, 3, 0]);
+        assert_eq!(dmx.framing_errors(), 0);
+    }
+
+    #[test]
+    fn rdm_round_trip() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        // Listen to our own responses.
+        let dmx = Rx::new(pio.sm_claim(0).unwrap(), PIN).unwrap();
+        let mut dmx = dmx.with_responder(pio.sm_claim(1).unwrap(), PIN, 16).unwrap();
+        let me = rdm::Uid::new(0x7a70, 0x1234);
+        let request = rdm::Message { destination: me, source: rdm::Uid::new(1, 2), transaction: 7, port_or_response: 1,
+                                     message_count: 0, sub_device: 0, command_class: rdm::GET_COMMAND, pid: 0x0060,
+                                     data: vec![] };
+        assert_eq!(rdm::Message::parse(&request.to_bytes()[1..]), Some(request.clone()));
+        assert!(request.is_for(me) && !request.is_for(rdm::Uid::new(0x7a70, 0x1235)));
+        let response = request.response(me, rdm::RESPONSE_ACK, vec![1, 0, 2]);
+        dmx.respond(&response).unwrap();
+        let packet = dmx.receive().unwrap();
+        assert!(packet.timing_ok(), "{packet:?}");
+        assert_eq!(packet.rdm(), Some(response));
+        backend.emulator().run(10_000);
+        assert_eq!(backend.emulator().pins() & 1 << 16, 0);
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// DS18B20 temperature sensors, any number of them on one 1-Wire bus:
+//
+//     let mut sensors = Ds18b20::new(OneWire::new(pio.sm_claim_unused()?, 4)?);
+//     let roms = sensors.sensors()?;
+//     for (rom, celsius) in roms.iter().zip(sensors.read_all(&roms)?) {
+//         println!("{rom}: {celsius:.2}°C");
+//     }
+//
+// read_all() starts every sensor converting at once and waits for them all, so it takes as long as one conversion
+// (750 ms at 12 bits, halving with each bit less) however many there are; read() does just the one. The sensors have to
+// be powered from their VDD pin: parasite powered ones need a strong pull-up during conversions, which OneWire
+// doesn't do, and can't say when they're finished.
+
+use std::time::{Duration, Instant};
+
+use super::onewire::{crc8, Error, OneWire, Rom};
+
+pub const FAMILY: u8 = 0x28;
+
+// Function commands.
+const CONVERT: u8 = 0x44;
+const WRITE_SCRATCHPAD: u8 = 0x4e;
+const READ_SCRATCHPAD: u8 = 0xbe;
+const COPY_SCRATCHPAD: u8 = 0x48;
+const RECALL_EEPROM: u8 = 0xb8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resolution {
+    Bits9,  // 0.5°C
+    Bits10, // 0.25°C
+    Bits11, // 0.125°C
+    Bits12, // 0.0625°C, what they come set to
+}
+
+impl Resolution {
+    pub fn conversion_time(self) -> Duration {
+        Duration::from_micros(93_750 << self as u32)
+    }
+
+    fn from_config(config: u8) -> Resolution {
+        match config >> 5 & 3 {
+            0 => Resolution::Bits9,
+            1 => Resolution::Bits10,
+            2 => Resolution::Bits11,
+            _ => Resolution::Bits12,
+        }
+    }
+}
+
+// The 9 bytes of a sensor's RAM: temperature, the alarm thresholds (or 2 bytes of whatever you like), and the
+// configuration register. The rest is reserved, and then the CRC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scratchpad(pub [u8; 9]);
+
+impl Scratchpad {
+    // Degrees C. The bits below the resolution are undefined, so they're dropped.
+    pub fn temperature(&self) -> f64 {
+        let raw = i16::from_le_bytes([self.0[0], self.0[1]]);
+        let undefined = 3 - self.resolution() as u32;
+        (raw >> undefined << undefined) as f64 / 16.0
+    }
+
+    // Alarm thresholds, in whole degrees C. search_alarms() finds the sensors whose last conversion was above high
+    // or at or below low.
+    pub fn alarms(&self) -> (i8, i8) {
+        (self.0[2] as i8, self.0[3] as i8)
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        Resolution::from_config(self.0[4])
+    }
+}
+
+pub struct Ds18b20<'a> {
+    bus: OneWire<'a>,
+}
+
+impl<'a> Ds18b20<'a> {
+    pub fn new(bus: OneWire<'a>) -> Ds18b20<'a> {
+        Ds18b20 { bus }
+    }
+
+    pub fn into_inner(self) -> OneWire<'a> {
+        self.bus
+    }
+
+    // The DS18B20s on the bus, leaving out anything else that's on it.
+    pub fn sensors(&mut self) -> Result<Vec<Rom>, Error> {
+        Ok(self.bus.search()?.into_iter().filter(|rom| rom.family() == FAMILY).collect())
+    }
+
+    // The sensors whose last reading was out of the range set_alarms() gave them.
+    pub fn alarms(&mut self) -> Result<Vec<Rom>, Error> {
+        Ok(self.bus.search_alarms()?.into_iter().filter(|rom| rom.family() == FAMILY).collect())
+    }
+
+    // Starts a conversion on one sensor, or all of them with None, and waits for it.
+    pub fn convert(&mut self, rom: Option<Rom>) -> Result<(), Error> {
+        self.bus.transaction(rom, &[CONVERT], &mut [])?;
+        // They read as 0 until they're done. 1 s is longer than the slowest takes.
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !self.bus.read_bit()? {
+            if Instant::now() > deadline {
+                Err(crate::Error::TimedOut)?;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    pub fn scratchpad(&mut self, rom: Option<Rom>) -> Result<Scratchpad, Error> {
+        let mut scratchpad = Scratchpad([0; 9]);
+        self.bus.transaction(rom, &[READ_SCRATCHPAD], &mut scratchpad.0)?;
+        // All 1s is a sensor that's gone away mid-read (its CRC would be 0xff otherwise).
+        if crc8(&scratchpad.0) != 0 || scratchpad.0 == [0xff; 9] {
+            Err(Error::Crc)?;
+        }
+        Ok(scratchpad)
+    }
+
+    // The last conversion's result, in degrees C.
+    pub fn temperature(&mut self, rom: Option<Rom>) -> Result<f64, Error> {
+        Ok(self.scratchpad(rom)?.temperature())
+    }
+
+    // Converts and reads one sensor.
+    pub fn read(&mut self, rom: Option<Rom>) -> Result<f64, Error> {
+        self.convert(rom)?;
+        self.temperature(rom)
+    }
+
+    // Converts on all the sensors at once, then reads each of `roms`.
+    pub fn read_all(&mut self, roms: &[Rom]) -> Result<Vec<f64>, Error> {
+        self.convert(None)?;
+        roms.iter().map(|&rom| self.temperature(Some(rom))).collect()
+    }
+
+    pub fn resolution(&mut self, rom: Option<Rom>) -> Result<Resolution, Error> {
+        Ok(self.scratchpad(rom)?.resolution())
+    }
+
+    // Both of these write the alarms and configuration together, so they read back the other first. Settings only
+    // last until power off unless save()d.
+    pub fn set_resolution(&mut self, rom: Option<Rom>, resolution: Resolution) -> Result<(), Error> {
+        let (high, low) = self.scratchpad(rom)?.alarms();
+        self.write_scratchpad(rom, high, low, resolution)
+    }
+
+    pub fn set_alarms(&mut self, rom: Option<Rom>, high: i8, low: i8) -> Result<(), Error> {
+        let resolution = self.scratchpad(rom)?.resolution();
+        self.write_scratchpad(rom, high, low, resolution)
+    }
+
+    // Copies the alarms and resolution to EEPROM, where the sensors get them from at power on.
+    pub fn save(&mut self, rom: Option<Rom>) -> Result<(), Error> {
+        self.bus.transaction(rom, &[COPY_SCRATCHPAD], &mut [])?;
+        std::thread::sleep(Duration::from_millis(10));
+        Ok(())
+    }
+
+    // Puts back what's in EEPROM.
+    pub fn restore(&mut self, rom: Option<Rom>) -> Result<(), Error> {
+        self.bus.transaction(rom, &[RECALL_EEPROM], &mut [])?;
+        std::thread::sleep(Duration::from_millis(1));
+        Ok(())
+    }
+
+    fn write_scratchpad(&mut self, rom: Option<Rom>, high: i8, low: i8, resolution: Resolution) -> Result<(), Error> {
+        let config = (resolution as u8) << 5 | 0x1f;
+        self.bus.transaction(rom, &[WRITE_SCRATCHPAD, high as u8, low as u8, config], &mut [])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::onewire::tests::{bus, rom, Device, PIN};
+
+    fn sensor(serial: u64, raw: i16) -> Device {
+        let [lsb, msb] = raw.to_le_bytes();
+        Device::new(rom(FAMILY, serial), &[lsb, msb, 75, 70, 0x7f, 0xff, 0x0c, 0x10, 0])
+    }
+
+    #[test]
+    fn temperatures() {
+        assert_eq!(Resolution::Bits9.conversion_time(), Duration::from_micros(93_750));
+        assert_eq!(Resolution::Bits12.conversion_time(), Duration::from_millis(750));
+
+        let devices = vec![sensor(1, 0x0191), sensor(2, -0x01a3), Device::new(rom(0x10, 3), &[0; 9])];
+        let (_backend, bus, pio) = bus(devices);
+        let mut sensors = Ds18b20::new(OneWire::new(pio.sm_claim(0).unwrap(), PIN).unwrap());
+        let roms = sensors.sensors().unwrap();
+        assert_eq!(roms.len(), 2);
+        assert!(roms.iter().all(|rom| rom.family() == FAMILY));
+        let mut celsius = sensors.read_all(&roms).unwrap();
+        celsius.sort_by(f64::total_cmp);
+        assert_eq!(celsius, [-26.1875, 25.0625]);
+
+        let rom = rom(FAMILY, 2);
+        sensors.set_resolution(Some(rom), Resolution::Bits10).unwrap();
+        assert_eq!(sensors.resolution(Some(rom)).unwrap(), Resolution::Bits10);
+        assert_eq!(sensors.temperature(Some(rom)).unwrap(), -26.25);
+        sensors.set_alarms(Some(rom), 30, -10).unwrap();
+        let scratchpad = sensors.scratchpad(Some(rom)).unwrap();
+        assert_eq!((scratchpad.alarms(), scratchpad.resolution()), ((30, -10), Resolution::Bits10));
+        let bus = bus.lock().unwrap();
+        assert_eq!(bus.devices[0].scratchpad[2..5], [75, 70, 0x7f]);
+        assert!(bus.devices.iter().all(|d| d.commands == [CONVERT]));
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// The high time, low time and duty cycle of a signal, period after period, for PWM output sensors and fan tachometers:
+//
+//     let mut sensor = DutyCycle::new(pio.sm_claim_unused()?, 5)?;
+//     loop {
+//         if let Some(reading) = sensor.reading()? {
+//             println!("{:.1}% at {} Hz", reading.duty * 100.0, reading.frequency);
+//         }
+//     }
+//
+// The SM times each high and the low after it to the tick (3 cycles of the PIO clock, 15 ns) and pushes them together
+// at the next rising edge, so every period's measured, however late it's read, and it doesn't matter how fast or
+// slow the signal is: from under a minute a period up to a few MHz, where each half has to last a few ticks. The RX
+// FIFO holds 2 periods, and when it's full the ones after are dropped whole, never half of one.
+//
+// A signal that stops doesn't push anything, so reading() gives None once the latest is older than with_timeout() (1 s
+// to start with). Stuck for more than a minute, the SM starts again from the next rising edge.
+
+use std::time::{Duration, Instant};
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioMovStatus, PioProgram, SmConfig, StateMachine};
+
+const TICK_CYCLES: u64 = 3;
+// From the rising edge to the first tick of the high
+const RISE_CYCLES: u64 = 7;
+
+// X counts high ticks and Y low ones, both down from !0. The input's sampled in the middle cycle of every tick, and at
+// each rising edge both counts are pushed (or neither, if they won't both fit).
+const PROGRAM: &str = "
+    .program duty_cycle
+    restart:
+        wait 0 pin 0
+    sync:
+        nop
+        jmp pin synced
+        jmp sync
+    synced:
+        jmp next            [5]
+    rise:
+        mov isr, ~x
+        mov x, status
+        jmp !x drop
+        push noblock
+        mov isr, ~y
+        push noblock
+    next:
+        mov x, ~null
+        mov y, ~null
+    high:
+        jmp x-- high_1
+        jmp restart
+    high_1:
+        jmp pin high        [1]
+    low:
+        jmp y-- low_1
+        jmp restart
+    low_1:
+        jmp pin rise
+        jmp low
+    drop:
+        jmp next            [2]
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub high: f64,      // s
+    pub low: f64,       // s
+    pub period: f64,    // s
+    pub frequency: f64, // Hz
+    pub duty: f64,      // 0 to 1, high over the period
+}
+
+pub struct DutyCycle<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    timeout: Duration,
+    latest: Option<(Reading, Instant)>,
+}
+
+impl<'a> DutyCycle<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<DutyCycle<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut duty = DutyCycle { sm, program: None, pin, timeout: Duration::from_secs(1), latest: None };
+        duty.setup()?;
+        Ok(duty)
+    }
+
+    // How long to go without a period finishing before taking the signal to be gone.
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self, Error> {
+        if timeout.is_zero() {
+            Err(Error::ParamErr { param: "timeout", should_be: "more than 0".to_string() })?;
+        }
+        self.timeout = timeout;
+        Ok(self)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Takes in whatever periods have finished since last time.
+    pub fn update(&mut self) -> Result<(), Error> {
+        while !self.sm.is_rx_fifo_empty()? {
+            let reading = self.get()?;
+            self.latest = Some((reading, Instant::now()));
+        }
+        Ok(())
+    }
+
+    // The latest period, or None if there hasn't been one within the timeout.
+    pub fn reading(&mut self) -> Result<Option<Reading>, Error> {
+        self.update()?;
+        Ok(self.latest.filter(|(_, at)| at.elapsed() <= self.timeout).map(|(reading, _)| reading))
+    }
+
+    // Waits for the next period to finish (however long that takes: there's no timeout).
+    pub fn measure(&mut self) -> Result<Reading, Error> {
+        self.update()?;
+        let reading = self.get()?;
+        self.latest = Some((reading, Instant::now()));
+        Ok(reading)
+    }
+
+    // The SM pushes both words at once, so the second's never far behind.
+    fn get(&mut self) -> Result<Reading, Error> {
+        let (high, low) = (self.sm.get(true)?, self.sm.get(true)?);
+        let hz = pio_clock_hz() as f64;
+        let high = (TICK_CYCLES * high as u64 + RISE_CYCLES) as f64 / hz;
+        let low = (TICK_CYCLES * low as u64) as f64 / hz;
+        let period = high + low;
+        Ok(Reading { high, low, period, frequency: 1.0 / period, duty: high / period })
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.pin)?
+            .set_jmp_pin(self.pin)?
+            .set_mov_status(PioMovStatus::RxLessThan, pio.chip().fifo_depth as u32 - 1)? // Room for both words
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, pio_clock_hz() as f64)?)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pindirs_with_mask(0, 1 << self.pin)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        self.latest = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const PIN: u32 = 5;
+
+    // High then low for the given number of cycles, over and over. All low if the period's 0.
+    #[derive(Debug)]
+    struct Pwm {
+        high: u64,
+        low: u64,
+    }
+
+    impl Peripheral for Pwm {
+        fn step(&mut self, _pins: u32, now: u64) -> (u32, u32) {
+            let high = self.high + self.low > 0 && now % (self.high + self.low) < self.high;
+            ((high as u32) << PIN, 1 << PIN)
+        }
+    }
+
+    #[test]
+    fn periods() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pwm = Arc::new(Mutex::new(Pwm { high: 1300, low: 700 }));
+        backend.emulator().attach(pwm.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut sensor = DutyCycle::new(pio.sm_claim(0).unwrap(), PIN).unwrap()
+            .with_timeout(Duration::from_millis(1)).unwrap();
+        let near = |seconds: f64, cycles: u64| (seconds * 200e6 - cycles as f64).abs() <= TICK_CYCLES as f64;
+
+        let reading = sensor.measure().unwrap();
+        assert!(near(reading.high, 1300) && near(reading.low, 700), "{reading:?}");
+        assert!((reading.duty - 0.65).abs() < 0.002 && (reading.frequency - 100e3).abs() < 200.0, "{reading:?}");
+
+        // Left unread, the FIFO fills up with whole periods (after the one the change landed in).
+        *pwm.lock().unwrap() = Pwm { high: 20, low: 180 };
+        sensor.measure().unwrap();
+        backend.emulator().run(20_000);
+        for _ in 0..2 {
+            let reading = sensor.measure().unwrap();
+            assert!(near(reading.high, 20) && near(reading.low, 180), "{reading:?}");
+        }
+        assert!(sensor.sm.is_rx_fifo_empty().unwrap());
+
+        *pwm.lock().unwrap() = Pwm { high: 0, low: 0 };
+        backend.emulator().run(20_000);
+        sensor.update().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(sensor.reading().unwrap(), None);
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Every edge on a run of up to 8 consecutive pins, with when it happened:
+//
+//     let mut capture = EdgeCapture::new(pio.sm_claim_unused()?, 4, 2)?;   // pins 4 and 5
+//     loop {
+//         for event in capture.events()? {
+//             println!("{} {:?} at {} cycles", event.pin, event.edge, event.time);
+//         }
+//     }
+//
+// The SM samples the pins every 10 cycles of the PIO clock (50 ns) and pushes them with a timestamp whenever any of
+// them change, so the times are good to the sample however late they're read, which kernel GPIO events, stamped when
+// the interrupt gets handled, can't manage. Edges on different pins in the same sample come out in pin order with the
+// same time.
+//
+// The timestamps are the bottom 31 - pins bits of a count of samples, so poll() (which events() does) has to be
+// called at least that often: every 0.4 s with 8 pins, or about a minute with 1. The RX FIFO holds 8 changes and
+// changes that don't fit are lost, so when the pins are busy, poll often.
+
+use std::collections::{vec_deque, VecDeque};
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioMovStatus, PioProgram, SmConfig, StateMachine};
+
+pub(super) const CYCLES_PER_SAMPLE: u64 = 10;
+const MAX_PINS: u32 = 8;
+
+const MOV_ISR_NULL: u16 = 0xa0c3; // mov isr, null
+const IN_PINS: u16 = 0x4000;      // in pins, <bit count>
+const MOV_OSR_ISR: u16 = 0xa0e6;  // mov osr, isr
+const MOV_Y_NULL: u16 = 0xa043;   // mov y, null
+
+// Y counts down once per sample and OSR holds the last sample. Every path through takes a whole number of samples'
+// worth of cycles and counts each of them, so Y keeps time. Each word pushed is a flag (1 if the CPU asked for the
+// time, 0 for a change), the bottom 31 - pins bits of Y, then the pins. multi_uart uses it too.
+pub(super) fn program(pins: u32) -> String {
+    format!("
+    .program edge_capture
+    changed:
+        mov y, isr                  ; The count back
+        mov isr, null
+        in y, {bits}
+        in x, {pins}
+        push noblock
+        mov osr, x
+        jmp y-- changed_2
+    changed_2:
+        jmp y-- top         [4]     ; That was two samples' worth
+        jmp top
+    request:
+        mov x, osr
+        pull noblock                ; Take the request
+        mov osr, x
+        mov isr, ~null
+        in y, {bits}
+        in x, {pins}
+        push block
+        jmp y-- top
+    .wrap_target
+    top:
+        mov x, status               ; All 1s unless the CPU has asked for the time
+        jmp !x request
+        mov isr, null
+        in pins, {pins}
+        mov x, isr
+        mov isr, y                  ; Out of the way while the pins are compared with the last sample
+        mov y, osr
+        jmp x!=y changed
+        mov y, isr
+        jmp y-- top
+    .wrap
+    ", bits = 31 - pins)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub pin: u32,
+    pub edge: Edge,
+    pub time: u64, // PIO clock cycles since it started
+}
+
+pub struct EdgeCapture<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    base: u32,
+    pins: u32,
+    levels: Option<u32>, // As of the last word from the SM, once there's been one
+    now: u64,            // In samples
+    stamp: u32,          // The last timestamp from the SM
+    events: VecDeque<Event>,
+}
+
+impl<'a> EdgeCapture<'a> {
+    pub fn new(sm: StateMachine<'a>, base: u32, pins: u32) -> Result<EdgeCapture<'a>, Error> {
+        if !(1..=MAX_PINS).contains(&pins) {
+            Err(Error::ParamErr { param: "pins", should_be: format!("1..={MAX_PINS}") })?;
+        }
+        for pin in base..base + pins {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let mut capture = EdgeCapture { sm, program: None, base, pins, levels: None, now: 0, stamp: 0,
+                                        events: VecDeque::new() };
+        capture.setup()?;
+        Ok(capture)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // The levels of the pins as of the last poll(), the first pin in bit 0.
+    pub fn levels(&self) -> u32 {
+        self.levels.unwrap_or(0)
+    }
+
+    // Takes the changes the SM has seen, then asks it the time, which keeps the timestamps straight through quiet
+    // spells.
+    pub fn poll(&mut self) -> Result<(), Error> {
+        self.sm.put(0, true)?;
+        loop {
+            let word = self.sm.get(true)?;
+            self.received(word);
+            if word & 1 << 31 != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    // Polls, then hands over every edge since last time, oldest first.
+    pub fn events(&mut self) -> Result<vec_deque::Drain<'_, Event>, Error> {
+        self.poll()?;
+        Ok(self.events.drain(..))
+    }
+
+    fn received(&mut self, word: u32) {
+        let mask = (1 << (31 - self.pins)) - 1;
+        let stamp = !(word >> self.pins) & mask; // Y counts down
+        self.now += (stamp.wrapping_sub(self.stamp) & mask) as u64;
+        self.stamp = stamp;
+        let levels = word & ((1 << self.pins) - 1);
+        let Some(last) = self.levels.replace(levels) else {
+            return; // Where they started
+        };
+        for n in (0..self.pins).filter(|n| (levels ^ last) >> n & 1 != 0) {
+            let edge = if levels >> n & 1 != 0 { Edge::Rising } else { Edge::Falling };
+            self.events.push_back(Event { pin: self.base + n, edge, time: self.now * CYCLES_PER_SAMPLE });
+        }
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&program(self.pins))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.base)?
+            .set_in_shift(false, false, 32)?
+            .set_mov_status(PioMovStatus::TxLessThan, 1)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, pio_clock_hz() as f64)?)?
+            .set_wrap(wrap_target, wrap)?;
+        let pins = ((1 << self.pins) - 1) << self.base;
+        self.sm.set_pindirs_with_mask(0, pins)?;
+        for pin in self.base..self.base + self.pins {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.init(wrap_target as u16, &config)?; // At top
+        self.sm.clear_fifos()?;
+        // The pins as they are now for the last sample, so the first word, whatever it is, says where they started.
+        for instr in [MOV_ISR_NULL, IN_PINS | self.pins as u16, MOV_OSR_ISR, MOV_Y_NULL] {
+            self.sm.exec(instr, false)?;
+        }
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        // Y's count of 0 reads as -1 samples.
+        (self.levels, self.now, self.stamp) = (None, 0, (1 << (31 - self.pins)) - 1);
+        self.events.clear();
+        self.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    // Pin 4 high from 2000 to 2500 cycles, pin 5 high until 3000 and from 4000 on, and pin 6 high from 3000 to 3100.
+    #[derive(Debug)]
+    struct Signals;
+
+    impl Peripheral for Signals {
+        fn step(&mut self, _pins: u32, now: u64) -> (u32, u32) {
+            let high = [(2000..2500).contains(&now), !(3000..4000).contains(&now), (3000..3100).contains(&now)];
+            (high.iter().enumerate().map(|(n, &high)| (high as u32) << (4 + n)).sum(), 0b111 << 4)
+        }
+    }
+
+    #[test]
+    fn edges() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        backend.emulator().attach(Arc::new(Mutex::new(Signals)));
+        backend.emulator().set_inputs(0b010 << 4, 0b111 << 4); // Signals doesn't get a say until the first cycle
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut capture = EdgeCapture::new(pio.sm_claim(0).unwrap(), 4, 3).unwrap();
+        let start = backend.emulator().cycle();
+        assert!(start < 2000);
+        assert_eq!(capture.levels(), 0b010);
+
+        backend.emulator().run(5000 - start);
+        let events: Vec<Event> = capture.events().unwrap().collect();
+        assert_eq!(events.iter().map(|event| (event.pin, event.edge)).collect::<Vec<_>>(),
+                   [(4, Edge::Rising), (4, Edge::Falling), (5, Edge::Falling), (6, Edge::Rising), (6, Edge::Falling),
+                    (5, Edge::Rising)]);
+        assert_eq!(events[2].time, events[3].time);
+        for (event, at) in events.iter().zip([2000, 2500, 3000, 3000, 3100, 4000]) {
+            let late = event.time as i64 - (at - start as i64);
+            assert!((0..=2 * CYCLES_PER_SAMPLE as i64).contains(&late), "{event:?} {late}");
+        }
+        assert_eq!(capture.events().unwrap().count(), 0);
+        assert!(EdgeCapture::new(capture.into_inner().unwrap(), 4, 9).is_err());
+    }
+}
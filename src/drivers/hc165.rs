@@ -0,0 +1,232 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Inputs on a chain of 74HC165 shift registers, read on three pins (tie CLK INH low). Input n is bit n % 8 (A is bit
+// 0) of chip n / 8, counting from the chip wired to the Pi:
+//
+//     let mut inputs = Hc165::new(pio.sm_claim_unused()?, 10, 11, 12, 2)?   // data, clock, load, chips
+//         .with_debounce(3)?;
+//     loop {
+//         for change in inputs.poll()? {
+//             println!("input {} is now {}", change.input, change.level);
+//         }
+//     }
+//
+// The SM scans the chain over and over, a whole number of words each time (bits past the end of the chain are read
+// and thrown away), for as long as there's room in the RX FIFO. poll() works through whatever scans have piled up, so
+// the changes come out in the order they happened, though anything that came and went while the FIFO was full is
+// missed. An input only changes once it's read the same for `debounce` scans in a row.
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 2.0;
+
+const PROGRAM: &str = "
+    .program hc165
+    .side_set 1
+        pull block        side 0
+        out y, 32         side 0   ; Bit count - 1
+    .wrap_target
+        set pins, 0       side 0 [1]   ; Load the inputs
+        set pins, 1       side 0
+        mov x, y          side 0
+    bitloop:
+        in pins, 1        side 0
+        jmp x-- bitloop   side 1
+    .wrap
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub input: usize,
+    pub level: bool,
+}
+
+pub struct Hc165<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    data: u32,
+    clock: u32,
+    load: u32,
+    clkdiv: ClkDiv,
+    debounce: u8,
+    state: Vec<u8>,
+    counts: Vec<u8>, // Scans in a row each input has disagreed with `state`
+    scan: Vec<u32>,  // The scan in progress
+    primed: bool,
+}
+
+impl<'a> Hc165<'a> {
+    // Starts out at a 4 MHz clock with no debouncing.
+    pub fn new(sm: StateMachine<'a>, data: u32, clock: u32, load: u32, chips: usize) -> Result<Hc165<'a>, Error> {
+        for pin in [data, clock, load] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        if chips == 0 {
+            Err(Error::ParamErr { param: "chips", should_be: "at least 1".to_string() })?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, 4_000_000.0 * CYCLES_PER_BIT)?;
+        let mut hc165 = Hc165 { sm, program: None, data, clock, load, clkdiv, debounce: 1, state: vec![0; chips],
+                                counts: vec![0; chips * 8], scan: Vec::new(), primed: false };
+        hc165.setup()?;
+        Ok(hc165)
+    }
+
+    pub fn with_frequency(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz * CYCLES_PER_BIT)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    // How many scans in a row an input has to read the same before it changes. 1 is no debouncing.
+    pub fn with_debounce(mut self, scans: u8) -> Result<Self, Error> {
+        if scans == 0 {
+            Err(Error::ParamErr { param: "scans", should_be: "at least 1".to_string() })?;
+        }
+        self.debounce = scans;
+        Ok(self)
+    }
+
+    // The number of inputs (8 per chip).
+    pub fn len(&self) -> usize {
+        self.state.len() * 8
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.is_empty()
+    }
+
+    // Works through the scans the SM has made since last time, returning the inputs that changed. The first scan
+    // just sets the starting state.
+    pub fn poll(&mut self) -> Result<Vec<Change>, Error> {
+        let mut changes = Vec::new();
+        let words = self.state.len().div_ceil(4);
+        while !self.sm.is_rx_fifo_empty()? {
+            self.scan.push(self.sm.get(false)?);
+            if self.scan.len() == words {
+                self.debounce_scan(&mut changes);
+                self.scan.clear();
+            }
+        }
+        Ok(changes)
+    }
+
+    // The debounced level of an input, as of the last poll().
+    pub fn input(&self, n: usize) -> Result<bool, Error> {
+        if n >= self.len() {
+            Err(Error::ParamErr { param: "n", should_be: format!("< {}", self.len()) })?;
+        }
+        Ok(self.state[n / 8] & 1 << (n % 8) != 0)
+    }
+
+    // All the debounced inputs, a byte per chip.
+    pub fn inputs(&self) -> &[u8] {
+        &self.state
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    fn debounce_scan(&mut self, changes: &mut Vec<Change>) {
+        let bytes: Vec<u8> = self.scan.iter().flat_map(|word| word.to_be_bytes()).collect();
+        if !self.primed {
+            let chips = self.state.len();
+            self.state.copy_from_slice(&bytes[..chips]);
+            self.primed = true;
+            return;
+        }
+        for (input, count) in self.counts.iter_mut().enumerate() {
+            let (chip, bit) = (input / 8, 1 << (input % 8));
+            let level = bytes[chip] & bit != 0;
+            if level == (self.state[chip] & bit != 0) {
+                *count = 0;
+                continue;
+            }
+            *count += 1;
+            if *count >= self.debounce {
+                self.state[chip] ^= bit;
+                *count = 0;
+                changes.push(Change { input, level });
+            }
+        }
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.data)?
+            .set_set_pins(self.load, 1)?
+            .set_sideset(1, false, false)?
+            .set_sideset_pins(self.clock)?
+            .set_in_shift(false, true, 32)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        let outputs = 1 << self.clock | 1 << self.load;
+        self.sm.set_pins_with_mask(1 << self.load, outputs)?;
+        self.sm.set_pindirs_with_mask(outputs, outputs | 1 << self.data)?;
+        for pin in [self.data, self.clock, self.load] {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.put(self.state.len().div_ceil(4) as u32 * 32 - 1, true)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        self.scan.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    // Runs the emulator with a model of a 165 chain on its pins whose parallel inputs are `inputs`, until the RX FIFO
+    // is full. `shift` is the chain's shift registers.
+    fn scan(backend: &EmulatorBackend, shift: &mut [u8], inputs: &[u8]) {
+        let mut emu = backend.emulator();
+        let mut last = emu.pins();
+        while !emu.is_rx_full(0) {
+            emu.step();
+            let pins = emu.pins();
+            if pins & 1 << 12 == 0 {
+                shift.copy_from_slice(inputs);
+            } else if pins & !last & 1 << 11 != 0 {
+                for i in 0..shift.len() {
+                    shift[i] = shift[i] << 1 | shift.get(i + 1).map_or(0, |next| next >> 7);
+                }
+            }
+            emu.set_input(10, shift[0] & 0x80 != 0);
+            last = pins;
+        }
+    }
+
+    #[test]
+    fn debounced_changes() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut inputs = Hc165::new(pio.sm_claim(0).unwrap(), 10, 11, 12, 5).unwrap().with_debounce(5).unwrap();
+        let mut shift = [0; 5];
+        // 2 words a scan, so the FIFO holds 4 scans.
+        scan(&backend, &mut shift, &[0x81, 0, 0, 0, 0x40]);
+        assert_eq!(inputs.poll().unwrap(), []); // The first scan sets the initial state
+        assert_eq!(inputs.inputs(), [0x81, 0, 0, 0, 0x40]);
+        assert!(inputs.input(7).unwrap());
+        assert!(inputs.input(38).unwrap());
+        // 4 scans isn't enough to get past the debouncing, but 5 is.
+        scan(&backend, &mut shift, &[0x80, 0, 0x04, 0, 0x40]);
+        assert_eq!(inputs.poll().unwrap(), []);
+        scan(&backend, &mut shift, &[0x80, 0, 0x04, 0, 0x40]);
+        assert_eq!(inputs.poll().unwrap(), [Change { input: 0, level: false }, Change { input: 18, level: true }]);
+        assert_eq!(inputs.inputs(), [0x80, 0, 0x04, 0, 0x40]);
+        assert_eq!(inputs.poll().unwrap(), []);
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Outputs on a chain of 74HC595 shift registers, driven from three pins. Output n is bit n % 8 (QA is bit 0) of chip
+// n / 8, counting from the chip wired to the Pi:
+//
+//     let mut outputs = Hc595::new(pio.sm_claim_unused()?, 10, 11, 12, 2)?;   // data, clock, latch, chips
+//     outputs.set_output(9, true)?;   // Second chip's QB
+//
+// By default every change goes straight out to the chain. With Refresh::Manual changes collect until update(), so
+// a batch of them lands on the outputs at the same moment:
+//
+//     let mut outputs = outputs.with_refresh(Refresh::Manual)?;
+//     outputs.set_output(0, true)?;
+//     outputs.set_output(15, true)?;
+//     outputs.update()?;
+//
+// Each update sends a word with the bit count and then the bits, padded at the start to whole words (the padding just
+// falls off the far end of the chain), and the program pulses the latch once they're all in.
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 2.0;
+
+const PROGRAM: &str = "
+    .program hc595
+    .side_set 1
+        out x, 32         side 0   ; Bit count - 1, autopulled like the data (a pull here could drop one autopull already fetched)
+    bitloop:
+        out pins, 1       side 0
+        jmp x-- bitloop   side 1
+        set pins, 1       side 0 [1]
+        set pins, 0       side 0
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refresh {
+    Immediate, // Every change is sent as it's made.
+    Manual,    // Nothing is sent until update().
+}
+
+pub struct Hc595<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    data: u32,
+    clock: u32,
+    latch: u32,
+    clkdiv: ClkDiv,
+    refresh: Refresh,
+    state: Vec<u8>,
+}
+
+impl<'a> Hc595<'a> {
+    // Starts out at a 4 MHz shift clock with every output low.
+    pub fn new(sm: StateMachine<'a>, data: u32, clock: u32, latch: u32, chips: usize) -> Result<Hc595<'a>, Error> {
+        for pin in [data, clock, latch] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        if chips == 0 {
+            Err(Error::ParamErr { param: "chips", should_be: "at least 1".to_string() })?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, 4_000_000.0 * CYCLES_PER_BIT)?;
+        let mut hc595 = Hc595 { sm, program: None, data, clock, latch, clkdiv, refresh: Refresh::Immediate, state: vec![0; chips] };
+        hc595.setup()?;
+        hc595.update()?;
+        Ok(hc595)
+    }
+
+    pub fn with_frequency(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz * CYCLES_PER_BIT)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn with_refresh(mut self, refresh: Refresh) -> Result<Self, Error> {
+        self.refresh = refresh;
+        Ok(self)
+    }
+
+    pub fn refresh(&self) -> Refresh {
+        self.refresh
+    }
+
+    // The number of outputs (8 per chip).
+    pub fn len(&self) -> usize {
+        self.state.len() * 8
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.is_empty()
+    }
+
+    pub fn set_output(&mut self, n: usize, level: bool) -> Result<(), Error> {
+        self.check_output(n)?;
+        let bit = 1 << (n % 8);
+        self.state[n / 8] = if level { self.state[n / 8] | bit } else { self.state[n / 8] & !bit };
+        self.changed()
+    }
+
+    // What the output was last set to (which, with Refresh::Manual, it may not be showing yet).
+    pub fn output(&self, n: usize) -> Result<bool, Error> {
+        self.check_output(n)?;
+        Ok(self.state[n / 8] & 1 << (n % 8) != 0)
+    }
+
+    // Sets all the outputs, a byte per chip.
+    pub fn set_outputs(&mut self, chips: &[u8]) -> Result<(), Error> {
+        if chips.len() != self.state.len() {
+            Err(Error::ParamErr { param: "chips", should_be: format!("{} bytes", self.state.len()) })?;
+        }
+        self.state.copy_from_slice(chips);
+        self.changed()
+    }
+
+    pub fn outputs(&self) -> &[u8] {
+        &self.state
+    }
+
+    // Shifts the current state out to the chain and latches it.
+    pub fn update(&mut self) -> Result<(), Error> {
+        let words = self.state.len().div_ceil(4);
+        let mut bytes = vec![0; words * 4 - self.state.len()];
+        bytes.extend(self.state.iter().rev());
+        self.sm.put(words as u32 * 32 - 1, true)?;
+        for word in bytes.chunks(4) {
+            self.sm.put(u32::from_be_bytes(word.try_into().expect("whole words")), true)?;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    fn check_output(&self, n: usize) -> Result<(), Error> {
+        if n >= self.len() {
+            Err(Error::ParamErr { param: "n", should_be: format!("< {}", self.len()) })?;
+        }
+        Ok(())
+    }
+
+    fn changed(&mut self) -> Result<(), Error> {
+        match self.refresh {
+            Refresh::Immediate => self.update(),
+            Refresh::Manual    => Ok(()),
+        }
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.data, 1)?
+            .set_set_pins(self.latch, 1)?
+            .set_sideset(1, false, false)?
+            .set_sideset_pins(self.clock)?
+            .set_out_shift(false, true, 32)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        let pins = 1 << self.data | 1 << self.clock | 1 << self.latch;
+        self.sm.set_pins_with_mask(0, pins)?;
+        self.sm.set_pindirs_with_mask(pins, pins)?;
+        for pin in [self.data, self.clock, self.latch] {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    // Plays the emulator's pins into a model of a 595 chain until `latches` latch pulses have gone by.
+    fn chain(backend: &EmulatorBackend, chips: usize, latches: usize) -> Vec<u8> {
+        let mut emu = backend.emulator();
+        let (mut shift, mut outputs) = (vec![0_u8; chips], vec![0_u8; chips]);
+        let (mut last, mut seen) = (emu.pins(), 0);
+        while seen < latches {
+            emu.step();
+            let pins = emu.pins();
+            if pins & !last & 1 << 11 != 0 {
+                let mut carry = pins & 1 << 10 != 0;
+                for chip in shift.iter_mut() {
+                    let out = *chip & 0x80 != 0;
+                    *chip = *chip << 1 | carry as u8;
+                    carry = out;
+                }
+            }
+            if pins & !last & 1 << 12 != 0 {
+                outputs.copy_from_slice(&shift);
+                seen += 1;
+            }
+            last = pins;
+        }
+        outputs
+    }
+
+    #[test]
+    fn virtual_outputs() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut outputs = Hc595::new(pio.sm_claim(0).unwrap(), 10, 11, 12, 5).unwrap().with_refresh(Refresh::Manual).unwrap();
+        assert_eq!(chain(&backend, 5, 1), [0; 5]);
+        outputs.set_output(0, true).unwrap();
+        outputs.set_output(9, true).unwrap();
+        outputs.set_output(39, true).unwrap();
+        assert!(outputs.set_output(40, true).is_err());
+        assert!(outputs.output(9).unwrap());
+        outputs.update().unwrap();
+        assert_eq!(chain(&backend, 5, 1), [0x01, 0x02, 0, 0, 0x80]);
+
+        let mut outputs = outputs.with_refresh(Refresh::Immediate).unwrap();
+        outputs.set_outputs(&[1, 2, 3, 4, 5]).unwrap();
+        outputs.set_output(0, false).unwrap();
+        assert_eq!(chain(&backend, 5, 2), [0, 2, 3, 4, 5]);
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// HC-SR04 ultrasonic rangefinders, any number of them taking turns on one SM. Each has a trigger pin and an echo pin
+// (which is 5 V, so put it through a divider):
+//
+//     let mut sonar = HcSr04::new(pio.sm_claim_unused()?, &[(5, 6), (7, 8)])?   // (trigger, echo) for each
+//         .with_temperature(25.0)?;
+//     loop {
+//         for reading in sonar.measure_all()? {
+//             println!("{}: {:?}", reading.sensor, reading.distance);   // Some(metres), or None if nothing's in range
+//         }
+//     }
+//
+// The SM sends the 10 µs trigger pulse and times the echo pulse itself, to 0.2 µs (0.03 mm), rather than it being
+// however long it took a process to notice two GPIO edges. Sensors are pinged one at a time, waiting at least 60 ms
+// from one ping to the next (with_interval() changes that) so one's echo isn't heard by the next.
+
+use std::time::{Duration, Instant};
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioProgram, SmConfig, StateMachine};
+
+const SM_HZ: f64 = 10_000_000.0;
+const CYCLES_PER_COUNT: f64 = 2.0;
+
+// The CPU sends how many counts to wait before giving up. The count is pushed when the echo starts and when it ends,
+// or !0 both times if it never starts. The end is !0 if it doesn't end in time either.
+const PROGRAM: &str = "
+    .program hc_sr04
+    .wrap_target
+        pull
+        mov x, osr
+        set pins, 1     [31]    ; 10 µs trigger
+        nop             [31]
+        nop             [31]
+        nop             [3]
+        set pins, 0
+    rise:
+        jmp pin high
+        jmp x-- rise
+        in x, 32
+        jmp done
+    high:
+        in x, 32
+    fall:
+        jmp x-- still
+        jmp done
+    still:
+        jmp pin fall
+    done:
+        in x, 32
+    .wrap
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub sensor: usize,         // Index into the sensors given to new()
+    pub time: Instant,         // When it was triggered
+    pub echo: Option<Duration>,
+    pub distance: Option<f64>, // Metres
+}
+
+pub struct HcSr04<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    sensors: Vec<(u32, u32)>,
+    clkdiv: ClkDiv,
+    speed: f64, // Of sound, m/s
+    timeout: Duration,
+    interval: Duration,
+    last: Option<Instant>,
+    next: usize,
+}
+
+impl<'a> HcSr04<'a> {
+    pub fn new(sm: StateMachine<'a>, sensors: &[(u32, u32)]) -> Result<HcSr04<'a>, Error> {
+        if sensors.is_empty() {
+            Err(Error::ParamErr { param: "sensors", should_be: "at least one".to_string() })?;
+        }
+        for &(trigger, echo) in sensors {
+            sm.pio().check_gpio(trigger as u16)?;
+            sm.pio().check_gpio(echo as u16)?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, SM_HZ)?;
+        let mut sonar = HcSr04 { sm, program: None, sensors: sensors.to_vec(), clkdiv, speed: speed_of_sound(20.0),
+                                 timeout: Duration::from_millis(50), interval: Duration::from_millis(60), last: None,
+                                 next: 0 };
+        sonar.setup()?;
+        Ok(sonar)
+    }
+
+    // The speed of sound depends on the air temperature (about 0.17% per °C), which is the biggest source of error.
+    // 20 °C unless told otherwise.
+    pub fn with_temperature(mut self, celsius: f64) -> Result<Self, Error> {
+        if !(-40.0..=85.0).contains(&celsius) {
+            Err(Error::ParamErr { param: "celsius", should_be: "-40 to 85".to_string() })?;
+        }
+        self.speed = speed_of_sound(celsius);
+        Ok(self)
+    }
+
+    // How long to wait for an echo. A sensor with nothing in range gives up after about 38 ms, and some never send
+    // one at all.
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self, Error> {
+        if timeout.is_zero() || timeout > Duration::from_secs(1) {
+            Err(Error::ParamErr { param: "timeout", should_be: "up to 1 s".to_string() })?;
+        }
+        self.timeout = timeout;
+        Ok(self)
+    }
+
+    // The least time from one ping to the next.
+    pub fn with_interval(mut self, interval: Duration) -> Result<Self, Error> {
+        self.interval = interval;
+        Ok(self)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Pings one sensor and waits for the result.
+    pub fn measure(&mut self, sensor: usize) -> Result<Reading, Error> {
+        let Some(&(trigger, echo)) = self.sensors.get(sensor) else {
+            Err(Error::ParamErr { param: "sensor", should_be: format!("< {}", self.sensors.len()) })?
+        };
+        if let Some(last) = self.last {
+            std::thread::sleep(self.interval.saturating_sub(last.elapsed()));
+        }
+        self.sm.set_enabled(false)?;
+        self.sm.init(self.program.as_ref().map_or(0, |p| p.offset()), &self.config(trigger, echo)?)?;
+        self.sm.set_enabled(true)?;
+        let hz = self.clkdiv.actual_frequency(pio_clock_hz() as f64);
+        let time = Instant::now();
+        self.last = Some(time);
+        self.sm.put((self.timeout.as_secs_f64() * hz / CYCLES_PER_COUNT) as u32, true)?;
+        let (rise, fall) = (self.sm.get(true)?, self.sm.get(true)?);
+        let echo = (rise != !0 && fall != !0)
+            .then(|| Duration::from_secs_f64((rise - fall) as f64 * CYCLES_PER_COUNT / hz));
+        Ok(Reading { sensor, time, echo, distance: echo.map(|echo| echo.as_secs_f64() * self.speed / 2.0) })
+    }
+
+    // Pings the next sensor in turn.
+    pub fn measure_next(&mut self) -> Result<Reading, Error> {
+        let sensor = self.next;
+        self.next = (sensor + 1) % self.sensors.len();
+        self.measure(sensor)
+    }
+
+    // Pings each of them once.
+    pub fn measure_all(&mut self) -> Result<Vec<Reading>, Error> {
+        (0..self.sensors.len()).map(|sensor| self.measure(sensor)).collect()
+    }
+
+    fn config(&self, trigger: u32, echo: u32) -> Result<SmConfig, Error> {
+        let (wrap_target, wrap) = self.program.as_ref().map_or((0, 0), |p| p.wrap());
+        SmConfig::default()
+            .set_set_pins(trigger, 1)?
+            .set_jmp_pin(echo)?
+            .set_in_shift(false, true, 32)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        self.program = Some(pio.load_program(&PioProgram::assemble(PROGRAM)?)?);
+        for &(trigger, echo) in &self.sensors {
+            self.sm.set_pins_with_mask(0, 1 << trigger)?;
+            self.sm.set_pindirs_with_mask(1 << trigger, 1 << trigger | 1 << echo)?;
+            pio.pio_gpio_init(trigger as u16)?;
+            pio.pio_gpio_init(echo as u16)?;
+        }
+        self.sm.clear_fifos()?;
+        Ok(())
+    }
+}
+
+// m/s in dry air.
+fn speed_of_sound(celsius: f64) -> f64 {
+    331.3 * (1.0 + celsius / 273.15).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const US: u64 = 200; // Cycles
+
+    // A sensor that answers 400 µs after its trigger ends with an echo `width` µs long, or not at all. Notes how long
+    // each trigger pulse was.
+    #[derive(Debug)]
+    struct Sensor {
+        trigger: u32,
+        echo: u32,
+        width: Option<u64>,
+        rose: Option<u64>,
+        echo_at: Option<u64>,
+        triggers: Vec<u64>,
+    }
+
+    impl Peripheral for Sensor {
+        fn step(&mut self, pins: u32, now: u64) -> (u32, u32) {
+            let trigger = pins >> self.trigger & 1 != 0;
+            match (trigger, self.rose) {
+                (true, None) => self.rose = Some(now),
+                (false, Some(rose)) => {
+                    self.triggers.push(now - rose);
+                    self.rose = None;
+                    self.echo_at = Some(now + 400 * US);
+                },
+                _ => {},
+            }
+            let high = match (self.echo_at, self.width) {
+                (Some(at), Some(width)) => (at..at + width * US).contains(&now),
+                _ => false,
+            };
+            ((high as u32) << self.echo, 1 << self.echo)
+        }
+    }
+
+    #[test]
+    fn distances() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(10_000_000);
+        let sensors: Vec<_> = [(5, 6, Some(2915)), (7, 8, None), (9, 10, Some(1000))].iter()
+            .map(|&(trigger, echo, width)| {
+                let sensor = Sensor { trigger, echo, width, rose: None, echo_at: None, triggers: vec![] };
+                let sensor = Arc::new(Mutex::new(sensor));
+                backend.emulator().attach(sensor.clone());
+                sensor
+            }).collect();
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut sonar = HcSr04::new(pio.sm_claim(0).unwrap(), &[(5, 6), (7, 8), (9, 10)]).unwrap()
+            .with_timeout(Duration::from_millis(5)).unwrap()
+            .with_interval(Duration::ZERO).unwrap();
+
+        let readings = sonar.measure_all().unwrap();
+        assert_eq!(readings.iter().map(|r| r.sensor).collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(readings[0].time < readings[1].time);
+        assert_eq!(readings[0].echo, Some(Duration::from_micros(2915)));
+        assert!((readings[0].distance.unwrap() - 0.5).abs() < 0.001, "{:?}", readings[0].distance);
+        assert_eq!((readings[1].echo, readings[1].distance), (None, None));
+        assert_eq!(readings[2].echo, Some(Duration::from_micros(1000)));
+        assert_eq!(sonar.measure_next().unwrap().sensor, 0);
+        assert_eq!(sonar.measure_next().unwrap().sensor, 1);
+
+        let hotter = sonar.with_temperature(40.0).unwrap().measure(0).unwrap();
+        assert!(hotter.distance.unwrap() > readings[0].distance.unwrap());
+        assert_eq!(sensors[0].lock().unwrap().triggers, [10 * US; 3]);
+        assert_eq!(sensors[1].lock().unwrap().triggers, [10 * US; 2]);
+    }
+}
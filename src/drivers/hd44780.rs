@@ -0,0 +1,354 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// HD44780 character LCDs (and the many clones: the 16x2 and 20x4 modules) on 4 data lines:
+//
+//     // RS, E, D4 (with D5-D7 on 9-11), columns, rows
+//     let mut lcd = Hd44780::new(pio.sm_claim_unused()?, 5, 7, 8, 16, 2)?;
+//     lcd.create_char(0, &[0x00, 0x0a, 0x1f, 0x1f, 0x0e, 0x04, 0x00, 0x00])?;
+//     lcd.print("Hello")?;
+//     lcd.set_cursor(0, 1)?;
+//     lcd.write(&[0])?;   // The heart from create_char()
+//
+// The SM does all the timing: RS ahead of E, a 1 µs E pulse with the nibble on D4-D7, and then the wait for the
+// command to finish, so nothing's ever sent too soon however the CPU is scheduled. Without R/W (tied low) the wait is
+// the longest the command might take (50 µs, or 2 ms for clear() and home()). with_busy_flag() has the SM read the busy
+// flag instead, which is usually much quicker, but the display drives D4-D7 to read it and a 5 V one would drive them
+// to 5 V, so only do that with a 3.3 V display or level shifters.
+//
+// It starts the display from scratch in 4 bit mode, so it doesn't matter what state it was in, as long as it's been
+// powered for 40 ms. print() sends characters as they are, which is right for ASCII apart from \ and ~ on the usual
+// Japanese character ROM, and anything else becomes a ?.
+
+use std::time::Duration;
+
+use crate::{pio_clock_hz, proc_pio::PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS, ClkDiv, Error, LoadedProgram, PioFifoJoin,
+            PioProgram, SmConfig, StateMachine};
+
+const HZ: f64 = 1_000_000.0; // So delays are in µs
+const COMMAND_US: u32 = 50;
+const SLOW_US: u32 = 2000; // Clear and home
+const INIT_US: [u32; 4] = [4100, 100, 100, 100];
+
+const CLEAR: u8 = 0x01;
+const HOME: u8 = 0x02;
+const ENTRY_MODE_INCREMENT: u8 = 0x06;
+const DISPLAY_CONTROL: u8 = 0x08;
+const DISPLAY_ON: u8 = 0x04;
+const CURSOR_ON: u8 = 0x02;
+const BLINK_ON: u8 = 0x01;
+const FUNCTION_SET_4_BIT: u8 = 0x20;
+const TWO_LINES: u8 = 0x08;
+const SET_CGRAM: u8 = 0x40;
+const SET_DDRAM: u8 = 0x80;
+
+// Each word is a nibble: RS, the nibble, whether to wait for the busy flag after it, then how many µs to wait
+// otherwise. Out pins are D4-D7, set pins RS (and R/W after it), side-set E, and the in pin D7.
+const PROGRAM: &str = "
+    .program hd44780
+    .side_set 1 opt
+    .wrap_target
+    start:
+        out x, 1
+        jmp !x command
+        set pins, 1
+        jmp nibble
+    command:
+        set pins, 0
+    nibble:
+        out pins, 4         side 1
+        out x, 1            side 0
+        jmp x-- busy
+        out x, 26
+    delay:
+        jmp x-- delay
+    .wrap
+    busy:
+        out pindirs, 4              ; D4-D7 in, since the rest of the word's 0
+        set pins, 2                 ; RS low, R/W high
+    poll:
+        nop                 side 1
+        in pins, 1                  ; The busy flag, while E's high
+        nop                 side 0
+        nop                 side 1  ; The other nibble, which is the address
+        mov y, isr          side 0
+        mov isr, null
+        jmp y-- poll
+        set pins, 0
+        mov osr, ~null
+        out pindirs, 32             ; D4-D7 out again, and on to the next word
+        jmp start
+";
+
+pub struct Hd44780<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    rs: u32,
+    e: u32,
+    data: u32,
+    rw: Option<u32>,
+    columns: u8,
+    rows: u8,
+    control: u8,
+}
+
+impl<'a> Hd44780<'a> {
+    // `data` is D4, with D5-D7 on the pins after it. It starts out cleared, with the display on and no cursor.
+    pub fn new(sm: StateMachine<'a>, rs: u32, e: u32, data: u32, columns: u8, rows: u8)
+               -> Result<Hd44780<'a>, Error> {
+        if !(1..=4).contains(&rows) || !(1..=40).contains(&columns) || columns as u32 * rows as u32 > 80 {
+            Err(Error::ParamErr { param: "columns", should_be: "up to 40, and 80 characters all told".to_string() })?;
+        }
+        for pin in [rs, e, data, data + 3] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let mut lcd = Hd44780 { sm, program: None, rs, e, data, rw: None, columns, rows, control: DISPLAY_ON };
+        lcd.setup()?;
+        lcd.init()?;
+        Ok(lcd)
+    }
+
+    // Waits for the busy flag after each byte, with R/W on `rw`, which has to be the pin after RS.
+    pub fn with_busy_flag(mut self, rw: u32) -> Result<Self, Error> {
+        if rw != self.rs + 1 {
+            Err(Error::ParamErr { param: "rw", should_be: format!("{} (the pin after RS)", self.rs + 1) })?;
+        }
+        self.sm.pio().check_gpio(rw as u16)?;
+        self.flush()?;
+        self.rw = Some(rw);
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.flush()?;
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.command(CLEAR, SLOW_US)
+    }
+
+    // Moves the cursor to the top left, and undoes any scrolling.
+    pub fn home(&mut self) -> Result<(), Error> {
+        self.command(HOME, SLOW_US)
+    }
+
+    pub fn set_cursor(&mut self, column: u8, row: u8) -> Result<(), Error> {
+        if column >= self.columns || row >= self.rows {
+            Err(Error::ParamErr { param: "column", should_be: format!("within {}x{}", self.columns, self.rows) })?;
+        }
+        // Rows 2 and 3 carry on from the ends of 0 and 1
+        let start = [0x00, 0x40, self.columns, 0x40 + self.columns][row as usize];
+        self.command(SET_DDRAM | (start + column), COMMAND_US)
+    }
+
+    // At the cursor, which moves on after each character. Lines don't wrap onto the next row.
+    pub fn print(&mut self, text: &str) -> Result<(), Error> {
+        let bytes: Vec<u8> = text.chars().map(|c| if c.is_ascii() && !c.is_ascii_control() { c as u8 } else { b'?' })
+            .collect();
+        self.write(&bytes)
+    }
+
+    // Character codes as they are: 0-7 are create_char()'s.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        bytes.iter().try_for_each(|&byte| self.byte(true, byte, COMMAND_US))
+    }
+
+    // One of the 8 custom characters, 5x8 with each row's pixels in the bottom 5 bits. Leaves the cursor at the top
+    // left.
+    pub fn create_char(&mut self, index: u8, rows: &[u8; 8]) -> Result<(), Error> {
+        if index > 7 {
+            Err(Error::ParamErr { param: "index", should_be: "0..=7".to_string() })?;
+        }
+        self.command(SET_CGRAM | index << 3, COMMAND_US)?;
+        self.write(rows)?;
+        self.command(SET_DDRAM, COMMAND_US)
+    }
+
+    pub fn set_display(&mut self, on: bool) -> Result<(), Error> {
+        self.set_control(DISPLAY_ON, on)
+    }
+
+    pub fn set_cursor_visible(&mut self, visible: bool) -> Result<(), Error> {
+        self.set_control(CURSOR_ON, visible)
+    }
+
+    pub fn set_blink(&mut self, blink: bool) -> Result<(), Error> {
+        self.set_control(BLINK_ON, blink)
+    }
+
+    // Everything's gone out once the FIFO's empty and the SM is back to stalling on its first `out`.
+    pub fn is_idle(&self) -> Result<bool, Error> {
+        let hw = self.sm.read_hw_state_machine()?;
+        let offset = self.program.as_ref().map(|program| program.offset() as u32);
+        let stalled = hw.execctrl & PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS != 0;
+        Ok(self.sm.is_tx_fifo_empty()? && Some(hw.pc) == offset && stalled)
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        while !self.is_idle()? {
+            std::thread::sleep(Duration::from_micros(100));
+        }
+        Ok(())
+    }
+
+    fn set_control(&mut self, bit: u8, on: bool) -> Result<(), Error> {
+        self.control = if on { self.control | bit } else { self.control & !bit };
+        self.command(DISPLAY_CONTROL | self.control, COMMAND_US)
+    }
+
+    fn command(&self, command: u8, us: u32) -> Result<(), Error> {
+        self.byte(false, command, us)
+    }
+
+    fn byte(&self, rs: bool, byte: u8, us: u32) -> Result<(), Error> {
+        let busy = self.rw.is_some();
+        self.sm.put(word(rs, byte >> 4, false, 0), true)?;
+        self.sm.put(word(rs, byte & 0xf, busy, if busy { 0 } else { us }), true)
+    }
+
+    // Into 8 bit mode three times over (from whichever mode it was in, even half way through a byte), then 4 bit mode,
+    // a nibble at a time. The busy flag can't be read until then.
+    fn init(&mut self) -> Result<(), Error> {
+        for (nibble, us) in [3, 3, 3, 2].into_iter().zip(INIT_US) {
+            self.sm.put(word(false, nibble, false, us), true)?;
+        }
+        let lines = if self.rows > 1 { TWO_LINES } else { 0 };
+        self.command(FUNCTION_SET_4_BIT | lines, COMMAND_US)?;
+        self.command(DISPLAY_CONTROL, COMMAND_US)?;
+        self.clear()?;
+        self.command(ENTRY_MODE_INCREMENT, COMMAND_US)?;
+        self.command(DISPLAY_CONTROL | self.control, COMMAND_US)
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.data, 4)?
+            .set_set_pins(self.rs, if self.rw.is_some() { 2 } else { 1 })?
+            .set_sideset(2, true, false)?
+            .set_sideset_pins(self.e)?
+            .set_in_pins(self.data + 3)?
+            .set_out_shift(true, true, 32)?
+            .set_in_shift(false, false, 32)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, HZ)?)?
+            .set_wrap(wrap_target, wrap)?;
+        let pins = [Some(self.rs), Some(self.e), self.rw].into_iter().flatten()
+            .fold(0xf << self.data, |pins, pin| pins | 1 << pin);
+        self.sm.set_pins_with_mask(0, pins)?;
+        self.sm.set_pindirs_with_mask(pins, pins)?;
+        for pin in 0..32 {
+            if pins >> pin & 1 != 0 {
+                pio.pio_gpio_init(pin as u16)?;
+            }
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+fn word(rs: bool, nibble: u8, busy: bool, us: u32) -> u32 {
+    rs as u32 | (nibble as u32 & 0xf) << 1 | (busy as u32) << 5 | us << 6
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const RS: u32 = 5;
+    const RW: u32 = 6;
+    const E: u32 = 7;
+    const D4: u32 = 8;
+    const US: u64 = 200; // Cycles
+
+    // Takes a nibble on each falling E, in 8 bit mode until it's told otherwise, and is busy for `busy` µs after each
+    // byte, which it says on D7 when R/W is high. Counts E pulses under 450 ns and writes while it's busy.
+    #[derive(Debug, Default)]
+    struct Lcd {
+        busy: u64,
+        e: bool,
+        rose: u64,
+        four_bit: bool,
+        high: Option<u8>,
+        busy_until: u64,
+        received: Vec<(bool, u8)>,
+        short: u32,
+        early: u32,
+    }
+
+    impl Peripheral for Lcd {
+        fn step(&mut self, pins: u32, now: u64) -> (u32, u32) {
+            let (e, rw) = (pins >> E & 1 != 0, pins >> RW & 1 != 0);
+            if e && !self.e {
+                self.rose = now;
+            }
+            if !e && self.e && !rw {
+                self.short += (now - self.rose < 90) as u32;
+                let (rs, nibble) = (pins >> RS & 1 != 0, (pins >> D4 & 0xf) as u8);
+                let byte = match (self.four_bit, self.high.take()) {
+                    (false, _)         => Some(nibble << 4),
+                    (true, None)       => { self.high = Some(nibble); None },
+                    (true, Some(high)) => Some(high << 4 | nibble),
+                };
+                if let Some(byte) = byte {
+                    self.early += (self.four_bit && now < self.busy_until) as u32;
+                    self.four_bit |= nibble == 2 && !self.four_bit;
+                    self.busy_until = now + self.busy * US;
+                    self.received.push((rs, byte));
+                }
+            }
+            self.e = e;
+            let reading = e && rw;
+            (((now < self.busy_until) as u32) << (D4 + 3), (reading as u32) << (D4 + 3))
+        }
+    }
+
+    #[test]
+    fn prints() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let lcd = Arc::new(Mutex::new(Lcd { busy: 40, ..Lcd::default() }));
+        backend.emulator().attach(lcd.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut display = Hd44780::new(pio.sm_claim(0).unwrap(), RS, E, D4, 20, 4).unwrap();
+        display.set_cursor(3, 2).unwrap();
+        display.print("Hi").unwrap();
+        display.create_char(2, &[0x1f; 8]).unwrap();
+        backend.emulator().run(10_000 * US);
+        let received = std::mem::take(&mut lcd.lock().unwrap().received);
+        let commands = [0x30, 0x30, 0x30, 0x20, 0x28, 0x08, 0x01, 0x06, 0x0c, 0x80 | (20 + 3)];
+        let mut expected: Vec<(bool, u8)> = commands.iter().map(|&command| (false, command)).collect();
+        expected.extend([(true, b'H'), (true, b'i'), (false, 0x40 | 2 << 3)]);
+        expected.extend([(true, 0x1f); 8]);
+        expected.push((false, 0x80));
+        assert_eq!(received, expected);
+        assert_eq!(lcd.lock().map(|lcd| (lcd.short, lcd.early)).unwrap(), (0, 0));
+
+        // Much slower, but with the busy flag it waits just as long as it has to.
+        lcd.lock().unwrap().busy = 300;
+        let mut display = display.with_busy_flag(RW).unwrap();
+        let start = backend.emulator().cycle();
+        display.print("0123456789").unwrap();
+        backend.emulator().run_until(100_000 * US, |_| lcd.lock().unwrap().received.len() == 10).unwrap();
+        let elapsed = (backend.emulator().cycle() - start) / US;
+        assert!((2700..3300).contains(&elapsed), "{elapsed} µs");
+        let received = std::mem::take(&mut lcd.lock().unwrap().received);
+        assert_eq!(received.iter().map(|&(_, byte)| byte).collect::<Vec<_>>(), b"0123456789");
+        assert_eq!(lcd.lock().unwrap().early, 0);
+        assert!(display.set_cursor(20, 0).is_err());
+        backend.emulator().run(1000 * US);
+        assert!(display.into_inner().is_ok());
+    }
+}
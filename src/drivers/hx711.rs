@@ -0,0 +1,269 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// An HX711 load cell amplifier and 24 bit ADC, on its PD_SCK and DOUT pins:
+//
+//     let mut scale = Hx711::new(pio.sm_claim_unused()?, 5, 6)?;   // PD_SCK, DOUT
+//     scale.tare(10)?;                                               // Nothing on it
+//     scale.calibrate(500.0, 10)?;                                   // Now a 500 g weight
+//     println!("{:.1} g", scale.weight(5)?);
+//
+// The SM waits for each conversion, clocks it out and sends the extra pulses that pick the gain and channel for the
+// next one, so readings come in at the chip's own rate (10 or 80 a second, depending on its RATE pin) and queue up in
+// the RX FIFO. Holding PD_SCK high for 60 µs powers the chip down, which a descheduled bit-banging loop can do by
+// accident in the middle of a reading; the SM keeps each pulse to 2 µs whatever the CPU is up to. When the FIFO is
+// full the SM stops with PD_SCK low and the newest reading still in the chip, so read() gets the oldest one; the
+// averaging helpers throw away what's queued and start afresh.
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioProgram, SmConfig, StateMachine};
+
+// The input, and the number of pulses after the 24 data bits that selects it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gain {
+    A128 = 1, // Channel A, ±20 mV full scale at 5 V. What it powers up in.
+    B32  = 2, // Channel B, ±80 mV
+    A64  = 3, // Channel A, ±40 mV
+}
+
+fn program(gain: Gain) -> String {
+    format!("
+    .program hx711
+    .side_set 1
+    .wrap_target
+        wait 0 pin 0        side 0      ; DOUT goes low when a conversion is ready
+        set x, 23           side 0
+    bit:
+        nop                 side 1 [1]
+        in pins, 1          side 0
+        jmp x-- bit         side 0
+        push                side 0
+        set x, {}           side 0
+    gain:
+        nop                 side 1 [1]
+        jmp x-- gain        side 0 [1]
+    .wrap
+    ", gain as u32 - 1)
+}
+
+pub struct Hx711<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    sck: u32,
+    dout: u32,
+    gain: Gain,
+    offset: f64,
+    scale: f64,
+    stale: bool, // The next reading was taken with whatever gain the chip had before setup()
+}
+
+impl<'a> Hx711<'a> {
+    pub fn new(sm: StateMachine<'a>, sck: u32, dout: u32) -> Result<Hx711<'a>, Error> {
+        for pin in [sck, dout] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let mut hx711 = Hx711 { sm, program: None, sck, dout, gain: Gain::A128, offset: 0.0, scale: 1.0, stale: true };
+        hx711.setup()?;
+        Ok(hx711)
+    }
+
+    pub fn with_gain(mut self, gain: Gain) -> Result<Self, Error> {
+        self.gain = gain;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn gain(&self) -> Gain {
+        self.gain
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // The next raw reading, waiting for it if there isn't one queued.
+    pub fn read(&mut self) -> Result<i32, Error> {
+        loop {
+            let reading = self.get(true)?;
+            if !std::mem::take(&mut self.stale) {
+                return Ok(reading);
+            }
+        }
+    }
+
+    pub fn try_read(&mut self) -> Result<Option<i32>, Error> {
+        while !self.sm.is_rx_fifo_empty()? {
+            let reading = self.get(false)?;
+            if !std::mem::take(&mut self.stale) {
+                return Ok(Some(reading));
+            }
+        }
+        Ok(None)
+    }
+
+    // The mean of the next `n` raw readings, leaving out any that were already queued.
+    pub fn average(&mut self, n: usize) -> Result<f64, Error> {
+        if n == 0 {
+            Err(Error::ParamErr { param: "n", should_be: "at least 1".to_string() })?;
+        }
+        while self.try_read()?.is_some() {}
+        let mut sum = 0;
+        for _ in 0..n {
+            sum += self.read()? as i64;
+        }
+        Ok(sum as f64 / n as f64)
+    }
+
+    // Makes what's on it now zero.
+    pub fn tare(&mut self, n: usize) -> Result<(), Error> {
+        self.offset = self.average(n)?;
+        Ok(())
+    }
+
+    // Sets the scale from a known weight that's on it now (after tare()ing with nothing on it).
+    pub fn calibrate(&mut self, known: f64, n: usize) -> Result<(), Error> {
+        let counts = self.average(n)? - self.offset;
+        if counts == 0.0 || known == 0.0 {
+            Err(Error::ParamErr { param: "known", should_be: "a weight that changes the reading".to_string() })?;
+        }
+        self.scale = counts / known;
+        Ok(())
+    }
+
+    // Raw counts per unit of weight, and the raw reading that's zero.
+    pub fn scale(&self) -> (f64, f64) {
+        (self.scale, self.offset)
+    }
+
+    // For putting back what calibrate() and tare() worked out last time.
+    pub fn set_scale(&mut self, scale: f64, offset: f64) {
+        (self.scale, self.offset) = (scale, offset);
+    }
+
+    // The average of `n` readings, tared and scaled.
+    pub fn weight(&mut self, n: usize) -> Result<f64, Error> {
+        Ok((self.average(n)? - self.offset) / self.scale)
+    }
+
+    // Holds PD_SCK high, which turns the chip off after 60 µs.
+    pub fn power_down(&mut self) -> Result<(), Error> {
+        self.sm.set_enabled(false)?;
+        self.sm.set_pins_with_mask(1 << self.sck, 1 << self.sck)?;
+        Ok(())
+    }
+
+    // It comes back at channel A, gain 128, so the first reading is thrown away if that's not what's wanted.
+    pub fn power_up(&mut self) -> Result<(), Error> {
+        self.setup()
+    }
+
+    fn get(&mut self, blocking: bool) -> Result<i32, Error> {
+        Ok((self.sm.get(blocking)? << 8) as i32 >> 8)
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&program(self.gain))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.dout)?
+            .set_sideset(1, false, false)?
+            .set_sideset_pins(self.sck)?
+            .set_in_shift(false, false, 32)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, 1_000_000.0)?)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pins_with_mask(0, 1 << self.sck)?;
+        self.sm.set_pindirs_with_mask(1 << self.sck, 1 << self.sck | 1 << self.dout)?;
+        for pin in [self.sck, self.dout] {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        self.stale = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::{Arc, Mutex}};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const SCK: u32 = 5;
+    const DOUT: u32 = 6;
+
+    // Has a conversion ready 20 µs after each read, shifting bits out on PD_SCK's rising edges. Notes the pulses
+    // each read got and the longest PD_SCK was ever high.
+    #[derive(Debug, Default)]
+    struct Adc {
+        readings: VecDeque<i32>,
+        shift: u64, // DOUT is bit 32, so it's low for ready, then the MSB after the first edge
+        pulses: Vec<u32>,
+        longest_high: u64,
+        sck: bool,
+        edge: u64,
+        ready_at: Option<u64>,
+    }
+
+    impl Peripheral for Adc {
+        fn step(&mut self, pins: u32, now: u64) -> (u32, u32) {
+            let sck = pins >> SCK & 1 != 0;
+            if sck && !self.sck {
+                match self.pulses.last_mut() {
+                    Some(pulses) if *pulses < 27 => *pulses += 1,
+                    _ => self.pulses.push(1),
+                }
+                self.shift <<= 1;
+                if self.pulses.last() == Some(&25) {
+                    self.shift = !0; // DOUT goes high on the first gain pulse
+                    self.ready_at = Some(now + 20 * 200);
+                }
+            }
+            if sck != self.sck {
+                if !sck {
+                    self.longest_high = self.longest_high.max(now - self.edge);
+                }
+                (self.sck, self.edge) = (sck, now);
+            }
+            let ready = self.ready_at.is_none_or(|at| now >= at) && self.pulses.last().is_none_or(|&p| p >= 25);
+            if ready && !sck && let Some(reading) = self.readings.pop_front() {
+                self.shift = ((reading as u32) << 8) as u64;
+                self.ready_at = Some(u64::MAX);
+                self.pulses.push(0);
+            }
+            (((self.shift >> 32 & 1) as u32) << DOUT, 1 << DOUT)
+        }
+    }
+
+    #[test]
+    fn readings() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let adc = Arc::new(Mutex::new(Adc { shift: !0, ..Adc::default() }));
+        adc.lock().unwrap().readings.extend([999, 1000, -2, 0x7f_ffff, -0x80_0000, 1000, 1010, 1500, 1510]);
+        backend.emulator().attach(adc.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut hx711 = Hx711::new(pio.sm_claim(0).unwrap(), SCK, DOUT).unwrap().with_gain(Gain::A64).unwrap();
+        assert_eq!(hx711.gain(), Gain::A64);
+
+        assert_eq!(hx711.read().unwrap(), 1000); // The first is from before the gain changed
+        assert_eq!(hx711.read().unwrap(), -2);
+        assert_eq!(hx711.read().unwrap(), 0x7f_ffff);
+        assert_eq!(hx711.read().unwrap(), -0x80_0000);
+        hx711.tare(2).unwrap();
+        hx711.calibrate(50.0, 2).unwrap();
+        assert_eq!(hx711.scale(), (10.0, 1005.0));
+        assert_eq!(hx711.try_read().unwrap(), None);
+
+        backend.emulator().run(200 * 100);
+        let adc = adc.lock().unwrap();
+        assert_eq!(adc.pulses, [27; 9]);
+        assert!(adc.longest_high <= 2 * 200, "{}", adc.longest_high);
+    }
+}
@@ -0,0 +1,935 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// An I2C controller on any two pins, with clock stretching, repeated starts and 10 bit addresses:
+//
+//     let mut i2c = Master::new(pio.sm_claim_unused()?, 2, 3)?   // sda, scl
+//         .with_frequency(400_000.0)?;
+//     println!("Devices at {:02x?}", i2c.scan()?);
+//     let mut temperature = [0; 2];
+//     i2c.write_read(0x48, &[0x00], &mut temperature)?;
+//     i2c.transaction(Address::Ten(0x2a5), &mut [Operation::Write(&[0x10]), Operation::Read(&mut buffer)])?;
+//
+// With the `embedded-hal` feature it's an `embedded_hal::i2c::I2c`, for both 7 and 10 bit addresses.
+//
+// Both lines are open drain: the pads' output enables are inverted (OEOVER), so a 1 in a pindir lets the line go and a 0
+// pulls it low, and the output levels stay at 0. The pads' pull-ups are turned on, but they're weak, so the bus wants
+// real ones. The SM waits for SCL to actually go high after letting it go, which is all clock stretching takes. The
+// bus isn't shared with other controllers: there's no arbitration.
+//
+// Slave answers a controller at one 7 bit address, a byte at a time through a Handler. Registers is a ready made one
+// that looks like most sensors:
+//
+//     let mut slave = Slave::new(pio.sm_claim_unused()?, 2, 3, 0x48)?;
+//     let mut registers = Registers::new(16);
+//     registers.registers_mut()[0] = 0x19;
+//     loop {
+//         slave.serve(&mut registers)?;
+//     }
+//
+// It holds SCL low after every byte until serve() has decided what happens next (whether to ACK, what to send), so the
+// controller has to put up with clock stretching, and serve() wants calling promptly. Its program takes 27 of the
+// PIO's 32 instructions, so it doesn't fit alongside a Master. Up to 400 kHz.
+//
+// The Master's FIFO words' top halves are records: a byte (8 bits of data then the ack bit, with 1s for the bits the
+// other end drives) which comes back as the 9 bits seen on SDA, or a count of the instruction words to follow for the
+// SM to execute, which is how starts and stops get done.
+
+use std::collections::VecDeque;
+
+use crate::{gpio::Override, pio_clock_hz, ClkDiv, LoadedProgram, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 32.0;
+const SLAVE_CLOCK_HZ: f64 = 25_000_000.0; // Delays of 8 clocks are the data setup time
+
+fn program(scl: u32) -> String {
+    format!("
+    .program i2c
+    .side_set 1 opt pindirs
+    .wrap_target
+    start:
+        out x, 7                        ; Instructions - 1, or 0 for a byte
+        jmp !x byte
+        out null, 32                    ; The instructions come a word each
+    exec:
+        out exec, 16
+        jmp x-- exec
+        jmp start
+    byte:
+        set x, 8                        ; 8 data bits and the ack
+    bit:
+        out pindirs, 1          [7]
+        nop             side 1  [2]
+        wait 1 gpio {scl}       [4]     ; Clock stretching
+        in pins, 1              [7]
+        jmp x-- bit     side 0  [7]
+    .wrap
+")
+}
+
+// Never run, just assembled: these get sent to the SM for it to execute. SDA's the set pin, SCL the side-set.
+fn sequences(scl: u32) -> String {
+    format!("
+    .program i2c_sequences
+    .side_set 1 opt pindirs
+        set pindirs, 0  side 1  [7]     ; Start
+        set pindirs, 0  side 0  [7]
+        set pindirs, 1  side 0  [7]     ; Repeated start
+        nop             side 1  [7]
+        wait 1 gpio {scl}       [7]
+        set pindirs, 0          [7]
+        set pindirs, 0  side 0  [7]
+        set pindirs, 0  side 0  [7]     ; Stop
+        nop             side 1  [7]
+        wait 1 gpio {scl}       [7]
+        set pindirs, 1          [7]
+")
+}
+
+const START: std::ops::Range<usize> = 0..2;
+const RESTART: std::ops::Range<usize> = 2..7;
+const STOP: std::ops::Range<usize> = 7..11;
+
+#[derive(Debug)]
+pub enum Error {
+    Pio(crate::Error),
+    AddressNack, // Nothing answered the address
+    DataNack,    // The device didn't take a byte
+}
+
+impl From<crate::Error> for Error {
+    fn from(error: crate::Error) -> Self {
+        Error::Pio(error)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Pio(error)  => write!(f, "{error}"),
+            Error::AddressNack => write!(f, "No acknowledge for the address"),
+            Error::DataNack    => write!(f, "No acknowledge for the data"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Pio(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Address {
+    Seven(u8),
+    Ten(u16),
+}
+
+// Like embedded-hal's: adjacent operations of the same kind run together, and a change of direction is a repeated
+// start.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Operation<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Address,
+    Write,
+    Read,
+}
+
+pub struct Master<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    sequences: Vec<u16>,
+    sda: u32,
+    scl: u32,
+    clkdiv: ClkDiv,
+}
+
+impl<'a> Master<'a> {
+    // Starts out at 100 kHz.
+    pub fn new(sm: StateMachine<'a>, sda: u32, scl: u32) -> Result<Master<'a>, Error> {
+        for pin in [sda, scl] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, 100_000.0 * CYCLES_PER_BIT)?;
+        let sequences = PioProgram::assemble(&sequences(scl))?.instructions().to_vec();
+        let mut master = Master { sm, program: None, sequences, sda, scl, clkdiv };
+        master.setup()?;
+        Ok(master)
+    }
+
+    pub fn with_frequency(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz * CYCLES_PER_BIT)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    // The SCL rate actually achievable with the SM's clock divider, when nothing stretches it.
+    pub fn frequency(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64) / CYCLES_PER_BIT
+    }
+
+    // Waits for the last stop to go out and puts the pads back to normal.
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.drain_tx_fifo()?;
+        std::thread::sleep(std::time::Duration::from_secs_f64(4.0 * CYCLES_PER_BIT / self.clkdiv.actual_frequency(pio_clock_hz() as f64)));
+        self.sm.set_enabled(false)?;
+        let pins = 1 << self.sda | 1 << self.scl;
+        self.sm.set_pins_with_mask(pins, pins)?;
+        for pin in [self.sda, self.scl] {
+            self.sm.pio().gpio_set_oeover(pin as u16, Override::Normal as u16)?;
+        }
+        self.sm.set_pindirs_with_mask(0, pins)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    pub fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        self.transaction(Address::Seven(address), &mut [Operation::Write(bytes)])
+    }
+
+    pub fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        self.transaction(Address::Seven(address), &mut [Operation::Read(buffer)])
+    }
+
+    pub fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        self.transaction(Address::Seven(address), &mut [Operation::Write(bytes), Operation::Read(buffer)])
+    }
+
+    // Whether anything acknowledges the address (with a write of nothing).
+    pub fn probe(&mut self, address: u8) -> Result<bool, Error> {
+        match self.write(address, &[]) {
+            Err(Error::AddressNack) => Ok(false),
+            result                  => result.map(|_| true),
+        }
+    }
+
+    // The 7 bit addresses that aren't reserved and answer a probe.
+    pub fn scan(&mut self) -> Result<Vec<u8>, Error> {
+        let mut found = Vec::new();
+        for address in 0x08..0x78 {
+            if self.probe(address)? {
+                found.push(address);
+            }
+        }
+        Ok(found)
+    }
+
+    // A start, the operations and a stop. A NACK skips the rest of the bytes, but not the stop.
+    pub fn transaction(&mut self, address: Address, operations: &mut [Operation]) -> Result<(), Error> {
+        match address {
+            Address::Seven(a) if a > 0x7f => Err(crate::Error::ParamErr { param: "address", should_be: "0..=0x7f".to_string() })?,
+            Address::Ten(a) if a > 0x3ff  => Err(crate::Error::ParamErr { param: "address", should_be: "0..=0x3ff".to_string() })?,
+            _                             => {},
+        }
+        if operations.iter().any(|op| matches!(op, Operation::Read(buffer) if buffer.is_empty())) {
+            Err(crate::Error::ParamErr { param: "operations", should_be: "reads of at least a byte".to_string() })?;
+        }
+        let mut words = Vec::new();
+        let mut previous = None;
+        for (n, op) in operations.iter().enumerate() {
+            let read = matches!(op, Operation::Read(_));
+            if previous != Some(read) {
+                self.sequence(&mut words, if previous.is_none() { START } else { RESTART });
+                self.header(&mut words, address, read);
+            }
+            previous = Some(read);
+            match op {
+                Operation::Read(buffer) => {
+                    // NACK the last byte before a stop or a repeated start
+                    let last = !matches!(operations.get(n + 1), Some(Operation::Read(_)));
+                    for i in 0..buffer.len() {
+                        words.push(byte(0xff, last && i == buffer.len() - 1, Kind::Read));
+                    }
+                },
+                Operation::Write(bytes) => words.extend(bytes.iter().map(|&b| byte(b, true, Kind::Write))),
+            }
+        }
+        if previous.is_some() {
+            self.sequence(&mut words, STOP);
+        }
+
+        let (mut sent, mut pending, mut read, mut error) = (0, VecDeque::new(), Vec::new(), None);
+        while sent < words.len() || !pending.is_empty() {
+            // After a NACK, only the starts and the stop still go out.
+            if sent < words.len() && error.is_some() && words[sent].1.is_some() {
+                sent += 1;
+                continue;
+            }
+            if sent < words.len() && (pending.is_empty() || !self.sm.is_tx_fifo_full()?) {
+                let (word, kind) = words[sent];
+                self.sm.put(word, true)?;
+                pending.extend(kind);
+                sent += 1;
+                continue;
+            }
+            let bits = self.sm.get(true)?;
+            match pending.pop_front() {
+                Some(Kind::Read)                         => read.push((bits >> 1) as u8),
+                Some(Kind::Address) if bits & 1 != 0     => { error.get_or_insert(Error::AddressNack); },
+                Some(Kind::Write) if bits & 1 != 0       => { error.get_or_insert(Error::DataNack); },
+                _                                        => {},
+            }
+        }
+        if let Some(error) = error {
+            return Err(error);
+        }
+        let mut read = read.into_iter();
+        for op in operations {
+            if let Operation::Read(buffer) = op {
+                buffer.iter_mut().zip(&mut read).for_each(|(b, r)| *b = r);
+            }
+        }
+        Ok(())
+    }
+
+    fn header(&self, words: &mut Vec<(u32, Option<Kind>)>, address: Address, read: bool) {
+        match address {
+            Address::Seven(a) => words.push(byte(a << 1 | read as u8, true, Kind::Address)),
+            Address::Ten(a)   => {
+                let high = 0xf0 | ((a >> 8) as u8) << 1;
+                words.push(byte(high, true, Kind::Address));
+                words.push(byte(a as u8, true, Kind::Address));
+                if read {
+                    self.sequence(words, RESTART);
+                    words.push(byte(high | 1, true, Kind::Address));
+                }
+            },
+        }
+    }
+
+    fn sequence(&self, words: &mut Vec<(u32, Option<Kind>)>, range: std::ops::Range<usize>) {
+        words.push(((range.len() as u32 - 1) << 25, None));
+        words.extend(self.sequences[range].iter().map(|&instr| ((instr as u32) << 16, None)));
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&program(self.scl))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.sda, 1)?
+            .set_set_pins(self.sda, 1)?
+            .set_in_pins(self.sda)?
+            .set_sideset(2, true, true)?
+            .set_sideset_pins(self.scl)?
+            .set_out_shift(false, true, 16)?
+            .set_in_shift(false, true, 9)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        // The levels stay low and the (inverted) pindirs let go of both lines.
+        let pins = 1 << self.sda | 1 << self.scl;
+        self.sm.set_pins_with_mask(0, pins)?;
+        self.sm.set_pindirs_with_mask(pins, pins)?;
+        for pin in [self.sda, self.scl] {
+            pio.gpio_set_oeover(pin as u16, Override::Invert as u16)?;
+            pio.pio_gpio_init(pin as u16)?;
+            pio.set_pulls(pin as u16, true, false)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+// `ack` is true to let go of SDA for the other end's ack (or to NACK a read).
+fn byte(data: u8, ack: bool, kind: Kind) -> (u32, Option<Kind>) {
+    (((data as u32) << 1 | ack as u32) << 16, Some(kind))
+}
+
+// The slave's program. Its FIFO words go LSB first: a count for X (4 bits) and where to go next (5 bits), then
+// whatever that wants: an ack bit for `bit`, or 9 bits to send (a byte, MSB first, then a 1 to let the controller ack).
+fn slave_program(sda: u32, scl: u32) -> String {
+    format!("
+    .program i2c_slave
+    .side_set 1 opt pindirs
+    .wrap_target
+    idle:
+        wait 1 gpio {sda}   side 1
+        wait 0 gpio {sda}
+        jmp pin start                   ; SDA fell with SCL high
+    .wrap
+    start:
+        set y, 1
+    condition:                          ; A start (Y = 1) or a stop (Y = 0)
+        mov isr, ~y
+        push
+    rx:
+        set pindirs, 1      side 1
+        wait 0 gpio {scl}
+        wait 1 gpio {scl}
+        in pins, 1
+        mov y, isr
+    watch:                              ; Bit 0 can turn out to be a stop or a repeated start instead
+        mov osr, pins
+        out x, 1
+        jmp pin check
+        mov osr, ~null
+        set x, 6
+    bit:
+        out pindirs, 1      [7]
+        wait 1 gpio {scl}   side 1
+        in pins, 1
+        wait 0 gpio {scl}
+        jmp x-- bit
+        push                side 0      ; Hold SCL low until the CPU says what's next
+        pull
+        out x, 4
+        out pc, 5
+    check:
+        jmp x!=y condition
+        jmp watch
+")
+}
+
+const IDLE: u32 = 0;
+const RX: u32 = 6;
+const BIT: u32 = 16;
+const START_SEEN: u32 = !1;
+const STOP_SEEN: u32 = !0;
+
+// What the Pi looks like to the controller, a byte at a time. The defaults take everything and read as 0xff.
+pub trait Handler {
+    // We've been addressed, after a start or a repeated start.
+    fn start(&mut self, _read: bool) {}
+
+    // Returns whether to ACK the byte.
+    fn write(&mut self, _byte: u8) -> bool {
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        0xff
+    }
+
+    // The controller's stop, or a repeated start to another address.
+    fn stop(&mut self) {}
+}
+
+// A register file like most sensors have: a write's first byte picks the register, and the rest of the write (or the
+// reads after it) carry on from there a register at a time. Writes past the end are NACKed, reads wrap around.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registers {
+    registers: Vec<u8>,
+    pointer: usize,
+    pointed: bool, // This write has set the pointer already
+}
+
+impl Registers {
+    pub fn new(len: usize) -> Registers {
+        Registers { registers: vec![0; len], pointer: 0, pointed: false }
+    }
+
+    pub fn registers(&self) -> &[u8] {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut [u8] {
+        &mut self.registers
+    }
+}
+
+impl Handler for Registers {
+    fn start(&mut self, _read: bool) {
+        self.pointed = false;
+    }
+
+    fn write(&mut self, byte: u8) -> bool {
+        if !self.pointed {
+            self.pointed = true;
+            self.pointer = byte as usize;
+            return self.pointer < self.registers.len();
+        }
+        let Some(register) = self.registers.get_mut(self.pointer) else { return false };
+        *register = byte;
+        self.pointer += 1;
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let byte = *self.registers.get(self.pointer).unwrap_or(&0xff);
+        self.pointer = (self.pointer + 1) % self.registers.len().max(1);
+        byte
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Address,
+    Write,
+    Read,
+}
+
+pub struct Slave<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    sda: u32,
+    scl: u32,
+    address: u8,
+    phase: Phase,
+    addressed: bool,
+    skip: usize, // What the SM pushes after an ack bit it sent
+}
+
+impl<'a> Slave<'a> {
+    pub fn new(sm: StateMachine<'a>, sda: u32, scl: u32, address: u8) -> Result<Slave<'a>, Error> {
+        for pin in [sda, scl] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        if address > 0x7f {
+            Err(crate::Error::ParamErr { param: "address", should_be: "0..=0x7f".to_string() })?;
+        }
+        let mut slave = Slave { sm, program: None, sda, scl, address, phase: Phase::Address, addressed: false, skip: 0 };
+        slave.setup()?;
+        Ok(slave)
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    // Lets go of both lines and puts the pads back to normal.
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        let pins = 1 << self.sda | 1 << self.scl;
+        self.sm.set_pins_with_mask(pins, pins)?;
+        for pin in [self.sda, self.scl] {
+            self.sm.pio().gpio_set_oeover(pin as u16, Override::Normal as u16)?;
+        }
+        self.sm.set_pindirs_with_mask(0, pins)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Waits for the next thing to happen on the bus and hands it to `handler`.
+    pub fn serve(&mut self, handler: &mut impl Handler) -> Result<(), Error> {
+        let word = self.sm.get(true)?;
+        self.received(word, handler)
+    }
+
+    // Returns false instead of waiting when nothing's happened.
+    pub fn try_serve(&mut self, handler: &mut impl Handler) -> Result<bool, Error> {
+        if self.sm.is_rx_fifo_empty()? {
+            return Ok(false);
+        }
+        let word = self.sm.get(false)?;
+        self.received(word, handler)?;
+        Ok(true)
+    }
+
+    fn received(&mut self, word: u32, handler: &mut impl Handler) -> Result<(), Error> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return Ok(());
+        }
+        if word == START_SEEN || word == STOP_SEEN {
+            if word == STOP_SEEN && self.addressed {
+                handler.stop();
+                self.addressed = false;
+            }
+            self.phase = Phase::Address;
+            return Ok(());
+        }
+        match self.phase {
+            Phase::Address if (word as u8) >> 1 != self.address => {
+                if self.addressed {
+                    handler.stop();
+                    self.addressed = false;
+                }
+                self.ack(false, self.goto(IDLE))
+            },
+            Phase::Address => {
+                let read = word & 1 != 0;
+                self.addressed = true;
+                handler.start(read);
+                if read {
+                    self.phase = Phase::Read;
+                    let byte = handler.read();
+                    self.ack(true, self.transmit(byte))
+                } else {
+                    self.phase = Phase::Write;
+                    self.ack(true, self.goto(RX))
+                }
+            },
+            Phase::Write => {
+                let ack = handler.write(word as u8);
+                self.ack(ack, self.goto(RX))
+            },
+            // The controller's ack for the byte we sent. A NACK means that's all it wants.
+            Phase::Read if word & 1 == 0 => {
+                let byte = handler.read();
+                self.sm.put(self.transmit(byte), true)?;
+                Ok(())
+            },
+            Phase::Read => {
+                self.sm.put(self.goto(RX), true)?;
+                Ok(())
+            },
+        }
+    }
+
+    // Sends the ack bit, then carries on with `then`.
+    fn ack(&mut self, ack: bool, then: u32) -> Result<(), Error> {
+        self.sm.put(self.goto(BIT) | (!ack as u32) << 9, true)?;
+        self.sm.put(then, true)?;
+        self.skip += 1;
+        Ok(())
+    }
+
+    fn goto(&self, label: u32) -> u32 {
+        let offset = self.program.as_ref().map_or(0, |program| program.offset() as u32);
+        (offset + label) << 4
+    }
+
+    fn transmit(&self, byte: u8) -> u32 {
+        8 | self.goto(BIT) | (byte.reverse_bits() as u32) << 9 | 1 << 17
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&slave_program(self.sda, self.scl))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.sda, 1)?
+            .set_set_pins(self.sda, 1)?
+            .set_in_pins(self.sda)?
+            .set_jmp_pin(self.scl)?
+            .set_sideset(2, true, true)?
+            .set_sideset_pins(self.scl)?
+            .set_out_shift(true, false, 32)?
+            .set_in_shift(false, false, 32)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, SLAVE_CLOCK_HZ)?)?
+            .set_wrap(wrap_target, wrap)?;
+        let pins = 1 << self.sda | 1 << self.scl;
+        self.sm.set_pins_with_mask(0, pins)?;
+        self.sm.set_pindirs_with_mask(pins, pins)?;
+        for pin in [self.sda, self.scl] {
+            pio.gpio_set_oeover(pin as u16, Override::Invert as u16)?;
+            pio.pio_gpio_init(pin as u16)?;
+            pio.set_pulls(pin as u16, true, false)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+mod hal {
+    use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, NoAcknowledgeSource, SevenBitAddress, TenBitAddress};
+
+    use super::{Address, Error, Master, Operation};
+
+    impl embedded_hal::i2c::Error for Error {
+        fn kind(&self) -> ErrorKind {
+            match self {
+                Error::Pio(_)      => ErrorKind::Other,
+                Error::AddressNack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+                Error::DataNack    => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data),
+            }
+        }
+    }
+
+    impl ErrorType for Master<'_> {
+        type Error = Error;
+    }
+
+    fn operations<'a>(operations: &'a mut [embedded_hal::i2c::Operation<'_>]) -> Vec<Operation<'a>> {
+        operations.iter_mut().map(|op| match op {
+            embedded_hal::i2c::Operation::Read(buffer) => Operation::Read(buffer),
+            embedded_hal::i2c::Operation::Write(bytes) => Operation::Write(bytes),
+        }).collect()
+    }
+
+    impl I2c<SevenBitAddress> for Master<'_> {
+        fn transaction(&mut self, address: u8, ops: &mut [embedded_hal::i2c::Operation<'_>]) -> Result<(), Error> {
+            Master::transaction(self, Address::Seven(address), &mut operations(ops))
+        }
+    }
+
+    impl I2c<TenBitAddress> for Master<'_> {
+        fn transaction(&mut self, address: u16, ops: &mut [embedded_hal::i2c::Operation<'_>]) -> Result<(), Error> {
+            Master::transaction(self, Address::Ten(address), &mut operations(ops))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const SDA: u32 = 2;
+    const SCL: u32 = 3;
+    const STRETCH: u32 = 5000;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    enum State {
+        #[default]
+        Idle,
+        Address,
+        AddressLow, // The second byte of a 10 bit address
+        Write,
+        Read,
+    }
+
+    // An EEPROM-ish device at 0x50 and 0x2a5: the first byte written sets the pointer, the rest get stored. It holds
+    // SCL low for a while after every byte it takes.
+    #[derive(Debug)]
+    struct Device {
+        scl: bool,
+        sda: bool,
+        state: State,
+        bit: u32,       // Which of the 9 clocks of a byte is next
+        byte: u8,
+        sda_low: bool,  // Our side of SDA
+        stretch: u32,
+        ten_bit: bool,  // Addressed with a 10 bit write, so a 10 bit read can follow
+        pointer: Option<u8>,
+        memory: [u8; 256],
+        stretches: usize,
+    }
+
+    impl Device {
+        fn new() -> Device {
+            Device { scl: true, sda: true, state: State::Idle, bit: 0, byte: 0, sda_low: false, stretch: 0, ten_bit: false,
+                     pointer: None, memory: [0; 256], stretches: 0 }
+        }
+
+        // Whether to ack a byte we were sent.
+        fn received(&mut self) -> bool {
+            let byte = self.byte;
+            let (state, ack) = match self.state {
+                State::Address if byte >> 1 == 0x50                 => (if byte & 1 != 0 { State::Read } else { State::Write }, true),
+                State::Address if byte == 0xf0 | 2 << 1             => { self.ten_bit = false; (State::AddressLow, true) },
+                State::Address if byte == 0xf1 | 2 << 1             => (State::Read, self.ten_bit),
+                State::AddressLow if byte == 0xa5                   => { self.ten_bit = true; (State::Write, true) },
+                State::Write                                        => {
+                    match self.pointer {
+                        None          => self.pointer = Some(byte),
+                        Some(pointer) => { self.memory[pointer as usize] = byte; self.pointer = Some(pointer.wrapping_add(1)) },
+                    }
+                    (State::Write, true)
+                },
+                _                                                   => (State::Idle, false),
+            };
+            self.state = if ack { state } else { State::Idle };
+            if self.state == State::Write && self.pointer.is_some() {
+                self.stretch = STRETCH;
+                self.stretches += 1;
+            }
+            ack
+        }
+    }
+
+    impl Peripheral for Device {
+        fn step(&mut self, pins: u32, _cycle: u64) -> (u32, u32) {
+            let (scl, sda) = (pins >> SCL & 1 != 0, pins >> SDA & 1 != 0);
+            if scl && self.scl && sda != self.sda {
+                if !sda {
+                    (self.state, self.bit) = (State::Address, 8); // (Repeated) start. SCL falls next, for bit 0
+                } else {
+                    (self.state, self.ten_bit, self.pointer) = (State::Idle, false, None); // Stop
+                }
+                self.sda_low = false;
+            } else if scl && !self.scl && self.state != State::Idle {
+                if self.bit < 8 && self.state != State::Read {
+                    self.byte = self.byte << 1 | sda as u8;
+                } else if self.bit == 8 && self.state == State::Read && sda {
+                    self.state = State::Idle; // NACKed: that's all they want
+                }
+            } else if !scl && self.scl && self.state != State::Idle {
+                self.bit = (self.bit + 1) % 9;
+                self.sda_low = match (self.bit, self.state) {
+                    (8, State::Read) => false,
+                    (8, _)           => self.received(),
+                    (0, State::Read) => {
+                        let pointer = self.pointer.unwrap_or(0);
+                        self.byte = self.memory[pointer as usize];
+                        self.pointer = Some(pointer.wrapping_add(1));
+                        self.byte & 0x80 == 0
+                    },
+                    (n, State::Read) => self.byte >> (7 - n) & 1 == 0,
+                    _                => false,
+                };
+            }
+            (self.scl, self.sda) = (scl, sda);
+            self.stretch = self.stretch.saturating_sub(1);
+            (((self.stretch == 0) as u32) << SCL | (!self.sda_low as u32) << SDA, 1 << SCL | 1 << SDA)
+        }
+    }
+
+    #[test]
+    fn transactions() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let device = Arc::new(Mutex::new(Device::new()));
+        backend.emulator().attach(device.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut i2c = Master::new(pio.sm_claim(0).unwrap(), SDA, SCL).unwrap();
+
+        i2c.write(0x50, &[0x10, 1, 2, 3]).unwrap();
+        assert_eq!(device.lock().unwrap().memory[0x10..0x13], [1, 2, 3]);
+        assert_eq!(device.lock().unwrap().stretches, 4);
+        let mut buffer = [0; 3];
+        i2c.write_read(0x50, &[0x10], &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3]);
+        // Adjacent reads are one read, NACKed at the end.
+        let (mut a, mut b) = ([0; 1], [0; 2]);
+        i2c.transaction(Address::Seven(0x50), &mut [Operation::Write(&[0x11]), Operation::Read(&mut a), Operation::Read(&mut b)]).unwrap();
+        assert_eq!((a, b), ([2], [3, 0]));
+
+        assert!(matches!(i2c.write(0x51, &[0]), Err(Error::AddressNack)));
+        assert_eq!(i2c.scan().unwrap(), [0x50]);
+
+        i2c.transaction(Address::Ten(0x2a5), &mut [Operation::Write(&[0x20, 9, 8])]).unwrap();
+        i2c.transaction(Address::Ten(0x2a5), &mut [Operation::Write(&[0x20]), Operation::Read(&mut buffer)]).unwrap();
+        assert_eq!(buffer, [9, 8, 0]);
+        assert!(matches!(i2c.transaction(Address::Ten(0x1a5), &mut [Operation::Write(&[0])]), Err(Error::AddressNack)));
+        assert!(i2c.transaction(Address::Ten(0x400), &mut []).is_err());
+        assert!(i2c.read(0x50, &mut []).is_err());
+    }
+
+    // Registers, keeping track of the starts and stops.
+    #[derive(Debug)]
+    struct Logged {
+        registers: Registers,
+        starts: Vec<bool>,
+        stops: usize,
+    }
+
+    impl Handler for Logged {
+        fn start(&mut self, read: bool) {
+            self.starts.push(read);
+            self.registers.start(read);
+        }
+
+        fn write(&mut self, byte: u8) -> bool {
+            self.registers.write(byte)
+        }
+
+        fn read(&mut self) -> u8 {
+            self.registers.read()
+        }
+
+        fn stop(&mut self) {
+            self.stops += 1;
+        }
+    }
+
+    // Plays the controller by hand at about 100 kHz, serving the slave as it goes so its clock stretching works out.
+    struct Controller<'a, 'b> {
+        backend: &'a EmulatorBackend,
+        slave: &'a mut Slave<'b>,
+        handler: &'a mut Logged,
+    }
+
+    impl Controller<'_, '_> {
+        fn wait(&mut self, cycles: u64) {
+            for _ in 0..cycles / 100 {
+                self.backend.emulator().run(100);
+                while self.slave.try_serve(self.handler).unwrap() {}
+            }
+        }
+
+        fn line(&mut self, pin: u32, high: bool) {
+            self.backend.emulator().set_input(pin, high);
+            for _ in 0..1000 {
+                if !high || self.backend.emulator().pins() >> pin & 1 != 0 {
+                    break;
+                }
+                self.wait(100);
+            }
+            self.wait(500);
+        }
+
+        fn start(&mut self) {
+            self.line(SDA, true);
+            self.line(SCL, true);
+            self.line(SDA, false);
+            self.line(SCL, false);
+        }
+
+        fn stop(&mut self) {
+            self.line(SDA, false);
+            self.line(SCL, true);
+            self.line(SDA, true);
+        }
+
+        fn bit(&mut self, bit: bool) -> bool {
+            self.line(SDA, bit);
+            self.line(SCL, true);
+            let sda = self.backend.emulator().pins() >> SDA & 1 != 0;
+            self.line(SCL, false);
+            sda
+        }
+
+        // Returns whether it was ACKed.
+        fn write(&mut self, byte: u8) -> bool {
+            for n in (0..8).rev() {
+                self.bit(byte >> n & 1 != 0);
+            }
+            !self.bit(true)
+        }
+
+        fn read(&mut self, ack: bool) -> u8 {
+            let byte = (0..8).fold(0, |byte, _| byte << 1 | self.bit(true) as u8);
+            self.bit(!ack);
+            byte
+        }
+    }
+
+    #[test]
+    fn slave() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut slave = Slave::new(pio.sm_claim(0).unwrap(), SDA, SCL, 0x42).unwrap();
+        let mut handler = Logged { registers: Registers::new(8), starts: Vec::new(), stops: 0 };
+        backend.emulator().set_inputs(1 << SDA | 1 << SCL, 1 << SDA | 1 << SCL);
+        let mut c = Controller { backend: &backend, slave: &mut slave, handler: &mut handler };
+
+        c.start();
+        assert!(c.write(0x42 << 1));
+        assert!(c.write(2));
+        assert!(c.write(0xaa));
+        assert!(c.write(0xbb));
+        c.stop();
+        assert_eq!(c.handler.registers.registers()[2..4], [0xaa, 0xbb]);
+
+        c.start();
+        assert!(c.write(0x42 << 1));
+        assert!(c.write(2));
+        c.start();
+        assert!(c.write(0x42 << 1 | 1));
+        assert_eq!([c.read(true), c.read(true), c.read(false)], [0xaa, 0xbb, 0]);
+        c.stop();
+
+        // Somebody else's address, then a register past the end.
+        c.start();
+        assert!(!c.write(0x43 << 1));
+        c.write(0x55);
+        c.stop();
+        c.start();
+        assert!(c.write(0x42 << 1));
+        assert!(!c.write(9));
+        c.stop();
+        assert_eq!(c.handler.starts, [false, false, true, false]);
+        assert_eq!(c.handler.stops, 3);
+    }
+}
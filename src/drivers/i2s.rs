@@ -0,0 +1,470 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// I2S audio, with the Pi generating the clocks: BCLK on `clocks` and LRCLK (word select) on `clocks + 1`. Rx records
+// from a microphone or ADC, Tx plays to a DAC, and Duplex does both at once for codecs:
+//
+//     let mut mic = Rx::new(pio.sm_claim_unused()?, 20, 18, 48_000)?.with_bits(24)?;   // data in, BCLK (LRCLK on 19)
+//     let mut frames = [[0; 2]; 480];                                                     // [left, right]
+//     mic.read(&mut frames)?;
+//
+//     let mut codec = Duplex::new(pio.sm_claim_unused()?, pio.sm_claim_unused()?, 21, 20, 18, 48_000)?;
+//     codec.transfer(&playback, &mut recorded)?;   // recorded[i] was captured while playback[i] was playing
+//
+// Samples are 16 bits unless with_bits() says otherwise (anything from 8 to 32), each in a slot of that many BCLKs,
+// and go around as i32s. Frames go in and out of the FIFOs as a word per channel, always left then right, and the SMs
+// stop and wait (clocks and all) rather than drop or make up a sample, so the channels never get swapped. Keep the
+// FIFOs fed and drained to keep the sound going.
+//
+// Duplex's second SM doesn't generate anything: it's started in the same cycle as Tx's (with sm_enable_sync()) and
+// follows its BCLK, checking LRCLK after every bit so it's always in step. transfer() keeps no more frames in flight
+// than the RX FIFO holds, so it never has to stop and frames come back exactly lined up with the ones that went out.
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 4.0;
+
+// Both start at the last instruction, the second half of the bit before the left channel's first.
+fn tx_program(bits: u32) -> String {
+    format!("
+    .program i2s_tx
+    .side_set 2                             ; LRCLK, BCLK
+    left:
+        out pins, 1         side 0b00 [1]
+        jmp x-- left        side 0b01 [1]
+        out pins, 1         side 0b10 [1]   ; LRCLK changes a bit ahead of the other channel
+        set x, {n}          side 0b11 [1]
+    right:
+        out pins, 1         side 0b10 [1]
+        jmp x-- right       side 0b11 [1]
+        out pins, 1         side 0b00 [1]
+        set x, {n}          side 0b01 [1]
+    ", n = bits - 2)
+}
+
+fn rx_program(bits: u32) -> String {
+    format!("
+    .program i2s_rx
+    .side_set 2
+    left:
+        nop                 side 0b00 [1]
+        in pins, 1          side 0b01
+        jmp x-- left        side 0b01
+        nop                 side 0b10 [1]
+        in pins, 1          side 0b11
+        set x, {n}          side 0b11
+    right:
+        nop                 side 0b10 [1]
+        in pins, 1          side 0b11
+        jmp x-- right       side 0b11
+        nop                 side 0b00 [1]
+        in pins, 1          side 0b01
+        set x, {n}          side 0b01
+    ", n = bits - 2)
+}
+
+// Samples on each rising edge of BCLK, and pushes a channel once LRCLK has changed for the next one.
+fn follower_program(bclk: u32) -> String {
+    format!("
+    .program i2s_rx_follower
+    .wrap_target
+    left:
+        wait 0 gpio {bclk}
+        wait 1 gpio {bclk}
+        in pins, 1
+        jmp pin left_done
+        jmp left
+    left_done:
+        push
+    right:
+        wait 0 gpio {bclk}
+        wait 1 gpio {bclk}
+        in pins, 1
+        jmp pin right
+        push
+    .wrap
+    ")
+}
+
+fn check_bits(bits: u32) -> Result<(), Error> {
+    if !(8..=32).contains(&bits) {
+        Err(Error::ParamErr { param: "bits", should_be: "8..=32".to_string() })?;
+    }
+    Ok(())
+}
+
+fn clkdiv(rate: f64, bits: u32) -> Result<ClkDiv, Error> {
+    ClkDiv::for_frequency(pio_clock_hz() as f64, rate * 2.0 * bits as f64 * CYCLES_PER_BIT)
+}
+
+fn actual_rate(clkdiv: ClkDiv, bits: u32) -> f64 {
+    clkdiv.actual_frequency(pio_clock_hz() as f64) / (2.0 * bits as f64 * CYCLES_PER_BIT)
+}
+
+// BCLK idles high so that the first thing a follower sees is the fall before the first bit.
+fn init_clocks(sm: &StateMachine, clocks: u32) -> Result<(), Error> {
+    sm.set_pins_with_mask(1 << clocks, 3 << clocks)?;
+    sm.set_pindirs_with_mask(3 << clocks, 3 << clocks)?;
+    for pin in [clocks, clocks + 1] {
+        sm.pio().pio_gpio_init(pin as u16)?;
+    }
+    Ok(())
+}
+
+pub struct Tx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    dout: u32,
+    clocks: u32,
+    bits: u32,
+    rate: f64,
+    clkdiv: ClkDiv,
+}
+
+impl<'a> Tx<'a> {
+    pub fn new(sm: StateMachine<'a>, dout: u32, clocks: u32, sample_rate: u32) -> Result<Tx<'a>, Error> {
+        let tx = Tx::unstarted(sm, dout, clocks, sample_rate)?;
+        tx.sm.set_enabled(true)?;
+        Ok(tx)
+    }
+
+    fn unstarted(sm: StateMachine<'a>, dout: u32, clocks: u32, sample_rate: u32) -> Result<Tx<'a>, Error> {
+        for pin in [dout, clocks, clocks + 1] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let clkdiv = clkdiv(sample_rate as f64, 16)?;
+        let mut tx = Tx { sm, program: None, dout, clocks, bits: 16, rate: sample_rate as f64, clkdiv };
+        tx.setup()?;
+        Ok(tx)
+    }
+
+    pub fn with_bits(mut self, bits: u32) -> Result<Self, Error> {
+        self.set_bits(bits)?;
+        self.sm.set_enabled(true)?;
+        Ok(self)
+    }
+
+    fn set_bits(&mut self, bits: u32) -> Result<(), Error> {
+        check_bits(bits)?;
+        self.clkdiv = clkdiv(self.rate, bits)?;
+        self.bits = bits;
+        self.setup()
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    // The rate actually achievable with the SM's clock divider.
+    pub fn sample_rate(&self) -> f64 {
+        actual_rate(self.clkdiv, self.bits)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Samples are the bottom bits() bits of each i32.
+    pub fn write(&mut self, frames: &[[i32; 2]]) -> Result<(), Error> {
+        for frame in frames {
+            self.put(*frame)?;
+        }
+        Ok(())
+    }
+
+    // Writes the frames there's room for without waiting, and says how many that was.
+    pub fn try_write(&mut self, frames: &[[i32; 2]]) -> Result<usize, Error> {
+        let room = (self.capacity() - self.sm.get_tx_fifo_level()? as usize) / 2;
+        let count = room.min(frames.len());
+        self.write(&frames[..count])?;
+        Ok(count)
+    }
+
+    fn put(&mut self, frame: [i32; 2]) -> Result<(), Error> {
+        for sample in frame {
+            self.sm.put((sample as u32) << (32 - self.bits), true)?;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.sm.pio().chip().fifo_depth as usize * 2
+    }
+
+    // Leaves the SM disabled, so Duplex can start it along with the other one.
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&tx_program(self.bits))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.dout, 1)?
+            .set_sideset(2, false, false)?
+            .set_sideset_pins(self.clocks)?
+            .set_out_shift(false, true, self.bits)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        init_clocks(&self.sm, self.clocks)?;
+        self.sm.set_pins_with_mask(0, 1 << self.dout)?;
+        self.sm.set_pindirs_with_mask(1 << self.dout, 1 << self.dout)?;
+        pio.pio_gpio_init(self.dout as u16)?;
+        self.sm.init(program.offset() + 7, &config)?;
+        self.sm.clear_fifos()?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+pub struct Rx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    din: u32,
+    clocks: u32,
+    bits: u32,
+    rate: f64,
+    clkdiv: ClkDiv,
+    follower: bool, // Following someone else's clocks instead of making them
+}
+
+impl<'a> Rx<'a> {
+    pub fn new(sm: StateMachine<'a>, din: u32, clocks: u32, sample_rate: u32) -> Result<Rx<'a>, Error> {
+        let rx = Rx::unstarted(sm, din, clocks, sample_rate, false)?;
+        rx.sm.set_enabled(true)?;
+        Ok(rx)
+    }
+
+    fn unstarted(sm: StateMachine<'a>, din: u32, clocks: u32, sample_rate: u32, follower: bool) -> Result<Rx<'a>, Error> {
+        for pin in [din, clocks, clocks + 1] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let clkdiv = clkdiv(sample_rate as f64, 16)?;
+        let mut rx = Rx { sm, program: None, din, clocks, bits: 16, rate: sample_rate as f64, clkdiv, follower };
+        rx.setup()?;
+        Ok(rx)
+    }
+
+    pub fn with_bits(mut self, bits: u32) -> Result<Self, Error> {
+        self.set_bits(bits)?;
+        self.sm.set_enabled(true)?;
+        Ok(self)
+    }
+
+    fn set_bits(&mut self, bits: u32) -> Result<(), Error> {
+        check_bits(bits)?;
+        self.clkdiv = clkdiv(self.rate, bits)?;
+        self.bits = bits;
+        self.setup()
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    // The rate actually achievable with the SM's clock divider.
+    pub fn sample_rate(&self) -> f64 {
+        actual_rate(self.clkdiv, self.bits)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Samples come back sign extended from bits() bits.
+    pub fn read(&mut self, frames: &mut [[i32; 2]]) -> Result<(), Error> {
+        for frame in frames {
+            *frame = self.get()?;
+        }
+        Ok(())
+    }
+
+    // Reads the frames that have already arrived, up to frames.len(), and says how many that was.
+    pub fn try_read(&mut self, frames: &mut [[i32; 2]]) -> Result<usize, Error> {
+        let count = (self.sm.get_rx_fifo_level()? as usize / 2).min(frames.len());
+        self.read(&mut frames[..count])?;
+        Ok(count)
+    }
+
+    fn get(&mut self) -> Result<[i32; 2], Error> {
+        let shift = 32 - self.bits;
+        Ok([((self.sm.get(true)? << shift) as i32) >> shift, ((self.sm.get(true)? << shift) as i32) >> shift])
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let source = match self.follower {
+            true  => follower_program(self.clocks),
+            false => rx_program(self.bits),
+        };
+        let program = pio.load_program(&PioProgram::assemble(&source)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.din)?
+            .set_fifo_join(PioFifoJoin::Rx)?
+            .set_wrap(wrap_target, wrap)?;
+        let config = match self.follower {
+            true  => config.set_jmp_pin(self.clocks + 1)?
+                           .set_in_shift(false, false, 32)?,
+            false => config.set_sideset(2, false, false)?
+                           .set_sideset_pins(self.clocks)?
+                           .set_in_shift(false, true, self.bits)?
+                           .set_clkdiv_int_frac(self.clkdiv)?,
+        };
+        if !self.follower {
+            init_clocks(&self.sm, self.clocks)?;
+        }
+        self.sm.set_pindirs_with_mask(0, 1 << self.din)?;
+        pio.pio_gpio_init(self.din as u16)?;
+        self.sm.init(program.offset() + if self.follower { 0 } else { 11 }, &config)?;
+        self.sm.clear_fifos()?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+pub struct Duplex<'a> {
+    tx: Tx<'a>,
+    rx: Rx<'a>,
+}
+
+impl<'a> Duplex<'a> {
+    pub fn new(tx_sm: StateMachine<'a>, rx_sm: StateMachine<'a>, dout: u32, din: u32, clocks: u32, sample_rate: u32)
+               -> Result<Duplex<'a>, Error> {
+        let tx = Tx::unstarted(tx_sm, dout, clocks, sample_rate)?;
+        let rx = Rx::unstarted(rx_sm, din, clocks, sample_rate, true)?;
+        let mut duplex = Duplex { tx, rx };
+        duplex.start()?;
+        Ok(duplex)
+    }
+
+    pub fn with_bits(mut self, bits: u32) -> Result<Self, Error> {
+        self.tx.set_bits(bits)?;
+        self.rx.set_bits(bits)?;
+        self.start()?;
+        Ok(self)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.tx.bits()
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.tx.sample_rate()
+    }
+
+    // (tx, rx)
+    pub fn into_inner(self) -> Result<(StateMachine<'a>, StateMachine<'a>), Error> {
+        Ok((self.tx.into_inner()?, self.rx.into_inner()?))
+    }
+
+    // Plays `write` while recording the same number of frames into `read`. Frames past the end of `write` go out as
+    // silence and frames past the end of `read` are dropped.
+    pub fn transfer(&mut self, write: &[[i32; 2]], read: &mut [[i32; 2]]) -> Result<(), Error> {
+        let len = read.len().max(write.len());
+        let depth = self.rx.sm.pio().chip().fifo_depth as usize; // Frames the joined RX FIFO holds
+        let mut sent = 0;
+        for i in 0..len {
+            while sent < len && sent - i < depth {
+                self.tx.put(write.get(sent).copied().unwrap_or_default())?;
+                sent += 1;
+            }
+            let frame = self.rx.get()?;
+            if let Some(r) = read.get_mut(i) {
+                *r = frame;
+            }
+        }
+        Ok(())
+    }
+
+    // Starts both together, Tx from the top of a frame and Rx waiting for its first bit.
+    fn start(&mut self) -> Result<(), Error> {
+        self.tx.sm.pio().sm_enable_sync(1 << self.tx.sm.index() | 1 << self.rx.sm.index())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const DOUT: u32 = 21;
+    const DIN: u32 = 20;
+    const BCLK: u32 = 18;
+    const LRCLK: u32 = 19;
+
+    // A microphone putting out the same frame over and over, noting what LRCLK was at each rising edge of BCLK. With
+    // `loopback` it just copies DOUT instead.
+    #[derive(Debug, Default)]
+    struct Mic {
+        bits: u32,
+        frame: [i32; 2],
+        bit: u32,
+        bclk: bool,
+        out: bool,
+        lrclk: Vec<bool>,
+        loopback: bool,
+    }
+
+    impl Peripheral for Mic {
+        fn step(&mut self, pins: u32, _cycle: u64) -> (u32, u32) {
+            let bclk = pins >> BCLK & 1 != 0;
+            if self.loopback {
+                self.out = pins >> DOUT & 1 != 0;
+            } else if !bclk && self.bclk {
+                let (channel, bit) = (self.bit / self.bits, self.bit % self.bits);
+                self.out = self.frame[channel as usize] >> (self.bits - 1 - bit) & 1 != 0;
+                self.bit = (self.bit + 1) % (self.bits * 2);
+            } else if bclk && !self.bclk {
+                self.lrclk.push(pins >> LRCLK & 1 != 0);
+            }
+            self.bclk = bclk;
+            ((self.out as u32) << DIN, 1 << DIN)
+        }
+    }
+
+    #[test]
+    fn capture() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let mic = Arc::new(Mutex::new(Mic { bits: 24, frame: [-1_000_000, 0x123456], bclk: true, ..Mic::default() }));
+        backend.emulator().attach(mic.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut rx = Rx::new(pio.sm_claim(0).unwrap(), DIN, BCLK, 48_000).unwrap().with_bits(24).unwrap();
+        assert!((rx.sample_rate() - 48_000.0).abs() < 10.0);
+
+        let mut frames = [[0; 2]; 4];
+        rx.read(&mut frames).unwrap();
+        assert_eq!(frames, [[-1_000_000, 0x123456]; 4]);
+        let lrclk = &mic.lock().unwrap().lrclk[..48];
+        let expected: Vec<bool> = (0..48).map(|bit| (23..47).contains(&bit)).collect();
+        assert_eq!(lrclk, expected);
+        assert!(rx.with_bits(33).is_err());
+    }
+
+    #[test]
+    fn duplex() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        backend.emulator().attach(Arc::new(Mutex::new(Mic { loopback: true, ..Mic::default() })));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut duplex = Duplex::new(pio.sm_claim(0).unwrap(), pio.sm_claim(1).unwrap(), DOUT, DIN, BCLK, 48_000).unwrap();
+
+        let write: Vec<[i32; 2]> = (0..20).map(|i| [i * 1000 - 10_000, -i]).collect();
+        let mut read = vec![[0; 2]; 20];
+        duplex.transfer(&write, &mut read).unwrap();
+        assert_eq!(read, write);
+
+        let mut duplex = duplex.with_bits(32).unwrap();
+        let write = [[i32::MIN, i32::MAX], [0x1234_5678, -2]];
+        let mut read = [[0; 2]; 2];
+        duplex.transfer(&write, &mut read).unwrap();
+        assert_eq!(read, write);
+    }
+}
@@ -0,0 +1,441 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Infrared remote control, both ways: an IR LED (through a transistor) on one pin and a 38 kHz receiver module (the
+// three legged kind, which demodulates and gives an active low output) on another, one SM each:
+//
+//     let tx = ir::Tx::new(pio.sm_claim_unused()?, 17)?;
+//     tx.send(&Frame::Nec { address: 0x04, command: 0x08 })?;
+//
+//     let mut rx = ir::Rx::new(pio.sm_claim_unused()?, 18)?;
+//     loop {
+//         match rx.receive()? {
+//             Frame::Nec { address, command }     => println!("NEC {address:#x} {command:#x}"),
+//             Frame::NecRepeat                    => println!("(held)"),
+//             Frame::Rc5 { address, command, .. } => println!("RC-5 {address} {command}"),
+//             Frame::Raw(timings)                 => println!("something else: {timings:?}"),
+//         }
+//     }
+//
+// Everything goes through lists of mark and space lengths in µs, starting with a mark (the carrier on, or the
+// receiver's output low), which is what Frame::Raw holds for anything that isn't NEC or RC-5, and what Frame::timings()
+// and Frame::decode() convert to and from.
+//
+// Tx's SM turns each one into whole periods of the carrier (38 kHz to start with, at a third duty cycle), so they
+// come out to the period however the CPU's doing. RC-5 is meant to be 36 kHz (with_carrier()), though most receivers
+// don't mind.
+//
+// Rx's SM times each mark and space to the µs and a frame ends with a space of more than 16 ms. The RX FIFO holds 16
+// of them, and an NEC frame is 67, so something has to be receiving while one comes in, and receive() is best.
+
+use std::time::Duration;
+
+use crate::{pio_clock_hz, proc_pio::PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS, ClkDiv, Error, LoadedProgram, PioFifoJoin,
+            PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_CARRIER: f64 = 12.0;
+const RX_HZ: f64 = 2_000_000.0; // 2 cycles a count, so counts are µs
+// What a space has to last for to end a frame, in µs
+const RX_LIMIT: u32 = 0x3fff;
+
+const NEC_UNIT: u32 = 562;
+const RC5_UNIT: u32 = 889;
+
+// Each word is how many carrier periods to send, less 1, then whether it's a mark in bit 0.
+const TX_PROGRAM: &str = "
+    .program ir_tx
+    .wrap_target
+    start:
+        pull
+        out y, 1
+        out x, 31
+        jmp !y space
+    mark:
+        set pins, 1         [3]
+        set pins, 0         [6]
+        jmp x-- mark
+    .wrap
+    space:
+        jmp x-- space       [11]
+        jmp start
+";
+
+// Marks are pushed as what's left of X counting down from !0, with the top bit set. Spaces are what's left of the
+// limit, under it, and 0 once it's run out and the frame's over.
+const RX_PROGRAM: &str = "
+    .program ir_rx
+        mov isr, ~null
+        in null, 14
+        mov osr, ~isr               ; The limit
+    .wrap_target
+        wait 0 pin 0
+    mark_start:
+        mov x, ~null
+    mark:
+        jmp pin mark_end
+        jmp x-- mark
+    mark_end:
+        mov isr, x
+        push noblock
+        mov x, osr
+    space:
+        jmp pin space_1
+        mov isr, x
+        push noblock
+        jmp mark_start
+    space_1:
+        jmp x-- space
+        mov isr, null
+        push noblock
+    .wrap
+";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Nec { address: u16, command: u8 }, // The address is 8 bits unless the byte after it isn't its inverse
+    NecRepeat,                         // Sent every 108 ms while the button's held
+    Rc5 { address: u8, command: u8, toggle: bool }, // 5 and 7 bits (RC-5X's). toggle flips with each press.
+    Raw(Vec<u32>),                     // µs, mark then space, ... ending with a mark
+}
+
+impl Frame {
+    // Mark and space lengths in µs, starting and ending with a mark.
+    pub fn timings(&self) -> Vec<u32> {
+        match self {
+            &Frame::Nec { address, command } => {
+                let address = if address > 0xff { address } else { address | (!address & 0xff) << 8 };
+                let bits = address as u32 | (command as u32) << 16 | (!command as u32) << 24;
+                let mut timings = vec![16 * NEC_UNIT, 8 * NEC_UNIT];
+                for bit in 0..32 {
+                    timings.extend([NEC_UNIT, if bits >> bit & 1 != 0 { 3 * NEC_UNIT } else { NEC_UNIT }]);
+                }
+                timings.push(NEC_UNIT);
+                timings
+            },
+            Frame::NecRepeat => vec![16 * NEC_UNIT, 4 * NEC_UNIT, NEC_UNIT],
+            &Frame::Rc5 { address, command, toggle } => {
+                // Bits from the top: the start bit, the field bit (command bit 6, inverted), toggle, address, command.
+                let bits = 1 << 13 | (!command as u32 >> 6 & 1) << 12 | (toggle as u32) << 11
+                         | (address as u32 & 0x1f) << 6 | command as u32 & 0x3f;
+                // A 1's a space then a mark, a 0 the other way round. The space before the first mark doesn't count.
+                let halves: Vec<bool> = (0..14).rev()
+                    .flat_map(|bit| [bits >> bit & 1 == 0, bits >> bit & 1 != 0])
+                    .collect();
+                let mut timings: Vec<u32> = halves.chunk_by(|a, b| a == b)
+                    .skip_while(|run| !run[0])
+                    .map(|run| run.len() as u32 * RC5_UNIT)
+                    .collect();
+                if halves.last() == Some(&false) {
+                    timings.pop(); // Nor does the one after the last
+                }
+                timings
+            },
+            Frame::Raw(timings) => timings.clone(),
+        }
+    }
+
+    // Recognizes NEC and RC-5 frames, allowing for the ±25% or so that receivers stretch and squash marks and spaces
+    // by. Anything else is Raw.
+    pub fn decode(timings: &[u32]) -> Frame {
+        Self::nec(timings).or_else(|| Self::rc5(timings)).unwrap_or_else(|| Frame::Raw(timings.to_vec()))
+    }
+
+    fn nec(timings: &[u32]) -> Option<Frame> {
+        let units = timings.iter().map(|&timing| units(timing, NEC_UNIT)).collect::<Option<Vec<u32>>>()?;
+        match units[..] {
+            [16, 4, 1] => Some(Frame::NecRepeat),
+            [16, 8, ref rest @ ..] if rest.len() == 65 => {
+                let mut bits = 0u32;
+                for (bit, pair) in rest.chunks(2).take(32).enumerate() {
+                    match pair {
+                        [1, 1] => {},
+                        [1, 3] => bits |= 1 << bit,
+                        _      => return None,
+                    }
+                }
+                let (address, command) = (bits as u16, (bits >> 16) as u8);
+                if (bits >> 24) as u8 != !command {
+                    return None;
+                }
+                let address = if address >> 8 == !address & 0xff { address & 0xff } else { address };
+                Some(Frame::Nec { address, command })
+            },
+            _ => None,
+        }
+    }
+
+    fn rc5(timings: &[u32]) -> Option<Frame> {
+        // Back into half bits, starting with the space before the first mark.
+        let mut halves = vec![false];
+        for (n, &timing) in timings.iter().enumerate() {
+            let units = units(timing, RC5_UNIT).filter(|&units| units <= 2)?;
+            halves.extend(std::iter::repeat_n(n % 2 == 0, units as usize));
+        }
+        if halves.len() % 2 == 1 {
+            halves.push(false); // The space a 0 at the end finishes with
+        }
+        if halves.len() != 28 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for pair in halves.chunks(2) {
+            match pair {
+                [false, true] => bits = bits << 1 | 1,
+                [true, false] => bits <<= 1,
+                _             => return None,
+            }
+        }
+        let command = (bits & 0x3f) as u8 | ((!bits >> 12 & 1) as u8) << 6;
+        Some(Frame::Rc5 { address: (bits >> 6 & 0x1f) as u8, command, toggle: bits >> 11 & 1 != 0 })
+    }
+}
+
+// How many `unit`s `timing` is, if it's within a quarter of a unit of a whole number of them.
+fn units(timing: u32, unit: u32) -> Option<u32> {
+    let units = (timing + unit / 2) / unit;
+    (units > 0 && timing.abs_diff(units * unit) <= unit / 4 * units.min(4)).then_some(units)
+}
+
+pub struct Tx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    clkdiv: ClkDiv,
+}
+
+impl<'a> Tx<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<Tx<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut tx = Tx { sm, program: None, pin, clkdiv: carrier(38_000.0)? };
+        tx.setup()?;
+        Ok(tx)
+    }
+
+    // 20 to 100 kHz.
+    pub fn with_carrier(mut self, hz: f64) -> Result<Self, Error> {
+        if !(20_000.0..=100_000.0).contains(&hz) {
+            Err(Error::ParamErr { param: "hz", should_be: "20 to 100 kHz".to_string() })?;
+        }
+        self.clkdiv = carrier(hz)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn carrier(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64) / CYCLES_PER_CARRIER
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        self.sm.set_pins_with_mask(0, 1 << self.pin)?;
+        Ok(self.sm)
+    }
+
+    // Queues the frame up and returns once the last of it's in the TX FIFO.
+    pub fn send(&self, frame: &Frame) -> Result<(), Error> {
+        self.send_raw(&frame.timings())
+    }
+
+    pub fn send_raw(&self, timings: &[u32]) -> Result<(), Error> {
+        let carrier = self.carrier();
+        for (n, &timing) in timings.iter().enumerate() {
+            let periods = ((timing as f64 * carrier / 1e6).round() as u32).max(1);
+            self.sm.put((periods - 1) << 1 | (n % 2 == 0) as u32, true)?;
+        }
+        Ok(())
+    }
+
+    // Everything has gone out once the FIFO's empty and the SM is back to stalling on its `pull`.
+    pub fn is_idle(&self) -> Result<bool, Error> {
+        let hw = self.sm.read_hw_state_machine()?;
+        let offset = self.program.as_ref().map(|program| program.offset() as u32);
+        let stalled = hw.execctrl & PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS != 0;
+        Ok(self.sm.is_tx_fifo_empty()? && Some(hw.pc) == offset && stalled)
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        while !self.is_idle()? {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(TX_PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_set_pins(self.pin, 1)?
+            .set_out_shift(true, false, 32)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pins_with_mask(0, 1 << self.pin)?; // LED off
+        self.sm.set_consecutive_pindirs(self.pin, 1, true)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+fn carrier(hz: f64) -> Result<ClkDiv, Error> {
+    ClkDiv::for_frequency(pio_clock_hz() as f64, hz * CYCLES_PER_CARRIER)
+}
+
+pub struct Rx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    timings: Vec<u32>, // The frame so far
+}
+
+impl<'a> Rx<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<Rx<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut rx = Rx { sm, program: None, pin, timings: vec![] };
+        rx.setup()?;
+        Ok(rx)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Waits for the next frame.
+    pub fn receive(&mut self) -> Result<Frame, Error> {
+        loop {
+            let word = self.sm.get(true)?;
+            if let Some(frame) = self.received(word) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    // Returns None instead of waiting when no frame has finished.
+    pub fn try_receive(&mut self) -> Result<Option<Frame>, Error> {
+        while !self.sm.is_rx_fifo_empty()? {
+            let word = self.sm.get(false)?;
+            if let Some(frame) = self.received(word) {
+                return Ok(Some(frame));
+            }
+        }
+        Ok(None)
+    }
+
+    fn received(&mut self, word: u32) -> Option<Frame> {
+        match word {
+            0 if !self.timings.is_empty() => return Some(Frame::decode(&std::mem::take(&mut self.timings))),
+            0 => {},
+            word if word >> 31 != 0 => self.timings.push(!word),
+            word if !self.timings.is_empty() => self.timings.push(RX_LIMIT - word),
+            _ => {},
+        }
+        None
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(RX_PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.pin)?
+            .set_jmp_pin(self.pin)?
+            .set_in_shift(false, false, 32)?
+            .set_fifo_join(PioFifoJoin::Rx)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, RX_HZ)?)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_consecutive_pindirs(self.pin, 1, false)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        self.timings.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const TX: u32 = 5;
+    const RX: u32 = 6;
+    const US: u64 = 200; // Cycles
+
+    // A receiver module: its output goes low while there's carrier on TX (give or take a period), or plays back
+    // timings given to it directly.
+    #[derive(Debug, Default)]
+    struct Receiver {
+        carrier: Option<u64>, // When TX was last high
+        rises: Vec<u64>,
+        playing: Vec<(u64, u64)>, // Marks, in cycles
+    }
+
+    impl Peripheral for Receiver {
+        fn step(&mut self, pins: u32, now: u64) -> (u32, u32) {
+            if pins >> TX & 1 != 0 {
+                if self.carrier.is_none_or(|at| at + 1 < now) {
+                    self.rises.push(now);
+                }
+                self.carrier = Some(now);
+            }
+            let carrier = self.carrier.is_some_and(|at| now - at < 30 * US);
+            let playing = self.playing.iter().any(|&(start, end)| (start..end).contains(&now));
+            (((!carrier && !playing) as u32) << RX, 1 << RX)
+        }
+    }
+
+    #[test]
+    fn frames() {
+        for frame in [Frame::Nec { address: 0x04, command: 0x08 }, Frame::Nec { address: 0x1234, command: 0xff },
+                      Frame::NecRepeat, Frame::Rc5 { address: 5, command: 35, toggle: true },
+                      Frame::Rc5 { address: 0, command: 64, toggle: false }, Frame::Raw(vec![100, 200, 300])] {
+            assert_eq!(Frame::decode(&frame.timings()), frame);
+        }
+
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(100_000_000);
+        let receiver = Arc::new(Mutex::new(Receiver::default()));
+        backend.emulator().attach(receiver.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let tx = Tx::new(pio.sm_claim(0).unwrap(), TX).unwrap();
+        let mut rx = Rx::new(pio.sm_claim(1).unwrap(), RX).unwrap();
+
+        // 600 µs of carrier is 23 periods of it, 26.3 µs apart (300 µs is 11), and the receiver makes that a mark.
+        tx.send_raw(&[600, 600, 300]).unwrap();
+        let Frame::Raw(timings) = rx.receive().unwrap() else { panic!() };
+        let rises = receiver.lock().unwrap().rises.clone();
+        assert_eq!(rises.len(), 23 + 11);
+        assert!(rises.windows(2).take(22).all(|w| (w[1] - w[0]).abs_diff(5263) <= 1), "{rises:?}");
+        assert_eq!(timings.len(), 3);
+        assert!(timings.iter().zip([600, 600, 300]).all(|(&timing, sent)| timing.abs_diff(sent) <= 30), "{timings:?}");
+
+        // A receiver's idea of an NEC frame, marks stretched by 50 µs.
+        let start = backend.emulator().cycle() + 1000;
+        let mut at = start;
+        let mut playing = vec![];
+        let frame = Frame::Nec { address: 0x04, command: 0x08 };
+        for (n, timing) in frame.timings().into_iter().enumerate() {
+            let (timing, stretch) = (timing as u64 * US, if n % 2 == 0 { 50 * US } else { 0 });
+            if n % 2 == 0 {
+                playing.push((at, at + timing + stretch));
+            }
+            at += timing;
+        }
+        receiver.lock().unwrap().playing = playing;
+        assert_eq!(rx.receive().unwrap(), frame);
+        assert!(tx.with_carrier(10_000.0).is_err());
+    }
+}
@@ -0,0 +1,427 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A JTAG adapter on any four pins, for boundary scan, reading IDCODEs and loading FPGAs:
+//
+//     let mut jtag = Jtag::new(pio.sm_claim_unused()?, 20, 21, 22, 23)?   // tck, tms, tdi, tdo
+//         .with_frequency(10_000_000.0)?;
+//     println!("{:08x?}", jtag.idcodes(8)?);
+//     jtag.scan_ir(6, &[0x05])?;                  // CFG_IN, on a Xilinx 7 series
+//     jtag.write_dr(bitstream.len() * 8, &bitstream)?;
+//
+// Scans go LSB first (bit n is bits[n / 8] >> n % 8), start and end in Run-Test/Idle, and return what came back on TDO
+// in the same layout. The TAP's state is tracked here, so goto() takes the shortest TMS path to wherever it's asked.
+//
+// Each SM operation is a header word (bits - 1 in the low 30 bits, then TMS for all but the last bit, then TMS for the
+// last one) followed by the TDI bits, 32 to a word. TDO comes back the same way, with a final push for whatever didn't
+// fill a word, so an operation of n bits always returns n / 32 + 1 words. Long scans stream: the TX FIFO is kept fed
+// while TDO is read back, so a bitstream doesn't have to fit anywhere.
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 4.0;
+
+const PROGRAM: &str = "
+    .program jtag
+    .side_set 1 opt
+    .wrap_target
+        pull                side 0
+        out x, 30                       ; Bits - 1
+        out y, 1                        ; TMS for all but the last bit
+        jmp !y tms_low
+        set pins, 1
+        jmp last_tms
+    tms_low:
+        set pins, 0
+    last_tms:
+        out y, 1                        ; TMS for the last bit
+        jmp !x last
+        jmp x-- body
+    body:
+        jmp !osre tdi       side 0
+        pull                side 0
+    tdi:
+        out pins, 1         side 0
+        in pins, 1          side 1
+        jmp x-- body        side 1
+    last:
+        jmp !osre last_tdi  side 0
+        pull                side 0
+    last_tdi:
+        out pins, 1         side 0
+        jmp !y last_low     side 0
+        set pins, 1         side 0
+        jmp clock           side 0
+    last_low:
+        set pins, 0         side 0
+    clock:
+        in pins, 1          side 1
+        push                side 1
+    .wrap
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum State {
+    TestLogicReset,
+    RunTestIdle,
+    SelectDrScan,
+    CaptureDr,
+    ShiftDr,
+    Exit1Dr,
+    PauseDr,
+    Exit2Dr,
+    UpdateDr,
+    SelectIrScan,
+    CaptureIr,
+    ShiftIr,
+    Exit1Ir,
+    PauseIr,
+    Exit2Ir,
+    UpdateIr,
+}
+
+impl State {
+    const ALL: [State; 16] = [State::TestLogicReset, State::RunTestIdle, State::SelectDrScan, State::CaptureDr,
+                              State::ShiftDr, State::Exit1Dr, State::PauseDr, State::Exit2Dr, State::UpdateDr,
+                              State::SelectIrScan, State::CaptureIr, State::ShiftIr, State::Exit1Ir, State::PauseIr,
+                              State::Exit2Ir, State::UpdateIr];
+
+    // Where a TCK with this TMS takes the TAP.
+    pub fn next(self, tms: bool) -> State {
+        use State::*;
+        match (self, tms) {
+            (TestLogicReset, false) => RunTestIdle,    (TestLogicReset, true) => TestLogicReset,
+            (RunTestIdle, false)    => RunTestIdle,    (RunTestIdle, true)    => SelectDrScan,
+            (SelectDrScan, false)   => CaptureDr,      (SelectDrScan, true)   => SelectIrScan,
+            (CaptureDr, false)      => ShiftDr,        (CaptureDr, true)      => Exit1Dr,
+            (ShiftDr, false)        => ShiftDr,        (ShiftDr, true)        => Exit1Dr,
+            (Exit1Dr, false)        => PauseDr,        (Exit1Dr, true)        => UpdateDr,
+            (PauseDr, false)        => PauseDr,        (PauseDr, true)        => Exit2Dr,
+            (Exit2Dr, false)        => ShiftDr,        (Exit2Dr, true)        => UpdateDr,
+            (UpdateDr, false)       => RunTestIdle,    (UpdateDr, true)       => SelectDrScan,
+            (SelectIrScan, false)   => CaptureIr,      (SelectIrScan, true)   => TestLogicReset,
+            (CaptureIr, false)      => ShiftIr,        (CaptureIr, true)      => Exit1Ir,
+            (ShiftIr, false)        => ShiftIr,        (ShiftIr, true)        => Exit1Ir,
+            (Exit1Ir, false)        => PauseIr,        (Exit1Ir, true)        => UpdateIr,
+            (PauseIr, false)        => PauseIr,        (PauseIr, true)        => Exit2Ir,
+            (Exit2Ir, false)        => ShiftIr,        (Exit2Ir, true)        => UpdateIr,
+            (UpdateIr, false)       => RunTestIdle,    (UpdateIr, true)       => SelectDrScan,
+        }
+    }
+
+    // The shortest TMS sequence from here to `to`, found breadth first.
+    pub fn path(self, to: State) -> Vec<bool> {
+        let mut paths: Vec<Option<Vec<bool>>> = vec![None; State::ALL.len()];
+        paths[self as usize] = Some(Vec::new());
+        let mut queue = std::collections::VecDeque::from([self]);
+        while let Some(state) = queue.pop_front() {
+            if state == to {
+                break;
+            }
+            for tms in [false, true] {
+                let next = state.next(tms);
+                if paths[next as usize].is_none() {
+                    let mut path = paths[state as usize].clone().expect("queued states have paths");
+                    path.push(tms);
+                    paths[next as usize] = Some(path);
+                    queue.push_back(next);
+                }
+            }
+        }
+        paths[to as usize].take().expect("every state is reachable")
+    }
+}
+
+pub struct Jtag<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    tck: u32,
+    tms: u32,
+    tdi: u32,
+    tdo: u32,
+    clkdiv: ClkDiv,
+    state: State,
+}
+
+impl<'a> Jtag<'a> {
+    // Starts out at 1 MHz, with the TAP reset and then in Run-Test/Idle.
+    pub fn new(sm: StateMachine<'a>, tck: u32, tms: u32, tdi: u32, tdo: u32) -> Result<Jtag<'a>, Error> {
+        for pin in [tck, tms, tdi, tdo] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, 1_000_000.0 * CYCLES_PER_BIT)?;
+        let mut jtag = Jtag { sm, program: None, tck, tms, tdi, tdo, clkdiv, state: State::TestLogicReset };
+        jtag.setup()?;
+        jtag.reset()?;
+        jtag.goto(State::RunTestIdle)?;
+        Ok(jtag)
+    }
+
+    pub fn with_frequency(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz * CYCLES_PER_BIT)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    // The TCK rate actually achievable with the SM's clock divider.
+    pub fn frequency(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64) / CYCLES_PER_BIT
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Five TCKs with TMS high get to Test-Logic-Reset from anywhere, whatever we thought the state was.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.shift(5, true, true, &[], None)?;
+        self.state = State::TestLogicReset;
+        Ok(())
+    }
+
+    pub fn goto(&mut self, state: State) -> Result<(), Error> {
+        let path = self.state.path(state);
+        for run in path.chunk_by(|a, b| a == b) {
+            self.shift(run.len(), run[0], run[0], &[], None)?;
+        }
+        Ok(())
+    }
+
+    // Clocks TCK in Run-Test/Idle, which some operations (FPGA startup, flash programming) need time in.
+    pub fn run_test(&mut self, clocks: usize) -> Result<(), Error> {
+        self.goto(State::RunTestIdle)?;
+        self.shift(clocks, false, false, &[], None)
+    }
+
+    // Shifts `bits` bits through the instruction register(s), returning what they held.
+    pub fn scan_ir(&mut self, bits: usize, tdi: &[u8]) -> Result<Vec<u8>, Error> {
+        self.scan(State::ShiftIr, bits, tdi, true)
+    }
+
+    // Shifts `bits` bits through the data register(s) the instructions selected, returning what they held.
+    pub fn scan_dr(&mut self, bits: usize, tdi: &[u8]) -> Result<Vec<u8>, Error> {
+        self.scan(State::ShiftDr, bits, tdi, true)
+    }
+
+    // scan_dr() without keeping TDO, for big writes like bitstreams.
+    pub fn write_dr(&mut self, bits: usize, tdi: &[u8]) -> Result<(), Error> {
+        self.scan(State::ShiftDr, bits, tdi, false).map(|_| ())
+    }
+
+    // Resets the chain and reads out each device's IDCODE, from the one nearest TDO. Devices without one load a single 0
+    // bit into their bypass register instead, and show up as None. Stops at the end of the chain (where the 1s going in
+    // come out) or after `max` devices.
+    pub fn idcodes(&mut self, max: usize) -> Result<Vec<Option<u32>>, Error> {
+        self.reset()?;
+        let bits = (max + 1) * 32;
+        let tdo = self.scan(State::ShiftDr, bits, &vec![0xff; bits / 8], true)?;
+        let bit = |n: usize| tdo[n / 8] >> (n % 8) & 1 != 0;
+        let (mut ids, mut n) = (Vec::new(), 0);
+        while ids.len() < max && n + 32 <= bits {
+            if !bit(n) {
+                ids.push(None);
+                n += 1;
+                continue;
+            }
+            let id = (0..32).fold(0, |id, i| id | (bit(n + i) as u32) << i);
+            if id == !0 {
+                break;
+            }
+            ids.push(Some(id));
+            n += 32;
+        }
+        Ok(ids)
+    }
+
+    fn scan(&mut self, shift_state: State, bits: usize, tdi: &[u8], keep: bool) -> Result<Vec<u8>, Error> {
+        self.goto(shift_state)?;
+        let mut tdo = Vec::new();
+        self.shift(bits, false, true, tdi, keep.then_some(&mut tdo))?;
+        self.goto(State::RunTestIdle)?;
+        Ok(tdo)
+    }
+
+    // One SM operation: `bits` TCKs, TMS at `body_tms` for all but the last. TDI past the end of `tdi` is 0.
+    fn shift(&mut self, bits: usize, body_tms: bool, last_tms: bool, tdi: &[u8], mut tdo: Option<&mut Vec<u8>>) -> Result<(), Error> {
+        if bits == 0 {
+            return Ok(());
+        }
+        if bits > 1 << 30 {
+            Err(Error::ParamErr { param: "bits", should_be: "at most 2^30".to_string() })?;
+        }
+        let header = (bits as u32 - 1) | (body_tms as u32) << 30 | (last_tms as u32) << 31;
+        let word = |n: usize| {
+            let mut bytes = [0; 4];
+            for (b, &byte) in bytes.iter_mut().zip(tdi.iter().skip(n * 4)) {
+                *b = byte;
+            }
+            u32::from_le_bytes(bytes)
+        };
+        let (to_send, to_receive) = (1 + bits.div_ceil(32), bits / 32 + 1);
+        let (mut sent, mut received) = (0, 0);
+        while received < to_receive {
+            if sent < to_send && !self.sm.is_tx_fifo_full()? {
+                self.sm.put(if sent == 0 { header } else { word(sent - 1) }, false)?;
+                sent += 1;
+                continue;
+            }
+            let word = self.sm.get(true)?;
+            received += 1;
+            let valid = if received == to_receive { bits % 32 } else { 32 };
+            if let Some(tdo) = tdo.as_mut() && valid > 0 {
+                tdo.extend(&(word >> (32 - valid)).to_le_bytes()[..valid.div_ceil(8)]);
+            }
+        }
+        // Runs of the same TMS settle within a few clocks, so there's no need to walk all of them.
+        for _ in 0..(bits - 1).min(8) {
+            self.state = self.state.next(body_tms);
+        }
+        self.state = self.state.next(last_tms);
+        Ok(())
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.tdi, 1)?
+            .set_set_pins(self.tms, 1)?
+            .set_in_pins(self.tdo)?
+            .set_sideset(2, true, false)?
+            .set_sideset_pins(self.tck)?
+            .set_out_shift(true, false, 32)?
+            .set_in_shift(true, true, 32)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        let outputs = 1 << self.tck | 1 << self.tms | 1 << self.tdi;
+        self.sm.set_pins_with_mask(1 << self.tms, outputs)?;
+        self.sm.set_pindirs_with_mask(outputs, outputs | 1 << self.tdo)?;
+        for pin in [self.tck, self.tms, self.tdi, self.tdo] {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const TCK: u32 = 20;
+    const TMS: u32 = 21;
+    const TDI: u32 = 22;
+    const TDO: u32 = 23;
+
+    const IDCODE: u32 = 0x0362_d093;
+    const IR_IDCODE: u64 = 0b0001;
+    const IR_USER: u64 = 0b0010;
+
+    // One device with a 4 bit IR, an IDCODE and a 16 bit user register.
+    #[derive(Debug)]
+    struct Tap {
+        state: State,
+        tck: bool,
+        ir: u64,
+        user: u64,
+        shift: u64,
+        len: u32,
+        tdo: bool,
+    }
+
+    impl Tap {
+        fn capture(&mut self) {
+            (self.shift, self.len) = match self.state {
+                State::CaptureIr => (0b0001, 4),
+                _ => match self.ir {
+                    IR_IDCODE => (IDCODE as u64, 32),
+                    IR_USER   => (self.user, 16),
+                    _         => (0, 1), // Bypass
+                },
+            };
+        }
+    }
+
+    impl Peripheral for Tap {
+        fn step(&mut self, pins: u32, _cycle: u64) -> (u32, u32) {
+            let (tck, tms, tdi) = (pins >> TCK & 1 != 0, pins >> TMS & 1 != 0, pins >> TDI & 1);
+            if tck && !self.tck {
+                match self.state {
+                    State::CaptureDr | State::CaptureIr => self.capture(),
+                    State::ShiftDr | State::ShiftIr     => self.shift = self.shift >> 1 | (tdi as u64) << (self.len - 1),
+                    _                                   => {},
+                }
+                self.state = self.state.next(tms);
+                if self.state == State::TestLogicReset {
+                    self.ir = IR_IDCODE;
+                }
+            }
+            if !tck && self.tck {
+                match self.state {
+                    State::ShiftDr | State::ShiftIr        => self.tdo = self.shift & 1 != 0,
+                    State::UpdateIr                        => self.ir = self.shift,
+                    State::UpdateDr if self.ir == IR_USER  => self.user = self.shift,
+                    _                                      => {},
+                }
+            }
+            self.tck = tck;
+            ((self.tdo as u32) << TDO, 1 << TDO)
+        }
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(State::TestLogicReset.path(State::RunTestIdle), [false]);
+        assert_eq!(State::RunTestIdle.path(State::ShiftIr), [true, true, false, false]);
+        assert_eq!(State::Exit1Dr.path(State::RunTestIdle), [true, false]);
+        assert!(State::PauseDr.path(State::PauseDr).is_empty());
+        for from in State::ALL {
+            for to in State::ALL {
+                assert_eq!(from.path(to).into_iter().fold(from, State::next), to);
+            }
+        }
+    }
+
+    #[test]
+    fn scans() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let tap = Arc::new(Mutex::new(Tap { state: State::RunTestIdle, tck: false, ir: 0, user: 0, shift: 0, len: 1, tdo: false }));
+        backend.emulator().attach(tap.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut jtag = Jtag::new(pio.sm_claim(0).unwrap(), TCK, TMS, TDI, TDO).unwrap();
+        assert_eq!(jtag.state(), State::RunTestIdle);
+        assert_eq!(tap.lock().unwrap().state, State::RunTestIdle);
+
+        assert_eq!(jtag.idcodes(4).unwrap(), [Some(IDCODE)]);
+        // The IR captures 0b0001, whatever it's being loaded with.
+        assert_eq!(jtag.scan_ir(4, &[IR_USER as u8]).unwrap(), [0b0001]);
+        assert_eq!(jtag.scan_dr(16, &[0x34, 0x12]).unwrap(), [0, 0]);
+        assert_eq!(tap.lock().unwrap().user, 0x1234);
+        jtag.write_dr(16, &[0xcd, 0xab]).unwrap();
+        assert_eq!(jtag.scan_dr(16, &[0, 0]).unwrap(), [0xcd, 0xab]);
+        // Bypass is one bit, so TDO is TDI a clock late.
+        jtag.scan_ir(4, &[0x0f]).unwrap();
+        assert_eq!(jtag.scan_dr(9, &[0xff, 0x00]).unwrap(), [0xfe, 0x01]);
+        // Long enough to go round the FIFOs a few times.
+        let data: Vec<u8> = (0..200).map(|n| (n * 37) as u8).collect();
+        let late: Vec<u8> = (0..200).map(|n| data[n] << 1 | if n > 0 { data[n - 1] >> 7 } else { 0 }).collect();
+        assert_eq!(jtag.scan_dr(1600, &data).unwrap(), late);
+        jtag.run_test(100).unwrap();
+        assert_eq!((jtag.state(), tap.lock().unwrap().state), (State::RunTestIdle, State::RunTestIdle));
+    }
+}
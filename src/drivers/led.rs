@@ -0,0 +1,225 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// What the addressable LED drivers (ws2812 and apa102) have in common. Code that only wants to put colours on a strip
+// can take any LedStrip:
+//
+//     fn rainbow(strip: &mut impl LedStrip, n: usize) -> Result<(), Error> {
+//         strip.write(&(0..n).map(|i| RGB8::new((i * 255 / n) as u8, 0, 255 - (i * 255 / n) as u8)).collect::<Vec<_>>())
+//     }
+//
+// A Framebuffer goes on top of a strip and does what every LED project ends up wanting: gamma correction (so that 128
+// looks half as bright as 255), a global brightness, and temporal dithering to get back the dim end of the range that
+// gamma correction squashes into a handful of levels. You draw into it and present() what you've drawn:
+//
+//     let mut fb = Framebuffer::new(Ws2812::new(pio.sm_claim_unused()?, 18)?, 60)
+//         .with_brightness(64)?;
+//     fb.fill(RGB8::new(10, 0, 20));
+//     fb.present()?;
+//     loop { fb.refresh()?; }   // Keep dithering
+//
+// The dithering only works if frames keep going out, so call refresh() (or present()) a hundred or more times a second
+// while the picture is supposed to be showing. Drawing doesn't disturb what refresh() is sending until the next
+// present().
+
+pub use rgb::RGB8;
+
+use crate::{Error, StateMachine, XferDir};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RGBW8 {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub w: u8,
+}
+
+impl RGBW8 {
+    pub const fn new(r: u8, g: u8, b: u8, w: u8) -> RGBW8 {
+        RGBW8 { r, g, b, w }
+    }
+}
+
+impl From<RGB8> for RGBW8 {
+    fn from(rgb: RGB8) -> RGBW8 {
+        RGBW8::new(rgb.r, rgb.g, rgb.b, 0)
+    }
+}
+
+pub trait LedStrip {
+    // Sends a whole frame, first pixel nearest the controller.
+    fn write(&mut self, pixels: &[RGB8]) -> Result<(), Error>;
+    // Waits until the strip is showing the last frame written.
+    fn flush(&mut self) -> Result<(), Error>;
+}
+
+pub struct Framebuffer<S: LedStrip> {
+    strip: S,
+    back: Vec<RGB8>,
+    front: Vec<RGB8>,
+    gamma: [u16; 256], // 8.8 fixed point
+    brightness: u8,
+    dither: bool,
+    error: Vec<[u16; 3]>,
+    out: Vec<RGB8>,
+}
+
+impl<S: LedStrip> Framebuffer<S> {
+    // Starts out with a gamma of 2.2, full brightness and dithering on.
+    pub fn new(strip: S, len: usize) -> Framebuffer<S> {
+        let mut fb = Framebuffer { strip, back: vec![RGB8::default(); len], front: vec![RGB8::default(); len], gamma: [0; 256],
+                                   brightness: 255, dither: true, error: vec![[0; 3]; len], out: Vec::with_capacity(len) };
+        fb.set_gamma(2.2).expect("2.2 is a fine gamma");
+        fb
+    }
+
+    pub fn with_gamma(mut self, gamma: f64) -> Result<Self, Error> {
+        self.set_gamma(gamma)?;
+        Ok(self)
+    }
+
+    pub fn with_brightness(mut self, brightness: u8) -> Result<Self, Error> {
+        self.set_brightness(brightness);
+        Ok(self)
+    }
+
+    pub fn with_dithering(mut self, dither: bool) -> Result<Self, Error> {
+        self.dither = dither;
+        Ok(self)
+    }
+
+    // 1.0 turns gamma correction off.
+    pub fn set_gamma(&mut self, gamma: f64) -> Result<(), Error> {
+        if !(gamma > 0.0 && gamma <= 10.0) {
+            Err(Error::ParamErr { param: "gamma", should_be: "0.0 < gamma <= 10.0".to_string() })?;
+        }
+        for (i, level) in self.gamma.iter_mut().enumerate() {
+            *level = ((i as f64 / 255.0).powf(gamma) * 255.0 * 256.0).round() as u16;
+        }
+        Ok(())
+    }
+
+    // Scales everything after gamma correction, so it dims evenly.
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    pub fn len(&self) -> usize {
+        self.back.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.back.is_empty()
+    }
+
+    // The back buffer, which is what gets drawn on.
+    pub fn pixels(&self) -> &[RGB8] {
+        &self.back
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [RGB8] {
+        &mut self.back
+    }
+
+    pub fn fill(&mut self, color: RGB8) {
+        self.back.fill(color);
+    }
+
+    pub fn clear(&mut self) {
+        self.fill(RGB8::default());
+    }
+
+    // Shows what's been drawn.
+    pub fn present(&mut self) -> Result<(), Error> {
+        self.front.copy_from_slice(&self.back);
+        self.refresh()
+    }
+
+    // Sends the last present()ed picture again, dithered differently.
+    pub fn refresh(&mut self) -> Result<(), Error> {
+        self.out.clear();
+        for (pixel, error) in self.front.iter().zip(self.error.iter_mut()) {
+            let mut out = [0; 3];
+            for (c, (&value, error)) in [pixel.r, pixel.g, pixel.b].iter().zip(error.iter_mut()).enumerate() {
+                let level = self.gamma[value as usize] as u32 * self.brightness as u32 / 255;
+                out[c] = if self.dither {
+                    let level = level + *error as u32;
+                    let out = (level >> 8).min(255);
+                    *error = (level - (out << 8)).min(255) as u16;
+                    out as u8
+                } else {
+                    ((level + 128) >> 8).min(255) as u8
+                };
+            }
+            self.out.push(RGB8::new(out[0], out[1], out[2]));
+        }
+        self.strip.write(&self.out)
+    }
+
+    pub fn strip(&mut self) -> &mut S {
+        &mut self.strip
+    }
+
+    pub fn into_inner(self) -> S {
+        self.strip
+    }
+}
+
+// Queues a frame's words, through the FIFO if they fit and streamed with sm_xfer_data() if not. The SM's TX FIFO has
+// to be joined.
+pub(crate) fn queue(sm: &StateMachine, words: &[u32]) -> Result<(), Error> {
+    let pio = sm.pio();
+    if words.len() <= pio.chip().fifo_depth as usize * 2 {
+        for &word in words {
+            sm.put(word, true)?;
+        }
+    } else {
+        let bytes = size_of_val(words) as u32;
+        pio.sm_config_xfer(sm.index(), XferDir::ToSm, bytes.min(64 * 1024), 4)?;
+        pio.sm_xfer_data(sm.index(), XferDir::ToSm, bytes, &words[0])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Frames(Vec<Vec<RGB8>>);
+
+    impl LedStrip for Frames {
+        fn write(&mut self, pixels: &[RGB8]) -> Result<(), Error> {
+            self.0.push(pixels.to_vec());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn gamma_brightness_and_dithering() {
+        let mut fb = Framebuffer::new(Frames::default(), 2).with_dithering(false).unwrap();
+        fb.pixels_mut().copy_from_slice(&[RGB8::new(255, 128, 0), RGB8::new(1, 64, 192)]);
+        fb.present().unwrap();
+        assert_eq!(fb.strip().0[0], [RGB8::new(255, 56, 0), RGB8::new(0, 12, 137)]);
+
+        let mut fb = Framebuffer::new(Frames::default(), 1).with_gamma(1.0).unwrap().with_brightness(128).unwrap();
+        fb.fill(RGB8::new(255, 3, 1));
+        fb.present().unwrap();
+        fb.clear(); // Doesn't show until the next present()
+        for _ in 0..99 {
+            fb.refresh().unwrap();
+        }
+        // 1 at half brightness is on every other frame, 3 is on 1.5 on average.
+        let sums = fb.strip().0.iter().fold([0; 3], |sum, f| [sum[0] + f[0].r as u32, sum[1] + f[0].g as u32, sum[2] + f[0].b as u32]);
+        assert_eq!(sums, [12800, 150, 50]);
+        assert!(fb.with_gamma(0.0).is_err());
+    }
+}
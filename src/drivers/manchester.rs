@@ -0,0 +1,351 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Manchester and biphase mark line codes, where the clock goes along with the data on the one line, for RFID tags,
+// radio modules, S/PDIF-like links and home-grown protocols, one SM each way:
+//
+//     let tx = manchester::Tx::new(pio.sm_claim_unused()?, 5, 100_000, Encoding::Manchester)?;
+//     tx.send(b"hello")?;
+//
+//     let mut rx = manchester::Rx::new(pio.sm_claim_unused()?, 6, 100_000, Encoding::Manchester)?;
+//     println!("{:?}", rx.receive()?);
+//
+// Both encodings have an edge in every bit, and that's what Rx's SM keeps its clock by: it samples the line 3/4 of a
+// bit after each edge it takes as a clock edge and then waits for the next one, so the two ends don't have to agree
+// on the bit rate very closely at all (it'll follow one 15% out). It starts off on the first edge after the line's
+// been quiet, which is only right if the frame starts the right way (Manchester frames can start on either kind of
+// edge), but it gets back in step as soon as two bits differ, and a frame ends when the line's been quiet for more
+// than 1 1/2 bits. The SM only does the samples; which bits they make is up to the CPU, so anything else with a clock
+// edge in every bit can go in Encoding too.
+//
+// The framing's the usual kind, also done by the CPU: a preamble of alternating bits (16 of them unless
+// Tx::with_preamble() says otherwise) for a receiver to settle on, a sync word (the 16 bit 0x2dd4 to start with) that
+// says where the data starts, then the data, most significant bit first, and the line goes back to low. Rx hunts for
+// the sync word, so it doesn't matter how much of the preamble it got. receive_bits() and send_bits() leave all that
+// out, for protocols with framing of their own.
+
+use std::time::Duration;
+
+use crate::{pio_clock_hz, proc_pio::PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS, ClkDiv, Error, LoadedProgram, PioFifoJoin,
+            PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: u32 = 32;
+const SYNC: (u32, u32) = (0x2dd4, 16);
+
+// Half a bit at a time.
+const TX_PROGRAM: &str = "
+    .program manchester_tx
+    .wrap_target
+        out pins, 1         [15]
+    .wrap
+";
+
+// Samples are pushed 31 to a word, with a 1 above them so a frame's last word says how many it has, then a 0 when the
+// line goes quiet. Y counts the samples in the word and X times the wait for each edge.
+const RX_PROGRAM: &str = "
+    .program manchester_rx
+    quiet:
+        push noblock
+        push noblock
+    .wrap_target
+    start:
+        set x, 1
+        mov isr, x
+        set y, 31
+        jmp pin start_high
+        wait 1 pin 0
+        jmp edge
+    start_high:
+        wait 0 pin 0
+    edge:
+        jmp y-- not_full    [15]
+        push noblock
+        set x, 1
+        mov isr, x
+        set y, 30           [2]     ; Counting the one about to go in
+    sample:                         ; 3/4 of a bit after the edge, give or take
+        in pins, 1
+        jmp pin wait_fall
+        set x, 15
+    wait_rise:
+        jmp pin edge
+        jmp x-- wait_rise
+        jmp quiet
+    wait_fall:
+        set x, 15
+    wait_fall_1:
+        jmp pin wait_fall_2
+        jmp edge
+    wait_fall_2:
+        jmp x-- wait_fall_1
+        jmp quiet
+    not_full:
+        jmp sample          [5]
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Manchester,         // IEEE 802.3's: a 1 goes from low to high in the middle and a 0 from high to low
+    ManchesterInverted, // G. E. Thomas's, the other way round
+    BiphaseMark,        // An edge at the start of every bit and another in the middle of a 1, whichever way up
+}
+
+impl Encoding {
+    // The line's level for each half of each bit, starting from low.
+    fn halves(&self, bits: &[bool]) -> Vec<bool> {
+        let mut level = false;
+        bits.iter().flat_map(|&bit| match self {
+            Encoding::Manchester         => [!bit, bit],
+            Encoding::ManchesterInverted => [bit, !bit],
+            Encoding::BiphaseMark        => {
+                level = !level;
+                let first = level;
+                level ^= bit;
+                [first, level]
+            },
+        }).collect()
+    }
+
+    // The bits in the SM's samples. A Manchester sample is the first half of the bit after the edge it was timed from
+    // (the one in the middle of the bit before). A biphase mark one is the second half of the bit the edge started, so
+    // it takes the one before to tell whether the line changed in the middle.
+    fn bits(&self, samples: &[bool]) -> Vec<bool> {
+        match self {
+            Encoding::Manchester         => samples.iter().map(|&sample| !sample).collect(),
+            Encoding::ManchesterInverted => samples.to_vec(),
+            Encoding::BiphaseMark        => samples.windows(2).map(|pair| pair[0] == pair[1]).collect(),
+        }
+    }
+}
+
+fn to_bits(value: u32, bits: u32) -> impl Iterator<Item = bool> {
+    (0..bits).rev().map(move |bit| value >> bit & 1 != 0)
+}
+
+pub struct Tx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    encoding: Encoding,
+    preamble: u32,
+    sync: (u32, u32),
+}
+
+impl<'a> Tx<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32, bit_rate: u32, encoding: Encoding) -> Result<Tx<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut tx = Tx { sm, program: None, pin, encoding, preamble: 16, sync: SYNC };
+        tx.setup(bit_rate)?;
+        Ok(tx)
+    }
+
+    // How many alternating bits send() starts with.
+    pub fn with_preamble(mut self, bits: u32) -> Result<Self, Error> {
+        if bits > 1024 {
+            Err(Error::ParamErr { param: "bits", should_be: "up to 1024".to_string() })?;
+        }
+        self.preamble = bits;
+        Ok(self)
+    }
+
+    // The bottom `bits` of `pattern` go between the preamble and the data.
+    pub fn with_sync(mut self, pattern: u32, bits: u32) -> Result<Self, Error> {
+        if !(1..=32).contains(&bits) {
+            Err(Error::ParamErr { param: "bits", should_be: "1..=32".to_string() })?;
+        }
+        self.sync = (pattern, bits);
+        Ok(self)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        self.sm.set_pins_with_mask(0, 1 << self.pin)?;
+        Ok(self.sm)
+    }
+
+    // Sends a whole frame: the preamble, the sync word and `data`. Returns once the last of it's in the TX FIFO.
+    pub fn send(&self, data: &[u8]) -> Result<(), Error> {
+        let bits: Vec<bool> = (0..self.preamble).map(|n| n % 2 == 0)
+            .chain(to_bits(self.sync.0, self.sync.1))
+            .chain(data.iter().flat_map(|&byte| to_bits(byte as u32, 8)))
+            .collect();
+        self.send_bits(&bits)
+    }
+
+    // Just the bits, with nothing around them.
+    pub fn send_bits(&self, bits: &[bool]) -> Result<(), Error> {
+        let mut halves = self.encoding.halves(bits);
+        halves.resize((halves.len() / 32 + 1) * 32, false); // Back to low, for at least half a bit
+        for word in halves.chunks(32) {
+            self.sm.put(word.iter().fold(0, |word, &half| word << 1 | half as u32), true)?;
+        }
+        Ok(())
+    }
+
+    // Everything has gone out once the FIFO's empty and the SM is stalled waiting for more.
+    pub fn is_idle(&self) -> Result<bool, Error> {
+        let hw = self.sm.read_hw_state_machine()?;
+        let stalled = hw.execctrl & PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS != 0;
+        Ok(self.sm.is_tx_fifo_empty()? && stalled)
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        while !self.is_idle()? {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    fn setup(&mut self, bit_rate: u32) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(TX_PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let hz = bit_rate as f64 * CYCLES_PER_BIT as f64;
+        let config = SmConfig::default()
+            .set_out_pins(self.pin, 1)?
+            .set_out_shift(false, true, 32)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, hz)?)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pins_with_mask(0, 1 << self.pin)?;
+        self.sm.set_consecutive_pindirs(self.pin, 1, true)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+pub struct Rx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    encoding: Encoding,
+    sync: (u32, u32),
+    samples: Vec<bool>, // Of the frame so far
+}
+
+impl<'a> Rx<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32, bit_rate: u32, encoding: Encoding) -> Result<Rx<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut rx = Rx { sm, program: None, encoding, sync: SYNC, samples: vec![] };
+        rx.setup(pin, bit_rate)?;
+        Ok(rx)
+    }
+
+    // The bottom `bits` of `pattern` are what receive() looks for.
+    pub fn with_sync(mut self, pattern: u32, bits: u32) -> Result<Self, Error> {
+        if !(1..=32).contains(&bits) {
+            Err(Error::ParamErr { param: "bits", should_be: "1..=32".to_string() })?;
+        }
+        self.sync = (pattern, bits);
+        Ok(self)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Waits for a frame with the sync word in it, and returns the whole bytes after it. Frames without one are
+    // skipped.
+    pub fn receive(&mut self) -> Result<Vec<u8>, Error> {
+        let sync: Vec<bool> = to_bits(self.sync.0, self.sync.1).collect();
+        loop {
+            let bits = self.receive_bits()?;
+            let Some(start) = bits.windows(sync.len()).position(|window| window == sync) else { continue };
+            return Ok(bits[start + sync.len()..].chunks_exact(8)
+                      .map(|byte| byte.iter().fold(0, |byte, &bit| byte << 1 | bit as u8))
+                      .collect());
+        }
+    }
+
+    // Waits for the line to go quiet after some bits and returns them, preamble and all, except for the first one or
+    // two (which go on getting in step), and perhaps with a stray one at the end from the line going back to low.
+    pub fn receive_bits(&mut self) -> Result<Vec<bool>, Error> {
+        loop {
+            let word = self.sm.get(true)?;
+            if word != 0 {
+                let samples = 31 - word.leading_zeros(); // Under the 1
+                self.samples.extend(to_bits(word, samples));
+                continue;
+            }
+            let bits = self.encoding.bits(&std::mem::take(&mut self.samples));
+            if !bits.is_empty() {
+                return Ok(bits);
+            }
+        }
+    }
+
+    fn setup(&mut self, pin: u32, bit_rate: u32) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(RX_PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let hz = bit_rate as f64 * CYCLES_PER_BIT as f64;
+        let config = SmConfig::default()
+            .set_in_pins(pin)?
+            .set_jmp_pin(pin)?
+            .set_in_shift(false, false, 32)?
+            .set_fifo_join(PioFifoJoin::Rx)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, hz)?)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_consecutive_pindirs(pin, 1, false)?;
+        pio.pio_gpio_init(pin as u16)?;
+        self.sm.init(wrap_target as u16, &config)?; // At start
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const TX: u32 = 5;
+    const RX: u32 = 6;
+
+    // A wire from TX to RX.
+    #[derive(Debug)]
+    struct Wire;
+
+    impl Peripheral for Wire {
+        fn step(&mut self, pins: u32, _now: u64) -> (u32, u32) {
+            ((pins >> TX & 1) << RX, 1 << RX)
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        backend.emulator().attach(Arc::new(Mutex::new(Wire)));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let (mut tx_sm, mut rx_sm) = (pio.sm_claim(0).unwrap(), pio.sm_claim(1).unwrap());
+        for encoding in [Encoding::Manchester, Encoding::ManchesterInverted, Encoding::BiphaseMark] {
+            // The receiver 10% slow.
+            let tx = Tx::new(tx_sm, TX, 1_000_000, encoding).unwrap();
+            let mut rx = Rx::new(rx_sm, RX, 900_000, encoding).unwrap();
+            tx.send(b"\x00\xffhello").unwrap();
+            assert_eq!(rx.receive().unwrap(), b"\x00\xffhello");
+            let pattern = [true, true, false, false, true];
+            tx.send_bits(&[[true, false, true, false].as_slice(), &pattern].concat()).unwrap();
+            let bits = rx.receive_bits().unwrap();
+            assert!(bits.windows(5).any(|window| window == pattern), "{encoding:?} {bits:?}");
+            (tx_sm, rx_sm) = (tx.into_inner().unwrap(), rx.into_inner().unwrap());
+        }
+        let tx = Tx::new(tx_sm, TX, 1_000_000, Encoding::Manchester).unwrap();
+        assert!(tx.with_sync(0, 33).is_err());
+        assert!(Rx::new(rx_sm, RX, 20_000_000, Encoding::Manchester).is_err());
+    }
+}
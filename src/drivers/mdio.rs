@@ -0,0 +1,259 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// An MDIO (a.k.a. SMI) controller, for the management registers of Ethernet PHYs and switches, on any two pins:
+//
+//     let mut mdio = Mdio::new(pio.sm_claim_unused()?, 16, 17)?;   // mdc, mdio
+//     println!("PHYs at {:?}", mdio.scan()?);
+//     let bmsr = mdio.read(1, 1)?;                                   // Clause 22: PHY 1, register 1
+//     mdio.write45(1, 7, 0x0010, 0x0de1)?;                           // Clause 45: port 1, AN MMD, register 0x10
+//
+// Reads return None when nothing answers: the PHY pulls the line low for the second turnaround bit, and the line's
+// pull-up (the board's, plus the pad's) keeps it high otherwise.
+//
+// Every frame is sent with enough preamble to make it 64 bits up to the turnaround, so each one is a header word
+// (bits to send - 1 in the top half, bits to read in the bottom) and exactly two words of bits. Reads release the line
+// after those and clock in the turnaround and the data.
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 4.0;
+
+const PROGRAM: &str = "
+    .program mdio
+    .side_set 1 opt
+    .wrap_target
+    start:
+        out x, 16           side 0
+        out y, 16
+        set pindirs, 1
+    send:
+        out pins, 1         side 0 [1]
+        jmp x-- send        side 1 [1]
+        set pindirs, 0      side 0
+        jmp !y start
+        jmp y-- receive
+    receive:
+        nop                 side 0 [1]
+        in pins, 1          side 1
+        jmp y-- receive     side 1
+        push                side 0
+    .wrap
+";
+
+const READ_BITS: u32 = 18; // Turnaround and data
+
+pub struct Mdio<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    mdc: u32,
+    mdio: u32,
+    clkdiv: ClkDiv,
+}
+
+impl<'a> Mdio<'a> {
+    // Starts out at 1 MHz. The spec allows up to 2.5.
+    pub fn new(sm: StateMachine<'a>, mdc: u32, mdio: u32) -> Result<Mdio<'a>, Error> {
+        for pin in [mdc, mdio] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, 1_000_000.0 * CYCLES_PER_BIT)?;
+        let mut mdio = Mdio { sm, program: None, mdc, mdio, clkdiv };
+        mdio.setup()?;
+        Ok(mdio)
+    }
+
+    pub fn with_frequency(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz * CYCLES_PER_BIT)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    // The MDC rate actually achievable with the SM's clock divider.
+    pub fn frequency(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64) / CYCLES_PER_BIT
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Clause 22.
+    pub fn read(&mut self, phy: u8, reg: u8) -> Result<Option<u16>, Error> {
+        check("phy", phy)?;
+        check("reg", reg)?;
+        self.receive(0b0110, phy, reg)
+    }
+
+    pub fn write(&mut self, phy: u8, reg: u8, value: u16) -> Result<(), Error> {
+        check("phy", phy)?;
+        check("reg", reg)?;
+        self.send(0b0101, phy, reg, value)
+    }
+
+    // Clause 45: sets the MMD's address register, then reads or writes through it.
+    pub fn read45(&mut self, port: u8, device: u8, reg: u16) -> Result<Option<u16>, Error> {
+        check("port", port)?;
+        check("device", device)?;
+        self.send(0b0000, port, device, reg)?;
+        self.receive(0b0011, port, device)
+    }
+
+    pub fn write45(&mut self, port: u8, device: u8, reg: u16, value: u16) -> Result<(), Error> {
+        check("port", port)?;
+        check("device", device)?;
+        self.send(0b0000, port, device, reg)?;
+        self.send(0b0001, port, device, value)
+    }
+
+    // The Clause 22 addresses something answers at.
+    pub fn scan(&mut self) -> Result<Vec<u8>, Error> {
+        let mut phys = Vec::new();
+        for phy in 0..32 {
+            if self.read(phy, 2)?.is_some() { // PHY ID
+                phys.push(phy);
+            }
+        }
+        Ok(phys)
+    }
+
+    // Start and op code (4 bits), the two 5 bit addresses, a turnaround we drive (10), and 16 bits of data.
+    fn send(&mut self, start_op: u32, address: u8, reg: u8, data: u16) -> Result<(), Error> {
+        let frame = start_op << 28 | (address as u32) << 23 | (reg as u32) << 18 | 0b10 << 16 | data as u32;
+        for word in [63 << 16, !0, frame] {
+            self.sm.put(word, true)?;
+        }
+        Ok(())
+    }
+
+    // Start, op code and addresses, after 50 bits of preamble.
+    fn receive(&mut self, start_op: u32, address: u8, reg: u8) -> Result<Option<u16>, Error> {
+        let frame = !0 << 14 | start_op << 10 | (address as u32) << 5 | reg as u32;
+        for word in [63 << 16 | READ_BITS, !0, frame] {
+            self.sm.put(word, true)?;
+        }
+        let bits = self.sm.get(true)?;
+        Ok((bits & 1 << 16 == 0).then_some(bits as u16))
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.mdio, 1)?
+            .set_set_pins(self.mdio, 1)?
+            .set_in_pins(self.mdio)?
+            .set_sideset(2, true, false)?
+            .set_sideset_pins(self.mdc)?
+            .set_out_shift(false, true, 32)?
+            .set_in_shift(false, false, 32)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pins_with_mask(1 << self.mdio, 1 << self.mdc | 1 << self.mdio)?;
+        self.sm.set_pindirs_with_mask(1 << self.mdc, 1 << self.mdc | 1 << self.mdio)?;
+        for pin in [self.mdc, self.mdio] {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        pio.set_pulls(self.mdio as u16, true, false)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+fn check(param: &'static str, address: u8) -> Result<(), Error> {
+    if address > 31 {
+        Err(Error::ParamErr { param, should_be: "0..=31".to_string() })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const MDC: u32 = 16;
+    const MDIO: u32 = 17;
+    const PHY: u32 = 3;
+
+    // A PHY at address 3 with both kinds of register. It plays the pull-up too.
+    #[derive(Debug, Default)]
+    struct Phy {
+        mdc: bool,
+        ones: u32,
+        frame: Option<(u32, u32)>, // Bits so far, and how many
+        out: VecDeque<Option<bool>>,
+        driving: Option<bool>,
+        regs: HashMap<(u32, u32), u16>, // Clause 22 registers are device 32
+        address: u32,
+    }
+
+    impl Peripheral for Phy {
+        fn step(&mut self, pins: u32, _cycle: u64) -> (u32, u32) {
+            let mdc = pins >> MDC & 1 != 0;
+            if mdc && !self.mdc {
+                let bit = pins >> MDIO & 1;
+                match self.frame {
+                    None if bit == 1   => self.ones += 1,
+                    None               => { self.frame = (self.ones >= 32).then_some((0, 1)); self.ones = 0 },
+                    Some((bits, n))    => self.frame = Some((bits << 1 | bit, n + 1)),
+                }
+                if let Some((bits, 14)) = self.frame && (bits >> 5 & 31 != PHY || bits >> 10 == 0b0110 || bits >> 10 == 0b0011) {
+                    let (c22, phy, dev) = (bits >> 12 == 1, bits >> 5 & 31, bits & 31);
+                    if phy == PHY {
+                        let value = if c22 { self.regs.get(&(32, dev)) } else { self.regs.get(&(dev, self.address)) };
+                        let value = *value.unwrap_or(&0);
+                        self.out.extend([None, Some(false)]);
+                        self.out.extend((0..16).rev().map(|n| Some(value >> n & 1 != 0)));
+                    }
+                    self.frame = None;
+                }
+                if let Some((bits, 32)) = self.frame {
+                    let (op, dev, value) = (bits >> 28, bits >> 18 & 31, bits as u16);
+                    match op {
+                        0b0101 => { self.regs.insert((32, dev), value); },
+                        0b0000 => self.address = value as u32,
+                        0b0001 => { self.regs.insert((dev, self.address), value); },
+                        _      => {},
+                    }
+                    self.frame = None;
+                }
+                self.driving = self.out.pop_front().flatten();
+            }
+            self.mdc = mdc;
+            ((self.driving.unwrap_or(true) as u32) << MDIO, 1 << MDIO)
+        }
+    }
+
+    #[test]
+    fn registers() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let phy = Arc::new(Mutex::new(Phy::default()));
+        phy.lock().unwrap().regs.insert((32, 2), 0x0022);
+        backend.emulator().attach(phy.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut mdio = Mdio::new(pio.sm_claim(0).unwrap(), MDC, MDIO).unwrap();
+
+        assert_eq!(mdio.read(3, 2).unwrap(), Some(0x0022));
+        assert_eq!(mdio.read(4, 2).unwrap(), None);
+        mdio.write(3, 4, 0x01e1).unwrap();
+        assert_eq!(mdio.read(3, 4).unwrap(), Some(0x01e1));
+        assert_eq!(phy.lock().unwrap().regs[&(32, 4)], 0x01e1);
+        mdio.write45(3, 7, 0x0010, 0x0de1).unwrap();
+        mdio.write45(3, 1, 0x0010, 0xbeef).unwrap();
+        assert_eq!(mdio.read45(3, 7, 0x0010).unwrap(), Some(0x0de1));
+        assert_eq!(phy.lock().unwrap().regs[&(1, 0x0010)], 0xbeef);
+        assert_eq!(mdio.scan().unwrap(), [3]);
+        assert!(mdio.read(32, 0).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Up to 8 UART receivers on consecutive pins, all at the same baud rate, on a single SM. Good for reading a pile of
+// serial sensors at 9600 baud without a pile of USB adapters:
+//
+//     let mut uarts = MultiRx::new(pio.sm_claim_unused()?, 10, 4, 9600)?;   // pins 10..=13
+//     loop {
+//         for channel in 0..uarts.channels() {
+//             while let Some(byte) = uarts.try_read_byte(channel)? {
+//                 println!("{channel}: {byte:#04x}");
+//             }
+//         }
+//     }
+//
+// The SM samples all the pins 16 times a bit and only pushes when one of them changes, with a timestamp (it's
+// edge_capture's program, clocked to suit the baud rate). The frames are put back together from those on the CPU, so
+// each channel gets what uart::Rx would have given it: receive() and try_receive() say how each frame went, and the
+// byte stream (try_read_byte() and read()) leaves out the ones that went wrong.
+//
+// The RX FIFO only holds 8 changes, so poll often (anything that calls poll() will do) when the lines are busy. Changes
+// that don't fit are lost, and the frames they were part of come out garbled.
+
+use std::collections::VecDeque;
+
+use super::{edge_capture::{program, CYCLES_PER_SAMPLE}, uart::{Parity, Received}};
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioMovStatus, PioProgram, SmConfig, StateMachine};
+
+const SAMPLES_PER_BIT: u64 = 16;
+
+const MOV_OSR_NULL: u16 = 0xa0e3; // mov osr, null
+
+struct Frame {
+    start: u64, // The sample the start bit was seen on
+    bits: u32,
+    value: u32,
+}
+
+#[derive(Default)]
+struct Channel {
+    level: bool,
+    frame: Option<Frame>,
+    received: VecDeque<Received>,
+}
+
+impl Channel {
+    // Samples the bits whose middles come before `now`, which all read whatever the line was doing last.
+    fn advance(&mut self, now: u64, parity: Parity) {
+        while let Some(frame) = &mut self.frame {
+            if frame.start + SAMPLES_PER_BIT * (frame.bits as u64 + 1) + SAMPLES_PER_BIT / 2 >= now {
+                break;
+            }
+            frame.value |= (self.level as u32) << frame.bits;
+            frame.bits += 1;
+            if frame.bits > parity.bits() {
+                self.received.push_back(parity.check(frame.value));
+                self.frame = None;
+            }
+        }
+    }
+
+    fn change(&mut self, now: u64, level: bool) {
+        if self.level && !level && self.frame.is_none() {
+            self.frame = Some(Frame { start: now, bits: 0, value: 0 });
+        }
+        self.level = level;
+    }
+}
+
+pub struct MultiRx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    base: u32,
+    clkdiv: ClkDiv,
+    parity: Parity,
+    channels: Vec<Channel>,
+    now: u64,   // In samples
+    stamp: u32, // The last timestamp from the SM
+}
+
+impl<'a> MultiRx<'a> {
+    pub fn new(sm: StateMachine<'a>, base: u32, channels: u32, baud: u32) -> Result<MultiRx<'a>, Error> {
+        if !(1..=8).contains(&channels) {
+            Err(Error::ParamErr { param: "channels", should_be: "1..=8".to_string() })?;
+        }
+        for pin in base..base + channels {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, baud as f64 * (SAMPLES_PER_BIT * CYCLES_PER_SAMPLE) as f64)?;
+        let mut rx = MultiRx { sm, program: None, base, clkdiv, parity: Parity::None,
+                               channels: (0..channels).map(|_| Channel::default()).collect(), now: 0, stamp: 0 };
+        rx.setup()?;
+        Ok(rx)
+    }
+
+    pub fn with_parity(mut self, parity: Parity) -> Result<Self, Error> {
+        self.parity = parity;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn parity(&self) -> Parity {
+        self.parity
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn baud_rate(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64) / (SAMPLES_PER_BIT * CYCLES_PER_SAMPLE) as f64
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Decodes the changes the SM has seen, then asks it the time so that frames that have finished since the last
+    // change come out too.
+    pub fn poll(&mut self) -> Result<(), Error> {
+        self.sm.put(0, true)?;
+        loop {
+            let word = self.sm.get(true)?;
+            self.event(word);
+            if word & 1 << 31 != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    // Polls if there's nothing waiting for the channel.
+    pub fn try_receive(&mut self, channel: usize) -> Result<Option<Received>, Error> {
+        self.check_channel(channel)?;
+        if self.channels[channel].received.is_empty() {
+            self.poll()?;
+        }
+        Ok(self.channels[channel].received.pop_front())
+    }
+
+    // Waits for the channel's next frame.
+    pub fn receive(&mut self, channel: usize) -> Result<Received, Error> {
+        loop {
+            if let Some(received) = self.try_receive(channel)? {
+                return Ok(received);
+            }
+            self.sleep();
+        }
+    }
+
+    // Returns None instead of waiting when the channel hasn't received any intact bytes.
+    pub fn try_read_byte(&mut self, channel: usize) -> Result<Option<u8>, Error> {
+        while let Some(received) = self.try_receive(channel)? {
+            if let Received::Byte(byte) = received {
+                return Ok(Some(byte));
+            }
+        }
+        Ok(None)
+    }
+
+    // Waits for at least one byte on the channel, then takes whatever else has already arrived, like
+    // std::io::Read::read().
+    pub fn read(&mut self, channel: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut n = 0;
+        while n < buf.len() {
+            match self.try_read_byte(channel)? {
+                Some(byte)    => { buf[n] = byte; n += 1 },
+                None if n > 0 => break,
+                None          => self.sleep(),
+            }
+        }
+        Ok(n)
+    }
+
+    fn check_channel(&self, channel: usize) -> Result<(), Error> {
+        if channel >= self.channels.len() {
+            Err(Error::ParamErr { param: "channel", should_be: format!("< {}", self.channels.len()) })?;
+        }
+        Ok(())
+    }
+
+    // About a frame.
+    fn sleep(&self) {
+        std::thread::sleep(std::time::Duration::from_secs_f64(10.0 / self.baud_rate()));
+    }
+
+    fn event(&mut self, word: u32) {
+        let width = self.channels.len() as u32;
+        let mask = (1 << (31 - width)) - 1;
+        let stamp = !(word >> width) & mask; // Y counts down
+        self.now += (stamp.wrapping_sub(self.stamp) & mask) as u64;
+        self.stamp = stamp;
+        for (n, channel) in self.channels.iter_mut().enumerate() {
+            channel.advance(self.now, self.parity);
+            channel.change(self.now, word >> n & 1 != 0);
+        }
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        let width = self.channels.len() as u32;
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&program(width))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.base)?
+            .set_in_shift(false, false, 32)?
+            .set_mov_status(PioMovStatus::TxLessThan, 1)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        let pins = ((1 << width) - 1) << self.base;
+        self.sm.set_pindirs_with_mask(0, pins)?;
+        for pin in self.base..self.base + width {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.exec(MOV_OSR_NULL, false)?; // The first sample shows up as a change and sets the starting levels
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        for channel in self.channels.iter_mut() {
+            *channel = Channel::default();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    // A frame's bits at half a bit each, so the channels can be out of step with each other.
+    fn frame(byte: u8, parity: Option<bool>, stop: bool) -> Vec<bool> {
+        let bits = std::iter::once(false).chain((0..8).map(|i| byte >> i & 1 != 0)).chain(parity).chain([stop]);
+        bits.flat_map(|bit| [bit, bit]).collect()
+    }
+
+    // Plays the lines (half a bit at 1 Mbaud is 100 clocks) into pins 10 onwards, polling every couple of bits, then
+    // leaves them idle.
+    fn play(backend: &EmulatorBackend, rx: &mut MultiRx, lines: &[Vec<bool>]) {
+        let start = backend.emulator().cycle();
+        let len = lines.iter().map(|line| line.len()).max().unwrap_or(0) + 8;
+        loop {
+            let mut emu = backend.emulator();
+            for _ in 0..400 {
+                let t = ((emu.cycle() - start) / 100) as usize;
+                for (n, line) in lines.iter().enumerate() {
+                    emu.set_input(10 + n as u32, line.get(t).copied().unwrap_or(true));
+                }
+                emu.step();
+            }
+            let done = emu.cycle() - start >= len as u64 * 100;
+            drop(emu);
+            rx.poll().unwrap();
+            if done {
+                return;
+            }
+        }
+    }
+
+    fn received(rx: &mut MultiRx, channel: usize) -> Vec<Received> {
+        std::iter::from_fn(|| rx.try_receive(channel).unwrap()).collect()
+    }
+
+    #[test]
+    fn channels() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut rx = MultiRx::new(pio.sm_claim(0).unwrap(), 10, 3, 1_000_000).unwrap();
+        let idle = vec![true; 4];
+        play(&backend, &mut rx, &[idle.clone(), idle.clone(), idle.clone()]);
+        play(&backend, &mut rx, &[
+            [frame(b'H', None, true), frame(b'i', None, true)].concat(),
+            [vec![true], frame(0x00, None, true), frame(0xff, None, true)].concat(),
+            [vec![false; 30], idle.clone(), frame(0x55, None, false), idle.clone(), frame(b'x', None, true)].concat(),
+        ]);
+        assert_eq!(received(&mut rx, 0), [Received::Byte(b'H'), Received::Byte(b'i')]);
+        assert_eq!(received(&mut rx, 1), [Received::Byte(0x00), Received::Byte(0xff)]);
+        assert_eq!(received(&mut rx, 2), [Received::Break, Received::FramingError(0x55), Received::Byte(b'x')]);
+
+        let mut rx = rx.with_parity(Parity::Even).unwrap();
+        play(&backend, &mut rx, &[idle.clone(), idle.clone(), idle.clone()]);
+        play(&backend, &mut rx, &[frame(0x03, Some(false), true), frame(0x03, Some(true), true), frame(0x07, Some(true), true)]);
+        assert_eq!(rx.try_read_byte(0).unwrap(), Some(0x03));
+        assert_eq!(rx.try_read_byte(1).unwrap(), None);
+        let mut buf = [0; 4];
+        assert_eq!(rx.read(2, &mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 0x07);
+        assert!(rx.try_receive(3).is_err());
+    }
+}
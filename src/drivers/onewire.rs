@@ -0,0 +1,521 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A Dallas/Maxim 1-Wire bus master on any pin (with a pull-up, 4.7 kΩ to 3.3 V, on the bus; the pad's is too weak on
+// its own for more than a device or two on a short wire):
+//
+//     let mut bus = OneWire::new(pio.sm_claim_unused()?, 4)?;
+//     for rom in bus.search()? {
+//         println!("{rom}");                                    // 28-0000075a1b2c
+//     }
+//     let mut scratchpad = [0; 9];
+//     bus.transaction(Some(rom), &[0xbe], &mut scratchpad)?;    // Select the device, send a command, read the answer
+//
+// The SM does the timing: each FIFO word is either a reset (which comes back with whether anything answered with a
+// presence pulse) or up to 8 time slots, which come back as what was on the bus in each. The SM runs at 1 µs a cycle
+// and uses the standard speed timings: slots are 71 µs, reads are sampled 15 µs in, and resets are 480 µs low then
+// 480 µs high. The line is only ever pulled low or let go, never driven high, so parasite powered devices that need
+// a strong pull-up while they're busy won't work.
+
+use std::fmt;
+
+use crate::{pio_clock_hz, ClkDiv, LoadedProgram, PioProgram, SmConfig, StateMachine};
+
+// A word is 1 for a reset, or 0 then the number of slots - 1 (3 bits) then the bits to write, inverted (a 1 holds the
+// line low for the whole slot). Writing a 1 is also how a bit is read.
+const PROGRAM: &str = "
+    .program onewire
+    .wrap_target
+        pull
+        out x, 1
+        jmp !x bits
+        set pindirs, 1
+        set y, 15
+    reset_low:
+        jmp y-- reset_low   [29]    ; 16 × 30 µs
+        set pindirs, 0      [31]
+        nop                 [31]
+        nop                 [4]
+        in pins, 1                  ; 70 µs after letting go
+        set y, 12
+    reset_high:
+        jmp y-- reset_high  [31]
+        jmp done
+    bits:
+        out y, 3
+    bit:
+        set pindirs, 1      [5]
+        out pindirs, 1      [8]
+        in pins, 1          [29]    ; 15 µs into the slot
+        nop                 [14]
+        set pindirs, 0      [9]     ; Recovery time
+        jmp y-- bit
+    done:
+        push
+    .wrap
+";
+
+#[derive(Debug)]
+pub enum Error {
+    Pio(crate::Error),
+    NoPresence, // Nothing answered the reset
+    Crc,        // What came back didn't check out
+}
+
+impl From<crate::Error> for Error {
+    fn from(error: crate::Error) -> Self {
+        Error::Pio(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Pio(error) => write!(f, "{error}"),
+            Error::NoPresence => write!(f, "No device on the bus"),
+            Error::Crc        => write!(f, "CRC mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Pio(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// ROM commands.
+const READ_ROM: u8 = 0x33;
+const MATCH_ROM: u8 = 0x55;
+const SKIP_ROM: u8 = 0xcc;
+const SEARCH_ROM: u8 = 0xf0;
+const ALARM_SEARCH: u8 = 0xec;
+
+// A device's 64 bit ID, in the order it comes off the bus: family code, 48 bit serial number (LSB first), CRC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Rom(pub [u8; 8]);
+
+impl Rom {
+    pub fn family(&self) -> u8 {
+        self.0[0]
+    }
+
+    pub fn is_valid(&self) -> bool {
+        crc8(&self.0[..7]) == self.0[7]
+    }
+
+    fn bit(&self, n: usize) -> bool {
+        self.0[n / 8] >> (n % 8) & 1 != 0
+    }
+}
+
+// The way Linux's w1 driver names them: family, then the serial number MSB first.
+impl fmt::Display for Rom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}-", self.0[0])?;
+        self.0[1..7].iter().rev().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+// The Dallas/Maxim CRC (x^8 + x^5 + x^4 + 1), as used in ROMs and scratchpads. Running it over data with its CRC on the
+// end gives 0.
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 1 != 0 { crc >> 1 ^ 0x8c } else { crc >> 1 })
+    })
+}
+
+pub struct OneWire<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+}
+
+impl<'a> OneWire<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<OneWire<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut bus = OneWire { sm, program: None, pin };
+        bus.setup()?;
+        Ok(bus)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Says whether anything answered.
+    pub fn reset(&mut self) -> Result<bool, Error> {
+        self.sm.put(1, true)?;
+        Ok(self.sm.get(true)? >> 31 == 0)
+    }
+
+    // Up to 8 slots, the first bit of `bits` first, returning what was on the bus in each: what was written, unless
+    // a device held the line low for a 0. Send 1s to read.
+    pub fn touch_bits(&mut self, bits: u8, count: u32) -> Result<u8, Error> {
+        if !(1..=8).contains(&count) {
+            Err(crate::Error::ParamErr { param: "count", should_be: "1..=8".to_string() })?;
+        }
+        self.sm.put((count - 1) << 1 | (!bits as u32 & 0xff) << 4, true)?;
+        Ok((self.sm.get(true)? >> (32 - count)) as u8)
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> Result<(), Error> {
+        self.touch_bits(bit as u8, 1)?;
+        Ok(())
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, Error> {
+        Ok(self.touch_bits(1, 1)? != 0)
+    }
+
+    pub fn write_byte(&mut self, byte: u8) -> Result<(), Error> {
+        self.touch_bits(byte, 8)?;
+        Ok(())
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, Error> {
+        self.touch_bits(0xff, 8)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        bytes.iter().try_for_each(|&byte| self.write_byte(byte))
+    }
+
+    pub fn read(&mut self, bytes: &mut [u8]) -> Result<(), Error> {
+        for byte in bytes {
+            *byte = self.read_byte()?;
+        }
+        Ok(())
+    }
+
+    // Resets the bus and addresses one device (or all of them, with None, which only makes sense for reads when
+    // there's just the one).
+    pub fn select(&mut self, rom: Option<Rom>) -> Result<(), Error> {
+        if !self.reset()? {
+            Err(Error::NoPresence)?;
+        }
+        match rom {
+            Some(rom) => { self.write_byte(MATCH_ROM)?; self.write(&rom.0) },
+            None      => self.write_byte(SKIP_ROM),
+        }
+    }
+
+    // select(), then a function command and its arguments, then reads the reply.
+    pub fn transaction(&mut self, rom: Option<Rom>, write: &[u8], read: &mut [u8]) -> Result<(), Error> {
+        self.select(rom)?;
+        self.write(write)?;
+        self.read(read)
+    }
+
+    // The ROM of the only device on the bus.
+    pub fn read_rom(&mut self) -> Result<Rom, Error> {
+        if !self.reset()? {
+            Err(Error::NoPresence)?;
+        }
+        self.write_byte(READ_ROM)?;
+        let mut rom = Rom([0; 8]);
+        self.read(&mut rom.0)?;
+        if !rom.is_valid() {
+            Err(Error::Crc)?;
+        }
+        Ok(rom)
+    }
+
+    // Every device on the bus, in order of their ROMs read LSB first.
+    pub fn search(&mut self) -> Result<Vec<Rom>, Error> {
+        self.search_with(SEARCH_ROM)
+    }
+
+    // The devices with an alarm condition.
+    pub fn search_alarms(&mut self) -> Result<Vec<Rom>, Error> {
+        self.search_with(ALARM_SEARCH)
+    }
+
+    // Each pass reads a bit and its complement from every device still in the running and writes the direction to
+    // go: 01 or 10 means they all agree, 00 means they differ. At the last difference where the previous pass went 0,
+    // this one goes 1; before it, the same way as last time; after it, 0.
+    fn search_with(&mut self, command: u8) -> Result<Vec<Rom>, Error> {
+        let mut roms = Vec::new();
+        let mut last_zero = None;
+        loop {
+            if !self.reset()? {
+                return Ok(roms);
+            }
+            self.write_byte(command)?;
+            let mut rom = Rom([0; 8]);
+            let mut zero = None;
+            for n in 0..64 {
+                let direction = match self.touch_bits(0b11, 2)? {
+                    0b01 => true,
+                    0b10 => false,
+                    0b00 => {
+                        let direction = match last_zero {
+                            Some(last) if n < last => roms.last().is_some_and(|r: &Rom| r.bit(n)),
+                            Some(last)             => n == last,
+                            None                   => false,
+                        };
+                        if !direction {
+                            zero = Some(n);
+                        }
+                        direction
+                    },
+                    _ if n == 0 => return Ok(roms), // Nobody's in this search (no alarms, say)
+                    _ => Err(Error::Crc)?,          // Everyone dropped out: something glitched
+                };
+                self.write_bit(direction)?;
+                rom.0[n / 8] |= (direction as u8) << (n % 8);
+            }
+            if !rom.is_valid() {
+                Err(Error::Crc)?;
+            }
+            roms.push(rom);
+            if zero.is_none() {
+                return Ok(roms);
+            }
+            last_zero = zero;
+        }
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.pin, 1)?
+            .set_set_pins(self.pin, 1)?
+            .set_in_pins(self.pin)?
+            .set_out_shift(true, false, 32)?
+            .set_in_shift(true, false, 32)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, 1_000_000.0)?)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pins_with_mask(0, 1 << self.pin)?;
+        self.sm.set_pindirs_with_mask(0, 1 << self.pin)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        pio.set_pulls(self.pin as u16, true, false)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{collections::VecDeque, sync::{Arc, Mutex}};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    pub(crate) const PIN: u32 = 4;
+    const US: u64 = 200; // Cycles
+
+    pub(crate) fn rom(family: u8, serial: u64) -> Rom {
+        let mut rom = [0; 8];
+        rom[0] = family;
+        rom[1..7].copy_from_slice(&serial.to_le_bytes()[..6]);
+        rom[7] = crc8(&rom[..7]);
+        Rom(rom)
+    }
+
+    #[derive(Debug)]
+    enum Phase {
+        Asleep,
+        Rom,
+        Match(usize),
+        Search(usize, u32), // Bit, and which of its 3 slots
+        Function,
+        Write(usize),       // Where in the scratchpad
+        Send,
+    }
+
+    // Enough of a device for the ROM commands, plus reading (0xbe) and writing (0x4e) a scratchpad whose last byte is
+    // its CRC. Any other function command is just noted.
+    #[derive(Debug)]
+    pub(crate) struct Device {
+        pub(crate) rom: Rom,
+        pub(crate) scratchpad: Vec<u8>,
+        pub(crate) commands: Vec<u8>,
+        pub(crate) alarm: bool,
+        phase: Phase,
+        byte: (u8, u32),
+        tx: VecDeque<bool>,
+        low_until: u64,
+        sample_at: Option<u64>,
+    }
+
+    impl Device {
+        pub(crate) fn new(rom: Rom, scratchpad: &[u8]) -> Device {
+            Device { rom, scratchpad: scratchpad.to_vec(), commands: Vec::new(), alarm: false, phase: Phase::Asleep,
+                     byte: (0, 0), tx: VecDeque::new(), low_until: 0, sample_at: None }
+        }
+
+        fn reset(&mut self) {
+            (self.phase, self.byte, self.sample_at) = (Phase::Rom, (0, 0), None);
+            self.tx.clear();
+        }
+
+        fn slot(&mut self, now: u64) {
+            let send = match self.phase {
+                Phase::Send         => self.tx.pop_front(),
+                Phase::Search(n, 0) => Some(self.rom.bit(n)),
+                Phase::Search(n, 1) => Some(!self.rom.bit(n)),
+                _                   => None,
+            };
+            if send == Some(false) {
+                self.low_until = now + 30 * US;
+            }
+            self.sample_at = Some(now + 30 * US);
+        }
+
+        fn received(&mut self, bit: bool) {
+            self.phase = match self.phase {
+                Phase::Match(n) if bit != self.rom.bit(n)      => Phase::Asleep,
+                Phase::Match(63)                               => Phase::Function,
+                Phase::Match(n)                                => Phase::Match(n + 1),
+                Phase::Search(n, 2) if bit != self.rom.bit(n)  => Phase::Asleep,
+                Phase::Search(63, 2)                           => Phase::Function,
+                Phase::Search(n, 2)                            => Phase::Search(n + 1, 0),
+                Phase::Search(n, slot)                         => Phase::Search(n, slot + 1),
+                Phase::Send if self.tx.is_empty()              => Phase::Asleep,
+                Phase::Rom | Phase::Function | Phase::Write(_) => {
+                    self.byte = (self.byte.0 | (bit as u8) << self.byte.1, self.byte.1 + 1);
+                    if self.byte.1 < 8 {
+                        return;
+                    }
+                    let byte = std::mem::take(&mut self.byte).0;
+                    self.byte(byte)
+                },
+                _ => return,
+            }
+        }
+
+        fn byte(&mut self, byte: u8) -> Phase {
+            let bits = |bytes: &[u8]| bytes.iter().flat_map(|b| (0..8).map(move |n| b >> n & 1 != 0)).collect();
+            match (&self.phase, byte) {
+                (Phase::Rom, READ_ROM)                      => { self.tx = bits(&self.rom.0); Phase::Send },
+                (Phase::Rom, MATCH_ROM)                     => Phase::Match(0),
+                (Phase::Rom, SKIP_ROM)                      => Phase::Function,
+                (Phase::Rom, SEARCH_ROM)                    => Phase::Search(0, 0),
+                (Phase::Rom, ALARM_SEARCH) if self.alarm    => Phase::Search(0, 0),
+                (Phase::Rom, _)                             => Phase::Asleep,
+                (Phase::Function, 0xbe)                     => {
+                    let len = self.scratchpad.len();
+                    self.scratchpad[len - 1] = crc8(&self.scratchpad[..len - 1]);
+                    self.tx = bits(&self.scratchpad);
+                    Phase::Send
+                },
+                (Phase::Function, 0x4e)                     => Phase::Write(2),
+                (Phase::Function, command)                  => { self.commands.push(command); Phase::Function },
+                (Phase::Write(at), byte)                    => {
+                    self.scratchpad[*at] = byte;
+                    if at + 1 < self.scratchpad.len() - 1 { Phase::Write(at + 1) } else { Phase::Asleep }
+                },
+                _                                           => Phase::Asleep,
+            }
+        }
+    }
+
+    // The devices and the wire between them. The master's falling edges start slots, and a low of more than 400 µs
+    // is a reset, answered 30 µs later with 120 µs of presence pulse.
+    #[derive(Debug, Default)]
+    pub(crate) struct Bus {
+        pub(crate) devices: Vec<Device>,
+        line: bool,
+        fell: u64,
+        presence: (u64, u64),
+    }
+
+    impl Bus {
+        pub(crate) fn new(devices: Vec<Device>) -> Bus {
+            Bus { devices, line: true, ..Bus::default() }
+        }
+
+        fn pulling(&self, now: u64) -> bool {
+            (self.presence.0..self.presence.1).contains(&now) || self.devices.iter().any(|d| now < d.low_until)
+        }
+    }
+
+    impl Peripheral for Bus {
+        fn step(&mut self, pins: u32, now: u64) -> (u32, u32) {
+            let line = pins >> PIN & 1 != 0;
+            if self.line && !line && !self.pulling(now) {
+                self.fell = now;
+                for device in &mut self.devices {
+                    device.slot(now);
+                }
+            }
+            if !self.line && line && now - self.fell > 400 * US && !self.devices.is_empty() {
+                self.presence = (now + 30 * US, now + 150 * US);
+                self.fell = now; // So the end of the presence pulse isn't another reset
+                for device in &mut self.devices {
+                    device.reset();
+                }
+            }
+            for device in &mut self.devices {
+                if device.sample_at == Some(now) {
+                    device.received(line);
+                }
+            }
+            self.line = line;
+            ((!self.pulling(now) as u32) << PIN, 1 << PIN)
+        }
+    }
+
+    pub(crate) fn bus(devices: Vec<Device>) -> (EmulatorBackend, Arc<Mutex<Bus>>, Rp1PIO) {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let bus = Arc::new(Mutex::new(Bus::new(devices)));
+        backend.emulator().attach(bus.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        (backend, bus, pio)
+    }
+
+    #[test]
+    fn search_and_transactions() {
+        assert_eq!(crc8(&[0x02, 0x1c, 0xb8, 0x01, 0x00, 0x00, 0x00]), 0xa2); // From Maxim's app note 27
+        let roms = [rom(0x28, 0x0000_075a_1b2c), rom(0x28, 0x0000_075a_1b2d), rom(0x10, 0x1234)];
+        assert_eq!(roms[0].to_string(), "28-0000075a1b2c");
+        let mut devices: Vec<Device> = roms.iter().map(|&r| Device::new(r, &[r.0[0], 2, 3, 4, 5, 0])).collect();
+        devices[1].alarm = true;
+        let (_backend, bus, pio) = bus(devices);
+        let mut ow = OneWire::new(pio.sm_claim(0).unwrap(), PIN).unwrap();
+
+        let mut found = ow.search().unwrap();
+        found.sort();
+        let mut expected = roms.to_vec();
+        expected.sort();
+        assert_eq!(found, expected);
+        assert_eq!(ow.search_alarms().unwrap(), [roms[1]]);
+
+        let mut scratchpad = [0; 6];
+        ow.transaction(Some(roms[2]), &[0xbe], &mut scratchpad).unwrap();
+        assert_eq!(scratchpad[..5], [0x10, 2, 3, 4, 5]);
+        assert_eq!(crc8(&scratchpad), 0);
+        ow.transaction(Some(roms[0]), &[0x4e, 7, 8, 9], &mut []).unwrap();
+        ow.transaction(None, &[0x44], &mut []).unwrap();
+        let bus = bus.lock().unwrap();
+        assert_eq!(bus.devices[0].scratchpad[..5], [0x28, 2, 7, 8, 9]);
+        assert_eq!(bus.devices[1].scratchpad[..5], [0x28, 2, 3, 4, 5]);
+        assert!(bus.devices.iter().all(|d| d.commands == [0x44]));
+    }
+
+    #[test]
+    fn one_device_and_none() {
+        let (_backend, _bus, pio) = bus(vec![Device::new(rom(0x01, 42), &[0])]);
+        let mut ow = OneWire::new(pio.sm_claim(0).unwrap(), PIN).unwrap();
+        assert_eq!(ow.read_rom().unwrap(), rom(0x01, 42));
+
+        let (_backend, _bus, pio) = bus(vec![]);
+        let mut ow = OneWire::new(pio.sm_claim(0).unwrap(), PIN).unwrap();
+        assert!(!ow.reset().unwrap());
+        assert_eq!(ow.search().unwrap(), []);
+        assert!(matches!(ow.transaction(None, &[0xbe], &mut [0]), Err(Error::NoPresence)));
+    }
+}
@@ -0,0 +1,454 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// On/off keyed 433 and 315 MHz remotes, the fixed code kind that work cheap mains sockets and doorbells, with a
+// transmitter module's data pin on one pin and a receiver module's on another, one SM each:
+//
+//     let tx = Tx::new(pio.sm_claim_unused()?, 17)?;
+//     tx.send(&Protocol::PT2262, &Code::pt2262("0FFF0FFFFFF0")?)?;   // Socket A on
+//
+//     let mut rx = Rx::new(pio.sm_claim_unused()?, 27)?;
+//     loop {
+//         let received = rx.receive()?;
+//         println!("{:#x} ({} bits, {} µs pulses)", received.code.value, received.code.bits, received.pulse);
+//     }
+//
+// A Protocol says how a code is sent: each bit is a high then a low, so many pulses long each, and a frame is the bits,
+// most significant first, then a sync (a short high and a long low). PT2262s and EV1527s (and their clones, in most
+// remotes) send their 24 bits the same way as each other; what differs is what the bits mean, which Code::pt2262() and
+// Code::ev1527() take care of. Others are just a Protocol away: the fields are all public.
+//
+// Tx's SM times every high and low to the µs. A frame's sent with_repeats() times (10 to start with), as remotes do,
+// since receivers take a frame or two to settle. Rx's SM times them too, and the CPU looks for codes in between syncs,
+// going by the bits to work out the sender's pulse length, which varies from remote to remote. A receiver module
+// with nothing to hear turns its gain all the way up and gives out noise, so Rx's SM pushes all the time: something
+// has to be receiving (receive() is best) or the RX FIFO fills up and frames are lost. Each repeat of a frame
+// arrives as another Received.
+
+use std::time::Duration;
+
+use crate::{pio_clock_hz, proc_pio::PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS, ClkDiv, Error, LoadedProgram, PioFifoJoin,
+            PioProgram, SmConfig, StateMachine};
+
+const TX_HZ: f64 = 1_000_000.0;
+// Cycles of each high or low taken up by the pull and outs, before the delay
+const TX_OVERHEAD: u32 = 4;
+const RX_HZ: f64 = 2_000_000.0; // 2 cycles a count, so counts are µs
+// µs of each high or low taken up by pushing the last one, before the count starts
+const RX_OVERHEAD: u32 = 2;
+const MAX_BITS: usize = 64;
+
+// The level, then how many µs to hold it for, less TX_OVERHEAD.
+const TX_PROGRAM: &str = "
+    .program ook_tx
+    .wrap_target
+        pull
+        out pins, 1
+        out x, 31
+    delay:
+        jmp x-- delay
+    .wrap
+";
+
+// Highs are pushed as what's left of X counting down from !0, with the top bit set, and lows as what's left of it
+// counting down from OSR, which the CPU sets to the longest low worth timing. Longer ones are pushed as 0 then.
+const RX_PROGRAM: &str = "
+    .program ook_rx
+    .wrap_target
+    start:
+        mov x, ~null
+    high:
+        jmp pin high_1
+        mov isr, x
+        push noblock
+        mov x, osr
+    low:
+        jmp pin low_end
+        jmp x-- low
+        jmp timeout
+    low_end:
+        mov isr, x
+        push noblock
+    .wrap
+    high_1:
+        jmp x-- high
+        jmp high
+    timeout:
+        mov isr, null
+        push noblock
+        wait 1 pin 0
+        jmp start
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protocol {
+    pub pulse: u32,       // µs, what the rest are counted in
+    pub sync: (u32, u32), // Pulses high, then low
+    pub zero: (u32, u32),
+    pub one: (u32, u32),
+}
+
+impl Protocol {
+    // What rc-switch calls protocol 1.
+    pub const PT2262: Protocol = Protocol { pulse: 350, sync: (1, 31), zero: (1, 3), one: (3, 1) };
+    pub const EV1527: Protocol = Protocol::PT2262;
+
+    // High and low lengths in µs for one frame, ending with the sync.
+    pub fn timings(&self, code: &Code) -> Vec<u32> {
+        (0..code.bits).rev()
+            .map(|bit| if code.value >> bit & 1 != 0 { self.one } else { self.zero })
+            .chain([self.sync])
+            .flat_map(|(high, low)| [high * self.pulse, low * self.pulse])
+            .collect()
+    }
+
+    // The code in one frame's timings, ending with the sync, along with the pulse length it was sent with. Bits are
+    // taken back from the sync for as long as they make sense, so noise ahead of the frame doesn't matter. The pulse
+    // length is worked out from the bits, since receivers stretch highs into the lows after them and the sync's low
+    // may have been cut short.
+    pub fn decode(&self, timings: &[u32]) -> Option<(Code, u32)> {
+        let [.., high, low, sync_high, sync_low] = *timings else { return None };
+        let units = |(high, low): (u32, u32)| high + low;
+        let pulse = (high + low) * 2 / (units(self.zero) + units(self.one));
+        if !(self.pulse / 2..=self.pulse * 3 / 2).contains(&pulse) || !near(sync_high, self.sync.0, pulse) ||
+           sync_low + pulse * 6 / 10 < self.sync.1 * pulse {
+            return None;
+        }
+        let (mut code, mut total, mut total_units) = (Code { value: 0, bits: 0 }, 0, 0);
+        for pair in timings[..timings.len() - 2].rchunks_exact(2).take(MAX_BITS) {
+            let (high, low) = (pair[0], pair[1]);
+            let bit = [self.zero, self.one].into_iter()
+                .position(|(h, l)| near(high, h, pulse) && near(low, l, pulse));
+            let Some(bit) = bit else { break };
+            code.value |= (bit as u64) << code.bits;
+            code.bits += 1;
+            total += high + low;
+            total_units += units([self.zero, self.one][bit]);
+        }
+        (code.bits > 0).then(|| (code, total / total_units))
+    }
+}
+
+// Whether `timing` is within 60% of a pulse of `pulses` of them, as rc-switch has it.
+fn near(timing: u32, pulses: u32, pulse: u32) -> bool {
+    timing.abs_diff(pulses * pulse) <= pulse * 6 / 10
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Code {
+    pub value: u64, // The first bit sent is the top one
+    pub bits: u32,
+}
+
+impl Code {
+    // A 20 bit address (each remote's own, fixed when it's made) and 4 bits of buttons.
+    pub fn ev1527(address: u32, data: u8) -> Code {
+        Code { value: ((address & 0xfffff) << 4 | data as u32 & 0xf) as u64, bits: 24 }
+    }
+
+    // 12 address and data pins, each tied low (0), high (1) or left floating (F), usually printed on the socket or set
+    // with DIP switches. Each one's sent as two bits.
+    pub fn pt2262(trits: &str) -> Result<Code, Error> {
+        if trits.len() != 12 {
+            Err(Error::ParamErr { param: "trits", should_be: "12 of them".to_string() })?;
+        }
+        let mut value = 0;
+        for trit in trits.chars() {
+            value = value << 2 | match trit.to_ascii_uppercase() {
+                '0' => 0b00,
+                '1' => 0b11,
+                'F' => 0b01,
+                _   => Err(Error::ParamErr { param: "trits", should_be: "0, 1 or F".to_string() })?,
+            };
+        }
+        Ok(Code { value, bits: 24 })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Received {
+    pub protocol: usize, // Index into with_protocols()'s, or 0 for the default
+    pub code: Code,
+    pub pulse: u32,      // µs, as sent
+}
+
+pub struct Tx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    repeats: u32,
+}
+
+impl<'a> Tx<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<Tx<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut tx = Tx { sm, program: None, pin, repeats: 10 };
+        tx.setup()?;
+        Ok(tx)
+    }
+
+    // How many times each frame is sent.
+    pub fn with_repeats(mut self, repeats: u32) -> Result<Self, Error> {
+        if repeats == 0 {
+            Err(Error::ParamErr { param: "repeats", should_be: "at least 1".to_string() })?;
+        }
+        self.repeats = repeats;
+        Ok(self)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        self.sm.set_pins_with_mask(0, 1 << self.pin)?;
+        Ok(self.sm)
+    }
+
+    // Queues the frame up, repeats and all, and returns once the last of it's in the TX FIFO.
+    pub fn send(&self, protocol: &Protocol, code: &Code) -> Result<(), Error> {
+        let frame = protocol.timings(code);
+        (0..self.repeats).try_for_each(|_| self.send_raw(&frame))
+    }
+
+    // High and low lengths in µs, starting with a high, at least 4 µs each. It's left low at the end either way.
+    pub fn send_raw(&self, timings: &[u32]) -> Result<(), Error> {
+        for (n, &timing) in timings.iter().enumerate() {
+            self.sm.put((timing.max(TX_OVERHEAD) - TX_OVERHEAD) << 1 | (n % 2 == 0) as u32, true)?;
+        }
+        if timings.len() % 2 == 1 {
+            self.sm.put(0, true)?;
+        }
+        Ok(())
+    }
+
+    // Everything has gone out once the FIFO's empty and the SM is back to stalling on its `pull`.
+    pub fn is_idle(&self) -> Result<bool, Error> {
+        let hw = self.sm.read_hw_state_machine()?;
+        let offset = self.program.as_ref().map(|program| program.offset() as u32);
+        let stalled = hw.execctrl & PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS != 0;
+        Ok(self.sm.is_tx_fifo_empty()? && Some(hw.pc) == offset && stalled)
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        while !self.is_idle()? {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(TX_PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.pin, 1)?
+            .set_out_shift(true, false, 32)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, TX_HZ)?)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pins_with_mask(0, 1 << self.pin)?; // Transmitter off
+        self.sm.set_consecutive_pindirs(self.pin, 1, true)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+pub struct Rx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    protocols: Vec<Protocol>,
+    timings: Vec<u32>, // Since the last sync, starting with a high
+}
+
+impl<'a> Rx<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<Rx<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut rx = Rx { sm, program: None, pin, protocols: vec![Protocol::PT2262], timings: vec![] };
+        rx.setup()?;
+        Ok(rx)
+    }
+
+    // What to look for, instead of just PT2262/EV1527. The first that makes sense of a frame wins.
+    pub fn with_protocols(mut self, protocols: &[Protocol]) -> Result<Self, Error> {
+        if protocols.is_empty() {
+            Err(Error::ParamErr { param: "protocols", should_be: "at least one".to_string() })?;
+        }
+        if protocols.iter().any(|p| p.pulse == 0 || p.sync.1 == 0) {
+            Err(Error::ParamErr { param: "protocols", should_be: "non-zero pulse and sync".to_string() })?;
+        }
+        self.protocols = protocols.to_vec();
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Waits for the next frame that makes sense.
+    pub fn receive(&mut self) -> Result<Received, Error> {
+        loop {
+            let word = self.sm.get(true)?;
+            if let Some(received) = self.received(word) {
+                return Ok(received);
+            }
+        }
+    }
+
+    // Returns None instead of waiting when nothing's been received.
+    pub fn try_receive(&mut self) -> Result<Option<Received>, Error> {
+        while !self.sm.is_rx_fifo_empty()? {
+            let word = self.sm.get(false)?;
+            if let Some(received) = self.received(word) {
+                return Ok(Some(received));
+            }
+        }
+        Ok(None)
+    }
+
+    fn received(&mut self, word: u32) -> Option<Received> {
+        let (high, timing) = match word >> 31 {
+            1 => (true, !word + RX_OVERHEAD),
+            _ => (false, self.limit() - word + RX_OVERHEAD),
+        };
+        if high != self.timings.len().is_multiple_of(2) {
+            self.timings.clear(); // A low with no high before it, or one lost to a full FIFO
+            if !high {
+                return None;
+            }
+        }
+        self.timings.push(timing);
+        let sync = |p: &&Protocol| !high && timing >= p.sync.1 * p.pulse / 2; // Only a long enough low can be one
+        if !self.protocols.iter().any(|p| sync(&p)) {
+            if self.timings.len() > 2 * MAX_BITS + 2 {
+                self.timings.drain(..2);
+            }
+            return None;
+        }
+        let found = self.protocols.iter().enumerate()
+            .filter(|(_, p)| sync(p))
+            .find_map(|(protocol, p)| p.decode(&self.timings).map(|(code, pulse)| Received { protocol, code, pulse }));
+        self.timings.clear();
+        found
+    }
+
+    // Twice the longest sync, as long as anything needs to be timed.
+    fn limit(&self) -> u32 {
+        self.protocols.iter().map(|p| (p.sync.1 as u64 * p.pulse as u64 * 2).min(0x7fff_ffff) as u32).max().unwrap_or(0)
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(RX_PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.pin)?
+            .set_jmp_pin(self.pin)?
+            .set_in_shift(false, false, 32)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, RX_HZ)?)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_consecutive_pindirs(self.pin, 1, false)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.put(self.limit(), false)?;
+        self.sm.exec_pull()?;
+        self.sm.set_config(&config.set_fifo_join(PioFifoJoin::Rx)?)?; // Only once the TX FIFO's done with
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        self.timings.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const TX: u32 = 5;
+    const RX: u32 = 6;
+    const US: u64 = 200; // Cycles
+
+    // Something quicker than PT2262 to emulate.
+    const FAST: Protocol = Protocol { pulse: 50, sync: (1, 10), zero: (1, 2), one: (2, 1) };
+
+    // Notes each time TX changes, and plays highs and lows (in cycles) on RX.
+    #[derive(Debug, Default)]
+    struct Radio {
+        tx: Vec<u64>,
+        level: bool,
+        playing: Vec<(u64, u64)>,
+    }
+
+    impl Peripheral for Radio {
+        fn step(&mut self, pins: u32, now: u64) -> (u32, u32) {
+            if (pins >> TX & 1 != 0) != self.level {
+                self.level = !self.level;
+                self.tx.push(now);
+            }
+            let high = self.playing.iter().any(|&(start, end)| (start..end).contains(&now));
+            ((high as u32) << RX, 1 << RX)
+        }
+    }
+
+    #[test]
+    fn codes() {
+        let socket = Code::pt2262("0FFF0FFFFFF0").unwrap();
+        assert_eq!(socket.value, 0b00_01_01_01_00_01_01_01_01_01_01_00);
+        let code = Code::ev1527(0x12345, 0x6);
+        assert_eq!(Protocol::EV1527.decode(&Protocol::EV1527.timings(&code)), Some((code, 350)));
+        assert!(Code::pt2262("0FFF0FFFFFF2").is_err());
+
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(10_000_000);
+        let radio = Arc::new(Mutex::new(Radio::default()));
+        backend.emulator().attach(radio.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let tx = Tx::new(pio.sm_claim(0).unwrap(), TX).unwrap().with_repeats(2).unwrap();
+        let mut rx = Rx::new(pio.sm_claim(1).unwrap(), RX).unwrap()
+            .with_protocols(&[Protocol::PT2262, FAST]).unwrap();
+
+        // What goes out is timed to the µs.
+        let code = Code { value: 0xa5c3, bits: 16 };
+        tx.send(&FAST, &code).unwrap();
+        backend.emulator().run(10_000 * US);
+        let edges = radio.lock().unwrap().tx.clone();
+        let timings: Vec<u32> = edges.windows(2).map(|w| ((w[1] - w[0]) / US) as u32).collect();
+        let frame = FAST.timings(&code);
+        assert_eq!(timings[..frame.len() - 1], frame[..frame.len() - 1]);
+        assert_eq!(timings[frame.len()..], frame[..frame.len() - 1]);
+
+        // Noise, then the frame twice with its pulses 20% long and highs stretched by 15 µs.
+        let start = backend.emulator().cycle() + 1000 * US;
+        let mut playing: Vec<(u64, u64)> = (0..20).map(|n| (start + n * 137 * US, start + n * 137 * US + 40 * US))
+            .collect();
+        let mut at = start + 3000 * US;
+        for _ in 0..2 {
+            for (n, timing) in FAST.timings(&code).into_iter().enumerate() {
+                let timing = timing as u64 * 6 / 5 * US;
+                if n % 2 == 0 {
+                    playing.push((at, at + timing + 15 * US));
+                }
+                at += timing;
+            }
+        }
+        radio.lock().unwrap().playing = playing;
+        for _ in 0..2 {
+            let received = rx.receive().unwrap();
+            assert_eq!((received.protocol, received.code), (1, code));
+            assert_eq!(received.pulse, 60);
+        }
+        assert!(rx.with_protocols(&[]).is_err());
+    }
+}
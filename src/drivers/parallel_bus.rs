@@ -0,0 +1,398 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// The 8 or 16 bit parallel MCU bus of TFT controllers like the ILI9341 and ILI9486, which is a lot quicker than their
+// SPI for pushing pixels around:
+//
+//     // D0 (with D1-D7 on 9-15), bits, DC, WR
+//     let mut bus = ParallelBus::new(pio.sm_claim_unused()?, 8, 8, 20, 22)?
+//         .with_cs(21)?                              // The pin after DC
+//         .with_rd(23)?                              // The pin after WR
+//         .with_frequency(15_000_000.0)?;
+//     let mut id = [0; 4];
+//     bus.read(0xd3, &mut id)?;                      // A dummy read, then 0x00, 0x93, 0x41
+//     bus.command(0x2a, &[0, 0, 0, 239])?;           // Column address set
+//     bus.command(0x2c, &[])?;                       // Memory write
+//     bus.write_pixels(&[0xf800; 320 * 240])?;       // Red, as RGB565
+//
+// It's the 8080 style bus (active low WR and RD strobes) unless with_interface() says it's the 6800 style (an active
+// high E strobe, with R/W saying which way). DC is low for commands and high for their parameters and data. CS, RD
+// (R/W for a 6800 bus) and reads are optional, since plenty of boards tie CS low and never read anything back.
+//
+// The SM writes a word every 2 cycles of its clock, so the frequency is the number of writes a second: 15 MHz is about
+// the fastest an ILI9341 will take, which is 30 MB/s of pixels on a 16 bit bus. Data is packed as many to a FIFO word
+// as fit, and anything longer than the FIFO is streamed with sm_xfer_data(). Reads hold the strobe for 32 cycles, which
+// is slow, but plenty for the controllers' register reads. CS is raised again after every command and after its data.
+
+use std::time::Duration;
+
+use crate::{pio_clock_hz, proc_pio::PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS, ClkDiv, Error, LoadedProgram, PioProgram,
+            SmConfig, StateMachine, XferDir};
+
+const CYCLES_PER_WRITE: f64 = 2.0;
+
+// What a header word is followed by.
+const WRITE: u32 = 0; // Words packed full of data
+const READ: u32 = 1; // A word of 0s for the pindirs, then one of 1s to drive the bus again after the reads
+const SINGLE: u32 = 2; // One datum a word, for whatever's left over after packing
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interface {
+    I8080,
+    M6800,
+}
+
+// The side-set values for each step, with the strobe (WR or E) in bit 0 and RD or R/W in bit 1.
+struct Strobes {
+    idle: u8,
+    write: u8,
+    read_setup: u8,
+    read: u8,
+}
+
+impl Interface {
+    fn strobes(self) -> Strobes {
+        match self {
+            Interface::I8080 => Strobes { idle: 0b11, write: 0b10, read_setup: 0b11, read: 0b01 },
+            Interface::M6800 => Strobes { idle: 0b00, write: 0b01, read_setup: 0b10, read: 0b11 },
+        }
+    }
+}
+
+pub struct ParallelBus<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    data: u32,
+    bits: u32,
+    dc: u32,
+    wr: u32,
+    cs: Option<u32>,
+    rd: Option<u32>,
+    interface: Interface,
+    clkdiv: ClkDiv,
+    words: Vec<u32>,
+}
+
+impl<'a> ParallelBus<'a> {
+    // `data` is D0, with the rest of the `bits` (8 or 16) data lines on the pins after it. Starts out as an 8080 bus
+    // at 10 MHz.
+    pub fn new(sm: StateMachine<'a>, data: u32, bits: u32, dc: u32, wr: u32) -> Result<ParallelBus<'a>, Error> {
+        if bits != 8 && bits != 16 {
+            Err(Error::ParamErr { param: "bits", should_be: "8 or 16".to_string() })?;
+        }
+        for pin in [data, data + bits - 1, dc, wr] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, 10_000_000.0 * CYCLES_PER_WRITE)?;
+        let mut bus = ParallelBus { sm, program: None, data, bits, dc, wr, cs: None, rd: None,
+                                    interface: Interface::I8080, clkdiv, words: Vec::new() };
+        bus.setup()?;
+        Ok(bus)
+    }
+
+    // The chip select has to be the pin after DC.
+    pub fn with_cs(mut self, cs: u32) -> Result<Self, Error> {
+        if cs != self.dc + 1 {
+            Err(Error::ParamErr { param: "cs", should_be: format!("{} (the pin after DC)", self.dc + 1) })?;
+        }
+        self.sm.pio().check_gpio(cs as u16)?;
+        self.cs = Some(cs);
+        self.setup()?;
+        Ok(self)
+    }
+
+    // RD (or R/W on a 6800 bus), which has to be the pin after WR (or E). Without it read() is an error.
+    pub fn with_rd(mut self, rd: u32) -> Result<Self, Error> {
+        if rd != self.wr + 1 {
+            Err(Error::ParamErr { param: "rd", should_be: format!("{} (the pin after WR)", self.wr + 1) })?;
+        }
+        self.sm.pio().check_gpio(rd as u16)?;
+        self.rd = Some(rd);
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn with_interface(mut self, interface: Interface) -> Result<Self, Error> {
+        self.interface = interface;
+        self.setup()?;
+        Ok(self)
+    }
+
+    // Writes a second.
+    pub fn with_frequency(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz * CYCLES_PER_WRITE)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    // The write rate actually achievable with the SM's clock divider.
+    pub fn frequency(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64) / CYCLES_PER_WRITE
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn interface(&self) -> Interface {
+        self.interface
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.flush()?;
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // A command with DC low, then its parameters with DC high, a byte to a write (in the bottom 8 bits on a 16 bit
+    // bus).
+    pub fn command(&mut self, command: u8, params: &[u8]) -> Result<(), Error> {
+        self.send(false, 1, [command as u32].into_iter())?;
+        self.write_data(params)
+    }
+
+    pub fn write_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.send(true, data.len(), data.iter().map(|&byte| byte as u32))
+    }
+
+    // 16 bit data (RGB565 pixels, usually), a write each on a 16 bit bus or the high byte then the low one on an 8 bit
+    // bus.
+    pub fn write_pixels(&mut self, pixels: &[u16]) -> Result<(), Error> {
+        match self.bits {
+            16 => self.send(true, pixels.len(), pixels.iter().map(|&pixel| pixel as u32)),
+            _  => self.send(true, pixels.len() * 2, pixels.iter().flat_map(|pixel| pixel.to_be_bytes()).map(u32::from)),
+        }
+    }
+
+    // Sends `command` and then reads data.len() words back.
+    pub fn read(&mut self, command: u8, data: &mut [u16]) -> Result<(), Error> {
+        if self.rd.is_none() {
+            Err(Error::ParamErr { param: "rd", should_be: "set with with_rd() to read".to_string() })?;
+        }
+        self.send(false, 1, [command as u32].into_iter())?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        for word in [header(true, READ, data.len()), 0, !0] {
+            self.sm.put(word, true)?;
+        }
+        for datum in data.iter_mut() {
+            *datum = (self.sm.get(true)? & ((1 << self.bits) - 1)) as u16;
+        }
+        Ok(())
+    }
+
+    // Everything's gone out once the FIFO's empty and the SM is back to stalling on its first `out`.
+    pub fn is_idle(&self) -> Result<bool, Error> {
+        let hw = self.sm.read_hw_state_machine()?;
+        let offset = self.program.as_ref().map(|program| program.offset() as u32);
+        let stalled = hw.execctrl & PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS != 0;
+        Ok(self.sm.is_tx_fifo_empty()? && Some(hw.pc) == offset && stalled)
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        while !self.is_idle()? {
+            std::thread::sleep(Duration::from_micros(10));
+        }
+        Ok(())
+    }
+
+    // Packs as many whole words as there are, then sends whatever's left a datum to a word.
+    fn send(&mut self, data: bool, count: usize, mut datums: impl Iterator<Item = u32>) -> Result<(), Error> {
+        let (bits, per_word) = (self.bits, (32 / self.bits) as usize);
+        let whole = count / per_word * per_word;
+        self.words.clear();
+        if whole > 0 {
+            self.words.push(header(data, WRITE, whole));
+            for _ in 0..whole / per_word {
+                let word = (0..per_word).fold(0, |word, n| word | datums.next().unwrap_or(0) << (n as u32 * bits));
+                self.words.push(word);
+            }
+        }
+        if count > whole {
+            self.words.push(header(data, SINGLE, count - whole));
+            self.words.extend(datums);
+        }
+        self.stream()
+    }
+
+    fn stream(&self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        if self.words.len() <= pio.chip().fifo_depth as usize {
+            for &word in &self.words {
+                self.sm.put(word, true)?;
+            }
+        } else {
+            let bytes = size_of_val(&self.words[..]) as u32;
+            pio.sm_config_xfer(self.sm.index(), XferDir::ToSm, bytes.min(64 * 1024), 4)?;
+            pio.sm_xfer_data(self.sm.index(), XferDir::ToSm, bytes, &self.words[0])?;
+        }
+        Ok(())
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let side_bits = if self.rd.is_some() { 2 } else { 1 };
+        let side = |value: u8| value & ((1 << side_bits) - 1);
+        let Strobes { idle, write, read_setup, read } = self.interface.strobes();
+        let (idle, write, read_setup, read) = (side(idle), side(write), side(read_setup), side(read));
+        let bits = self.bits;
+        let program = pio.load_program(&PioProgram::assemble(&format!("
+            .program parallel_bus
+            .side_set {side_bits}
+            .wrap_target
+                out x, 1                side {idle}     ; DC
+                jmp !x command          side {idle}
+                set pins, 1             side {idle}     ; DC high, and CS low
+                jmp header              side {idle}
+            command:
+                set pins, 0             side {idle}
+            header:
+                out y, 2                side {idle}     ; WRITE, READ or SINGLE
+                out x, 29               side {idle}     ; How many, less 1
+                jmp y-- other           side {idle}
+            write:
+                out pins, {bits}        side {write}
+                jmp x-- write           side {idle}
+                jmp done                side {idle}
+            other:
+                jmp !y read             side {idle}
+            single:
+                out pins, {bits}        side {write}
+                out null, {rest}        side {idle}
+                jmp x-- single          side {idle}
+                jmp done                side {idle}
+            read:
+                out pindirs, 32         side {idle}     ; Let go of the bus
+            next:
+                set y, 7                side {read_setup}
+            strobe:
+                jmp y-- strobe          side {read} [3]
+                in pins, {bits}         side {read}
+                jmp x-- next            side {read_setup} [7]
+                out pindirs, 32         side {idle}     ; And take it back
+            done:
+                set pins, 2             side {idle}     ; CS high
+            .wrap
+        ", rest = 32 - bits))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.data, bits)?
+            .set_in_pins(self.data)?
+            .set_set_pins(self.dc, if self.cs.is_some() { 2 } else { 1 })?
+            .set_sideset(side_bits, false, false)?
+            .set_sideset_pins(self.wr)?
+            .set_out_shift(true, true, 32)?
+            .set_in_shift(false, true, bits)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        let data = ((1 << bits) - 1) << self.data;
+        let control = [Some(self.dc), self.cs, Some(self.wr), self.rd].into_iter().flatten()
+            .fold(0, |pins, pin| pins | 1 << pin);
+        let levels = self.cs.map_or(0, |cs| 1 << cs) | (idle as u32) << self.wr;
+        self.sm.set_pins_with_mask(levels, data | control)?;
+        self.sm.set_pindirs_with_mask(data | control, data | control)?;
+        for pin in (0..32).filter(|pin| (data | control) >> pin & 1 != 0) {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+fn header(data: bool, kind: u32, count: usize) -> u32 {
+    data as u32 | kind << 1 | (count as u32 - 1) << 3
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const D0: u32 = 4;
+    const DC: u32 = 20;
+    const CS: u32 = 21;
+    const WR: u32 = 22;
+    const RD: u32 = 23;
+
+    // Latches (DC, data) on each WR rising edge (or E falling one) while CS is low, and drives `id` onto the bus a
+    // word per RD strobe.
+    #[derive(Debug, Default)]
+    struct Display {
+        m6800: bool,
+        strobe: bool,
+        reading: bool,
+        id: Vec<u16>,
+        received: Vec<(bool, u16)>,
+    }
+
+    impl Peripheral for Display {
+        fn step(&mut self, pins: u32, _cycle: u64) -> (u32, u32) {
+            let (cs, dc, data) = (pins >> CS & 1 != 0, pins >> DC & 1 != 0, (pins >> D0 & 0xffff) as u16);
+            let (strobe, rd) = (pins >> WR & 1 != 0, pins >> RD & 1 != 0);
+            let (strobe, reading) = match self.m6800 {
+                false => (!strobe, !rd),
+                true  => (strobe && !rd, strobe && rd),
+            };
+            if self.strobe && !strobe && !cs {
+                self.received.push((dc, data));
+            }
+            if self.reading && !reading && !self.id.is_empty() {
+                self.id.remove(0);
+            }
+            (self.strobe, self.reading) = (strobe, reading);
+            match (reading && !cs, self.id.first()) {
+                (true, Some(&id)) => ((id as u32) << D0, 0xffff << D0),
+                _                 => (0, 0),
+            }
+        }
+    }
+
+    #[test]
+    fn writes_and_reads() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let display = Arc::new(Mutex::new(Display { id: vec![0xff, 0x00, 0x93, 0x41], ..Display::default() }));
+        backend.emulator().attach(display.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut bus = ParallelBus::new(pio.sm_claim(0).unwrap(), D0, 8, DC, WR).unwrap()
+            .with_cs(CS).unwrap()
+            .with_rd(RD).unwrap()
+            .with_frequency(25_000_000.0).unwrap();
+        assert_eq!(bus.frequency(), 25_000_000.0);
+
+        let mut id = [0; 4];
+        bus.read(0xd3, &mut id).unwrap();
+        assert_eq!(id, [0xff, 0x00, 0x93, 0x41]);
+        bus.command(0x2a, &[0, 0, 0, 239, 7]).unwrap();
+        bus.write_pixels(&[0xf800, 0x07e0, 0x001f]).unwrap();
+        backend.emulator().run(1000);
+        let received = std::mem::take(&mut display.lock().unwrap().received);
+        let mut expected = vec![(false, 0xd3), (false, 0x2a)];
+        expected.extend([0, 0, 0, 239, 7, 0xf8, 0x00, 0x07, 0xe0, 0x00, 0x1f].map(|byte| (true, byte)));
+        assert_eq!(received, expected);
+        let sm = bus.into_inner().unwrap();
+        assert!(ParallelBus::new(sm, D0, 12, DC, WR).is_err());
+
+        // 16 bits wide, with E and R/W instead.
+        display.lock().unwrap().m6800 = true;
+        let mut bus = ParallelBus::new(pio.sm_claim(1).unwrap(), D0, 16, DC, WR).unwrap()
+            .with_cs(CS).unwrap()
+            .with_rd(RD).unwrap()
+            .with_interface(Interface::M6800).unwrap();
+        display.lock().unwrap().id = vec![0x1234, 0xabcd];
+        let mut id = [0; 2];
+        bus.read(0x04, &mut id).unwrap();
+        assert_eq!(id, [0x1234, 0xabcd]);
+        bus.write_pixels(&[0xf800, 0x07e0, 0x001f]).unwrap();
+        backend.emulator().run(1000);
+        let received = std::mem::take(&mut display.lock().unwrap().received);
+        assert_eq!(received, [(false, 0x04), (true, 0xf800), (true, 0x07e0), (true, 0x001f)]);
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Samples out to consecutive pins at a steady rate, for an R-2R resistor ladder or a DAC with a parallel input, which
+// makes an arbitrary waveform generator:
+//
+//     let mut dac = ParallelDac::new(pio.sm_claim_unused()?, 4, 8, 1_000_000)?;   // pins 4..=11, 1 MS/s
+//     dac.write(&[0, 64, 128, 192, 255])?;
+//     dac.play(&sine(256, 8), 1000.0, Duration::from_secs(2))?;                   // a 1 kHz sine for 2 seconds
+//
+// play() steps through a table of one cycle of any waveform (sine(), square(), triangle() and sawtooth() make the
+// usual ones) at whatever frequency, with a phase accumulator, and carries on from the same phase next time, so
+// back to back calls join up without a glitch.
+//
+// Samples are packed as many to a FIFO word as fit, so a write() that doesn't fill its last word leaves the rest
+// waiting for the next one; flush() sends them. Writes much longer than the FIFO are streamed with sm_xfer_data(), and
+// the SM takes a sample every cycle, so the rate is mostly limited by how fast that can go. When the SM runs out it
+// holds the last sample.
+
+use std::{f64::consts::TAU, time::Duration};
+
+use super::led;
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+// Samples generated at a time by play().
+const CHUNK: usize = 4096;
+
+pub struct ParallelDac<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    base: u32,
+    bits: u32,
+    clkdiv: ClkDiv,
+    pending: Vec<u16>, // Samples that don't fill a word yet
+    phase: u32,
+    words: Vec<u32>,
+}
+
+impl<'a> ParallelDac<'a> {
+    pub fn new(sm: StateMachine<'a>, base: u32, bits: u32, sample_rate: u32) -> Result<ParallelDac<'a>, Error> {
+        if !(1..=16).contains(&bits) {
+            Err(Error::ParamErr { param: "bits", should_be: "1..=16".to_string() })?;
+        }
+        for pin in base..base + bits {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, sample_rate as f64)?;
+        let mut dac = ParallelDac { sm, program: None, base, bits, clkdiv, pending: Vec::new(), phase: 0,
+                                    words: Vec::new() };
+        dac.setup()?;
+        Ok(dac)
+    }
+
+    pub fn with_sample_rate(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    // The rate actually achievable with the SM's clock divider.
+    pub fn sample_rate(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64)
+    }
+
+    // Stops the SM, leaving the pins at the last sample.
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Queues samples, of which only the bottom bits() bits are used.
+    pub fn write(&mut self, samples: &[u16]) -> Result<(), Error> {
+        let per_word = self.per_word();
+        self.pending.extend_from_slice(samples);
+        let whole = self.pending.len() / per_word * per_word;
+        self.words.clear();
+        self.words.extend(self.pending[..whole].chunks(per_word).map(|chunk| {
+            chunk.iter().enumerate().fold(0, |word, (n, &s)| word | (s as u32 & mask(self.bits)) << (n as u32 * self.bits))
+        }));
+        self.pending.drain(..whole);
+        if self.words.is_empty() {
+            return Ok(());
+        }
+        led::queue(&self.sm, &self.words)
+    }
+
+    // Sends any samples left over from the last write(), filling out the word by repeating the last of them.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if let Some(&last) = self.pending.last() {
+            let padding = vec![last; self.per_word() - self.pending.len()];
+            self.write(&padding)?;
+        }
+        Ok(())
+    }
+
+    // Plays one cycle's worth of `table` over and over, `frequency` times a second.
+    pub fn play(&mut self, table: &[u16], frequency: f64, duration: Duration) -> Result<(), Error> {
+        if table.is_empty() {
+            Err(Error::ParamErr { param: "table", should_be: "not empty".to_string() })?;
+        }
+        let rate = self.sample_rate();
+        if !(0.0..rate / 2.0).contains(&frequency) {
+            Err(Error::ParamErr { param: "frequency", should_be: format!("less than {}", rate / 2.0) })?;
+        }
+        let step = (frequency / rate * 2f64.powi(32)).round() as u32;
+        let mut remaining = (duration.as_secs_f64() * rate).round() as usize;
+        let mut samples = Vec::with_capacity(CHUNK.min(remaining));
+        while remaining > 0 {
+            samples.clear();
+            for _ in 0..CHUNK.min(remaining) {
+                samples.push(table[((self.phase as u64 * table.len() as u64) >> 32) as usize]);
+                self.phase = self.phase.wrapping_add(step);
+            }
+            remaining -= samples.len();
+            self.write(&samples)?;
+        }
+        Ok(())
+    }
+
+    fn per_word(&self) -> usize {
+        (32 / self.bits) as usize
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&format!("
+            .program parallel_dac
+            .wrap_target
+                out pins, {}
+            .wrap
+        ", self.bits))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.base, self.bits)?
+            .set_out_shift(true, true, self.per_word() as u32 * self.bits)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        let pins = mask(self.bits) << self.base;
+        for pin in self.base..self.base + self.bits {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.set_pins_with_mask(0, pins)?;
+        self.sm.set_pindirs_with_mask(pins, pins)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.pending.clear();
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+fn mask(bits: u32) -> u32 {
+    (1 << bits) - 1
+}
+
+fn table(len: usize, bits: u32, f: impl Fn(f64) -> f64) -> Vec<u16> {
+    let top = mask(bits) as f64;
+    (0..len).map(|n| (f(n as f64 / len as f64) * top).round().clamp(0.0, top) as u16).collect()
+}
+
+// Tables of one cycle, `len` samples long and spanning the whole range of a `bits` bit DAC.
+pub fn sine(len: usize, bits: u32) -> Vec<u16> {
+    table(len, bits, |t| 0.5 - 0.5 * (t * TAU).cos())
+}
+
+// High for `duty` (0 to 1) of the cycle.
+pub fn square(len: usize, bits: u32, duty: f64) -> Vec<u16> {
+    table(len, bits, |t| if t < duty { 1.0 } else { 0.0 })
+}
+
+pub fn triangle(len: usize, bits: u32) -> Vec<u16> {
+    table(len, bits, |t| 1.0 - (2.0 * t - 1.0).abs())
+}
+
+pub fn sawtooth(len: usize, bits: u32) -> Vec<u16> {
+    table(len, bits, |t| t * len as f64 / (len - 1).max(1) as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const BASE: u32 = 4;
+
+    // Records what's on the DAC's pins each time it changes.
+    #[derive(Debug, Default)]
+    struct Probe(Vec<u16>);
+
+    impl Peripheral for Probe {
+        fn step(&mut self, pins: u32, _cycle: u64) -> (u32, u32) {
+            let value = (pins >> BASE & 0xff) as u16;
+            if self.0.last() != Some(&value) {
+                self.0.push(value);
+            }
+            (0, 0)
+        }
+    }
+
+    #[test]
+    fn waveforms() {
+        assert_eq!(sine(3, 2), [0, 2, 2]);
+        assert_eq!(square(4, 4, 0.25), [15, 0, 0, 0]);
+        assert_eq!(triangle(4, 8), [0, 128, 255, 128]);
+        assert_eq!(sawtooth(4, 2), [0, 1, 2, 3]);
+
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let probe = Arc::new(Mutex::new(Probe::default()));
+        backend.emulator().attach(probe.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut dac = ParallelDac::new(pio.sm_claim(0).unwrap(), BASE, 8, 1_000_000).unwrap();
+        assert_eq!(dac.sample_rate(), 1_000_000.0);
+
+        dac.write(&[1, 2, 3, 4, 5, 0x106]).unwrap(); // The last two wait for two more, and 0x106 is too big
+        dac.play(&[10, 20, 30, 40], 250_000.0, Duration::from_micros(8)).unwrap();
+        dac.write(&[7]).unwrap();
+        dac.flush().unwrap();
+        backend.emulator().run(200 * 20);
+        assert_eq!(probe.lock().unwrap().0, [0, 1, 2, 3, 4, 5, 6, 10, 20, 30, 40, 10, 20, 30, 40, 7]);
+
+        assert!(dac.play(&[0], 600_000.0, Duration::from_millis(1)).is_err());
+        assert!(ParallelDac::new(pio.sm_claim(1).unwrap(), BASE, 17, 1000).is_err());
+    }
+}
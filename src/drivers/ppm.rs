@@ -0,0 +1,214 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// CPPM (PPM-sum), the one-wire output of older RC receivers and trainer ports, where each channel is the time from one
+// short pulse to the next and a long gap ends the frame:
+//
+//     let mut ppm = Ppm::new(pio.sm_claim_unused()?, 5)?.with_channels(8)?;
+//     loop {
+//         let channels = ppm.frame()?;       // µs, about 1000 to 2000
+//         steer(channels[0], channels[1]);
+//     }
+//
+// The SM keeps a 1 µs count going and pushes it at each rising edge, so the times are exact however late they're read.
+// Measuring from rising edge to rising edge gives the same answer whichever way up the pulses are. A frame only counts
+// if it's between two gaps, every channel is within with_range(), and it has with_channels() channels (or, without
+// that, as many as the frame before it), so a glitch or edges lost to a full RX FIFO throw the frame away rather than
+// shifting everything along a channel.
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+const COUNT_HZ: f64 = 1_000_000.0;
+const CYCLES_PER_COUNT: f64 = 4.0;
+const MAX_CHANNELS: usize = 16;
+
+// Every path through takes 4 cycles and counts X down once.
+const PROGRAM: &str = "
+    .program ppm
+        mov x, ~null
+    .wrap_target
+    low:
+        jmp x-- low_1
+    low_1:
+        jmp pin rise
+        jmp low             [1]
+    rise:
+        mov isr, x
+        push noblock
+    high:
+        jmp x-- high_1
+    high_1:
+        jmp pin still
+        nop                 [1]
+    .wrap
+    still:
+        jmp high            [1]
+";
+
+pub struct Ppm<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    range: (u32, u32),       // µs. Anything longer is a gap.
+    channels: Option<usize>,
+    seen: Option<usize>,     // Channels in the last frame
+    last: Option<u32>,       // Count at the last edge
+    frame: Option<Vec<u32>>, // None until the next gap, after a bad channel
+}
+
+impl<'a> Ppm<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<Ppm<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut ppm = Ppm { sm, program: None, pin, range: (750, 2250), channels: None, seen: None, last: None,
+                            frame: None };
+        ppm.setup()?;
+        Ok(ppm)
+    }
+
+    // How many channels a frame has to have.
+    pub fn with_channels(mut self, channels: usize) -> Result<Self, Error> {
+        if !(1..=MAX_CHANNELS).contains(&channels) {
+            Err(Error::ParamErr { param: "channels", should_be: format!("1 to {}", MAX_CHANNELS) })?;
+        }
+        self.channels = Some(channels);
+        Ok(self)
+    }
+
+    // The shortest and longest a channel can be, in µs. Anything longer is the gap between frames, so this has to be
+    // less than the shortest gap.
+    pub fn with_range(mut self, min: u32, max: u32) -> Result<Self, Error> {
+        if min >= max {
+            Err(Error::ParamErr { param: "min", should_be: "less than max".to_string() })?;
+        }
+        self.range = (min, max);
+        Ok(self)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Waits for the next good frame: each channel, in µs.
+    pub fn frame(&mut self) -> Result<Vec<u32>, Error> {
+        loop {
+            let count = self.sm.get(true)?;
+            if let Some(frame) = self.received(count) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    // Returns None instead of waiting when no frame has finished arriving.
+    pub fn try_frame(&mut self) -> Result<Option<Vec<u32>>, Error> {
+        while !self.sm.is_rx_fifo_empty()? {
+            let count = self.sm.get(false)?;
+            if let Some(frame) = self.received(count) {
+                return Ok(Some(frame));
+            }
+        }
+        Ok(None)
+    }
+
+    fn received(&mut self, count: u32) -> Option<Vec<u32>> {
+        let last = self.last.replace(count)?;
+        let us = last.wrapping_sub(count); // It counts down
+        let (min, max) = self.range;
+        if us > max {
+            let frame = self.frame.replace(Vec::with_capacity(MAX_CHANNELS))?;
+            let expected = self.channels.or(self.seen.replace(frame.len()));
+            return (!frame.is_empty() && expected == Some(frame.len())).then_some(frame);
+        }
+        match &mut self.frame {
+            Some(frame) if us >= min && frame.len() < MAX_CHANNELS => frame.push(us),
+            _                                                      => self.frame = None,
+        }
+        None
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_jmp_pin(self.pin)?
+            .set_fifo_join(PioFifoJoin::Rx)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, COUNT_HZ * CYCLES_PER_COUNT)?)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pindirs_with_mask(0, 1 << self.pin)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::{Arc, Mutex}};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const PIN: u32 = 5;
+    const US: u64 = 200; // Cycles
+
+    // Sends 300 µs pulses at the given times (µs), after which the line stays low.
+    #[derive(Debug)]
+    struct Transmitter {
+        edges: VecDeque<u64>,
+    }
+
+    impl Transmitter {
+        // Each frame's channels, then a 4 ms gap, starting 100 µs in.
+        fn new(frames: &[&[u64]]) -> Transmitter {
+            let mut edges = VecDeque::new();
+            let mut t = 100;
+            for frame in frames {
+                edges.push_back(t);
+                for channel in *frame {
+                    t += channel;
+                    edges.push_back(t);
+                }
+                t += 4000;
+            }
+            edges.push_back(t);
+            Transmitter { edges }
+        }
+    }
+
+    impl Peripheral for Transmitter {
+        fn step(&mut self, _pins: u32, now: u64) -> (u32, u32) {
+            let t = now / US;
+            while self.edges.front().is_some_and(|&edge| t >= edge + 300) {
+                self.edges.pop_front();
+            }
+            let high = self.edges.front().is_some_and(|&edge| t >= edge);
+            ((high as u32) << PIN, 1 << PIN)
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(10_000_000);
+        let a: &[u64] = &[1000, 1500, 2000, 1200];
+        let b: &[u64] = &[1100, 1100, 1100];
+        let c: &[u64] = &[1900, 1800, 1700, 1600];
+        let glitch: &[u64] = &[1000, 500, 1000, 1500, 2000];
+        backend.emulator().attach(Arc::new(Mutex::new(Transmitter::new(&[a, a, a, b, glitch, c, c]))));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut ppm = Ppm::new(pio.sm_claim(0).unwrap(), PIN).unwrap();
+
+        // The first frame is only a gap to line up with, and the second sets how many channels there should be.
+        assert_eq!(ppm.frame().unwrap(), [1000, 1500, 2000, 1200]);
+        // b has too few channels and the glitch has too short a one, which leaves c to set the count again.
+        assert_eq!(ppm.frame().unwrap(), [1900, 1800, 1700, 1600]);
+        assert_eq!(ppm.try_frame().unwrap(), None);
+        assert!(ppm.with_channels(17).is_err());
+    }
+}
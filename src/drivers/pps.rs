@@ -0,0 +1,322 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Timestamps a GPS receiver's pulse per second against the system clocks, for checking (or disciplining) them:
+//
+//     let mut pps = Pps::new(pio.sm_claim_unused()?, 18)?;
+//     loop {
+//         let edge = pps.next_edge()?;
+//         println!("{:?}: {:+.3} µs ± {:.3}", edge.realtime, edge.offset * 1e6, edge.uncertainty * 1e6);
+//         if let Some(stats) = pps.stats() {
+//             println!("{:+.3} µs mean, {:.3} µs jitter, PIO clock {:+.3} ppm", stats.offset * 1e6,
+//                      stats.jitter * 1e6, stats.ppm);
+//         }
+//     }
+//
+// The SM counts ticks of 4 PIO clock cycles (20 ns) and pushes the count at each rising edge, so edges are timed to
+// the tick however late they're read. To put them on the system clocks, each edge is followed by sample(), which asks
+// the SM for the tick it's on and reads CLOCK_MONOTONIC and CLOCK_REALTIME either side, a few times over, keeping the
+// quickest. Half of that round trip (usually a few µs) is the edge's `uncertainty`. The ticks in between are converted
+// at the rate the edges themselves say the PIO clock runs at, so the PIO clock's own error doesn't come into it.
+//
+// An edge's `offset` is how far CLOCK_REALTIME is ahead of the GPS second it marks, taking it to be the nearest one.
+// stats() keeps the last 64 edges and gives the mean and standard deviation of their offsets and how far off the PIO
+// clock is. with_period() is for receivers that pulse more often than once a second.
+//
+// The counts are 31 bits, which wrap after 43 s, and the RX FIFO holds 8 edges (the SM stops counting when it's full),
+// so call next_edge() or try_edge() at least every few seconds.
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioMovStatus, PioProgram, SmConfig, StateMachine};
+
+const TICK_CYCLES: u64 = 4;
+const TICK_MASK: u64 = (1 << 31) - 1;
+const WINDOW: usize = 64;
+const SAMPLES: usize = 3;
+// How close an interval has to be to the period to be taken as one period (and not a missed edge or a glitch)
+const MAX_PPM: f64 = 500.0;
+
+// Y counts down once every 4 cycles: every block of 4 (the loops, and the paths out of them to the next block of 4)
+// has exactly one `jmp y--`. The SM checks for a request (any word from the CPU) in each tick and the pin at the end of
+// it. Each word pushed is the bottom 31 bits of Y then a flag: 0 for an edge, 1 (from the request) for a sample.
+const PROGRAM: &str = "
+    .program pps
+    rise:
+        in y, 31
+        in null, 1
+        jmp y-- high        [1]
+    high:
+        jmp y-- high_1
+    high_1:
+        mov x, status
+        jmp !x high_request
+        jmp pin high                ; Falls into low when the pulse ends
+    .wrap_target
+    low:
+        jmp y-- low_1
+    low_1:
+        mov x, status
+        jmp !x low_request
+        jmp pin rise
+    .wrap
+    low_request:
+        pull
+        jmp y-- low_request_1
+    low_request_1:
+        in y, 31
+        in osr, 1
+        jmp low
+    high_request:
+        pull
+        jmp y-- high_request_1
+    high_request_1:
+        in y, 31
+        in osr, 1
+        jmp high
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edge {
+    pub ticks: u64,          // Since new(), 4 PIO clock cycles each
+    pub monotonic: Duration, // CLOCK_MONOTONIC at the edge
+    pub realtime: Duration,  // CLOCK_REALTIME at the edge, since the epoch
+    pub offset: f64,         // s CLOCK_REALTIME is ahead of the nearest whole period
+    pub uncertainty: f64,    // s either way, from sampling the clocks
+}
+
+// The SM's tick and the system clocks, all at the same moment (give or take `uncertainty`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub ticks: u64,
+    pub monotonic: Duration,
+    pub realtime: Duration,
+    pub uncertainty: f64, // s
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub edges: usize,
+    pub offset: f64, // s, the mean
+    pub jitter: f64, // s, the offsets' standard deviation
+    pub ppm: f64,    // How much faster than it should the PIO clock runs, going by the edges
+}
+
+pub struct Pps<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    period: Duration,
+    ticks: u64,              // The latest count, unwrapped
+    pending: VecDeque<u64>,  // Edges that came in while sample() was waiting
+    history: VecDeque<Edge>,
+}
+
+impl<'a> Pps<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<Pps<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut pps = Pps { sm, program: None, pin, period: Duration::from_secs(1), ticks: 0, pending: VecDeque::new(),
+                            history: VecDeque::new() };
+        pps.setup()?;
+        Ok(pps)
+    }
+
+    // The time between pulses, for receivers set to something other than 1 PPS.
+    pub fn with_period(mut self, period: Duration) -> Result<Self, Error> {
+        if !(Duration::from_millis(1)..=Duration::from_secs(10)).contains(&period) {
+            Err(Error::ParamErr { param: "period", should_be: "1 ms to 10 s".to_string() })?;
+        }
+        self.period = period;
+        self.history.clear();
+        Ok(self)
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Waits for the next edge (however long that takes: there's no timeout).
+    pub fn next_edge(&mut self) -> Result<Edge, Error> {
+        let ticks = match self.pending.pop_front() {
+            Some(ticks) => ticks,
+            None        => { let word = self.sm.get(true)?; self.unwrap(word) },
+        };
+        self.edge(ticks)
+    }
+
+    pub fn try_edge(&mut self) -> Result<Option<Edge>, Error> {
+        if self.pending.is_empty() && self.sm.is_rx_fifo_empty()? {
+            return Ok(None);
+        }
+        self.next_edge().map(Some)
+    }
+
+    // Over the last 64 edges, or None until there've been two.
+    pub fn stats(&self) -> Option<Stats> {
+        if self.history.len() < 2 {
+            return None;
+        }
+        let n = self.history.len() as f64;
+        let offset = self.history.iter().map(|edge| edge.offset).sum::<f64>() / n;
+        let variance = self.history.iter().map(|edge| (edge.offset - offset).powi(2)).sum::<f64>() / (n - 1.0);
+        let nominal = TICK_CYCLES as f64 / pio_clock_hz() as f64;
+        let intervals: Vec<u64> = self.history.iter().zip(self.history.iter().skip(1))
+            .map(|(a, b)| b.ticks - a.ticks)
+            .filter(|&ticks| self.is_period(ticks))
+            .collect();
+        let seconds = intervals.iter().sum::<u64>() as f64 * nominal;
+        let ppm = match intervals.len() {
+            0     => 0.0,
+            count => (seconds / (count as f64 * self.period.as_secs_f64()) - 1.0) * 1e6,
+        };
+        Some(Stats { edges: self.history.len(), offset, jitter: variance.sqrt(), ppm })
+    }
+
+    // Reads the SM's tick and the system clocks together. next_edge() does this after each edge, so it's only needed
+    // for putting times of your own on the SM's ticks.
+    pub fn sample(&mut self) -> Result<Sample, Error> {
+        let mut best = self.sample_once()?;
+        for _ in 1..SAMPLES {
+            let sample = self.sample_once()?;
+            if sample.uncertainty < best.uncertainty {
+                best = sample;
+            }
+        }
+        Ok(best)
+    }
+
+    fn sample_once(&mut self) -> Result<Sample, Error> {
+        let (monotonic, realtime) = (now(libc::CLOCK_MONOTONIC), now(libc::CLOCK_REALTIME));
+        self.sm.put(1, true)?;
+        let ticks = loop {
+            let word = self.sm.get(true)?;
+            let ticks = self.unwrap(word);
+            if word & 1 != 0 {
+                break ticks;
+            }
+            self.pending.push_back(ticks);
+        };
+        let (realtime_after, monotonic_after) = (now(libc::CLOCK_REALTIME), now(libc::CLOCK_MONOTONIC));
+        let round_trip = monotonic_after.saturating_sub(monotonic);
+        Ok(Sample { ticks, monotonic: monotonic + round_trip / 2,
+                    realtime: realtime + realtime_after.saturating_sub(realtime) / 2,
+                    uncertainty: round_trip.as_secs_f64() / 2.0 })
+    }
+
+    fn edge(&mut self, ticks: u64) -> Result<Edge, Error> {
+        let sample = self.sample()?;
+        // At the rate since the last edge, if it was a period ago
+        let seconds_per_tick = match self.history.back() {
+            Some(last) if self.is_period(ticks - last.ticks) => self.period.as_secs_f64() / (ticks - last.ticks) as f64,
+            _                                               => TICK_CYCLES as f64 / pio_clock_hz() as f64,
+        };
+        let before = Duration::from_secs_f64((sample.ticks - ticks) as f64 * seconds_per_tick);
+        let realtime = sample.realtime.saturating_sub(before);
+        let period = self.period.as_nanos();
+        let ns = match realtime.as_nanos() % period {
+            ns if ns > period / 2 => ns as f64 - period as f64,
+            ns                    => ns as f64,
+        };
+        let edge = Edge { ticks, monotonic: sample.monotonic.saturating_sub(before), realtime, offset: ns / 1e9,
+                          uncertainty: sample.uncertainty };
+        if self.history.len() == WINDOW {
+            self.history.pop_front();
+        }
+        self.history.push_back(edge);
+        Ok(edge)
+    }
+
+    fn is_period(&self, ticks: u64) -> bool {
+        let seconds = ticks as f64 * TICK_CYCLES as f64 / pio_clock_hz() as f64;
+        (seconds / self.period.as_secs_f64() - 1.0).abs() < MAX_PPM / 1e6
+    }
+
+    // Y counts down from wherever it started, so its 31 bits negated count up, and they're taken to be the first count
+    // that matches after the latest.
+    fn unwrap(&mut self, word: u32) -> u64 {
+        let count = (word as u64 >> 1).wrapping_neg() & TICK_MASK;
+        self.ticks += count.wrapping_sub(self.ticks) & TICK_MASK;
+        self.ticks
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_jmp_pin(self.pin)?
+            .set_in_shift(false, true, 32)?
+            .set_mov_status(PioMovStatus::TxLessThan, 1)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, pio_clock_hz() as f64)?)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pindirs_with_mask(0, 1 << self.pin)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.init(wrap_target as u16, &config)?; // At low, so it doesn't start with an edge
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+fn now(clock: libc::clockid_t) -> Duration {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // Safety: `time` is a valid timespec for the call to fill in.
+    unsafe { libc::clock_gettime(clock, &mut time) };
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const PIN: u32 = 18;
+    const PERIOD: u64 = 200_000; // Cycles, or 1 ms
+    const WIDTH: u64 = 20_001;
+
+    #[derive(Debug)]
+    struct Receiver;
+
+    impl Peripheral for Receiver {
+        fn step(&mut self, _pins: u32, now: u64) -> (u32, u32) {
+            (((now % PERIOD > PERIOD - WIDTH) as u32) << PIN, 1 << PIN)
+        }
+    }
+
+    #[test]
+    fn edges() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        backend.emulator().attach(Arc::new(Mutex::new(Receiver)));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut pps = Pps::new(pio.sm_claim(0).unwrap(), PIN).unwrap()
+            .with_period(Duration::from_millis(1)).unwrap();
+        assert!(pps.try_edge().unwrap().is_none());
+
+        // Samples (taken while the pulse is high, straight after each edge, or low, here) don't throw the count off.
+        let mut ticks = Vec::new();
+        for n in 0..5 {
+            ticks.push(pps.next_edge().unwrap().ticks);
+            let sample = pps.sample().unwrap();
+            assert!(sample.ticks > ticks[n]);
+            backend.emulator().run(PERIOD / 2 + n as u64 * 7);
+            pps.sample().unwrap();
+        }
+        let intervals: Vec<u64> = ticks.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert_eq!(intervals, [PERIOD / TICK_CYCLES; 4]);
+        let stats = pps.stats().unwrap();
+        assert_eq!(stats.edges, 5);
+        assert!(stats.ppm.abs() < 1e-6, "{stats:?}");
+        assert!(Pps::new(pio.sm_claim(1).unwrap(), PIN).unwrap().with_period(Duration::ZERO).is_err());
+    }
+}
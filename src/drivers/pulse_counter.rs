@@ -0,0 +1,248 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Counts rising edges on a pin, for flow meters, Geiger counters, encoders without the direction and the like:
+//
+//     let mut counter = PulseCounter::new(pio.sm_claim_unused()?, 5)?;
+//     loop {
+//         std::thread::sleep(Duration::from_secs(1));
+//         println!("{} pulses", counter.take()?);   // Since the last take(), and starts again from 0
+//     }
+//
+// or, to have the SM cut the count up into intervals itself, exactly (to 5 ns) however late they're read:
+//
+//     let mut counter = PulseCounter::new(pio.sm_claim_unused()?, 5)?
+//         .with_interval(Duration::from_millis(100))?;
+//     loop {
+//         for count in counter.intervals()? {
+//             println!("{} pulses/s", count * 10);
+//         }
+//     }
+//
+// The SM does the counting at the full PIO clock, so it keeps up with anything up to about 30 MHz (each half of a
+// pulse has to last 3 cycles) however busy the CPU is. It hands over the count as it was at a single moment, so
+// take() never loses a pulse or counts one twice. Without an interval the SM is asked for it there and then; with one
+// count() and take() go by the end of the last whole interval.
+//
+// The count the SM keeps is 32 bits, so it has to be looked at (anything that calls update() will do) at least every
+// 2^32 pulses, a couple of minutes at 30 MHz. The RX FIFO holds 8 intervals, and when it's full the next ones run
+// together into one.
+
+use std::{collections::{vec_deque, VecDeque}, time::Duration};
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioProgram, SmConfig, StateMachine};
+
+const TICK_CYCLES: u64 = 3;
+// The tick the count's pushed in
+const REPORT_CYCLES: u64 = 8;
+
+const PULL_BLOCK: u16 = 0x80a0;     // pull block
+const MOV_X_NOT_NULL: u16 = 0xa02b; // mov x, ~null
+const MOV_Y_OSR: u16 = 0xa047;      // mov y, osr
+const MOV_Y_NULL: u16 = 0xa043;     // mov y, null
+
+// X counts rising edges down from !0. Y counts ticks down from OSR, and when it runs out the count is pushed, so the
+// CPU gets it straight away by exec()ing a mov y, null. Every tick takes 3 cycles and samples the pin once, except the
+// one the count's pushed in.
+const PROGRAM: &str = "
+    .program pulse_counter
+    high:
+        jmp y-- high_1
+        jmp report_high
+    high_1:
+        jmp pin high        [1]
+    low:
+        jmp y-- low_1
+        jmp report_low
+    low_1:
+        jmp pin rise
+        jmp low
+    rise:
+        jmp x-- high
+        jmp high                    ; Once every 2^32
+    report_high:
+        mov y, osr                  ; Before the push, so a mov y, null after it isn't lost
+        mov isr, ~x
+        push noblock
+        jmp high_1
+    report_low:
+        mov y, osr
+        mov isr, ~x
+        push noblock
+        jmp low_1
+";
+
+pub struct PulseCounter<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    interval: Option<Duration>,
+    total: u64, // Since it started
+    last: u32,  // The SM's count the last time it was pushed
+    taken: u64, // The total at the last take()
+    intervals: VecDeque<u32>,
+}
+
+impl<'a> PulseCounter<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<PulseCounter<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut counter = PulseCounter { sm, program: None, pin, interval: None, total: 0, last: 0, taken: 0,
+                                         intervals: VecDeque::new() };
+        counter.setup()?;
+        Ok(counter)
+    }
+
+    // Has the SM push the count every `interval` (1 ms to 60 s), for intervals(). The counts start again from 0.
+    pub fn with_interval(mut self, interval: Duration) -> Result<Self, Error> {
+        if !(Duration::from_millis(1)..=Duration::from_secs(60)).contains(&interval) {
+            Err(Error::ParamErr { param: "interval", should_be: "1 ms to 60 s".to_string() })?;
+        }
+        self.interval = Some(interval);
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Pulses since it started or the last take().
+    pub fn count(&mut self) -> Result<u64, Error> {
+        if self.interval.is_some() {
+            self.update()?;
+        } else {
+            self.request()?;
+        }
+        Ok(self.total - self.taken)
+    }
+
+    // count(), and starts again from 0.
+    pub fn take(&mut self) -> Result<u64, Error> {
+        let count = self.count()?;
+        self.taken = self.total;
+        Ok(count)
+    }
+
+    // The count in each whole interval since last time, oldest first. Always empty without with_interval().
+    pub fn intervals(&mut self) -> Result<vec_deque::Drain<'_, u32>, Error> {
+        self.update()?;
+        Ok(self.intervals.drain(..))
+    }
+
+    // Takes in whatever the SM has pushed since last time.
+    pub fn update(&mut self) -> Result<(), Error> {
+        while !self.sm.is_rx_fifo_empty()? {
+            let count = self.sm.get(false)?;
+            self.received(count);
+        }
+        Ok(())
+    }
+
+    // Makes the SM push the count now. If its own push beat it to it, that'll do just as well (and the one asked for
+    // turns up next time).
+    fn request(&mut self) -> Result<(), Error> {
+        self.update()?;
+        self.sm.exec(MOV_Y_NULL, false)?;
+        let count = self.sm.get(true)?;
+        self.received(count);
+        Ok(())
+    }
+
+    fn received(&mut self, count: u32) {
+        let pulses = count.wrapping_sub(self.last);
+        self.total += pulses as u64;
+        self.last = count;
+        if self.interval.is_some() {
+            self.intervals.push_back(pulses);
+        }
+    }
+
+    // Without an interval the SM pushes every 2^32 ticks (a minute) anyway, which doesn't matter.
+    fn ticks(&self) -> u32 {
+        let Some(interval) = self.interval else { return !0 };
+        let cycles = (interval.as_secs_f64() * pio_clock_hz() as f64).round() as u64;
+        ((cycles - REPORT_CYCLES + 1) / TICK_CYCLES) as u32 // The nearest
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.pin)?
+            .set_jmp_pin(self.pin)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, pio_clock_hz() as f64)?)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pindirs_with_mask(0, 1 << self.pin)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.init(program.offset(), &config)?; // At high, which never counts an edge even if the pin's low
+        self.sm.clear_fifos()?;
+        self.sm.put(self.ticks(), false)?;
+        for instr in [PULL_BLOCK, MOV_Y_OSR, MOV_X_NOT_NULL] {
+            self.sm.exec(instr, false)?;
+        }
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        (self.total, self.last, self.taken) = (0, 0, 0);
+        self.intervals.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const PIN: u32 = 5;
+
+    // A pulse every `period` cycles, keeping count of them.
+    #[derive(Debug)]
+    struct Pulses {
+        period: u64,
+        sent: u64,
+    }
+
+    impl Peripheral for Pulses {
+        fn step(&mut self, _pins: u32, now: u64) -> (u32, u32) {
+            let high = now % self.period < 20;
+            self.sent += now.is_multiple_of(self.period) as u64;
+            ((high as u32) << PIN, 1 << PIN)
+        }
+    }
+
+    #[test]
+    fn counts() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pulses = Arc::new(Mutex::new(Pulses { period: 200, sent: 0 }));
+        backend.emulator().attach(pulses.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut counter = PulseCounter::new(pio.sm_claim(0).unwrap(), PIN).unwrap();
+        let sent = || pulses.lock().unwrap().sent;
+
+        // Nothing's lost or counted twice between takes (give or take the one in flight).
+        let start = sent();
+        let mut taken = 0;
+        for cycles in [10_000, 33_333, 1] {
+            backend.emulator().run(cycles);
+            taken += counter.take().unwrap();
+            assert!((sent() - start).abs_diff(taken) <= 1, "{} {taken}", sent() - start);
+        }
+        assert_eq!(counter.count().unwrap(), 0);
+
+        // 1 ms is 1000 pulses, every time.
+        let mut counter = counter.with_interval(Duration::from_millis(1)).unwrap();
+        backend.emulator().run(1_000_000);
+        let counts: Vec<u32> = counter.intervals().unwrap().collect();
+        assert_eq!(counts.len(), 5);
+        assert!(counts.iter().all(|&count| count == 1000), "{counts:?}");
+        assert_eq!(counter.take().unwrap(), 5000);
+        assert!(counter.with_interval(Duration::from_secs(61)).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A quadrature encoder on two consecutive pins (A, then B), counting every edge of both:
+//
+//     let mut encoder = Quadrature::new(pio.sm_claim_unused()?, 5)?   // A on 5, B on 6
+//         .with_cutoff(Duration::from_millis(50))?;
+//     loop {
+//         let (position, velocity) = (encoder.position()?, encoder.velocity()?);   // counts, counts/s
+//         ...
+//     }
+//
+// The SM does the counting, so no edges are missed however busy the CPU is, and it times each edge too, so the velocity
+// comes from how long the last few edges took rather than how many turned up since the last look. That's smooth at low
+// speeds, where counting per interval jumps between 0 and a count or two, and up to date at high ones. Edges further
+// apart than with_cutoff() (100 ms to start with) read as stopped. The SM's clock is set so its timer lasts exactly
+// that long, which also limits how fast it can go: about 14,000 / cutoff (in seconds) edges a second, so 140,000 at
+// 100 ms. Anything faster skips edges.
+//
+// The SM pushes the position and timing at each edge, and again every cutoff with no edges, so update() needs calling
+// (position() and velocity() do) before the 8 deep RX FIFO fills; edges that don't fit are dropped, which only loses
+// velocity readings, as long as the position hasn't moved 32,768 counts in the meantime.
+//
+// The program is a jump table, so it has to be loaded at the start of instruction memory, and it takes all but one
+// instruction of it.
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+// The timer runs down from TICKS at each edge. Idle loops take IDLE_CYCLES and each counts a tick; the loop that
+// finds an edge takes EDGE_CYCLES and doesn't.
+const TICKS: u32 = 0x7fff;
+const IDLE_CYCLES: u32 = 6;
+const EDGE_CYCLES: u32 = 14;
+// Edges timed for the velocity.
+const AVERAGE: usize = 4;
+
+const MOV_X_NULL: u16 = 0xa023;   // mov x, null
+const SET_Y_1: u16 = 0xe041;      // set y, 1
+const MOV_Y_REV_Y: u16 = 0xa052;  // mov y, ::y
+const MOV_OSR_PINS: u16 = 0xa0e0; // mov osr, pins
+
+// Indexed by the old and new states of B and A. Pushes the position's bottom 16 bits and the timer's, which is past
+// TICKS for the heartbeat when it runs out. Y starts half way round, since going down through 0 falls through to idle
+// without pushing (which only costs a velocity reading, but would otherwise happen every time it passed its start).
+const PROGRAM: &str = "
+    .program quadrature
+    .origin 0
+        jmp idle            ; 00 -> 00
+        jmp up              ; 00 -> 01
+        jmp down            ; 00 -> 10
+        jmp idle            ; 00 -> 11, missed one
+        jmp down            ; 01 -> 00
+        jmp idle            ; 01 -> 01
+        jmp idle            ; 01 -> 10, missed one
+        jmp up              ; 01 -> 11
+        jmp up              ; 10 -> 00
+        jmp idle            ; 10 -> 01, missed one
+        jmp idle            ; 10 -> 10
+        jmp down            ; 10 -> 11
+        jmp idle            ; 11 -> 00, missed one
+        jmp down            ; 11 -> 01
+        jmp up              ; 11 -> 10
+        jmp idle            ; 11 -> 11
+    up:
+        mov y, ~y
+        jmp y-- up_1
+    up_1:
+        mov y, ~y
+    .wrap_target
+    edge:
+        in y, 16
+        in x, 16
+        push noblock
+        mov isr, ~null
+        in null, 15
+        mov x, ~isr         ; TICKS
+    sample:
+        out isr, 2
+        in pins, 2
+        mov osr, isr
+        mov pc, isr
+    down:
+        jmp y-- edge        [2]
+    idle:
+        jmp x-- sample
+    .wrap
+";
+
+pub struct Quadrature<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    cutoff: Duration,
+    hz: f64,                     // The SM's actual clock
+    position: i64,
+    last: u16,                   // The SM's position at the last push
+    edges: VecDeque<(i64, f64)>, // The last few edges' directions and periods (s)
+}
+
+impl<'a> Quadrature<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<Quadrature<'a>, Error> {
+        for pin in [pin, pin + 1] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let mut encoder = Quadrature { sm, program: None, pin, cutoff: Duration::from_millis(100), hz: 0.0, position: 0,
+                                       last: 0, edges: VecDeque::with_capacity(AVERAGE) };
+        encoder.setup()?;
+        Ok(encoder)
+    }
+
+    // Edges further apart than this read as stopped. Shorter goes faster: see above.
+    pub fn with_cutoff(mut self, cutoff: Duration) -> Result<Self, Error> {
+        if cutoff.is_zero() {
+            Err(Error::ParamErr { param: "cutoff", should_be: "more than 0".to_string() })?;
+        }
+        self.cutoff = cutoff;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Counts, from where it started or was last set_position()ed.
+    pub fn position(&mut self) -> Result<i64, Error> {
+        self.update()?;
+        Ok(self.position)
+    }
+
+    pub fn set_position(&mut self, position: i64) -> Result<(), Error> {
+        self.update()?;
+        self.position = position;
+        Ok(())
+    }
+
+    // Counts/s, from the time the last few edges took (as long as they all went the same way).
+    pub fn velocity(&mut self) -> Result<f64, Error> {
+        self.update()?;
+        let time: f64 = self.edges.iter().map(|&(_, period)| period).sum();
+        Ok(self.edges.iter().map(|&(direction, _)| direction).sum::<i64>() as f64 / time.max(f64::MIN_POSITIVE))
+    }
+
+    // Takes in whatever the SM has pushed since last time.
+    pub fn update(&mut self) -> Result<(), Error> {
+        while !self.sm.is_rx_fifo_empty()? {
+            let word = self.sm.get(false)?;
+            self.received(word);
+        }
+        Ok(())
+    }
+
+    fn received(&mut self, word: u32) {
+        let (position, timer) = ((word >> 16) as u16, word & 0xffff);
+        let moved = position.wrapping_sub(self.last) as i16 as i64;
+        self.position += moved;
+        self.last = position;
+        let direction = moved.signum();
+        if timer > TICKS || moved != direction {
+            self.edges.clear(); // Timed out, or edges went missing so the timing's off
+            return;
+        }
+        if self.edges.back().is_some_and(|&(last, _)| last != direction) {
+            self.edges.clear();
+        }
+        if self.edges.len() == AVERAGE {
+            self.edges.pop_front();
+        }
+        self.edges.push_back((direction, (EDGE_CYCLES + IDLE_CYCLES * (TICKS - timer)) as f64 / self.hz));
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let hz = (EDGE_CYCLES + IDLE_CYCLES * TICKS) as f64 / self.cutoff.as_secs_f64();
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz.min(pio_clock_hz() as f64))?;
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.pin)?
+            .set_in_shift(false, false, 32)?
+            .set_out_shift(true, false, 32)?
+            .set_fifo_join(PioFifoJoin::Rx)?
+            .set_clkdiv_int_frac(clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pindirs_with_mask(0, 3 << self.pin)?;
+        for pin in [self.pin, self.pin + 1] {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        // The position starts at 0 (as far as the bottom 16 bits go), the pins as they are now, and the timer run out,
+        // so the first push says so.
+        for instr in [SET_Y_1, MOV_Y_REV_Y, MOV_OSR_PINS, MOV_X_NULL] {
+            self.sm.exec(instr, false)?;
+        }
+        self.sm.set_enabled(true)?;
+        self.hz = clkdiv.actual_frequency(pio_clock_hz() as f64);
+        self.program = Some(program);
+        self.last = 0;
+        self.edges.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const PIN: u32 = 5;
+    const US: u64 = 200; // Cycles
+
+    // Steps through the states in order (forwards, with A leading) or backwards at the given times, in µs.
+    #[derive(Debug, Default)]
+    struct Encoder {
+        edges: Vec<(u64, bool)>,
+        next: usize,
+        state: usize,
+    }
+
+    impl Peripheral for Encoder {
+        fn step(&mut self, _pins: u32, now: u64) -> (u32, u32) {
+            while let Some(&(at, forwards)) = self.edges.get(self.next) && now >= at * US {
+                self.state = if forwards { self.state + 1 } else { self.state + 3 } % 4;
+                self.next += 1;
+            }
+            ([0b00, 0b01, 0b11, 0b10][self.state % 4] << PIN, 3 << PIN)
+        }
+    }
+
+    #[test]
+    fn position_and_velocity() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let encoder = Arc::new(Mutex::new(Encoder::default()));
+        // 40 edges forwards 20 µs apart, then 10 backwards 50 µs apart, then nothing.
+        let forwards = (0..40).map(|n| (100 + n * 20, true));
+        let backwards = (0..10).map(|n| (1000 + n * 50, false));
+        encoder.lock().unwrap().edges = forwards.chain(backwards).collect();
+        backend.emulator().attach(encoder.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut quadrature = Quadrature::new(pio.sm_claim(0).unwrap(), PIN).unwrap()
+            .with_cutoff(Duration::from_millis(1)).unwrap();
+        assert_eq!(quadrature.velocity().unwrap(), 0.0);
+
+        let mut run_to = |us: u64| {
+            while backend.emulator().cycle() < us * US {
+                backend.emulator().run(100 * US);
+                quadrature.update().unwrap();
+            }
+            (quadrature.position().unwrap(), quadrature.velocity().unwrap())
+        };
+        let (position, velocity) = run_to(900);
+        assert_eq!(position, 40);
+        assert!((velocity - 50_000.0).abs() < 100.0, "{velocity}");
+        let (position, velocity) = run_to(1500);
+        assert_eq!(position, 30);
+        assert!((velocity + 20_000.0).abs() < 100.0, "{velocity}");
+        assert_eq!(run_to(3000), (30, 0.0)); // A cutoff after the last edge
+    }
+}
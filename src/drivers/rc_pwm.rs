@@ -0,0 +1,289 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Servo style PWM coming in, from the channel outputs of an RC receiver (or a servo's feedback line), one SM for each
+// channel:
+//
+//     let mut rc = RcPwm::new(vec![pio.sm_claim_unused()?, pio.sm_claim_unused()?], &[5, 6])?;   // throttle, steering
+//     loop {
+//         match rc.widths()?[..] {
+//             [Some(throttle), Some(steering)] => drive(throttle, steering),   // µs, about 1000 to 2000
+//             _ => stop(),                                                       // Lost the signal
+//         }
+//     }
+//
+// Each SM keeps a 1 µs count going and pushes it whenever its pin changes, along with which way it went, so pulses are
+// measured and timestamped to the µs however late they're read. The RX FIFO holds 4 pulses (80 ms at 50 Hz), so
+// something has to call one of these at least that often; when it fills the newest edges are lost, but the edges that
+// are left still pair up properly. Pulses outside with_range() are taken to be noise and ignored, and a channel that
+// hasn't had a good pulse within with_timeout() (100 ms to start with) reads as None.
+
+use std::time::{Duration, Instant};
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+const COUNT_HZ: f64 = 1_000_000.0;
+const CYCLES_PER_COUNT: f64 = 5.0;
+const COUNT_BITS: u32 = 31;
+
+// Every path through takes 5 cycles and counts X down once. Edges are pushed as X's low 31 bits and then the level.
+const PROGRAM: &str = "
+    .program rc_pwm
+        set y, 1
+        mov x, ~null
+    .wrap_target
+    low:
+        jmp x-- low_1
+    low_1:
+        jmp pin rise
+        jmp low             [2]
+    rise:
+        in x, 31
+        in y, 1
+        push noblock
+    high:
+        jmp x-- high_1
+    high_1:
+        jmp pin still
+        in x, 31
+        in null, 1
+        push noblock
+    .wrap
+    still:
+        jmp high            [2]
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pulse {
+    pub width: u32,          // µs
+    pub period: Option<u32>, // µs since the one before started, if there was one
+    pub time: Instant,       // When it started
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Channel {
+    rose: Option<u64>, // µs since start, for the latest rise
+    last: Option<u64>, // The one before that
+    high: bool,
+    pulse: Option<Pulse>,
+}
+
+pub struct RcPwm<'a> {
+    sms: Vec<StateMachine<'a>>,
+    program: Option<LoadedProgram<'a>>,
+    pins: Vec<u32>,
+    channels: Vec<Channel>,
+    range: (u32, u32), // µs
+    timeout: Duration,
+    start: Instant, // When the counts were all 0
+}
+
+impl<'a> RcPwm<'a> {
+    // An SM for each pin.
+    pub fn new(sms: Vec<StateMachine<'a>>, pins: &[u32]) -> Result<RcPwm<'a>, Error> {
+        if pins.is_empty() || pins.len() != sms.len() {
+            Err(Error::ParamErr { param: "pins", should_be: "one for each SM".to_string() })?;
+        }
+        for &pin in pins {
+            sms[0].pio().check_gpio(pin as u16)?;
+        }
+        let mut rc = RcPwm { sms, program: None, pins: pins.to_vec(), channels: vec![Channel::default(); pins.len()],
+                             range: (500, 2500), timeout: Duration::from_millis(100), start: Instant::now() };
+        rc.setup()?;
+        Ok(rc)
+    }
+
+    // The shortest and longest pulses that count, in µs.
+    pub fn with_range(mut self, min: u32, max: u32) -> Result<Self, Error> {
+        if min >= max {
+            Err(Error::ParamErr { param: "min", should_be: "less than max".to_string() })?;
+        }
+        self.range = (min, max);
+        Ok(self)
+    }
+
+    // How long a channel can go without a pulse before it's taken to be lost.
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self, Error> {
+        if timeout.is_zero() {
+            Err(Error::ParamErr { param: "timeout", should_be: "more than 0".to_string() })?;
+        }
+        self.timeout = timeout;
+        Ok(self)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn into_inner(mut self) -> Result<Vec<StateMachine<'a>>, Error> {
+        for sm in &self.sms {
+            sm.set_enabled(false)?;
+        }
+        self.program = None;
+        Ok(std::mem::take(&mut self.sms))
+    }
+
+    // Takes in whatever edges have come in since last time.
+    pub fn update(&mut self) -> Result<(), Error> {
+        for channel in 0..self.sms.len() {
+            while !self.sms[channel].is_rx_fifo_empty()? {
+                let word = self.sms[channel].get(false)?;
+                self.received(channel, word);
+            }
+        }
+        Ok(())
+    }
+
+    // The latest pulse on a channel, or None if it's lost the signal.
+    pub fn pulse(&mut self, channel: usize) -> Result<Option<Pulse>, Error> {
+        if channel >= self.channels.len() {
+            Err(Error::ParamErr { param: "channel", should_be: format!("< {}", self.channels.len()) })?;
+        }
+        self.update()?;
+        Ok(self.channels[channel].pulse.filter(|pulse| pulse.time.elapsed() <= self.timeout))
+    }
+
+    // Each channel's latest pulse width, in µs.
+    pub fn widths(&mut self) -> Result<Vec<Option<u32>>, Error> {
+        (0..self.channels.len()).map(|channel| Ok(self.pulse(channel)?.map(|pulse| pulse.width))).collect()
+    }
+
+    // Waits for a new pulse on a channel (however long that takes: there's no timeout).
+    pub fn next_pulse(&mut self, channel: usize) -> Result<Pulse, Error> {
+        if channel >= self.channels.len() {
+            Err(Error::ParamErr { param: "channel", should_be: format!("< {}", self.channels.len()) })?;
+        }
+        loop {
+            let word = self.sms[channel].get(true)?;
+            if let Some(pulse) = self.received(channel, word) {
+                return Ok(pulse);
+            }
+        }
+    }
+
+    fn received(&mut self, channel: usize, word: u32) -> Option<Pulse> {
+        let at = self.micros(word >> 1);
+        let state = &mut self.channels[channel];
+        if word & 1 != 0 {
+            // At 0 it was already high when it started, so the start of the pulse was missed.
+            state.last = std::mem::replace(&mut state.rose, (at > 1).then_some(at));
+            state.high = state.rose.is_some();
+            return None;
+        }
+        if !std::mem::take(&mut state.high) {
+            return None;
+        }
+        let width = at.checked_sub(state.rose?)?;
+        let (min, max) = self.range;
+        if !(min as u64..=max as u64).contains(&width) {
+            return None;
+        }
+        let rose = at - width;
+        let pulse = Pulse { width: width as u32, period: state.last.map(|last| (rose - last) as u32),
+                            time: self.start + Duration::from_micros(rose) };
+        state.pulse = Some(pulse);
+        Some(pulse)
+    }
+
+    // µs since start from what was left of X, which wraps every 35 minutes. It's taken to be the time closest to now
+    // that fits, so it works as long as edges aren't left in the FIFO for more than about 17 minutes.
+    fn micros(&self, count: u32) -> u64 {
+        let modulus = 1_i64 << COUNT_BITS;
+        let now = self.start.elapsed().as_micros() as i64;
+        let ago = (now - (modulus - 1 - count as i64)).rem_euclid(modulus);
+        let ago = if ago >= modulus / 2 { ago - modulus } else { ago };
+        (now - ago).max(0) as u64
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sms[0].pio();
+        for sm in &self.sms {
+            sm.set_enabled(false)?;
+        }
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let mut mask = 0;
+        for (sm, &pin) in self.sms.iter().zip(&self.pins) {
+            let config = SmConfig::default()
+                .set_jmp_pin(pin)?
+                .set_in_shift(false, false, 32)?
+                .set_fifo_join(PioFifoJoin::Rx)?
+                .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, COUNT_HZ * CYCLES_PER_COUNT)?)?
+                .set_wrap(wrap_target, wrap)?;
+            sm.set_pindirs_with_mask(0, 1 << pin)?;
+            pio.pio_gpio_init(pin as u16)?;
+            sm.init(program.offset(), &config)?;
+            sm.clear_fifos()?;
+            mask |= 1 << sm.index();
+        }
+        self.channels.fill(Channel::default());
+        self.start = Instant::now();
+        pio.sm_enable_sync(mask)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const PINS: [u32; 3] = [4, 6, 7];
+    const US: u64 = 200; // Cycles
+
+    // A receiver sending each channel's pulse (µs, or None for nothing) 200 µs into every 5 ms frame.
+    #[derive(Debug)]
+    struct Receiver {
+        widths: [Option<u64>; 3],
+    }
+
+    impl Peripheral for Receiver {
+        fn step(&mut self, _pins: u32, now: u64) -> (u32, u32) {
+            let t = now / US % 5000;
+            let levels = PINS.iter().zip(self.widths)
+                .map(|(&pin, width)| (width.is_some_and(|width| (200..200 + width).contains(&t)) as u32) << pin)
+                .fold(0, |levels, level| levels | level);
+            (levels, PINS.iter().map(|pin| 1 << pin).sum())
+        }
+    }
+
+    #[test]
+    fn pulses() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let receiver = Arc::new(Mutex::new(Receiver { widths: [Some(1000), Some(1500), Some(2000)] }));
+        backend.emulator().attach(receiver.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let sms = (0..3).map(|n| pio.sm_claim(n).unwrap()).collect();
+        let mut rc = RcPwm::new(sms, &PINS).unwrap().with_timeout(Duration::from_millis(15)).unwrap();
+        // The emulator's much slower than real time, so keep the clocks lined up.
+        let sync = |rc: &mut RcPwm| rc.start = Instant::now() - Duration::from_micros(backend.emulator().cycle() / US);
+
+        let first = rc.next_pulse(0).unwrap();
+        let second = rc.next_pulse(0).unwrap();
+        assert_eq!((first.width, first.period), (1000, None));
+        assert_eq!((second.width, second.period), (1000, Some(5000)));
+        assert_eq!(second.time - first.time, Duration::from_micros(5000));
+
+        // The others' FIFOs overflow in the meantime, but what's left still pairs up.
+        backend.emulator().run(200 * 12_000);
+        rc.update().unwrap();
+        backend.emulator().run(200 * 6_000);
+        sync(&mut rc);
+        assert_eq!(rc.widths().unwrap(), [Some(1000), Some(1500), Some(2000)]);
+
+        receiver.lock().unwrap().widths[2] = None;
+        for _ in 0..4 {
+            backend.emulator().run(200 * 5_000);
+            sync(&mut rc);
+            rc.update().unwrap();
+        }
+        assert_eq!(rc.widths().unwrap(), [Some(1000), Some(1500), None]);
+        assert_eq!(rc.pulse(1).unwrap().unwrap().period, Some(5000));
+        assert!(rc.pulse(3).is_err());
+        assert_eq!(rc.into_inner().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Futaba SBUS, the serial output of most RC receivers: 16 proportional channels, 2 digital ones and the failsafe
+// flags, in a 25 byte frame every 7 or 14ms:
+//
+//     let mut sbus = Sbus::new(pio.sm_claim_unused()?, 5)?;
+//     loop {
+//         let frame = sbus.frame()?;
+//         if frame.failsafe { land() } else { steer(frame.channels[0]) }
+//     }
+//
+// SBUS is a UART (100 kbaud, 8E2) with the levels upside down. Rather than needing an inverter in front of the pin,
+// the pad's input override flips it back (with_inversion(false) turns that off, for receivers that have already done
+// it). The bytes come from uart::Rx, and frames are found by their header and footer bytes, so it picks up from
+// anywhere in the stream. A frame with a byte that failed its parity or framing check is dropped.
+
+use super::uart::{Parity, Received, Rx};
+use crate::{gpio::Override, Error, Rp1PIO, StateMachine};
+
+const BAUD: u32 = 100_000;
+const FRAME_LEN: usize = 25;
+const HEADER: u8 = 0x0f;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Frame {
+    pub channels: [u16; 16], // 11 bits each, usually 172..=1811 with 992 in the middle
+    pub ch17: bool,
+    pub ch18: bool,
+    pub frame_lost: bool, // The receiver missed a frame from the transmitter
+    pub failsafe: bool,   // The receiver has lost the transmitter altogether
+}
+
+impl Frame {
+    fn parse(bytes: &[u8]) -> Option<Frame> {
+        // SBUS2 receivers put telemetry slot numbers in the footer's top nibble.
+        if bytes.len() != FRAME_LEN || bytes[0] != HEADER || !(bytes[24] == 0x00 || bytes[24] & 0x0f == 0x04) {
+            return None;
+        }
+        let mut frame = Frame::default();
+        for (n, channel) in frame.channels.iter_mut().enumerate() {
+            let (byte, shift) = (1 + n * 11 / 8, n * 11 % 8);
+            let bits = u32::from_le_bytes([bytes[byte], bytes[byte + 1], *bytes.get(byte + 2).unwrap_or(&0), 0]);
+            *channel = (bits >> shift & 0x7ff) as u16;
+        }
+        let flags = bytes[23];
+        frame.ch17 = flags & 0x01 != 0;
+        frame.ch18 = flags & 0x02 != 0;
+        frame.frame_lost = flags & 0x04 != 0;
+        frame.failsafe = flags & 0x08 != 0;
+        Some(frame)
+    }
+}
+
+pub struct Sbus<'a> {
+    rx: Rx<'a>,
+    pio: &'a Rp1PIO,
+    pin: u32,
+    bytes: Vec<u8>,
+}
+
+impl<'a> Sbus<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<Sbus<'a>, Error> {
+        let pio = sm.pio();
+        let rx = Rx::new(sm, pin, BAUD)?.with_parity(Parity::Even)?;
+        let sbus = Sbus { rx, pio, pin, bytes: Vec::with_capacity(FRAME_LEN) };
+        sbus.set_inversion(true)?;
+        Ok(sbus)
+    }
+
+    pub fn with_inversion(self, invert: bool) -> Result<Self, Error> {
+        self.set_inversion(invert)?;
+        Ok(self)
+    }
+
+    fn set_inversion(&self, invert: bool) -> Result<(), Error> {
+        let over = if invert { Override::Invert } else { Override::Normal };
+        self.pio.gpio_set_inover(self.pin as u16, over as u16)
+    }
+
+    // Waits for the next good frame.
+    pub fn frame(&mut self) -> Result<Frame, Error> {
+        loop {
+            if let Some(frame) = self.received(self.rx.receive()?) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    // Returns None instead of waiting when no frame has finished arriving.
+    pub fn try_frame(&mut self) -> Result<Option<Frame>, Error> {
+        while let Some(received) = self.rx.try_receive()? {
+            if let Some(frame) = self.received(received) {
+                return Ok(Some(frame));
+            }
+        }
+        Ok(None)
+    }
+
+    // Puts the pin's input back to normal.
+    pub fn into_inner(self) -> Result<StateMachine<'a>, Error> {
+        self.set_inversion(false)?;
+        self.rx.into_inner()
+    }
+
+    fn received(&mut self, received: Received) -> Option<Frame> {
+        let Received::Byte(byte) = received else {
+            self.bytes.clear();
+            return None;
+        };
+        if self.bytes.is_empty() && byte != HEADER {
+            return None;
+        }
+        self.bytes.push(byte);
+        if self.bytes.len() < FRAME_LEN {
+            return None;
+        }
+        if let Some(frame) = Frame::parse(&self.bytes) {
+            self.bytes.clear();
+            return Some(frame);
+        }
+        // Wasn't really a header. Try again from the next one.
+        let next = self.bytes[1..].iter().position(|&b| b == HEADER).map_or(self.bytes.len(), |n| n + 1);
+        self.bytes.drain(..next);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    fn encode(frame: &Frame) -> Vec<u8> {
+        let mut bytes = vec![HEADER];
+        let (mut bits, mut count) = (0_u32, 0);
+        for &channel in &frame.channels {
+            bits |= (channel as u32) << count;
+            count += 11;
+            while count >= 8 {
+                bytes.push(bits as u8);
+                (bits, count) = (bits >> 8, count - 8);
+            }
+        }
+        bytes.push(frame.ch17 as u8 | (frame.ch18 as u8) << 1 | (frame.frame_lost as u8) << 2 | (frame.failsafe as u8) << 3);
+        bytes.push(0);
+        bytes
+    }
+
+    // Sends bytes at 100 kbaud (2000 clocks a bit), 8E2, with the `bad` one's parity wrong. The emulator doesn't do
+    // input overrides, so these are the levels after the inversion. Frames are picked up as they go, so the RX FIFO
+    // doesn't overflow.
+    fn send(backend: &EmulatorBackend, sbus: &mut Sbus, bytes: &[u8], bad: Option<usize>) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        for (n, &byte) in bytes.iter().enumerate() {
+            let parity = byte.count_ones() & 1 != 0;
+            let bits = std::iter::once(false).chain((0..8).map(|i| byte >> i & 1 != 0)).chain([parity ^ (bad == Some(n)), true, true]);
+            let mut emu = backend.emulator();
+            for bit in bits {
+                emu.set_input(5, bit);
+                emu.run(2000);
+            }
+            drop(emu);
+            frames.extend(sbus.try_frame().unwrap());
+        }
+        frames
+    }
+
+    #[test]
+    fn frames() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut sbus = Sbus::new(pio.sm_claim(0).unwrap(), 5).unwrap();
+        backend.emulator().set_input(5, true);
+        backend.emulator().run(10_000);
+        let mut frame = Frame { failsafe: true, ch18: true, ..Frame::default() };
+        for (n, channel) in frame.channels.iter_mut().enumerate() {
+            *channel = 172 + n as u16 * 100;
+        }
+        let bytes = encode(&frame);
+        // Some junk first (including a stray header byte), then the frame, then one with a parity error.
+        let junk = [0x12, HEADER, 0x34];
+        assert_eq!(send(&backend, &mut sbus, &[&junk[..], &bytes].concat(), None), [frame]);
+        assert_eq!(send(&backend, &mut sbus, &bytes, Some(7)), []);
+        assert_eq!(send(&backend, &mut sbus, &bytes, None), [frame]);
+    }
+}
@@ -0,0 +1,625 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// SD and SDHC/SDXC cards in SPI mode, as a block device on any four pins:
+//
+//     let mut card = SdCard::new(pio.sm_claim_unused()?, 10, 11, 12, 13)?;   // sck, mosi, miso, cs
+//     let mut blocks = [[0; BLOCK_SIZE]; 2];
+//     card.read(&mut blocks, 0)?;
+//     blocks[1][..5].copy_from_slice(b"hello");
+//     card.write(&blocks[1..], 1)?;
+//
+// The card is woken up at 400 kHz and then run at 25 MHz (with_frequency() changes that). Commands and data both
+// carry CRCs, and the card is told to check them too. Block numbers are always in 512 byte blocks, whatever the card
+// addresses in.
+//
+// With the `embedded-sdmmc` feature, BlockDevice wraps an SdCard in a RefCell and implements
+// embedded_sdmmc::BlockDevice, so a card can be handed straight to embedded_sdmmc::VolumeManager:
+//
+//     let volumes = VolumeManager::new(BlockDevice::new(card), clock);
+//
+// MISO needs a pull-up: cards leave it floating until they've been told they're in SPI mode.
+
+use std::time::{Duration, Instant};
+
+use super::spi::{Device, Master, PioSpi};
+use crate::StateMachine;
+
+pub const BLOCK_SIZE: usize = 512;
+pub type Block = [u8; BLOCK_SIZE];
+
+const GO_IDLE_STATE: u8 = 0;
+const SEND_IF_COND: u8 = 8;
+const SEND_CSD: u8 = 9;
+const STOP_TRANSMISSION: u8 = 12;
+const SET_BLOCKLEN: u8 = 16;
+const READ_SINGLE_BLOCK: u8 = 17;
+const READ_MULTIPLE_BLOCK: u8 = 18;
+const WRITE_BLOCK: u8 = 24;
+const WRITE_MULTIPLE_BLOCK: u8 = 25;
+const APP_CMD: u8 = 55;
+const READ_OCR: u8 = 58;
+const CRC_ON_OFF: u8 = 59;
+const SD_SEND_OP_COND: u8 = 41; // After APP_CMD
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+
+const START_BLOCK: u8 = 0xfe;
+const START_MULTIPLE_WRITE: u8 = 0xfc;
+const STOP_MULTIPLE_WRITE: u8 = 0xfd;
+const DATA_ACCEPTED: u8 = 0x05;
+
+const OCR_CCS: u32 = 1 << 30; // Block addressed (SDHC/SDXC)
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardType {
+    Sd1,
+    Sd2,
+    Sdhc, // And SDXC: anything addressed in blocks rather than bytes
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Pio(crate::Error),
+    NoCard,                      // Nothing answered the reset
+    Unsupported,                 // Not an SD card, or not one that runs at 3.3V
+    Command { cmd: u8, r1: u8 }, // The card didn't like a command
+    Timeout,
+    Crc,
+    ReadFailed(u8),  // The card's data error token
+    WriteFailed(u8), // The card's data response
+    OutOfRange { block: u32, blocks: u32 },
+}
+
+impl From<crate::Error> for Error {
+    fn from(error: crate::Error) -> Self {
+        Error::Pio(error)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Pio(error)                   => write!(f, "{error}"),
+            Error::NoCard                       => write!(f, "No card"),
+            Error::Unsupported                  => write!(f, "Unsupported card"),
+            Error::Command { cmd, r1 }          => write!(f, "CMD{cmd} failed: R1 {r1:#04x}"),
+            Error::Timeout                      => write!(f, "Card timed out"),
+            Error::Crc                          => write!(f, "CRC mismatch"),
+            Error::ReadFailed(token)            => write!(f, "Read failed: error token {token:#04x}"),
+            Error::WriteFailed(response)        => write!(f, "Write failed: data response {response:#04x}"),
+            Error::OutOfRange { block, blocks } => write!(f, "Block {block} is past the end of the card ({blocks} blocks)"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Pio(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+fn crc7(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| (0..8).rev().fold(crc, |crc, bit| {
+        let feedback = (crc >> 6 ^ byte >> bit) & 1 != 0;
+        (crc << 1 & 0x7f) ^ if feedback { 0x09 } else { 0 }
+    }))
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+        if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 }
+    }))
+}
+
+// MOSI has to stay high while reading, so reads are transfers of 0xffs.
+fn receive(bus: &mut PioSpi, buf: &mut [u8]) -> Result<(), Error> {
+    buf.fill(0xff);
+    Ok(bus.transfer_in_place(buf)?)
+}
+
+fn receive_byte(bus: &mut PioSpi) -> Result<u8, Error> {
+    let mut byte = [0];
+    receive(bus, &mut byte)?;
+    Ok(byte[0])
+}
+
+// The card holds MISO low while it's busy.
+fn wait_ready(bus: &mut PioSpi, timeout: Duration) -> Result<(), Error> {
+    let deadline = Instant::now() + timeout;
+    while receive_byte(bus)? != 0xff {
+        if Instant::now() > deadline {
+            Err(Error::Timeout)?;
+        }
+    }
+    Ok(())
+}
+
+// Sends a command and returns its R1, leaving the rest of the response for the caller.
+fn command(bus: &mut PioSpi, cmd: u8, arg: u32) -> Result<u8, Error> {
+    if cmd != GO_IDLE_STATE {
+        wait_ready(bus, Duration::from_millis(500))?;
+    }
+    let mut frame = [0x40 | cmd, 0, 0, 0, 0, 0];
+    frame[1..5].copy_from_slice(&arg.to_be_bytes());
+    frame[5] = crc7(&frame[..5]) << 1 | 1;
+    bus.write(&frame)?;
+    if cmd == STOP_TRANSMISSION {
+        receive_byte(bus)?; // Stuff byte
+    }
+    for _ in 0..8 {
+        let r1 = receive_byte(bus)?;
+        if r1 & 0x80 == 0 {
+            return Ok(r1);
+        }
+    }
+    Err(Error::Timeout)
+}
+
+fn check(cmd: u8, r1: u8) -> Result<(), Error> {
+    if r1 != 0 {
+        Err(Error::Command { cmd, r1 })?;
+    }
+    Ok(())
+}
+
+fn read_data(bus: &mut PioSpi, buf: &mut [u8]) -> Result<(), Error> {
+    let deadline = Instant::now() + Duration::from_millis(200);
+    let token = loop {
+        match receive_byte(bus)? {
+            0xff if Instant::now() > deadline => Err(Error::Timeout)?,
+            0xff                              => continue,
+            token                             => break token,
+        }
+    };
+    if token != START_BLOCK {
+        Err(Error::ReadFailed(token))?;
+    }
+    receive(bus, buf)?;
+    let mut crc = [0; 2];
+    receive(bus, &mut crc)?;
+    if u16::from_be_bytes(crc) != crc16(buf) {
+        Err(Error::Crc)?;
+    }
+    Ok(())
+}
+
+fn write_data(bus: &mut PioSpi, token: u8, block: &Block) -> Result<(), Error> {
+    bus.write(&[0xff, token])?;
+    bus.write(block)?;
+    bus.write(&crc16(block).to_be_bytes())?;
+    let response = receive_byte(bus)?;
+    if response & 0x1f != DATA_ACCEPTED {
+        Err(Error::WriteFailed(response))?;
+    }
+    wait_ready(bus, Duration::from_millis(500))
+}
+
+pub struct SdCard<'a> {
+    spi: Master<'a>,
+    device: usize,
+    card_type: CardType,
+    blocks: u32,
+}
+
+impl<'a> SdCard<'a> {
+    pub fn new(sm: StateMachine<'a>, sck: u32, mosi: u32, miso: u32, cs: u32) -> Result<SdCard<'a>, Error> {
+        sm.pio().set_pulls(miso as u16, true, false)?;
+        let mut spi = Master::new(sm, sck, mosi, miso)?;
+        let device = spi.add_device(Device { frequency: 400_000.0, ..Device::new(cs) })?;
+        let mut card = SdCard { spi, device, card_type: CardType::Sd1, blocks: 0 };
+        card.init()?;
+        card.spi.set_frequency(device, 25_000_000.0)?;
+        Ok(card)
+    }
+
+    pub fn with_frequency(mut self, hz: f64) -> Result<Self, Error> {
+        self.spi.set_frequency(self.device, hz)?;
+        Ok(self)
+    }
+
+    pub fn card_type(&self) -> CardType {
+        self.card_type
+    }
+
+    pub fn num_blocks(&self) -> u32 {
+        self.blocks
+    }
+
+    pub fn into_inner(self) -> Result<StateMachine<'a>, Error> {
+        Ok(self.spi.into_inner()?)
+    }
+
+    // Reads consecutive blocks starting at `start`.
+    pub fn read(&mut self, blocks: &mut [Block], start: u32) -> Result<(), Error> {
+        self.check_range(start, blocks.len())?;
+        let address = self.address(start);
+        self.select(|bus| {
+            if let [block] = blocks {
+                check(READ_SINGLE_BLOCK, command(bus, READ_SINGLE_BLOCK, address)?)?;
+                return read_data(bus, block);
+            }
+            check(READ_MULTIPLE_BLOCK, command(bus, READ_MULTIPLE_BLOCK, address)?)?;
+            let read = blocks.iter_mut().try_for_each(|block| read_data(bus, block));
+            // Stop the card even if a block went wrong.
+            let r1 = command(bus, STOP_TRANSMISSION, 0)?;
+            read?;
+            check(STOP_TRANSMISSION, r1)
+        })
+    }
+
+    // Writes consecutive blocks starting at `start`, returning once the card has finished programming them.
+    pub fn write(&mut self, blocks: &[Block], start: u32) -> Result<(), Error> {
+        self.check_range(start, blocks.len())?;
+        let address = self.address(start);
+        self.select(|bus| {
+            if let [block] = blocks {
+                check(WRITE_BLOCK, command(bus, WRITE_BLOCK, address)?)?;
+                return write_data(bus, START_BLOCK, block);
+            }
+            check(WRITE_MULTIPLE_BLOCK, command(bus, WRITE_MULTIPLE_BLOCK, address)?)?;
+            for block in blocks {
+                write_data(bus, START_MULTIPLE_WRITE, block)?;
+            }
+            bus.write(&[STOP_MULTIPLE_WRITE, 0xff])?;
+            wait_ready(bus, Duration::from_millis(500))
+        })
+    }
+
+    fn check_range(&self, start: u32, count: usize) -> Result<(), Error> {
+        match start.checked_add(count as u32) {
+            Some(end) if end <= self.blocks => Ok(()),
+            _                               => Err(Error::OutOfRange { block: start.saturating_add(count as u32).saturating_sub(1), blocks: self.blocks }),
+        }
+    }
+
+    fn address(&self, block: u32) -> u32 {
+        match self.card_type {
+            CardType::Sdhc => block,
+            _              => block * BLOCK_SIZE as u32,
+        }
+    }
+
+    // Runs `f` with the card selected, then clocks a byte with it deselected so it lets go of MISO.
+    fn select<T>(&mut self, f: impl FnOnce(&mut PioSpi<'a>) -> Result<T, Error>) -> Result<T, Error> {
+        let result = self.spi.transaction(self.device, |bus| Ok(f(bus)))?;
+        self.spi.unselected(self.device, |bus| bus.write(&[0xff]))?;
+        result
+    }
+
+    fn init(&mut self) -> Result<(), Error> {
+        // At least 74 clocks with CS high to wake it up.
+        self.spi.unselected(self.device, |bus| bus.write(&[0xff; 10]))?;
+        let mut r1 = 0xff;
+        for _ in 0..10 {
+            r1 = self.select(|bus| command(bus, GO_IDLE_STATE, 0))?;
+            if r1 == R1_IDLE {
+                break;
+            }
+        }
+        if r1 != R1_IDLE {
+            Err(Error::NoCard)?;
+        }
+
+        // Version 1 cards don't know CMD8. Later ones echo the check pattern back if they're happy with the voltage.
+        let (r1, r7) = self.select(|bus| {
+            let r1 = command(bus, SEND_IF_COND, 0x1aa)?;
+            let mut r7 = [0; 4];
+            if r1 & R1_ILLEGAL_COMMAND == 0 {
+                receive(bus, &mut r7)?;
+            }
+            Ok((r1, r7))
+        })?;
+        let v2 = r1 & R1_ILLEGAL_COMMAND == 0;
+        if v2 && r7[2..] != [0x01, 0xaa] {
+            Err(Error::Unsupported)?;
+        }
+
+        let r1 = self.select(|bus| command(bus, CRC_ON_OFF, 1))?;
+        if r1 != R1_IDLE {
+            Err(Error::Command { cmd: CRC_ON_OFF, r1 })?;
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        loop {
+            let r1 = self.select(|bus| {
+                let r1 = command(bus, APP_CMD, 0)?;
+                if r1 & !R1_IDLE != 0 {
+                    return Ok(r1);
+                }
+                command(bus, SD_SEND_OP_COND, if v2 { OCR_CCS } else { 0 })
+            })?;
+            match r1 {
+                0                                    => break,
+                R1_IDLE if Instant::now() > deadline => Err(Error::Timeout)?,
+                R1_IDLE                              => {},
+                r1 if v2                             => Err(Error::Command { cmd: SD_SEND_OP_COND, r1 })?,
+                _                                    => Err(Error::Unsupported)?, // MMC, which wants CMD1
+            }
+        }
+
+        self.card_type = if v2 {
+            let ocr = self.select(|bus| {
+                check(READ_OCR, command(bus, READ_OCR, 0)?)?;
+                let mut ocr = [0; 4];
+                receive(bus, &mut ocr)?;
+                Ok(u32::from_be_bytes(ocr))
+            })?;
+            if ocr & OCR_CCS != 0 { CardType::Sdhc } else { CardType::Sd2 }
+        } else {
+            CardType::Sd1
+        };
+        if self.card_type != CardType::Sdhc {
+            let r1 = self.select(|bus| command(bus, SET_BLOCKLEN, BLOCK_SIZE as u32))?;
+            check(SET_BLOCKLEN, r1)?;
+        }
+
+        let mut csd = [0; 16];
+        self.select(|bus| {
+            check(SEND_CSD, command(bus, SEND_CSD, 0)?)?;
+            read_data(bus, &mut csd)
+        })?;
+        self.blocks = csd_blocks(&csd);
+        Ok(())
+    }
+}
+
+// The card's size from its CSD register, which comes in two layouts.
+fn csd_blocks(csd: &[u8; 16]) -> u32 {
+    if csd[0] >> 6 == 1 {
+        let c_size = (csd[7] as u32 & 0x3f) << 16 | (csd[8] as u32) << 8 | csd[9] as u32;
+        (c_size + 1) * 1024
+    } else {
+        let c_size = (csd[6] as u32 & 0x03) << 10 | (csd[7] as u32) << 2 | (csd[8] as u32) >> 6;
+        let c_size_mult = (csd[9] as u32 & 0x03) << 1 | (csd[10] as u32) >> 7;
+        let read_bl_len = csd[5] as u32 & 0x0f;
+        (c_size + 1) << (c_size_mult + 2 + read_bl_len - 9)
+    }
+}
+
+#[cfg(feature = "embedded-sdmmc")]
+pub use sdmmc::BlockDevice;
+
+#[cfg(feature = "embedded-sdmmc")]
+mod sdmmc {
+    use std::cell::RefCell;
+
+    use embedded_sdmmc::{Block, BlockCount, BlockIdx};
+
+    use super::{Error, SdCard, BLOCK_SIZE};
+
+    // BlockDevice's methods take &self, so the card goes in a RefCell.
+    pub struct BlockDevice<'a>(RefCell<SdCard<'a>>);
+
+    impl<'a> BlockDevice<'a> {
+        pub fn new(card: SdCard<'a>) -> BlockDevice<'a> {
+            BlockDevice(RefCell::new(card))
+        }
+
+        pub fn into_inner(self) -> SdCard<'a> {
+            self.0.into_inner()
+        }
+    }
+
+    impl embedded_sdmmc::BlockDevice for BlockDevice<'_> {
+        type Error = Error;
+
+        fn read(&self, blocks: &mut [Block], start: BlockIdx) -> Result<(), Error> {
+            let mut data = vec![[0; BLOCK_SIZE]; blocks.len()];
+            self.0.borrow_mut().read(&mut data, start.0)?;
+            for (block, data) in blocks.iter_mut().zip(data) {
+                block.contents = data;
+            }
+            Ok(())
+        }
+
+        fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), Error> {
+            let data: Vec<_> = blocks.iter().map(|block| block.contents).collect();
+            self.0.borrow_mut().write(&data, start.0)
+        }
+
+        fn num_blocks(&self) -> Result<BlockCount, Error> {
+            Ok(BlockCount(self.0.borrow().num_blocks()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::{Arc, Mutex}};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const SCK: u32 = 10;
+    const MOSI: u32 = 11;
+    const MISO: u32 = 12;
+    const CS: u32 = 13;
+
+    // A 1024 block card on the other end of the pins, SPI mode 0.
+    #[derive(Debug)]
+    struct Card {
+        sdhc: bool,
+        blocks: Vec<Block>,
+        sck: bool,
+        selected: bool,
+        rx: u8,
+        rx_bits: u8,
+        tx: u8,
+        tx_bits: u8,
+        out: VecDeque<u8>,
+        command: Vec<u8>,
+        app: bool,
+        ready: bool,
+        reading: Option<usize>,
+        writing: Option<(usize, bool)>, // Next block, and whether it's a multiple block write
+        data: Vec<u8>,
+    }
+
+    impl Card {
+        fn new(sdhc: bool) -> Card {
+            Card { sdhc, blocks: vec![[0; BLOCK_SIZE]; 1024], sck: false, selected: false, rx: 0, rx_bits: 0, tx: 0xff,
+                   tx_bits: 0, out: VecDeque::new(), command: Vec::new(), app: false, ready: false, reading: None,
+                   writing: None, data: Vec::new() }
+        }
+
+        fn data_block(&mut self, data: &[u8]) {
+            self.out.extend([0xff, START_BLOCK]);
+            self.out.extend(data);
+            self.out.extend(crc16(data).to_be_bytes());
+        }
+
+        fn byte(&mut self, byte: u8) {
+            if let Some((block, multiple)) = self.writing {
+                match (self.data.len(), byte) {
+                    (0, STOP_MULTIPLE_WRITE) if multiple => { self.writing = None; self.out.extend([0xff, 0, 0]); },
+                    (0, START_BLOCK | START_MULTIPLE_WRITE) => self.data.push(byte),
+                    (0, _) => {},
+                    _ => self.data.push(byte),
+                }
+                if self.data.len() == 1 + BLOCK_SIZE + 2 {
+                    let data = std::mem::take(&mut self.data);
+                    let ok = crc16(&data[1..=BLOCK_SIZE]).to_be_bytes() == data[BLOCK_SIZE + 1..];
+                    if ok {
+                        self.blocks[block].copy_from_slice(&data[1..=BLOCK_SIZE]);
+                    }
+                    self.out.extend([if ok { DATA_ACCEPTED } else { 0x0b }, 0, 0, 0]);
+                    self.writing = multiple.then_some((block + 1, true));
+                }
+                return;
+            }
+            if self.command.is_empty() && byte & 0xc0 != 0x40 {
+                return;
+            }
+            self.command.push(byte);
+            if self.command.len() < 6 {
+                return;
+            }
+            let command = std::mem::take(&mut self.command);
+            let (cmd, arg) = (command[0] & 0x3f, u32::from_be_bytes(command[1..5].try_into().unwrap()));
+            let block = if self.sdhc { arg } else { arg / BLOCK_SIZE as u32 } as usize;
+            let idle = !self.ready as u8;
+            if command[5] != crc7(&command[..5]) << 1 | 1 {
+                self.out.extend([0xff, 0x08]);
+                return;
+            }
+            self.out.clear();
+            let app = std::mem::take(&mut self.app);
+            match (app, cmd) {
+                (_, GO_IDLE_STATE)            => { self.ready = false; self.out.extend([0xff, R1_IDLE]) },
+                (_, SEND_IF_COND)             => self.out.extend([0xff, idle, 0, 0, 0x01, 0xaa]),
+                (_, CRC_ON_OFF)               => self.out.extend([0xff, idle]),
+                (_, APP_CMD)                  => { self.app = true; self.out.extend([0xff, idle]) },
+                (true, SD_SEND_OP_COND)       => { self.out.extend([0xff, idle]); self.ready = true },
+                (_, READ_OCR)                 => self.out.extend([0xff, 0, if self.sdhc { 0xc0 } else { 0x80 }, 0xff, 0x80, 0]),
+                (_, SET_BLOCKLEN)             => self.out.extend([0xff, 0]),
+                (_, SEND_CSD)                 => {
+                    self.out.extend([0xff, 0]);
+                    self.data_block(&[0x40, 0x0e, 0, 0x32, 0x5b, 0x59, 0, 0, 0, 0, 0x7f, 0x80, 0x0a, 0x40, 0, 0x01]);
+                },
+                (_, READ_SINGLE_BLOCK)        => { self.out.extend([0xff, 0]); self.data_block(&self.blocks[block].clone()) },
+                (_, READ_MULTIPLE_BLOCK)      => { self.out.extend([0xff, 0]); self.reading = Some(block) },
+                (_, STOP_TRANSMISSION)        => { self.reading = None; self.out.extend([0xff, 0xff, 0]) },
+                (_, WRITE_BLOCK)              => { self.out.extend([0xff, 0]); self.writing = Some((block, false)) },
+                (_, WRITE_MULTIPLE_BLOCK)     => { self.out.extend([0xff, 0]); self.writing = Some((block, true)) },
+                _                             => self.out.extend([0xff, idle | R1_ILLEGAL_COMMAND]),
+            }
+        }
+    }
+
+    impl Peripheral for Card {
+        fn step(&mut self, pins: u32, _cycle: u64) -> (u32, u32) {
+            let (sck, selected, mosi) = (pins >> SCK & 1 != 0, pins >> CS & 1 == 0, pins >> MOSI & 1);
+            if selected && !self.selected {
+                (self.rx_bits, self.tx_bits, self.tx) = (0, 0, self.out.pop_front().unwrap_or(0xff));
+            }
+            if selected && sck && !self.sck {
+                self.rx = self.rx << 1 | mosi as u8;
+                self.rx_bits += 1;
+                if self.rx_bits == 8 {
+                    self.rx_bits = 0;
+                    self.byte(self.rx);
+                }
+            }
+            // Only falling edges that follow a rising one: SCK can still be on its way down from the last transfer
+            // when CS goes low.
+            if selected && !sck && self.sck && self.rx_bits != self.tx_bits {
+                self.tx_bits += 1;
+                if self.tx_bits == 8 {
+                    self.tx_bits = 0;
+                    if self.out.is_empty() && let Some(block) = self.reading {
+                        self.data_block(&self.blocks[block].clone());
+                        self.reading = Some(block + 1);
+                    }
+                    self.tx = self.out.pop_front().unwrap_or(0xff);
+                }
+            }
+            (self.sck, self.selected) = (sck, selected);
+            let miso = if selected { (self.tx >> (7 - self.tx_bits) & 1) as u32 } else { 1 };
+            (miso << MISO, 1 << MISO)
+        }
+    }
+
+    #[test]
+    fn crcs() {
+        assert_eq!(crc7(&[0x40, 0, 0, 0, 0]) << 1 | 1, 0x95);
+        assert_eq!(crc7(&[0x48, 0, 0, 0x01, 0xaa]) << 1 | 1, 0x87);
+        assert_eq!(crc16(&[0xff; BLOCK_SIZE]), 0x7fa1);
+    }
+
+    #[test]
+    fn blocks() {
+        for sdhc in [true, false] {
+            let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(10_000_000);
+            let card = Arc::new(Mutex::new(Card::new(sdhc)));
+            card.lock().unwrap().blocks[3][..4].copy_from_slice(b"boot");
+            backend.emulator().attach(card.clone());
+            let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+            let mut sd = SdCard::new(pio.sm_claim(0).unwrap(), SCK, MOSI, MISO, CS).unwrap();
+            assert_eq!(sd.card_type(), if sdhc { CardType::Sdhc } else { CardType::Sd2 });
+            assert_eq!(sd.num_blocks(), 1024);
+
+            let mut blocks = [[0; BLOCK_SIZE]; 3];
+            sd.read(&mut blocks[..1], 3).unwrap();
+            assert_eq!(&blocks[0][..4], b"boot");
+            blocks[1][..5].copy_from_slice(b"hello");
+            blocks[2][BLOCK_SIZE - 1] = 0x42;
+            sd.write(&blocks[1..2], 7).unwrap();
+            sd.write(&blocks, 10).unwrap();
+            assert_eq!(&card.lock().unwrap().blocks[7][..5], b"hello");
+            assert_eq!(card.lock().unwrap().blocks[12][BLOCK_SIZE - 1], 0x42);
+
+            let mut read = [[0xaa; BLOCK_SIZE]; 3];
+            sd.read(&mut read, 10).unwrap();
+            assert_eq!(read, blocks);
+            assert!(matches!(sd.read(&mut read, 1022), Err(Error::OutOfRange { block: 1024, blocks: 1024 })));
+        }
+    }
+    #[cfg(feature = "embedded-sdmmc")]
+    #[test]
+    fn block_device() {
+        use embedded_sdmmc::{Block, BlockCount, BlockDevice as _, BlockIdx};
+
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(10_000_000);
+        let card = Arc::new(Mutex::new(Card::new(true)));
+        card.lock().unwrap().blocks[5][..4].copy_from_slice(b"fat!");
+        backend.emulator().attach(card.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let sd = BlockDevice::new(SdCard::new(pio.sm_claim(0).unwrap(), SCK, MOSI, MISO, CS).unwrap());
+        assert_eq!(sd.num_blocks().unwrap(), BlockCount(1024));
+
+        let mut blocks = [Block::new(), Block::new()];
+        sd.read(&mut blocks, BlockIdx(4)).unwrap();
+        assert_eq!(&blocks[1].contents[..4], b"fat!");
+        blocks[0].contents[..3].copy_from_slice(b"mbr");
+        sd.write(&blocks, BlockIdx(20)).unwrap();
+        assert_eq!(&card.lock().unwrap().blocks[20][..3], b"mbr");
+        assert_eq!(&card.lock().unwrap().blocks[21][..4], b"fat!");
+        assert!(matches!(sd.read(&mut blocks, BlockIdx(1023)), Err(Error::OutOfRange { .. })));
+        sd.into_inner().into_inner().unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Hobby servos (or ESCs, or anything else that takes RC style PWM) on consecutive pins, three to an SM:
+//
+//     let mut servos = Servos::new(vec![pio.sm_claim_unused()?, pio.sm_claim_unused()?], 4, 5)?;   // pins 4..=8
+//     servos.set_angle(0, 90.0)?;
+//     servos.set_pulse_width(4, 1250)?;      // µs
+//
+// Each SM plays its servos' pulses one after the other and then waits out the rest of the period (20 ms, for 50 Hz,
+// unless with_frequency() says otherwise), with 1 µs resolution. It keeps doing that on its own with whatever it was
+// last sent, so nothing depends on the CPU keeping up, and new settings only take effect at the start of a period so
+// a pulse is never cut short or stretched. A servo gets no pulses at all until it's given a position, rather than being
+// sent somewhere it might not be safe to go, and disable() stops them again (which lets most servos go limp).
+// into_inner() waits for the period in progress to finish before stopping, and leaves the pins low.
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioMovStatus, PioProgram, SmConfig, StateMachine};
+
+pub const CHANNELS_PER_SM: u32 = 3;
+
+// Cycles (µs) in a period besides the pulses and the gap, and the gap's units.
+const OVERHEAD: u32 = 26;
+const GAP_UNIT: u32 = 8;
+
+// A period is two words of two 16 bit fields, a channel's pins (3 bits) then how long to hold them, and for the last
+// field, the gap. The words are kept in Y and OSR: each field is copied to the ISR before it's used, and the words
+// swap around so they're back where they started at the end. New ones are only taken when both are there, and if
+// there's more than one pair waiting, only the last counts.
+const PROGRAM: &str = "
+    .program servo
+    .wrap_target
+        mov x, status
+        jmp !x update
+        mov osr, y
+        mov y, isr
+        jmp first
+    update:
+        pull
+        mov y, osr
+        pull
+        mov x, status
+        jmp !x update               ; Skip to the latest
+    first:
+        in osr, 16
+        out pins, 3
+        out x, 13
+    pulse_0:
+        jmp x-- pulse_0
+        mov pins, null
+        in osr, 16
+        out pins, 3
+        out x, 13
+    pulse_1:
+        jmp x-- pulse_1
+        mov pins, null
+        mov osr, y
+        mov y, isr
+        in osr, 16
+        out pins, 3
+        out x, 13
+    pulse_2:
+        jmp x-- pulse_2
+        mov pins, null
+        in osr, 16
+        out null, 3
+        out x, 13
+    gap:
+        jmp x-- gap         [7]
+    .wrap
+";
+
+pub struct Servos<'a> {
+    sms: Vec<StateMachine<'a>>,
+    program: Option<LoadedProgram<'a>>,
+    base: u32,
+    widths: Vec<Option<u32>>, // µs, None for no pulses
+    period: u32,              // µs
+    range: (u32, u32),        // µs at 0° and 180°
+}
+
+impl<'a> Servos<'a> {
+    // Up to CHANNELS_PER_SM channels for each SM. Starts at 50 Hz, with 0 to 180° being 1 to 2 ms.
+    pub fn new(sms: Vec<StateMachine<'a>>, base: u32, channels: u32) -> Result<Servos<'a>, Error> {
+        if sms.is_empty() || channels == 0 || channels > sms.len() as u32 * CHANNELS_PER_SM {
+            Err(Error::ParamErr { param: "channels", should_be: format!("1 to {} for each SM", CHANNELS_PER_SM) })?;
+        }
+        for pin in base..base + channels {
+            sms[0].pio().check_gpio(pin as u16)?;
+        }
+        let mut servos = Servos { sms, program: None, base, widths: vec![None; channels as usize], period: 20_000,
+                                  range: (1000, 2000) };
+        servos.setup()?;
+        Ok(servos)
+    }
+
+    pub fn with_frequency(mut self, hz: f64) -> Result<Self, Error> {
+        let period = (1_000_000.0 / hz).round();
+        if !(period > 0.0 && period <= (OVERHEAD + GAP_UNIT * 0x1fff) as f64) {
+            Err(Error::ParamErr { param: "hz", should_be: "at least 16".to_string() })?;
+        }
+        let old = std::mem::replace(&mut self.period, period as u32);
+        if let Err(error) = self.update_all() {
+            self.period = old;
+            Err(error)?;
+        }
+        Ok(self)
+    }
+
+    // The pulse widths set_angle() goes between, in µs. 1000 to 2000 is safe for anything, but plenty of servos go
+    // further: 500 to 2500 is common.
+    pub fn with_range(mut self, min: u32, max: u32) -> Result<Self, Error> {
+        if min >= max {
+            Err(Error::ParamErr { param: "min", should_be: "less than max".to_string() })?;
+        }
+        self.range = (min, max);
+        Ok(self)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.widths.len()
+    }
+
+    pub fn frequency(&self) -> f64 {
+        1_000_000.0 / self.period as f64
+    }
+
+    // Degrees, 0 to 180.
+    pub fn set_angle(&mut self, channel: usize, degrees: f64) -> Result<(), Error> {
+        if !(0.0..=180.0).contains(&degrees) {
+            Err(Error::ParamErr { param: "degrees", should_be: "0 to 180".to_string() })?;
+        }
+        let (min, max) = self.range;
+        self.set_pulse_width(channel, (min as f64 + (max - min) as f64 * degrees / 180.0).round() as u32)
+    }
+
+    // µs, 3 to 8000 (though servos only go from about 500 to 2500).
+    pub fn set_pulse_width(&mut self, channel: usize, us: u32) -> Result<(), Error> {
+        if !(3..=8000).contains(&us) {
+            Err(Error::ParamErr { param: "us", should_be: "3 to 8000".to_string() })?;
+        }
+        self.set(channel, Some(us))
+    }
+
+    pub fn pulse_width(&self, channel: usize) -> Option<u32> {
+        self.widths.get(channel).copied().flatten()
+    }
+
+    // Stops the pulses to one servo.
+    pub fn disable(&mut self, channel: usize) -> Result<(), Error> {
+        self.set(channel, None)
+    }
+
+    pub fn into_inner(mut self) -> Result<Vec<StateMachine<'a>>, Error> {
+        self.widths.fill(None);
+        self.update_all()?;
+        for sm in &self.sms {
+            sm.drain_tx_fifo()?;
+            sm.set_enabled(false)?;
+        }
+        self.program = None;
+        Ok(std::mem::take(&mut self.sms))
+    }
+
+    fn set(&mut self, channel: usize, width: Option<u32>) -> Result<(), Error> {
+        if channel >= self.widths.len() {
+            Err(Error::ParamErr { param: "channel", should_be: format!("< {}", self.widths.len()) })?;
+        }
+        let old = std::mem::replace(&mut self.widths[channel], width);
+        if let Err(error) = self.update(channel / CHANNELS_PER_SM as usize) {
+            self.widths[channel] = old;
+            Err(error)?;
+        }
+        Ok(())
+    }
+
+    fn update_all(&self) -> Result<(), Error> {
+        (0..self.sms.len()).try_for_each(|sm| self.update(sm))
+    }
+
+    // Sends an SM its channels' widths, as long as they fit in a period.
+    fn update(&self, sm: usize) -> Result<(), Error> {
+        let first = sm * CHANNELS_PER_SM as usize;
+        let mut fields = [0; 4];
+        for (n, width) in self.widths.iter().skip(first).take(CHANNELS_PER_SM as usize).enumerate() {
+            if let Some(width) = width {
+                fields[n] = (width - 3) << 3 | 1 << n; // The pulse lasts 3 cycles longer than the count
+            }
+        }
+        let busy: u32 = OVERHEAD + fields[..3].iter().map(|field| field >> 3).sum::<u32>();
+        if busy > self.period {
+            let most = self.period - OVERHEAD;
+            Err(Error::ParamErr { param: "us", should_be: format!("at most {most} µs in total for each SM") })?;
+        }
+        fields[3] = ((self.period - busy) / GAP_UNIT).saturating_sub(1) << 3;
+        self.sms[sm].put(fields[2] | fields[3] << 16, true)?;
+        self.sms[sm].put(fields[0] | fields[1] << 16, true)?;
+        Ok(())
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sms[0].pio();
+        for sm in &self.sms {
+            sm.set_enabled(false)?;
+        }
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let channels = self.widths.len() as u32;
+        let mut mask = 0;
+        for (n, sm) in self.sms.iter().enumerate() {
+            let base = self.base + n as u32 * CHANNELS_PER_SM;
+            let count = channels.saturating_sub(n as u32 * CHANNELS_PER_SM).min(CHANNELS_PER_SM);
+            let pins = ((1 << count) - 1) << base;
+            let config = SmConfig::default()
+                .set_out_pins(base, count)?
+                .set_out_shift(true, false, 32)?
+                .set_in_shift(true, false, 32)?
+                .set_mov_status(PioMovStatus::TxLessThan, 2)?
+                .set_fifo_join(PioFifoJoin::Tx)?
+                .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, 1_000_000.0)?)?
+                .set_wrap(wrap_target, wrap)?;
+            sm.set_pins_with_mask(0, pins)?;
+            sm.set_pindirs_with_mask(pins, pins)?;
+            for pin in base..base + count {
+                pio.pio_gpio_init(pin as u16)?;
+            }
+            sm.init(program.offset(), &config)?;
+            sm.clear_fifos()?;
+            mask |= 1 << sm.index();
+        }
+        self.update_all()?;
+        pio.sm_enable_sync(mask)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const BASE: u32 = 4;
+    const US: u64 = 200; // Cycles
+
+    // Each pulse on each pin: (pin, when it started, how long it was), in µs.
+    #[derive(Debug, Default)]
+    struct Scope {
+        last: u32,
+        rose: [u64; 32],
+        pulses: Vec<(u32, u64, u64)>,
+    }
+
+    impl Peripheral for Scope {
+        fn step(&mut self, pins: u32, now: u64) -> (u32, u32) {
+            for pin in BASE..BASE + 5 {
+                match (self.last >> pin & 1, pins >> pin & 1) {
+                    (0, 1) => self.rose[pin as usize] = now,
+                    (1, 0) => {
+                        let rose = self.rose[pin as usize];
+                        self.pulses.push((pin, rose / US, (now - rose) / US));
+                    },
+                    _ => {},
+                }
+            }
+            self.last = pins;
+            (0, 0)
+        }
+    }
+
+    #[test]
+    fn pulses() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let scope = Arc::new(Mutex::new(Scope::default()));
+        backend.emulator().attach(scope.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut servos = Servos::new(vec![pio.sm_claim(0).unwrap(), pio.sm_claim(1).unwrap()], BASE, 5).unwrap()
+            .with_frequency(200.0).unwrap()
+            .with_range(500, 2500).unwrap();
+        assert_eq!(servos.frequency(), 200.0);
+        backend.emulator().run(200 * 10_000);
+        assert_eq!(scope.lock().unwrap().pulses, []); // Nothing until they're told where to go
+
+        servos.set_angle(0, 90.0).unwrap();
+        servos.set_pulse_width(1, 1000).unwrap();
+        servos.set_pulse_width(4, 2000).unwrap();
+        assert_eq!(servos.pulse_width(0), Some(1500));
+        backend.emulator().run(200 * 20_000);
+        let pulses = std::mem::take(&mut scope.lock().unwrap().pulses);
+        for pin in [BASE, BASE + 1, BASE + 4] {
+            let starts: Vec<u64> = pulses.iter().filter(|p| p.0 == pin).map(|p| p.1).collect();
+            assert!(starts.len() >= 3, "{pulses:?}");
+            assert!(starts.windows(2).all(|w| (w[1] - w[0]).abs_diff(5000) <= GAP_UNIT as u64), "{starts:?}");
+        }
+        assert!(pulses.iter().all(|&(pin, _, width)| width == [1500, 1000, 0, 0, 2000][(pin - BASE) as usize]));
+
+        servos.disable(1).unwrap();
+        assert!(servos.set_pulse_width(2, 4000).is_err()); // 1.5 + 4 ms is too much for 5 ms
+        assert!(servos.set_angle(5, 0.0).is_err());
+        let sms = servos.into_inner().unwrap();
+        assert_eq!(sms.len(), 2);
+        let end = backend.emulator().cycle() / US;
+        backend.emulator().run(200 * 20_000);
+        let scope = scope.lock().unwrap();
+        assert!(scope.pulses.iter().all(|&(pin, start, _)| pin != BASE + 1 || start < end), "{:?}", scope.pulses);
+        assert!(scope.pulses.iter().all(|&(_, start, _)| start < end), "{:?}", scope.pulses);
+        assert_eq!(scope.last >> BASE & 0x1f, 0);
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Seven-segment displays. Segments are a byte per digit, segment a in bit 0 through g in bit 6, and the decimal point
+// (or colon) in bit 7. Anything that shows digits implements SegmentDisplay, which knows how to turn numbers and text
+// into segments:
+//
+//     display.display_number(-42)?;
+//     display.display_str("12.34")?;
+//
+// Multiplexed drives up to 4 bare digits: 8 consecutive segment pins (a..g, dp) shared by all the digits, and a
+// consecutive pin per digit to turn it on. The SM cycles through the digits on its own, so the CPU only gets involved
+// when the picture changes:
+//
+//     let mut display = Multiplexed::new(pio.sm_claim_unused()?, 2, 10, 4)?   // segments, digits, digit count
+//         .with_polarity(true, false)?;   // Common anode digits switched by PNP transistors, say
+//     display.display_number(1234)?;
+//
+// The SM keeps the last frame it was sent in X and, when there's nothing new in the FIFO, `pull noblock` gives it that
+// again.
+//
+// For TM1637 modules see the tm1637 module.
+
+use crate::{gpio::Override, pio_clock_hz, ClkDiv, Error, LoadedProgram, PioProgram, SmConfig, StateMachine};
+
+// The segments for a character, if there's a reasonable way to show it.
+pub fn segments(c: char) -> Option<u8> {
+    Some(match c.to_ascii_uppercase() {
+        '0' => 0x3f, '1' => 0x06, '2' => 0x5b, '3' => 0x4f, '4' => 0x66,
+        '5' => 0x6d, '6' => 0x7d, '7' => 0x07, '8' => 0x7f, '9' => 0x6f,
+        'A' => 0x77, 'B' => 0x7c, 'C' => 0x39, 'D' => 0x5e, 'E' => 0x79, 'F' => 0x71, 'G' => 0x3d, 'H' => 0x76,
+        'I' => 0x30, 'J' => 0x1e, 'L' => 0x38, 'N' => 0x54, 'O' => 0x5c, 'P' => 0x73, 'R' => 0x50, 'S' => 0x6d,
+        'T' => 0x78, 'U' => 0x3e, 'Y' => 0x6e,
+        '-' => 0x40, '_' => 0x08, '=' => 0x48, ' ' => 0x00,
+        _ => None?,
+    })
+}
+
+// Turns text into segments, folding each '.' into the digit before it.
+pub fn encode(s: &str) -> Result<Vec<u8>, Error> {
+    let mut digits: Vec<u8> = Vec::new();
+    for c in s.chars() {
+        match (c, digits.last_mut()) {
+            ('.', Some(last)) if *last & 0x80 == 0 => *last |= 0x80,
+            ('.', _)                               => digits.push(0x80),
+            (c, _) => digits.push(segments(c).ok_or_else(|| Error::ParamErr { param: "s", should_be: format!("displayable, not {c:?}") })?),
+        }
+    }
+    Ok(digits)
+}
+
+pub trait SegmentDisplay {
+    fn digits(&self) -> usize;
+
+    // Sets the digits from the left. Any not given are blanked.
+    fn set_segments(&mut self, segments: &[u8]) -> Result<(), Error>;
+
+    // Right aligned.
+    fn display_number(&mut self, n: i64) -> Result<(), Error> {
+        self.display_str(&format!("{n:>width$}", width = self.digits()))
+    }
+
+    // Left aligned.
+    fn display_str(&mut self, s: &str) -> Result<(), Error> {
+        let segments = encode(s)?;
+        if segments.len() > self.digits() {
+            Err(Error::ParamErr { param: "s", should_be: format!("at most {} digits", self.digits()) })?;
+        }
+        self.set_segments(&segments)
+    }
+
+    fn clear(&mut self) -> Result<(), Error> {
+        self.set_segments(&[])
+    }
+}
+
+pub struct Multiplexed<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    segment_base: u32,
+    digit_base: u32,
+    digits: u32,
+    refresh_hz: f64,
+    segments_active_high: bool,
+    digits_active_high: bool,
+    frame: u32,
+}
+
+impl<'a> Multiplexed<'a> {
+    // Starts out with everything active high, going round all the digits 1000 times a second.
+    pub fn new(sm: StateMachine<'a>, segment_base: u32, digit_base: u32, digits: u32) -> Result<Multiplexed<'a>, Error> {
+        if !(1..=4).contains(&digits) {
+            Err(Error::ParamErr { param: "digits", should_be: "1..=4".to_string() })?;
+        }
+        for pin in (segment_base..segment_base + 8).chain(digit_base..digit_base + digits) {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let mut display = Multiplexed { sm, program: None, segment_base, digit_base, digits, refresh_hz: 1000.0,
+                                        segments_active_high: true, digits_active_high: true, frame: 0 };
+        display.setup()?;
+        Ok(display)
+    }
+
+    pub fn with_polarity(mut self, segments_active_high: bool, digits_active_high: bool) -> Result<Self, Error> {
+        self.segments_active_high = segments_active_high;
+        self.digits_active_high = digits_active_high;
+        self.setup()?;
+        Ok(self)
+    }
+
+    // How many times a second each digit gets lit.
+    pub fn with_refresh_rate(mut self, hz: f64) -> Result<Self, Error> {
+        self.refresh_hz = hz;
+        self.setup()?;
+        Ok(self)
+    }
+
+    // Stops the SM with everything off and puts the pins back to normal polarity.
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        let pio = self.sm.pio();
+        self.sm.set_pins_with_mask(0, self.pin_mask())?;
+        for pin in self.pins() {
+            pio.gpio_set_outover(pin as u16, Override::Normal as u16)?;
+        }
+        Ok(self.sm)
+    }
+
+    fn pins(&self) -> impl Iterator<Item = u32> {
+        (self.segment_base..self.segment_base + 8).chain(self.digit_base..self.digit_base + self.digits)
+    }
+
+    fn pin_mask(&self) -> u32 {
+        self.pins().fold(0, |mask, pin| mask | 1 << pin)
+    }
+
+    fn delay(&self) -> u32 {
+        (1 << (5 - self.digits)) - 1
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let mut source = format!(".program seven_segment\n.side_set {}\n.wrap_target\n", self.digits);
+        source += "    pull noblock side 0\n    mov x, osr side 0\n";
+        for digit in 0..self.digits {
+            // Blank while the segments change so they don't ghost onto the next digit.
+            source += &format!("    out pins, 8 side 0\n    set y, 31 side {}\nd{digit}:\n    jmp y-- d{digit} side {} [{}]\n",
+                               1 << digit, 1 << digit, self.delay());
+        }
+        source += ".wrap\n";
+        let cycles_per_refresh = 2 + self.digits * (2 + 32 * (self.delay() + 1));
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, self.refresh_hz * cycles_per_refresh as f64)?;
+
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&source)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.segment_base, 8)?
+            .set_sideset(self.digits, false, false)?
+            .set_sideset_pins(self.digit_base)?
+            .set_out_shift(true, false, 32)?
+            .set_clkdiv_int_frac(clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pins_with_mask(0, self.pin_mask())?;
+        self.sm.set_pindirs_with_mask(self.pin_mask(), self.pin_mask())?;
+        for pin in self.pins() {
+            let active_high = if pin >= self.segment_base && pin < self.segment_base + 8 { self.segments_active_high } else { self.digits_active_high };
+            pio.pio_gpio_init(pin as u16)?;
+            pio.gpio_set_outover(pin as u16, if active_high { Override::Normal } else { Override::Invert } as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.put(self.frame, true)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+impl SegmentDisplay for Multiplexed<'_> {
+    fn digits(&self) -> usize {
+        self.digits as usize
+    }
+
+    fn set_segments(&mut self, segments: &[u8]) -> Result<(), Error> {
+        if segments.len() > self.digits as usize {
+            Err(Error::ParamErr { param: "segments", should_be: format!("at most {} digits", self.digits) })?;
+        }
+        self.frame = segments.iter().rev().fold(0, |frame, &s| frame << 8 | s as u32);
+        self.sm.put(self.frame, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    #[test]
+    fn encoding() {
+        assert_eq!(encode("12.3").unwrap(), [0x06, 0x5b | 0x80, 0x4f]);
+        assert_eq!(encode(".-").unwrap(), [0x80, 0x40]);
+        assert_eq!(encode("1..").unwrap(), [0x06 | 0x80, 0x80]);
+        assert!(encode("K").is_err());
+    }
+
+    #[test]
+    fn multiplexing() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut display = Multiplexed::new(pio.sm_claim(0).unwrap(), 2, 10, 3).unwrap();
+        display.display_number(-7).unwrap();
+        assert!(display.display_number(1234).is_err());
+        // Watch each digit come on twice (the first time round might still be showing the blank frame).
+        let mut emu = backend.emulator();
+        let mut seen = Vec::new();
+        while seen.len() < 6 {
+            emu.step();
+            let pins = emu.pins();
+            let digit = pins >> 10 & 0b111;
+            if digit != 0 && seen.last().is_none_or(|&(d, _)| d != digit) {
+                seen.push((digit, pins >> 2 & 0xff));
+            }
+        }
+        assert_eq!(seen[3..], [(0b001, 0), (0b010, 0x40), (0b100, 0x07)]);
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Audio out of a single GPIO, with no DAC: 16 bit samples go through a sigma-delta modulator on the CPU and the SM
+// plays the resulting bits out at many times the sample rate. An RC low pass filter on the pin (1 kΩ and 10 nF, say)
+// turns that back into audio:
+//
+//     let mut dac = SigmaDelta::new(pio.sm_claim_unused()?, 18, 48_000)?;
+//     let tone: Vec<i16> = (0..48_000).map(|i| ((i as f64 * 440.0 * TAU / 48_000.0).sin() * 16_000.0) as i16).collect();
+//     dac.write(&tone)?;
+//
+// The modulator is second order, and each sample turns into 64 bits unless with_oversampling() says otherwise (any
+// multiple of 32 up to 512). More is quieter, but it's a lot of bits: 48 kHz at 64x is 3 Mbit/s, so writes much
+// longer than the FIFO are streamed with sm_xfer_data(). write() returns once the samples are queued. When the SM runs
+// out it plays 1010... until there's more, which is what silence looks like, so gaps don't click.
+
+use super::led;
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 2.0;
+const SILENCE: u32 = 0xaaaa_aaaa;
+
+// X holds SILENCE, for pull noblock to fall back on. The last bit of each word is unrolled so the pull fits in it:
+// with the pull threshold at 31, !osre is false once there's one bit left.
+const PROGRAM: &str = "
+    .program sigma_delta
+        pull
+        mov x, osr
+    .wrap_target
+    bit:
+        out pins, 1
+        jmp !osre bit
+        out pins, 1
+        pull noblock
+    .wrap
+";
+
+const FULL_SCALE: i32 = 1 << 15;
+
+// Second order, with the integrators clamped so a run of full scale samples can't send it off into the weeds.
+#[derive(Debug, Default, Clone, Copy)]
+struct Modulator {
+    i1: i32,
+    i2: i32,
+}
+
+impl Modulator {
+    fn bit(&mut self, sample: i16) -> bool {
+        let bit = self.i2 >= 0;
+        let y = if bit { FULL_SCALE } else { -FULL_SCALE };
+        self.i1 = (self.i1 + sample as i32 - y).clamp(-FULL_SCALE * 4, FULL_SCALE * 4);
+        self.i2 = (self.i2 + self.i1 - y).clamp(-FULL_SCALE * 16, FULL_SCALE * 16);
+        bit
+    }
+
+    // The bits for a sample, first bit in the LSB of the first word (the SM shifts right).
+    fn extend(&mut self, words: &mut Vec<u32>, sample: i16, oversampling: u32) {
+        for _ in 0..oversampling / 32 {
+            words.push((0..32).fold(0, |word, n| word | (self.bit(sample) as u32) << n));
+        }
+    }
+}
+
+pub struct SigmaDelta<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    rate: f64,
+    oversampling: u32,
+    clkdiv: ClkDiv,
+    modulator: Modulator,
+    words: Vec<u32>,
+}
+
+impl<'a> SigmaDelta<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32, sample_rate: u32) -> Result<SigmaDelta<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let rate = sample_rate as f64;
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, rate * 64.0 * CYCLES_PER_BIT)?;
+        let mut dac = SigmaDelta { sm, program: None, pin, rate, oversampling: 64, clkdiv,
+                                   modulator: Modulator::default(), words: Vec::new() };
+        dac.setup()?;
+        Ok(dac)
+    }
+
+    pub fn with_oversampling(mut self, oversampling: u32) -> Result<Self, Error> {
+        if !(32..=512).contains(&oversampling) || !oversampling.is_multiple_of(32) {
+            Err(Error::ParamErr { param: "oversampling", should_be: "a multiple of 32 up to 512".to_string() })?;
+        }
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, self.rate * oversampling as f64 * CYCLES_PER_BIT)?;
+        self.oversampling = oversampling;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn oversampling(&self) -> u32 {
+        self.oversampling
+    }
+
+    // The rate actually achievable with the SM's clock divider.
+    pub fn sample_rate(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64) / (self.oversampling as f64 * CYCLES_PER_BIT)
+    }
+
+    // Stops the SM wherever it is, leaving the pin at whatever the last bit was.
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    pub fn write(&mut self, samples: &[i16]) -> Result<(), Error> {
+        self.words.clear();
+        for &sample in samples {
+            self.modulator.extend(&mut self.words, sample, self.oversampling);
+        }
+        if self.words.is_empty() {
+            return Ok(());
+        }
+        led::queue(&self.sm, &self.words)
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.pin, 1)?
+            .set_out_shift(true, false, 31)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.set_pins_with_mask(0, 1 << self.pin)?;
+        self.sm.set_pindirs_with_mask(1 << self.pin, 1 << self.pin)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.put(SILENCE, true)?;
+        self.sm.set_enabled(true)?;
+        self.modulator = Modulator::default();
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const PIN: u32 = 18;
+    const RATE: u32 = 15_625; // 100 PIO clocks per SM cycle at 64x
+
+    // Records the pin every cycle, starting from the first time it goes high.
+    #[derive(Debug, Default)]
+    struct Probe(Vec<bool>);
+
+    impl Peripheral for Probe {
+        fn step(&mut self, pins: u32, _cycle: u64) -> (u32, u32) {
+            let level = pins >> PIN & 1 != 0;
+            if level || !self.0.is_empty() {
+                self.0.push(level);
+            }
+            (0, 0)
+        }
+    }
+
+    #[test]
+    fn modulates() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let probe = Arc::new(Mutex::new(Probe::default()));
+        backend.emulator().attach(probe.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut dac = SigmaDelta::new(pio.sm_claim(0).unwrap(), PIN, RATE).unwrap();
+        assert_eq!(dac.sample_rate(), RATE as f64);
+
+        dac.write(&[16_384; 8]).unwrap();
+        backend.emulator().run(200 * (32 + 8 * 64 + 64 + 2));
+        // Bit n is in the middle of its 200 cycles. Silence starts 0 then 1, and the probe started on that 1.
+        let bits: Vec<bool> = probe.lock().unwrap().0.iter().skip(100).step_by(200).copied().collect();
+        let silence: Vec<bool> = (0..64).map(|n| n % 2 == 1).collect();
+        assert_eq!(bits[..31], silence[1..32]);
+        let (mut modulator, mut words) = (Modulator::default(), Vec::new());
+        for _ in 0..8 {
+            modulator.extend(&mut words, 16_384, 64);
+        }
+        let expected: Vec<bool> = words.iter().flat_map(|word| (0..32).map(move |n| word >> n & 1 != 0)).collect();
+        assert_eq!(bits[31..31 + 512], expected);
+        assert_eq!(bits[31 + 512..31 + 512 + 64], silence);
+        let ones = expected.iter().filter(|&&b| b).count() as f64 / 512.0;
+        assert!((ones - 0.75).abs() < 0.01, "{ones}");
+
+        assert!(dac.with_oversampling(48).is_err());
+    }
+}
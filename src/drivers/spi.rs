@@ -0,0 +1,781 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// An SPI controller on any three pins, using the spi_cpha0/spi_cpha1 programs from the pico-examples. Words are 8 bits
+// unless with_word_size() says otherwise (anything from 1 to 32):
+//
+//     let mut spi = PioSpi::new(pio.sm_claim_unused()?, 10, 11, 12)?   // sck, mosi, miso
+//         .with_mode(3)?
+//         .with_frequency(4_000_000.0)?;
+//     let mut id = [0x9f, 0, 0, 0];
+//     spi.transfer_in_place(&mut id)?;
+//
+// Chip select isn't handled by PioSpi: with embedded-hal, wrap it in an `ExclusiveDevice` (from embedded-hal-bus) along
+// with an OutputPin. Or use a Master, which drives a chip select per device and switches the bus to each device's
+// mode, speed, bit order and word size as it goes:
+//
+//     let mut spi = Master::new(pio.sm_claim_unused()?, 10, 11, 12)?;
+//     let flash = spi.add_device(Device { frequency: 20_000_000.0, ..Device::new(13) })?;
+//     let adc = spi.add_device(Device { mode: 1, word_size: 12, ..Device::new(14) })?;
+//     let mut id = [0; 4];
+//     spi.transfer(flash, &mut id, &[0x9f])?;
+//     let sample = spi.transaction(adc, |bus| { let mut s = [0]; bus.read_words(&mut s)?; Ok(s[0]) })?;
+//
+// CPOL is done by inverting SCK at the pad, so the programs only ever deal with an idle-low clock. Transfers keep the
+// TX FIFO topped up, so the clock doesn't stop between words.
+//
+// Slave is the other end: some other controller drives SCK and CS, and the Pi answers, which is handy for standing in
+// for a peripheral while testing firmware. Responses are queued up ahead of the transaction they're for:
+//
+//     let mut spi = Slave::new(pio.sm_claim_unused()?, 10, 13, 11, 12)?;   // sck, cs, mosi, miso
+//     loop {
+//         spi.respond(&[0x00, 0xef, 0x40])?;
+//         let request = spi.transaction()?;
+//     }
+//
+// MISO is only driven while CS is low. Whatever of a response the controller doesn't clock out is dropped when CS goes
+// away, and 0s go out once it runs out. The slave's SM runs at the full PIO clock and looks at SCK a few times per
+// edge, so it keeps up with controllers to about 10 MHz. It only notices CS going away between bytes, so controllers
+// need to stick to whole bytes.
+
+use std::time::Duration;
+
+use crate::{gpio::Override, pio_clock_hz, ClkDiv, Error, LoadedProgram, PioMovStatus, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+// What the transfers carry words in. Only the bottom word_size() bits of each are used.
+trait Word: Copy + 'static {
+    fn to_u32(self) -> u32;
+    fn from_u32(word: u32) -> Self;
+}
+
+macro_rules! word {
+    ($($t:ty),*) => {$(
+        impl Word for $t {
+            fn to_u32(self) -> u32 { self as u32 }
+            fn from_u32(word: u32) -> Self { word as $t }
+        }
+    )*}
+}
+
+word!(u8, u16, u32);
+
+pub struct PioSpi<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    sck: u32,
+    mosi: u32,
+    miso: u32,
+    mode: u8,
+    bit_order: BitOrder,
+    word_size: u32,
+    clkdiv: ClkDiv,
+}
+
+impl<'a> PioSpi<'a> {
+    // Starts out in mode 0, MSB first, 8 bit words, at 1 MHz.
+    pub fn new(sm: StateMachine<'a>, sck: u32, mosi: u32, miso: u32) -> Result<PioSpi<'a>, Error> {
+        for pin in [sck, mosi, miso] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, 1_000_000.0 * CYCLES_PER_BIT)?;
+        let mut spi = PioSpi { sm, program: None, sck, mosi, miso, mode: 0, bit_order: BitOrder::MsbFirst, word_size: 8,
+                              clkdiv };
+        spi.setup()?;
+        Ok(spi)
+    }
+
+    pub fn with_mode(mut self, mode: u8) -> Result<Self, Error> {
+        if mode > 3 {
+            Err(Error::ParamErr { param: "mode", should_be: "0..=3".to_string() })?;
+        }
+        self.mode = mode;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn with_frequency(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz * CYCLES_PER_BIT)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn with_bit_order(mut self, bit_order: BitOrder) -> Result<Self, Error> {
+        self.bit_order = bit_order;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn with_word_size(mut self, bits: u32) -> Result<Self, Error> {
+        if !(1..=32).contains(&bits) {
+            Err(Error::ParamErr { param: "bits", should_be: "1..=32".to_string() })?;
+        }
+        self.word_size = bits;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
+    // The SCK rate actually achievable with the SM's clock divider.
+    pub fn frequency(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64) / CYCLES_PER_BIT
+    }
+
+    pub fn bit_order(&self) -> BitOrder {
+        self.bit_order
+    }
+
+    pub fn word_size(&self) -> u32 {
+        self.word_size
+    }
+
+    // Stops the SM and puts SCK back to normal polarity.
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        self.sm.pio().gpio_set_outover(self.sck as u16, Override::Normal as u16)?;
+        Ok(self.sm)
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let (cpol, cpha) = (self.mode & 2 != 0, self.mode & 1 != 0);
+        let source = if cpha {
+            ".program spi_cpha1\n.side_set 1\nout x, 1 side 0\nmov pins, x side 1 [1]\nin pins, 1 side 0\n"
+        } else {
+            ".program spi_cpha0\n.side_set 1\nout pins, 1 side 0 [1]\nin pins, 1 side 1 [1]\n"
+        };
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(source)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let shift_right = self.bit_order == BitOrder::LsbFirst;
+        let config = SmConfig::default()
+            .set_out_pins(self.mosi, 1)?
+            .set_in_pins(self.miso)?
+            .set_sideset(1, false, false)?
+            .set_sideset_pins(self.sck)?
+            .set_out_shift(shift_right, true, self.word_size)?
+            .set_in_shift(shift_right, true, self.word_size)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pins_with_mask(0, 1 << self.sck | 1 << self.mosi)?;
+        self.sm.set_pindirs_with_mask(1 << self.sck | 1 << self.mosi, 1 << self.sck | 1 << self.mosi | 1 << self.miso)?;
+        for pin in [self.sck, self.mosi, self.miso] {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        pio.gpio_set_outover(self.sck as u16, if cpol { Override::Invert } else { Override::Normal } as u16)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+
+    // The shifters take words from the top of the register when shifting left, and leave them in the bottom.
+    fn out_word(&self, word: u32) -> u32 {
+        match self.bit_order {
+            BitOrder::MsbFirst => word << (32 - self.word_size),
+            BitOrder::LsbFirst => word,
+        }
+    }
+
+    fn in_word(&self, word: u32) -> u32 {
+        match self.bit_order {
+            BitOrder::MsbFirst => word,
+            BitOrder::LsbFirst => word >> (32 - self.word_size),
+        }
+    }
+
+    // Clocks `write` out while reading the same number of words into `read`. Words past the end of `write` go out as 0
+    // and words past the end of `read` are dropped. Keeps the TX FIFO topped up so the clock doesn't stop between
+    // words.
+    fn transfer_any<W: Word>(&mut self, read: &mut [W], write: &[W]) -> Result<(), Error> {
+        let len = read.len().max(write.len());
+        let depth = self.sm.pio().chip().fifo_depth as usize;
+        let mut sent = 0;
+        for i in 0..len {
+            while sent < len && sent - i < depth {
+                self.sm.put(self.out_word(write.get(sent).map_or(0, |w| w.to_u32())), true)?;
+                sent += 1;
+            }
+            let word = self.in_word(self.sm.get(true)?);
+            if let Some(r) = read.get_mut(i) {
+                *r = W::from_u32(word);
+            }
+        }
+        Ok(())
+    }
+
+    fn transfer_in_place_any<W: Word>(&mut self, words: &mut [W]) -> Result<(), Error> {
+        let depth = self.sm.pio().chip().fifo_depth as usize;
+        let mut sent = 0;
+        for i in 0..words.len() {
+            while sent < words.len() && sent - i < depth {
+                self.sm.put(self.out_word(words[sent].to_u32()), true)?;
+                sent += 1;
+            }
+            words[i] = W::from_u32(self.in_word(self.sm.get(true)?));
+        }
+        Ok(())
+    }
+
+    pub fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Error> {
+        self.transfer_any(read, write)
+    }
+
+    pub fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        self.transfer_in_place_any(words)
+    }
+
+    pub fn read(&mut self, words: &mut [u8]) -> Result<(), Error> {
+        self.transfer_any(words, &[])
+    }
+
+    pub fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        self.transfer_any(&mut [], words)
+    }
+
+    // The same again for words wider than a byte.
+    pub fn transfer_words(&mut self, read: &mut [u32], write: &[u32]) -> Result<(), Error> {
+        self.transfer_any(read, write)
+    }
+
+    pub fn transfer_words_in_place(&mut self, words: &mut [u32]) -> Result<(), Error> {
+        self.transfer_in_place_any(words)
+    }
+
+    pub fn read_words(&mut self, words: &mut [u32]) -> Result<(), Error> {
+        self.transfer_any(words, &[])
+    }
+
+    pub fn write_words(&mut self, words: &[u32]) -> Result<(), Error> {
+        self.transfer_any(&mut [], words)
+    }
+}
+
+// One of the things on a Master's bus, and how to talk to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Device {
+    pub cs: u32,
+    pub mode: u8,
+    pub frequency: f64,
+    pub bit_order: BitOrder,
+    pub word_size: u32,
+    pub cs_active_high: bool,
+}
+
+impl Device {
+    // Mode 0, MSB first, 8 bit words, at 1 MHz, with an active low chip select.
+    pub fn new(cs: u32) -> Device {
+        Device { cs, mode: 0, frequency: 1_000_000.0, bit_order: BitOrder::MsbFirst, word_size: 8, cs_active_high: false }
+    }
+}
+
+pub struct Master<'a> {
+    spi: PioSpi<'a>,
+    devices: Vec<Device>,
+    current: Option<usize>,
+}
+
+impl<'a> Master<'a> {
+    pub fn new(sm: StateMachine<'a>, sck: u32, mosi: u32, miso: u32) -> Result<Master<'a>, Error> {
+        Ok(Master { spi: PioSpi::new(sm, sck, mosi, miso)?, devices: Vec::new(), current: None })
+    }
+
+    // Returns the number the other calls know the device by. Its chip select is driven (deselected) from here on.
+    pub fn add_device(&mut self, device: Device) -> Result<usize, Error> {
+        if device.mode > 3 {
+            Err(Error::ParamErr { param: "mode", should_be: "0..=3".to_string() })?;
+        }
+        if !(1..=32).contains(&device.word_size) {
+            Err(Error::ParamErr { param: "word_size", should_be: "1..=32".to_string() })?;
+        }
+        ClkDiv::for_frequency(pio_clock_hz() as f64, device.frequency * CYCLES_PER_BIT)?;
+        let pio = self.spi.sm.pio();
+        pio.check_gpio(device.cs as u16)?;
+        if [self.spi.sck, self.spi.mosi, self.spi.miso].contains(&device.cs) || self.devices.iter().any(|d| d.cs == device.cs) {
+            Err(Error::ParamErr { param: "cs", should_be: "a pin of its own".to_string() })?;
+        }
+        self.select(&device, false)?;
+        self.spi.sm.set_pindirs_with_mask(1 << device.cs, 1 << device.cs)?;
+        pio.pio_gpio_init(device.cs as u16)?;
+        self.devices.push(device);
+        Ok(self.devices.len() - 1)
+    }
+
+    pub fn device(&self, device: usize) -> Option<&Device> {
+        self.devices.get(device)
+    }
+
+    // For devices that start slow and speed up once they've been set up, like SD cards.
+    pub fn set_frequency(&mut self, device: usize, hz: f64) -> Result<(), Error> {
+        ClkDiv::for_frequency(pio_clock_hz() as f64, hz * CYCLES_PER_BIT)?;
+        let Some(d) = self.devices.get_mut(device) else {
+            Err(Error::ParamErr { param: "device", should_be: format!("less than {}", self.devices.len()) })?
+        };
+        d.frequency = hz;
+        if self.current == Some(device) {
+            self.current = None;
+        }
+        Ok(())
+    }
+
+    // Runs `f` with the device selected, so it can do several transfers without its chip select going away in between.
+    pub fn transaction<T>(&mut self, device: usize, f: impl FnOnce(&mut PioSpi<'a>) -> Result<T, Error>) -> Result<T, Error> {
+        let Some(&d) = self.devices.get(device) else {
+            Err(Error::ParamErr { param: "device", should_be: format!("less than {}", self.devices.len()) })?
+        };
+        self.configure(device, &d)?;
+        self.select(&d, true)?;
+        let result = f(&mut self.spi);
+        // The last word is back, but the SM can still be in the last half of its clock.
+        std::thread::sleep(Duration::from_secs_f64(0.5 / self.spi.frequency()));
+        self.select(&d, false)?;
+        result
+    }
+
+    // Runs `f` with the bus set up for the device but nothing selected, for things like an SD card's wake up clocks.
+    pub fn unselected<T>(&mut self, device: usize, f: impl FnOnce(&mut PioSpi<'a>) -> Result<T, Error>) -> Result<T, Error> {
+        let Some(&d) = self.devices.get(device) else {
+            Err(Error::ParamErr { param: "device", should_be: format!("less than {}", self.devices.len()) })?
+        };
+        self.configure(device, &d)?;
+        f(&mut self.spi)
+    }
+
+    pub fn transfer(&mut self, device: usize, read: &mut [u32], write: &[u32]) -> Result<(), Error> {
+        self.transaction(device, |spi| spi.transfer_words(read, write))
+    }
+
+    pub fn transfer_in_place(&mut self, device: usize, words: &mut [u32]) -> Result<(), Error> {
+        self.transaction(device, |spi| spi.transfer_words_in_place(words))
+    }
+
+    pub fn read(&mut self, device: usize, words: &mut [u32]) -> Result<(), Error> {
+        self.transaction(device, |spi| spi.read_words(words))
+    }
+
+    pub fn write(&mut self, device: usize, words: &[u32]) -> Result<(), Error> {
+        self.transaction(device, |spi| spi.write_words(words))
+    }
+
+    // The chip selects are left driven, deselected, so nothing on the bus wakes up on a floating pin.
+    pub fn into_inner(self) -> Result<StateMachine<'a>, Error> {
+        self.spi.into_inner()
+    }
+
+    fn configure(&mut self, device: usize, d: &Device) -> Result<(), Error> {
+        if self.current == Some(device) {
+            return Ok(());
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, d.frequency * CYCLES_PER_BIT)?;
+        let spi = &mut self.spi;
+        if (spi.mode, spi.bit_order, spi.word_size, spi.clkdiv) != (d.mode, d.bit_order, d.word_size, clkdiv) {
+            (spi.mode, spi.bit_order, spi.word_size, spi.clkdiv) = (d.mode, d.bit_order, d.word_size, clkdiv);
+            spi.setup()?;
+        }
+        self.current = Some(device);
+        Ok(())
+    }
+
+    // Between transactions the SM sits stalled waiting for data, so the `set` this execs doesn't get in its way.
+    fn select(&self, d: &Device, selected: bool) -> Result<(), Error> {
+        self.spi.sm.set_pins_with_mask(if selected == d.cs_active_high { 1 << d.cs } else { 0 }, 1 << d.cs)
+    }
+}
+
+// What the slave pushes when CS goes away. Bytes only ever fill 8 bits of a word, so it can't be one.
+const END_OF_TRANSACTION: u32 = 0xffff_ffff;
+
+// Between bytes the ISR and OSR are both empty, so the OSR can be used to pick SCK out of the pins while also watching
+// CS with `jmp pin`. Within a byte it just waits for the edges. The response byte waits in X while the OSR's busy.
+fn slave_program(mode: u8, bit_order: BitOrder, sck: u32, cs: u32, mosi: u32) -> String {
+    let (cpol, cpha) = (mode & 2 != 0, mode & 1 != 0);
+    let (lead, trail) = (!cpol as u8, cpol as u8);
+    let sck_bit = (sck + 32 - mosi) % 32;
+    let skip = match bit_order {
+        BitOrder::MsbFirst => 31 - sck_bit,
+        BitOrder::LsbFirst => sck_bit,
+    };
+    let mut source = format!(".program spi_slave\n.wrap_target\n    wait 0 gpio {cs}\n    set pindirs, 1\nbyte:\n    mov x, null\n    pull noblock\n");
+    if !cpha {
+        source += "    out pins, 1\n";
+    }
+    source += "    mov x, osr\npoll:\n    jmp pin done\n    mov osr, pins\n";
+    if skip > 0 {
+        source += &format!("    out null, {skip}\n");
+    }
+    source += &format!("    out y, 1\n    jmp {} poll\n    mov osr, x\n", if cpol { "y--" } else { "!y" });
+    source += &if cpha {
+        format!("    set y, 7\n    jmp first\nbit:\n    wait {lead} gpio {sck}\nfirst:\n    out pins, 1\n")
+            + &format!("    wait {trail} gpio {sck}\n    in pins, 1\n    jmp y-- bit\n    jmp byte\n")
+    } else {
+        format!("    set y, 6\n    in pins, 1\nbit:\n    wait {trail} gpio {sck}\n    out pins, 1\n    wait {lead} gpio {sck}\n")
+            + &format!("    in pins, 1\n    jmp y-- bit\n    wait {trail} gpio {sck}\n    jmp byte\n")
+    };
+    // Let go of MISO and throw away what's left of the response.
+    source += "done:\n    set pindirs, 0\ndrain:\n    pull noblock\n    mov x, status\n    jmp !x drain\n    mov isr, ~null\n    push\n.wrap\n";
+    source
+}
+
+pub struct Slave<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    sck: u32,
+    cs: u32,
+    mosi: u32,
+    miso: u32,
+    mode: u8,
+    bit_order: BitOrder,
+    request: Vec<u8>,
+}
+
+impl<'a> Slave<'a> {
+    // Starts out in mode 0, MSB first.
+    pub fn new(sm: StateMachine<'a>, sck: u32, cs: u32, mosi: u32, miso: u32) -> Result<Slave<'a>, Error> {
+        for pin in [sck, cs, mosi, miso] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let mut spi = Slave { sm, program: None, sck, cs, mosi, miso, mode: 0, bit_order: BitOrder::MsbFirst, request: Vec::new() };
+        spi.setup()?;
+        Ok(spi)
+    }
+
+    pub fn with_mode(mut self, mode: u8) -> Result<Self, Error> {
+        if mode > 3 {
+            Err(Error::ParamErr { param: "mode", should_be: "0..=3".to_string() })?;
+        }
+        self.mode = mode;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn with_bit_order(mut self, bit_order: BitOrder) -> Result<Self, Error> {
+        self.bit_order = bit_order;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
+    pub fn bit_order(&self) -> BitOrder {
+        self.bit_order
+    }
+
+    // Stops the SM and lets go of MISO.
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        self.sm.set_pindirs_with_mask(0, 1 << self.miso)?;
+        Ok(self.sm)
+    }
+
+    // Queues bytes for MISO. Waits for room when the TX FIFO's full, which means waiting for the controller to clock
+    // them out.
+    pub fn respond(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        for &byte in bytes {
+            let word = match self.bit_order {
+                BitOrder::MsbFirst => (byte as u32) << 24,
+                BitOrder::LsbFirst => byte as u32,
+            };
+            self.sm.put(word, true)?;
+        }
+        Ok(())
+    }
+
+    // Waits for the controller to finish a transaction and returns the bytes it sent.
+    pub fn transaction(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(request) = self.received(self.sm.get(true)?) {
+                return Ok(request);
+            }
+        }
+    }
+
+    // Returns None instead of waiting when no transaction has finished.
+    pub fn try_transaction(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        while !self.sm.is_rx_fifo_empty()? {
+            if let Some(request) = self.received(self.sm.get(false)?) {
+                return Ok(Some(request));
+            }
+        }
+        Ok(None)
+    }
+
+    fn received(&mut self, word: u32) -> Option<Vec<u8>> {
+        if word == END_OF_TRANSACTION {
+            return Some(std::mem::take(&mut self.request));
+        }
+        self.request.push(match self.bit_order {
+            BitOrder::MsbFirst => word as u8,
+            BitOrder::LsbFirst => (word >> 24) as u8,
+        });
+        None
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let source = slave_program(self.mode, self.bit_order, self.sck, self.cs, self.mosi);
+        let program = pio.load_program(&PioProgram::assemble(&source)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let shift_right = self.bit_order == BitOrder::LsbFirst;
+        let config = SmConfig::default()
+            .set_out_pins(self.miso, 1)?
+            .set_set_pins(self.miso, 1)?
+            .set_in_pins(self.mosi)?
+            .set_jmp_pin(self.cs)?
+            .set_out_shift(shift_right, false, 32)?
+            .set_in_shift(shift_right, true, 8)?
+            .set_mov_status(PioMovStatus::TxLessThan, 1)?
+            .set_wrap(wrap_target, wrap)?;
+        let pins = 1 << self.sck | 1 << self.cs | 1 << self.mosi | 1 << self.miso;
+        self.sm.set_pins_with_mask(0, 1 << self.miso)?;
+        self.sm.set_pindirs_with_mask(0, pins)?;
+        for pin in [self.sck, self.cs, self.mosi, self.miso] {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.request.clear();
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+mod hal {
+    use embedded_hal::spi::{ErrorKind, ErrorType, SpiBus};
+
+    use super::PioSpi;
+    use crate::Error;
+
+    impl embedded_hal::spi::Error for Error {
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    impl ErrorType for PioSpi<'_> {
+        type Error = Error;
+    }
+
+    // Every transfer waits for its last word to come back, so there's never anything left to flush.
+    impl SpiBus<u8> for PioSpi<'_> {
+        fn read(&mut self, words: &mut [u8]) -> Result<(), Error> {
+            PioSpi::read(self, words)
+        }
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+            PioSpi::write(self, words)
+        }
+
+        fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Error> {
+            PioSpi::transfer(self, read, write)
+        }
+
+        fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Error> {
+            PioSpi::transfer_in_place(self, words)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "embedded-hal-async")]
+mod hal_async {
+    use embedded_hal_async::spi::SpiBus;
+
+    use super::{PioSpi, Word};
+    use crate::{drivers::asynch::yield_now, Error};
+
+    impl PioSpi<'_> {
+        // transfer() without blocking in the put() and get() ioctls.
+        async fn transfer_async<W: Word>(&mut self, read: &mut [W], write: &[W]) -> Result<(), Error> {
+            let len = read.len().max(write.len());
+            let depth = self.sm.pio().chip().fifo_depth as usize;
+            let (mut sent, mut received) = (0, 0);
+            while received < len {
+                if sent < len && sent - received < depth && !self.sm.is_tx_fifo_full()? {
+                    self.sm.put(self.out_word(write.get(sent).map_or(0, |w| w.to_u32())), false)?;
+                    sent += 1;
+                } else if !self.sm.is_rx_fifo_empty()? {
+                    let word = self.in_word(self.sm.get(false)?);
+                    if let Some(r) = read.get_mut(received) {
+                        *r = W::from_u32(word);
+                    }
+                    received += 1;
+                } else {
+                    yield_now().await;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl SpiBus<u8> for PioSpi<'_> {
+        async fn read(&mut self, words: &mut [u8]) -> Result<(), Error> {
+            self.transfer_async(words, &[]).await
+        }
+
+        async fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+            self.transfer_async(&mut [], words).await
+        }
+
+        async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Error> {
+            self.transfer_async(read, write).await
+        }
+
+        async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Error> {
+            let write = words.to_vec();
+            self.transfer_async(words, &write).await
+        }
+
+        async fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    #[test]
+    fn loopback() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend), Chip::new());
+        for (mode, bit_order) in [(0, BitOrder::MsbFirst), (1, BitOrder::LsbFirst), (3, BitOrder::MsbFirst)] {
+            // MISO on the MOSI pin reads back what's being sent.
+            let mut spi = PioSpi::new(pio.sm_claim(0).unwrap(), 2, 3, 3).unwrap()
+                .with_mode(mode).unwrap()
+                .with_bit_order(bit_order).unwrap();
+            let mut data = [0xa5, 0x3c, 0x01, 0x80, 0xff, 0x00, 0x5a];
+            spi.transfer_in_place(&mut data).unwrap();
+            assert_eq!(data, [0xa5, 0x3c, 0x01, 0x80, 0xff, 0x00, 0x5a], "mode {mode}");
+            let mut read = [0; 3];
+            spi.transfer(&mut read, &[1, 2]).unwrap();
+            assert_eq!(read, [1, 2, 0]);
+            spi.into_inner().unwrap().unclaim().unwrap();
+        }
+    }
+
+    #[test]
+    fn master() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut spi = Master::new(pio.sm_claim(0).unwrap(), 2, 3, 3).unwrap();
+        let flash = spi.add_device(Device::new(5)).unwrap();
+        let adc = spi.add_device(Device { mode: 1, bit_order: BitOrder::LsbFirst, word_size: 12, cs_active_high: true,
+                                          ..Device::new(6) }).unwrap();
+        let wide = spi.add_device(Device { mode: 3, word_size: 32, frequency: 5_000_000.0, ..Device::new(7) }).unwrap();
+        assert!(spi.add_device(Device::new(2)).is_err());
+        assert!(spi.add_device(Device { word_size: 33, ..Device::new(8) }).is_err());
+        let deselected = 1 << 5 | 1 << 7; // 6 is active high
+        assert_eq!(backend.emulator().pins() & 0b111 << 5, deselected);
+
+        let selected = |cs: u32| deselected ^ 1 << cs;
+        let mut read = [0; 3];
+        spi.transaction(flash, |bus| {
+            assert_eq!(backend.emulator().pins() & 0b111 << 5, selected(5));
+            bus.transfer_words(&mut read, &[0xa5, 0x13c])
+        }).unwrap();
+        assert_eq!(read, [0xa5, 0x3c, 0]);
+        let mut words = [0xabc, 0x123, 0xfff];
+        spi.transaction(adc, |bus| {
+            assert_eq!(backend.emulator().pins() & 0b111 << 5, selected(6));
+            bus.transfer_words_in_place(&mut words)
+        }).unwrap();
+        assert_eq!(words, [0xabc, 0x123, 0xfff]);
+        let mut words = [0xdeadbeef, 1, 0x80000000];
+        spi.transfer_in_place(wide, &mut words).unwrap();
+        assert_eq!(words, [0xdeadbeef, 1, 0x80000000]);
+        assert_eq!(spi.device(wide).unwrap().word_size, 32);
+        assert_eq!(backend.emulator().pins() & 0b111 << 5, deselected);
+        assert!(spi.write(3, &[0]).is_err());
+    }
+
+    // Plays the controller: clocks `bytes` out on MOSI (pin 11) with SCK on 10 and CS on 13, reading MISO (12) on the
+    // edge the mode says to.
+    fn control(backend: &EmulatorBackend, mode: u8, lsb_first: bool, bytes: &[u8]) -> Vec<u8> {
+        let (cpol, cpha) = (mode & 2 != 0, mode & 1 != 0);
+        let mut emu = backend.emulator();
+        emu.set_input(10, cpol);
+        emu.set_input(13, false);
+        emu.run(50);
+        let mut read = Vec::new();
+        for &byte in bytes {
+            let mut miso = 0;
+            for n in 0..8 {
+                let bit = if lsb_first { n } else { 7 - n };
+                emu.set_input(11, byte >> bit & 1 != 0);
+                emu.run(20);
+                for edge in [!cpol, cpol] {
+                    if (edge != cpol) != cpha {
+                        miso |= ((emu.pins() >> 12 & 1) as u8) << bit;
+                    }
+                    emu.set_input(10, edge);
+                    emu.run(20);
+                }
+            }
+            read.push(miso);
+        }
+        emu.set_input(13, true);
+        emu.run(100);
+        read
+    }
+
+    #[test]
+    fn slave() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        backend.emulator().set_input(13, true);
+        for (mode, bit_order) in [(0, BitOrder::MsbFirst), (1, BitOrder::LsbFirst), (2, BitOrder::MsbFirst), (3, BitOrder::MsbFirst)] {
+            let lsb_first = bit_order == BitOrder::LsbFirst;
+            let mut spi = Slave::new(pio.sm_claim(0).unwrap(), 10, 13, 11, 12).unwrap()
+                .with_mode(mode).unwrap()
+                .with_bit_order(bit_order).unwrap();
+            spi.respond(&[0x12, 0xc4]).unwrap();
+            assert_eq!(control(&backend, mode, lsb_first, &[0xa5, 0x0f, 0x80]), [0x12, 0xc4, 0], "mode {mode}");
+            assert_eq!(spi.transaction().unwrap(), [0xa5, 0x0f, 0x80]);
+            assert_eq!(backend.emulator().pindirs() & 1 << 12, 0);
+            // The rest of a response that doesn't get clocked out doesn't carry over.
+            spi.respond(&[0x5a, 0x77]).unwrap();
+            assert_eq!(control(&backend, mode, lsb_first, &[0x01]), [0x5a]);
+            assert_eq!(control(&backend, mode, lsb_first, &[0x02]), [0]);
+            assert_eq!(spi.try_transaction().unwrap(), Some(vec![0x01]));
+            assert_eq!(spi.try_transaction().unwrap(), Some(vec![0x02]));
+            assert_eq!(spi.try_transaction().unwrap(), None);
+            spi.into_inner().unwrap().unclaim().unwrap();
+        }
+    }
+
+    #[cfg(feature = "embedded-hal-async")]
+    #[test]
+    fn async_loopback() {
+        use embedded_hal_async::spi::SpiBus;
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut spi = PioSpi::new(pio.sm_claim(0).unwrap(), 2, 3, 3).unwrap();
+        let mut data = [0xa5, 0x3c, 0x01, 0x80, 0xff, 0x00, 0x5a];
+        crate::drivers::asynch::tests::block_on(SpiBus::transfer_in_place(&mut spi, &mut data), || backend.emulator().run(10)).unwrap();
+        assert_eq!(data, [0xa5, 0x3c, 0x01, 0x80, 0xff, 0x00, 0x5a]);
+    }
+}
@@ -0,0 +1,312 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A stepper motor driver (A4988, DRV8825, TMC2208 and the like) on its STEP and DIR pins:
+//
+//     let mut motor = Stepper::new(pio.sm_claim_unused()?, 5, 6)?   // STEP, DIR
+//         .with_speed(4000.0)?                                     // steps/s
+//         .with_acceleration(20_000.0)?                            // steps/s²
+//         .with_profile(Profile::SCurve)?;
+//     motor.move_by(3200)?;
+//     motor.move_to(0)?;
+//     motor.wait()?;
+//
+// The SM takes a word for each step, sets DIR, waits out the step's interval and then sends a 3 µs pulse, so the
+// timing is to the µs however busy the CPU is. The intervals come from Profile::intervals(), which ramps the speed up
+// and down either linearly (Trapezoid) or along an S curve (SCurve, which keeps the acceleration itself from
+// changing suddenly, for less jerk and ringing), and long moves are streamed with sm_xfer_data(). The moves are
+// queued, and the SM stops with STEP low whenever it runs out, so the last step of a move is never cut short.
+
+use super::led;
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+// The shortest interval, in µs: the cycles the SM needs for each step besides the wait.
+pub const MIN_INTERVAL: u32 = 9;
+const MAX_INTERVAL: u32 = 1 << 30;
+
+// Each word is DIR in bit 0, whether to step in bit 1, and then the wait before the step. wait() sends one that
+// doesn't, which can only be taken once the step before it has finished.
+const PROGRAM: &str = "
+    .program stepper
+    .side_set 1
+    .wrap_target
+    next:
+        pull                side 0
+        out pins, 1         side 0
+        out y, 1            side 0
+        out x, 30           side 0
+    wait:
+        jmp x-- wait        side 0
+        jmp !y next         side 0
+        nop                 side 1 [2]
+    .wrap
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Trapezoid, // Constant acceleration up to speed and back down
+    SCurve,    // Acceleration eases in and out (as a smoothstep), peaking at the given acceleration
+}
+
+impl Profile {
+    // The µs between each of `steps` steps (and the move's start, for the first) for a move that gets up to `speed`
+    // steps/s, if it's long enough to, at `acceleration` steps/s².
+    pub fn intervals(self, steps: u64, speed: f64, acceleration: f64) -> Result<Vec<u32>, Error> {
+        if !(speed > 0.0 && speed <= 1e6 / MIN_INTERVAL as f64) {
+            Err(Error::ParamErr { param: "speed", should_be: format!("up to {} steps/s", 1_000_000 / MIN_INTERVAL) })?;
+        }
+        if !(acceleration > 0.0 && acceleration.is_finite()) {
+            Err(Error::ParamErr { param: "acceleration", should_be: "more than 0".to_string() })?;
+        }
+        // Getting up to speed takes the same distance either way, but the S curve takes longer to cover it.
+        let k = match self { Profile::Trapezoid => 1.0, Profile::SCurve => 1.5 };
+        let total = steps as f64;
+        let speed = speed.min((total * acceleration / k).sqrt());
+        let ramp_time = k * speed / acceleration;
+        let ramp = speed * ramp_time / 2.0;
+        let time = 2.0 * ramp_time + (total - 2.0 * ramp) / speed;
+        let ramping = |t: f64| {
+            let x = t / ramp_time;
+            speed * ramp_time * match self {
+                Profile::Trapezoid => x * x / 2.0,
+                Profile::SCurve    => x * x * x - x.powi(4) / 2.0,
+            }
+        };
+        let position = |t: f64| match t {
+            t if t < ramp_time        => ramping(t),
+            t if t > time - ramp_time => total - ramping(time - t),
+            t                         => ramp + (t - ramp_time) * speed,
+        };
+        let mut intervals = Vec::with_capacity(steps as usize);
+        let mut last = 0;
+        for step in 1..=steps {
+            // It only ever goes forwards, so the time it gets to each step is a bisection away.
+            let (mut early, mut late) = (0.0, time);
+            for _ in 0..64 {
+                let t = (early + late) / 2.0;
+                if position(t) < step as f64 { early = t } else { late = t }
+            }
+            let us = (late * 1e6).round() as u64;
+            let interval = (us - last).clamp(MIN_INTERVAL as u64, MAX_INTERVAL as u64) as u32;
+            last += interval as u64;
+            intervals.push(interval);
+        }
+        Ok(intervals)
+    }
+}
+
+pub struct Stepper<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    step: u32,
+    dir: u32,
+    speed: f64,        // steps/s
+    acceleration: f64, // steps/s²
+    profile: Profile,
+    position: i64,     // Once everything queued is done
+    forwards: bool,    // DIR, as of the last step queued
+    words: Vec<u32>,
+}
+
+impl<'a> Stepper<'a> {
+    // Starts at 1000 steps/s, accelerating at 5000 steps/s² along a trapezoid.
+    pub fn new(sm: StateMachine<'a>, step: u32, dir: u32) -> Result<Stepper<'a>, Error> {
+        for pin in [step, dir] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let mut stepper = Stepper { sm, program: None, step, dir, speed: 1000.0, acceleration: 5000.0,
+                                    profile: Profile::Trapezoid, position: 0, forwards: true,
+                                    words: Vec::new() };
+        stepper.setup()?;
+        Ok(stepper)
+    }
+
+    // The top speed, in steps/s.
+    pub fn with_speed(mut self, speed: f64) -> Result<Self, Error> {
+        Profile::Trapezoid.intervals(0, speed, self.acceleration)?;
+        self.speed = speed;
+        Ok(self)
+    }
+
+    // In steps/s². For an S curve, it's the most it ever gets to.
+    pub fn with_acceleration(mut self, acceleration: f64) -> Result<Self, Error> {
+        Profile::Trapezoid.intervals(0, self.speed, acceleration)?;
+        self.acceleration = acceleration;
+        Ok(self)
+    }
+
+    pub fn with_profile(mut self, profile: Profile) -> Result<Self, Error> {
+        self.profile = profile;
+        Ok(self)
+    }
+
+    // Where it will be once the moves queued so far are done, in steps from where it started (or set_position()).
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: i64) {
+        self.position = position;
+    }
+
+    // Waits for the moves queued so far to finish, then stops the SM with STEP low.
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.wait()?;
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Queues a move of `steps` (negative for backwards), ramping up and down.
+    pub fn move_by(&mut self, steps: i64) -> Result<(), Error> {
+        let intervals = self.profile.intervals(steps.unsigned_abs(), self.speed, self.acceleration)?;
+        self.steps(steps >= 0, &intervals)
+    }
+
+    pub fn move_to(&mut self, position: i64) -> Result<(), Error> {
+        self.move_by(position - self.position)
+    }
+
+    // Queues a step `intervals[n]` µs after the one before for each of `intervals`, for making up moves that
+    // Profile doesn't.
+    pub fn steps(&mut self, forwards: bool, intervals: &[u32]) -> Result<(), Error> {
+        if let Some(bad) = intervals.iter().find(|&&us| !(MIN_INTERVAL..=MAX_INTERVAL).contains(&us)) {
+            let should_be = format!("{MIN_INTERVAL} to {MAX_INTERVAL} µs, not {bad}");
+            Err(Error::ParamErr { param: "intervals", should_be })?;
+        }
+        if intervals.is_empty() {
+            return Ok(());
+        }
+        self.words.clear();
+        self.words.extend(intervals.iter().map(|&us| (us - MIN_INTERVAL) << 2 | 1 << 1 | forwards as u32));
+        led::queue(&self.sm, &self.words)?;
+        self.forwards = forwards;
+        self.position += if forwards { intervals.len() as i64 } else { -(intervals.len() as i64) };
+        Ok(())
+    }
+
+    // Waits for the moves queued so far to finish.
+    pub fn wait(&mut self) -> Result<(), Error> {
+        self.sm.put(self.forwards as u32, true)?;
+        self.sm.drain_tx_fifo()
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.dir, 1)?
+            .set_sideset(1, false, false)?
+            .set_sideset_pins(self.step)?
+            .set_out_shift(true, false, 32)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, 1_000_000.0)?)?
+            .set_wrap(wrap_target, wrap)?;
+        let pins = 1 << self.step | 1 << self.dir;
+        self.sm.set_pins_with_mask(0, pins)?;
+        self.sm.set_pindirs_with_mask(pins, pins)?;
+        for pin in [self.step, self.dir] {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const STEP: u32 = 5;
+    const DIR: u32 = 6;
+    const US: u64 = 200; // Cycles
+
+    // Each step: when it started (µs), how long STEP was high, DIR, and how long DIR had been steady.
+    #[derive(Debug, Default)]
+    struct Driver {
+        steps: Vec<(u64, u64, bool, u64)>,
+        rose: u64,
+        dir: bool,
+        dir_changed: u64,
+        last: u32,
+    }
+
+    impl Peripheral for Driver {
+        fn step(&mut self, pins: u32, now: u64) -> (u32, u32) {
+            let (step, dir) = (pins >> STEP & 1 != 0, pins >> DIR & 1 != 0);
+            if dir != self.dir {
+                (self.dir, self.dir_changed) = (dir, now);
+            }
+            match (self.last >> STEP & 1 != 0, step) {
+                (false, true) => self.rose = now,
+                (true, false) => self.steps.push((self.rose / US, (now - self.rose) / US, dir,
+                                                  (self.rose - self.dir_changed) / US)),
+                _             => {},
+            }
+            self.last = pins;
+            (0, 0)
+        }
+    }
+
+    #[test]
+    fn profiles() {
+        // 0.1 s up to speed and 0.1 s back down, covering 50 steps each, and 100 steps at full speed.
+        let trapezoid = Profile::Trapezoid.intervals(200, 1000.0, 10_000.0).unwrap();
+        assert_eq!(trapezoid.len(), 200);
+        assert_eq!(trapezoid.iter().sum::<u32>(), 300_000);
+        assert_eq!(trapezoid[0], 14142); // √(2/a)
+        assert_eq!(trapezoid[100], 1000);
+        assert!(trapezoid[..50].windows(2).all(|w| w[0] >= w[1]));
+        assert!(trapezoid.iter().zip(trapezoid.iter().rev()).all(|(a, b)| a.abs_diff(*b) <= 1));
+
+        // The S curve takes 0.15 s each way, and starts off slower.
+        let s = Profile::SCurve.intervals(200, 1000.0, 10_000.0).unwrap();
+        assert!(s.iter().sum::<u32>().abs_diff(350_000) <= 1);
+        assert!(s[0] > trapezoid[0]);
+        assert_eq!(s[100], 1000);
+
+        // Too short to get up to speed.
+        let short = Profile::Trapezoid.intervals(20, 1000.0, 10_000.0).unwrap();
+        assert_eq!(short.iter().sum::<u32>(), 89443); // 2 √(20/a)
+        assert!(short.iter().all(|&us| us > 1000));
+
+        assert!(Profile::Trapezoid.intervals(10, 200_000.0, 10_000.0).is_err());
+        assert!(Profile::SCurve.intervals(0, 1000.0, 10_000.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn steps() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let driver = Arc::new(Mutex::new(Driver::default()));
+        backend.emulator().attach(driver.clone());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut motor = Stepper::new(pio.sm_claim(0).unwrap(), STEP, DIR).unwrap()
+            .with_speed(20_000.0).unwrap()
+            .with_acceleration(20_000_000.0).unwrap();
+        // (Short enough to fit in the FIFO: the emulator doesn't do DMA.)
+        motor.move_by(6).unwrap();
+        motor.steps(false, &[100, 50]).unwrap();
+        motor.move_to(3).unwrap();
+        assert_eq!(motor.position(), 3);
+        motor.into_inner().unwrap();
+
+        let intervals = Profile::Trapezoid.intervals(6, 20_000.0, 20_000_000.0).unwrap();
+        let driver = driver.lock().unwrap();
+        let starts: Vec<u64> = driver.steps.iter().map(|s| s.0).collect();
+        let gaps: Vec<u64> = starts.windows(2).map(|w| w[1] - w[0]).collect();
+        assert_eq!(gaps[..5], intervals[1..].iter().map(|&us| us as u64).collect::<Vec<_>>()[..]);
+        assert_eq!(gaps[5..7], [100, 50]);
+        assert_eq!(driver.steps.len(), 9);
+        assert!(driver.steps.iter().all(|s| s.1 == 3 && s.3 >= 2));
+        assert_eq!(driver.steps.iter().map(|s| s.2).collect::<Vec<_>>(), [[true; 6], [false; 6]].concat()[..9]);
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// TM1637 LED display drivers, the chip on most of the cheap 4 and 6 digit modules. Its two wire interface looks a bit
+// like I2C (open drain, start and stop conditions, an ack after every byte) but it's LSB first and has no addresses,
+// so the I2C controllers can't talk to it:
+//
+//     let mut display = Tm1637::new(pio.sm_claim_unused()?, 20, 21, 4)?   // clock, data, digits
+//         .with_brightness(3)?;
+//     display.display_str("12.34")?;
+//
+// Both lines are only ever pulled low or let go (the pindirs do the work), so they need pull-ups. The modules have
+// them, and the pads' own pull-ups are turned on too. The acks aren't checked.
+//
+// Each FIFO word is a byte. A command is a byte with the number of bytes to follow it, then those bytes, inverted so
+// that a 0 drives the data line low.
+
+use super::seven_segment::SegmentDisplay;
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 16.0;
+
+const PROGRAM: &str = "
+    .program tm1637
+    .side_set 1 opt pindirs
+    .wrap_target
+        out x, 8                    ; Bytes - 1
+        set pindirs, 1      [7]     ; Start: data low with the clock high
+    byteloop:
+        set y, 7            side 1 [7]
+    bitloop:
+        out pindirs, 1      side 1 [7]
+        jmp y-- bitloop     side 0 [7]
+        set pindirs, 0      side 1 [7]   ; Let go of data for the ack
+        nop                 side 0 [7]
+        jmp x-- byteloop    side 1 [7]
+        set pindirs, 1      side 1 [7]
+        nop                 side 0 [7]
+        set pindirs, 0             [7]   ; Stop: data high with the clock high
+    .wrap
+";
+
+const DATA_AUTO_INCREMENT: u8 = 0x40;
+const ADDRESS: u8 = 0xc0;
+const DISPLAY_ON: u8 = 0x88;
+
+pub struct Tm1637<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    clock: u32,
+    data: u32,
+    digits: usize,
+    clkdiv: ClkDiv,
+    brightness: u8,
+}
+
+impl<'a> Tm1637<'a> {
+    // Starts out at 100 kHz and full brightness.
+    pub fn new(sm: StateMachine<'a>, clock: u32, data: u32, digits: usize) -> Result<Tm1637<'a>, Error> {
+        if !(1..=6).contains(&digits) {
+            Err(Error::ParamErr { param: "digits", should_be: "1..=6".to_string() })?;
+        }
+        for pin in [clock, data] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, 100_000.0 * CYCLES_PER_BIT)?;
+        let mut display = Tm1637 { sm, program: None, clock, data, digits, clkdiv, brightness: 7 };
+        display.setup()?;
+        display.clear()?;
+        display.set_brightness(7)?;
+        Ok(display)
+    }
+
+    pub fn with_frequency(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz * CYCLES_PER_BIT)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn with_brightness(mut self, brightness: u8) -> Result<Self, Error> {
+        self.set_brightness(brightness)?;
+        Ok(self)
+    }
+
+    // 0..=7, which the chip turns into a pulse width of 1/16 to 14/16.
+    pub fn set_brightness(&mut self, brightness: u8) -> Result<(), Error> {
+        if brightness > 7 {
+            Err(Error::ParamErr { param: "brightness", should_be: "0..=7".to_string() })?;
+        }
+        self.brightness = brightness;
+        self.command(&[DISPLAY_ON | brightness])
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    // Turns the display off, leaving what it's showing alone. set_brightness() turns it back on.
+    pub fn off(&mut self) -> Result<(), Error> {
+        self.command(&[DISPLAY_ON & !0x08])
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        while !self.sm.is_tx_fifo_empty()? {
+            std::thread::sleep(std::time::Duration::from_micros(100));
+        }
+        std::thread::sleep(std::time::Duration::from_secs_f64(2.0 * 9.0 * CYCLES_PER_BIT / self.clkdiv.actual_frequency(pio_clock_hz() as f64)));
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    fn command(&self, bytes: &[u8]) -> Result<(), Error> {
+        self.sm.put(bytes.len() as u32 - 1, true)?;
+        for &byte in bytes {
+            self.sm.put(!byte as u32, true)?;
+        }
+        Ok(())
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(PROGRAM)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.data, 1)?
+            .set_set_pins(self.data, 1)?
+            .set_sideset(2, true, true)?
+            .set_sideset_pins(self.clock)?
+            .set_out_shift(true, true, 8)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        let pins = 1 << self.clock | 1 << self.data;
+        self.sm.set_pins_with_mask(0, pins)?;
+        self.sm.set_pindirs_with_mask(0, pins)?;
+        for pin in [self.clock, self.data] {
+            pio.pio_gpio_init(pin as u16)?;
+            pio.set_pulls(pin as u16, true, false)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+impl SegmentDisplay for Tm1637<'_> {
+    fn digits(&self) -> usize {
+        self.digits
+    }
+
+    fn set_segments(&mut self, segments: &[u8]) -> Result<(), Error> {
+        if segments.len() > self.digits {
+            Err(Error::ParamErr { param: "segments", should_be: format!("at most {} digits", self.digits) })?;
+        }
+        let mut data = vec![ADDRESS];
+        data.extend(segments);
+        data.resize(1 + self.digits, 0);
+        self.command(&[DATA_AUTO_INCREMENT])?;
+        self.command(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    // Decodes `count` commands off the lines. They're pulled up, so they're high unless their pindir is set. A byte is 8
+    // bits and an ack, and the stop condition adds one more clock.
+    fn commands(backend: &EmulatorBackend, count: usize) -> Vec<Vec<u8>> {
+        let mut emu = backend.emulator();
+        let lines = |emu: &Emulator| (emu.pindirs() & 1 << 20 == 0, emu.pindirs() & 1 << 21 == 0);
+        let (mut commands, mut bits) = (Vec::new(), Vec::new());
+        let (mut last, start) = (lines(&emu), emu.cycle());
+        while commands.len() < count && emu.cycle() - start < 10_000_000 {
+            emu.step();
+            let (clock, data) = lines(&emu);
+            match (last, (clock, data)) {
+                ((true, true), (true, false)) => bits.clear(),                            // Start
+                ((true, false), (true, true)) => commands.push(bits.chunks_exact(9)       // Stop
+                    .map(|byte| byte[..8].iter().rev().fold(0, |b, &bit| b << 1 | bit as u8)).collect()),
+                ((false, _), (true, data))    => bits.push(data),                         // Rising clock
+                _                             => {},
+            }
+            last = (clock, data);
+        }
+        commands
+    }
+
+    #[test]
+    fn commands_and_segments() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let display = Tm1637::new(pio.sm_claim(0).unwrap(), 20, 21, 4).unwrap();
+        // That's more than fits in the FIFO, so it's been partly sent already. Let the rest go.
+        backend.emulator().run_until(10_000_000, |emu| emu.sm(0).tx_level() == 0).unwrap();
+        backend.emulator().run(100_000);
+        let mut display = display.with_brightness(2).unwrap();
+        assert_eq!(commands(&backend, 1), [vec![0x8a]]);
+        display.display_str("1.2").unwrap();
+        assert_eq!(commands(&backend, 2), [vec![0x40], vec![0xc0, 0x86, 0x5b, 0, 0]]);
+        assert!(display.set_brightness(8).is_err());
+    }
+}
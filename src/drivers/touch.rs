@@ -0,0 +1,286 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Capacitive touch pads, with nothing but a resistor (1 MΩ or so) from each pad's pin up to 3.3 V:
+//
+//     let mut touch = Touch::new(pio.sm_claim_unused()?, &[5, 6, 7])?
+//         .with_thresholds(2, 0.2, 0.1)?;      // The big pad needs more of a touch
+//     loop {
+//         for reading in touch.measure_all()? {
+//             if reading.changed {
+//                 println!("{}: {}", reading.pad, if reading.touched { "touched" } else { "released" });
+//             }
+//         }
+//     }
+//
+// The SM shorts a pad to ground, lets go and times how long the resistor takes to charge it back up to a 1, to 10 ns.
+// A finger adds capacitance, so it takes longer. Pads are measured one at a time on the one SM.
+//
+// Each pad's baseline is the average of a few readings when it's set up (so don't touch them then: calibrate() does it
+// again) and then follows slow drift while it isn't touched. It counts as touched once a reading is more than the touch
+// threshold over the baseline (10% to start with) and released again once it's back under the release threshold (5%),
+// which being lower stops it chattering in between.
+
+use std::time::Duration;
+
+use crate::{pio_clock_hz, ClkDiv, Error, LoadedProgram, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_COUNT: u64 = 2;
+// Readings averaged for the baseline, and how much of each later one it takes in.
+const CALIBRATION_READINGS: usize = 8;
+const TRACKING: f64 = 1.0 / 32.0;
+
+// The CPU sends how many counts to wait for the pad to charge. What's left of them is pushed, or !0 if it never did.
+const PROGRAM: &str = "
+    .program touch
+    .wrap_target
+        pull
+        mov x, osr
+        set y, 7
+        set pindirs, 1              ; Discharge
+    discharge:
+        jmp y-- discharge   [31]
+        set pindirs, 0              ; And let the resistor charge it
+    charge:
+        jmp pin done
+        jmp x-- charge
+    done:
+        in x, 32
+    .wrap
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub pad: usize,               // Index into the pins given to new()
+    pub charge: Option<Duration>, // None if it never charged, which is what a missing resistor looks like
+    pub delta: f64,               // Over the baseline, as a fraction of it
+    pub touched: bool,
+    pub changed: bool,            // Touched or released since the last reading
+}
+
+#[derive(Debug, Clone)]
+struct Pad {
+    pin: u32,
+    baseline: Option<f64>, // s
+    touch: f64,
+    release: f64,
+    touched: bool,
+}
+
+pub struct Touch<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pads: Vec<Pad>,
+    timeout: Duration,
+    next: usize,
+}
+
+impl<'a> Touch<'a> {
+    pub fn new(sm: StateMachine<'a>, pins: &[u32]) -> Result<Touch<'a>, Error> {
+        if pins.is_empty() {
+            Err(Error::ParamErr { param: "pins", should_be: "at least one".to_string() })?;
+        }
+        for &pin in pins {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let pads = pins.iter()
+            .map(|&pin| Pad { pin, baseline: None, touch: 0.1, release: 0.05, touched: false })
+            .collect();
+        let mut touch = Touch { sm, program: None, pads, timeout: Duration::from_millis(1), next: 0 };
+        touch.setup()?;
+        touch.calibrate()?;
+        Ok(touch)
+    }
+
+    // How far over the baseline a pad has to get to count as touched, and come back under to count as released
+    // again, as fractions of it.
+    pub fn with_thresholds(mut self, pad: usize, touch: f64, release: f64) -> Result<Self, Error> {
+        self.check_pad(pad)?;
+        if !(0.0 < release && release < touch && touch.is_finite()) {
+            Err(Error::ParamErr { param: "release", should_be: "more than 0 and less than touch".to_string() })?;
+        }
+        (self.pads[pad].touch, self.pads[pad].release) = (touch, release);
+        Ok(self)
+    }
+
+    // How long to wait for a pad to charge. It should only ever take a few tens of µs.
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self, Error> {
+        if timeout.is_zero() || timeout > Duration::from_secs(1) {
+            Err(Error::ParamErr { param: "timeout", should_be: "up to 1 s".to_string() })?;
+        }
+        self.timeout = timeout;
+        Ok(self)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Starts the baselines again from scratch, with nothing touched.
+    pub fn calibrate(&mut self) -> Result<(), Error> {
+        for pad in 0..self.pads.len() {
+            let charges: Vec<f64> = (0..CALIBRATION_READINGS)
+                .map(|_| self.charge(pad))
+                .collect::<Result<Vec<_>, Error>>()?
+                .into_iter().flatten().collect();
+            let baseline = (!charges.is_empty()).then(|| charges.iter().sum::<f64>() / charges.len() as f64);
+            (self.pads[pad].baseline, self.pads[pad].touched) = (baseline, false);
+        }
+        Ok(())
+    }
+
+    pub fn baseline(&self, pad: usize) -> Result<Option<Duration>, Error> {
+        self.check_pad(pad)?;
+        Ok(self.pads[pad].baseline.map(Duration::from_secs_f64))
+    }
+
+    pub fn is_touched(&self, pad: usize) -> Result<bool, Error> {
+        self.check_pad(pad)?;
+        Ok(self.pads[pad].touched)
+    }
+
+    // Measures one pad and works out whether it's touched.
+    pub fn measure(&mut self, pad: usize) -> Result<Reading, Error> {
+        self.check_pad(pad)?;
+        let charge = self.charge(pad)?;
+        let state = &mut self.pads[pad];
+        let was = state.touched;
+        let delta = match (charge, state.baseline) {
+            (Some(charge), Some(baseline)) => charge / baseline - 1.0,
+            (Some(charge), None) => {
+                state.baseline = Some(charge); // The first time it's charged
+                0.0
+            },
+            (None, _) => 0.0,
+        };
+        state.touched = if was { delta > state.release } else { delta > state.touch };
+        if let (Some(charge), Some(baseline), false) = (charge, state.baseline, state.touched) {
+            state.baseline = Some(baseline + (charge - baseline) * TRACKING);
+        }
+        Ok(Reading { pad, charge: charge.map(Duration::from_secs_f64), delta, touched: state.touched,
+                     changed: state.touched != was })
+    }
+
+    // Measures the next pad in turn.
+    pub fn measure_next(&mut self) -> Result<Reading, Error> {
+        let pad = self.next;
+        self.next = (pad + 1) % self.pads.len();
+        self.measure(pad)
+    }
+
+    // Measures each of them once.
+    pub fn measure_all(&mut self) -> Result<Vec<Reading>, Error> {
+        (0..self.pads.len()).map(|pad| self.measure(pad)).collect()
+    }
+
+    fn check_pad(&self, pad: usize) -> Result<(), Error> {
+        if pad >= self.pads.len() {
+            Err(Error::ParamErr { param: "pad", should_be: format!("< {}", self.pads.len()) })?;
+        }
+        Ok(())
+    }
+
+    // How long the pad took to charge, in s.
+    fn charge(&mut self, pad: usize) -> Result<Option<f64>, Error> {
+        let pin = self.pads[pad].pin;
+        self.sm.set_enabled(false)?;
+        self.sm.init(self.program.as_ref().map_or(0, |p| p.offset()), &self.config(pin)?)?;
+        self.sm.set_enabled(true)?;
+        let hz = pio_clock_hz() as f64;
+        let counts = (self.timeout.as_secs_f64() * hz / CYCLES_PER_COUNT as f64) as u32;
+        self.sm.put(counts, true)?;
+        let left = self.sm.get(true)?;
+        Ok((left != !0).then(|| ((counts - left) as u64 * CYCLES_PER_COUNT + 1) as f64 / hz))
+    }
+
+    fn config(&self, pin: u32) -> Result<SmConfig, Error> {
+        let (wrap_target, wrap) = self.program.as_ref().map_or((0, 0), |p| p.wrap());
+        SmConfig::default()
+            .set_set_pins(pin, 1)?
+            .set_jmp_pin(pin)?
+            .set_in_shift(false, true, 32)?
+            .set_clkdiv_int_frac(ClkDiv::for_frequency(pio_clock_hz() as f64, pio_clock_hz() as f64)?)?
+            .set_wrap(wrap_target, wrap)
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        self.program = Some(pio.load_program(&PioProgram::assemble(PROGRAM)?)?);
+        for pad in &self.pads {
+            self.sm.set_pins_with_mask(0, 1 << pad.pin)?; // Low whenever it's driven
+            self.sm.set_pindirs_with_mask(0, 1 << pad.pin)?;
+            pio.pio_gpio_init(pad.pin as u16)?;
+        }
+        self.sm.clear_fifos()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    // How long the SM shorts a pad for.
+    const DISCHARGE_CYCLES: u64 = 257;
+
+    // A pad that charges back up to a 1 `delay` cycles after it's let go. All it can see is the pin going low, so it
+    // goes by the discharge starting.
+    #[derive(Debug)]
+    struct Pad {
+        pin: u32,
+        delay: u64,
+        high: bool,
+        grounded: u64,
+    }
+
+    impl Peripheral for Pad {
+        fn step(&mut self, pins: u32, now: u64) -> (u32, u32) {
+            if self.high && pins >> self.pin & 1 == 0 {
+                (self.high, self.grounded) = (false, now);
+            }
+            self.high |= now >= self.grounded + DISCHARGE_CYCLES + self.delay;
+            ((self.high as u32) << self.pin, 1 << self.pin)
+        }
+    }
+
+    #[test]
+    fn touches() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pads: Vec<_> = [(5, 2000), (6, 3000)].iter().map(|&(pin, delay)| {
+            let pad = Arc::new(Mutex::new(Pad { pin, delay, high: true, grounded: 0 }));
+            backend.emulator().attach(pad.clone());
+            pad
+        }).collect();
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut touch = Touch::new(pio.sm_claim(0).unwrap(), &[5, 6]).unwrap()
+            .with_timeout(Duration::from_micros(100)).unwrap();
+        let baseline = touch.baseline(0).unwrap().unwrap();
+        assert!((baseline.as_secs_f64() * 200e6 / 2000.0 - 1.0).abs() < 0.01, "{baseline:?}");
+        assert!(touch.measure_all().unwrap().iter().all(|reading| !reading.touched && reading.delta.abs() < 0.01));
+
+        // Touched at 20% over, still touched at 7.5% and released under 5%.
+        let mut measure = |delay: u64| {
+            pads[0].lock().unwrap().delay = delay;
+            let reading = touch.measure(0).unwrap();
+            (reading.touched, reading.changed)
+        };
+        assert_eq!(measure(2400), (true, true));
+        assert_eq!(measure(2400), (true, false));
+        assert_eq!(measure(2150), (true, false));
+        assert_eq!(measure(2000), (false, true));
+        assert_eq!(touch.baseline(0).unwrap().unwrap(), baseline); // It only moves when it isn't touched
+        assert!(!touch.is_touched(1).unwrap());
+
+        pads[1].lock().unwrap().delay = 100_000;
+        assert_eq!(touch.measure(1).unwrap().charge, None);
+        assert!(touch.baseline(1).unwrap().is_some());
+        assert!(touch.with_thresholds(0, 0.1, 0.2).is_err());
+    }
+}
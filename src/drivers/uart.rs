@@ -0,0 +1,718 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A soft UART on any pin, using the uart_tx/uart_rx programs from the pico-examples, one SM each way:
+//
+//     let mut uart = Uart::new(pio.sm_claim_unused()?, pio.sm_claim_unused()?, 14, 15, 115_200)?;  // tx pin, rx pin
+//     uart.tx.write(b"AT\r\n")?;
+//     let reply = uart.rx.read_byte()?;
+//
+// It's 8N1 unless with_parity() says otherwise. Both SMs run at 8 clocks per bit, and Rx samples in the middle of
+// each bit. Rx pushes the whole frame, stop bit included, so every byte can be checked: receive() says whether it
+// arrived intact, had a parity or framing error, or was a break (the line held low for longer than a frame). The
+// byte stream (read_byte(), read() and the Read traits) just leaves out the frames that didn't arrive intact.
+//
+//     match uart.rx.receive()? {
+//         Received::Byte(b)                                    => println!("{b:#04x}"),
+//         Received::ParityError(_) | Received::FramingError(_) => println!("garbled"),
+//         Received::Break                                      => println!("break"),
+//     }
+//
+// For RS-485, with_rs485() has the Tx SM work the transceiver's driver enable as well, timed to the bit:
+//
+//     let uart = Uart::new(pio.sm_claim_unused()?, pio.sm_claim_unused()?, 14, 15, 115_200)?
+//         .with_rs485(17, Duration::from_micros(10), Duration::from_micros(10))?;  // DE pin, turnaround before and after
+//
+// With the `embedded-hal-nb` and `embedded-io` features Tx, Rx and Uart implement embedded_hal_nb::serial::{Read, Write}
+// and embedded_io::{Read, Write}, and with `embedded-io-async`, embedded_io_async::{Read, Write}.
+
+use std::time::Duration;
+
+use crate::{pio_clock_hz, proc_pio::PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS, ClkDiv, Error, LoadedProgram, PioFifoJoin,
+            PioMovStatus, PioProgram, SmConfig, StateMachine};
+
+const CYCLES_PER_BIT: f64 = 8.0;
+
+// The bits after the start bit (the data, then the parity bit if there is one) are counted in X.
+fn tx_program(bits: u32) -> String {
+    format!("
+    .program uart_tx
+    .side_set 1 opt
+        pull            side 1 [7]   ; Stop bit, or stall with the line idle
+        set x, {}       side 0 [7]   ; Start bit
+    bitloop:
+        out pins, 1
+        jmp x-- bitloop        [6]
+    ", bits - 1)
+}
+
+// In RS-485 mode the side-set is the transceiver's DE pin. It goes up `before` bit times ahead of the start bit and
+// stays up across bytes that follow on straight away, until `after` bit times past the last stop bit. The stop bit's
+// last cycle is the `set y` of the wait after it, or a nop when there isn't one.
+fn rs485_tx_program(bits: u32, before: u32, after: u32) -> String {
+    let wait = |label: &str, side: &str, n: u32| match n {
+        0 => String::new(),
+        n => format!("    set y, {} {side}\n{label}:\n    jmp y-- {label} [7]\n", n - 1),
+    };
+    let after = match after {
+        0 => "    nop\n".to_string(),
+        n => wait("after", "", n),
+    };
+    format!("
+    .program uart_tx_rs485
+    .side_set 1 opt
+    .wrap_target
+        pull                side 0      ; Transceiver off until there's something to send
+    {}
+        jmp byte
+    next:
+        pull
+    byte:
+        set pins, 0         side 1 [6]  ; Start bit
+        set x, {}
+    bitloop:
+        out pins, 1
+        jmp x-- bitloop            [6]
+        set pins, 1                [4]  ; Stop bit
+        mov x, status                   ; All 1s when there's nothing more to send
+        jmp !x next
+    {}
+    .wrap
+    ", wait("before", "side 1", before), bits - 1, after)
+}
+
+fn rx_program(bits: u32) -> String {
+    format!("
+    .program uart_rx
+    .wrap_target
+        wait 0 pin 0            ; Start bit
+        set x, {} [10]          ; Then on to the middle of the first data bit
+    bitloop:
+        in pins, 1
+        jmp x-- bitloop [6]
+        in pins, 1              ; The stop bit, which should be 1
+        push
+        wait 1 pin 0            ; A break holds the line low past the stop bit, so wait for it to let go
+    .wrap
+    ", bits - 1)
+}
+
+fn clkdiv(baud: u32) -> Result<ClkDiv, Error> {
+    ClkDiv::for_frequency(pio_clock_hz() as f64, baud as f64 * CYCLES_PER_BIT)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Parity {
+    #[default]
+    None,
+    Even, // The parity bit makes the number of 1s even
+    Odd,
+}
+
+impl Parity {
+    // Bits after the start bit, not counting the stop bit.
+    pub(crate) fn bits(self) -> u32 {
+        match self {
+            Parity::None => 8,
+            _            => 9,
+        }
+    }
+
+    fn bit(self, byte: u8) -> Option<u32> {
+        match self {
+            Parity::None => None,
+            Parity::Even => Some(byte.count_ones() & 1),
+            Parity::Odd  => Some(!byte.count_ones() & 1),
+        }
+    }
+
+    // Checks a frame: the data, the parity bit if there is one, then the stop bit.
+    pub(crate) fn check(self, frame: u32) -> Received {
+        let byte = frame as u8;
+        match (frame >> self.bits() & 1 != 0, self.bit(byte)) {
+            (false, _) if frame == 0                         => Received::Break,
+            (false, _)                                       => Received::FramingError(byte),
+            (true, Some(parity)) if frame >> 8 & 1 != parity => Received::ParityError(byte),
+            (true, _)                                        => Received::Byte(byte),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Received {
+    Byte(u8),
+    ParityError(u8),
+    FramingError(u8), // No stop bit, so the byte probably isn't what was sent
+    Break,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rs485 {
+    de: u32,
+    before: u32, // Bit times
+    after: u32,
+}
+
+pub struct Tx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    clkdiv: ClkDiv,
+    parity: Parity,
+    rs485: Option<Rs485>,
+}
+
+impl<'a> Tx<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32, baud: u32) -> Result<Tx<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut tx = Tx { sm, program: None, pin, clkdiv: clkdiv(baud)?, parity: Parity::None, rs485: None };
+        tx.setup()?;
+        Ok(tx)
+    }
+
+    pub fn with_parity(mut self, parity: Parity) -> Result<Self, Error> {
+        self.parity = parity;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn parity(&self) -> Parity {
+        self.parity
+    }
+
+    // Drives an RS-485 transceiver's DE pin (and ~RE, if they're tied together) high for exactly as long as it's
+    // sending, plus `before` ahead of the first start bit and `after` past the last stop bit for the transceiver and
+    // the far end to turn around. Both are rounded up to whole bit times, up to 32 of them.
+    pub fn with_rs485(mut self, de: u32, before: Duration, after: Duration) -> Result<Self, Error> {
+        self.sm.pio().check_gpio(de as u16)?;
+        let bits = |param, delay: Duration| -> Result<u32, Error> {
+            let bits = (delay.as_secs_f64() * self.baud_rate() - 1e-6).ceil().max(0.0) as u32;
+            if bits > 32 {
+                Err(Error::ParamErr { param, should_be: "at most 32 bit times".to_string() })?;
+            }
+            Ok(bits)
+        };
+        self.rs485 = Some(Rs485 { de, before: bits("before", before)?, after: bits("after", after)? });
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn baud_rate(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64) / CYCLES_PER_BIT
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        if let Some(rs485) = self.rs485 {
+            self.sm.set_pins_with_mask(0, 1 << rs485.de)?;
+        }
+        Ok(self.sm)
+    }
+
+    fn frame(&self, byte: u8) -> u32 {
+        byte as u32 | self.parity.bit(byte).unwrap_or(0) << 8
+    }
+
+    pub fn write_byte(&self, byte: u8) -> Result<(), Error> {
+        self.sm.put(self.frame(byte), true)
+    }
+
+    // Returns false instead of waiting when the TX FIFO is full.
+    pub fn try_write_byte(&self, byte: u8) -> Result<bool, Error> {
+        if self.sm.is_tx_fifo_full()? {
+            return Ok(false);
+        }
+        self.sm.put(self.frame(byte), false)?;
+        Ok(true)
+    }
+
+    pub fn write(&self, bytes: &[u8]) -> Result<(), Error> {
+        bytes.iter().try_for_each(|&byte| self.write_byte(byte))
+    }
+
+    // Everything has gone out once the FIFO's empty and the SM is back to stalling on its `pull`.
+    pub fn is_idle(&self) -> Result<bool, Error> {
+        let hw = self.sm.read_hw_state_machine()?;
+        let offset = self.program.as_ref().map(|program| program.offset() as u32);
+        Ok(self.sm.is_tx_fifo_empty()? && Some(hw.pc) == offset && hw.execctrl & PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS != 0)
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        while !self.is_idle()? {
+            std::thread::sleep(Duration::from_secs_f64(1.0 / self.baud_rate()));
+        }
+        Ok(())
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let source = match self.rs485 {
+            None        => tx_program(self.parity.bits()),
+            Some(rs485) => rs485_tx_program(self.parity.bits(), rs485.before, rs485.after),
+        };
+        let program = pio.load_program(&PioProgram::assemble(&source)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.pin, 1)?
+            .set_set_pins(self.pin, 1)?
+            .set_sideset(2, true, false)?
+            .set_sideset_pins(self.rs485.map_or(self.pin, |rs485| rs485.de))?
+            .set_out_shift(true, false, 32)?
+            .set_mov_status(PioMovStatus::TxLessThan, 1)?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pins_with_mask(1 << self.pin, 1 << self.pin)?; // Idle high
+        self.sm.set_consecutive_pindirs(self.pin, 1, true)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        if let Some(rs485) = self.rs485 {
+            self.sm.set_pins_with_mask(0, 1 << rs485.de)?;
+            self.sm.set_consecutive_pindirs(rs485.de, 1, true)?;
+            pio.pio_gpio_init(rs485.de as u16)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+pub struct Rx<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    clkdiv: ClkDiv,
+    parity: Parity,
+}
+
+impl<'a> Rx<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32, baud: u32) -> Result<Rx<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut rx = Rx { sm, program: None, pin, clkdiv: clkdiv(baud)?, parity: Parity::None };
+        rx.setup()?;
+        Ok(rx)
+    }
+
+    pub fn with_parity(mut self, parity: Parity) -> Result<Self, Error> {
+        self.parity = parity;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn parity(&self) -> Parity {
+        self.parity
+    }
+
+    pub fn baud_rate(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64) / CYCLES_PER_BIT
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // The frame is shifted in from the top, so it ends up in the top bits of the word.
+    fn decode(&self, word: u32) -> Received {
+        self.parity.check(word >> (31 - self.parity.bits()))
+    }
+
+    // Waits for the next frame and says how it went.
+    pub fn receive(&self) -> Result<Received, Error> {
+        Ok(self.decode(self.sm.get(true)?))
+    }
+
+    // Returns None instead of waiting when nothing's been received.
+    pub fn try_receive(&self) -> Result<Option<Received>, Error> {
+        if self.sm.is_rx_fifo_empty()? {
+            return Ok(None);
+        }
+        Ok(Some(self.decode(self.sm.get(false)?)))
+    }
+
+    // The next byte that arrived intact.
+    pub fn read_byte(&self) -> Result<u8, Error> {
+        loop {
+            if let Received::Byte(byte) = self.receive()? {
+                return Ok(byte);
+            }
+        }
+    }
+
+    // Returns None instead of waiting when no intact bytes have been received.
+    pub fn try_read_byte(&self) -> Result<Option<u8>, Error> {
+        while let Some(received) = self.try_receive()? {
+            if let Received::Byte(byte) = received {
+                return Ok(Some(byte));
+            }
+        }
+        Ok(None)
+    }
+
+    // Waits for at least one byte, then takes whatever else has already arrived, like std::io::Read::read().
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let Some((first, rest)) = buf.split_first_mut() else { return Ok(0) };
+        *first = self.read_byte()?;
+        for (n, byte) in rest.iter_mut().enumerate() {
+            match self.try_read_byte()? {
+                Some(b) => *byte = b,
+                None    => return Ok(n + 1),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&rx_program(self.parity.bits()))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.pin)?
+            .set_jmp_pin(self.pin)?
+            .set_in_shift(true, false, 32)?
+            .set_fifo_join(PioFifoJoin::Rx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_consecutive_pindirs(self.pin, 1, false)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+}
+
+pub struct Uart<'a> {
+    pub tx: Tx<'a>,
+    pub rx: Rx<'a>,
+}
+
+impl<'a> Uart<'a> {
+    pub fn new(tx_sm: StateMachine<'a>, rx_sm: StateMachine<'a>, tx_pin: u32, rx_pin: u32, baud: u32) -> Result<Uart<'a>, Error> {
+        Ok(Uart { rx: Rx::new(rx_sm, rx_pin, baud)?, tx: Tx::new(tx_sm, tx_pin, baud)? })
+    }
+
+    pub fn with_parity(self, parity: Parity) -> Result<Self, Error> {
+        Ok(Uart { rx: self.rx.with_parity(parity)?, tx: self.tx.with_parity(parity)? })
+    }
+
+    pub fn with_rs485(self, de: u32, before: Duration, after: Duration) -> Result<Self, Error> {
+        Ok(Uart { tx: self.tx.with_rs485(de, before, after)?, ..self })
+    }
+
+    pub fn split(self) -> (Tx<'a>, Rx<'a>) {
+        (self.tx, self.rx)
+    }
+}
+
+#[cfg(feature = "embedded-hal-nb")]
+mod hal_nb {
+    use embedded_hal_nb::{nb, serial::{ErrorKind, ErrorType, Read, Write}};
+
+    use super::{Rx, Tx, Uart};
+    use crate::Error;
+
+    impl embedded_hal_nb::serial::Error for Error {
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    impl ErrorType for Tx<'_> { type Error = Error; }
+    impl ErrorType for Rx<'_> { type Error = Error; }
+    impl ErrorType for Uart<'_> { type Error = Error; }
+
+    impl Write<u8> for Tx<'_> {
+        fn write(&mut self, word: u8) -> nb::Result<(), Error> {
+            match self.try_write_byte(word)? {
+                true  => Ok(()),
+                false => Err(nb::Error::WouldBlock),
+            }
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Error> {
+            match self.is_idle()? {
+                true  => Ok(()),
+                false => Err(nb::Error::WouldBlock),
+            }
+        }
+    }
+
+    impl Read<u8> for Rx<'_> {
+        fn read(&mut self) -> nb::Result<u8, Error> {
+            self.try_read_byte()?.ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    impl Write<u8> for Uart<'_> {
+        fn write(&mut self, word: u8) -> nb::Result<(), Error> { Write::write(&mut self.tx, word) }
+        fn flush(&mut self) -> nb::Result<(), Error>           { Write::flush(&mut self.tx) }
+    }
+
+    impl Read<u8> for Uart<'_> {
+        fn read(&mut self) -> nb::Result<u8, Error> { Read::read(&mut self.rx) }
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+mod io {
+    use embedded_io::{ErrorKind, ErrorType, Read, ReadReady, Write, WriteReady};
+
+    use super::{Rx, Tx, Uart};
+    use crate::Error;
+
+    impl embedded_io::Error for Error {
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    impl ErrorType for Tx<'_> { type Error = Error; }
+    impl ErrorType for Rx<'_> { type Error = Error; }
+    impl ErrorType for Uart<'_> { type Error = Error; }
+
+    // Waits for room for the first byte, then writes as many more as fit in the FIFO.
+    impl Write for Tx<'_> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            let Some((&first, rest)) = buf.split_first() else { return Ok(0) };
+            self.write_byte(first)?;
+            for (n, &byte) in rest.iter().enumerate() {
+                if !self.try_write_byte(byte)? {
+                    return Ok(n + 1);
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Tx::flush(self)
+        }
+    }
+
+    impl WriteReady for Tx<'_> {
+        fn write_ready(&mut self) -> Result<bool, Error> {
+            Ok(!self.sm.is_tx_fifo_full()?)
+        }
+    }
+
+    impl Read for Rx<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            Rx::read(self, buf)
+        }
+    }
+
+    impl ReadReady for Rx<'_> {
+        fn read_ready(&mut self) -> Result<bool, Error> {
+            Ok(!self.sm.is_rx_fifo_empty()?)
+        }
+    }
+
+    impl Write for Uart<'_> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> { Write::write(&mut self.tx, buf) }
+        fn flush(&mut self) -> Result<(), Error>                { Write::flush(&mut self.tx) }
+    }
+
+    impl Read for Uart<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> { Read::read(&mut self.rx, buf) }
+    }
+}
+
+#[cfg(feature = "embedded-io-async")]
+mod io_async {
+    use embedded_io_async::{Read, Write};
+
+    use super::{Rx, Tx, Uart};
+    use crate::{drivers::asynch::yield_now, Error};
+
+    impl Write for Tx<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            while self.sm.is_tx_fifo_full()? {
+                yield_now().await;
+            }
+            let mut n = 0;
+            while n < buf.len() && self.try_write_byte(buf[n])? {
+                n += 1;
+            }
+            Ok(n)
+        }
+
+        async fn flush(&mut self) -> Result<(), Error> {
+            while !self.is_idle()? {
+                yield_now().await;
+            }
+            Ok(())
+        }
+    }
+
+    impl Read for Rx<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            // Frames that didn't arrive intact get skipped, so there might be nothing to return even once there's
+            // something in the FIFO.
+            let mut n = 0;
+            while n < buf.len() {
+                match self.try_read_byte()? {
+                    Some(byte)    => { buf[n] = byte; n += 1 },
+                    None if n > 0 => break,
+                    None          => yield_now().await,
+                }
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Uart<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Error> { Write::write(&mut self.tx, buf).await }
+        async fn flush(&mut self) -> Result<(), Error>                { Write::flush(&mut self.tx).await }
+    }
+
+    impl Read for Uart<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> { Read::read(&mut self.rx, buf).await }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    #[test]
+    fn loopback() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        // Same pin both ways; Uart::new() sets up the Rx first so the Tx gets to leave it an output.
+        let uart = Uart::new(pio.sm_claim(0).unwrap(), pio.sm_claim(1).unwrap(), 6, 6, 1_000_000).unwrap();
+        uart.tx.write(b"Hi\x00\xff").unwrap();
+        let mut buf = [0; 4];
+        for byte in buf.iter_mut() {
+            *byte = uart.rx.read_byte().unwrap();
+        }
+        assert_eq!(&buf, b"Hi\x00\xff");
+        assert_eq!(uart.rx.try_read_byte().unwrap(), None);
+        backend.emulator().run(10_000);
+        assert!(uart.tx.is_idle().unwrap());
+        assert!(backend.emulator().pins() & 1 << 6 != 0);
+    }
+
+    #[test]
+    fn parity_loopback() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let uart = Uart::new(pio.sm_claim(0).unwrap(), pio.sm_claim(1).unwrap(), 6, 6, 1_000_000).unwrap()
+            .with_parity(Parity::Odd).unwrap();
+        uart.tx.write(b"\x00\x01\x03").unwrap();
+        for byte in [0x00, 0x01, 0x03] {
+            assert_eq!(uart.rx.receive().unwrap(), Received::Byte(byte));
+        }
+    }
+
+    // Drives the pin through some bits (start bit included) at 1 Mbaud, which is 200 clocks a bit, then leaves it idle.
+    fn send(backend: &EmulatorBackend, bits: &[u8]) {
+        let mut emu = backend.emulator();
+        for &bit in bits.iter().chain(&[1, 1]) {
+            emu.set_input(7, bit != 0);
+            emu.run(200);
+        }
+    }
+
+    #[test]
+    fn errors_and_breaks() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let rx = Rx::new(pio.sm_claim(0).unwrap(), 7, 1_000_000).unwrap().with_parity(Parity::Even).unwrap();
+        send(&backend, &[1, 1]);
+        // 0x41, LSB first, then the parity and stop bits.
+        send(&backend, &[0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 1]);
+        send(&backend, &[0, 1, 0, 0, 0, 0, 0, 1, 0, 1, 1]);
+        send(&backend, &[0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        send(&backend, &[0; 30]);
+        send(&backend, &[0, 0, 1, 0, 0, 0, 0, 1, 0, 0, 1]);
+        assert_eq!(rx.receive().unwrap(), Received::Byte(0x41));
+        assert_eq!(rx.receive().unwrap(), Received::ParityError(0x41));
+        assert_eq!(rx.receive().unwrap(), Received::FramingError(0x41));
+        assert_eq!(rx.receive().unwrap(), Received::Break);
+        assert_eq!(rx.receive().unwrap(), Received::Byte(0x42));
+        assert_eq!(rx.try_receive().unwrap(), None);
+
+        // The byte stream skips the frames that went wrong.
+        send(&backend, &[0, 1, 0, 0, 0, 0, 0, 1, 0, 1, 1]);
+        send(&backend, &[0; 30]);
+        send(&backend, &[0, 1, 1, 0, 0, 0, 0, 1, 0, 1, 1]);
+        let mut buf = [0; 4];
+        assert_eq!(rx.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 0x43);
+    }
+
+    #[test]
+    fn rs485_direction() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let uart = Uart::new(pio.sm_claim(0).unwrap(), pio.sm_claim(1).unwrap(), 6, 6, 1_000_000).unwrap()
+            .with_rs485(7, Duration::from_micros(2), Duration::from_micros(1)).unwrap();
+        uart.tx.write(b"AB").unwrap();
+        // Note when DE goes up and down, and the first and last edges on the line. A bit is 200 clocks.
+        let mut emu = backend.emulator();
+        let (mut de, mut line, mut last) = (Vec::new(), Vec::new(), emu.pins());
+        while de.len() < 2 {
+            emu.step();
+            let pins = emu.pins();
+            if (pins ^ last) & 1 << 7 != 0 { de.push(emu.cycle()) }
+            if (pins ^ last) & 1 << 6 != 0 { line.push(emu.cycle()) }
+            last = pins;
+        }
+        drop(emu);
+        assert_eq!(line.len(), 12, "DE stays up across both bytes"); // 6 edges each
+        // 'B' ends with a 0, so the last edge is the start of its stop bit.
+        let (first, end) = (line[0], *line.last().unwrap() + 200);
+        assert!(first - de[0] >= 400 && first - de[0] <= 475, "{de:?} {line:?}");
+        assert!(de[1] - end >= 200 && de[1] - end < 250, "{de:?} {line:?}");
+        assert_eq!(uart.rx.read_byte().unwrap(), b'A');
+        assert_eq!(uart.rx.read_byte().unwrap(), b'B');
+    }
+
+    #[test]
+    fn rs485_stop_bit() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let tx = Tx::new(pio.sm_claim(0).unwrap(), 6, 1_000_000).unwrap()
+            .with_rs485(7, Duration::ZERO, Duration::ZERO).unwrap();
+        tx.write(b"B").unwrap();
+        let mut emu = backend.emulator();
+        let (mut de, mut line, mut last) = (Vec::new(), Vec::new(), emu.pins());
+        while de.len() < 2 {
+            emu.step();
+            let pins = emu.pins();
+            if (pins ^ last) & 1 << 7 != 0 { de.push(emu.cycle()) }
+            if (pins ^ last) & 1 << 6 != 0 { line.push(emu.cycle()) }
+            last = pins;
+        }
+        // DE goes up with the start bit and, with no turnaround time after, down at the very end of the stop bit: a
+        // whole 200 clock bit after 'B''s last data bit (a 0) ends.
+        assert_eq!(de[0], line[0], "{de:?} {line:?}");
+        assert_eq!(de[1] - line.last().unwrap(), 200, "{de:?} {line:?}");
+    }
+
+    #[cfg(feature = "embedded-io-async")]
+    #[test]
+    fn async_loopback() {
+        use embedded_io_async::{Read, Write};
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut uart = Uart::new(pio.sm_claim(0).unwrap(), pio.sm_claim(1).unwrap(), 6, 6, 1_000_000).unwrap();
+        let run = || backend.emulator().run(10);
+        crate::drivers::asynch::tests::block_on(uart.write_all(b"async"), run).unwrap();
+        let mut buf = [0; 5];
+        crate::drivers::asynch::tests::block_on(uart.read_exact(&mut buf), run).unwrap();
+        assert_eq!(&buf, b"async");
+        crate::drivers::asynch::tests::block_on(Write::flush(&mut uart), run).unwrap();
+    }
+}
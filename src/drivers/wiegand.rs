@@ -0,0 +1,321 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Card readers and keypads with a Wiegand interface: two lines, normally high, and a short low pulse on D0 for each 0
+// bit or D1 for each 1. (They're 5 V, so go through a level shifter or divider.)
+//
+//     let mut reader = Wiegand::new(pio.sm_claim_unused()?, 20, 21)?;   // D0, D1
+//     loop {
+//         match reader.read() {
+//             Ok(card) => println!("{:?} {:?}", card.facility(), card.number()),
+//             Err(error) => println!("{error}"),
+//         }
+//     }
+//
+// There's no start or stop: a frame ends when the lines have been quiet for the timeout (25 ms unless with_timeout()
+// says otherwise), which the SM times, so a frame is never split or run together with the next however late read()
+// gets to it. Frames whose length isn't one of with_lengths() (26, 34 and 37 to start with) or whose parity is wrong
+// come back as errors. The parity is the usual kind: the first bit makes the first half even and the last bit makes the
+// second half odd.
+
+use std::{fmt, time::Duration};
+
+use crate::{pio_clock_hz, ClkDiv, LoadedProgram, PioFifoJoin, PioProgram, SmConfig, StateMachine};
+
+// The SM counts down from 2^TIMEOUT_BITS - 1 through a poll loop of CYCLES_PER_POLL, resetting after each bit.
+const TIMEOUT_BITS: u32 = 12;
+const CYCLES_PER_POLL: f64 = 5.0;
+
+// Each bit goes in as 10 for a 0 or 11 for a 1, 16 to a word with autopush, so a full word always has its top bit
+// set. At the end of a frame what's left is pushed, which doesn't (it might be nothing at all).
+fn program(d1: u32) -> String {
+    format!("
+    .program wiegand
+    .wrap_target
+        push
+        mov x, ~null
+    poll:
+        mov osr, pins
+        out y, 1
+        jmp !y zero
+        jmp pin quiet
+        jmp one
+    quiet:
+        jmp x-- poll
+    .wrap
+    zero:
+        wait 1 pin 0
+        set y, 2
+        jmp bit
+    one:
+        wait 1 gpio {d1}
+        set y, 3
+    bit:
+        in y, 2
+        mov osr, ~null
+        out x, {TIMEOUT_BITS}
+        jmp poll
+    ")
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Pio(crate::Error),
+    Length(u32), // A frame that isn't one of the lengths asked for
+    Parity(Card),
+}
+
+impl From<crate::Error> for Error {
+    fn from(error: crate::Error) -> Self {
+        Error::Pio(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Pio(error)   => write!(f, "{error}"),
+            Error::Length(len)  => write!(f, "Unexpected {len} bit frame"),
+            Error::Parity(card) => write!(f, "Parity error in {} bit frame {:#x}", card.len, card.bits),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Pio(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// A frame as it came in, the first bit in the most significant place, parity bits and all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Card {
+    pub bits: u64,
+    pub len: u32,
+}
+
+impl Card {
+    pub fn parity_ok(&self) -> bool {
+        if self.len < 2 {
+            return false;
+        }
+        let half = self.len.div_ceil(2); // The halves share the middle bit when there's an odd number
+        (self.bits >> (self.len - half)).count_ones().is_multiple_of(2) && (self.bits & mask(half)).count_ones() % 2 == 1
+    }
+
+    // Everything between the parity bits.
+    pub fn data(&self) -> u64 {
+        self.bits >> 1 & mask(self.len.saturating_sub(2))
+    }
+
+    // For the standard formats (H10301 26 bit, 34 bit and H10304 37 bit), the facility code and card number.
+    pub fn facility(&self) -> Option<u32> {
+        self.fields().map(|(facility, _)| facility)
+    }
+
+    pub fn number(&self) -> Option<u32> {
+        self.fields().map(|(_, number)| number)
+    }
+
+    fn fields(&self) -> Option<(u32, u32)> {
+        let number_bits = match self.len {
+            26 => 16,
+            34 => 16,
+            37 => 19,
+            _ => return None,
+        };
+        Some(((self.data() >> number_bits) as u32, (self.data() & mask(number_bits)) as u32))
+    }
+}
+
+fn mask(bits: u32) -> u64 {
+    if bits >= 64 { !0 } else { (1 << bits) - 1 }
+}
+
+pub struct Wiegand<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    d0: u32,
+    d1: u32,
+    clkdiv: ClkDiv,
+    lengths: Vec<u32>,
+    frame: Card, // The one coming in
+}
+
+impl<'a> Wiegand<'a> {
+    pub fn new(sm: StateMachine<'a>, d0: u32, d1: u32) -> Result<Wiegand<'a>, Error> {
+        for pin in [d0, d1] {
+            sm.pio().check_gpio(pin as u16)?;
+        }
+        let mut reader = Wiegand { sm, program: None, d0, d1, clkdiv: clkdiv(Duration::from_millis(25))?,
+                                   lengths: vec![26, 34, 37], frame: Card { bits: 0, len: 0 } };
+        reader.setup()?;
+        Ok(reader)
+    }
+
+    // How long the lines have to be quiet to end a frame: longer than the reader ever leaves between bits (a few
+    // ms at most, usually), and shorter than between cards. 1 to 50 ms.
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self, Error> {
+        if !(Duration::from_millis(1)..=Duration::from_millis(50)).contains(&timeout) {
+            Err(crate::Error::ParamErr { param: "timeout", should_be: "1 to 50 ms".to_string() })?;
+        }
+        self.clkdiv = clkdiv(timeout)?;
+        self.setup()?;
+        Ok(self)
+    }
+
+    // The frame lengths to accept, up to 64 bits.
+    pub fn with_lengths(mut self, lengths: &[u32]) -> Result<Self, Error> {
+        if lengths.iter().any(|len| !(2..=64).contains(len)) {
+            Err(crate::Error::ParamErr { param: "lengths", should_be: "2 to 64 bits".to_string() })?;
+        }
+        self.lengths = lengths.to_vec();
+        Ok(self)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        let hz = self.clkdiv.actual_frequency(pio_clock_hz() as f64);
+        Duration::from_secs_f64(CYCLES_PER_POLL * (1 << TIMEOUT_BITS) as f64 / hz)
+    }
+
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    // Waits for the next card.
+    pub fn read(&mut self) -> Result<Card, Error> {
+        loop {
+            if let Some(card) = self.take(true)? {
+                return card;
+            }
+        }
+    }
+
+    pub fn try_read(&mut self) -> Result<Option<Card>, Error> {
+        while !self.sm.is_rx_fifo_empty()? {
+            if let Some(card) = self.take(false)? {
+                return card.map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    // Takes a word from the FIFO, returning the frame if that finished one (and it wasn't empty).
+    fn take(&mut self, blocking: bool) -> Result<Option<Result<Card, Error>>, Error> {
+        let word = self.sm.get(blocking)?;
+        let symbols = if word >> 31 != 0 { 16 } else { (32 - word.leading_zeros()).div_ceil(2) };
+        for n in (0..symbols).rev() {
+            self.frame.bits = self.frame.bits << 1 | (word >> (n * 2) & 1) as u64;
+            self.frame.len += 1;
+        }
+        if symbols == 16 {
+            return Ok(None);
+        }
+        let card = std::mem::replace(&mut self.frame, Card { bits: 0, len: 0 });
+        Ok(match card.len {
+            0                                     => None,
+            len if !self.lengths.contains(&len)   => Some(Err(Error::Length(len))),
+            _ if !card.parity_ok()                => Some(Err(Error::Parity(card))),
+            _                                     => Some(Ok(card)),
+        })
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&program(self.d1))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_in_pins(self.d0)?
+            .set_jmp_pin(self.d1)?
+            .set_in_shift(false, true, 32)?
+            .set_out_shift(true, false, 32)?
+            .set_fifo_join(PioFifoJoin::Rx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_pindirs_with_mask(0, 1 << self.d0 | 1 << self.d1)?;
+        for pin in [self.d0, self.d1] {
+            pio.pio_gpio_init(pin as u16)?;
+            pio.set_pulls(pin as u16, true, false)?;
+        }
+        self.sm.init(program.offset(), &config)?;
+        self.sm.clear_fifos()?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        self.frame = Card { bits: 0, len: 0 };
+        Ok(())
+    }
+}
+
+fn clkdiv(timeout: Duration) -> Result<ClkDiv, crate::Error> {
+    ClkDiv::for_frequency(pio_clock_hz() as f64, CYCLES_PER_POLL * (1 << TIMEOUT_BITS) as f64 / timeout.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip, Rp1PIO};
+
+    const D0: u32 = 20;
+    const D1: u32 = 21;
+    const US: u64 = 200; // Cycles
+
+    // Sends each frame as 20 µs pulses 200 µs apart, with 3 ms between frames.
+    #[derive(Debug, Default)]
+    struct Reader(Vec<Vec<bool>>);
+
+    impl Peripheral for Reader {
+        fn step(&mut self, _pins: u32, now: u64) -> (u32, u32) {
+            let mut at = 100 * US;
+            for frame in &self.0 {
+                let (bit, into) = (((now - at.min(now)) / (200 * US)) as usize, (now - at.min(now)) % (200 * US));
+                if now >= at && bit < frame.len() {
+                    let low = into < 20 * US;
+                    let (d0, d1) = (!low || frame[bit], !low || !frame[bit]);
+                    return ((d0 as u32) << D0 | (d1 as u32) << D1, 3 << D0);
+                }
+                at += frame.len() as u64 * 200 * US + 3000 * US;
+            }
+            (3 << D0, 3 << D0)
+        }
+    }
+
+    fn bits(value: u64, len: u32) -> Vec<bool> {
+        (0..len).rev().map(|n| value >> n & 1 != 0).collect()
+    }
+
+    #[test]
+    fn cards() {
+        // Facility 123, card 45678: 1 01111011 1011001001101110 1
+        let h10301 = Card { bits: 1 << 25 | 123 << 17 | 45678 << 1 | 1, len: 26 };
+        assert!(h10301.parity_ok());
+        assert_eq!((h10301.facility(), h10301.number()), (Some(123), Some(45678)));
+        let long = Card { bits: 1 << 33 | 0xdead_beef << 1, len: 34 };
+        assert!(long.parity_ok());
+        assert_eq!((long.facility(), long.number()), (Some(0xdead), Some(0xbeef)));
+        assert!(!Card { bits: long.bits ^ 1, ..long }.parity_ok());
+
+        let frames = [bits(h10301.bits, 26), bits(long.bits, 34), bits(h10301.bits ^ 1 << 3, 26), bits(5, 3)];
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(10_000_000);
+        backend.emulator().attach(Arc::new(Mutex::new(Reader(frames.to_vec()))));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut reader = Wiegand::new(pio.sm_claim(0).unwrap(), D0, D1).unwrap()
+            .with_timeout(Duration::from_millis(1)).unwrap();
+        assert!((reader.timeout().as_secs_f64() - 0.001).abs() < 1e-6);
+
+        assert_eq!(reader.try_read().unwrap(), None);
+        assert_eq!(reader.read().unwrap(), h10301);
+        assert_eq!(reader.read().unwrap(), long);
+        assert!(matches!(reader.read(), Err(Error::Parity(Card { len: 26, .. }))));
+        assert!(matches!(reader.read(), Err(Error::Length(3))));
+        assert!(Wiegand::new(pio.sm_claim(1).unwrap(), D0, D1).unwrap().with_lengths(&[65]).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// WS2812 ("NeoPixel") strips and their relatives, using the ws2812 program from the pico-examples:
+//
+//     let mut strip = Ws2812::new(pio.sm_claim_unused()?, 18)?;
+//     strip.write(&[RGB8::new(255, 0, 0), RGB8::new(0, 255, 0), RGB8::new(0, 0, 255)])?;
+//
+// The defaults are for a WS2812B: GRB at 800 kHz. Other parts differ in their timing and in what order they want the
+// channels, and the RGBW ones take a fourth byte for the white LED:
+//
+//     let mut strip = Ws2812::new(pio.sm_claim_unused()?, 18)?
+//         .with_timing(Timing::SK6812)?
+//         .with_color_order(ColorOrder::Grbw)?;
+//     strip.write_rgbw(&[RGBW8::new(0, 0, 0, 255)])?;
+//
+// A strip latches what it's been sent once the line has been low for a while (50 µs on the originals, 280 µs on the
+// newer WS2812Bs), so write() returns as soon as the frame is queued and the next write() waits out the rest of the
+// frame plus the reset time before starting. Frames longer than the FIFO are streamed with sm_xfer_data().
+
+use std::time::{Duration, Instant};
+
+pub use super::led::{RGB8, RGBW8};
+
+use super::led::{self, LedStrip};
+use crate::{Error, LoadedProgram, PioProgram, SmConfig, StateMachine};
+
+// The order the channels go down the wire. The `w` orders are for RGBW parts; on the others white is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorOrder {
+    Rgb, Rbg, Grb, Gbr, Brg, Bgr,
+    Rgbw, Rbgw, Grbw, Gbrw, Brgw, Bgrw,
+}
+
+impl ColorOrder {
+    pub fn has_white(self) -> bool {
+        use ColorOrder::*;
+        matches!(self, Rgbw | Rbgw | Grbw | Gbrw | Brgw | Bgrw)
+    }
+
+    pub fn bits(self) -> u32 {
+        if self.has_white() { 32 } else { 24 }
+    }
+
+    // The pixel as it's shifted out, MSB first from the top of the word.
+    fn word(self, p: RGBW8) -> u32 {
+        use ColorOrder::*;
+        let [a, b, c] = match self {
+            Rgb | Rgbw => [p.r, p.g, p.b],
+            Rbg | Rbgw => [p.r, p.b, p.g],
+            Grb | Grbw => [p.g, p.r, p.b],
+            Gbr | Gbrw => [p.g, p.b, p.r],
+            Brg | Brgw => [p.b, p.r, p.g],
+            Bgr | Bgrw => [p.b, p.g, p.r],
+        };
+        u32::from_be_bytes([a, b, c, if self.has_white() { p.w } else { 0 }])
+    }
+}
+
+// Each bit takes t1 + t2 + t3 SM cycles: high for t1 then low for t2 + t3 for a 0, high for t1 + t2 then low for t3
+// for a 1. `reset` is how long the line has to stay low for the strip to latch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    pub bit_rate: f64,
+    pub t1: u8,
+    pub t2: u8,
+    pub t3: u8,
+    pub reset: Duration,
+}
+
+impl Timing {
+    // 0.25/1.0 µs and 0.875/0.375 µs.
+    pub const WS2812: Timing = Timing { bit_rate: 800_000.0, t1: 2, t2: 5, t3: 3, reset: Duration::from_micros(300) };
+    // The same shape at half the speed.
+    pub const WS2811: Timing = Timing { bit_rate: 400_000.0, t1: 2, t2: 5, t3: 3, reset: Duration::from_micros(300) };
+    // Wants a 1's high time shorter than the WS2812's: 0.25/1.0 µs and 0.625/0.625 µs.
+    pub const SK6812: Timing = Timing { bit_rate: 800_000.0, t1: 2, t2: 3, t3: 5, reset: Duration::from_micros(100) };
+    // Wants a 0's high time of at least 0.3 µs: 0.375/0.875 µs and 0.875/0.375 µs.
+    pub const WS2813: Timing = Timing { bit_rate: 800_000.0, t1: 3, t2: 4, t3: 3, reset: Duration::from_micros(300) };
+
+    fn cycles_per_bit(&self) -> u32 {
+        self.t1 as u32 + self.t2 as u32 + self.t3 as u32
+    }
+}
+
+pub struct Ws2812<'a> {
+    sm: StateMachine<'a>,
+    program: Option<LoadedProgram<'a>>,
+    pin: u32,
+    timing: Timing,
+    order: ColorOrder,
+    latched_at: Instant,
+    words: Vec<u32>,
+}
+
+impl<'a> Ws2812<'a> {
+    pub fn new(sm: StateMachine<'a>, pin: u32) -> Result<Ws2812<'a>, Error> {
+        sm.pio().check_gpio(pin as u16)?;
+        let mut strip = Ws2812 { sm, program: None, pin, timing: Timing::WS2812, order: ColorOrder::Grb,
+                                 latched_at: Instant::now(), words: Vec::new() };
+        strip.setup()?;
+        Ok(strip)
+    }
+
+    pub fn with_timing(mut self, timing: Timing) -> Result<Self, Error> {
+        // With one side-set pin there are 4 bits left for the delay.
+        for (param, t) in [("t1", timing.t1), ("t2", timing.t2), ("t3", timing.t3)] {
+            if !(1..=16).contains(&t) {
+                Err(Error::ParamErr { param, should_be: "1..=16".to_string() })?;
+            }
+        }
+        self.timing = timing;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn with_color_order(mut self, order: ColorOrder) -> Result<Self, Error> {
+        self.order = order;
+        self.setup()?;
+        Ok(self)
+    }
+
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    pub fn color_order(&self) -> ColorOrder {
+        self.order
+    }
+
+    // Sends a frame, first waiting for the previous one to latch. On an RGBW strip white is left off.
+    pub fn write(&mut self, pixels: &[RGB8]) -> Result<(), Error> {
+        self.words.clear();
+        self.words.extend(pixels.iter().map(|&p| self.order.word(p.into())));
+        self.send()
+    }
+
+    pub fn write_rgbw(&mut self, pixels: &[RGBW8]) -> Result<(), Error> {
+        self.words.clear();
+        self.words.extend(pixels.iter().map(|&p| self.order.word(p)));
+        self.send()
+    }
+
+    // Waits until the last frame has been sent and latched.
+    pub fn flush(&self) -> Result<(), Error> {
+        std::thread::sleep(self.latched_at.saturating_duration_since(Instant::now()));
+        Ok(())
+    }
+
+    // Stops the SM, leaving the line low.
+    pub fn into_inner(mut self) -> Result<StateMachine<'a>, Error> {
+        self.flush()?;
+        self.sm.set_enabled(false)?;
+        self.program = None;
+        Ok(self.sm)
+    }
+
+    fn setup(&mut self) -> Result<(), Error> {
+        let Timing { t1, t2, t3, .. } = self.timing;
+        let source = format!("
+            .program ws2812
+            .side_set 1
+            .wrap_target
+            bitloop:
+                out x, 1       side 0 [{}]
+                jmp !x do_zero side 1 [{}]
+            do_one:
+                jmp  bitloop   side 1 [{}]
+            do_zero:
+                nop            side 0 [{}]
+            .wrap
+        ", t3 - 1, t1 - 1, t2 - 1, t2 - 1);
+        let pio = self.sm.pio();
+        self.flush()?;
+        self.sm.set_enabled(false)?;
+        self.program = None; // Make room first
+        let program = pio.load_program(&PioProgram::assemble(&source)?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::for_ws2812(self.pin)?
+            .set_out_shift(false, true, self.order.bits())?
+            .set_clkdiv_hz(self.timing.bit_rate * self.timing.cycles_per_bit() as f64)?
+            .set_wrap(wrap_target, wrap)?;
+        pio.pio_gpio_init(self.pin as u16)?;
+        self.sm.set_pins_with_mask(0, 1 << self.pin)?;
+        self.sm.set_pindirs_with_mask(1 << self.pin, 1 << self.pin)?;
+        self.sm.init(program.offset(), &config)?;
+        self.sm.set_enabled(true)?;
+        self.program = Some(program);
+        Ok(())
+    }
+
+    fn send(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        if self.words.is_empty() {
+            return Ok(());
+        }
+        led::queue(&self.sm, &self.words)?;
+        // The first word went out before we finished queueing and the SM never stalls mid-frame, so the frame is done
+        // by now plus the time the whole thing takes.
+        let frame = Duration::from_secs_f64(self.words.len() as f64 * self.order.bits() as f64 / self.timing.bit_rate);
+        self.latched_at = Instant::now() + frame + self.timing.reset;
+        Ok(())
+    }
+}
+
+impl LedStrip for Ws2812<'_> {
+    fn write(&mut self, pixels: &[RGB8]) -> Result<(), Error> {
+        Ws2812::write(self, pixels)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ws2812::flush(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, pio_clock_hz, Chip, Rp1PIO};
+
+    // Reads bytes back off the pin by how long each high pulse is.
+    fn decode(backend: &EmulatorBackend, pin: u32, timing: Timing, bytes: usize) -> Vec<u8> {
+        let clocks_per_cycle = pio_clock_hz() as f64 / (timing.bit_rate * timing.cycles_per_bit() as f64);
+        let threshold = (timing.t1 as f64 + timing.t2 as f64 / 2.0) * clocks_per_cycle;
+        let mut emu = backend.emulator();
+        let mut bits = Vec::new();
+        let (mut high, mut low) = (0, 0);
+        while bits.len() < bytes * 8 && low < 100_000 {
+            emu.step();
+            if emu.pins() & 1 << pin != 0 {
+                high += 1;
+                low = 0;
+            } else {
+                if high > 0 {
+                    bits.push(high as f64 > threshold);
+                }
+                high = 0;
+                low += 1;
+            }
+        }
+        bits.chunks(8).map(|byte| byte.iter().fold(0, |b, &bit| b << 1 | bit as u8)).collect()
+    }
+
+    fn emulated() -> (EmulatorBackend, Rp1PIO) {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        (backend, pio)
+    }
+
+    #[test]
+    fn grb_msb_first() {
+        let (backend, pio) = emulated();
+        let mut strip = Ws2812::new(pio.sm_claim(0).unwrap(), 18).unwrap();
+        strip.write(&[RGB8::new(0x12, 0x34, 0x56), RGB8::new(0xff, 0, 0x01)]).unwrap();
+        assert_eq!(decode(&backend, 18, Timing::WS2812, 6), [0x34, 0x12, 0x56, 0, 0xff, 0x01]);
+    }
+
+    #[test]
+    fn rgbw_and_slow_strips() {
+        let (backend, pio) = emulated();
+        let mut strip = Ws2812::new(pio.sm_claim(0).unwrap(), 18).unwrap()
+            .with_timing(Timing::SK6812).unwrap()
+            .with_color_order(ColorOrder::Grbw).unwrap();
+        strip.write_rgbw(&[RGBW8::new(1, 2, 3, 4)]).unwrap();
+        assert_eq!(decode(&backend, 18, Timing::SK6812, 4), [2, 1, 3, 4]);
+
+        let mut strip = strip.with_timing(Timing::WS2811).unwrap().with_color_order(ColorOrder::Rgb).unwrap();
+        strip.write_rgbw(&[RGBW8::new(0xa5, 0x0f, 0xf0, 0xff)]).unwrap();
+        assert_eq!(decode(&backend, 18, Timing::WS2811, 3), [0xa5, 0x0f, 0xf0]);
+
+        assert!(strip.with_timing(Timing { t1: 0, ..Timing::WS2812 }).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A backend that says what it would do instead of doing it, for trying out scripts away from the machinery they
+// control:
+//
+//     let pio = Rp1PIO::with_backend(Box::new(DryRun::new()), Chip::new());
+//
+// Every ioctl is logged (to stderr by default) with its decoded args and result:
+//
+//     dry-run: SM_PUT SmPutArgs { sm: 0, blocking: 1, rsvd: 0, data: 4660 } -> Ok(0)
+//
+// Rp1PIO's parameter checks all still run, and the ioctls go to a MockPio so claiming, program placement and so on
+// succeed or fail the way they would on a real PIO block. Nothing touches the hardware.
+
+use std::{ffi::c_void, io::Write, path::Path, sync::Mutex};
+
+use libc::c_ulong;
+
+use crate::{lock, mock::MockPio, Error, PioBackend};
+use crate::ioctl::*;
+
+pub struct DryRun {
+    mock: MockPio,
+    log: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Default for DryRun {
+    fn default() -> Self {
+        DryRun::new()
+    }
+}
+
+impl DryRun {
+    pub fn new() -> DryRun {
+        DryRun::with_log(Box::new(std::io::stderr()))
+    }
+
+    pub fn with_log(log: Box<dyn Write + Send>) -> DryRun {
+        DryRun { mock: MockPio::new(), log: Mutex::new(log) }
+    }
+
+    // The pretend PIO block, to check what the script left it looking like.
+    pub fn mock(&self) -> &MockPio {
+        &self.mock
+    }
+}
+
+impl PioBackend for DryRun {
+    unsafe fn ioctl(&self, request: c_ulong, args: *mut c_void) -> Result<u32, Error> {
+        let described = unsafe { describe_args(request, args) };
+        let result = unsafe { self.mock.ioctl(request, args) };
+        let outcome = match &result {
+            Ok(r)  => format!("Ok({r})"),
+            Err(e) => format!("Err({e})"),
+        };
+        let _ = writeln!(lock(&self.log), "dry-run: {} {described} -> {outcome}", request_name(request));
+        result
+    }
+
+    fn devname(&self) -> &Path {
+        Path::new("dry-run")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Chip, PioProgram, Rp1PIO};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { lock(&self.0).write(buf) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn logs_decoded_ioctls() {
+        let log = SharedBuf::default();
+        let pio = Rp1PIO::with_backend(Box::new(DryRun::with_log(Box::new(log.clone()))), Chip::new());
+        pio.add_program(&PioProgram::new(&[0xe001, 0x0000], None)).unwrap();
+        let sm = pio.sm_claim(2).unwrap();
+        sm.put(0x1234, false).unwrap();
+        assert!(pio.sm_claim(2).is_err());
+        let log = String::from_utf8(lock(&log.0).clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines, [
+            "dry-run: ADD_PROGRAM AddProgramArgs { num_instrs: 2, origin: 65535, instrs: [e001, 0000] } -> Ok(30)",
+            "dry-run: SM_CLAIM SmClaimArgs { mask: 4 } -> Ok(0)",
+            "dry-run: SM_PUT SmPutArgs { sm: 2, blocking: 0, rsvd: 0, data: 4660 } -> Ok(0)",
+            "dry-run: SM_CLAIM SmClaimArgs { mask: 4 } -> Err(IOError: Device or resource busy (os error 16))",
+        ]);
+    }
+}
@@ -0,0 +1,1147 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A software PIO block, for running programs without the hardware:
+//
+//     let mut emu = Emulator::new(&Chip::new());
+//     emu.load(&program, 0)?;
+//     emu.init(0, 0, &config)?;
+//     emu.set_enabled(0, true);
+//     emu.put(0, 0x1234);
+//     emu.run(100);
+//     assert_eq!(emu.pins() & 1 << 4, 0);
+//
+// Timing follows the RP2040 datasheet's description of the PIO: every instruction takes one SM clock plus its delay,
+// stalls repeat the instruction (without its delay) until they clear, side-set happens on the first cycle of an
+// instruction and the fractional clock divider gates which system clocks the SM runs on. step() is one system clock.
+//
+// EmulatorBackend puts an Emulator behind the PioBackend interface so Rp1PIO based code can drive it directly.
+//
+// To see what a program actually did, trace it and open the result in GTKWave or PulseView:
+//
+//     emu.start_trace(0b11 << 4);   // GPIOs 4 and 5
+//     emu.run(10_000);
+//     emu.write_vcd(File::create("trace.vcd")?, 200_000_000)?;
+//
+// Whatever's on the other end of the pins (a sensor, a memory card) can be modelled as a Peripheral and attached. It
+// sees the pins every system clock and drives the inputs it's connected to.
+
+use std::{collections::VecDeque, ffi::c_void, io::Write, path::Path, sync::{Arc, Mutex, MutexGuard}};
+
+use libc::c_ulong;
+
+use crate::instruction::*;
+use crate::{gpio::Override, lock, proc_pio::*, Chip, ClkDiv, Error, PioBackend, PioFifoJoin, PioMovStatus, PioProgram, SmConfig};
+use crate::ioctl::*;
+use crate::vcd::VcdWriter;
+
+#[derive(Debug, Clone, Default)]
+pub struct EmulatedSm {
+    pub config: SmConfig,
+    pub enabled: bool,
+    pub pc: u8,
+    pub x: u32,
+    pub y: u32,
+    pub isr: u32,
+    pub isr_count: u32,
+    pub osr: u32,
+    pub osr_count: u32,
+    pub stalled: bool,
+    tx: VecDeque<u32>,
+    rx: VecDeque<u32>,
+    delay: u8,             // Cycles of delay left on the current instruction
+    exec: Option<u16>,     // An instruction from `exec`, `out exec` or `mov exec`, to run instead of fetching
+    irq_wait: Option<u8>,  // `irq wait` set this flag and is waiting for it to clear
+    clk_acc: u32,          // Clock divider accumulator, in 1/256ths of a system clock
+    side_set_done: bool,   // Side-set is only done on the first cycle of a stalled instruction
+}
+
+impl EmulatedSm {
+    pub fn tx_level(&self) -> usize { self.tx.len() }
+    pub fn rx_level(&self) -> usize { self.rx.len() }
+}
+
+// Something outside the PIO wired to the pins. step() gets every pin's level after each system clock and returns the
+// levels it's driving and which pins it's driving them on.
+pub trait Peripheral: std::fmt::Debug + Send {
+    fn step(&mut self, pins: u32, cycle: u64) -> (u32, u32);
+}
+
+#[derive(Debug, Clone)]
+pub struct Emulator {
+    instr_mem: Vec<u16>,
+    sms: Vec<EmulatedSm>,
+    fifo_depth: usize,
+    irq: u8,
+    levels: u32,   // Driven by the SMs
+    pindirs: u32,  // Ditto
+    oe_invert: u32, // The pads' output enable overrides
+    oe_low: u32,
+    oe_high: u32,
+    inputs: u32,   // What's on pins that aren't outputs
+    cycle: u64,
+    trace: Option<Trace>,
+    peripherals: Vec<Arc<Mutex<dyn Peripheral>>>, // Shared with any clones
+}
+
+// What changed and when, for write_vcd().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Event {
+    Pins(u32),
+    Pc(u8, u8),
+    TxLevel(u8, u8),
+    RxLevel(u8, u8),
+    Tx(u8, u32),    // A word put into the TX FIFO
+    Rx(u8, u32),    // A word the SM pushed to the RX FIFO
+}
+
+#[derive(Debug, Clone)]
+struct Trace {
+    pins: u32,     // Which pins to trace
+    start: u64,
+    last: Vec<Event>,
+    events: Vec<(u64, Event)>,
+}
+
+enum Outcome {
+    Next,          // Carry on to the next instruction (with wrap)
+    Jump(u8),
+    Stall,
+    Exec(u16),     // `out exec`/`mov exec`: run this next, then carry on after the `out`/`mov`
+}
+
+fn mask(bits: u32) -> u32 {
+    if bits >= 32 { !0 } else { (1 << bits) - 1 }
+}
+
+impl Emulator {
+    pub fn new(chip: &Chip) -> Emulator {
+        Emulator {
+            instr_mem: vec![0; chip.instr_count as usize],
+            sms: vec![EmulatedSm { config: SmConfig::default(), ..EmulatedSm::default() }; chip.sm_count as usize],
+            fifo_depth: chip.fifo_depth as usize,
+            irq: 0,
+            levels: 0,
+            pindirs: 0,
+            oe_invert: 0,
+            oe_low: 0,
+            oe_high: 0,
+            inputs: 0,
+            cycle: 0,
+            trace: None,
+            peripherals: Vec::new(),
+        }
+    }
+
+    fn check_sm(&self, sm: u16) -> Result<usize, Error> {
+        if (sm as usize) < self.sms.len() { Ok(sm as usize) }
+        else { Err(Error::BadSM { sm, max: self.sms.len() as u16 }) }
+    }
+
+    // Copies `program` into instruction memory at `offset`, relocating its jmps like the kernel does.
+    pub fn load(&mut self, program: &PioProgram, offset: u16) -> Result<(), Error> {
+        if offset as usize + program.len() > self.instr_mem.len() {
+            Err(Error::TooManyInstructions { instructions: program.len(), max: self.instr_mem.len() as u16 - offset.min(self.instr_mem.len() as u16) })?;
+        }
+        for (i, &instr) in program.instructions().iter().enumerate() {
+            self.instr_mem[offset as usize + i] = if instr >> 13 == 0 { instr & !0x1f | (instr + offset) & 0x1f } else { instr };
+        }
+        Ok(())
+    }
+
+    pub fn instruction_memory(&self) -> &[u16] {
+        &self.instr_mem
+    }
+
+    pub fn write_instruction(&mut self, addr: u8, instr: u16) {
+        self.instr_mem[addr as usize] = instr;
+    }
+
+    // Like StateMachine::init(): disables the SM, applies `config`, clears its FIFOs and state and points it at `pc`.
+    pub fn init(&mut self, sm: u16, pc: u8, config: &SmConfig) -> Result<(), Error> {
+        let sm = self.check_sm(sm)?;
+        self.sms[sm] = EmulatedSm { config: *config, pc, osr_count: 32, ..EmulatedSm::default() };
+        Ok(())
+    }
+
+    pub fn set_config(&mut self, sm: u16, config: &SmConfig) -> Result<(), Error> {
+        let sm = self.check_sm(sm)?;
+        self.sms[sm].config = *config;
+        Ok(())
+    }
+
+    pub fn set_enabled(&mut self, sm: u16, enabled: bool) -> Result<(), Error> {
+        let sm = self.check_sm(sm)?;
+        self.sms[sm].enabled = enabled;
+        Ok(())
+    }
+
+    pub fn sm(&self, sm: u16) -> &EmulatedSm {
+        &self.sms[sm as usize]
+    }
+
+    pub fn sm_mut(&mut self, sm: u16) -> &mut EmulatedSm {
+        &mut self.sms[sm as usize]
+    }
+
+    pub fn sm_count(&self) -> u16 {
+        self.sms.len() as u16
+    }
+
+    // Runs `instr` on the SM's next cycle, whether or not it's enabled.
+    pub fn exec(&mut self, sm: u16, instr: u16) -> Result<(), Error> {
+        let sm = self.check_sm(sm)?;
+        self.sms[sm].exec = Some(instr);
+        if !self.sms[sm].enabled {
+            self.execute(sm);
+        }
+        Ok(())
+    }
+
+    fn tx_depth(&self, sm: usize) -> usize {
+        match self.sms[sm].config.get_fifo_join() { Ok(PioFifoJoin::Tx) => self.fifo_depth * 2, Ok(PioFifoJoin::Rx) => 0, _ => self.fifo_depth }
+    }
+
+    fn rx_depth(&self, sm: usize) -> usize {
+        match self.sms[sm].config.get_fifo_join() { Ok(PioFifoJoin::Rx) => self.fifo_depth * 2, Ok(PioFifoJoin::Tx) => 0, _ => self.fifo_depth }
+    }
+
+    // Returns false (and drops `word`) if the TX FIFO is full.
+    pub fn put(&mut self, sm: u16, word: u32) -> bool {
+        let sm = sm as usize;
+        if self.sms[sm].tx.len() >= self.tx_depth(sm) {
+            return false;
+        }
+        self.sms[sm].tx.push_back(word);
+        self.record(Event::Tx(sm as u8, word));
+        true
+    }
+
+    pub fn get(&mut self, sm: u16) -> Option<u32> {
+        self.sms[sm as usize].rx.pop_front()
+    }
+
+    pub fn clear_fifos(&mut self, sm: u16) {
+        let sm = &mut self.sms[sm as usize];
+        sm.tx.clear();
+        sm.rx.clear();
+    }
+
+    pub fn is_tx_full(&self, sm: u16) -> bool  { self.sms[sm as usize].tx.len() >= self.tx_depth(sm as usize) }
+    pub fn is_rx_full(&self, sm: u16) -> bool  { self.sms[sm as usize].rx.len() >= self.rx_depth(sm as usize) }
+
+    // Drives the pins in `mask` that aren't outputs.
+    pub fn set_inputs(&mut self, levels: u32, mask: u32) {
+        self.inputs = self.inputs & !mask | levels & mask;
+    }
+
+    pub fn set_input(&mut self, pin: u32, level: bool) {
+        self.set_inputs((level as u32) << pin, 1 << pin);
+    }
+
+    // The level of every pin: the SMs' outputs where they're driving, the inputs elsewhere.
+    pub fn pins(&self) -> u32 {
+        let pindirs = self.pindirs();
+        self.levels & pindirs | self.inputs & !pindirs
+    }
+
+    // Which pins are outputs, after the output enable overrides.
+    pub fn pindirs(&self) -> u32 {
+        (self.pindirs ^ self.oe_invert) & !self.oe_low | self.oe_high
+    }
+
+    // What gpio_set_oeover() does. The other overrides and the pulls aren't modelled.
+    pub fn set_oeover(&mut self, pin: u32, over: Override) {
+        let bit = 1 << pin;
+        let (invert, low, high) = match over {
+            Override::Normal => (0, 0, 0),
+            Override::Invert => (bit, 0, 0),
+            Override::Low    => (0, bit, 0),
+            Override::High   => (0, 0, bit),
+        };
+        self.oe_invert = self.oe_invert & !bit | invert;
+        self.oe_low = self.oe_low & !bit | low;
+        self.oe_high = self.oe_high & !bit | high;
+    }
+
+    // What set_pins()/set_pindirs() do: poke the outputs directly.
+    pub fn set_pins(&mut self, levels: u32, mask: u32) {
+        self.levels = self.levels & !mask | levels & mask;
+    }
+
+    pub fn set_pindirs(&mut self, dirs: u32, mask: u32) {
+        self.pindirs = self.pindirs & !mask | dirs & mask;
+    }
+
+    pub fn irq_flags(&self) -> u8 {
+        self.irq
+    }
+
+    pub fn set_irq_flags(&mut self, flags: u8) {
+        self.irq = flags;
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    // One system clock.
+    pub fn step(&mut self) {
+        for sm in 0..self.sms.len() {
+            if !self.sms[sm].enabled { continue }
+            let div = self.sms[sm].config.get_clkdiv();
+            let divisor = match div.div { 0 => 65536 * 256, d => d as u32 * 256 + div.frac as u32 };
+            let state = &mut self.sms[sm];
+            state.clk_acc += 256;
+            if state.clk_acc < divisor { continue }
+            state.clk_acc -= divisor;
+            self.execute(sm);
+        }
+        self.cycle += 1;
+        for peripheral in &self.peripherals {
+            let (levels, mask) = lock(peripheral).step(self.pins(), self.cycle);
+            self.inputs = self.inputs & !mask | levels & mask;
+        }
+        self.sample();
+    }
+
+    // Keep a clone of the Arc to look at the peripheral's state afterwards.
+    pub fn attach(&mut self, peripheral: Arc<Mutex<dyn Peripheral>>) {
+        self.peripherals.push(peripheral);
+    }
+
+    pub fn run(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.step();
+        }
+    }
+
+    // Steps until `done` says so, giving up after `max_cycles`. Returns the number of cycles it took.
+    pub fn run_until(&mut self, max_cycles: u64, mut done: impl FnMut(&Emulator) -> bool) -> Option<u64> {
+        for cycles in 0..max_cycles {
+            if done(self) {
+                return Some(cycles);
+            }
+            self.step();
+        }
+        done(self).then_some(max_cycles)
+    }
+
+    // Starts recording `pins`, the SMs' PCs and their FIFOs, throwing away any previous trace.
+    pub fn start_trace(&mut self, pins: u32) {
+        self.trace = Some(Trace { pins, start: self.cycle, last: Vec::new(), events: Vec::new() });
+        self.sample();
+    }
+
+    pub fn stop_trace(&mut self) {
+        self.trace = None;
+    }
+
+    fn snapshot(&self, pins: u32) -> Vec<Event> {
+        let mut snapshot = vec![Event::Pins(self.pins() & pins)];
+        for (i, sm) in self.sms.iter().enumerate() {
+            snapshot.extend([Event::Pc(i as u8, sm.pc), Event::TxLevel(i as u8, sm.tx.len() as u8), Event::RxLevel(i as u8, sm.rx.len() as u8)]);
+        }
+        snapshot
+    }
+
+    fn sample(&mut self) {
+        let Some(pins) = self.trace.as_ref().map(|trace| trace.pins) else { return };
+        let snapshot = self.snapshot(pins);
+        let cycle = self.cycle;
+        let trace = self.trace.as_mut().expect("checked above");
+        for (i, &event) in snapshot.iter().enumerate() {
+            if trace.last.get(i) != Some(&event) {
+                trace.events.push((cycle, event));
+            }
+        }
+        trace.last = snapshot;
+    }
+
+    fn record(&mut self, event: Event) {
+        let cycle = self.cycle;
+        if let Some(trace) = &mut self.trace {
+            trace.events.push((cycle, event));
+        }
+    }
+
+    // Writes out the trace so far. `clock_hz` is the system clock, for turning cycles into time.
+    pub fn write_vcd(&self, out: impl Write, clock_hz: u32) -> Result<(), Error> {
+        let Some(trace) = &self.trace else { Err(Error::ParamErr { param: "trace", should_be: "started with start_trace()".to_string() })? };
+        let mut vcd = VcdWriter::new(out, "1 ns");
+        let pins: Vec<(u32, _)> = (0..32).filter(|pin| trace.pins & 1 << pin != 0)
+            .map(|pin| Ok((pin, vcd.wire("pins", &format!("gpio{pin}"), 1)?)))
+            .collect::<Result<_, Error>>()?;
+        let mut sms = Vec::new();
+        for sm in 0..self.sms.len() {
+            let scope = format!("sm{sm}");
+            sms.push([vcd.wire(&scope, "pc", 5)?, vcd.wire(&scope, "tx_level", 4)?, vcd.wire(&scope, "rx_level", 4)?,
+                      vcd.wire(&scope, "tx", 32)?, vcd.wire(&scope, "rx", 32)?]);
+        }
+        let ns = |cycle: u64| ((cycle - trace.start) as u128 * 1_000_000_000 / clock_hz.max(1) as u128) as u64;
+        for &(cycle, event) in &trace.events {
+            let time = ns(cycle);
+            match event {
+                Event::Pins(levels) => for &(pin, wire) in &pins {
+                    vcd.change(time, wire, (levels >> pin & 1) as u64)?;
+                },
+                Event::Pc(sm, pc)           => vcd.change(time, sms[sm as usize][0], pc as u64)?,
+                Event::TxLevel(sm, level)   => vcd.change(time, sms[sm as usize][1], level as u64)?,
+                Event::RxLevel(sm, level)   => vcd.change(time, sms[sm as usize][2], level as u64)?,
+                Event::Tx(sm, word)         => vcd.change(time, sms[sm as usize][3], word as u64)?,
+                Event::Rx(sm, word)         => vcd.change(time, sms[sm as usize][4], word as u64)?,
+            }
+        }
+        vcd.finish(ns(self.cycle))?;
+        Ok(())
+    }
+
+    // One SM clock.
+    fn execute(&mut self, sm: usize) {
+        if self.sms[sm].delay > 0 {
+            self.sms[sm].delay -= 1;
+            return;
+        }
+        let (bit_count, optional, pindirs) = self.sms[sm].config.get_sideset();
+        let side_set = SideSet::new(bit_count.saturating_sub(optional as u32) as u8, optional, pindirs);
+        let from_exec = self.sms[sm].exec.is_some();
+        let raw = self.sms[sm].exec.unwrap_or(self.instr_mem[self.sms[sm].pc as usize]);
+        let Ok(instr) = Instruction::decode(raw, side_set) else {
+            // Reserved encodings. Treat them as nops rather than wedging the SM.
+            self.advance(sm, Outcome::Next, 0, from_exec);
+            return;
+        };
+        if let (Some(value), false) = (instr.side_set, self.sms[sm].side_set_done) {
+            let base = self.sms[sm].config.get_sideset_pins();
+            let pins_mask = mask(side_set.count as u32).rotate_left(base);
+            let value = (value as u32).rotate_left(base);
+            if pindirs { self.pindirs = self.pindirs & !pins_mask | value & pins_mask }
+            else       { self.levels  = self.levels  & !pins_mask | value & pins_mask }
+        }
+        let outcome = self.operate(sm, instr.operation);
+        self.advance(sm, outcome, instr.delay, from_exec);
+    }
+
+    fn advance(&mut self, sm: usize, outcome: Outcome, delay: u8, from_exec: bool) {
+        let (wrap_target, wrap) = self.sms[sm].config.get_wrap();
+        let state = &mut self.sms[sm];
+        if let Outcome::Stall = outcome {
+            state.stalled = true;
+            state.side_set_done = true;
+            return;
+        }
+        state.stalled = false;
+        state.side_set_done = false;
+        state.exec = None;
+        state.delay = delay;
+        let next = if state.pc as u32 == wrap { wrap_target as u8 } else { (state.pc + 1) % self.instr_mem.len() as u8 };
+        match outcome {
+            Outcome::Jump(addr)  => state.pc = addr & 0x1f,
+            Outcome::Exec(instr) => {
+                state.exec = Some(instr);
+                state.delay = 0; // The delay of `out exec`/`mov exec` themselves is ignored.
+                if !from_exec { state.pc = next }
+            },
+            Outcome::Next if from_exec => {},
+            Outcome::Next  => state.pc = next,
+            Outcome::Stall => unreachable!(),
+        }
+    }
+
+    fn in_pins(&self, sm: usize) -> u32 {
+        self.pins().rotate_right(self.sms[sm].config.get_in_pins())
+    }
+
+    fn write_pins(&mut self, base: u32, count: u32, value: u32, dirs: bool) {
+        let pins_mask = mask(count).rotate_left(base);
+        let value = value.rotate_left(base);
+        if dirs { self.pindirs = self.pindirs & !pins_mask | value & pins_mask }
+        else    { self.levels  = self.levels  & !pins_mask | value & pins_mask }
+    }
+
+    fn push(&mut self, sm: usize) -> bool {
+        if self.sms[sm].rx.len() >= self.rx_depth(sm) {
+            return false;
+        }
+        let state = &mut self.sms[sm];
+        state.rx.push_back(state.isr);
+        let word = state.isr;
+        state.isr = 0;
+        state.isr_count = 0;
+        self.record(Event::Rx(sm as u8, word));
+        true
+    }
+
+    fn pull(&mut self, sm: usize) -> bool {
+        let state = &mut self.sms[sm];
+        let Some(word) = state.tx.pop_front() else { return false };
+        state.osr = word;
+        state.osr_count = 0;
+        true
+    }
+
+    fn operate(&mut self, sm: usize, operation: Operation) -> Outcome {
+        let config = self.sms[sm].config;
+        let (in_right, autopush, push_threshold) = config.get_in_shift();
+        let (out_right, autopull, pull_threshold) = config.get_out_shift();
+        match operation {
+            Operation::Jmp { condition, address } => {
+                let state = &mut self.sms[sm];
+                let taken = match condition {
+                    JmpCondition::Always      => true,
+                    JmpCondition::XZero       => state.x == 0,
+                    JmpCondition::XPostDec    => { let taken = state.x != 0; state.x = state.x.wrapping_sub(1); taken },
+                    JmpCondition::YZero       => state.y == 0,
+                    JmpCondition::YPostDec    => { let taken = state.y != 0; state.y = state.y.wrapping_sub(1); taken },
+                    JmpCondition::XNotEqualY  => state.x != state.y,
+                    JmpCondition::Pin         => self.pins() >> config.get_jmp_pin() & 1 != 0,
+                    JmpCondition::NotOsrEmpty => state.osr_count < pull_threshold,
+                };
+                if taken { Outcome::Jump(address) } else { Outcome::Next }
+            },
+            Operation::Wait { polarity, source, index } => {
+                let met = match source {
+                    WaitSource::Gpio => (self.pins() >> index & 1 != 0) == polarity,
+                    WaitSource::Pin  => (self.in_pins(sm) >> index & 1 != 0) == polarity,
+                    WaitSource::Irq  => {
+                        let flag = IrqIndex::decode(index).resolve(sm as u16);
+                        let met = (self.irq >> flag & 1 != 0) == polarity;
+                        if met && polarity { self.irq &= !(1 << flag) }
+                        met
+                    },
+                };
+                if met { Outcome::Next } else { Outcome::Stall }
+            },
+            Operation::In { source, bit_count } => {
+                let bit_count = bit_count as u32;
+                if autopush && self.sms[sm].isr_count + bit_count >= push_threshold && self.is_rx_full(sm as u16) {
+                    return Outcome::Stall;
+                }
+                let state = &self.sms[sm];
+                let data = match source {
+                    InSource::Pins => self.in_pins(sm),
+                    InSource::X    => state.x,
+                    InSource::Y    => state.y,
+                    InSource::Null => 0,
+                    InSource::Isr  => state.isr,
+                    InSource::Osr  => state.osr,
+                } & mask(bit_count);
+                let state = &mut self.sms[sm];
+                state.isr = match (in_right, bit_count) {
+                    (_, 32)    => data,
+                    (true, n)  => state.isr >> n | data << (32 - n),
+                    (false, n) => state.isr << n | data,
+                };
+                state.isr_count = (state.isr_count + bit_count).min(32);
+                if autopush && state.isr_count >= push_threshold {
+                    self.push(sm);
+                }
+                Outcome::Next
+            },
+            Operation::Out { destination, bit_count } => {
+                let bit_count = bit_count as u32;
+                if autopull && self.sms[sm].osr_count >= pull_threshold && !self.pull(sm) {
+                    return Outcome::Stall;
+                }
+                let state = &mut self.sms[sm];
+                let data = match (out_right, bit_count) {
+                    (_, 32)    => std::mem::take(&mut state.osr),
+                    (true, n)  => { let data = state.osr & mask(n); state.osr >>= n; data },
+                    (false, n) => { let data = state.osr >> (32 - n); state.osr <<= n; data },
+                };
+                state.osr_count = (state.osr_count + bit_count).min(32);
+                let outcome = match destination {
+                    OutDestination::Pins    => { let (base, count) = config.get_out_pins(); self.write_pins(base, count, data, false); Outcome::Next },
+                    OutDestination::X       => { self.sms[sm].x = data; Outcome::Next },
+                    OutDestination::Y       => { self.sms[sm].y = data; Outcome::Next },
+                    OutDestination::Null    => Outcome::Next,
+                    OutDestination::Pindirs => { let (base, count) = config.get_out_pins(); self.write_pins(base, count, data, true); Outcome::Next },
+                    OutDestination::Pc      => Outcome::Jump(data as u8),
+                    OutDestination::Isr     => { self.sms[sm].isr = data; self.sms[sm].isr_count = bit_count; Outcome::Next },
+                    OutDestination::Exec    => Outcome::Exec(data as u16),
+                };
+                // Autopull refills the OSR in the background as soon as it can.
+                if autopull && self.sms[sm].osr_count >= pull_threshold {
+                    self.pull(sm);
+                }
+                outcome
+            },
+            Operation::Push { if_full, block } => {
+                if if_full && self.sms[sm].isr_count < push_threshold {
+                    return Outcome::Next;
+                }
+                match (self.push(sm), block) {
+                    (true, _)      => Outcome::Next,
+                    (false, true)  => Outcome::Stall,
+                    (false, false) => { self.sms[sm].isr = 0; self.sms[sm].isr_count = 0; Outcome::Next },
+                }
+            },
+            Operation::Pull { if_empty, block } => {
+                if if_empty && self.sms[sm].osr_count < pull_threshold {
+                    return Outcome::Next;
+                }
+                match (self.pull(sm), block) {
+                    (true, _)      => Outcome::Next,
+                    (false, true)  => Outcome::Stall,
+                    (false, false) => { let state = &mut self.sms[sm]; state.osr = state.x; Outcome::Next },
+                }
+            },
+            Operation::Mov { destination, op, source } => {
+                let state = &self.sms[sm];
+                let data = match source {
+                    MovSource::Pins   => self.in_pins(sm),
+                    MovSource::X      => state.x,
+                    MovSource::Y      => state.y,
+                    MovSource::Null   => 0,
+                    MovSource::Status => {
+                        let (status_sel, n) = config.get_mov_status();
+                        let level = match status_sel { PioMovStatus::TxLessThan => state.tx.len(), PioMovStatus::RxLessThan => state.rx.len() };
+                        if (level as u32) < n { !0 } else { 0 }
+                    },
+                    MovSource::Isr    => state.isr,
+                    MovSource::Osr    => state.osr,
+                };
+                let data = match op { MovOp::None => data, MovOp::Invert => !data, MovOp::Reverse => data.reverse_bits() };
+                let state = &mut self.sms[sm];
+                match destination {
+                    MovDestination::Pins => { let (base, count) = config.get_out_pins(); self.write_pins(base, count, data, false); },
+                    MovDestination::X    => state.x = data,
+                    MovDestination::Y    => state.y = data,
+                    MovDestination::Exec => return Outcome::Exec(data as u16),
+                    MovDestination::Pc   => return Outcome::Jump(data as u8),
+                    MovDestination::Isr  => { state.isr = data; state.isr_count = 0 },
+                    MovDestination::Osr  => { state.osr = data; state.osr_count = 0 },
+                }
+                Outcome::Next
+            },
+            Operation::Irq { clear, wait, index } => {
+                let flag = index.resolve(sm as u16);
+                if let Some(waiting) = self.sms[sm].irq_wait {
+                    if self.irq >> waiting & 1 != 0 { return Outcome::Stall }
+                    self.sms[sm].irq_wait = None;
+                    return Outcome::Next;
+                }
+                if clear {
+                    self.irq &= !(1 << flag);
+                    return Outcome::Next;
+                }
+                self.irq |= 1 << flag;
+                if wait {
+                    self.sms[sm].irq_wait = Some(flag);
+                    return Outcome::Stall;
+                }
+                Outcome::Next
+            },
+            Operation::Set { destination, data } => {
+                let data = data as u32;
+                match destination {
+                    SetDestination::Pins    => { let (base, count) = config.get_set_pins(); self.write_pins(base, count, data, false) },
+                    SetDestination::X       => self.sms[sm].x = data,
+                    SetDestination::Y       => self.sms[sm].y = data,
+                    SetDestination::Pindirs => { let (base, count) = config.get_set_pins(); self.write_pins(base, count, data, true) },
+                }
+                Outcome::Next
+            },
+        }
+    }
+
+    // The registers read_hw() can see, so StateMachine::read_hw_state_machine() and friends work against an emulator.
+    fn register(&self, addr: u32) -> u32 {
+        let sm_stride = 8 * 4;
+        match addr {
+            PROC_PIO_CTRL_OFFSET => self.sms.iter().enumerate().fold(0, |ctrl, (i, sm)| ctrl | (sm.enabled as u32) << i),
+            PROC_PIO_FSTAT_OFFSET => (0..self.sms.len()).fold(0, |fstat, i| {
+                let sm = &self.sms[i];
+                fstat | ((sm.rx.len() >= self.rx_depth(i)) as u32) << (PROC_PIO_FSTAT_RXFULL_LSB + i as u32)
+                      | (sm.rx.is_empty() as u32) << (PROC_PIO_FSTAT_RXEMPTY_LSB + i as u32)
+                      | ((sm.tx.len() >= self.tx_depth(i)) as u32) << (PROC_PIO_FSTAT_TXFULL_LSB + i as u32)
+                      | (sm.tx.is_empty() as u32) << (PROC_PIO_FSTAT_TXEMPTY_LSB + i as u32)
+            }),
+            PROC_PIO_FLEVEL_OFFSET => self.sms.iter().enumerate()
+                .fold(0, |flevel, (i, sm)| flevel | (sm.tx.len() as u32 & 0xf) << (i * 8) | (sm.rx.len() as u32 & 0xf) << (i * 8 + 4)),
+            PROC_PIO_FLEVEL2_OFFSET => self.sms.iter().enumerate()
+                .fold(0, |flevel2, (i, sm)| flevel2 | (sm.tx.len() as u32 >> 4 & 1) << (i * 8) | (sm.rx.len() as u32 >> 4 & 1) << (i * 8 + 4)),
+            PROC_PIO_IRQ_OFFSET => self.irq as u32,
+            PROC_PIO_DBG_PADOUT_OFFSET => self.levels,
+            PROC_PIO_DBG_PADOE_OFFSET => self.pindirs,
+            PROC_PIO_DBG_CFGINFO_OFFSET =>
+                (self.fifo_depth as u32) << PROC_PIO_DBG_CFGINFO_FIFO_DEPTH_LSB & PROC_PIO_DBG_CFGINFO_FIFO_DEPTH_BITS |
+                (self.sms.len() as u32) << PROC_PIO_DBG_CFGINFO_SM_COUNT_LSB & PROC_PIO_DBG_CFGINFO_SM_COUNT_BITS |
+                (self.instr_mem.len() as u32) << PROC_PIO_DBG_CFGINFO_IMEM_SIZE_LSB & PROC_PIO_DBG_CFGINFO_IMEM_SIZE_BITS,
+            addr if addr >= PROC_PIO_SM0_CLKDIV_OFFSET && addr < PROC_PIO_SM0_CLKDIV_OFFSET + sm_stride * self.sms.len() as u32 => {
+                let sm = &self.sms[((addr - PROC_PIO_SM0_CLKDIV_OFFSET) / sm_stride) as usize];
+                let (clkdiv, execctrl, shiftctrl, pinctrl) = sm.config.to_raw();
+                match (addr - PROC_PIO_SM0_CLKDIV_OFFSET) % sm_stride / 4 {
+                    0 => clkdiv,
+                    1 => execctrl | if sm.stalled { PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS } else { 0 },
+                    2 => shiftctrl,
+                    3 => sm.pc as u32,
+                    4 => sm.exec.unwrap_or(self.instr_mem[sm.pc as usize]) as u32,
+                    5 => pinctrl,
+                    _ => 0,
+                }
+            },
+            _ => 0,
+        }
+    }
+}
+
+
+// An Emulator as a PioBackend. Blocking FIFO operations run the emulator until they can complete, giving up with
+// Error::TimedOut after `timeout_cycles`. Claims and instruction memory allocation are tracked like the kernel does.
+#[derive(Clone)]
+pub struct EmulatorBackend {
+    state: Arc<Mutex<BackendState>>,
+    timeout_cycles: u64,
+}
+
+struct BackendState {
+    emu: Emulator,
+    claimed: u16,
+    used: Vec<bool>, // Instruction slots handed out by add_program
+}
+
+fn errno(errno: i32) -> Error {
+    std::io::Error::from_raw_os_error(errno).into()
+}
+
+// Safety: the caller (PioBackend::ioctl()) guarantees `args` points to an `A`.
+unsafe fn args<'a, A>(args: *mut c_void) -> &'a mut A {
+    unsafe { &mut *(args as *mut A) }
+}
+
+impl EmulatorBackend {
+    pub fn new(emu: Emulator) -> EmulatorBackend {
+        let used = vec![false; emu.instr_mem.len()];
+        EmulatorBackend { state: Arc::new(Mutex::new(BackendState { emu, claimed: 0, used })), timeout_cycles: 1_000_000 }
+    }
+
+    pub fn with_timeout(mut self, cycles: u64) -> EmulatorBackend {
+        self.timeout_cycles = cycles;
+        self
+    }
+
+    // The emulator, for poking at inputs, running it between calls or inspecting state.
+    pub fn emulator(&self) -> MappedEmulator<'_> {
+        MappedEmulator(lock(&self.state))
+    }
+
+    fn add_program(state: &mut BackendState, args: &AddProgramArgs, commit: bool) -> Result<u32, Error> {
+        let len = args.num_instrs as usize;
+        let fits = |offset: usize| offset + len <= state.used.len() && state.used[offset..offset + len].iter().all(|used| !used);
+        let offset = match args.origin {
+            0xffff => (0..state.used.len()).rev().find(|&offset| fits(offset)),
+            origin => fits(origin as usize).then_some(origin as usize),
+        };
+        let Some(offset) = offset else { return if commit { Err(errno(libc::EBUSY)) } else { Ok(0) } };
+        if !commit {
+            return Ok(1);
+        }
+        state.used[offset..offset + len].fill(true);
+        state.emu.load(&PioProgram::new(&args.instrs[..len], None), offset as u16)?;
+        Ok(offset as u32)
+    }
+
+    fn for_mask(state: &mut BackendState, mask: u16, mut f: impl FnMut(&mut Emulator, u16) -> Result<(), Error>) -> Result<u32, Error> {
+        for sm in (0..state.emu.sm_count()).filter(|sm| mask & 1 << sm != 0) {
+            f(&mut state.emu, sm)?;
+        }
+        Ok(0)
+    }
+
+    fn check_sm(state: &BackendState, sm: u16) -> Result<u16, Error> {
+        state.emu.check_sm(sm).map(|_| sm).map_err(|_| errno(libc::EINVAL))
+    }
+}
+
+pub struct MappedEmulator<'a>(MutexGuard<'a, BackendState>);
+
+impl std::ops::Deref for MappedEmulator<'_> {
+    type Target = Emulator;
+    fn deref(&self) -> &Emulator { &self.0.emu }
+}
+
+impl std::ops::DerefMut for MappedEmulator<'_> {
+    fn deref_mut(&mut self) -> &mut Emulator { &mut self.0.emu }
+}
+
+impl PioBackend for EmulatorBackend {
+    unsafe fn ioctl(&self, request: c_ulong, ptr: *mut c_void) -> Result<u32, Error> {
+        let state = &mut *lock(&self.state);
+        let timeout = self.timeout_cycles;
+        unsafe {
+            match request {
+                PIO_IOC_READ_HW => {
+                    let args = args::<AccessHwArgs>(ptr);
+                    let data = std::slice::from_raw_parts_mut(args.data as *mut u32, args.len as usize / size_of::<u32>());
+                    for (i, word) in data.iter_mut().enumerate() {
+                        *word = state.emu.register((args.addr & 0x0fff_ffff) + i as u32 * 4);
+                    }
+                    Ok(0)
+                },
+                PIO_IOC_CAN_ADD_PROGRAM => Self::add_program(state, args::<AddProgramArgs>(ptr), false),
+                PIO_IOC_ADD_PROGRAM => Self::add_program(state, args::<AddProgramArgs>(ptr), true),
+                PIO_IOC_REMOVE_PROGRAM => {
+                    let args = args::<RemoveProgramArgs>(ptr);
+                    let range = args.origin as usize..args.origin as usize + args.num_instrs as usize;
+                    state.used.get_mut(range).ok_or(errno(libc::EINVAL))?.fill(false);
+                    Ok(1)
+                },
+                PIO_IOC_CLEAR_INSTR_MEM => {
+                    state.used.fill(false);
+                    Ok(1)
+                },
+                PIO_IOC_SM_CLAIM => {
+                    let mask = args::<SmClaimArgs>(ptr).mask;
+                    if mask == 0 {
+                        let sm = (0..state.emu.sm_count()).find(|sm| state.claimed & 1 << sm == 0).ok_or(errno(libc::EBUSY))?;
+                        state.claimed |= 1 << sm;
+                        return Ok(sm as u32);
+                    }
+                    if state.claimed & mask != 0 {
+                        return Err(errno(libc::EBUSY));
+                    }
+                    state.claimed |= mask;
+                    Ok(0)
+                },
+                PIO_IOC_SM_UNCLAIM => {
+                    state.claimed &= !args::<SmClaimArgs>(ptr).mask;
+                    Ok(0)
+                },
+                PIO_IOC_SM_IS_CLAIMED => {
+                    let mask = args::<SmClaimArgs>(ptr).mask;
+                    Ok((state.claimed & mask == mask) as u32)
+                },
+                PIO_IOC_SM_INIT => {
+                    let args = args::<SmInitArgs>(ptr);
+                    state.emu.init(Self::check_sm(state, args.sm)?, args.initial_pc as u8, &args.config).map(|_| 0)
+                },
+                PIO_IOC_SM_SET_CONFIG => {
+                    let args = args::<SmSetConfigArgs>(ptr);
+                    state.emu.set_config(Self::check_sm(state, args.sm)?, &args.config).map(|_| 0)
+                },
+                PIO_IOC_SM_EXEC => {
+                    let args = args::<SmExecArgs>(ptr);
+                    let sm = Self::check_sm(state, args.sm)?;
+                    state.emu.exec(sm, args.instr)?;
+                    if args.blocking != 0 && state.emu.run_until(timeout, |emu| emu.sm(sm).exec.is_none()).is_none() {
+                        return Err(Error::TimedOut);
+                    }
+                    Ok(0)
+                },
+                PIO_IOC_SM_CLEAR_FIFOS => {
+                    state.emu.clear_fifos(Self::check_sm(state, args::<SmClearFifosArgs>(ptr).sm)?);
+                    Ok(0)
+                },
+                PIO_IOC_SM_SET_CLKDIV => {
+                    let args = args::<SmSetClkdivArgs>(ptr);
+                    let sm = Self::check_sm(state, args.sm)?;
+                    let config = state.emu.sm(sm).config.set_clkdiv_int_frac(ClkDiv { div: args.div_int, frac: args.div_frac })?;
+                    state.emu.set_config(sm, &config).map(|_| 0)
+                },
+                PIO_IOC_SM_SET_PINS => {
+                    let args = args::<SmSetPinsArgs>(ptr);
+                    state.emu.set_pins(args.values, args.mask);
+                    Ok(0)
+                },
+                PIO_IOC_SM_SET_PINDIRS => {
+                    let args = args::<SmSetPindirsArgs>(ptr);
+                    state.emu.set_pindirs(args.dirs, args.mask);
+                    Ok(0)
+                },
+                PIO_IOC_SM_SET_ENABLED => {
+                    let args = args::<SmSetEnabledArgs>(ptr);
+                    let enable = args.enable != 0;
+                    Self::for_mask(state, args.mask, |emu, sm| emu.set_enabled(sm, enable))
+                },
+                PIO_IOC_SM_RESTART => Self::for_mask(state, args::<SmRestartArgs>(ptr).mask, |emu, sm| {
+                    let state = emu.sm_mut(sm);
+                    *state = EmulatedSm { config: state.config, enabled: state.enabled, pc: state.pc, osr_count: 32,
+                                          tx: std::mem::take(&mut state.tx), rx: std::mem::take(&mut state.rx), ..EmulatedSm::default() };
+                    Ok(())
+                }),
+                PIO_IOC_SM_CLKDIV_RESTART => Self::for_mask(state, args::<SmRestartArgs>(ptr).mask, |emu, sm| { emu.sm_mut(sm).clk_acc = 0; Ok(()) }),
+                PIO_IOC_SM_ENABLE_SYNC => Self::for_mask(state, args::<SmEnableSyncArgs>(ptr).mask, |emu, sm| {
+                    emu.sm_mut(sm).clk_acc = 0;
+                    emu.set_enabled(sm, true)
+                }),
+                PIO_IOC_SM_PUT => {
+                    let args = args::<SmPutArgs>(ptr);
+                    let sm = Self::check_sm(state, args.sm)?;
+                    if args.blocking != 0 && state.emu.run_until(timeout, |emu| !emu.is_tx_full(sm)).is_none() {
+                        return Err(Error::TimedOut);
+                    }
+                    state.emu.put(sm, args.data);
+                    Ok(0)
+                },
+                PIO_IOC_SM_GET => {
+                    let args = args::<SmGetArgs>(ptr);
+                    let sm = Self::check_sm(state, args.sm)?;
+                    if args.blocking != 0 && state.emu.run_until(timeout, |emu| emu.sm(sm).rx_level() > 0).is_none() {
+                        return Err(Error::TimedOut);
+                    }
+                    args.data = state.emu.get(sm).unwrap_or(0);
+                    Ok(0)
+                },
+                PIO_IOC_SM_FIFO_STATE => {
+                    let args = args::<SmFifoStateArgs>(ptr);
+                    let sm = Self::check_sm(state, args.sm)?;
+                    let (level, full) = if args.tx != 0 { (state.emu.sm(sm).tx_level(), state.emu.is_tx_full(sm)) }
+                                        else            { (state.emu.sm(sm).rx_level(), state.emu.is_rx_full(sm)) };
+                    args.level = level as u16;
+                    args.empty = (level == 0) as u8;
+                    args.full = full as u8;
+                    Ok(0)
+                },
+                PIO_IOC_SM_DRAIN_TX => {
+                    let sm = Self::check_sm(state, args::<SmClearFifosArgs>(ptr).sm)?;
+                    if state.emu.run_until(timeout, |emu| emu.sm(sm).tx_level() == 0).is_none() {
+                        return Err(Error::TimedOut);
+                    }
+                    Ok(0)
+                },
+                PIO_IOC_GPIO_SET_OEOVER => {
+                    let args = args::<GpioSetArgs>(ptr);
+                    let over = match args.value {
+                        0 => Override::Normal,
+                        1 => Override::Invert,
+                        2 => Override::Low,
+                        3 => Override::High,
+                        _ => return Err(errno(libc::EINVAL)),
+                    };
+                    state.emu.set_oeover(args.gpio as u32, over);
+                    Ok(0)
+                },
+                // Not modeled: DMA, GPIO muxing and pad settings. They're accepted so drivers can run unmodified.
+                PIO_IOC_SM_CONFIG_XFER | PIO_IOC_SM_CONFIG_XFER32 | PIO_IOC_SM_XFER_DATA | PIO_IOC_SM_XFER_DATA32 |
+                PIO_IOC_WRITE_HW | PIO_IOC_SM_SET_DMACTRL |
+                PIO_IOC_GPIO_INIT | PIO_IOC_GPIO_SET_FUNCTION | PIO_IOC_GPIO_SET_PULLS | PIO_IOC_GPIO_SET_OUTOVER |
+                PIO_IOC_GPIO_SET_INOVER | PIO_IOC_GPIO_SET_INPUT_ENABLED | PIO_IOC_GPIO_SET_DRIVE_STRENGTH => Ok(0),
+                _ => Err(errno(libc::ENOTTY)),
+            }
+        }
+    }
+
+    fn devname(&self) -> &Path {
+        Path::new("emulator")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::Rp1PIO;
+
+    fn emulator() -> Emulator {
+        Emulator::new(&Chip::new())
+    }
+
+    #[test]
+    fn set_and_delay_timing() {
+        // set pins, 1 [3] / set pins, 0 [1]
+        let mut emu = emulator();
+        emu.load(&PioProgram::new(&[0xe301, 0xe100], None), 0).unwrap();
+        let config = SmConfig::default().set_set_pins(5, 1).unwrap().set_wrap(0, 1).unwrap();
+        emu.init(0, 0, &config).unwrap();
+        emu.set_pindirs(1 << 5, 1 << 5);
+        emu.set_enabled(0, true).unwrap();
+        let levels: Vec<u32> = (0..12).map(|_| { emu.step(); emu.pins() >> 5 & 1 }).collect();
+        assert_eq!(levels, [1, 1, 1, 1, 0, 0, 1, 1, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn peripherals() {
+        // An inverter from pin 5 to pin 6.
+        #[derive(Debug)]
+        struct Inverter;
+        impl Peripheral for Inverter {
+            fn step(&mut self, pins: u32, _cycle: u64) -> (u32, u32) {
+                (!pins >> 5 << 6, 1 << 6)
+            }
+        }
+        let mut emu = emulator();
+        emu.load(&PioProgram::new(&[0xe301, 0xe100], None), 0).unwrap();
+        let config = SmConfig::default().set_set_pins(5, 1).unwrap().set_wrap(0, 1).unwrap();
+        emu.init(0, 0, &config).unwrap();
+        emu.set_pindirs(1 << 5, 1 << 5);
+        emu.set_enabled(0, true).unwrap();
+        emu.attach(Arc::new(Mutex::new(Inverter)));
+        let levels: Vec<u32> = (0..6).map(|_| { emu.step(); emu.pins() >> 5 & 0b11 }).collect();
+        assert_eq!(levels, [0b01, 0b01, 0b01, 0b01, 0b10, 0b10]);
+    }
+
+    #[test]
+    fn out_exec_and_oeover() {
+        // out exec, 16 / set pins, 0: the executed instruction runs, then the one after the `out`.
+        let mut emu = emulator();
+        emu.load(&PioProgram::new(&[0x60f0, 0xe000], None), 0).unwrap();
+        let config = SmConfig::default().set_set_pins(5, 1).unwrap().set_out_shift(false, true, 16).unwrap().set_wrap(0, 1).unwrap();
+        emu.init(0, 0, &config).unwrap();
+        emu.set_pindirs(1 << 5, 1 << 5);
+        emu.put(0, 0xe001 << 16); // set pins, 1
+        emu.set_enabled(0, true).unwrap();
+        let levels: Vec<u32> = (0..4).map(|_| { emu.step(); emu.pins() >> 5 & 1 }).collect();
+        assert_eq!(levels, [0, 1, 0, 0]);
+        assert_eq!(emu.sm(0).pc, 0);
+
+        emu.set_input(5, true);
+        emu.set_oeover(5, Override::Invert);
+        assert_eq!((emu.pindirs() >> 5 & 1, emu.pins() >> 5 & 1), (0, 1));
+        emu.set_oeover(5, Override::Normal);
+        assert_eq!((emu.pindirs() >> 5 & 1, emu.pins() >> 5 & 1), (1, 0));
+    }
+
+    #[test]
+    fn vcd_trace() {
+        let mut emu = emulator();
+        emu.load(&PioProgram::new(&[0xe301, 0xe100], None), 0).unwrap();
+        let config = SmConfig::default().set_set_pins(5, 1).unwrap().set_wrap(0, 1).unwrap();
+        emu.init(0, 0, &config).unwrap();
+        emu.set_pindirs(1 << 5, 1 << 5);
+        emu.set_enabled(0, true).unwrap();
+        emu.start_trace(1 << 5);
+        emu.put(0, 0xabcd);
+        emu.run(6);
+        let mut out = Vec::new();
+        emu.write_vcd(&mut out, 100_000_000).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("$var wire 1 ! gpio5 $end"));
+        assert!(out.contains("#0\n0!\n"));
+        assert!(out.contains("#10\n1!\n"));
+        assert!(out.contains("#50\n0!\n"));
+        assert!(out.contains("b1010101111001101 "));
+        assert!(out.ends_with("#60\n"));
+    }
+
+    #[test]
+    fn clock_divider() {
+        let mut emu = emulator();
+        emu.load(&PioProgram::new(&[0xe001, 0xe000], None), 0).unwrap();
+        let config = SmConfig::default().set_set_pins(0, 1).unwrap().set_wrap(0, 1).unwrap().set_clkdiv(2.5).unwrap();
+        emu.init(0, 0, &config).unwrap();
+        emu.set_enabled(0, true).unwrap();
+        emu.run(100);
+        assert_eq!(emu.sm(0).pc, 0); // 40 instructions
+        emu.run(5);
+        assert_eq!(emu.sm(0).pc, 0);
+    }
+
+    #[test]
+    fn autopull_and_autopush_loopback() {
+        // out x, 8 / in x, 8 with autopull and autopush at 32 bits
+        let mut emu = emulator();
+        emu.load(&PioProgram::new(&[0x6028, 0x4028], None), 4).unwrap();
+        let config = SmConfig::default()
+            .set_wrap(4, 5).unwrap()
+            .set_out_shift(true, true, 32).unwrap()
+            .set_in_shift(true, true, 32).unwrap();
+        emu.init(0, 4, &config).unwrap();
+        emu.set_enabled(0, true).unwrap();
+        assert!(emu.put(0, 0x1234_5678));
+        assert!(emu.put(0, 0x9abc_def0));
+        emu.run(100);
+        assert_eq!(emu.get(0), Some(0x1234_5678));
+        assert_eq!(emu.get(0), Some(0x9abc_def0));
+        assert_eq!(emu.get(0), None);
+        assert!(emu.sm(0).stalled); // Waiting on the next autopull
+    }
+
+    #[test]
+    fn jmp_x_decrement_loop() {
+        // set x, 3 / loop: jmp x-- loop / push noblock with x in the isr first
+        let mut emu = emulator();
+        emu.load(&PioProgram::new(&[0xe023, 0x0041, 0x4020, 0x8000, 0x0004], None), 0).unwrap();
+        emu.init(0, 0, &SmConfig::default()).unwrap();
+        emu.set_enabled(0, true).unwrap();
+        emu.run(20);
+        assert_eq!(emu.get(0), Some(0xffff_ffff));
+    }
+
+    #[test]
+    fn wait_and_side_set() {
+        // .side_set 1: wait 1 gpio 3 side 1 / nop side 0
+        let mut emu = emulator();
+        emu.load(&PioProgram::new(&[0x3083, 0xa042], None), 0).unwrap();
+        let config = SmConfig::default().set_sideset(1, false, false).unwrap().set_sideset_pins(7).unwrap().set_wrap(1, 1).unwrap();
+        emu.init(0, 0, &config).unwrap();
+        emu.set_pindirs(1 << 7, 1 << 7);
+        emu.set_enabled(0, true).unwrap();
+        emu.run(10);
+        assert!(emu.sm(0).stalled);
+        assert_eq!(emu.pins() >> 7 & 1, 1);
+        emu.set_input(3, true);
+        emu.run(2);
+        assert!(!emu.sm(0).stalled);
+        assert_eq!(emu.pins() >> 7 & 1, 0);
+    }
+
+    #[test]
+    fn irq_between_sms() {
+        // SM0: irq wait 0 / jmp self, SM1: wait 1 irq 0 / jmp self
+        let mut emu = emulator();
+        emu.load(&PioProgram::new(&[0xc020, 0x0001, 0x20c0, 0x0003], None), 0).unwrap();
+        emu.init(0, 0, &SmConfig::default()).unwrap();
+        emu.init(1, 2, &SmConfig::default()).unwrap();
+        emu.set_enabled(0, true).unwrap();
+        emu.run(3);
+        assert!(emu.sm(0).stalled);
+        assert_eq!(emu.irq_flags(), 1);
+        emu.set_enabled(1, true).unwrap();
+        emu.run(3);
+        assert_eq!(emu.irq_flags(), 0);
+        assert_eq!(emu.sm(0).pc, 1);
+        assert_eq!(emu.sm(1).pc, 3);
+    }
+
+    #[test]
+    fn through_rp1pio() {
+        let backend = EmulatorBackend::new(emulator()).with_timeout(1000);
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        // pull block / mov isr, !osr / push block
+        let program = PioProgram::new(&[0x80a0, 0xa0cf, 0x8020], None).with_wrap(0, 2);
+        let loaded = pio.load_program(&program).unwrap();
+        let sm = pio.sm_claim_unused().unwrap();
+        let (wrap_target, wrap) = loaded.wrap();
+        sm.init(loaded.offset(), &SmConfig::default().set_wrap(wrap_target, wrap).unwrap()).unwrap();
+        sm.set_enabled(true).unwrap();
+        sm.put(0x0f0f_0000, true).unwrap();
+        assert_eq!(sm.get(true).unwrap(), !0x0f0f_0000);
+        assert_eq!(sm.read_hw_state_machine().unwrap().pc, loaded.offset() as u32);
+        assert!(matches!(sm.get(true), Err(Error::TimedOut)));
+        assert!(backend.emulator().cycle() > 1000);
+    }
+
+    #[test]
+    fn fifo_waits() {
+        let backend = EmulatorBackend::new(emulator());
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        // pull block / mov isr, !osr / push block
+        let program = PioProgram::new(&[0x80a0, 0xa0cf, 0x8020], None).with_wrap(0, 2);
+        let loaded = pio.load_program(&program).unwrap();
+        let sm = pio.sm_claim_unused().unwrap();
+        let (wrap_target, wrap) = loaded.wrap();
+        sm.init(loaded.offset(), &SmConfig::default().set_wrap(wrap_target, wrap).unwrap()).unwrap();
+        for word in 0..8 {
+            sm.put(word, false).unwrap();
+        }
+        let start = std::time::Instant::now();
+        assert_eq!(sm.wait_rx_available(1, Duration::from_millis(20)), Err(Error::TimedOut));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(sm.wait_tx_space(1, Duration::from_millis(1)), Err(Error::TimedOut));
+        assert_eq!(sm.wait_tx_empty(Duration::from_millis(1)), Err(Error::TimedOut));
+        assert!(matches!(sm.wait_tx_space(9, Duration::ZERO), Err(Error::ParamErr { param: "n", .. })));
+
+        sm.set_enabled(true).unwrap();
+        backend.emulator().run(100);
+        sm.wait_tx_empty(Duration::ZERO).unwrap();
+        sm.wait_tx_space(8, Duration::ZERO).unwrap();
+        sm.wait_rx_available(8, Duration::ZERO).unwrap();
+        assert_eq!(sm.get(false).unwrap(), !0);
+
+        sm.set_config(&SmConfig::default().set_fifo_join(PioFifoJoin::Rx).unwrap()).unwrap();
+        assert_eq!(sm.wait_rx_available(16, Duration::from_millis(1)), Err(Error::TimedOut));
+        assert!(matches!(sm.wait_tx_space(1, Duration::ZERO), Err(Error::ParamErr { param: "n", .. })));
+    }
+}
@@ -0,0 +1,635 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// piolib's C API on top of this crate, so C programs written against piolib can link against us instead:
+//
+//     cargo rustc --lib --release --features cdylib --crate-type cdylib
+//     cc -Iinclude app.c -Ltarget/release -lpio_pi5_rs
+//
+// include/piolib.h declares what's here. Most of piolib's header is static inline functions that call through the
+// PIO's chip vtable; these are real functions instead, with the same names and signatures, so code that uses them
+// compiles unchanged. Like piolib, errors are fatal (a message and exit(1)) unless pio_enable_fatal_errors(pio, false)
+// turns them into a sticky flag to check with pio_get_error(). gpio_*() functions work on the current PIO
+// (pio_select()), opening pio0 if nothing's been selected.
+
+#![allow(clippy::missing_safety_doc)] // The safety requirements are piolib's: valid PIO handles and pointers.
+
+use std::{cell::Cell, ffi::{c_char, c_int, c_uint, c_void, CStr}};
+
+use crate::{gpio::{DriveStrength, Function}, ClkDiv, Error, PioFifoJoin, PioMovStatus, PioProgram, Rp1PIO, SmConfig, XferDir};
+
+pub const PIO_ORIGIN_ANY: c_uint = !0;
+pub const PIO_ORIGIN_INVALID: c_uint = PIO_ORIGIN_ANY;
+
+// struct pio_instance. C only ever sees a pointer to it (`PIO`).
+pub struct PioInstance {
+    pio: Rp1PIO,
+    index: c_int,
+    error: Cell<bool>,
+    errors_are_fatal: Cell<bool>,
+}
+
+#[allow(non_camel_case_types)]
+pub type PIO = *mut PioInstance;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct pio_program_t {
+    pub instructions: *const u16,
+    pub length: u8,
+    pub origin: i8,
+    pub pio_version: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub struct pio_sm_config {
+    pub clkdiv: u32,
+    pub execctrl: u32,
+    pub shiftctrl: u32,
+    pub pinctrl: u32,
+}
+
+thread_local! {
+    static CURRENT: Cell<PIO> = const { Cell::new(std::ptr::null_mut()) };
+}
+
+fn pio_panic_str(msg: &str) -> ! {
+    eprintln!("PIO error: {msg}");
+    std::process::exit(1)
+}
+
+impl PioInstance {
+    fn check<T>(&self, result: Result<T, Error>, fallback: T) -> T {
+        match result {
+            Ok(value) => value,
+            Err(e) => {
+                if self.errors_are_fatal.get() {
+                    pio_panic_str(&e.to_string());
+                }
+                self.error.set(true);
+                fallback
+            },
+        }
+    }
+}
+
+unsafe fn instance<'a>(pio: PIO) -> &'a PioInstance {
+    unsafe { pio.as_ref() }.unwrap_or_else(|| pio_panic_str("NULL PIO"))
+}
+
+unsafe fn program(program: *const pio_program_t) -> PioProgram {
+    let program = unsafe { program.as_ref() }.unwrap_or_else(|| pio_panic_str("NULL program"));
+    let instructions = unsafe { std::slice::from_raw_parts(program.instructions, program.length as usize) };
+    PioProgram::new(instructions, (program.origin >= 0).then_some(program.origin as u8)).with_pio_version(program.pio_version)
+}
+
+fn offset(offset: c_uint) -> Option<u16> {
+    (offset != PIO_ORIGIN_ANY).then_some(offset as u16)
+}
+
+impl From<SmConfig> for pio_sm_config {
+    fn from(config: SmConfig) -> pio_sm_config {
+        let (clkdiv, execctrl, shiftctrl, pinctrl) = config.to_raw();
+        pio_sm_config { clkdiv, execctrl, shiftctrl, pinctrl }
+    }
+}
+
+impl From<&pio_sm_config> for SmConfig {
+    fn from(c: &pio_sm_config) -> SmConfig {
+        SmConfig::from_raw((c.clkdiv, c.execctrl, c.shiftctrl, c.pinctrl))
+    }
+}
+
+// The sm_config_set_*() functions, which fail the way piolib's valid_params_if() does: fatally.
+unsafe fn update_config(c: *mut pio_sm_config, f: impl FnOnce(SmConfig) -> Result<SmConfig, Error>) {
+    let c = unsafe { c.as_mut() }.unwrap_or_else(|| pio_panic_str("NULL pio_sm_config"));
+    match f(SmConfig::from(&*c)) {
+        Ok(config) => *c = config.into(),
+        Err(e)     => pio_panic_str(&e.to_string()),
+    }
+}
+
+// Instances
+
+#[unsafe(no_mangle)]
+pub extern "C" fn pio_init() -> c_int {
+    0
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn pio_open(idx: c_uint) -> PIO {
+    match Rp1PIO::new(idx as usize) {
+        Ok(pio) => Box::into_raw(Box::new(PioInstance { pio, index: idx as c_int, error: Cell::new(false), errors_are_fatal: Cell::new(true) })),
+        Err(_)  => std::ptr::null_mut(),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_open_by_name(name: *const c_char) -> PIO {
+    let Some(name) = (unsafe { name.as_ref() }).and_then(|_| unsafe { CStr::from_ptr(name) }.to_str().ok()) else { return std::ptr::null_mut() };
+    match Rp1PIO::open_path(name) {
+        Ok(pio) => Box::into_raw(Box::new(PioInstance { pio, index: -1, error: Cell::new(false), errors_are_fatal: Cell::new(true) })),
+        Err(_)  => std::ptr::null_mut(),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_close(pio: PIO) {
+    if pio.is_null() {
+        return;
+    }
+    CURRENT.with(|current| if current.get() == pio { current.set(std::ptr::null_mut()) });
+    drop(unsafe { Box::from_raw(pio) });
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_select(pio: PIO) {
+    CURRENT.with(|current| current.set(pio));
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn pio_get_current() -> PIO {
+    CURRENT.with(|current| {
+        if current.get().is_null() {
+            let pio = pio_open(0);
+            if pio.is_null() {
+                pio_panic_str("Failed to open PIO device");
+            }
+            current.set(pio);
+        }
+        current.get()
+    })
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_get_index(pio: PIO) -> c_int {
+    unsafe { instance(pio) }.index
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_enable_fatal_errors(pio: PIO, enable: bool) {
+    unsafe { instance(pio) }.errors_are_fatal.set(enable);
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_get_error(pio: PIO) -> bool {
+    unsafe { instance(pio) }.error.get()
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_clear_error(pio: PIO) {
+    unsafe { instance(pio) }.error.set(false);
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_panic(msg: *const c_char) -> ! {
+    let msg = if msg.is_null() { "panic".into() } else { unsafe { CStr::from_ptr(msg) }.to_string_lossy() };
+    pio_panic_str(&msg)
+}
+
+// Programs
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_can_add_program(pio: PIO, program: *const pio_program_t) -> bool {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.can_add_program(&unsafe { self::program(program) }), false)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_can_add_program_at_offset(pio: PIO, program: *const pio_program_t, offset: c_uint) -> bool {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.can_add_program_at_offset(&unsafe { self::program(program) }, self::offset(offset)), false)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_add_program(pio: PIO, program: *const pio_program_t) -> c_uint {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.add_program(&unsafe { self::program(program) }).map(c_uint::from), PIO_ORIGIN_INVALID)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_add_program_at_offset(pio: PIO, program: *const pio_program_t, offset: c_uint) -> c_uint {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.add_program_at_offset(&unsafe { self::program(program) }, self::offset(offset)).map(c_uint::from), PIO_ORIGIN_INVALID)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_remove_program(pio: PIO, program: *const pio_program_t, loaded_offset: c_uint) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.remove_program(&unsafe { self::program(program) }, offset(loaded_offset)).map(|_| ()), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_clear_instruction_memory(pio: PIO) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.clear_instruction_memory().map(|_| ()), ());
+}
+
+// State machines
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_claim(pio: PIO, sm: c_uint) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm_claim(sm as u16).map(|_| ()), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_claim_sm_mask(pio: PIO, sm_mask: c_uint) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm_claim_mask(sm_mask as u16).map(|_| ()), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_claim_unused_sm(pio: PIO, required: bool) -> c_int {
+    let pio = unsafe { instance(pio) };
+    match pio.pio.sm_claim_unused() {
+        Ok(sm)                => sm.index() as c_int,
+        Err(_) if !required   => -1,
+        Err(e)                => pio.check(Err(e), -1),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_unclaim(pio: PIO, sm: c_uint) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).unclaim().map(|_| ()), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_is_claimed(pio: PIO, sm: c_uint) -> bool {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm_is_claimed(sm as u16), false)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_init(pio: PIO, sm: c_uint, initial_pc: c_uint, config: *const pio_sm_config) -> c_int {
+    let pio = unsafe { instance(pio) };
+    let config = unsafe { config.as_ref() }.map(SmConfig::from).unwrap_or_default();
+    pio.check(pio.pio.sm(sm as u16).init(initial_pc as u16, &config).map(|_| 0), -1)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_set_config(pio: PIO, sm: c_uint, config: *const pio_sm_config) {
+    let pio = unsafe { instance(pio) };
+    let config = unsafe { config.as_ref() }.map(SmConfig::from).unwrap_or_else(|| pio_panic_str("NULL pio_sm_config"));
+    pio.check(pio.pio.sm(sm as u16).set_config(&config), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_exec(pio: PIO, sm: c_uint, instr: c_uint) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).exec(instr as u16, false), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_exec_wait_blocking(pio: PIO, sm: c_uint, instr: c_uint) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).exec(instr as u16, true), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_clear_fifos(pio: PIO, sm: c_uint) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).clear_fifos(), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_set_clkdiv_int_frac(pio: PIO, sm: c_uint, div_int: u16, div_frac: u8) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).set_clkdiv_int_frac(ClkDiv::from((div_int, div_frac))), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_set_clkdiv(pio: PIO, sm: c_uint, div: f32) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).set_clkdiv(div as f64), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_set_pins(pio: PIO, sm: c_uint, pin_values: u32) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).set_pins(pin_values), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_set_pins_with_mask(pio: PIO, sm: c_uint, pin_values: u32, pin_mask: u32) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).set_pins_with_mask(pin_values, pin_mask), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_set_pindirs_with_mask(pio: PIO, sm: c_uint, pin_dirs: u32, pin_mask: u32) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).set_pindirs_with_mask(pin_dirs, pin_mask), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_set_consecutive_pindirs(pio: PIO, sm: c_uint, pin_base: c_uint, pin_count: c_uint, is_out: bool) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).set_consecutive_pindirs(pin_base, pin_count, is_out), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_set_enabled(pio: PIO, sm: c_uint, enabled: bool) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).set_enabled(enabled), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_set_sm_mask_enabled(pio: PIO, mask: u32, enabled: bool) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm_set_enabled_mask(mask as u16, enabled), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_restart(pio: PIO, sm: c_uint) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).restart(), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_restart_sm_mask(pio: PIO, mask: u32) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm_restart_mask(mask as u16), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_clkdiv_restart(pio: PIO, sm: c_uint) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).clkdiv_restart(), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_clkdiv_restart_sm_mask(pio: PIO, mask: u32) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm_clkdiv_restart_mask(mask as u16), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_enable_sm_mask_in_sync(pio: PIO, mask: u32) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm_enable_sync(mask as u16), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_set_dmactrl(pio: PIO, sm: c_uint, is_tx: bool, ctrl: u32) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).set_dmactrl(is_tx, ctrl), ());
+}
+
+// FIFOs
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_put(pio: PIO, sm: c_uint, data: u32) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).put(data, false), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_put_blocking(pio: PIO, sm: c_uint, data: u32) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).put(data, true), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_get(pio: PIO, sm: c_uint) -> u32 {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).get(false), 0)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_get_blocking(pio: PIO, sm: c_uint) -> u32 {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).get(true), 0)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_is_rx_fifo_empty(pio: PIO, sm: c_uint) -> bool {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).is_rx_fifo_empty(), true)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_is_rx_fifo_full(pio: PIO, sm: c_uint) -> bool {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).is_rx_fifo_full(), false)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_get_rx_fifo_level(pio: PIO, sm: c_uint) -> c_uint {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).get_rx_fifo_level(), 0)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_is_tx_fifo_empty(pio: PIO, sm: c_uint) -> bool {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).is_tx_fifo_empty(), true)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_is_tx_fifo_full(pio: PIO, sm: c_uint) -> bool {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).is_tx_fifo_full(), false)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_get_tx_fifo_level(pio: PIO, sm: c_uint) -> c_uint {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).get_tx_fifo_level(), 0)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_drain_tx_fifo(pio: PIO, sm: c_uint) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.sm(sm as u16).drain_tx_fifo(), ());
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_config_xfer(pio: PIO, sm: c_uint, dir: c_uint, buf_size: c_uint, buf_count: c_uint) -> c_int {
+    let pio = unsafe { instance(pio) };
+    let dir = if dir == XferDir::ToSm as c_uint { XferDir::ToSm } else { XferDir::FromSm };
+    pio.check(pio.pio.sm_config_xfer(sm as u16, dir, buf_size, buf_count).map(|_| 0), -1)
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_sm_xfer_data(pio: PIO, sm: c_uint, dir: c_uint, data_bytes: c_uint, data: *mut c_void) -> c_int {
+    let pio = unsafe { instance(pio) };
+    let dir = if dir == XferDir::ToSm as c_uint { XferDir::ToSm } else { XferDir::FromSm };
+    // Safety: sm_xfer_data() only passes the pointer through to the kernel, which is what piolib does with it.
+    let data = unsafe { &*(data as *const u8) };
+    pio.check(pio.pio.sm_xfer_data(sm as u16, dir, data_bytes, data).map(|_| 0), -1)
+}
+
+// GPIOs
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn pio_gpio_init(pio: PIO, pin: c_uint) {
+    let pio = unsafe { instance(pio) };
+    pio.check(pio.pio.pio_gpio_init(pin as u16), ());
+}
+
+fn current() -> &'static PioInstance {
+    // Safety: pio_get_current() never returns NULL, and the PIO lives until pio_close(), which unselects it.
+    unsafe { &*pio_get_current() }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn gpio_init(gpio: c_uint) {
+    let pio = current();
+    pio.check(pio.pio.gpio_init(gpio as u16), ());
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn gpio_set_function(gpio: c_uint, func: c_uint) {
+    let func = match func {
+        0 => Function::XIP,  1 => Function::SPI,  2 => Function::UART, 3 => Function::I2C,  4 => Function::PWM,
+        5 => Function::SIO,  6 => Function::PIO0, 7 => Function::PIO1, 8 => Function::GPCK, 9 => Function::USB,
+        _ => Function::NULL,
+    };
+    let pio = current();
+    pio.check(pio.pio.gpio_set_function(gpio as u16, func), ());
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn gpio_set_pulls(gpio: c_uint, up: bool, down: bool) {
+    let pio = current();
+    pio.check(pio.pio.set_pulls(gpio as u16, up, down), ());
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn gpio_set_outover(gpio: c_uint, value: c_uint) {
+    let pio = current();
+    pio.check(pio.pio.gpio_set_outover(gpio as u16, value as u16), ());
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn gpio_set_inover(gpio: c_uint, value: c_uint) {
+    let pio = current();
+    pio.check(pio.pio.gpio_set_inover(gpio as u16, value as u16), ());
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn gpio_set_oeover(gpio: c_uint, value: c_uint) {
+    let pio = current();
+    pio.check(pio.pio.gpio_set_oeover(gpio as u16, value as u16), ());
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn gpio_set_input_enabled(gpio: c_uint, enabled: bool) {
+    let pio = current();
+    pio.check(pio.pio.gpio_set_input_enabled(gpio as u16, enabled), ());
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn gpio_set_drive_strength(gpio: c_uint, drive: c_uint) {
+    let drive = match drive {
+        0 => DriveStrength::_2MA, 1 => DriveStrength::_4MA, 2 => DriveStrength::_8MA, _ => DriveStrength::_12MA,
+    };
+    let pio = current();
+    pio.check(pio.pio.gpio_set_drive_strength(gpio as u16, drive), ());
+}
+
+// SM configs
+
+#[unsafe(no_mangle)]
+pub extern "C" fn pio_get_default_sm_config() -> pio_sm_config {
+    SmConfig::default().into()
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_out_pins(c: *mut pio_sm_config, out_base: c_uint, out_count: c_uint) {
+    unsafe { update_config(c, |config| config.set_out_pins(out_base, out_count)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_set_pins(c: *mut pio_sm_config, set_base: c_uint, set_count: c_uint) {
+    unsafe { update_config(c, |config| config.set_set_pins(set_base, set_count)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_in_pins(c: *mut pio_sm_config, in_base: c_uint) {
+    unsafe { update_config(c, |config| config.set_in_pins(in_base)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_sideset_pins(c: *mut pio_sm_config, sideset_base: c_uint) {
+    unsafe { update_config(c, |config| config.set_sideset_pins(sideset_base)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_sideset(c: *mut pio_sm_config, bit_count: c_uint, optional: bool, pindirs: bool) {
+    unsafe { update_config(c, |config| config.set_sideset(bit_count, optional, pindirs)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_clkdiv_int_frac(c: *mut pio_sm_config, div_int: u16, div_frac: u8) {
+    unsafe { update_config(c, |config| config.set_clkdiv_int_frac(ClkDiv::from((div_int, div_frac)))) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_clkdiv(c: *mut pio_sm_config, div: f32) {
+    unsafe { update_config(c, |config| config.set_clkdiv(div as f64)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_wrap(c: *mut pio_sm_config, wrap_target: c_uint, wrap: c_uint) {
+    unsafe { update_config(c, |config| config.set_wrap(wrap_target, wrap)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_jmp_pin(c: *mut pio_sm_config, pin: c_uint) {
+    unsafe { update_config(c, |config| config.set_jmp_pin(pin)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_in_shift(c: *mut pio_sm_config, shift_right: bool, autopush: bool, push_threshold: c_uint) {
+    unsafe { update_config(c, |config| config.set_in_shift(shift_right, autopush, push_threshold)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_out_shift(c: *mut pio_sm_config, shift_right: bool, autopull: bool, pull_threshold: c_uint) {
+    unsafe { update_config(c, |config| config.set_out_shift(shift_right, autopull, pull_threshold)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_fifo_join(c: *mut pio_sm_config, join: c_uint) {
+    let join = match join {
+        1 => PioFifoJoin::Tx,
+        2 => PioFifoJoin::Rx,
+        _ => PioFifoJoin::None,
+    };
+    unsafe { update_config(c, |config| config.set_fifo_join(join)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_out_special(c: *mut pio_sm_config, sticky: bool, has_enable_pin: bool, enable_pin_index: c_uint) {
+    unsafe { update_config(c, |config| config.set_out_special(sticky, has_enable_pin, enable_pin_index)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sm_config_set_mov_status(c: *mut pio_sm_config, status_sel: c_uint, status_n: c_uint) {
+    let status_sel = if status_sel == 0 { PioMovStatus::TxLessThan } else { PioMovStatus::RxLessThan };
+    unsafe { update_config(c, |config| config.set_mov_status(status_sel, status_n)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_round_trips() {
+        let mut c = pio_get_default_sm_config();
+        unsafe {
+            sm_config_set_out_pins(&mut c, 4, 2);
+            sm_config_set_wrap(&mut c, 3, 7);
+            sm_config_set_clkdiv_int_frac(&mut c, 5, 128);
+        }
+        let config = SmConfig::from(&c);
+        assert_eq!(config.get_out_pins(), (4, 2));
+        assert_eq!(config.get_wrap(), (3, 7));
+        assert_eq!(config.get_clkdiv(), ClkDiv::from((5, 128)));
+    }
+}
@@ -0,0 +1,397 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Playing waveforms out of a run of consecutive pins, one sample per SM clock, the other way round from capture:
+//
+//     let waveform = Waveform::from_csv(&std::fs::read_to_string("stimulus.csv")?)?;
+//     let generator = PatternGenerator::new(pio.sm_claim_unused()?, 4, waveform.channels.len() as u32)?
+//         .with_sample_rate(1_000_000.0)?;
+//     generator.play(&waveform.samples(generator.sample_rate()))?;
+//     generator.wait()?;
+//
+// Waveforms are a list of (time, levels) changes, and come from:
+//
+//   - CSV: a header naming the channels after the time column, then rows of time (in seconds) and a 0 or 1 for each
+//     channel: `time,clk,data` / `0,0,1` / `0.5e-6,1,1`.
+//   - VCD: every 1 bit wire becomes a channel, in the order they're declared.
+//   - JSON: `{ "channels": ["clk", "data"], "events": [[0, 2], [0.5e-6, 3]] }`, with channel n as bit n of the
+//     levels.
+//
+// Once the samples run out the SM stalls on its autopull, leaving the pins at the last sample's levels.
+
+use crate::{pio_clock_hz, ClkDiv, Error, PioFifoJoin, PioProgram, SmConfig, StateMachine, XferDir};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Waveform {
+    pub channels: Vec<String>,
+    pub events: Vec<(f64, u32)>,   // (seconds, levels), with channel n as bit n. Sorted by time.
+}
+
+fn bad(what: &str, line: usize, problem: impl std::fmt::Display) -> Error {
+    Error::ParamErr { param: "waveform", should_be: format!("valid {what} (line {line}: {problem})") }
+}
+
+impl Waveform {
+    pub fn from_csv(csv: &str) -> Result<Waveform, Error> {
+        let mut lines = csv.lines().enumerate().filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+        let (_, header) = lines.next().ok_or_else(|| bad("CSV", 1, "no header"))?;
+        let channels: Vec<String> = header.split(',').skip(1).map(|name| name.trim().to_string()).collect();
+        let mut events = Vec::new();
+        for (i, line) in lines {
+            let mut columns = line.split(',').map(str::trim);
+            let time = columns.next().and_then(|time| time.parse::<f64>().ok()).ok_or_else(|| bad("CSV", i + 1, "bad time"))?;
+            let mut levels = 0;
+            for n in 0..channels.len() {
+                match columns.next() {
+                    Some("0") => {},
+                    Some("1") => levels |= 1 << n,
+                    other     => Err(bad("CSV", i + 1, format!("{other:?} isn't 0 or 1")))?,
+                }
+            }
+            events.push((time, levels));
+        }
+        Waveform::new(channels, events)
+    }
+
+    pub fn from_vcd(vcd: &str) -> Result<Waveform, Error> {
+        let mut timescale = 1e-9;
+        let mut ids: Vec<String> = Vec::new();
+        let mut channels = Vec::new();
+        let mut events: Vec<(f64, u32)> = Vec::new();
+        let mut levels = 0;
+        let mut time = 0.0;
+        let mut words = vcd.lines().enumerate().flat_map(|(i, line)| line.split_whitespace().map(move |word| (i + 1, word))).peekable();
+        while let Some((line, word)) = words.next() {
+            match word {
+                "$timescale" => {
+                    let spec: String = std::iter::from_fn(|| words.next_if(|&(_, w)| w != "$end").map(|(_, w)| w)).collect();
+                    let split = spec.find(|c: char| !c.is_ascii_digit()).unwrap_or(spec.len());
+                    let unit = match &spec[split..] {
+                        "s" => 1.0, "ms" => 1e-3, "us" => 1e-6, "ns" => 1e-9, "ps" => 1e-12, "fs" => 1e-15,
+                        unit => Err(bad("VCD", line, format!("unknown timescale unit {unit:?}")))?,
+                    };
+                    timescale = spec[..split].parse::<f64>().map_err(|_| bad("VCD", line, "bad timescale"))? * unit;
+                },
+                "$var" => {
+                    let var: Vec<&str> = std::iter::from_fn(|| words.next_if(|&(_, w)| w != "$end").map(|(_, w)| w)).collect();
+                    let [_kind, width, id, name, ..] = var[..] else { Err(bad("VCD", line, "short $var"))? };
+                    if width == "1" {
+                        ids.push(id.to_string());
+                        channels.push(name.to_string());
+                    }
+                },
+                "$dumpvars" | "$end" => {},
+                word if word.starts_with('$') => { // Skip anything else up to its $end
+                    words.by_ref().find(|&(_, w)| w == "$end");
+                },
+                word if word.starts_with('#') => {
+                    let t = word[1..].parse::<u64>().map_err(|_| bad("VCD", line, "bad time"))? as f64 * timescale;
+                    if t != time {
+                        events.push((time, levels));
+                        time = t;
+                    }
+                },
+                word if word.starts_with(['b', 'B', 'r', 'R']) => { words.next(); }, // Vectors and reals aren't channels
+                word => {
+                    let (value, id) = word.split_at(1);
+                    if let Some(n) = ids.iter().position(|i| i == id) {
+                        match value {
+                            "1"     => levels |= 1 << n,
+                            _       => levels &= !(1 << n), // x and z come out low
+                        }
+                    }
+                },
+            }
+        }
+        events.push((time, levels));
+        events.dedup_by(|b, a| a.1 == b.1); // Keep the first of each run of identical levels
+        Waveform::new(channels, events)
+    }
+
+    pub fn from_json(json: &str) -> Result<Waveform, Error> {
+        let value = json::parse(json).map_err(|(offset, problem)| bad("JSON", json[..offset].lines().count().max(1), problem))?;
+        let field = |name| value.field(name).ok_or_else(|| bad("JSON", 1, format!("no \"{name}\"")));
+        let channels = field("channels")?.array().ok_or_else(|| bad("JSON", 1, "channels isn't an array"))?
+            .iter().map(|name| name.string().map(str::to_string).ok_or_else(|| bad("JSON", 1, "channel names should be strings")))
+            .collect::<Result<_, _>>()?;
+        let events = field("events")?.array().ok_or_else(|| bad("JSON", 1, "events isn't an array"))?
+            .iter().map(|event| match event.array() {
+                Some([time, levels]) => Ok((time.number().ok_or_else(|| bad("JSON", 1, "bad time"))?,
+                                            levels.number().ok_or_else(|| bad("JSON", 1, "bad levels"))? as u32)),
+                _ => Err(bad("JSON", 1, "events should be [time, levels]")),
+            }).collect::<Result<_, _>>()?;
+        Waveform::new(channels, events)
+    }
+
+    fn new(channels: Vec<String>, mut events: Vec<(f64, u32)>) -> Result<Waveform, Error> {
+        if channels.is_empty() || channels.len() > 32 {
+            Err(Error::ParamErr { param: "channels", should_be: "1..=32".to_string() })?;
+        }
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Waveform { channels, events })
+    }
+
+    // Seconds from 0 to the last change.
+    pub fn duration(&self) -> f64 {
+        self.events.last().map(|&(time, _)| time).unwrap_or(0.0)
+    }
+
+    // The levels at each tick of a `rate` Hz clock from time 0 up to and including the last change. Channels start out
+    // low if the first event is after 0.
+    pub fn samples(&self, rate: f64) -> Vec<u32> {
+        let count = (self.duration() * rate).round() as usize + 1;
+        let mut events = self.events.iter().peekable();
+        let mut levels = 0;
+        (0..count).map(|i| {
+            while let Some(&(_, next)) = events.next_if(|&&(time, _)| (time * rate).round() <= i as f64) {
+                levels = next;
+            }
+            levels
+        }).collect()
+    }
+}
+
+pub struct PatternGenerator<'a> {
+    sm: StateMachine<'a>,
+    base_pin: u32,
+    pin_count: u32,
+    clkdiv: ClkDiv,
+    dma: bool,
+}
+
+impl<'a> PatternGenerator<'a> {
+    pub fn new(sm: StateMachine<'a>, base_pin: u32, pin_count: u32) -> Result<PatternGenerator<'a>, Error> {
+        if !(1..=32).contains(&pin_count) {
+            Err(Error::ParamErr { param: "pin_count", should_be: "1..=32".to_string() })?;
+        }
+        if base_pin + pin_count > 32 {
+            Err(Error::ParamErr { param: "base_pin", should_be: format!("<= {}", 32 - pin_count) })?;
+        }
+        Ok(PatternGenerator { sm, base_pin, pin_count, clkdiv: ClkDiv::from((1, 0)), dma: true })
+    }
+
+    pub fn with_sample_rate(mut self, hz: f64) -> Result<Self, Error> {
+        self.clkdiv = ClkDiv::for_frequency(pio_clock_hz() as f64, hz)?;
+        Ok(self)
+    }
+
+    // Without DMA every word goes through a put() ioctl, which won't keep up with much more than a few MHz.
+    pub fn with_dma(mut self, dma: bool) -> Self {
+        self.dma = dma;
+        self
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.clkdiv.actual_frequency(pio_clock_hz() as f64)
+    }
+
+    pub fn into_inner(self) -> StateMachine<'a> {
+        self.sm
+    }
+
+    // As many whole samples as fit in a word.
+    fn pull_threshold(&self) -> u32 {
+        32 / self.pin_count * self.pin_count
+    }
+
+    // Starts the SM and streams `samples` (channel n in bit n) out to it, returning once they've all been queued. The
+    // last word is padded out by repeating the last sample.
+    pub fn play(&self, samples: &[u32]) -> Result<(), Error> {
+        let pio = self.sm.pio();
+        let program = pio.load_program(&PioProgram::assemble(&format!(".program generator\n.wrap_target\nout pins, {}\n.wrap\n", self.pin_count))?)?;
+        let (wrap_target, wrap) = program.wrap();
+        let config = SmConfig::default()
+            .set_out_pins(self.base_pin, self.pin_count)?
+            .set_out_shift(true, true, self.pull_threshold())?
+            .set_fifo_join(PioFifoJoin::Tx)?
+            .set_clkdiv_int_frac(self.clkdiv)?
+            .set_wrap(wrap_target, wrap)?;
+        self.sm.set_enabled(false)?;
+        for pin in self.base_pin..self.base_pin + self.pin_count {
+            pio.pio_gpio_init(pin as u16)?;
+        }
+        self.sm.set_pins_with_mask(samples.first().copied().unwrap_or(0) << self.base_pin, (((1_u64 << self.pin_count) - 1) as u32) << self.base_pin)?;
+        self.sm.set_consecutive_pindirs(self.base_pin, self.pin_count, true)?;
+        self.sm.init(program.offset(), &config)?;
+
+        let per_word = (self.pull_threshold() / self.pin_count) as usize;
+        let last = samples.last().copied().unwrap_or(0);
+        let words: Vec<u32> = samples.chunks(per_word).map(|chunk| {
+            (0..per_word).fold(0, |word, i| word | (chunk.get(i).copied().unwrap_or(last) as u64) << (i as u32 * self.pin_count)) as u32
+        }).collect();
+        if words.is_empty() {
+            return Ok(());
+        }
+        if self.dma {
+            let bytes = size_of_val(&words[..]) as u32;
+            pio.sm_config_xfer(self.sm.index(), XferDir::ToSm, bytes.min(64 * 1024), 4)?;
+            self.sm.set_enabled(true)?;
+            pio.sm_xfer_data(self.sm.index(), XferDir::ToSm, bytes, &words[0])?;
+        } else {
+            self.sm.set_enabled(true)?;
+            for word in words {
+                self.sm.put(word, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Waits for the TX FIFO to run dry, and then for the SM to get through the last word.
+    pub fn wait(&self) -> Result<(), Error> {
+        while !self.sm.is_tx_fifo_empty()? {
+            std::thread::sleep(std::time::Duration::from_micros(100));
+        }
+        std::thread::sleep(std::time::Duration::from_secs_f64(32.0 / self.sample_rate()));
+        Ok(())
+    }
+}
+
+// Just enough JSON for waveform files.
+mod json {
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Value {
+        Null,
+        Bool(bool),
+        Number(f64),
+        String(String),
+        Array(Vec<Value>),
+        Object(Vec<(String, Value)>),
+    }
+
+    impl Value {
+        pub fn field(&self, name: &str) -> Option<&Value> {
+            match self {
+                Value::Object(fields) => fields.iter().find(|(n, _)| n == name).map(|(_, value)| value),
+                _                     => None,
+            }
+        }
+        pub fn array(&self) -> Option<&[Value]> {
+            match self { Value::Array(values) => Some(values), _ => None }
+        }
+        pub fn string(&self) -> Option<&str> {
+            match self { Value::String(s) => Some(s), _ => None }
+        }
+        pub fn number(&self) -> Option<f64> {
+            match self { Value::Number(n) => Some(*n), _ => None }
+        }
+    }
+
+    type Result<T> = std::result::Result<T, (usize, String)>;
+
+    struct Parser<'a> {
+        s: &'a str,
+        pos: usize,
+    }
+
+    // On failure, the byte offset and what went wrong.
+    pub fn parse(s: &str) -> Result<Value> {
+        let mut parser = Parser { s, pos: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != s.len() { Err(parser.error("trailing junk"))? }
+        Ok(value)
+    }
+
+    impl Parser<'_> {
+        fn error(&self, problem: &str) -> (usize, String) {
+            (self.pos, problem.to_string())
+        }
+
+        fn skip_whitespace(&mut self) {
+            self.pos += self.s[self.pos..].len() - self.s[self.pos..].trim_start().len();
+        }
+
+        fn eat(&mut self, token: &str) -> bool {
+            self.skip_whitespace();
+            let found = self.s[self.pos..].starts_with(token);
+            if found { self.pos += token.len() }
+            found
+        }
+
+        fn list<T>(&mut self, end: &str, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+            let mut items = Vec::new();
+            if self.eat(end) { return Ok(items) }
+            loop {
+                items.push(item(self)?);
+                if self.eat(end) { return Ok(items) }
+                if !self.eat(",") { Err(self.error("expected ','"))? }
+            }
+        }
+
+        fn value(&mut self) -> Result<Value> {
+            self.skip_whitespace();
+            Ok(match () {
+                _ if self.eat("null")  => Value::Null,
+                _ if self.eat("true")  => Value::Bool(true),
+                _ if self.eat("false") => Value::Bool(false),
+                _ if self.eat("[")     => Value::Array(self.list("]", Self::value)?),
+                _ if self.eat("{")     => Value::Object(self.list("}", |p| {
+                    let name = p.string()?;
+                    if !p.eat(":") { Err(p.error("expected ':'"))? }
+                    Ok((name, p.value()?))
+                })?),
+                _ if self.s[self.pos..].starts_with('"') => Value::String(self.string()?),
+                _ => {
+                    let len = self.s[self.pos..].find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c))).unwrap_or(self.s.len() - self.pos);
+                    let number = self.s[self.pos..self.pos + len].parse().map_err(|_| self.error("expected a value"))?;
+                    self.pos += len;
+                    Value::Number(number)
+                },
+            })
+        }
+
+        fn string(&mut self) -> Result<String> {
+            if !self.eat("\"") { Err(self.error("expected a string"))? }
+            let mut out = String::new();
+            let mut chars = self.s[self.pos..].char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"'  => { self.pos += i + 1; return Ok(out) },
+                    '\\' => match chars.next().map(|(_, c)| c) {
+                        Some('n') => out.push('\n'),
+                        Some('t') => out.push('\t'),
+                        Some('r') => out.push('\r'),
+                        Some('u') => {
+                            let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                            out.push(u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32).unwrap_or('\u{fffd}'));
+                        },
+                        Some(c)   => out.push(c),
+                        None      => break,
+                    },
+                    c    => out.push(c),
+                }
+            }
+            Err(self.error("unterminated string"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    #[test]
+    fn waveform_formats() {
+        let csv = Waveform::from_csv("time,clk,data\n0,0,1\n2e-6,1,1\n3e-6,0,0\n").unwrap();
+        let vcd = Waveform::from_vcd("$timescale 1 us $end\n$scope module top $end\n$var wire 1 ! clk $end\n\
+                                      $var wire 1 \" data $end\n$var wire 8 # bus $end\n$upscope $end\n$enddefinitions $end\n\
+                                      #0\n$dumpvars\n0!\n1\"\nb101 #\n$end\n#2\n1!\n#3\n0!\n0\"\n").unwrap();
+        let json = Waveform::from_json(r#"{ "channels": ["clk", "data"], "events": [[0, 2], [2e-6, 3], [3e-6, 0]] }"#).unwrap();
+        assert_eq!(csv, json);
+        assert_eq!(vcd.channels, json.channels);
+        assert_eq!(vcd.samples(1e6), vec![2, 2, 3, 0]);
+        assert_eq!(csv.samples(2e6), vec![2, 2, 2, 2, 3, 3, 0]);
+    }
+
+    #[test]
+    fn plays_samples() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let generator = PatternGenerator::new(pio.sm_claim(0).unwrap(), 8, 3).unwrap().with_dma(false);
+        let samples: Vec<u32> = (0..30).map(|i| i % 8).collect();
+        generator.play(&samples).unwrap();
+        backend.emulator().run(100);
+        assert_eq!(backend.emulator().pins() >> 8 & 7, 29 % 8);
+    }
+}
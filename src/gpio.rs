@@ -29,3 +29,12 @@ pub enum DriveStrength {
     /**< 8 mA nominal drive strength */  _8MA = 2,
     /**< 12 mA nominal drive strength */ _12MA = 3,
 }
+
+// For gpio_set_outover()/gpio_set_inover()/gpio_set_oeover()
+#[repr(u16)]
+pub enum Override {
+    Normal = 0,
+    Invert = 1,
+    Low    = 2,
+    High   = 3,
+}
@@ -0,0 +1,130 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// embedded-hal GPIO adapters running on top of a claimed (but otherwise idle) state machine. Outputs go through
+// `set_pins_with_mask`, inputs are read by exec'ing `mov isr, pins; push` and pulling the result out of the RX FIFO.
+
+use embedded_hal::digital::{ErrorKind, ErrorType, InputPin, OutputPin};
+
+use crate::{Error, SmConfig, StateMachine, GPIOS_MASK};
+
+const MOV_ISR_PINS : u16 = 0xa0c0; // mov isr, pins
+const PUSH_NOBLOCK : u16 = 0x8000; // push noblock
+
+impl embedded_hal::digital::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+pub struct PioGpio<'a> {
+    sm: StateMachine<'a>,
+}
+
+impl<'a> PioGpio<'a> {
+    // Takes over the state machine. It is left disabled and only used to exec instructions.
+    pub fn new(sm: StateMachine<'a>) -> Result<PioGpio<'a>, Error> {
+        sm.set_enabled(false)?;
+        sm.set_config(&SmConfig::default().set_in_pins(0)?)?;
+        sm.clear_fifos()?;
+        Ok(PioGpio { sm })
+    }
+
+    pub fn output_pin(&self, pin: u16) -> Result<PioOutputPin<'_, 'a>, Error> {
+        self.sm.pio().pio_gpio_init(pin)?;
+        self.sm.set_consecutive_pindirs(pin as u32, 1, true)?;
+        Ok(PioOutputPin { gpio: self, mask: 1 << pin })
+    }
+
+    pub fn input_pin(&self, pin: u16) -> Result<PioInputPin<'_, 'a>, Error> {
+        self.sm.pio().gpio_set_input_enabled(pin, true)?;
+        Ok(PioInputPin { gpio: self, mask: 1 << pin })
+    }
+
+    // Samples all the GPIO levels at once.
+    pub fn levels(&self) -> Result<u32, Error> {
+        self.sm.exec(MOV_ISR_PINS, false)?;
+        self.sm.exec(PUSH_NOBLOCK, false)?;
+        Ok(self.sm.get(true)? & GPIOS_MASK)
+    }
+
+    pub fn into_inner(self) -> StateMachine<'a> {
+        self.sm
+    }
+}
+
+pub struct PioOutputPin<'g, 'a> {
+    gpio: &'g PioGpio<'a>,
+    mask: u32,
+}
+
+impl ErrorType for PioOutputPin<'_, '_> {
+    type Error = Error;
+}
+
+impl OutputPin for PioOutputPin<'_, '_> {
+    fn set_low(&mut self) -> Result<(), Error> {
+        self.gpio.sm.set_pins_with_mask(0, self.mask)
+    }
+
+    fn set_high(&mut self) -> Result<(), Error> {
+        self.gpio.sm.set_pins_with_mask(self.mask, self.mask)
+    }
+}
+
+pub struct PioInputPin<'g, 'a> {
+    gpio: &'g PioGpio<'a>,
+    mask: u32,
+}
+
+impl ErrorType for PioInputPin<'_, '_> {
+    type Error = Error;
+}
+
+impl InputPin for PioInputPin<'_, '_> {
+    fn is_high(&mut self) -> Result<bool, Error> {
+        Ok(self.gpio.levels()? & self.mask != 0)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Error> {
+        Ok(!self.is_high()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal::digital::{InputPin, OutputPin};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    #[test]
+    fn pins() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let gpio = PioGpio::new(pio.sm_claim(1).unwrap()).unwrap();
+
+        let mut led = gpio.output_pin(5).unwrap();
+        assert_eq!(backend.emulator().pindirs() & 1 << 5, 1 << 5);
+        led.set_high().unwrap();
+        assert_eq!(backend.emulator().pins() & 1 << 5, 1 << 5);
+        led.set_low().unwrap();
+        assert_eq!(backend.emulator().pins() & 1 << 5, 0);
+
+        let mut button = gpio.input_pin(6).unwrap();
+        assert_eq!(backend.emulator().pindirs() & 1 << 6, 0); // Inputs aren't driven
+        backend.emulator().set_input(6, true);
+        assert!(button.is_high().unwrap());
+        backend.emulator().set_input(6, false);
+        assert!(button.is_low().unwrap());
+
+        led.set_high().unwrap();
+        backend.emulator().set_inputs(0b101 << 20, 0b111 << 20);
+        let levels = gpio.levels().unwrap();
+        assert_eq!((levels & 1 << 5, levels >> 20 & 0b111, levels & 1 << 6), (1 << 5, 0b101, 0));
+        // Reading doesn't leave anything behind in the FIFO, or start the SM.
+        let sm = gpio.into_inner();
+        assert_eq!(sm.get_rx_fifo_level().unwrap(), 0);
+        assert!(!backend.emulator().sm(1).enabled);
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Typed PIO instructions: encoding, decoding and pioasm style disassembly (via Display).
+//
+// The delay/side-set field is shared, so both encoding and decoding need to know the side-set configuration of the
+// program the instruction belongs to (SideSet::default() for programs that don't use side-set).
+
+use std::fmt;
+
+use crate::{Chip, Error, PioProgram, GPIO_COUNT};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SideSet {
+    pub count: u8,      // Number of side-set pins, not including the enable bit (like pioasm's `.side_set count`)
+    pub optional: bool, // `.side_set count opt`
+    pub pindirs: bool,  // `.side_set count pindirs`
+}
+
+impl SideSet {
+    pub fn new(count: u8, optional: bool, pindirs: bool) -> SideSet {
+        SideSet { count, optional, pindirs }
+    }
+
+    // Bits taken from the delay/side-set field. This is what PINCTRL_SIDESET_COUNT wants.
+    pub fn bits(&self) -> u8 {
+        self.count + self.optional as u8
+    }
+
+    pub fn delay_bits(&self) -> u8 {
+        5 - self.bits()
+    }
+
+    pub fn max_delay(&self) -> u8 {
+        (1 << self.delay_bits()) - 1
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JmpCondition { Always = 0, XZero = 1, XPostDec = 2, YZero = 3, YPostDec = 4, XNotEqualY = 5, Pin = 6, NotOsrEmpty = 7 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitSource { Gpio = 0, Pin = 1, Irq = 2 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InSource { Pins = 0, X = 1, Y = 2, Null = 3, Isr = 6, Osr = 7 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutDestination { Pins = 0, X = 1, Y = 2, Null = 3, Pindirs = 4, Pc = 5, Isr = 6, Exec = 7 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovDestination { Pins = 0, X = 1, Y = 2, Exec = 4, Pc = 5, Isr = 6, Osr = 7 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovOp { None = 0, Invert = 1, Reverse = 2 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovSource { Pins = 0, X = 1, Y = 2, Null = 3, Status = 5, Isr = 6, Osr = 7 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetDestination { Pins = 0, X = 1, Y = 2, Pindirs = 4 }
+
+// IRQ indexes for `wait irq` and `irq`. `relative` adds the SM number (mod 4) to the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqIndex {
+    pub index: u8,
+    pub relative: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Jmp  { condition: JmpCondition, address: u8 },
+    Wait { polarity: bool, source: WaitSource, index: u8 }, // For WaitSource::Irq, index is IrqIndex::encode()d
+    In   { source: InSource, bit_count: u8 },               // bit_count is 1..=32
+    Out  { destination: OutDestination, bit_count: u8 },    // bit_count is 1..=32
+    Push { if_full: bool, block: bool },
+    Pull { if_empty: bool, block: bool },
+    Mov  { destination: MovDestination, op: MovOp, source: MovSource },
+    Irq  { clear: bool, wait: bool, index: IrqIndex },
+    Set  { destination: SetDestination, data: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub operation: Operation,
+    pub delay: u8,
+    pub side_set: Option<u8>,
+}
+
+impl IrqIndex {
+    pub fn new(index: u8) -> IrqIndex {
+        IrqIndex { index, relative: false }
+    }
+
+    pub fn rel(index: u8) -> IrqIndex {
+        IrqIndex { index, relative: true }
+    }
+
+    pub fn encode(&self) -> u8 {
+        self.index & 0x7 | if self.relative { 0x10 } else { 0 }
+    }
+
+    pub fn decode(bits: u8) -> IrqIndex {
+        IrqIndex { index: bits & 0x7, relative: bits & 0x10 != 0 }
+    }
+
+    // The IRQ flag actually affected when executed on state machine `sm`.
+    pub fn resolve(&self, sm: u16) -> u8 {
+        if self.relative { self.index & 0x4 | (self.index + sm as u8) & 0x3 } else { self.index }
+    }
+}
+
+fn bad_field(param: &'static str, should_be: String) -> Error {
+    Error::ParamErr { param, should_be }
+}
+
+impl Instruction {
+    pub fn new(operation: Operation) -> Instruction {
+        Instruction { operation, delay: 0, side_set: None }
+    }
+
+    pub fn nop() -> Instruction {
+        Instruction::new(Operation::Mov { destination: MovDestination::Y, op: MovOp::None, source: MovSource::Y })
+    }
+
+    pub fn delay(self, delay: u8) -> Instruction {
+        Instruction { delay, ..self }
+    }
+
+    pub fn side(self, value: u8) -> Instruction {
+        Instruction { side_set: Some(value), ..self }
+    }
+
+    pub fn encode(&self, side_set: SideSet) -> Result<u16, Error> {
+        use Operation::*;
+        let (opcode, arg1, arg2): (u16, u16, u16) = match self.operation {
+            Jmp { condition, address } => {
+                if address >= 32 { Err(bad_field("address", "< 32".to_string()))? }
+                (0b000, condition as u16, address as u16)
+            },
+            Wait { polarity, source, index } => {
+                if index >= 32 { Err(bad_field("index", "< 32".to_string()))? }
+                (0b001, (polarity as u16) << 2 | source as u16, index as u16)
+            },
+            In { source, bit_count } => {
+                if !(1..=32).contains(&bit_count) { Err(bad_field("bit_count", "in 1..=32".to_string()))? }
+                (0b010, source as u16, bit_count as u16 & 0x1f)
+            },
+            Out { destination, bit_count } => {
+                if !(1..=32).contains(&bit_count) { Err(bad_field("bit_count", "in 1..=32".to_string()))? }
+                (0b011, destination as u16, bit_count as u16 & 0x1f)
+            },
+            Push { if_full, block } => (0b100, (if_full as u16) << 1 | block as u16, 0),
+            Pull { if_empty, block } => (0b100, 0b100 | (if_empty as u16) << 1 | block as u16, 0),
+            Mov { destination, op, source } => (0b101, destination as u16, (op as u16) << 3 | source as u16),
+            Irq { clear, wait, index } => (0b110, (clear as u16) << 1 | wait as u16, index.encode() as u16),
+            Set { destination, data } => {
+                if data >= 32 { Err(bad_field("data", "< 32".to_string()))? }
+                (0b111, destination as u16, data as u16)
+            },
+        };
+        Ok(opcode << 13 | self.encode_delay_side_set(side_set)? << 8 | arg1 << 5 | arg2)
+    }
+
+    fn encode_delay_side_set(&self, side_set: SideSet) -> Result<u16, Error> {
+        if self.delay > side_set.max_delay() {
+            Err(bad_field("delay", format!("<= {}", side_set.max_delay())))?
+        }
+        let side = match (self.side_set, side_set.count, side_set.optional) {
+            (None, _, true)         => 0,
+            (None, 0, false)        => 0,
+            (None, _, false)        => Err(bad_field("side_set", "present (side-set is not optional)".to_string()))?,
+            (Some(_), 0, _)         => Err(bad_field("side_set", "absent (program has no side-set pins)".to_string()))?,
+            (Some(value), count, optional) => {
+                if value as u16 >= 1 << count { Err(bad_field("side_set", format!("< {}", 1 << count)))? }
+                (value as u16 | if optional { 1 << count } else { 0 }) << side_set.delay_bits()
+            },
+        };
+        Ok(side | self.delay as u16)
+    }
+
+    pub fn decode(instr: u16, side_set: SideSet) -> Result<Instruction, Error> {
+        use Operation::*;
+        let arg1 = (instr >> 5 & 0x7) as u8;
+        let arg2 = (instr & 0x1f) as u8;
+        let bad = || Error::ParamErr { param: "instr", should_be: format!("a valid PIO instruction, not {instr:#06x}") };
+        let operation = match instr >> 13 {
+            0b000 => Jmp { condition: jmp_condition(arg1), address: arg2 },
+            0b001 => Wait { polarity: arg1 & 0b100 != 0,
+                            source: match arg1 & 0b11 { 0 => WaitSource::Gpio, 1 => WaitSource::Pin, 2 => WaitSource::Irq, _ => Err(bad())? },
+                            index: arg2 },
+            0b010 => In { source: match arg1 { 0 => InSource::Pins, 1 => InSource::X, 2 => InSource::Y, 3 => InSource::Null,
+                                               6 => InSource::Isr, 7 => InSource::Osr, _ => Err(bad())? },
+                          bit_count: if arg2 == 0 { 32 } else { arg2 } },
+            0b011 => Out { destination: out_destination(arg1), bit_count: if arg2 == 0 { 32 } else { arg2 } },
+            0b100 if arg1 & 0b100 == 0 => Push { if_full: arg1 & 0b10 != 0, block: arg1 & 1 != 0 },
+            0b100 => Pull { if_empty: arg1 & 0b10 != 0, block: arg1 & 1 != 0 },
+            0b101 => Mov { destination: match arg1 { 0 => MovDestination::Pins, 1 => MovDestination::X, 2 => MovDestination::Y,
+                                                     4 => MovDestination::Exec, 5 => MovDestination::Pc, 6 => MovDestination::Isr,
+                                                     7 => MovDestination::Osr, _ => Err(bad())? },
+                           op: match arg2 >> 3 { 0 => MovOp::None, 1 => MovOp::Invert, 2 => MovOp::Reverse, _ => Err(bad())? },
+                           source: match arg2 & 0x7 { 0 => MovSource::Pins, 1 => MovSource::X, 2 => MovSource::Y, 3 => MovSource::Null,
+                                                      5 => MovSource::Status, 6 => MovSource::Isr, 7 => MovSource::Osr, _ => Err(bad())? } },
+            0b110 => {
+                if arg1 & 0b100 != 0 { Err(bad())? }
+                Irq { clear: arg1 & 0b10 != 0, wait: arg1 & 1 != 0, index: IrqIndex::decode(arg2) }
+            },
+            _ => Set { destination: match arg1 { 0 => SetDestination::Pins, 1 => SetDestination::X, 2 => SetDestination::Y,
+                                                 4 => SetDestination::Pindirs, _ => Err(bad())? },
+                       data: arg2 },
+        };
+        let field = (instr >> 8 & 0x1f) as u8;
+        let delay = field & side_set.max_delay();
+        let side = field >> side_set.delay_bits();
+        let side_set = match (side_set.count, side_set.optional) {
+            (0, _)         => None,
+            (count, true)  => if side >> count & 1 != 0 { Some(side & ((1 << count) - 1)) } else { None },
+            (_, false)     => Some(side),
+        };
+        Ok(Instruction { operation, delay, side_set })
+    }
+}
+
+fn jmp_condition(bits: u8) -> JmpCondition {
+    match bits & 0x7 {
+        0 => JmpCondition::Always,  1 => JmpCondition::XZero,      2 => JmpCondition::XPostDec, 3 => JmpCondition::YZero,
+        4 => JmpCondition::YPostDec, 5 => JmpCondition::XNotEqualY, 6 => JmpCondition::Pin,     _ => JmpCondition::NotOsrEmpty,
+    }
+}
+
+fn out_destination(bits: u8) -> OutDestination {
+    match bits & 0x7 {
+        0 => OutDestination::Pins,    1 => OutDestination::X,  2 => OutDestination::Y,   3 => OutDestination::Null,
+        4 => OutDestination::Pindirs, 5 => OutDestination::Pc, 6 => OutDestination::Isr, _ => OutDestination::Exec,
+    }
+}
+
+impl fmt::Display for IrqIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.index, if self.relative { " rel" } else { "" })
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Operation::*;
+        match *self {
+            Jmp { condition, address } => {
+                let condition = match condition {
+                    JmpCondition::Always => "",      JmpCondition::XZero => "!x, ",      JmpCondition::XPostDec => "x--, ",
+                    JmpCondition::YZero => "!y, ",   JmpCondition::YPostDec => "y--, ",  JmpCondition::XNotEqualY => "x!=y, ",
+                    JmpCondition::Pin => "pin, ",    JmpCondition::NotOsrEmpty => "!osre, ",
+                };
+                write!(f, "jmp {condition}{address}")
+            },
+            Wait { polarity, source: WaitSource::Irq, index } => write!(f, "wait {} irq {}", polarity as u8, IrqIndex::decode(index)),
+            Wait { polarity, source, index } => write!(f, "wait {} {} {index}", polarity as u8,
+                                                       if source == WaitSource::Gpio { "gpio" } else { "pin" }),
+            In { source, bit_count } => write!(f, "in {}, {bit_count}", match source {
+                InSource::Pins => "pins", InSource::X => "x", InSource::Y => "y", InSource::Null => "null",
+                InSource::Isr => "isr", InSource::Osr => "osr",
+            }),
+            Out { destination, bit_count } => write!(f, "out {}, {bit_count}", match destination {
+                OutDestination::Pins => "pins", OutDestination::X => "x", OutDestination::Y => "y", OutDestination::Null => "null",
+                OutDestination::Pindirs => "pindirs", OutDestination::Pc => "pc", OutDestination::Isr => "isr",
+                OutDestination::Exec => "exec",
+            }),
+            Push { if_full, block } => write!(f, "push{}{}", if if_full { " iffull" } else { "" }, if block { " block" } else { " noblock" }),
+            Pull { if_empty, block } => write!(f, "pull{}{}", if if_empty { " ifempty" } else { "" }, if block { " block" } else { " noblock" }),
+            Mov { destination: MovDestination::Y, op: MovOp::None, source: MovSource::Y } => write!(f, "nop"),
+            Mov { destination, op, source } => write!(f, "mov {}, {}{}", match destination {
+                MovDestination::Pins => "pins", MovDestination::X => "x", MovDestination::Y => "y", MovDestination::Exec => "exec",
+                MovDestination::Pc => "pc", MovDestination::Isr => "isr", MovDestination::Osr => "osr",
+            }, match op { MovOp::None => "", MovOp::Invert => "~", MovOp::Reverse => "::" }, match source {
+                MovSource::Pins => "pins", MovSource::X => "x", MovSource::Y => "y", MovSource::Null => "null",
+                MovSource::Status => "status", MovSource::Isr => "isr", MovSource::Osr => "osr",
+            }),
+            Irq { clear: true, index, .. } => write!(f, "irq clear {index}"),
+            Irq { wait: true, index, .. } => write!(f, "irq wait {index}"),
+            Irq { index, .. } => write!(f, "irq {index}"),
+            Set { destination, data } => write!(f, "set {}, {data}", match destination {
+                SetDestination::Pins => "pins", SetDestination::X => "x", SetDestination::Y => "y",
+                SetDestination::Pindirs => "pindirs",
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.operation)?;
+        if let Some(side) = self.side_set {
+            write!(f, " side {side}")?;
+        }
+        if self.delay != 0 {
+            write!(f, " [{}]", self.delay)?;
+        }
+        Ok(())
+    }
+}
+
+// Something in a program that will load and run on the RP1, but probably not the way it was meant to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramWarning {
+    pub index: usize,
+    pub instr: u16,
+    pub message: String,
+}
+
+impl fmt::Display for ProgramWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "instruction {} ({:#06x}): {}", self.index, self.instr, self.message)
+    }
+}
+
+// The RP1's PIO is version 0 (like the RP2040's) and only GPIOs 0-27 are wired to it. Its IRQ outputs go to the
+// RP1's own processor, which the Linux driver doesn't pass on.
+impl PioProgram {
+    // Fails on the first thing the RP1 can't run (PIO version 1 encodings, GPIOs it can't reach, jmps out of the
+    // program, a program that doesn't fit) and returns warnings for things that work but likely not as intended.
+    pub fn validate_for(&self, chip: &Chip) -> Result<Vec<ProgramWarning>, Error> {
+        if self.pio_version() > 0 {
+            Err(Error::ParamErr { param: "pio_version", should_be: "0 (the RP1's PIO doesn't have the version 1 extensions)".to_string() })?;
+        }
+        let max = self.origin().map(|origin| chip.instr_count.saturating_sub(origin as u16)).unwrap_or(chip.instr_count);
+        if self.len() > max as usize {
+            Err(Error::TooManyInstructions { instructions: self.len(), max })?;
+        }
+        if let Some((wrap_target, wrap)) = self.wrap() && (wrap_target as usize >= self.len() || wrap as usize >= self.len()) {
+            Err(Error::ParamErr { param: "wrap", should_be: format!("inside the program (< {})", self.len()) })?;
+        }
+
+        let side_set = self.side_set().unwrap_or_default();
+        let mut warnings = Vec::new();
+        for (index, &instr) in self.instructions().iter().enumerate() {
+            let bad = |reason: &str| Error::BadInstruction { index, instr, reason: reason.to_string() };
+            let arg1 = instr >> 5 & 0x7;
+            let arg2 = instr & 0x1f;
+            match instr >> 13 {
+                0b001 if arg1 & 0b11 == 0b11                     => Err(bad("`wait jmppin` needs PIO version 1"))?,
+                0b001 if arg1 & 0b11 == 0b10 && arg2 & 0x08 != 0 => Err(bad("`wait irq prev/next` needs PIO version 1"))?,
+                0b100 if arg2 & 0x10 != 0                        => Err(bad("`mov rxfifo[]` and `mov osr, rxfifo[]` need PIO version 1"))?,
+                0b100 if arg2 != 0                               => Err(bad("reserved bits are set in push/pull"))?,
+                0b101 if arg1 == 0b011                           => Err(bad("`mov pindirs` needs PIO version 1"))?,
+                0b110 if arg2 & 0x08 != 0                        => Err(bad("`irq prev/next` needs PIO version 1"))?,
+                _ => {},
+            }
+            let decoded = Instruction::decode(instr, side_set).map_err(|_| bad("not a valid PIO instruction"))?;
+            match decoded.operation {
+                Operation::Jmp { address, .. } if address as usize >= self.len() =>
+                    Err(bad(&format!("`{decoded}` jumps outside the program")))?,
+                Operation::Wait { source: WaitSource::Gpio, index: gpio, .. } if gpio as usize >= GPIO_COUNT =>
+                    Err(bad(&format!("`{decoded}` waits on GPIO {gpio}, but the RP1's PIO only reaches GPIOs 0-{}", GPIO_COUNT - 1)))?,
+                Operation::Irq { clear: false, wait, index: irq } if irq.index < 4 => warnings.push(ProgramWarning { index, instr,
+                    message: format!("`{decoded}` raises an IRQ that the RP1 doesn't route to Linux: only other SMs will see it{}",
+                                     if wait { ", so it waits until one of them clears it" } else { "" }) }),
+                _ => {},
+            }
+        }
+        Ok(warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(instructions: &[u16]) -> Result<Vec<ProgramWarning>, Error> {
+        PioProgram::new(instructions, None).validate_for(&Chip::new())
+    }
+
+    #[test]
+    fn rp1_restrictions() {
+        assert_eq!(validate(&[0xe001, 0x0000]).unwrap(), []);
+        assert!(matches!(validate(&[0xe001, 0x0005]), Err(Error::BadInstruction { index: 1, .. })));   // jmp 5
+        assert!(matches!(validate(&[0x209c]), Err(Error::BadInstruction { index: 0, .. })));           // wait 1 gpio 28
+        assert!(validate(&[0x209b]).is_ok());                                                          // wait 1 gpio 27
+        assert!(matches!(validate(&[0xa000, 0x8018]), Err(Error::BadInstruction { index: 1, .. })));   // mov rxfifo[0], isr
+        assert!(matches!(validate(&[0xa060]), Err(Error::BadInstruction { index: 0, .. })));           // mov pindirs, pins
+        assert!(matches!(validate(&[0xc008]), Err(Error::BadInstruction { index: 0, .. })));           // irq prev 0
+        assert!(validate(&[0x0000; 33]).is_err());
+        assert!(PioProgram::new(&[0xe001], None).with_pio_version(1).validate_for(&Chip::new()).is_err());
+        assert!(PioProgram::new(&[0xe001], None).with_wrap(0, 1).validate_for(&Chip::new()).is_err());
+    }
+
+    #[test]
+    fn irq_warnings() {
+        let warnings = validate(&[0xc020, 0xc004, 0xc041]).unwrap(); // irq wait 0, irq 4, irq clear 1
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].index, 0);
+    }
+}
@@ -3,27 +3,34 @@
 
 use libc::{c_ulong,_IOW,_IO,_IOWR};
 
-use crate::{SmConfig, INSTRUCTION_COUNT};
+use crate::SmConfig;
+
+// RP1_PIO_INSTRUCTION_COUNT from the kernel's rp1_pio_if.h. This is part of the ioctl ABI (the struct size is encoded
+// in the request number), so it's a limit on what the driver can be handed, not a property of any particular chip.
+pub(crate) const MAX_PROGRAM_INSTRS: usize = 32;
 
 #[repr(C)]
 pub(crate) struct AddProgramArgs {
     pub(crate) num_instrs: u16,
     pub(crate) origin:     u16,
-    pub(crate) instrs:     [u16; INSTRUCTION_COUNT as usize],
+    pub(crate) instrs:     [u16; MAX_PROGRAM_INSTRS],
 }
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct RemoveProgramArgs {
     pub(crate) num_instrs:  u16,
     pub(crate) origin:      u16,
 }
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmClaimArgs {
     pub(crate) mask: u16,
 }
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmInitArgs {
     pub(crate) sm:         u16,
     pub(crate) initial_pc: u16,
@@ -31,6 +38,7 @@ pub(crate) struct SmInitArgs {
 }
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmSetConfigArgs {
     pub(crate) sm:     u16,
     pub(crate) rsvd:   u16,
@@ -38,6 +46,7 @@ pub(crate) struct SmSetConfigArgs {
 }
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmExecArgs {
     pub(crate) sm:        u16,
     pub(crate) instr:     u16,
@@ -47,12 +56,14 @@ pub(crate) struct SmExecArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmClearFifosArgs {
     pub(crate) sm: u16,
 }
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmSetClkdivArgs {
     pub(crate) sm:        u16,
     pub(crate) div_int:   u16,
@@ -62,6 +73,7 @@ pub(crate) struct SmSetClkdivArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmSetPinsArgs {
     pub(crate) sm:      u16,
     pub(crate) rsvd:    u16,
@@ -71,6 +83,7 @@ pub(crate) struct SmSetPinsArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmSetPindirsArgs {
     pub(crate) sm:    u16,
     pub(crate) rsvd:  u16,
@@ -80,6 +93,7 @@ pub(crate) struct SmSetPindirsArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmSetEnabledArgs {
     pub(crate) mask:    u16,
     pub(crate) enable:  u8,
@@ -88,24 +102,28 @@ pub(crate) struct SmSetEnabledArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmRestartArgs {
     pub(crate) mask: u16,
 }
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmClkdivRestartArgs {
     pub(crate) mask: u16,
 }
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmEnableSyncArgs {
     pub(crate) mask: u16,
 }
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmPutArgs {
     pub(crate) sm:        u16,
     pub(crate) blocking:  u8,
@@ -115,6 +133,7 @@ pub(crate) struct SmPutArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmGetArgs {
     pub(crate) sm:        u16,
     pub(crate) blocking:  u8,
@@ -124,6 +143,7 @@ pub(crate) struct SmGetArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmSetDmactrlArgs {
     pub(crate) sm:     u16,
     pub(crate) is_tx:  u8,
@@ -133,6 +153,7 @@ pub(crate) struct SmSetDmactrlArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmFifoStateArgs {
     pub(crate) sm:     u16,
     pub(crate) tx:     u8,
@@ -144,12 +165,14 @@ pub(crate) struct SmFifoStateArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct GpioInitArgs {
     pub(crate) gpio: u16,
 }
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct GpioSetFunctionArgs {
     pub(crate) gpio: u16,
     pub(crate) func: u16,
@@ -157,6 +180,7 @@ pub(crate) struct GpioSetFunctionArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct GpioSetPullsArgs {
     pub(crate) gpio:  u16,
     pub(crate) up:    u8,
@@ -165,6 +189,7 @@ pub(crate) struct GpioSetPullsArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct GpioSetArgs {
     pub(crate) gpio:   u16,
     pub(crate) value:  u16,
@@ -172,6 +197,7 @@ pub(crate) struct GpioSetArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmConfigXferArgs {
     pub(crate) sm:         u16,
     pub(crate) dir:        u16,
@@ -181,6 +207,7 @@ pub(crate) struct SmConfigXferArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmConfigXfer32Args {
     pub(crate) sm:         u16,
     pub(crate) dir:        u16,
@@ -190,6 +217,7 @@ pub(crate) struct SmConfigXfer32Args {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmXferDataArgs {
     pub(crate) sm:          u16,
     pub(crate) dir:         u16,
@@ -200,6 +228,7 @@ pub(crate) struct SmXferDataArgs {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct SmXferData32Args {
     pub(crate) sm:          u16,
     pub(crate) dir:         u16,
@@ -209,6 +238,7 @@ pub(crate) struct SmXferData32Args {
 
 
 #[repr(C)]
+#[derive(Debug)]
 pub(crate) struct AccessHwArgs {
     pub(crate) addr:  u32,
     pub(crate) len:   u32,
@@ -263,3 +293,148 @@ pub(crate) const PIO_IOC_GPIO_SET_DRIVE_STRENGTH: c_ulong = _IOW::<GpioSetArgs>
 
 
 
+
+pub(crate) fn request_name(request: c_ulong) -> &'static str {
+    match request {
+        PIO_IOC_SM_CONFIG_XFER          => "SM_CONFIG_XFER",
+        PIO_IOC_SM_XFER_DATA            => "SM_XFER_DATA",
+        PIO_IOC_SM_XFER_DATA32          => "SM_XFER_DATA32",
+        PIO_IOC_SM_CONFIG_XFER32        => "SM_CONFIG_XFER32",
+        PIO_IOC_READ_HW                 => "READ_HW",
+        PIO_IOC_WRITE_HW                => "WRITE_HW",
+        PIO_IOC_CAN_ADD_PROGRAM         => "CAN_ADD_PROGRAM",
+        PIO_IOC_ADD_PROGRAM             => "ADD_PROGRAM",
+        PIO_IOC_REMOVE_PROGRAM          => "REMOVE_PROGRAM",
+        PIO_IOC_CLEAR_INSTR_MEM         => "CLEAR_INSTR_MEM",
+        PIO_IOC_SM_CLAIM                => "SM_CLAIM",
+        PIO_IOC_SM_UNCLAIM              => "SM_UNCLAIM",
+        PIO_IOC_SM_IS_CLAIMED           => "SM_IS_CLAIMED",
+        PIO_IOC_SM_INIT                 => "SM_INIT",
+        PIO_IOC_SM_SET_CONFIG           => "SM_SET_CONFIG",
+        PIO_IOC_SM_EXEC                 => "SM_EXEC",
+        PIO_IOC_SM_CLEAR_FIFOS          => "SM_CLEAR_FIFOS",
+        PIO_IOC_SM_SET_CLKDIV           => "SM_SET_CLKDIV",
+        PIO_IOC_SM_SET_PINS             => "SM_SET_PINS",
+        PIO_IOC_SM_SET_PINDIRS          => "SM_SET_PINDIRS",
+        PIO_IOC_SM_SET_ENABLED          => "SM_SET_ENABLED",
+        PIO_IOC_SM_RESTART              => "SM_RESTART",
+        PIO_IOC_SM_CLKDIV_RESTART       => "SM_CLKDIV_RESTART",
+        PIO_IOC_SM_ENABLE_SYNC          => "SM_ENABLE_SYNC",
+        PIO_IOC_SM_PUT                  => "SM_PUT",
+        PIO_IOC_SM_GET                  => "SM_GET",
+        PIO_IOC_SM_SET_DMACTRL          => "SM_SET_DMACTRL",
+        PIO_IOC_SM_FIFO_STATE           => "SM_FIFO_STATE",
+        PIO_IOC_SM_DRAIN_TX             => "SM_DRAIN_TX",
+        PIO_IOC_GPIO_INIT               => "GPIO_INIT",
+        PIO_IOC_GPIO_SET_FUNCTION       => "GPIO_SET_FUNCTION",
+        PIO_IOC_GPIO_SET_PULLS          => "GPIO_SET_PULLS",
+        PIO_IOC_GPIO_SET_OUTOVER        => "GPIO_SET_OUTOVER",
+        PIO_IOC_GPIO_SET_INOVER         => "GPIO_SET_INOVER",
+        PIO_IOC_GPIO_SET_OEOVER         => "GPIO_SET_OEOVER",
+        PIO_IOC_GPIO_SET_INPUT_ENABLED  => "GPIO_SET_INPUT_ENABLED",
+        PIO_IOC_GPIO_SET_DRIVE_STRENGTH => "GPIO_SET_DRIVE_STRENGTH",
+        _                               => "UNKNOWN",
+    }
+}
+
+// Only the instructions that are actually being used.
+impl std::fmt::Debug for AddProgramArgs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let instrs = &self.instrs[..(self.num_instrs as usize).min(MAX_PROGRAM_INSTRS)];
+        f.debug_struct("AddProgramArgs")
+            .field("num_instrs", &self.num_instrs)
+            .field("origin", &self.origin)
+            .field("instrs", &format_args!("{:04x?}", instrs))
+            .finish()
+    }
+}
+
+// The args for `request`, decoded for logging.
+//
+// Safety: `args` must point to the args struct for `request`.
+pub(crate) unsafe fn describe_args(request: c_ulong, args: *const std::ffi::c_void) -> String {
+    unsafe fn describe<A: std::fmt::Debug>(args: *const std::ffi::c_void) -> String {
+        unsafe { format!("{:?}", &*(args as *const A)) }
+    }
+    unsafe {
+        match request {
+            PIO_IOC_SM_CONFIG_XFER          => describe::<SmConfigXferArgs>(args),
+            PIO_IOC_SM_XFER_DATA            => describe::<SmXferDataArgs>(args),
+            PIO_IOC_SM_XFER_DATA32          => describe::<SmXferData32Args>(args),
+            PIO_IOC_SM_CONFIG_XFER32        => describe::<SmConfigXfer32Args>(args),
+            PIO_IOC_READ_HW                 => describe::<AccessHwArgs>(args),
+            PIO_IOC_WRITE_HW                => describe::<AccessHwArgs>(args),
+            PIO_IOC_CAN_ADD_PROGRAM         => describe::<AddProgramArgs>(args),
+            PIO_IOC_ADD_PROGRAM             => describe::<AddProgramArgs>(args),
+            PIO_IOC_REMOVE_PROGRAM          => describe::<RemoveProgramArgs>(args),
+            PIO_IOC_CLEAR_INSTR_MEM         => String::new(),
+            PIO_IOC_SM_CLAIM                => describe::<SmClaimArgs>(args),
+            PIO_IOC_SM_UNCLAIM              => describe::<SmClaimArgs>(args),
+            PIO_IOC_SM_IS_CLAIMED           => describe::<SmClaimArgs>(args),
+            PIO_IOC_SM_INIT                 => describe::<SmInitArgs>(args),
+            PIO_IOC_SM_SET_CONFIG           => describe::<SmSetConfigArgs>(args),
+            PIO_IOC_SM_EXEC                 => describe::<SmExecArgs>(args),
+            PIO_IOC_SM_CLEAR_FIFOS          => describe::<SmClearFifosArgs>(args),
+            PIO_IOC_SM_SET_CLKDIV           => describe::<SmSetClkdivArgs>(args),
+            PIO_IOC_SM_SET_PINS             => describe::<SmSetPinsArgs>(args),
+            PIO_IOC_SM_SET_PINDIRS          => describe::<SmSetPindirsArgs>(args),
+            PIO_IOC_SM_SET_ENABLED          => describe::<SmSetEnabledArgs>(args),
+            PIO_IOC_SM_RESTART              => describe::<SmRestartArgs>(args),
+            PIO_IOC_SM_CLKDIV_RESTART       => describe::<SmClkdivRestartArgs>(args),
+            PIO_IOC_SM_ENABLE_SYNC          => describe::<SmEnableSyncArgs>(args),
+            PIO_IOC_SM_PUT                  => describe::<SmPutArgs>(args),
+            PIO_IOC_SM_GET                  => describe::<SmGetArgs>(args),
+            PIO_IOC_SM_SET_DMACTRL          => describe::<SmSetDmactrlArgs>(args),
+            PIO_IOC_SM_FIFO_STATE           => describe::<SmFifoStateArgs>(args),
+            PIO_IOC_SM_DRAIN_TX             => describe::<SmClearFifosArgs>(args),
+            PIO_IOC_GPIO_INIT               => describe::<GpioInitArgs>(args),
+            PIO_IOC_GPIO_SET_FUNCTION       => describe::<GpioSetFunctionArgs>(args),
+            PIO_IOC_GPIO_SET_PULLS          => describe::<GpioSetPullsArgs>(args),
+            PIO_IOC_GPIO_SET_OUTOVER        => describe::<GpioSetArgs>(args),
+            PIO_IOC_GPIO_SET_INOVER         => describe::<GpioSetArgs>(args),
+            PIO_IOC_GPIO_SET_OEOVER         => describe::<GpioSetArgs>(args),
+            PIO_IOC_GPIO_SET_INPUT_ENABLED  => describe::<GpioSetArgs>(args),
+            PIO_IOC_GPIO_SET_DRIVE_STRENGTH => describe::<GpioSetArgs>(args),
+            _                               => format!("{args:?}"),
+        }
+    }
+}
+
+// The size of the args struct, as encoded in the request number (_IOC_SIZE()).
+pub(crate) fn args_size(request: c_ulong) -> usize {
+    (request >> 16 & 0x3fff) as usize
+}
+
+// Safety: `args` must point to the args struct for `request`.
+pub(crate) unsafe fn args_bytes<'a>(request: c_ulong, args: *mut std::ffi::c_void) -> &'a mut [u8] {
+    match args_size(request) {
+        0 => &mut [],
+        size => unsafe { std::slice::from_raw_parts_mut(args as *mut u8, size) },
+    }
+}
+
+// The args structs that have a pointer all keep it after the first 8 bytes.
+pub(crate) const POINTER_OFFSET: usize = 8;
+
+// Some args point at a buffer of their own: (data, len in bytes, whether the kernel writes to it).
+//
+// Safety: `args` must point to the args struct for `request`.
+pub(crate) unsafe fn args_buffer(request: c_ulong, args: *const std::ffi::c_void) -> Option<(*mut u8, usize, bool)> {
+    unsafe {
+        match request {
+            PIO_IOC_READ_HW | PIO_IOC_WRITE_HW => {
+                let args = &*(args as *const AccessHwArgs);
+                Some((args.data as *mut u8, args.len as usize, request == PIO_IOC_READ_HW))
+            },
+            PIO_IOC_SM_XFER_DATA => {
+                let args = &*(args as *const SmXferDataArgs);
+                Some((args.data as *mut u8, args.data_bytes as usize, args.dir != 0))
+            },
+            PIO_IOC_SM_XFER_DATA32 => {
+                let args = &*(args as *const SmXferData32Args);
+                Some((args.data as *mut u8, args.data_bytes as usize, args.dir != 0))
+            },
+            _ => None,
+        }
+    }
+}
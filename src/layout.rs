@@ -0,0 +1,146 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Packing several programs into instruction memory at once. Programs with an origin go exactly there, everything else
+// is fit into the gaps (largest first, searching from the top of memory down like the kernel does). If there's any
+// arrangement that fits, plan() finds it.
+//
+//     let loaded = pio.load_programs(&[&ws2812, &uart_tx, &fixed_at_0])?;
+//
+// Identical programs are only placed once, matching how Rp1PIO::load_program() shares them.
+
+use std::collections::HashMap;
+
+use crate::{Error, PioProgram};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Free,
+    Used,           // By something that isn't part of this plan.
+    Program(usize),
+}
+
+// Returns the offset for each of `programs`, in order. `used` is a mask of instruction slots that are already taken.
+pub fn plan(programs: &[&PioProgram], instr_count: u16, used: u64) -> Result<Vec<u16>, Error> {
+    if instr_count > 64 {
+        Err(Error::ParamErr { param: "instr_count", should_be: "<= 64".to_string() })?;
+    }
+    let mut slots: Vec<Slot> = (0..instr_count).map(|i| if used & 1 << i != 0 { Slot::Used } else { Slot::Free }).collect();
+
+    // Index of the first identical program for each program (or its own index).
+    let canonical: Vec<usize> = programs.iter().enumerate()
+        .map(|(i, program)| programs[..i].iter().position(|other| other == program).unwrap_or(i))
+        .collect();
+
+    let mut offsets: Vec<Option<u16>> = vec![None; programs.len()];
+    for (i, program) in programs.iter().enumerate() {
+        if program.len() >= instr_count as usize { // The same bound as Rp1PIO::add_program()
+            Err(Error::TooManyInstructions { instructions: program.len(), max: instr_count })?;
+        }
+        if canonical[i] != i { continue }
+        let Some(origin) = program.origin() else { continue };
+        if origin as usize + program.len() > instr_count as usize {
+            Err(Error::TooManyInstructions { instructions: program.len(), max: instr_count - origin as u16 })?;
+        }
+        for slot in &mut slots[origin as usize..origin as usize + program.len()] {
+            match *slot {
+                Slot::Free       => *slot = Slot::Program(i),
+                Slot::Used       => Err(Error::ProgramDoesNotFit { program: i })?,
+                Slot::Program(j) => Err(Error::ProgramOverlap { program: i, other: j })?,
+            }
+        }
+        offsets[i] = Some(origin as u16);
+    }
+
+    let mut relocatable: Vec<usize> = (0..programs.len()).filter(|&i| canonical[i] == i && offsets[i].is_none()).collect();
+    relocatable.sort_by_key(|&i| std::cmp::Reverse(programs[i].len()));
+    let free = slots.iter().filter(|&&slot| slot == Slot::Free).count();
+    if let Some(&smallest) = relocatable.last() && relocatable.iter().map(|&i| programs[i].len()).sum::<usize>() > free {
+        Err(Error::ProgramDoesNotFit { program: smallest })?;
+    }
+    place(programs, &relocatable, &mut slots, &mut offsets, &mut HashMap::new())
+        .map_err(|program| Error::ProgramDoesNotFit { program })?;
+
+    Ok(canonical.iter().map(|&c| offsets[c].expect("every canonical program should have been placed")).collect())
+}
+
+// Backtracking first-fit. Fails with the first program that couldn't be placed. Left alone that's exponential (every
+// way of shuffling the programs placed so far gets retried), so failures are remembered by how many programs were
+// left and the lengths of the free gaps: whether the rest fit only depends on those, not on where the gaps are, so
+// no shape of free memory is searched twice.
+fn place(programs: &[&PioProgram], todo: &[usize], slots: &mut [Slot], offsets: &mut [Option<u16>],
+         failed: &mut HashMap<(usize, Vec<usize>), usize>) -> Result<(), usize> {
+    let Some((&i, rest)) = todo.split_first() else { return Ok(()) };
+    let mut gaps: Vec<usize> = slots.split(|&slot| slot != Slot::Free).map(<[Slot]>::len)
+        .filter(|&len| len > 0)
+        .collect();
+    gaps.sort_unstable();
+    if let Some(&program) = failed.get(&(todo.len(), gaps.clone())) {
+        return Err(program);
+    }
+    let len = programs[i].len();
+    for offset in (0..=slots.len().saturating_sub(len)).rev() {
+        if !slots[offset..offset + len].iter().all(|&slot| slot == Slot::Free) { continue }
+        slots[offset..offset + len].fill(Slot::Program(i));
+        offsets[i] = Some(offset as u16);
+        if place(programs, rest, slots, offsets, failed).is_ok() {
+            return Ok(());
+        }
+        slots[offset..offset + len].fill(Slot::Free);
+        offsets[i] = None;
+    }
+    failed.insert((todo.len(), gaps), i);
+    Err(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(len: usize, tag: u16, origin: Option<u8>) -> PioProgram {
+        PioProgram::new(&vec![tag; len], origin)
+    }
+
+    #[test]
+    fn origins() {
+        let (fixed, other) = (program(4, 1, Some(2)), program(3, 2, Some(4)));
+        assert_eq!(plan(&[&fixed, &program(2, 3, None)], 32, 0).unwrap(), [2, 30]);
+        assert_eq!(plan(&[&fixed, &other], 32, 0), Err(Error::ProgramOverlap { program: 1, other: 0 }));
+        assert_eq!(plan(&[&fixed], 32, 0b10_0000), Err(Error::ProgramDoesNotFit { program: 0 }));
+        assert!(matches!(plan(&[&program(4, 1, Some(30))], 32, 0), Err(Error::TooManyInstructions { max: 2, .. })));
+    }
+
+    #[test]
+    fn identical_programs_share() {
+        let (a, b) = (program(20, 1, None), program(10, 2, None));
+        assert_eq!(plan(&[&a, &b, &a], 32, 0).unwrap(), [12, 2, 12]);
+        assert_eq!(plan(&[&a, &a, &a], 32, 0).unwrap(), [12, 12, 12]);
+    }
+
+    #[test]
+    fn fragmentation() {
+        // A 6 slot gap under a 4 slot one: the 3s have to go in the bottom gap and the 2s in the top, which first-fit
+        // only finds by backtracking.
+        let programs = [program(3, 1, None), program(3, 2, None), program(2, 3, None), program(2, 4, None)];
+        let programs: Vec<_> = programs.iter().collect();
+        assert_eq!(plan(&programs, 11, 1 << 6).unwrap(), [3, 0, 9, 7]);
+
+        // Sixteen 3 slot gaps only take sixteen 2 slot programs, however they're shuffled. This has to give up quickly.
+        let programs: Vec<_> = (0..17).map(|tag| program(2, tag, None)).collect();
+        let programs: Vec<_> = programs.iter().collect();
+        let used = (0..16).fold(0, |used, gap| used | 1 << (gap * 4 + 3));
+        assert_eq!(plan(&programs, 64, used), Err(Error::ProgramDoesNotFit { program: 0 }));
+    }
+
+    #[test]
+    fn full_memory() {
+        let big = program(31, 1, None);
+        assert_eq!(plan(&[&big], 32, 0).unwrap(), [1]);
+        assert_eq!(plan(&[&big, &program(1, 2, None)], 32, 0).unwrap(), [1, 0]);
+        assert_eq!(plan(&[&big, &program(2, 2, None)], 32, 0), Err(Error::ProgramDoesNotFit { program: 1 }));
+        assert_eq!(plan(&[&big], 32, 1 << 15), Err(Error::ProgramDoesNotFit { program: 0 }));
+        assert_eq!(plan(&[&program(32, 1, None)], 32, 0),
+                   Err(Error::TooManyInstructions { instructions: 32, max: 32 }));
+        assert_eq!(plan(&[&program(16, 1, None), &program(16, 2, None)], 32, 0).unwrap(), [16, 0]);
+    }
+}
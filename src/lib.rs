@@ -1,18 +1,54 @@
 // Copyright © 2025 David Caldwell <david@porkrind.org>
 // SPDX-License-Identifier: BSD-3-Clause
 
+mod backend;
+pub mod capture;
+mod clock;
 mod config;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+#[cfg(feature = "unsafe-direct")]
+pub mod direct;
+mod discover;
+pub mod drivers;
+pub mod dry_run;
+pub mod emulator;
+#[cfg(feature = "cdylib")]
+pub mod ffi;
+pub mod generator;
 pub mod gpio;
+pub mod instruction;
+#[cfg(feature = "embedded-hal")]
+pub mod hal;
 mod ioctl;
+pub mod layout;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod mock;
 #[path="proc-pio.rs"]
 pub mod proc_pio;
 #[path="pio-rp1.rs"]
 mod pio_rp1;
+pub mod pioasm;
+pub mod prelude;
+pub mod probe;
+pub mod record;
+pub mod registers;
+mod report;
+mod self_test;
+pub mod testing;
+pub mod vcd;
+pub mod watchdog;
 
 pub use self::pio_rp1::*;
-pub use self::config::SmConfig;
+pub use self::backend::{IoctlBackend, PioBackend};
+pub use self::config::{PinGroups, SmConfig};
+pub use self::clock::{pio_clock_hz, pio_clock_source, ClockSource, Frequency};
+pub use self::discover::PioDevice;
+pub use self::self_test::SelfTestReport;
 
-use std::sync::{LazyLock, Mutex};
+use std::collections::HashSet;
+use std::sync::{LazyLock, Mutex, MutexGuard};
 
 use crate::gpio::Function;
 
@@ -22,7 +58,8 @@ const GPIO_COUNT         : usize = 28;
 const GPIOS_MASK         : u32 = (1 << GPIO_COUNT) - 1;
 const GPIO_FUNC_PIO      : Function = Function::PIO1; // function 7
 
-#[derive(Clone)]
+#[derive(Debug,Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Chip {
     pub name: String,
     pub compatible: String,
@@ -33,7 +70,8 @@ pub struct Chip {
 
 impl Chip {
     pub fn new() -> Chip {
-        Chip { // Values taken from piolib/pio_rp1.c
+        // Values taken from piolib/pio_rp1.c. Rp1PIO replaces these with what the hardware reports, when it can.
+        Chip {
             name: "rp1".to_string(),
             compatible: "raspberrypi,rp1-pio".to_string(),
             instr_count: INSTRUCTION_COUNT,
@@ -43,54 +81,79 @@ impl Chip {
     }
 }
 
-#[derive(Clone)]
-struct PIOReservation {
-    chip: Chip,
-    in_use: bool,
+impl Default for Chip {
+    fn default() -> Self {
+        Chip::new()
+    }
+}
+
+// Reservations are keyed on the device itself rather than the path used to open it, so symlinks, udev aliases and
+// bind mounts of the same device all count as the same PIO.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+enum DeviceId {
+    CharDevice(u64),     // st_rdev
+    File { dev: u64, ino: u64 },
+    Backend(u64),        // Rp1PIO::with_backend(). Every one is distinct.
+}
+
+impl DeviceId {
+    fn of(file: &std::fs::File) -> Result<DeviceId, Error> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+        let metadata = file.metadata()?;
+        Ok(if metadata.file_type().is_char_device() { DeviceId::CharDevice(metadata.rdev()) }
+           else                                     { DeviceId::File { dev: metadata.dev(), ino: metadata.ino() } })
+    }
+
+    fn unique() -> DeviceId {
+        static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        DeviceId::Backend(NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed))
+    }
 }
 
 struct PIOInstance {
     chip: Chip,
-    index: usize,
+    id: DeviceId,
 }
 
-static INSTANCES: LazyLock<Mutex<Vec<PIOReservation>>> = LazyLock::new(|| {
-    Mutex::new(PIOReservation::reservations())
-});
+static INSTANCES: LazyLock<Mutex<HashSet<DeviceId>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
 
-impl PIOReservation {
-    fn reservations() -> Vec<PIOReservation> {
-        // Right now the RP1 only has a single PIO available for use.
-        vec![PIOReservation { chip: Chip::new(), in_use: false }]
-    }
+// None of our locks protect anything that a panic can leave half updated (they're all simple inserts, removes and
+// bit twiddles), so a panic on some other thread while holding one shouldn't take the rest of the process down too.
+pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
 }
 
 impl PIOInstance {
-    fn reserve(index: usize) -> Result<PIOInstance, Error> {
-        let chip = {
-            let mut instances = INSTANCES.lock().unwrap();
-            let Some(instance) = instances.get_mut(index) else {
-                return Err(Error::BadPIOInstance { index, max: instances.len() });
-            };
-            if instance.in_use {
-                return Err(Error::InstanceInUse);
-            }
-            instance.in_use = true;
-            instance.chip.clone()
-        };
-        Ok(PIOInstance { chip, index })
+    fn reserve(id: DeviceId, chip: Chip) -> Result<PIOInstance, Error> {
+        if !lock(&INSTANCES).insert(id) {
+            return Err(Error::InstanceInUse);
+        }
+        Ok(PIOInstance { chip, id })
     }
 }
 
 impl Drop for PIOInstance {
     fn drop(&mut self) {
-        // TODO: Can this deadlock with the above somehow? Think it through!
-        let mut instances = INSTANCES.lock().unwrap();
-        let instance = instances.get_mut(self.index).expect(&format!("Bad index in reserved PIO Instance: {}!", self.index));
-        instance.in_use = false;
+        // This can't deadlock with reserve(): INSTANCES is only ever held for the bookkeeping itself and never while
+        // a PIOInstance is being created or dropped.
+        lock(&INSTANCES).remove(&self.id);
     }
 }
 
+// INSTANCES is never touched on the ioctl path. Rp1PIO has a few locks of its own, all held just for the bookkeeping:
+// the optional pin conflict tracker (taken when configuring an SM), `owned` (loading and removing programs, enabling
+// and disabling SMs), `shared` (load_program() and dropping a LoadedProgram) and `errors` (every failed ioctl).
+// Successful GPIO calls and FIFO traffic take none of them and go straight to the kernel (which does its own
+// locking), so they don't serialize against each other in userspace. Sharing an Rp1PIO (and its StateMachines) across
+// threads relies on these staying Sync.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Rp1PIO>();
+    assert_send_sync::<StateMachine<'static>>();
+};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
 #[derive(Debug)]
 pub enum Error {
     BadPIOInstance { index: usize, max: usize },
@@ -110,15 +173,128 @@ pub enum Error {
     BadPinMask(u32),
     BadGPIO { gpio: u16, max: usize },
     ParamErr { param: &'static str, should_be: String },
+    PinConflict { sm: u16, other_sm: u16, pins: u32 },
+    ProgramOverlap { program: usize, other: usize },
+    ProgramDoesNotFit { program: usize },
+    ReplayMismatch { entry: usize, expected: String, got: String },
+    SelfTestFailed(String),
+    BadInstruction { index: usize, instr: u16, reason: String },
+    Assembly { line: usize, message: String },
+    Ioctl { op: &'static str, args: String, errno: i32, source: std::io::Error },
+    RollbackFailed { error: Box<Error>, rollback: Box<Error> }, // `error`, and then cleaning up after it failed too
+}
+
+// io::Errors don't compare, so IOError and Ioctl are equal if their errnos (and for Ioctl, the op and args) are.
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        use Error::*;
+        match (self, other) {
+            (BadPIOInstance { index: a, max: b }, BadPIOInstance { index: c, max: d })                  => (a, b) == (c, d),
+            (InstanceInUse, InstanceInUse) | (RemoteIOErr, RemoteIOErr) | (TimedOut, TimedOut)        => true,
+            (IOError(a), IOError(b))                                                                  => a.kind() == b.kind() && a.raw_os_error() == b.raw_os_error(),
+            (Unknown(a), Unknown(b))                                                                  => a == b,
+            (BadSM { sm: a, max: b }, BadSM { sm: c, max: d })                                        => (a, b) == (c, d),
+            (BadSMMask { sm_mask: a, max: b }, BadSMMask { sm_mask: c, max: d })                      => (a, b) == (c, d),
+            (OffsetOriginMismatch { origin: a, offset: b }, OffsetOriginMismatch { origin: c, offset: d }) => (a, b) == (c, d),
+            (OffsetTooLarge { offset: a, max: b }, OffsetTooLarge { offset: c, max: d })              => (a, b) == (c, d),
+            (TooManyInstructions { instructions: a, max: b }, TooManyInstructions { instructions: c, max: d }) => (a, b) == (c, d),
+            (BadPC { pc: a, max: b }, BadPC { pc: c, max: d })                                        => (a, b) == (c, d),
+            (BadDiv { div: a, min: b, max: c }, BadDiv { div: d, min: e, max: f })                    => (a, b, c) == (d, e, f),
+            (BadPinDirs(a), BadPinDirs(b)) | (BadPinMask(a), BadPinMask(b))                           => a == b,
+            (BadGPIO { gpio: a, max: b }, BadGPIO { gpio: c, max: d })                                => (a, b) == (c, d),
+            (ParamErr { param: a, should_be: b }, ParamErr { param: c, should_be: d })                => (a, b) == (c, d),
+            (PinConflict { sm: a, other_sm: b, pins: c }, PinConflict { sm: d, other_sm: e, pins: f }) => (a, b, c) == (d, e, f),
+            (ProgramOverlap { program: a, other: b }, ProgramOverlap { program: c, other: d })        => (a, b) == (c, d),
+            (ProgramDoesNotFit { program: a }, ProgramDoesNotFit { program: b })                      => a == b,
+            (ReplayMismatch { entry: a, expected: b, got: c }, ReplayMismatch { entry: d, expected: e, got: f }) => (a, b, c) == (d, e, f),
+            (SelfTestFailed(a), SelfTestFailed(b))                                                    => a == b,
+            (BadInstruction { index: a, instr: b, reason: c }, BadInstruction { index: d, instr: e, reason: f }) => (a, b, c) == (d, e, f),
+            (Assembly { line: a, message: b }, Assembly { line: c, message: d })                      => (a, b) == (c, d),
+            (Ioctl { op: a, args: b, errno: c, .. }, Ioctl { op: d, args: e, errno: f, .. })         => (a, b, c) == (d, e, f),
+            (RollbackFailed { error: a, rollback: b }, RollbackFailed { error: c, rollback: d })      => (a, b) == (c, d),
+            _ => false,
+        }
+    }
 }
 
 impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IOError(source) | Error::Ioctl { source, .. } => Some(source),
+            Error::RollbackFailed { error, .. }                  => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// Broad classes of Error, for callers that care about what sort of thing went wrong rather than exactly what.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    InvalidParam,      // The caller asked for something impossible
+    ResourceBusy,      // SMs, instruction memory, pins or the device are already in use
+    PermissionDenied,
+    Hardware,          // The RP1 or its firmware failed
+    Io,                // Anything else the kernel said no to
+    Timeout,
+    Other,
+}
+
+fn errno_kind(errno: i32) -> ErrorKind {
+    match errno {
+        libc::EBUSY                 => ErrorKind::ResourceBusy,
+        libc::ETIMEDOUT             => ErrorKind::Timeout,
+        libc::EACCES | libc::EPERM  => ErrorKind::PermissionDenied,
+        libc::EINVAL                => ErrorKind::InvalidParam,
+        libc::EREMOTEIO             => ErrorKind::Hardware,
+        _                           => ErrorKind::Io,
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::BadPIOInstance { .. } | Error::BadSM { .. } | Error::BadSMMask { .. } | Error::OffsetOriginMismatch { .. } |
+            Error::OffsetTooLarge { .. } | Error::TooManyInstructions { .. } | Error::BadPC { .. } | Error::BadDiv { .. } |
+            Error::BadPinDirs(_) | Error::BadPinMask(_) | Error::BadGPIO { .. } | Error::ParamErr { .. } |
+            Error::BadInstruction { .. } | Error::Assembly { .. } => ErrorKind::InvalidParam,
+            Error::InstanceInUse | Error::PinConflict { .. } | Error::ProgramOverlap { .. } |
+            Error::ProgramDoesNotFit { .. }                 => ErrorKind::ResourceBusy,
+            Error::RemoteIOErr | Error::Unknown(_) | Error::SelfTestFailed(_) => ErrorKind::Hardware,
+            Error::TimedOut                                 => ErrorKind::Timeout,
+            Error::IOError(error)                           => error.raw_os_error().map(errno_kind).unwrap_or(ErrorKind::Io),
+            Error::Ioctl { errno, .. }                      => errno_kind(*errno),
+            Error::ReplayMismatch { .. }                    => ErrorKind::Other,
+            Error::RollbackFailed { error, .. }             => error.kind(),
+        }
+    }
+
+    // `error`, with the failure of whatever tried to clean up after it (if it did fail).
+    pub(crate) fn rolled_back(error: Error, rollback: Result<(), Error>) -> Error {
+        match rollback {
+            Ok(())        => error,
+            Err(rollback) => Error::RollbackFailed { error: Box::new(error), rollback: Box::new(rollback) },
+        }
+    }
+
+    // The errno behind the error, if it came from the kernel.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Error::Ioctl { errno, .. }          => Some(*errno),
+            Error::IOError(error)               => error.raw_os_error(),
+            Error::RemoteIOErr                  => Some(libc::EREMOTEIO),
+            Error::TimedOut                     => Some(libc::ETIMEDOUT),
+            Error::Unknown(code)                => Some(-code),
+            Error::RollbackFailed { error, .. } => error.errno(),
+            _                                   => None,
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Error::BadPIOInstance { index, max }             => write!(f, "Bad PIO Instance: {index} must be less than {max}"),
+            Error::BadPIOInstance { index, max }             => write!(f, "Bad PIO Instance: {index} must be at most {max}"),
             Error::InstanceInUse                             => write!(f, "PIO Instance is in use"),
             Error::RemoteIOErr                               => write!(f, "Remote IO Error"),
             Error::TimedOut                                  => write!(f, "Timed Out"),
@@ -135,6 +311,15 @@ impl std::fmt::Display for Error {
             Error::BadPinMask(pin_mask)                      => write!(f, "Bad pin_dirs: The bits {pin_mask:#b} are out of range"),
             Error::BadGPIO { gpio, max }                     => write!(f, "Bad GPIO: {gpio} must be less than {max}"),
             Error::ParamErr {param, should_be }              => write!(f, "Bad Parameter \"{param}\": should be {should_be}"),
+            Error::PinConflict { sm, other_sm, pins }        => write!(f, "Pin Conflict: SM {sm} would drive pins {pins:#b} which SM {other_sm} already drives"),
+            Error::ProgramOverlap { program, other }         => write!(f, "Program Overlap: program {program}'s origin overlaps program {other}"),
+            Error::ProgramDoesNotFit { program }             => write!(f, "Program Does Not Fit: no room for program {program} in instruction memory"),
+            Error::ReplayMismatch { entry, expected, got }   => write!(f, "Replay Mismatch: trace entry {entry} is {expected}, but got {got}"),
+            Error::SelfTestFailed(reason)                    => write!(f, "Self Test Failed: {reason}"),
+            Error::BadInstruction { index, instr, reason }   => write!(f, "Bad Instruction {index} ({instr:#06x}): {reason}"),
+            Error::Assembly { line, message }                => write!(f, "Assembly Error on line {line}: {message}"),
+            Error::Ioctl { op, args, errno: _, source }      => write!(f, "Ioctl {op} Failed ({args}): {source}"),
+            Error::RollbackFailed { error, rollback }        => write!(f, "{error} (and then undoing it failed: {rollback})"),
         }
     }
 }
@@ -146,6 +331,8 @@ impl From<std::io::Error> for Error {
 }
 
 #[repr(u32)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PioFifoJoin {
     None = 0,
     Tx   = 1,
@@ -153,7 +340,77 @@ pub enum PioFifoJoin {
 }
 
 #[repr(u32)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PioMovStatus {
     TxLessThan = 0,
     RxLessThan = 1,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fake ids that can't collide with a real device (or with each other across tests, since INSTANCES is global).
+    fn fake_id(ino: u64) -> DeviceId {
+        DeviceId::File { dev: u64::MAX, ino }
+    }
+
+    #[test]
+    fn reserve_and_release() {
+        let instance = PIOInstance::reserve(fake_id(1), Chip::new()).unwrap();
+        assert!(matches!(PIOInstance::reserve(fake_id(1), Chip::new()), Err(Error::InstanceInUse)));
+        drop(instance);
+        PIOInstance::reserve(fake_id(1), Chip::new()).unwrap();
+    }
+
+    #[test]
+    fn release_on_panic() {
+        let result = std::thread::spawn(|| {
+            let _instance = PIOInstance::reserve(fake_id(2), Chip::new()).unwrap();
+            panic!("while holding a reservation");
+        }).join();
+        assert!(result.is_err());
+        PIOInstance::reserve(fake_id(2), Chip::new()).unwrap();
+    }
+
+    #[test]
+    fn survives_poisoned_lock() {
+        let result = std::thread::spawn(|| {
+            let _guard = INSTANCES.lock().unwrap();
+            panic!("while holding INSTANCES");
+        }).join();
+        assert!(result.is_err());
+        assert!(INSTANCES.is_poisoned());
+        let instance = PIOInstance::reserve(fake_id(3), Chip::new()).unwrap();
+        assert!(matches!(PIOInstance::reserve(fake_id(3), Chip::new()), Err(Error::InstanceInUse)));
+        drop(instance);
+        PIOInstance::reserve(fake_id(3), Chip::new()).unwrap();
+    }
+
+    #[test]
+    fn ioctl_errors_have_context() {
+        let pio = Rp1PIO::with_backend(Box::new(mock::MockPio::new()), Chip::new());
+        let _sm = pio.sm_claim(1).unwrap();
+        let error = pio.sm_claim(1).err().unwrap();
+        assert!(matches!(&error, Error::Ioctl { op: "SM_CLAIM", errno: libc::EBUSY, .. }), "{error:?}");
+        assert_eq!(error.errno(), Some(libc::EBUSY));
+        assert!(error.to_string().contains("mask: 2"));
+        assert!(std::error::Error::source(&error).is_some());
+    }
+
+    #[test]
+    fn error_kinds_and_equality() {
+        assert_eq!(Error::BadSM { sm: 5, max: 4 }, Error::BadSM { sm: 5, max: 4 });
+        assert_ne!(Error::BadSM { sm: 5, max: 4 }, Error::BadSM { sm: 6, max: 4 });
+        assert_eq!(Error::from(std::io::Error::from_raw_os_error(libc::EBUSY)), Error::from(std::io::Error::from_raw_os_error(libc::EBUSY)));
+        assert_eq!(Error::BadSM { sm: 5, max: 4 }.kind(), ErrorKind::InvalidParam);
+        assert_eq!(Error::TimedOut.kind(), ErrorKind::Timeout);
+        assert_eq!(Error::from(std::io::Error::from_raw_os_error(libc::EACCES)).kind(), ErrorKind::PermissionDenied);
+
+        let pio = Rp1PIO::with_backend(Box::new(mock::MockPio::new()), Chip::new());
+        let _sm = pio.sm_claim(0).unwrap();
+        assert_eq!(pio.sm_claim(0).err().unwrap().kind(), ErrorKind::ResourceBusy);
+        assert_eq!(pio.sm_claim(9).err().unwrap(), Error::BadSM { sm: 9, max: 4 });
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Counters for how hard an Rp1PIO is being worked, with the `metrics` feature:
+//
+//     let stats = pio.stats()?;
+//     println!("{} bytes in, {} RX overruns on SM0", stats.bytes_rx, stats.rx_overruns[0]);
+//
+// Everything counts from when the Rp1PIO was opened (or reset_stats()). The FIFO overrun/underrun counts come from
+// the sticky FDEBUG flags, which are only looked at (and cleared) when stats() is called, so they count how many
+// times stats() saw the flag set, not how many words were lost.
+
+use std::{collections::BTreeMap, ffi::c_void, sync::atomic::{AtomicU64, Ordering::Relaxed}, time::Duration};
+
+use libc::c_ulong;
+
+use crate::{proc_pio::*, Error, Rp1PIO};
+use crate::ioctl::*;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub ioctls: BTreeMap<&'static str, u64>,  // By request name
+    pub bytes_tx: u64,                        // Into TX FIFOs, by put() and DMA
+    pub bytes_rx: u64,                        // Out of RX FIFOs, by get() and DMA
+    pub rx_overruns: Vec<u64>,                // Per SM: FDEBUG.RXSTALL, an SM found its RX FIFO full
+    pub tx_underruns: Vec<u64>,               // Per SM: FDEBUG.TXSTALL, an SM found its TX FIFO empty
+    pub blocking_waits: u64,                  // Blocking put()s, get()s, exec()s and TX drains
+    pub blocking_wait_time: Duration,         // ...and how long they took altogether
+    pub timeouts: u64,
+}
+
+// ioctl request numbers are small, so they index straight into `ioctls`.
+const MAX_REQUEST_NR: usize = 64;
+
+pub(crate) struct Metrics {
+    ioctls: [AtomicU64; MAX_REQUEST_NR],
+    bytes_tx: AtomicU64,
+    bytes_rx: AtomicU64,
+    rx_overruns: Vec<AtomicU64>,
+    tx_underruns: Vec<AtomicU64>,
+    blocking_waits: AtomicU64,
+    blocking_wait_ns: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+fn request_nr(request: c_ulong) -> usize {
+    (request & 0xff) as usize % MAX_REQUEST_NR
+}
+
+impl Metrics {
+    pub(crate) fn new(sm_count: u16) -> Metrics {
+        Metrics {
+            ioctls: std::array::from_fn(|_| AtomicU64::new(0)),
+            bytes_tx: AtomicU64::new(0),
+            bytes_rx: AtomicU64::new(0),
+            rx_overruns: (0..sm_count).map(|_| AtomicU64::new(0)).collect(),
+            tx_underruns: (0..sm_count).map(|_| AtomicU64::new(0)).collect(),
+            blocking_waits: AtomicU64::new(0),
+            blocking_wait_ns: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+        }
+    }
+
+    // Safety: `args` must point to the args struct for `request`.
+    pub(crate) unsafe fn record(&self, request: c_ulong, args: *const c_void, result: &Result<u32, Error>, elapsed: Duration) {
+        self.ioctls[request_nr(request)].fetch_add(1, Relaxed);
+        let blocking = unsafe {
+            match request {
+                PIO_IOC_SM_PUT      => (*(args as *const SmPutArgs)).blocking != 0,
+                PIO_IOC_SM_GET      => (*(args as *const SmGetArgs)).blocking != 0,
+                PIO_IOC_SM_EXEC     => (*(args as *const SmExecArgs)).blocking != 0,
+                PIO_IOC_SM_DRAIN_TX => true,
+                _                   => false,
+            }
+        };
+        if blocking {
+            self.blocking_waits.fetch_add(1, Relaxed);
+            self.blocking_wait_ns.fetch_add(elapsed.as_nanos() as u64, Relaxed);
+        }
+        match result {
+            Err(Error::TimedOut) => { self.timeouts.fetch_add(1, Relaxed); },
+            Err(_) => {},
+            Ok(_) => {
+                let (bytes, tx) = unsafe {
+                    match request {
+                        PIO_IOC_SM_PUT         => (4, true),
+                        PIO_IOC_SM_GET         => (4, false),
+                        PIO_IOC_SM_XFER_DATA   => { let args = &*(args as *const SmXferDataArgs);   (args.data_bytes as u64, args.dir != 0) },
+                        PIO_IOC_SM_XFER_DATA32 => { let args = &*(args as *const SmXferData32Args); (args.data_bytes as u64, args.dir != 0) },
+                        _                      => (0, false),
+                    }
+                };
+                if tx { self.bytes_tx.fetch_add(bytes, Relaxed) } else { self.bytes_rx.fetch_add(bytes, Relaxed) };
+            },
+        }
+    }
+
+    fn record_fdebug(&self, fdebug: u32) {
+        for (sm, (overruns, underruns)) in self.rx_overruns.iter().zip(&self.tx_underruns).enumerate() {
+            if fdebug & 1 << (PROC_PIO_FDEBUG_RXSTALL_LSB + sm as u32) != 0 { overruns.fetch_add(1, Relaxed); }
+            if fdebug & 1 << (PROC_PIO_FDEBUG_TXSTALL_LSB + sm as u32) != 0 { underruns.fetch_add(1, Relaxed); }
+        }
+    }
+
+    fn reset(&self) {
+        let counters = self.ioctls.iter().chain(&self.rx_overruns).chain(&self.tx_underruns)
+            .chain([&self.bytes_tx, &self.bytes_rx, &self.blocking_waits, &self.blocking_wait_ns, &self.timeouts]);
+        for counter in counters {
+            counter.store(0, Relaxed);
+        }
+    }
+}
+
+// Every request, so stats() can name them. Unused ones are left out of Stats::ioctls.
+const REQUESTS: [c_ulong; 37] = [
+    PIO_IOC_SM_CONFIG_XFER, PIO_IOC_SM_XFER_DATA, PIO_IOC_SM_XFER_DATA32, PIO_IOC_SM_CONFIG_XFER32, PIO_IOC_READ_HW,
+    PIO_IOC_WRITE_HW, PIO_IOC_CAN_ADD_PROGRAM, PIO_IOC_ADD_PROGRAM, PIO_IOC_REMOVE_PROGRAM, PIO_IOC_CLEAR_INSTR_MEM,
+    PIO_IOC_SM_CLAIM, PIO_IOC_SM_UNCLAIM, PIO_IOC_SM_IS_CLAIMED, PIO_IOC_SM_INIT, PIO_IOC_SM_SET_CONFIG, PIO_IOC_SM_EXEC,
+    PIO_IOC_SM_CLEAR_FIFOS, PIO_IOC_SM_SET_CLKDIV, PIO_IOC_SM_SET_PINS, PIO_IOC_SM_SET_PINDIRS, PIO_IOC_SM_SET_ENABLED,
+    PIO_IOC_SM_RESTART, PIO_IOC_SM_CLKDIV_RESTART, PIO_IOC_SM_ENABLE_SYNC, PIO_IOC_SM_PUT, PIO_IOC_SM_GET,
+    PIO_IOC_SM_SET_DMACTRL, PIO_IOC_SM_FIFO_STATE, PIO_IOC_SM_DRAIN_TX, PIO_IOC_GPIO_INIT, PIO_IOC_GPIO_SET_FUNCTION,
+    PIO_IOC_GPIO_SET_PULLS, PIO_IOC_GPIO_SET_OUTOVER, PIO_IOC_GPIO_SET_INOVER, PIO_IOC_GPIO_SET_OEOVER,
+    PIO_IOC_GPIO_SET_INPUT_ENABLED, PIO_IOC_GPIO_SET_DRIVE_STRENGTH,
+];
+
+impl Rp1PIO {
+    // Checks FDEBUG for overruns and underruns (clearing it), then takes a snapshot of the counters.
+    pub fn stats(&self) -> Result<Stats, Error> {
+        let mut fdebug = [0];
+        self.read_hw(PROC_PIO_FDEBUG_OFFSET, &mut fdebug)?;
+        let seen = fdebug[0] & (PROC_PIO_FDEBUG_RXSTALL_BITS | PROC_PIO_FDEBUG_TXSTALL_BITS);
+        if seen != 0 {
+            self.write_hw(PROC_PIO_FDEBUG_OFFSET, &[seen])?; // Write 1 to clear
+        }
+        let metrics = &self.metrics;
+        metrics.record_fdebug(seen);
+        Ok(Stats {
+            ioctls: REQUESTS.iter()
+                .map(|&request| (request_name(request), metrics.ioctls[request_nr(request)].load(Relaxed)))
+                .filter(|&(_, count)| count != 0)
+                .collect(),
+            bytes_tx: metrics.bytes_tx.load(Relaxed),
+            bytes_rx: metrics.bytes_rx.load(Relaxed),
+            rx_overruns: metrics.rx_overruns.iter().map(|count| count.load(Relaxed)).collect(),
+            tx_underruns: metrics.tx_underruns.iter().map(|count| count.load(Relaxed)).collect(),
+            blocking_waits: metrics.blocking_waits.load(Relaxed),
+            blocking_wait_time: Duration::from_nanos(metrics.blocking_wait_ns.load(Relaxed)),
+            timeouts: metrics.timeouts.load(Relaxed),
+        })
+    }
+
+    pub fn reset_stats(&self) {
+        self.metrics.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{mock::MockPio, Chip, Error, Rp1PIO};
+
+    #[test]
+    fn counts() {
+        let mock = MockPio::new();
+        let pio = Rp1PIO::with_backend(Box::new(mock.clone()), Chip::new());
+        let sm = pio.sm_claim(0).unwrap();
+        sm.put(1, true).unwrap();
+        sm.put(2, false).unwrap();
+        mock.push_rx(0, &[3]);
+        sm.get(true).unwrap();
+        assert_eq!(sm.get(true), Err(Error::TimedOut));
+
+        let stats = pio.stats().unwrap();
+        assert_eq!(stats.ioctls.get("SM_PUT"), Some(&2));
+        assert_eq!(stats.ioctls.get("SM_GET"), Some(&2));
+        assert_eq!(stats.ioctls.get("SM_CLAIM"), Some(&1));
+        assert_eq!((stats.bytes_tx, stats.bytes_rx), (8, 4));
+        assert_eq!(stats.blocking_waits, 3);
+        assert_eq!(stats.timeouts, 1);
+        assert_eq!(stats.rx_overruns, [0; 4]);
+
+        pio.reset_stats();
+        assert_eq!(pio.stats().unwrap().ioctls.get("SM_PUT"), None);
+    }
+}
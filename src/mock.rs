@@ -0,0 +1,733 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A stand-in for the kernel driver that just keeps track of what it's been asked to do, so code using Rp1PIO can be
+// tested on machines without a PIO:
+//
+//     let mock = MockPio::new();
+//     let pio = mock.pio();
+//     my_driver(&pio)?;
+//     let offset = mock.expect_program_loaded(&MY_PROGRAM);
+//     mock.expect_enabled(0);
+//     assert_eq!(mock.take_tx(0), [1, 2, 3]);
+//
+// Nothing gets executed: words put into a TX FIFO pile up until take_tx() and get() only returns what push_rx()
+// queued up. A blocking get() on an empty RX FIFO fails with Error::TimedOut rather than hanging the test. Error
+// paths can be tested with fail_next(), which makes the next ioctl of a kind fail:
+//
+//     mock.fail_next("SM_INIT", libc::EIO);
+
+use std::{collections::VecDeque, ffi::c_void, path::Path, sync::{Arc, Mutex}};
+
+use libc::c_ulong;
+
+use crate::{lock, proc_pio::*, Chip, ClkDiv, Error, PioBackend, Rp1PIO, SmConfig, XferDir, GPIO_COUNT};
+use crate::ioctl::*;
+
+#[derive(Debug, Clone, Default)]
+struct MockSm {
+    claimed: bool,
+    enabled: bool,
+    config: Option<SmConfig>,
+    pc: u16,
+    pins: u32,
+    pindirs: u32,
+    tx: Vec<u32>,
+    rx: VecDeque<u32>,
+    executed: Vec<u16>,
+    dmactrl: [u32; 2], // [rx, tx]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MockGpio {
+    pub function: Option<u16>, // None until something sets it
+    pub pull_up: bool,
+    pub pull_down: bool,
+    pub input_enabled: bool,
+    pub drive_strength: u16,
+}
+
+#[derive(Debug)]
+struct MockState {
+    chip: Chip,
+    instr_mem: Vec<Option<u16>>,
+    programs: Vec<(u16, Vec<u16>)>, // (offset, instructions)
+    sms: Vec<MockSm>,
+    gpios: Vec<MockGpio>,
+    failures: Vec<(&'static str, i32)>, // (request name, errno) for fail_next()
+}
+
+#[derive(Debug, Clone)]
+pub struct MockPio {
+    state: Arc<Mutex<MockState>>,
+}
+
+fn errno(errno: i32) -> Error {
+    std::io::Error::from_raw_os_error(errno).into()
+}
+
+// Safety: the caller (PioBackend::ioctl()) guarantees `args` points to an `A`.
+unsafe fn args<'a, A>(args: *mut c_void) -> &'a mut A {
+    unsafe { &mut *(args as *mut A) }
+}
+
+impl Default for MockPio {
+    fn default() -> Self {
+        MockPio::new()
+    }
+}
+
+impl MockPio {
+    pub fn new() -> MockPio {
+        MockPio::with_chip(Chip::new())
+    }
+
+    pub fn with_chip(chip: Chip) -> MockPio {
+        let state = MockState {
+            instr_mem: vec![None; chip.instr_count as usize],
+            programs: Vec::new(),
+            sms: vec![MockSm::default(); chip.sm_count as usize],
+            gpios: vec![MockGpio { function: None, pull_up: false, pull_down: false, input_enabled: false, drive_strength: 0 }; GPIO_COUNT],
+            failures: Vec::new(),
+            chip,
+        };
+        MockPio { state: Arc::new(Mutex::new(state)) }
+    }
+
+    // An Rp1PIO backed by this mock. The mock can still be inspected afterwards.
+    pub fn pio(&self) -> Rp1PIO {
+        let chip = lock(&self.state).chip.clone();
+        Rp1PIO::with_backend(Box::new(self.clone()), chip)
+    }
+
+    fn sm<T>(&self, sm: u16, f: impl FnOnce(&mut MockSm) -> T) -> T {
+        f(&mut lock(&self.state).sms[sm as usize])
+    }
+
+    ///// Inspecting and poking at the state
+
+    // The offset `instructions` is loaded at, if it is.
+    pub fn program_offset(&self, instructions: &[u16]) -> Option<u16> {
+        lock(&self.state).programs.iter().find(|(_, loaded)| loaded == instructions).map(|&(offset, _)| offset)
+    }
+
+    pub fn instruction_memory(&self) -> Vec<Option<u16>> {
+        lock(&self.state).instr_mem.clone()
+    }
+
+    pub fn is_claimed(&self, sm: u16) -> bool   { self.sm(sm, |sm| sm.claimed) }
+    pub fn is_enabled(&self, sm: u16) -> bool   { self.sm(sm, |sm| sm.enabled) }
+    pub fn config(&self, sm: u16) -> Option<SmConfig> { self.sm(sm, |sm| sm.config) }
+    pub fn pc(&self, sm: u16) -> u16            { self.sm(sm, |sm| sm.pc) }
+    pub fn executed(&self, sm: u16) -> Vec<u16> { self.sm(sm, |sm| sm.executed.clone()) }
+
+    // Pin levels and directions as last set through set_pins*()/set_pindirs*() on `sm`.
+    pub fn pins(&self, sm: u16) -> u32    { self.sm(sm, |sm| sm.pins) }
+    pub fn pindirs(&self, sm: u16) -> u32 { self.sm(sm, |sm| sm.pindirs) }
+
+    pub fn gpio(&self, gpio: u16) -> MockGpio {
+        lock(&self.state).gpios[gpio as usize]
+    }
+
+    // Everything that's been put into `sm`'s TX FIFO since the last call.
+    pub fn take_tx(&self, sm: u16) -> Vec<u32> {
+        self.sm(sm, |sm| std::mem::take(&mut sm.tx))
+    }
+
+    // Queues up words for `sm`'s RX FIFO, as if the SM had pushed them.
+    pub fn push_rx(&self, sm: u16, words: &[u32]) {
+        self.sm(sm, |sm| sm.rx.extend(words))
+    }
+
+    // Makes the next `request` ioctl (named as in traces, "SM_INIT" say) fail with `errno` without doing anything.
+    // Calling it again queues up another failure.
+    pub fn fail_next(&self, request: &'static str, errno: i32) {
+        lock(&self.state).failures.push((request, errno));
+    }
+
+    ///// Assertions. These panic with a description of what's actually there.
+
+    pub fn expect_program_loaded(&self, instructions: &[u16]) -> u16 {
+        self.program_offset(instructions)
+            .unwrap_or_else(|| panic!("program {instructions:04x?} is not loaded. Loaded: {:04x?}", lock(&self.state).programs))
+    }
+
+    pub fn expect_program_loaded_at(&self, instructions: &[u16], offset: u16) {
+        let loaded_at = self.expect_program_loaded(instructions);
+        assert_eq!(loaded_at, offset, "program {instructions:04x?} is loaded at the wrong offset");
+    }
+
+    pub fn expect_program_not_loaded(&self, instructions: &[u16]) {
+        if let Some(offset) = self.program_offset(instructions) {
+            panic!("program {instructions:04x?} is still loaded at {offset}");
+        }
+    }
+
+    pub fn expect_claimed(&self, sm: u16) {
+        assert!(self.is_claimed(sm), "SM {sm} is not claimed");
+    }
+
+    pub fn expect_enabled(&self, sm: u16) {
+        assert!(self.is_enabled(sm), "SM {sm} is not enabled");
+    }
+
+    pub fn expect_disabled(&self, sm: u16) {
+        assert!(!self.is_enabled(sm), "SM {sm} is enabled");
+    }
+
+    pub fn expect_config(&self, sm: u16) -> SmConfig {
+        self.config(sm).unwrap_or_else(|| panic!("SM {sm} was never configured"))
+    }
+
+    ///// The "driver"
+
+    fn register(state: &MockState, addr: u32) -> u32 {
+        let field = |value: u32, bits: u32, lsb: u32| (value << lsb) & bits;
+        let sm_stride = 8 * 4;
+        match addr {
+            PROC_PIO_CTRL_OFFSET => state.sms.iter().enumerate().fold(0, |ctrl, (i, sm)| ctrl | (sm.enabled as u32) << i),
+            PROC_PIO_FSTAT_OFFSET => state.sms.iter().enumerate().fold(0, |fstat, (i, sm)| {
+                fstat | (sm.rx.is_empty() as u32) << (PROC_PIO_FSTAT_RXEMPTY_LSB + i as u32)
+                      | ((sm.rx.len() >= state.chip.fifo_depth as usize) as u32) << (PROC_PIO_FSTAT_RXFULL_LSB + i as u32)
+                      | 1 << (PROC_PIO_FSTAT_TXEMPTY_LSB + i as u32)
+            }),
+            PROC_PIO_FLEVEL_OFFSET => state.sms.iter().enumerate().fold(0, |flevel, (i, sm)| flevel | (sm.rx.len().min(15) as u32) << (i * 8 + 4)),
+            PROC_PIO_DBG_CFGINFO_OFFSET =>
+                field(state.chip.fifo_depth as u32,  PROC_PIO_DBG_CFGINFO_FIFO_DEPTH_BITS, PROC_PIO_DBG_CFGINFO_FIFO_DEPTH_LSB) |
+                field(state.chip.sm_count as u32,    PROC_PIO_DBG_CFGINFO_SM_COUNT_BITS,   PROC_PIO_DBG_CFGINFO_SM_COUNT_LSB) |
+                field(state.chip.instr_count as u32, PROC_PIO_DBG_CFGINFO_IMEM_SIZE_BITS,  PROC_PIO_DBG_CFGINFO_IMEM_SIZE_LSB),
+            addr if addr >= PROC_PIO_SM0_CLKDIV_OFFSET && addr < PROC_PIO_SM0_CLKDIV_OFFSET + sm_stride * state.sms.len() as u32 => {
+                let sm = &state.sms[((addr - PROC_PIO_SM0_CLKDIV_OFFSET) / sm_stride) as usize];
+                let (clkdiv, execctrl, shiftctrl, pinctrl) = sm.config.unwrap_or_default().to_raw();
+                match (addr - PROC_PIO_SM0_CLKDIV_OFFSET) % sm_stride / 4 {
+                    0 => clkdiv,
+                    1 => execctrl,
+                    2 => shiftctrl,
+                    3 => sm.pc as u32,
+                    4 => state.instr_mem.get(sm.pc as usize).copied().flatten().unwrap_or(0) as u32,
+                    5 => pinctrl,
+                    6 => sm.dmactrl[1],
+                    _ => sm.dmactrl[0],
+                }
+            },
+            _ => 0,
+        }
+    }
+
+    fn add_program(state: &mut MockState, args: &AddProgramArgs, commit: bool) -> Result<u32, Error> {
+        let len = args.num_instrs as usize;
+        let fits = |offset: usize| offset + len <= state.instr_mem.len() && state.instr_mem[offset..offset + len].iter().all(Option::is_none);
+        let offset = match args.origin {
+            0xffff => (0..state.instr_mem.len()).rev().find(|&offset| fits(offset)),
+            origin => fits(origin as usize).then_some(origin as usize),
+        };
+        let Some(offset) = offset else { return if commit { Err(errno(libc::EBUSY)) } else { Ok(0) } };
+        if !commit {
+            return Ok(1);
+        }
+        let instructions = args.instrs[..len].to_vec();
+        for (slot, &instr) in state.instr_mem[offset..offset + len].iter_mut().zip(&instructions) {
+            *slot = Some(instr);
+        }
+        state.programs.push((offset as u16, instructions));
+        Ok(offset as u32)
+    }
+
+    fn remove_program(state: &mut MockState, args: &RemoveProgramArgs) -> Result<u32, Error> {
+        let Some(i) = state.programs.iter().position(|(offset, instructions)| *offset == args.origin && instructions.len() == args.num_instrs as usize)
+            else { return Err(errno(libc::ENOENT)) };
+        let (offset, instructions) = state.programs.remove(i);
+        state.instr_mem[offset as usize..offset as usize + instructions.len()].fill(None);
+        Ok(1)
+    }
+
+    fn claim(state: &mut MockState, mask: u16) -> Result<u32, Error> {
+        if mask == 0 {
+            let Some(sm) = state.sms.iter().position(|sm| !sm.claimed) else { return Err(errno(libc::EBUSY)) };
+            state.sms[sm].claimed = true;
+            return Ok(sm as u32);
+        }
+        if state.sms.iter().enumerate().any(|(i, sm)| mask & 1 << i != 0 && sm.claimed) {
+            return Err(errno(libc::EBUSY));
+        }
+        Self::for_mask(state, mask, |sm| sm.claimed = true)
+    }
+
+    fn for_mask(state: &mut MockState, mask: u16, f: impl Fn(&mut MockSm)) -> Result<u32, Error> {
+        if mask >> state.sms.len() != 0 {
+            return Err(errno(libc::EINVAL));
+        }
+        state.sms.iter_mut().enumerate().filter(|&(i, _)| mask & 1 << i != 0).for_each(|(_, sm)| f(sm));
+        Ok(0)
+    }
+
+    fn sm_mut(state: &mut MockState, sm: u16) -> Result<&mut MockSm, Error> {
+        state.sms.get_mut(sm as usize).ok_or(errno(libc::EINVAL))
+    }
+
+    fn gpio_mut(state: &mut MockState, gpio: u16) -> Result<&mut MockGpio, Error> {
+        state.gpios.get_mut(gpio as usize).ok_or(errno(libc::EINVAL))
+    }
+
+    fn xfer(state: &mut MockState, sm: u16, dir: u16, data_bytes: usize, data: *const c_void) -> Result<u32, Error> {
+        let sm = Self::sm_mut(state, sm)?;
+        let words = data_bytes / size_of::<u32>();
+        if dir == XferDir::ToSm as u16 {
+            // Safety: sm_xfer_data() hands us `data_bytes` worth of `data`.
+            sm.tx.extend_from_slice(unsafe { std::slice::from_raw_parts(data as *const u32, words) });
+        } else {
+            // Safety: as above, and the kernel writes to it too.
+            let buf = unsafe { std::slice::from_raw_parts_mut(data as *mut u32, words) };
+            if sm.rx.len() < words {
+                return Err(Error::TimedOut);
+            }
+            buf.iter_mut().for_each(|word| *word = sm.rx.pop_front().unwrap_or(0));
+        }
+        Ok(0)
+    }
+}
+
+impl PioBackend for MockPio {
+    unsafe fn ioctl(&self, request: c_ulong, ptr: *mut c_void) -> Result<u32, Error> {
+        let state = &mut *lock(&self.state);
+        if let Some(i) = state.failures.iter().position(|&(name, _)| name == request_name(request)) {
+            return Err(errno(state.failures.remove(i).1));
+        }
+        unsafe {
+            match request {
+                PIO_IOC_SM_CONFIG_XFER | PIO_IOC_SM_CONFIG_XFER32 => Ok(0),
+                PIO_IOC_SM_XFER_DATA => {
+                    let args = args::<SmXferDataArgs>(ptr);
+                    Self::xfer(state, args.sm, args.dir, args.data_bytes as usize, args.data)
+                },
+                PIO_IOC_SM_XFER_DATA32 => {
+                    let args = args::<SmXferData32Args>(ptr);
+                    Self::xfer(state, args.sm, args.dir, args.data_bytes as usize, args.data)
+                },
+                PIO_IOC_READ_HW => {
+                    let args = args::<AccessHwArgs>(ptr);
+                    let data = std::slice::from_raw_parts_mut(args.data as *mut u32, args.len as usize / size_of::<u32>());
+                    for (i, word) in data.iter_mut().enumerate() {
+                        *word = Self::register(state, (args.addr & 0x0fff_ffff) + i as u32 * 4);
+                    }
+                    Ok(0)
+                },
+                PIO_IOC_WRITE_HW => Ok(0),
+                PIO_IOC_CAN_ADD_PROGRAM => Self::add_program(state, args::<AddProgramArgs>(ptr), false),
+                PIO_IOC_ADD_PROGRAM => Self::add_program(state, args::<AddProgramArgs>(ptr), true),
+                PIO_IOC_REMOVE_PROGRAM => Self::remove_program(state, args::<RemoveProgramArgs>(ptr)),
+                PIO_IOC_CLEAR_INSTR_MEM => {
+                    state.programs.clear();
+                    state.instr_mem.fill(None);
+                    Ok(1)
+                },
+                PIO_IOC_SM_CLAIM => Self::claim(state, args::<SmClaimArgs>(ptr).mask),
+                PIO_IOC_SM_UNCLAIM => Self::for_mask(state, args::<SmClaimArgs>(ptr).mask, |sm| sm.claimed = false),
+                PIO_IOC_SM_IS_CLAIMED => {
+                    let mask = args::<SmClaimArgs>(ptr).mask;
+                    Ok(state.sms.iter().enumerate().all(|(i, sm)| mask & 1 << i == 0 || sm.claimed) as u32)
+                },
+                PIO_IOC_SM_INIT => {
+                    let args = args::<SmInitArgs>(ptr);
+                    let sm = Self::sm_mut(state, args.sm)?;
+                    *sm = MockSm { claimed: sm.claimed, config: Some(args.config), pc: args.initial_pc,
+                                   pins: sm.pins, pindirs: sm.pindirs, tx: std::mem::take(&mut sm.tx), ..MockSm::default() };
+                    Ok(0)
+                },
+                PIO_IOC_SM_SET_CONFIG => {
+                    let args = args::<SmSetConfigArgs>(ptr);
+                    Self::sm_mut(state, args.sm)?.config = Some(args.config);
+                    Ok(0)
+                },
+                PIO_IOC_SM_EXEC => {
+                    let args = args::<SmExecArgs>(ptr);
+                    Self::sm_mut(state, args.sm)?.executed.push(args.instr);
+                    Ok(0)
+                },
+                PIO_IOC_SM_CLEAR_FIFOS => {
+                    Self::sm_mut(state, args::<SmClearFifosArgs>(ptr).sm)?.rx.clear();
+                    Ok(0)
+                },
+                PIO_IOC_SM_SET_CLKDIV => {
+                    let args = args::<SmSetClkdivArgs>(ptr);
+                    let sm = Self::sm_mut(state, args.sm)?;
+                    sm.config = Some(sm.config.unwrap_or_default().set_clkdiv_int_frac(ClkDiv { div: args.div_int, frac: args.div_frac })?);
+                    Ok(0)
+                },
+                PIO_IOC_SM_SET_PINS => {
+                    let args = args::<SmSetPinsArgs>(ptr);
+                    let sm = Self::sm_mut(state, args.sm)?;
+                    sm.pins = sm.pins & !args.mask | args.values & args.mask;
+                    Ok(0)
+                },
+                PIO_IOC_SM_SET_PINDIRS => {
+                    let args = args::<SmSetPindirsArgs>(ptr);
+                    let sm = Self::sm_mut(state, args.sm)?;
+                    sm.pindirs = sm.pindirs & !args.mask | args.dirs & args.mask;
+                    Ok(0)
+                },
+                PIO_IOC_SM_SET_ENABLED => {
+                    let args = args::<SmSetEnabledArgs>(ptr);
+                    let enable = args.enable != 0;
+                    Self::for_mask(state, args.mask, |sm| sm.enabled = enable)
+                },
+                PIO_IOC_SM_RESTART | PIO_IOC_SM_CLKDIV_RESTART => Self::for_mask(state, args::<SmRestartArgs>(ptr).mask, |_| {}),
+                PIO_IOC_SM_ENABLE_SYNC => Self::for_mask(state, args::<SmEnableSyncArgs>(ptr).mask, |sm| sm.enabled = true),
+                PIO_IOC_SM_PUT => {
+                    let args = args::<SmPutArgs>(ptr);
+                    Self::sm_mut(state, args.sm)?.tx.push(args.data);
+                    Ok(0)
+                },
+                PIO_IOC_SM_GET => {
+                    let args = args::<SmGetArgs>(ptr);
+                    let sm = Self::sm_mut(state, args.sm)?;
+                    args.data = match (sm.rx.pop_front(), args.blocking != 0) {
+                        (Some(data), _) => data,
+                        (None, true)    => return Err(Error::TimedOut),
+                        (None, false)   => 0,
+                    };
+                    Ok(0)
+                },
+                PIO_IOC_SM_SET_DMACTRL => {
+                    let args = args::<SmSetDmactrlArgs>(ptr);
+                    Self::sm_mut(state, args.sm)?.dmactrl[args.is_tx as usize] = args.ctrl;
+                    Ok(0)
+                },
+                PIO_IOC_SM_FIFO_STATE => {
+                    let args = args::<SmFifoStateArgs>(ptr);
+                    let depth = state.chip.fifo_depth as usize;
+                    let sm = Self::sm_mut(state, args.sm)?;
+                    // The TX FIFO is always empty: as far as anyone can tell, the SM consumes everything instantly.
+                    let level = if args.tx != 0 { 0 } else { sm.rx.len().min(depth) };
+                    args.level = level as u16;
+                    args.empty = (level == 0) as u8;
+                    args.full = (level >= depth) as u8;
+                    Ok(0)
+                },
+                PIO_IOC_SM_DRAIN_TX => Self::sm_mut(state, args::<SmClearFifosArgs>(ptr).sm).map(|_| 0),
+                PIO_IOC_GPIO_INIT => {
+                    let gpio = Self::gpio_mut(state, args::<GpioInitArgs>(ptr).gpio)?;
+                    gpio.function = Some(crate::gpio::Function::SIO as u16);
+                    gpio.input_enabled = true;
+                    Ok(0)
+                },
+                PIO_IOC_GPIO_SET_FUNCTION => {
+                    let args = args::<GpioSetFunctionArgs>(ptr);
+                    Self::gpio_mut(state, args.gpio)?.function = Some(args.func);
+                    Ok(0)
+                },
+                PIO_IOC_GPIO_SET_PULLS => {
+                    let args = args::<GpioSetPullsArgs>(ptr);
+                    let gpio = Self::gpio_mut(state, args.gpio)?;
+                    gpio.pull_up = args.up != 0;
+                    gpio.pull_down = args.down != 0;
+                    Ok(0)
+                },
+                PIO_IOC_GPIO_SET_INPUT_ENABLED => {
+                    let args = args::<GpioSetArgs>(ptr);
+                    Self::gpio_mut(state, args.gpio)?.input_enabled = args.value != 0;
+                    Ok(0)
+                },
+                PIO_IOC_GPIO_SET_DRIVE_STRENGTH => {
+                    let args = args::<GpioSetArgs>(ptr);
+                    Self::gpio_mut(state, args.gpio)?.drive_strength = args.value;
+                    Ok(0)
+                },
+                PIO_IOC_GPIO_SET_OUTOVER | PIO_IOC_GPIO_SET_INOVER | PIO_IOC_GPIO_SET_OEOVER =>
+                    Self::gpio_mut(state, args::<GpioSetArgs>(ptr).gpio).map(|_| 0),
+                _ => Err(errno(libc::ENOTTY)),
+            }
+        }
+    }
+
+    fn devname(&self) -> &Path {
+        Path::new("mock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PioFifoJoin, PioProgram};
+
+    const PROGRAM: [u16; 3] = [0x6008, 0x6018, 0x0000];
+
+    #[test]
+    fn programs() {
+        let mock = MockPio::new();
+        let pio = mock.pio();
+        let program = PioProgram::new(&PROGRAM, None);
+        let offset = pio.add_program(&program).unwrap();
+        assert_eq!(offset, 29);
+        mock.expect_program_loaded_at(&PROGRAM, offset);
+        assert!(pio.can_add_program_at_offset(&program, Some(0)).unwrap());
+        assert!(!pio.can_add_program_at_offset(&program, Some(28)).unwrap());
+        pio.remove_program(&program, Some(offset)).unwrap();
+        mock.expect_program_not_loaded(&PROGRAM);
+    }
+
+    #[test]
+    fn shared_programs() {
+        let mock = MockPio::new();
+        let pio = mock.pio();
+        let program = PioProgram::new(&PROGRAM, None);
+        let a = pio.load_program(&program).unwrap();
+        let b = pio.load_program(&program).unwrap();
+        assert_eq!(a.offset(), b.offset());
+        assert_eq!(mock.instruction_memory().iter().flatten().count(), PROGRAM.len());
+        drop(a);
+        mock.expect_program_loaded(&PROGRAM);
+        drop(b);
+        mock.expect_program_not_loaded(&PROGRAM);
+    }
+
+    #[test]
+    fn claims() {
+        let mock = MockPio::new();
+        let pio = mock.pio();
+        let sm = pio.sm_claim(2).unwrap();
+        mock.expect_claimed(2);
+        assert!(pio.sm_claim(2).is_err());
+        assert_eq!(pio.sm_claim_unused().unwrap().index(), 0);
+        sm.unclaim().unwrap();
+        assert!(!mock.is_claimed(2));
+        assert!(matches!(pio.sm_claim(4), Err(Error::BadSM { sm: 4, max: 4 })));
+    }
+
+    #[test]
+    fn state_machine() {
+        let mock = MockPio::new();
+        let pio = mock.pio();
+        let sm = pio.sm_claim_unused().unwrap();
+        let config = SmConfig::default().set_out_pins(4, 2).unwrap().set_fifo_join(PioFifoJoin::Tx).unwrap();
+        sm.init(5, &config).unwrap();
+        assert_eq!(mock.expect_config(sm.index()), config);
+        assert_eq!(mock.pc(sm.index()), 5);
+        assert_eq!(sm.get_config().unwrap(), config);
+        sm.set_enabled(true).unwrap();
+        mock.expect_enabled(sm.index());
+        assert!(sm.read_hw_state_machine().unwrap().enabled);
+
+        sm.put(1, true).unwrap();
+        sm.put(2, true).unwrap();
+        assert_eq!(mock.take_tx(sm.index()), [1, 2]);
+
+        mock.push_rx(sm.index(), &[7, 8]);
+        assert_eq!(sm.get_rx_fifo_level().unwrap(), 2);
+        assert_eq!(sm.get(true).unwrap(), 7);
+        assert_eq!(sm.get(true).unwrap(), 8);
+        assert!(matches!(sm.get(true), Err(Error::TimedOut)));
+
+        sm.set_consecutive_pindirs(4, 2, true).unwrap();
+        sm.set_pins_with_mask(0b10_0000, 0b11_0000).unwrap();
+        assert_eq!(mock.pindirs(sm.index()), 0b11_0000);
+        assert_eq!(mock.pins(sm.index()), 0b10_0000);
+        sm.exec(0xa042, false).unwrap();
+        assert_eq!(mock.executed(sm.index()), [0xa042]);
+    }
+
+    #[test]
+    fn pin_conflicts() {
+        let mock = MockPio::new();
+        let mut pio = mock.pio();
+        pio.set_pin_conflict_detection(true);
+        let (a, b) = (pio.sm_claim(0).unwrap(), pio.sm_claim(1).unwrap());
+        let config = SmConfig::default().set_out_pins(4, 2).unwrap();
+        a.init(0, &config).unwrap();
+        let overlapping = SmConfig::default().set_sideset_pins(5).unwrap().set_sideset(1, false, false).unwrap();
+        assert_eq!(b.init(0, &overlapping), Err(Error::PinConflict { sm: 1, other_sm: 0, pins: 0b10_0000 }));
+        assert_eq!(b.set_config(&overlapping), Err(Error::PinConflict { sm: 1, other_sm: 0, pins: 0b10_0000 }));
+        a.set_config(&overlapping).unwrap(); // An SM doesn't conflict with itself
+
+        // A failed init or set_config mustn't leave its pins recorded.
+        mock.fail_next("SM_INIT", libc::EIO);
+        assert!(b.init(0, &SmConfig::default().set_set_pins(8, 1).unwrap()).is_err());
+        mock.fail_next("SM_SET_CONFIG", libc::EIO);
+        assert!(b.set_config(&SmConfig::default().set_set_pins(9, 1).unwrap()).is_err());
+        a.init(0, &SmConfig::default().set_set_pins(8, 2).unwrap()).unwrap();
+
+        // Unclaiming releases them, unless the unclaim fails.
+        let c = pio.sm_claim(2).unwrap();
+        c.init(0, &SmConfig::default().set_set_pins(12, 1).unwrap()).unwrap();
+        c.unclaim().unwrap();
+        b.init(0, &SmConfig::default().set_set_pins(12, 1).unwrap()).unwrap();
+        mock.fail_next("SM_UNCLAIM", libc::EIO);
+        assert!(a.unclaim().is_err());
+        assert!(matches!(b.init(0, &SmConfig::default().set_set_pins(9, 1).unwrap()),
+                         Err(Error::PinConflict { sm: 1, other_sm: 0, pins: 0b10_0000_0000 })));
+    }
+
+    #[test]
+    fn discover_chip_config() {
+        let sizes = |chip: &Chip| (chip.instr_count, chip.sm_count, chip.fifo_depth);
+        let mock = MockPio::with_chip(Chip { instr_count: 16, sm_count: 2, fifo_depth: 4, ..Chip::new() });
+        let mut pio = Rp1PIO::with_backend(Box::new(mock.clone()), Chip::new());
+        pio.discover_chip_config();
+        assert_eq!(sizes(pio.chip()), (16, 2, 4));
+        assert!(matches!(pio.sm_claim(2), Err(Error::BadSM { sm: 2, max: 2 })));
+
+        // A kernel without READ_HW keeps the defaults.
+        let mut pio = Rp1PIO::with_backend(Box::new(mock.clone()), Chip::new());
+        mock.fail_next("READ_HW", libc::ENOTTY);
+        pio.discover_chip_config();
+        assert_eq!(sizes(pio.chip()), sizes(&Chip::new()));
+    }
+
+    #[test]
+    fn sm_claim_mask() {
+        // More SMs than the RP1's 4
+        let mock = MockPio::with_chip(Chip { sm_count: 6, ..Chip::new() });
+        let pio = mock.pio();
+        let sms = pio.sm_claim_mask(0b11_0010).unwrap();
+        assert_eq!(sms.iter().map(|sm| sm.index()).collect::<Vec<_>>(), [1, 4, 5]);
+        assert!((0..6).all(|sm| mock.is_claimed(sm) == (0b11_0010 & 1 << sm != 0)));
+        assert!(matches!(pio.sm_claim_mask(0b100_0000), Err(Error::BadSMMask { .. })));
+    }
+
+    #[test]
+    fn init_strict() {
+        let mock = MockPio::new();
+        let pio = mock.pio();
+        let sm = pio.sm_claim_unused().unwrap();
+        let program = PioProgram::new(&PROGRAM, None); // out pins, 8; out pins, 24; jmp 0
+        let offset = pio.add_program(&program).unwrap();
+        let wrapped = SmConfig::default().set_wrap(offset as u32, offset as u32 + 2).unwrap();
+        let config = wrapped.set_out_pins(0, 24).unwrap();
+        // Rejected without initializing anything
+        assert!(matches!(sm.init_strict(&program, offset, &SmConfig::default()),
+                         Err(Error::ParamErr { param: "wrap_target", .. })));
+        assert!(matches!(sm.init_strict(&program, offset, &wrapped), Err(Error::ParamErr { param: "out_count", .. })));
+        assert_eq!(mock.config(sm.index()), None);
+        sm.init_strict(&program, offset, &config).unwrap();
+        assert_eq!((mock.expect_config(sm.index()), mock.pc(sm.index())), (config, offset));
+    }
+
+    #[test]
+    fn set_frequency() {
+        let mock = MockPio::new();
+        let pio = mock.pio();
+        let sm = pio.sm_claim_unused().unwrap();
+        let sys = crate::pio_clock_hz() as f64;
+        let nominal = crate::pio_clock_source() == crate::ClockSource::Nominal;
+
+        let exact = sm.set_frequency(sys / 2.5).unwrap();
+        assert_eq!(mock.expect_config(sm.index()).get_clkdiv(), ClkDiv { div: 2, frac: 128 });
+        assert_eq!((exact.actual_hz, exact.error_hz(), exact.nominal), (sys / 2.5, 0.0, nominal));
+
+        let rounded = sm.set_frequency(sys / (100.0 + 0.4 / 256.0)).unwrap();
+        assert_eq!(mock.expect_config(sm.index()).get_clkdiv(), ClkDiv { div: 100, frac: 0 });
+        assert_eq!(rounded.actual_hz, sys / 100.0);
+        assert!(rounded.error_hz() > 0.0 && rounded.error_ppm() < 16.0, "{rounded:?}");
+
+        // Out of range leaves the divider alone.
+        assert!(matches!(sm.set_frequency(sys * 1.5), Err(Error::BadDiv { .. })));
+        assert!(matches!(sm.set_frequency(sys / 70_000.0), Err(Error::BadDiv { .. })));
+        assert_eq!(mock.expect_config(sm.index()).get_clkdiv(), ClkDiv { div: 100, frac: 0 });
+    }
+
+    #[test]
+    fn swap_program() {
+        const OLD: [u16; 2] = [0xe001, 0x0000]; // set pins, 1 / jmp 0
+        const NEW: [u16; 3] = [0xe002, 0xe003, 0x0000];
+        let mock = MockPio::new();
+        let pio = mock.pio();
+        let sm = pio.sm_claim(0).unwrap();
+        let mut loaded = pio.load_program(&PioProgram::new(&OLD, None)).unwrap();
+        sm.init(loaded.offset(), &SmConfig::default()).unwrap();
+        sm.set_enabled(true).unwrap();
+        sm.swap_program(&mut loaded, &PioProgram::new(&NEW, None)).unwrap();
+        mock.expect_program_not_loaded(&OLD);
+        mock.expect_program_loaded_at(&NEW, loaded.offset());
+        assert_eq!(mock.pc(0), loaded.offset());
+        mock.expect_enabled(0);
+
+        // Neither try at loading OLD works, so NEW goes back where it was.
+        let offset = loaded.offset();
+        mock.fail_next("ADD_PROGRAM", libc::EIO);
+        mock.fail_next("ADD_PROGRAM", libc::EIO);
+        let error = sm.swap_program(&mut loaded, &PioProgram::new(&OLD, None)).unwrap_err();
+        assert_eq!(error.errno(), Some(libc::EIO));
+        assert!(!matches!(error, Error::RollbackFailed { .. }));
+        mock.expect_program_loaded_at(&NEW, offset);
+        assert_eq!((mock.pc(0), loaded.offset()), (offset, offset));
+        mock.expect_enabled(0);
+
+        // Removing NEW to make room fails, so it stays loaded and stays tracked.
+        mock.fail_next("ADD_PROGRAM", libc::EIO);
+        mock.fail_next("REMOVE_PROGRAM", libc::EIO);
+        assert!(sm.swap_program(&mut loaded, &PioProgram::new(&OLD, None)).is_err());
+        mock.expect_program_loaded_at(&NEW, offset);
+        mock.expect_enabled(0);
+
+        // And then NEW can't be put back either, which leaves the SM stopped.
+        mock.fail_next("ADD_PROGRAM", libc::EIO);
+        mock.fail_next("ADD_PROGRAM", libc::EIO);
+        mock.fail_next("ADD_PROGRAM", libc::ENOSPC);
+        let error = sm.swap_program(&mut loaded, &PioProgram::new(&OLD, None)).unwrap_err();
+        let Error::RollbackFailed { error, rollback } = error else { panic!("{error:?}") };
+        assert_eq!((error.errno(), rollback.errno()), (Some(libc::EIO), Some(libc::ENOSPC)));
+        mock.expect_program_not_loaded(&NEW);
+        mock.expect_disabled(0);
+    }
+
+    #[test]
+    fn rx_drain_and_discard() {
+        let mock = MockPio::new();
+        let pio = mock.pio();
+        let sm = pio.sm_claim_unused().unwrap();
+        mock.push_rx(sm.index(), &[1, 2, 3, 4, 5]);
+        sm.discard_rx(2).unwrap();
+        assert_eq!(sm.get(true).unwrap(), 3);
+        assert_eq!(sm.drain_rx_fifo().unwrap(), 2);
+        assert!(sm.is_rx_fifo_empty().unwrap());
+        assert_eq!(sm.drain_rx_fifo().unwrap(), 0);
+        assert!(matches!(sm.discard_rx(1), Err(Error::TimedOut)));
+    }
+
+    #[test]
+    fn typed_exec() {
+        use crate::instruction::{Instruction, Operation, SetDestination, SideSet};
+        let mock = MockPio::new();
+        let pio = mock.pio();
+        let sm = pio.sm_claim_unused().unwrap();
+        sm.exec_set_pins(2).unwrap();
+        sm.exec_jmp(7).unwrap();
+        sm.exec_pull().unwrap();
+        sm.exec_sequence(&[Instruction::nop(), Instruction::nop().delay(3)]).unwrap();
+        assert_eq!(mock.executed(sm.index()), [0xe002, 0x0007, 0x80a0, 0xa042, 0xa342]);
+
+        let config = SmConfig::default().set_sideset(2, false, false).unwrap();
+        sm.init(0, &config).unwrap();
+        let set_x = Instruction::new(Operation::Set { destination: SetDestination::X, data: 5 });
+        assert!(sm.exec_jmp(0).is_err()); // No side value for a side-set that isn't optional
+        assert!(sm.exec_sequence(&[set_x.side(1), set_x]).is_err());
+        sm.exec_instr(set_x.side(1), true).unwrap();
+        let executed = mock.executed(sm.index()); // init() starts it over
+        assert_eq!(executed.len(), 1);
+        assert_eq!(Instruction::decode(executed[0], SideSet::new(2, false, false)).unwrap(), set_x.side(1));
+    }
+
+    #[test]
+    fn gpios() {
+        let mock = MockPio::new();
+        let pio = mock.pio();
+        pio.pio_gpio_init(3).unwrap();
+        pio.set_pulls(3, true, false).unwrap();
+        let gpio = mock.gpio(3);
+        assert_eq!(gpio.function, Some(crate::gpio::Function::PIO1 as u16));
+        assert!(gpio.pull_up && !gpio.pull_down);
+        assert!(pio.gpio_init(28).is_err());
+    }
+
+    #[test]
+    fn teardown() {
+        let mock = MockPio::new();
+        let mut pio = mock.pio();
+        pio.set_teardown_policy(crate::TeardownPolicy::DisableAndRemove);
+        pio.add_program(&PioProgram::new(&PROGRAM, None)).unwrap();
+        pio.sm_claim(1).unwrap().set_enabled(true).unwrap();
+        drop(pio);
+        mock.expect_disabled(1);
+        mock.expect_program_not_loaded(&PROGRAM);
+    }
+}
@@ -1,28 +1,163 @@
 // Copyright © 2025 David Caldwell <david@porkrind.org>
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::{ffi::c_void, fs::File, os::fd::AsRawFd, path::{Path, PathBuf}};
+use std::{collections::VecDeque, ffi::c_void, fs::File, path::Path, sync::Mutex, time::{Duration, Instant, SystemTime}};
 
 use libc::c_ulong;
 
-use crate::{proc_pio::*, Chip, Error, PIOInstance, SmConfig, GPIOS_MASK, GPIO_COUNT, GPIO_FUNC_PIO, INSTRUCTION_COUNT};
+use crate::{lock, pio_clock_hz, proc_pio::*, Chip, IoctlBackend, PioBackend, DeviceId, Error, Frequency, PIOInstance, SmConfig, GPIOS_MASK, GPIO_COUNT, GPIO_FUNC_PIO};
 use crate::gpio::*;
+use crate::instruction::{Instruction, JmpCondition, Operation, SetDestination, SideSet};
 use crate::ioctl::*;
 
 pub struct Rp1PIO {
     base: PIOInstance,
-    devname: PathBuf,
-    fd: std::os::fd::OwnedFd,
+    backend: Box<dyn PioBackend>,
+    driven_pins: Option<Mutex<Vec<u32>>>, // Per SM, when pin conflict detection is on.
+    teardown: TeardownPolicy,
+    fifo_poll_interval: Duration, // For StateMachine::wait_*()
+    owned: Mutex<Owned>,
+    shared: Mutex<Vec<SharedProgram>>, // Programs loaded through load_program().
+    errors: Mutex<VecDeque<(SystemTime, String)>>, // The last ERROR_HISTORY failed ioctls, oldest first.
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: crate::metrics::Metrics,
+}
+
+// What to clean up when an Rp1PIO is dropped. The kernel releases SM claims and instruction memory when the device is
+// closed, but it leaves the SMs themselves running (and driving pins) with whatever is in instruction memory.
+// Note that Drop doesn't run if the process is killed or aborts, so this only covers normal exits and unwinding
+// panics.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub enum TeardownPolicy {
+    #[default]
+    Leave,            // Leave everything running, as if the Rp1PIO had been leaked.
+    DisableSMs,       // Disable any SMs this Rp1PIO enabled.
+    DisableAndRemove, // Disable SMs and also remove any programs this Rp1PIO loaded.
+}
+
+// The SMs and programs this Rp1PIO is responsible for, for TeardownPolicy.
+#[derive(Debug,Default)]
+struct Owned {
+    enabled_sms: u16,
+    programs: Vec<(u16, u16)>, // (num_instrs, offset)
+}
+
+const ERROR_HISTORY: usize = 16;
+
+struct SharedProgram {
+    instructions: Vec<u16>,
+    offset: u16,
+    refs: usize,
+}
+
+// One event per ioctl, at trace level normally and debug level when it fails. Decoding the args isn't free so it only
+// happens if someone's listening.
+#[cfg(feature = "tracing")]
+unsafe fn trace_ioctl(request: c_ulong, args: *const c_void, result: &Result<u32, Error>, elapsed: std::time::Duration) {
+    use tracing::{event, enabled, Level};
+    let duration_us = elapsed.as_secs_f64() * 1e6;
+    match result {
+        Ok(r) if enabled!(Level::TRACE) =>
+            event!(Level::TRACE, op = request_name(request), args = unsafe { describe_args(request, args) }, result = r, duration_us),
+        Err(e) if enabled!(Level::DEBUG) =>
+            event!(Level::DEBUG, op = request_name(request), args = unsafe { describe_args(request, args) }, error = %e, duration_us),
+        _ => {},
+    }
 }
 
 impl Rp1PIO {
     pub fn new(index: usize) -> Result<Rp1PIO, Error> {
-        let devname = format!("/dev/pio{index}").into();
-        Ok(Rp1PIO {
-            base: PIOInstance::reserve(index)?,
-            fd: File::open(&devname)?.into(),
-            devname,
-        })
+        Rp1PIO::open_path(Rp1PIO::devname_in(Path::new("/dev"), index)?)
+    }
+
+    // For device nodes that aren't /dev/pio<N>: udev symlinks, nodes bind mounted into containers, etc.
+    pub fn open_path(path: impl AsRef<Path>) -> Result<Rp1PIO, Error> {
+        let devname = path.as_ref().to_path_buf();
+        let file = File::open(&devname)?;
+        let id = DeviceId::of(&file)?;
+        let mut pio = Rp1PIO::with_instance(PIOInstance::reserve(id, Chip::for_device(id))?,
+                                            Box::new(IoctlBackend::from_file(file, devname)));
+        pio.discover_chip_config();
+        Ok(pio)
+    }
+
+    // Runs everything through `backend` instead of the kernel. `chip` is taken as is.
+    pub fn with_backend(backend: Box<dyn PioBackend>, chip: Chip) -> Rp1PIO {
+        let base = PIOInstance::reserve(DeviceId::unique(), chip).expect("backend ids should be unique");
+        Rp1PIO::with_instance(base, backend)
+    }
+
+    fn with_instance(base: PIOInstance, backend: Box<dyn PioBackend>) -> Rp1PIO {
+        Rp1PIO {
+            #[cfg(feature = "metrics")]
+            metrics: crate::metrics::Metrics::new(base.chip.sm_count),
+            base,
+            backend,
+            driven_pins: None,
+            teardown: TeardownPolicy::default(),
+            fifo_poll_interval: Duration::from_micros(10),
+            owned: Mutex::new(Owned::default()),
+            shared: Mutex::new(Vec::new()),
+            errors: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // The PIO block describes itself in DBG_CFGINFO. If we can't read it, stick with the defaults.
+    pub(crate) fn discover_chip_config(&mut self) {
+        let mut cfginfo = [0];
+        if self.read_hw(PROC_PIO_DBG_CFGINFO_OFFSET, &mut cfginfo).is_err() {
+            return;
+        }
+        let field = |bits: u32, lsb: u32| ((cfginfo[0] & bits) >> lsb) as u16;
+        let chip = &mut self.base.chip;
+        match field(PROC_PIO_DBG_CFGINFO_IMEM_SIZE_BITS,  PROC_PIO_DBG_CFGINFO_IMEM_SIZE_LSB)  { 0 => {}, n => chip.instr_count = n }
+        match field(PROC_PIO_DBG_CFGINFO_SM_COUNT_BITS,   PROC_PIO_DBG_CFGINFO_SM_COUNT_LSB)   { 0 => {}, n => chip.sm_count = n }
+        match field(PROC_PIO_DBG_CFGINFO_FIFO_DEPTH_BITS, PROC_PIO_DBG_CFGINFO_FIFO_DEPTH_LSB) { 0 => {}, n => chip.fifo_depth = n }
+        #[cfg(feature = "metrics")]
+        { self.metrics = crate::metrics::Metrics::new(self.base.chip.sm_count); }
+    }
+
+    // When enabled, StateMachine::init() and set_config() fail with Error::PinConflict if the config would have the
+    // SM drive out/set/side-set pins that another SM (of this Rp1PIO) is already configured to drive.
+    pub fn set_pin_conflict_detection(&mut self, enabled: bool) {
+        self.driven_pins = enabled.then(|| Mutex::new(vec![0; self.base.chip.sm_count as usize]));
+    }
+
+    // Checks `pins` against the other SMs, runs `ioctl`, and only if that worked records `sm` as driving them. The
+    // lock is held across the ioctl so two SMs can't both pass the check.
+    fn track_driven_pins<T>(&self, sm: u16, pins: u32, ioctl: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+        let Some(ref driven_pins) = self.driven_pins else { return ioctl() };
+        let mut driven_pins = lock(driven_pins);
+        if let Some((other_sm, other_pins)) = driven_pins.iter().enumerate()
+                                                         .find(|&(other_sm, other_pins)| other_sm != sm as usize && other_pins & pins != 0) {
+            Err(Error::PinConflict { sm, other_sm: other_sm as u16, pins: other_pins & pins })?;
+        }
+        let result = ioctl()?;
+        driven_pins[sm as usize] = pins;
+        Ok(result)
+    }
+
+    pub fn set_teardown_policy(&mut self, policy: TeardownPolicy) {
+        self.teardown = policy;
+    }
+
+    pub fn teardown_policy(&self) -> TeardownPolicy {
+        self.teardown
+    }
+
+    // How often StateMachine::wait_tx_empty() and friends check the FIFOs. Shorter notices sooner, at the cost of more
+    // ioctls. 10 µs by default.
+    pub fn set_fifo_poll_interval(&mut self, interval: Duration) {
+        self.fifo_poll_interval = interval;
+    }
+
+    pub fn fifo_poll_interval(&self) -> Duration {
+        self.fifo_poll_interval
+    }
+
+    #[cfg(feature = "daemon")]
+    pub(crate) fn backend(&self) -> &dyn PioBackend {
+        self.backend.as_ref()
     }
 
     pub fn chip(&self) -> &Chip {
@@ -30,22 +165,64 @@ impl Rp1PIO {
     }
 
     pub fn devname(&self) -> &Path {
-        self.devname.as_path()
+        self.backend.devname()
     }
 
+    // Errors from the kernel get tagged with the ioctl and its args. TimedOut is left alone since it's the one
+    // callers expect to handle.
     unsafe fn rp1_ioctl_mut_ptr(&self, request: c_ulong, args: *mut c_void) -> Result<u32, Error> {
-        const NEG_EREMOTEIO: i32 = -libc::EREMOTEIO;
-        const NEG_ETIMEDOUT: i32 = -libc::ETIMEDOUT;
-        match unsafe {
-            libc::ioctl(self.fd.as_raw_fd(), request, args)
-        } {
-            NEG_EREMOTEIO   => Err(Error::RemoteIOErr),
-            NEG_ETIMEDOUT   => Err(Error::TimedOut),
-            -1              => Err(std::io::Error::last_os_error())?,
-            r@ ..-1         => Err(Error::Unknown(r)),
-            r@ 0..          => Ok(r as u32),
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("ioctl", op = request_name(request)).entered();
+        #[cfg(any(feature = "tracing", feature = "metrics"))]
+        let start = std::time::Instant::now();
+        let result = unsafe { self.backend.ioctl(request, args) };
+        #[cfg(feature = "metrics")]
+        unsafe { self.metrics.record(request, args, &result, start.elapsed()) };
+        #[cfg(feature = "tracing")]
+        unsafe { trace_ioctl(request, args, &result, start.elapsed()) };
+        result.map_err(|error| {
+            let (errno, source) = match error {
+                Error::IOError(source) => (source.raw_os_error().unwrap_or(libc::EIO), source),
+                Error::Unknown(_) | Error::RemoteIOErr => {
+                    let errno = error.errno().expect("kernel errors have an errno");
+                    (errno, std::io::Error::from_raw_os_error(errno))
+                },
+                Error::TimedOut => return error,
+                error => { self.record_error(format!("{}: {error}", request_name(request))); return error },
+            };
+            let error = Error::Ioctl { op: request_name(request), args: unsafe { describe_args(request, args) }, errno, source };
+            self.record_error(error.to_string());
+            error
+        })
+    }
+
+    fn record_error(&self, error: String) {
+        let mut errors = lock(&self.errors);
+        if errors.len() == ERROR_HISTORY {
+            errors.pop_front();
         }
+        errors.push_back((SystemTime::now(), error));
+    }
+
+    // The most recent failed ioctls (not counting timeouts), oldest first.
+    pub fn recent_errors(&self) -> Vec<(SystemTime, String)> {
+        lock(&self.errors).iter().cloned().collect()
     }
+
+    // (offset, instructions if known, how many LoadedPrograms share it) for each program this Rp1PIO has loaded.
+    // Programs loaded with add_program() only have their length tracked.
+    pub(crate) fn loaded_programs(&self) -> Vec<(u16, Result<Vec<u16>, u16>, usize)> {
+        let shared = lock(&self.shared);
+        let mut programs: Vec<_> = lock(&self.owned).programs.iter().map(|&(num_instrs, offset)| {
+            match shared.iter().find(|program| program.offset == offset && program.instructions.len() == num_instrs as usize) {
+                Some(program) => (offset, Ok(program.instructions.clone()), program.refs),
+                None          => (offset, Err(num_instrs), 0),
+            }
+        }).collect();
+        programs.sort_by_key(|&(offset, ..)| offset);
+        programs
+    }
+
     unsafe fn rp1_ioctl_const_ptr(&self, request: c_ulong, args: *const c_void) -> Result<u32, Error> {
         unsafe { self.rp1_ioctl_mut_ptr(request, args as *mut c_void) }
     }
@@ -66,10 +243,12 @@ impl Rp1PIO {
     }
 
     fn check_sm_mask(&self, mask: u16) -> Result<(), Error> {
-        if mask < (1 << self.base.chip.sm_count) {
+        // SM masks are u16 in the ioctl ABI, so that's as many SMs as a chip can have.
+        let all_sms = ((1_u32 << self.base.chip.sm_count.min(16)) - 1) as u16;
+        if mask & !all_sms == 0 {
             Ok(())
         } else {
-            Err(Error::BadSMMask { sm_mask: mask, max: (1 << self.base.chip.sm_count) - 1 })
+            Err(Error::BadSMMask { sm_mask: mask, max: all_sms })
         }
     }
 
@@ -98,32 +277,34 @@ impl Rp1PIO {
     }
 
     fn add_program_args(&self, program: &PioProgram, offset: Option<u16>) -> Result<AddProgramArgs, Error> {
+        let instr_count = self.base.chip.instr_count;
         let offset = match (program.origin, offset) {
             (..0,         None)         => !0,
-            (..0,         Some(offset)) => offset as u16,
+            (..0,         Some(offset)) => offset,
             (origin, None)              => origin as u16,
             (origin, Some(offset)) if origin == offset as i8
                                         => origin as u16,
             (origin, Some(offset))      =>
                 Err(Error::OffsetOriginMismatch { origin: origin as u8, offset })?,
         };
-        if offset != !0 && offset >= INSTRUCTION_COUNT {
-            Err(Error::OffsetTooLarge { offset, max: INSTRUCTION_COUNT })?;
+        if offset != !0 && offset >= instr_count {
+            Err(Error::OffsetTooLarge { offset, max: instr_count })?;
         }
-        if program.instructions.len() >= INSTRUCTION_COUNT as usize {
-            Err(Error::TooManyInstructions { instructions: program.instructions.len(), max: INSTRUCTION_COUNT })?;
+        if program.instructions.len() >= instr_count as usize {
+            Err(Error::TooManyInstructions { instructions: program.instructions.len(), max: instr_count })?;
         }
-        if offset != !0 && offset as usize + program.instructions.len() > INSTRUCTION_COUNT as usize {
-            Err(Error::TooManyInstructions { instructions: program.instructions.len(), max: INSTRUCTION_COUNT - offset })?;
+        if offset != !0 && offset as usize + program.instructions.len() > instr_count as usize {
+            Err(Error::TooManyInstructions { instructions: program.instructions.len(), max: instr_count - offset })?;
+        }
+        if program.instructions.len() > MAX_PROGRAM_INSTRS {
+            Err(Error::TooManyInstructions { instructions: program.instructions.len(), max: MAX_PROGRAM_INSTRS as u16 })?;
         }
         let mut args = AddProgramArgs {
             num_instrs: program.instructions.len() as u16,
             origin: offset,
-            instrs: [0; INSTRUCTION_COUNT as usize],
+            instrs: [0; MAX_PROGRAM_INSTRS],
         };
-        for (i, insn) in program.instructions.iter().enumerate() {
-            args.instrs[i] = *insn;
-        }
+        args.instrs[..program.instructions.len()].copy_from_slice(&program.instructions);
         Ok(args)
     }
 
@@ -139,8 +320,9 @@ impl Rp1PIO {
 
     pub fn add_program_at_offset(&self, program: &PioProgram, offset: Option<u16>) -> Result<u16, Error> {
         let args = self.add_program_args(program, offset)?;
-        self.rp1_ioctl(PIO_IOC_ADD_PROGRAM, &args)
-            .map(|offset| offset as u16)
+        let offset = self.rp1_ioctl(PIO_IOC_ADD_PROGRAM, &args)? as u16;
+        lock(&self.owned).programs.push((args.num_instrs, offset));
+        Ok(offset)
     }
 
     pub fn add_program(&self, program: &PioProgram) -> Result<u16, Error> {
@@ -151,51 +333,129 @@ impl Rp1PIO {
         let args = RemoveProgramArgs { num_instrs: program.instructions.len() as u16,
                                            origin: offset.unwrap_or(!0),
         };
-        if program.instructions.len() >= INSTRUCTION_COUNT as usize {
-            Err(Error::TooManyInstructions { instructions: program.instructions.len(), max: INSTRUCTION_COUNT })?;
+        let instr_count = self.base.chip.instr_count;
+        if program.instructions.len() >= instr_count as usize {
+            Err(Error::TooManyInstructions { instructions: program.instructions.len(), max: instr_count })?;
         }
-        if args.origin != !0 && args.origin as usize + program.instructions.len() > INSTRUCTION_COUNT as usize {
-            Err(Error::TooManyInstructions { instructions: program.instructions.len(), max: INSTRUCTION_COUNT - args.origin })?;
+        if args.origin != !0 && args.origin as usize + program.instructions.len() > instr_count as usize {
+            Err(Error::TooManyInstructions { instructions: program.instructions.len(), max: instr_count - args.origin })?;
         }
-        self.rp1_ioctl(PIO_IOC_REMOVE_PROGRAM, &args)
-            .map(|r| r != 0)
+        let removed = self.rp1_ioctl(PIO_IOC_REMOVE_PROGRAM, &args)? != 0;
+        let mut owned = lock(&self.owned);
+        if let Some(i) = owned.programs.iter().position(|&(num_instrs, offset)| num_instrs == args.num_instrs &&
+                                                                                  (args.origin == !0 || offset == args.origin)) {
+            owned.programs.remove(i);
+        }
+        Ok(removed)
     }
 
-    pub fn clear_instruction_memory(&self) -> Result<bool, Error> {
-        unsafe {
-            self.rp1_ioctl_const_ptr(PIO_IOC_CLEAR_INSTR_MEM, std::ptr::null::<c_void>() as *const c_void)
+    // Loads `program`, unless an identical one has already been loaded through here (at an offset that satisfies
+    // `program`'s origin), in which case that one is shared. It gets removed when the last LoadedProgram referring to
+    // it is dropped.
+    pub fn load_program(&self, program: &PioProgram) -> Result<LoadedProgram<'_>, Error> {
+        self.load_program_at_offset(program, None)
+    }
+
+    pub fn load_program_at_offset(&self, program: &PioProgram, offset: Option<u16>) -> Result<LoadedProgram<'_>, Error> {
+        let mut shared = lock(&self.shared);
+        let origin = offset.or(program.origin().map(|origin| origin as u16));
+        let offset = match shared.iter_mut().find(|loaded| loaded.instructions == program.instructions &&
+                                                            origin.is_none_or(|origin| origin == loaded.offset)) {
+            Some(loaded) => {
+                loaded.refs += 1;
+                loaded.offset
+            },
+            None => {
+                let offset = self.add_program_at_offset(program, offset)?;
+                shared.push(SharedProgram { instructions: program.instructions.clone(), offset, refs: 1 });
+                offset
+            },
+        };
+        Ok(LoadedProgram { pio: self, program: program.clone(), offset })
+    }
+
+    // Plans a layout for all of `programs` (see layout::plan()) around what this Rp1PIO has already loaded, then loads
+    // them. Programs loaded by other processes aren't known about, so this can still fail. Either way, nothing stays
+    // loaded unless everything did.
+    pub fn load_programs(&self, programs: &[&PioProgram]) -> Result<Vec<LoadedProgram<'_>>, Error> {
+        let used = lock(&self.owned).programs.iter()
+            .fold(0_u64, |used, &(num_instrs, offset)| used | ((1_u64 << num_instrs) - 1) << offset);
+        let offsets = crate::layout::plan(programs, self.base.chip.instr_count, used)?;
+        programs.iter().zip(offsets)
+            .map(|(program, offset)| self.load_program_at_offset(program, Some(offset)))
+            .collect()
+    }
+
+    // Puts back a program that swap_program() unloaded out from under its LoadedProgram.
+    fn restore_program(&self, loaded: &LoadedProgram) -> Result<(), Error> {
+        self.add_program_at_offset(&loaded.program, Some(loaded.offset))?;
+        lock(&self.shared).push(SharedProgram { instructions: loaded.program.instructions.clone(),
+                                                         offset: loaded.offset, refs: 1 });
+        Ok(())
+    }
+
+    fn release_program(&self, program: &PioProgram, offset: u16) -> Result<(), Error> {
+        let mut shared = lock(&self.shared);
+        let Some(i) = shared.iter().position(|loaded| loaded.offset == offset && loaded.instructions == program.instructions)
+            else { return Ok(()) };
+        shared[i].refs -= 1;
+        if shared[i].refs == 0 {
+            shared.remove(i);
+            self.remove_program(program, Some(offset))?;
         }
-        .map(|r| r != 0)
+        Ok(())
+    }
+
+    pub fn clear_instruction_memory(&self) -> Result<bool, Error> {
+        let cleared = unsafe {
+            self.rp1_ioctl_const_ptr(PIO_IOC_CLEAR_INSTR_MEM, std::ptr::null::<c_void>())
+        }? != 0;
+        lock(&self.owned).programs.clear();
+        Ok(cleared)
     }
 
     pub fn sm_claim(&self, sm: u16) -> Result<StateMachine<'_>, Error> {
         self.check_sm_param(sm)?;
         let args = SmClaimArgs { mask: 1 << sm };
         self.rp1_ioctl(PIO_IOC_SM_CLAIM, &args)?;
-        Ok(StateMachine { pio: &self, index: sm })
+        Ok(StateMachine { pio: self, index: sm })
     }
 
     pub fn sm_claim_mask(&self, mask: u16) -> Result<Vec<StateMachine<'_>>, Error> {
         self.check_sm_mask(mask)?;
         let args = SmClaimArgs { mask };
         self.rp1_ioctl(PIO_IOC_SM_CLAIM, &args)?;
-        (0..4).filter_map(|sm| match mask & 1<<sm {
+        (0..self.base.chip.sm_count).filter_map(|sm| match mask & 1<<sm {
             0 => None,
-            _ => Some(Ok(StateMachine { pio: &self, index: sm })),
+            _ => Some(Ok(StateMachine { pio: self, index: sm })),
         }).collect()
     }
 
     pub fn sm_claim_unused(&self) -> Result<StateMachine<'_>, Error> {
         let args = SmClaimArgs { mask: 0 };
-        Ok(StateMachine { pio: &self,
+        Ok(StateMachine { pio: self,
                           index: self.rp1_ioctl(PIO_IOC_SM_CLAIM, &args)? as u16 })
     }
 
+    pub fn sm_is_claimed(&self, sm: u16) -> Result<bool, Error> {
+        self.check_sm_param(sm)?;
+        let args = SmClaimArgs { mask: 1 << sm };
+        self.rp1_ioctl(PIO_IOC_SM_IS_CLAIMED, &args)
+            .map(|r| r > 0)
+    }
+
+    // For callers that own the Rp1PIO and so can't hold on to a borrowed StateMachine. Does not claim.
+    pub(crate) fn sm(&self, index: u16) -> StateMachine<'_> {
+        StateMachine { pio: self, index }
+    }
+
     pub fn sm_set_enabled_mask(&self, mask: u16, enabled:bool) -> Result<(), Error> {
         self.check_sm_mask(mask)?;
         let args = SmSetEnabledArgs { mask, enable: enabled.into(), rsvd:0 };
-        self.rp1_ioctl(PIO_IOC_SM_SET_ENABLED, &args)
-            .map(|_| ())
+        self.rp1_ioctl(PIO_IOC_SM_SET_ENABLED, &args)?;
+        let mut owned = lock(&self.owned);
+        if enabled { owned.enabled_sms |= mask } else { owned.enabled_sms &= !mask }
+        Ok(())
     }
 
     pub fn sm_restart_mask(&self, mask: u16) -> Result<(), Error> {
@@ -215,14 +475,15 @@ impl Rp1PIO {
     pub fn sm_enable_sync(&self, mask: u16) -> Result<(), Error> {
         self.check_sm_mask(mask)?;
         let args = SmEnableSyncArgs { mask };
-        self.rp1_ioctl(PIO_IOC_SM_ENABLE_SYNC, &args)
-            .map(|_| ())
+        self.rp1_ioctl(PIO_IOC_SM_ENABLE_SYNC, &args)?;
+        lock(&self.owned).enabled_sms |= mask;
+        Ok(())
     }
 
 
     ///// GPIO Stuff. FIXME: Should this go somehwere else?? Or perhaps be folded into rpi-pal?
 
-    fn check_gpio(&self, gpio: u16) -> Result<(), Error> {
+    pub(crate) fn check_gpio(&self, gpio: u16) -> Result<(), Error> {
         if gpio < GPIO_COUNT as u16 { Ok(()) }
         else { Err(Error::BadGPIO { gpio, max: GPIO_COUNT }) }
     }
@@ -302,6 +563,69 @@ impl Rp1PIO {
         let args = AccessHwArgs { addr, len: data.len() as u32, data: data as *const [u32] as *mut c_void };
         self.rp1_ioctl(PIO_IOC_WRITE_HW, &args)
     }
+
+    // The raw state of any SM, claimed or not (by us or anyone else).
+    pub fn read_hw_state_machine(&self, sm: u16) -> Result<StateMachineHw, Error> {
+        // Taken from piolib/examples/rp1sm.c in https://github.com/raspberrypi/utils
+        let mut data = [0; 8];
+        self.read_hw(PROC_PIO_SM0_CLKDIV_OFFSET + sm as u32 * 8 * 4, &mut data)?;
+        let mut ctrl_data = [0; 1];
+        self.read_hw(PROC_PIO_CTRL_OFFSET, &mut ctrl_data)?;
+        Ok(StateMachineHw {
+            ctrl       : ctrl_data[0],
+            enabled    : ctrl_data[0] >> sm as u32 & 1 != 0,
+            clkdiv     : data[0],
+            execctrl   : data[1],
+            shiftctrl  : data[2],
+            pc         : data[3],
+            instr      : data[4],
+            pinctrl    : data[5],
+            dmactrl_tx : data[6],
+            dmactrl_rx : data[7],
+        })
+    }
+
+    pub fn read_hw_fifo(&self, sm: u16) -> Result<FifoHw, Error> {
+        // Taken from piolib/examples/rp1sm.c in https://github.com/raspberrypi/utils
+        let mut data = [0; 4];
+        self.read_hw(PROC_PIO_FSTAT_OFFSET, &mut data)?;
+        let raw = RawFifoHw {
+            fstat   : data[0],
+            flevel  : data[2],
+            flevel2 : data[3],
+        };
+        Ok(FifoHw {
+            tx: FifoState {
+                level: ((raw.flevel >> (sm * 8)) & 0xf) + (((raw.flevel2 >> (sm * 8)) & 1) << 4),
+                full: raw.fstat & (1<<PROC_PIO_FSTAT_TXFULL_LSB << sm) != 0,
+                empty: raw.fstat & (1<<PROC_PIO_FSTAT_TXEMPTY_LSB << sm) != 0,
+            },
+            rx: FifoState {
+                level: ((raw.flevel >> (sm * 8 + 4)) & 0xf) + (((raw.flevel2 >> (sm * 8 + 4)) & 1) << 4),
+                full: raw.fstat & (1<<PROC_PIO_FSTAT_RXFULL_LSB << sm) != 0,
+                empty: raw.fstat & (1<<PROC_PIO_FSTAT_RXEMPTY_LSB << sm) != 0,
+            },
+            raw,
+        })
+    }
+}
+
+impl Drop for Rp1PIO {
+    fn drop(&mut self) {
+        if self.teardown == TeardownPolicy::Leave {
+            return;
+        }
+        let owned = std::mem::take(self.owned.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        if owned.enabled_sms != 0 {
+            let args = SmSetEnabledArgs { mask: owned.enabled_sms, enable: 0, rsvd: 0 };
+            let _ = self.rp1_ioctl(PIO_IOC_SM_SET_ENABLED, &args);
+        }
+        if self.teardown == TeardownPolicy::DisableAndRemove {
+            for (num_instrs, origin) in owned.programs {
+                let _ = self.rp1_ioctl(PIO_IOC_REMOVE_PROGRAM, &RemoveProgramArgs { num_instrs, origin });
+            }
+        }
+    }
 }
 
 
@@ -311,9 +635,17 @@ pub struct StateMachine<'a> {
 }
 
 impl<'a> StateMachine<'a> {
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn pio(&self) -> &'a Rp1PIO {
+        self.pio
+    }
+
     pub fn unclaim(self) -> Result<bool, Error> {
         let args = SmClaimArgs { mask: 1 << self.index };
-        self.pio.rp1_ioctl(PIO_IOC_SM_UNCLAIM, &args)
+        self.pio.track_driven_pins(self.index, 0, || self.pio.rp1_ioctl(PIO_IOC_SM_UNCLAIM, &args))
             .map(|r| r != 0)
     }
 
@@ -324,17 +656,87 @@ impl<'a> StateMachine<'a> {
     }
 
     pub fn init(&self, initial_pc: u16, config: &SmConfig) -> Result<(), Error> {
-        if initial_pc >= INSTRUCTION_COUNT {
-            Err(Error::BadPC { pc: initial_pc, max: INSTRUCTION_COUNT })?;
+        let instr_count = self.pio.base.chip.instr_count;
+        if initial_pc >= instr_count {
+            Err(Error::BadPC { pc: initial_pc, max: instr_count })?;
+        }
+        let (wrap_target, wrap) = config.get_wrap();
+        if wrap >= instr_count as u32 || wrap_target >= instr_count as u32 {
+            Err(Error::ParamErr { param: "wrap", should_be: format!("< {instr_count}") })?;
         }
         let args = SmInitArgs { sm: self.index, initial_pc, config: *config };
-        self.pio.rp1_ioctl(PIO_IOC_SM_INIT, &args)
+        self.pio.track_driven_pins(self.index, config.driven_pins(), || self.pio.rp1_ioctl(PIO_IOC_SM_INIT, &args))
             .map(|_| ())
     }
 
+    // Like init(), but starts at the beginning of `program` (loaded at `offset`) and first checks that `config`
+    // actually makes sense for it.
+    pub fn init_strict(&self, program: &PioProgram, offset: u16, config: &SmConfig) -> Result<(), Error> {
+        config.validate_for(program, offset)?;
+        self.init(offset, config)
+    }
+
+    // Replaces the program this SM is running with `new`, keeping the rest of its configuration. The SM is paused
+    // while its program is swapped and restarted at the beginning of `new`, with the wrap adjusted to suit. If `new`
+    // doesn't fit alongside `old` and nothing else is sharing `old`, `old` is unloaded first to make room. On
+    // success `old` refers to the new program. On failure the old program is put back and the SM is restarted where
+    // it was (with its FIFOs cleared). If the old program can't be put back the SM is left stopped, and the error is
+    // Error::RollbackFailed.
+    pub fn swap_program(&self, old: &mut LoadedProgram<'a>, new: &PioProgram) -> Result<(), Error> {
+        let hw = self.read_hw_state_machine()?;
+        let config = SmConfig::from_hw(&hw);
+        self.set_enabled(false)?;
+
+        let result = self.load_replacement(old, new).and_then(|(loaded, removed_old)| {
+            let (wrap_target, wrap) = loaded.wrap();
+            match config.set_wrap(wrap_target, wrap).and_then(|new_config| self.init(loaded.offset, &new_config)) {
+                Ok(()) => Ok(loaded),
+                Err(e) => {
+                    drop(loaded);
+                    Err(if removed_old { Error::rolled_back(e, self.pio.restore_program(old)) } else { e })
+                },
+            }
+        });
+        let restart = || if hw.enabled { self.set_enabled(true) } else { Ok(()) };
+        match result {
+            Ok(loaded) => {
+                *old = loaded;
+                restart()
+            },
+            // The old program's gone, so there's nothing safe to run
+            Err(e @ Error::RollbackFailed { .. }) => Err(e),
+            Err(e) => {
+                let pc = (hw.pc & PROC_PIO_SM0_ADDR_BITS) as u16;
+                Err(Error::rolled_back(e, self.init(pc, &config).and_then(|()| restart())))
+            },
+        }
+    }
+
+    // Loads `new`, unloading `old` first if that's what it takes. Returns whether `old` was unloaded.
+    fn load_replacement(&self, old: &LoadedProgram<'a>, new: &PioProgram) -> Result<(LoadedProgram<'a>, bool), Error> {
+        let first_try = match self.pio.load_program(new) {
+            Ok(loaded) => return Ok((loaded, false)),
+            Err(e) => e,
+        };
+        let mut shared = lock(&self.pio.shared);
+        let Some(i) = shared.iter().position(|loaded| loaded.offset == old.offset && loaded.instructions == old.program.instructions)
+            else { return Err(first_try) };
+        if shared[i].refs > 1 {
+            return Err(first_try);
+        }
+        self.pio.remove_program(&old.program, Some(old.offset))?;
+        shared.remove(i); // Only once it's really gone, so old's Drop still frees it otherwise
+        drop(shared);
+        match self.pio.load_program(new) {
+            Ok(loaded) => Ok((loaded, true)),
+            Err(e) => Err(Error::rolled_back(e, self.pio.restore_program(old))),
+        }
+    }
+
     pub fn set_config(&self, config: &SmConfig) -> Result<(), Error> {
         let args = SmInitArgs { sm: self.index, initial_pc:0, config: *config };
-        self.pio.rp1_ioctl(PIO_IOC_SM_SET_CONFIG, &args)
+        self.pio.track_driven_pins(self.index, config.driven_pins(),
+                                   || self.pio.rp1_ioctl(PIO_IOC_SM_SET_CONFIG, &args))
             .map(|_| ())
     }
 
@@ -344,6 +746,52 @@ impl<'a> StateMachine<'a> {
             .map(|_| ())
     }
 
+    // Like exec(), but encoded for whatever side-set the SM is configured with (read back from it each time, so keep
+    // to exec() where speed matters). Without a side() value that's an error if the side-set isn't optional, rather
+    // than silently driving the side-set pins low.
+    pub fn exec_instr(&self, instr: Instruction, blocking: bool) -> Result<(), Error> {
+        self.exec(instr.encode(self.side_set()?)?, blocking)
+    }
+
+    // Runs each in turn, waiting for one to finish before the next. They're all encoded first, so a bad one means
+    // none of them run.
+    pub fn exec_sequence(&self, instrs: &[Instruction]) -> Result<(), Error> {
+        let side_set = self.side_set()?;
+        let encoded = instrs.iter().map(|instr| instr.encode(side_set)).collect::<Result<Vec<u16>, Error>>()?;
+        for instr in encoded {
+            self.exec(instr, true)?;
+        }
+        Ok(())
+    }
+
+    // `address` is absolute, so add the program's offset().
+    pub fn exec_jmp(&self, address: u8) -> Result<(), Error> {
+        self.exec_instr(Instruction::new(Operation::Jmp { condition: JmpCondition::Always, address }), false)
+    }
+
+    pub fn exec_set_pins(&self, value: u8) -> Result<(), Error> {
+        self.exec_instr(Instruction::new(Operation::Set { destination: SetDestination::Pins, data: value }), false)
+    }
+
+    pub fn exec_set_pindirs(&self, value: u8) -> Result<(), Error> {
+        self.exec_instr(Instruction::new(Operation::Set { destination: SetDestination::Pindirs, data: value }), false)
+    }
+
+    // `pull block`, which stalls the SM until there's something in the TX FIFO.
+    pub fn exec_pull(&self) -> Result<(), Error> {
+        self.exec_instr(Instruction::new(Operation::Pull { if_empty: false, block: true }), false)
+    }
+
+    // `push noblock`, so a full RX FIFO loses the ISR rather than stalling.
+    pub fn exec_push(&self) -> Result<(), Error> {
+        self.exec_instr(Instruction::new(Operation::Push { if_full: false, block: false }), false)
+    }
+
+    fn side_set(&self) -> Result<SideSet, Error> {
+        let (bit_count, optional, pindirs) = self.get_config()?.get_sideset();
+        Ok(SideSet::new(bit_count.saturating_sub(optional as u32) as u8, optional, pindirs))
+    }
+
     pub fn clear_fifos(&self) -> Result<(), Error> {
         let args = SmClearFifosArgs { sm: self.index };
         self.pio.rp1_ioctl(PIO_IOC_SM_CLEAR_FIFOS, &args)
@@ -360,6 +808,14 @@ impl<'a> StateMachine<'a> {
         self.set_clkdiv_int_frac(div.try_into()?)
     }
 
+    pub fn set_frequency(&self, hz: f64) -> Result<Frequency, Error> {
+        let clock_hz = pio_clock_hz() as f64;
+        let div = ClkDiv::for_frequency(clock_hz, hz)?;
+        self.set_clkdiv_int_frac(div)?;
+        Ok(Frequency { target_hz: hz, actual_hz: div.actual_frequency(clock_hz),
+                       nominal: crate::pio_clock_source() == crate::ClockSource::Nominal })
+    }
+
     pub fn set_pins(&self, pin_values: u32) -> Result<(), Error> {
         self.set_pins_with_mask(pin_values, GPIOS_MASK)
         // let args = SmSetPinsArgs { sm: self.index, values: pin_values, mask: GPIOS_MASK, rsvd:0 };
@@ -450,70 +906,116 @@ impl<'a> StateMachine<'a> {
         Ok(self.fifo_state(true)?.level)
     }
 
+    // Waits for the SM to have pulled everything out of the TX FIFO (though not for it to have finished with the last
+    // word). These poll at the Rp1PIO's fifo_poll_interval() and give up with Error::TimedOut once `timeout` is up.
+    pub fn wait_tx_empty(&self, timeout: Duration) -> Result<(), Error> {
+        self.wait_fifo(true, timeout, |state| state.empty)
+    }
+
+    // Waits for room to put() `n` words without blocking.
+    pub fn wait_tx_space(&self, n: u32, timeout: Duration) -> Result<(), Error> {
+        let depth = self.fifo_depth(true, n)?;
+        self.wait_fifo(true, timeout, |state| depth - state.level.min(depth) >= n)
+    }
+
+    // Waits for `n` words to get() without blocking.
+    pub fn wait_rx_available(&self, n: u32, timeout: Duration) -> Result<(), Error> {
+        if n > self.pio.chip().fifo_depth as u32 {
+            self.fifo_depth(false, n)?; // Only fits if the TX FIFO's been joined on
+        }
+        self.wait_fifo(false, timeout, |state| state.level >= n)
+    }
+
+    fn wait_fifo(&self, tx: bool, timeout: Duration, done: impl Fn(&FifoState) -> bool) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if done(&self.fifo_state(tx)?) {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                Err(Error::TimedOut)?;
+            }
+            std::thread::sleep(self.pio.fifo_poll_interval.min(deadline - now));
+        }
+    }
+
+    // Twice as deep when the other FIFO's been joined onto it, which takes reading SHIFTCTRL. Too shallow for `n` is
+    // an error, since waiting for that would never end.
+    fn fifo_depth(&self, tx: bool, n: u32) -> Result<u32, Error> {
+        let stride = PROC_PIO_SM1_SHIFTCTRL_OFFSET - PROC_PIO_SM0_SHIFTCTRL_OFFSET;
+        let mut shiftctrl = [0];
+        self.pio.read_hw(PROC_PIO_SM0_SHIFTCTRL_OFFSET + self.index as u32 * stride, &mut shiftctrl)?;
+        let joined = |bits: u32| shiftctrl[0] & bits != 0;
+        let depth = self.pio.chip().fifo_depth as u32;
+        let depth = match (joined(PROC_PIO_SM0_SHIFTCTRL_FJOIN_TX_BITS), joined(PROC_PIO_SM0_SHIFTCTRL_FJOIN_RX_BITS)) {
+            (true, false) => if tx { depth * 2 } else { 0 },
+            (false, true) => if tx { 0 } else { depth * 2 },
+            _             => depth,
+        };
+        if n > depth {
+            Err(Error::ParamErr { param: "n", should_be: format!("<= {depth} (the FIFO depth)") })?;
+        }
+        Ok(depth)
+    }
+
     pub fn drain_tx_fifo(&self) -> Result<(), Error> {
         let args = SmClearFifosArgs { sm: self.index };
         self.pio.rp1_ioctl(PIO_IOC_SM_DRAIN_TX, &args)
             .map(|_| ())
     }
 
+    // Throws away what's in the RX FIFO right now, returning how many words that was. Anything the SM pushes meanwhile
+    // stays, so this always finishes. Unlike clear_fifos() it leaves the TX FIFO alone.
+    pub fn drain_rx_fifo(&self) -> Result<usize, Error> {
+        let level = self.get_rx_fifo_level()?.min(self.pio.chip().fifo_depth as u32 * 2); // Joined, at the most
+        for _ in 0..level {
+            self.get(false)?;
+        }
+        Ok(level as usize)
+    }
+
+    // Throws away the next `n` words from the RX FIFO, waiting for them like get(true) does. If one doesn't come (an
+    // Error::TimedOut, say) the ones before it are gone already and the error doesn't say how many that was, so use
+    // get() for anything that needs to count them.
+    pub fn discard_rx(&self, n: usize) -> Result<(), Error> {
+        for _ in 0..n {
+            self.get(true)?;
+        }
+        Ok(())
+    }
+
     pub fn read_hw_state_machine(&self) -> Result<StateMachineHw, Error> {
-        // Taken from piolib/examples/rp1sm.c in https://github.com/raspberrypi/utils
-        let mut data = [0; 8];
-        self.pio.read_hw(PROC_PIO_SM0_CLKDIV_OFFSET + self.index as u32 * 8 * 4, &mut data)?;
-        let mut ctrl_data = [0; 1];
-        self.pio.read_hw(PROC_PIO_CTRL_OFFSET, &mut ctrl_data)?;
-        Ok(StateMachineHw {
-            ctrl       : ctrl_data[0],
-            enabled    : ctrl_data[0] >> self.index as u32 & 1 != 0,
-            clkdiv     : data[0],
-            execctrl   : data[1],
-            shiftctrl  : data[2],
-            pc         : data[3],
-            instr      : data[4],
-            pinctrl    : data[5],
-            dmactrl_tx : data[6],
-            dmactrl_rx : data[7],
-        })
+        self.pio.read_hw_state_machine(self.index)
+    }
+
+    // The SM's current configuration, suitable for tweaking and passing back to set_config().
+    pub fn get_config(&self) -> Result<SmConfig, Error> {
+        Ok(SmConfig::from_hw(&self.read_hw_state_machine()?))
     }
 
     pub fn read_hw_fifo(&self) -> Result<FifoHw, Error> {
-        // Taken from piolib/examples/rp1sm.c in https://github.com/raspberrypi/utils
-        let mut data = [0; 4];
-        self.pio.read_hw(PROC_PIO_FSTAT_OFFSET, &mut data)?;
-        let raw = RawFifoHw {
-            fstat   : data[0],
-            flevel  : data[2],
-            flevel2 : data[3],
-        };
-        Ok(FifoHw {
-            tx: FifoState {
-                level: ((raw.flevel >> (self.index * 8)) & 0xf) + (((raw.flevel2 >> (self.index * 8)) & 1) << 4),
-                full: raw.fstat & (1<<PROC_PIO_FSTAT_TXFULL_LSB << self.index) != 0,
-                empty: raw.fstat & (1<<PROC_PIO_FSTAT_TXEMPTY_LSB << self.index) != 0,
-            },
-            rx: FifoState {
-                level: ((raw.flevel >> (self.index * 8 + 4)) & 0xf) + (((raw.flevel2 >> (self.index * 8 + 4)) & 1) << 4),
-                full: raw.fstat & (1<<PROC_PIO_FSTAT_RXFULL_LSB << self.index) != 0,
-                empty: raw.fstat & (1<<PROC_PIO_FSTAT_RXEMPTY_LSB << self.index) != 0,
-            },
-            raw,
-        })
+        self.pio.read_hw_fifo(self.index)
     }
 }
 
 
 #[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum XferDir {
     ToSm   = 0,
     FromSm = 1,
 }
 
 
+#[derive(Debug,Clone,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PioProgram {
     instructions: Vec<u16>,
     origin: i8,
-    #[allow(dead_code)]
     pio_version: u8,
+    side_set: Option<SideSet>,
+    wrap: Option<(u8, u8)>, // (wrap_target, wrap), relative to the start of the program
 }
 
 impl PioProgram {
@@ -521,11 +1023,105 @@ impl PioProgram {
         PioProgram { instructions: instructions.to_owned(),
             origin: origin.map(|o| o as i8).unwrap_or(-1),
             pio_version: 0,
+            side_set: None,
+            wrap: None,
+        }
+    }
+
+    // pioasm's `.side_set`. Optional, but lets SmConfig::validate_for() check the config against it.
+    pub fn with_side_set(mut self, side_set: SideSet) -> PioProgram {
+        self.side_set = Some(side_set);
+        self
+    }
+
+    // pioasm's `.wrap_target` and `.wrap`, as instruction indexes into the program.
+    pub fn with_wrap(mut self, wrap_target: u8, wrap: u8) -> PioProgram {
+        self.wrap = Some((wrap_target, wrap));
+        self
+    }
+
+    // pioasm's `.pio_version`. The RP1 only runs version 0 programs, see validate_for().
+    pub fn with_pio_version(mut self, pio_version: u8) -> PioProgram {
+        self.pio_version = pio_version;
+        self
+    }
+
+    pub fn instructions(&self) -> &[u16] {
+        &self.instructions
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    pub fn origin(&self) -> Option<u8> {
+        (self.origin >= 0).then_some(self.origin as u8)
+    }
+
+    pub fn side_set(&self) -> Option<SideSet> {
+        self.side_set
+    }
+
+    pub fn wrap(&self) -> Option<(u8, u8)> {
+        self.wrap
+    }
+
+    pub fn pio_version(&self) -> u8 {
+        self.pio_version
+    }
+}
+
+
+// A reference counted handle to a program in instruction memory, from Rp1PIO::load_program().
+pub struct LoadedProgram<'a> {
+    pio: &'a Rp1PIO,
+    program: PioProgram,
+    offset: u16,
+}
+
+impl<'a> LoadedProgram<'a> {
+    pub fn offset(&self) -> u16 {
+        self.offset
+    }
+
+    pub fn program(&self) -> &PioProgram {
+        &self.program
+    }
+
+    pub fn pio(&self) -> &'a Rp1PIO {
+        self.pio
+    }
+
+    // The program's wrap (the whole program if it doesn't specify one) at the offset it's loaded at, in the form
+    // SmConfig::set_wrap() takes.
+    pub fn wrap(&self) -> (u32, u32) {
+        let (wrap_target, wrap) = self.program.wrap().unwrap_or((0, self.program.len().saturating_sub(1) as u8));
+        (self.offset as u32 + wrap_target as u32, self.offset as u32 + wrap as u32)
+    }
+}
+
+impl Clone for LoadedProgram<'_> {
+    fn clone(&self) -> Self {
+        if let Some(loaded) = lock(&self.pio.shared).iter_mut()
+                                  .find(|loaded| loaded.offset == self.offset && loaded.instructions == self.program.instructions) {
+            loaded.refs += 1;
         }
+        LoadedProgram { pio: self.pio, program: self.program.clone(), offset: self.offset }
+    }
+}
+
+impl Drop for LoadedProgram<'_> {
+    fn drop(&mut self) {
+        let _ = self.pio.release_program(&self.program, self.offset);
     }
 }
 
 
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
 pub struct ClkDiv {
     pub div: u16,
     pub frac: u8,
@@ -535,8 +1131,8 @@ impl TryFrom<f64> for ClkDiv {
     type Error=Error;
 
     fn try_from(div: f64) -> Result<Self, Self::Error> {
-        if div != 0_f64 && (div < 1_f64 || div > 65536_f64) {
-            Err(Error::BadDiv { div: div, min: 1_f64, max: 65536_f64 })?;
+        if div != 0_f64 && !(1_f64..=65536_f64).contains(&div) {
+            Err(Error::BadDiv { div, min: 1_f64, max: 65536_f64 })?;
         }
         let div_int = div as u16;
         if div_int == 0 {
@@ -549,6 +1145,7 @@ impl TryFrom<f64> for ClkDiv {
 
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StateMachineHw {
     pub ctrl       : u32, // PROC_PIO_CTRL_OFFSET contains enable for all SMs
     pub enabled    : bool,
@@ -563,6 +1160,7 @@ pub struct StateMachineHw {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RawFifoHw {
     pub fstat   : u32,
     pub flevel  : u32,
@@ -570,6 +1168,7 @@ pub struct RawFifoHw {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FifoHw {
     pub raw: RawFifoHw,
     pub tx: FifoState,
@@ -577,6 +1176,7 @@ pub struct FifoHw {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FifoState {
     pub level: u32,
     pub full: bool,
@@ -0,0 +1,674 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A runtime assembler for pioasm source, so programs can live in .pio files (or strings) instead of hex arrays:
+//
+//     let program = PioProgram::assemble("
+//         .program blink
+//         .side_set 1
+//         loop:
+//             set pins, 1 side 0 [15]
+//             set pins, 0 side 1 [15]
+//             jmp loop
+//     ")?;
+//
+// assemble() takes a whole file with any number of programs and also hands back their public labels, public
+// .defines and `% language { ... %}` blocks, which is what the pioasm-rs binary needs to write the same outputs as
+// pioasm. Instructions before the first .program make up an unnamed program. PIO version 1 (RP2350) instructions and
+// the pioasm 2 config directives (.in, .out, .set, .fifo, .mov_status, .clock_div) aren't supported.
+
+use std::collections::HashMap;
+
+use crate::{instruction::*, Error, PioProgram};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembledProgram {
+    pub name: String,
+    pub program: PioProgram,
+    pub public_labels: Vec<(String, u8)>,
+    pub defines: Vec<(String, i64)>,          // Public .defines
+    pub code_blocks: Vec<(String, String)>,   // (language, code) from `% language { ... %}`
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Assembly {
+    pub programs: Vec<AssembledProgram>,
+    pub defines: Vec<(String, i64)>,          // Public .defines from before the first .program
+    pub code_blocks: Vec<(String, String)>,
+}
+
+impl PioProgram {
+    // Assembles source containing exactly one program.
+    pub fn assemble(source: &str) -> Result<PioProgram, Error> {
+        let mut assembly = assemble(source)?;
+        match assembly.programs.len() {
+            1 => Ok(assembly.programs.remove(0).program),
+            n => Err(Error::Assembly { line: 0, message: format!("expected exactly one program, found {n}") }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(i64),
+    Punct(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Ident(ident) => write!(f, "\"{ident}\""),
+            Token::Int(int)     => write!(f, "{int}"),
+            Token::Punct(punct) => write!(f, "\"{punct}\""),
+        }
+    }
+}
+
+// Longest first, so "::" isn't taken for two ":"s.
+const PUNCTUATION: [&str; 23] = ["::", "--", "!=", "<<", ">>", ",", ":", "[", "]", "(", ")", "+", "-", "*", "/", "!", "~",
+                                 "&", "|", "^", "%", "{", "}"];
+
+fn strip_comments(line: &str, in_comment: &mut bool) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+    while !rest.is_empty() {
+        if *in_comment {
+            match rest.find("*/") {
+                Some(end) => { rest = &rest[end + 2..]; *in_comment = false; out.push(' ') },
+                None      => break,
+            }
+        } else if rest.starts_with("/*") {
+            rest = &rest[2..];
+            *in_comment = true;
+        } else if rest.starts_with("//") || rest.starts_with(';') {
+            break;
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')).unwrap_or(rest.len());
+            let word = &rest[..len];
+            tokens.push(if c.is_ascii_digit() { Token::Int(parse_int(word)?) } else { Token::Ident(word.to_string()) });
+            rest = &rest[len..];
+        } else if let Some(punct) = PUNCTUATION.iter().find(|&&punct| rest.starts_with(punct)) {
+            tokens.push(Token::Punct(punct));
+            rest = &rest[punct.len()..];
+        } else {
+            Err(format!("unexpected character {c:?}"))?;
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn parse_int(word: &str) -> Result<i64, String> {
+    let lower = word.to_ascii_lowercase();
+    let parsed = if let Some(hex) = lower.strip_prefix("0x") { i64::from_str_radix(hex, 16) }
+                 else if let Some(bin) = lower.strip_prefix("0b") { i64::from_str_radix(bin, 2) }
+                 else { lower.parse() };
+    parsed.map_err(|_| format!("bad number {word:?}"))
+}
+
+type Defines = HashMap<String, (Vec<Token>, usize)>;
+
+// Everything about a program that pass 1 collects. Nothing is evaluated until all the labels are known.
+#[derive(Default)]
+struct ProgramSource {
+    name: String,
+    line: usize,
+    instructions: Vec<(Vec<Token>, usize)>,
+    labels: HashMap<String, i64>,
+    public_labels: Vec<(String, u8)>,
+    defines: Defines,
+    public_defines: Vec<String>,
+    origin: Option<(Vec<Token>, usize)>,
+    side_set: Option<(Vec<Token>, usize)>,
+    pio_version: Option<(Vec<Token>, usize)>,
+    wrap_target: Option<usize>,
+    wrap: Option<usize>,
+    code_blocks: Vec<(String, String)>,
+}
+
+struct Cursor<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(tokens: &'a [Token]) -> Cursor<'a> {
+        Cursor { tokens, pos: 0 }
+    }
+
+    fn peek_at(&self, n: usize) -> Option<&'a Token> {
+        self.tokens.get(self.pos + n)
+    }
+
+    fn peek(&self) -> Option<&'a Token> {
+        self.peek_at(0)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn is_keyword_at(&self, n: usize, keyword: &str) -> bool {
+        matches!(self.peek_at(n), Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword_at(0, keyword);
+        if found { self.pos += 1 }
+        found
+    }
+
+    fn eat_punct(&mut self, punct: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Punct(p)) if *p == punct);
+        if found { self.pos += 1 }
+        found
+    }
+
+    fn expect_punct(&mut self, punct: &str) -> Result<(), String> {
+        if self.eat_punct(punct) { Ok(()) } else { Err(format!("expected \"{punct}\", found {}", self.describe())) }
+    }
+
+    fn keyword(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Ident(ident)) => Ok(ident.to_ascii_lowercase()),
+            _ => { self.pos -= 1; Err(format!("expected a keyword, found {}", self.describe())) },
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Ident(ident)) => Ok(ident.clone()),
+            _ => { self.pos -= 1; Err(format!("expected a name, found {}", self.describe())) },
+        }
+    }
+
+    fn describe(&self) -> String {
+        self.peek().map(|token| token.to_string()).unwrap_or_else(|| "end of line".to_string())
+    }
+
+    fn expect_end(&self) -> Result<(), String> {
+        if self.at_end() { Ok(()) } else { Err(format!("unexpected {}", self.describe())) }
+    }
+}
+
+// Looks symbols up in the program first, then the globals.
+struct Symbols<'a> {
+    labels: &'a HashMap<String, i64>,
+    defines: [&'a Defines; 2],
+}
+
+const MAX_DEPTH: usize = 32;
+
+impl Symbols<'_> {
+    fn lookup(&self, name: &str, depth: usize) -> Result<i64, String> {
+        if let Some(&address) = self.labels.get(name) {
+            return Ok(address);
+        }
+        let Some((tokens, _)) = self.defines.iter().find_map(|defines| defines.get(name)) else {
+            return Err(format!("undefined symbol \"{name}\""));
+        };
+        if depth > MAX_DEPTH {
+            Err(format!("\"{name}\" is defined in terms of itself"))?;
+        }
+        let mut cursor = Cursor::new(tokens);
+        let value = self.expression(&mut cursor, depth + 1)?;
+        cursor.expect_end()?;
+        Ok(value)
+    }
+
+    // A plain value: a number, a symbol, or an expression in parentheses.
+    fn value(&self, c: &mut Cursor, depth: usize) -> Result<i64, String> {
+        match c.next() {
+            Some(Token::Int(int))     => Ok(*int),
+            Some(Token::Ident(ident)) => self.lookup(ident, depth),
+            Some(Token::Punct("-"))   => Ok(-self.value(c, depth)?),
+            Some(Token::Punct("("))   => {
+                let value = self.expression(c, depth)?;
+                c.expect_punct(")")?;
+                Ok(value)
+            },
+            _ => { c.pos -= 1; Err(format!("expected a value, found {}", c.describe())) },
+        }
+    }
+
+    fn expression(&self, c: &mut Cursor, depth: usize) -> Result<i64, String> {
+        self.binary(c, depth, 0)
+    }
+
+    // Precedence climbing, loosest first.
+    fn binary(&self, c: &mut Cursor, depth: usize, level: usize) -> Result<i64, String> {
+        const LEVELS: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+        if level == LEVELS.len() {
+            return self.unary(c, depth);
+        }
+        let mut left = self.binary(c, depth, level + 1)?;
+        while let Some(Token::Punct(op)) = c.peek() && LEVELS[level].contains(op) {
+            c.next();
+            let right = self.binary(c, depth, level + 1)?;
+            left = match *op {
+                "|"  => left | right,
+                "^"  => left ^ right,
+                "&"  => left & right,
+                "<<" => left.checked_shl(right as u32).ok_or("shift out of range")?,
+                ">>" => left.checked_shr(right as u32).ok_or("shift out of range")?,
+                "+"  => left + right,
+                "-"  => left - right,
+                "*"  => left * right,
+                "/"  => left.checked_div(right).ok_or("division by zero")?,
+                _    => left.checked_rem(right).ok_or("division by zero")?,
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&self, c: &mut Cursor, depth: usize) -> Result<i64, String> {
+        if c.eat_punct("-") { return Ok(-self.unary(c, depth)?) }
+        if c.eat_punct("~") { return Ok(!self.unary(c, depth)?) }
+        if c.eat_punct("::") { return Ok((self.unary(c, depth)? as u32).reverse_bits() as i64) }
+        self.value(c, depth)
+    }
+}
+
+fn in_range(value: i64, max: i64, what: &str) -> Result<u8, String> {
+    if (0..=max).contains(&value) { Ok(value as u8) } else { Err(format!("{what} must be in 0..={max}, not {value}")) }
+}
+
+fn jmp_condition(c: &mut Cursor) -> Result<JmpCondition, String> {
+    if c.eat_punct("!") {
+        return match c.keyword()?.as_str() {
+            "x"    => Ok(JmpCondition::XZero),
+            "y"    => Ok(JmpCondition::YZero),
+            "osre" => Ok(JmpCondition::NotOsrEmpty),
+            other  => Err(format!("unknown jmp condition \"!{other}\"")),
+        };
+    }
+    let followed_by = |c: &Cursor, punct: &str| matches!(c.peek_at(1), Some(Token::Punct(p)) if *p == punct);
+    let condition = if c.is_keyword_at(0, "x") && followed_by(c, "--") { JmpCondition::XPostDec }
+        else if c.is_keyword_at(0, "y") && followed_by(c, "--") { JmpCondition::YPostDec }
+        else if c.is_keyword_at(0, "x") && followed_by(c, "!=") && c.is_keyword_at(2, "y") { c.pos += 1; JmpCondition::XNotEqualY }
+        else if c.is_keyword_at(0, "pin") && c.peek_at(1).is_some_and(|next| !matches!(next, Token::Punct("[")))
+                                          && !c.is_keyword_at(1, "side") && !c.is_keyword_at(1, "sideset") { c.pos -= 1; JmpCondition::Pin }
+        else { return Ok(JmpCondition::Always) };
+    c.pos += 2;
+    Ok(condition)
+}
+
+fn operation(c: &mut Cursor, symbols: &Symbols) -> Result<Operation, String> {
+    let value = |c: &mut Cursor| symbols.value(c, 0);
+    let mnemonic = c.keyword()?;
+    Ok(match mnemonic.as_str() {
+        "nop" => Instruction::nop().operation,
+        "jmp" => {
+            let condition = jmp_condition(c)?;
+            c.eat_punct(",");
+            Operation::Jmp { condition, address: in_range(value(c)?, 31, "jmp target")? }
+        },
+        "wait" => {
+            let polarity = if c.is_keyword_at(0, "gpio") || c.is_keyword_at(0, "pin") || c.is_keyword_at(0, "irq") { 1 }
+                           else { in_range(value(c)?, 1, "wait polarity")? };
+            let source = match c.keyword()?.as_str() {
+                "gpio" => WaitSource::Gpio,
+                "pin"  => WaitSource::Pin,
+                "irq"  => WaitSource::Irq,
+                other  => Err(format!("unknown wait source \"{other}\""))?,
+            };
+            c.eat_punct(",");
+            let index = if source == WaitSource::Irq {
+                let index = in_range(value(c)?, 7, "irq number")?;
+                IrqIndex { index, relative: c.eat_keyword("rel") }.encode()
+            } else {
+                in_range(value(c)?, 31, "wait index")?
+            };
+            Operation::Wait { polarity: polarity != 0, source, index }
+        },
+        "in" => {
+            let source = match c.keyword()?.as_str() {
+                "pins" => InSource::Pins, "x" => InSource::X, "y" => InSource::Y, "null" => InSource::Null,
+                "isr" => InSource::Isr, "osr" => InSource::Osr,
+                other => Err(format!("unknown in source \"{other}\""))?,
+            };
+            c.eat_punct(",");
+            Operation::In { source, bit_count: bit_count(value(c)?)? }
+        },
+        "out" => {
+            let destination = match c.keyword()?.as_str() {
+                "pins" => OutDestination::Pins, "x" => OutDestination::X, "y" => OutDestination::Y, "null" => OutDestination::Null,
+                "pindirs" => OutDestination::Pindirs, "pc" => OutDestination::Pc, "isr" => OutDestination::Isr,
+                "exec" => OutDestination::Exec,
+                other => Err(format!("unknown out destination \"{other}\""))?,
+            };
+            c.eat_punct(",");
+            Operation::Out { destination, bit_count: bit_count(value(c)?)? }
+        },
+        "push" | "pull" => {
+            let (mut if_flag, mut block) = (false, true);
+            loop {
+                if c.eat_keyword(if mnemonic == "push" { "iffull" } else { "ifempty" }) { if_flag = true }
+                else if c.eat_keyword("block") { block = true }
+                else if c.eat_keyword("noblock") { block = false }
+                else { break }
+            }
+            if mnemonic == "push" { Operation::Push { if_full: if_flag, block } } else { Operation::Pull { if_empty: if_flag, block } }
+        },
+        "mov" => {
+            let destination = match c.keyword()?.as_str() {
+                "pins" => MovDestination::Pins, "x" => MovDestination::X, "y" => MovDestination::Y, "exec" => MovDestination::Exec,
+                "pc" => MovDestination::Pc, "isr" => MovDestination::Isr, "osr" => MovDestination::Osr,
+                other => Err(format!("unknown mov destination \"{other}\""))?,
+            };
+            c.eat_punct(",");
+            let op = if c.eat_punct("!") || c.eat_punct("~") { MovOp::Invert } else if c.eat_punct("::") { MovOp::Reverse } else { MovOp::None };
+            let source = match c.keyword()?.as_str() {
+                "pins" => MovSource::Pins, "x" => MovSource::X, "y" => MovSource::Y, "null" => MovSource::Null,
+                "status" => MovSource::Status, "isr" => MovSource::Isr, "osr" => MovSource::Osr,
+                other => Err(format!("unknown mov source \"{other}\""))?,
+            };
+            Operation::Mov { destination, op, source }
+        },
+        "irq" => {
+            let (clear, wait) = if c.eat_keyword("clear") { (true, false) }
+                                else if c.eat_keyword("wait") { (false, true) }
+                                else { let _ = c.eat_keyword("set") || c.eat_keyword("nowait"); (false, false) };
+            let index = in_range(value(c)?, 7, "irq number")?;
+            Operation::Irq { clear, wait, index: IrqIndex { index, relative: c.eat_keyword("rel") } }
+        },
+        "set" => {
+            let destination = match c.keyword()?.as_str() {
+                "pins" => SetDestination::Pins, "x" => SetDestination::X, "y" => SetDestination::Y,
+                "pindirs" => SetDestination::Pindirs,
+                other => Err(format!("unknown set destination \"{other}\""))?,
+            };
+            c.eat_punct(",");
+            Operation::Set { destination, data: in_range(value(c)?, 31, "set value")? }
+        },
+        other => Err(format!("unknown instruction \"{other}\""))?,
+    })
+}
+
+fn bit_count(count: i64) -> Result<u8, String> {
+    if (1..=32).contains(&count) { Ok(count as u8) } else { Err(format!("bit count must be in 1..=32, not {count}")) }
+}
+
+fn instruction(c: &mut Cursor, symbols: &Symbols, side_set: SideSet) -> Result<u16, String> {
+    if c.eat_keyword(".word") {
+        let word = symbols.value(c, 0)?;
+        c.expect_end()?;
+        return u16::try_from(word).map_err(|_| format!(".word must fit in 16 bits, not {word}"));
+    }
+    let mut instruction = Instruction::new(operation(c, symbols)?);
+    loop {
+        if c.eat_keyword("side") || c.eat_keyword("sideset") {
+            instruction.side_set = Some(in_range(symbols.value(c, 0)?, 31, "side-set value")?);
+        } else if c.eat_punct("[") {
+            instruction.delay = in_range(symbols.expression(c, 0)?, 31, "delay")?;
+            c.expect_punct("]")?;
+        } else {
+            break;
+        }
+    }
+    c.expect_end()?;
+    instruction.encode(side_set).map_err(|e| e.to_string())
+}
+
+fn error(line: usize) -> impl Fn(String) -> Error {
+    move |message| Error::Assembly { line, message }
+}
+
+fn directive(directive: &Option<(Vec<Token>, usize)>) -> Option<(Cursor<'_>, usize)> {
+    directive.as_ref().map(|(tokens, line)| (Cursor::new(tokens), *line))
+}
+
+// Pass 2: with every label known, evaluate and encode.
+fn finish(source: ProgramSource, globals: &Defines) -> Result<AssembledProgram, Error> {
+    let symbols = Symbols { labels: &source.labels, defines: [&source.defines, globals] };
+    let side_set = match directive(&source.side_set) {
+        None => None,
+        Some((mut c, line)) => {
+            let count = symbols.value(&mut c, 0).and_then(|count| in_range(count, 5, "side-set count")).map_err(error(line))?;
+            let (mut optional, mut pindirs) = (false, false);
+            loop {
+                if c.eat_keyword("opt") { optional = true }
+                else if c.eat_keyword("pindirs") { pindirs = true }
+                else { break }
+            }
+            c.expect_end().map_err(error(line))?;
+            if count + optional as u8 > 5 {
+                Err(error(line)("optional side-set can have at most 4 pins".to_string()))?;
+            }
+            Some(SideSet::new(count, optional, pindirs))
+        },
+    };
+    let origin = match directive(&source.origin) {
+        None => None,
+        Some((mut c, line)) => Some(symbols.value(&mut c, 0).and_then(|origin| in_range(origin, 31, "origin")).map_err(error(line))?),
+    };
+    let pio_version = match directive(&source.pio_version) {
+        None => 0,
+        Some((mut c, line)) => symbols.value(&mut c, 0).and_then(|version| in_range(version, 0, "pio_version (only version 0 is supported)"))
+                                      .map_err(error(line))?,
+    };
+
+    let mut instructions = Vec::new();
+    for (tokens, line) in &source.instructions {
+        instructions.push(instruction(&mut Cursor::new(tokens), &symbols, side_set.unwrap_or_default()).map_err(error(*line))?);
+    }
+    if instructions.len() > 32 {
+        Err(error(source.line)(format!("program \"{}\" has {} instructions, but only 32 fit", source.name, instructions.len())))?;
+    }
+    if instructions.is_empty() {
+        Err(error(source.line)(format!("program \"{}\" has no instructions", source.name)))?;
+    }
+
+    let mut program = PioProgram::new(&instructions, origin).with_pio_version(pio_version)
+        .with_wrap(source.wrap_target.unwrap_or(0) as u8, source.wrap.unwrap_or(instructions.len() - 1) as u8);
+    if let Some(side_set) = side_set {
+        program = program.with_side_set(side_set);
+    }
+    let mut defines = Vec::new();
+    for name in &source.public_defines {
+        defines.push((name.clone(), symbols.lookup(name, 0).map_err(error(source.defines[name].1))?));
+    }
+    Ok(AssembledProgram { name: source.name, program, public_labels: source.public_labels, defines, code_blocks: source.code_blocks })
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, Error> {
+    let mut assembly = Assembly::default();
+    let mut globals = Defines::new();
+    let mut public_globals = Vec::new();
+    let mut global_blocks = Vec::new();
+    let mut programs: Vec<ProgramSource> = Vec::new();
+    let mut in_comment = false;
+    let mut code_block: Option<(String, String, usize)> = None;
+
+    for (number, raw) in source.lines().enumerate() {
+        let number = number + 1;
+        let err = error(number);
+        if let Some((lang, code, _)) = &mut code_block {
+            if raw.trim() == "%}" {
+                let block = (std::mem::take(lang), std::mem::take(code));
+                match programs.last_mut() { Some(program) => program.code_blocks.push(block), None => global_blocks.push(block) }
+                code_block = None;
+            } else {
+                *code += raw;
+                *code += "\n";
+            }
+            continue;
+        }
+        if !in_comment && let Some(rest) = raw.trim().strip_prefix('%') {
+            let lang = rest.trim().strip_suffix('{').ok_or_else(|| err("expected \"% language {\"".to_string()))?;
+            code_block = Some((lang.trim().to_string(), String::new(), number));
+            continue;
+        }
+        let tokens = tokenize(&strip_comments(raw, &mut in_comment)).map_err(&err)?;
+        let mut c = Cursor::new(&tokens);
+        if c.at_end() {
+            continue;
+        }
+
+        if let Some(Token::Ident(first)) = c.peek() && first.starts_with('.') && !first.eq_ignore_ascii_case(".word") {
+            let directive = c.keyword().map_err(&err)?;
+            let rest = || tokens[c.pos..].to_vec();
+            match directive.as_str() {
+                ".program" => {
+                    let name = c.ident().map_err(&err)?;
+                    c.expect_end().map_err(&err)?;
+                    programs.push(ProgramSource { name, line: number, ..Default::default() });
+                },
+                ".define" => {
+                    let public = c.eat_keyword("public");
+                    let name = c.ident().map_err(&err)?;
+                    let (defines, public_names) = match programs.last_mut() {
+                        Some(program) => (&mut program.defines, &mut program.public_defines),
+                        None          => (&mut globals, &mut public_globals),
+                    };
+                    if defines.insert(name.clone(), (tokens[c.pos..].to_vec(), number)).is_some() {
+                        Err(err(format!("\"{name}\" is already defined")))?;
+                    }
+                    if public { public_names.push(name) }
+                },
+                ".lang_opt" => {},
+                _ => {
+                    let program = programs.last_mut().ok_or_else(|| err(format!("{directive} outside of a .program")))?;
+                    match directive.as_str() {
+                        ".origin"      => program.origin = Some((rest(), number)),
+                        ".side_set"    => program.side_set = Some((rest(), number)),
+                        ".pio_version" => program.pio_version = Some((rest(), number)),
+                        ".wrap_target" => program.wrap_target = Some(program.instructions.len()),
+                        ".wrap"        => program.wrap = Some(program.instructions.len().checked_sub(1)
+                                                          .ok_or_else(|| err(".wrap needs an instruction before it".to_string()))?),
+                        _              => Err(err(format!("unsupported directive \"{directive}\"")))?,
+                    }
+                },
+            }
+            continue;
+        }
+
+        if programs.is_empty() {
+            programs.push(ProgramSource { line: number, ..Default::default() });
+        }
+        let program = programs.last_mut().unwrap();
+        let public = c.is_keyword_at(0, "public") && matches!(c.peek_at(2), Some(Token::Punct(":")));
+        if public || matches!(c.peek_at(1), Some(Token::Punct(":"))) {
+            if public { c.next(); }
+            let label = c.ident().map_err(&err)?;
+            c.next();
+            let address = program.instructions.len();
+            if program.labels.insert(label.clone(), address as i64).is_some() {
+                Err(err(format!("label \"{label}\" is already defined")))?;
+            }
+            if public { program.public_labels.push((label, address as u8)) }
+        }
+        if !c.at_end() {
+            program.instructions.push((tokens[c.pos..].to_vec(), number));
+        }
+    }
+    if let Some((_, _, line)) = code_block {
+        Err(error(line)("code block is missing its \"%}\"".to_string()))?;
+    }
+
+    let no_labels = HashMap::new();
+    let symbols = Symbols { labels: &no_labels, defines: [&globals, &globals] };
+    for name in public_globals {
+        assembly.defines.push((name.clone(), symbols.lookup(&name, 0).map_err(error(globals[&name].1))?));
+    }
+    for program in programs {
+        assembly.programs.push(finish(program, &globals)?);
+    }
+    assembly.code_blocks = global_blocks;
+    Ok(assembly)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_like_pioasm() {
+        // Expected encodings from pioasm.
+        let assembly = assemble("
+            .define public T1 2
+            .define T2 (T1 * 2 + 1)
+            .define T3 3
+
+            .program ws2812
+            .side_set 1
+            .wrap_target
+            bitloop:
+                out x, 1       side 0 [T3 - 1] ; Side-set still takes place when instruction stalls
+                jmp !x do_zero side 1 [T1 - 1]
+            do_one:
+                jmp  bitloop   side 1 [T2 - 1]
+            public do_zero:
+                nop            side 0 [T2 - 1]
+            .wrap
+
+            % c-sdk {
+            // init code
+            %}
+
+            .program misc
+            .origin 4
+                wait 1 gpio 5
+                wait 0 irq 2 rel
+                in pins, 32
+                push iffull noblock
+                pull ifempty
+                mov x, ~status
+                mov osr, ::isr
+                irq clear 3
+                irq wait 1 rel
+                set pindirs, 0b11 /* block
+                comment */
+                jmp x-- 0
+                jmp x!=y, 3
+                jmp pin 1
+                .word 0x1234
+        ").unwrap();
+        assert_eq!(assembly.defines, [("T1".to_string(), 2)]);
+        let ws2812 = &assembly.programs[0];
+        assert_eq!(ws2812.name, "ws2812");
+        assert_eq!(ws2812.program.instructions(), [0x6221, 0x1123, 0x1400, 0xa442]);
+        assert_eq!(ws2812.program.side_set(), Some(SideSet::new(1, false, false)));
+        assert_eq!(ws2812.program.wrap(), Some((0, 3)));
+        assert_eq!(ws2812.public_labels, [("do_zero".to_string(), 3)]);
+        assert_eq!(ws2812.code_blocks, [("c-sdk".to_string(), "            // init code\n".to_string())]);
+
+        let misc = &assembly.programs[1];
+        assert_eq!(misc.program.origin(), Some(4));
+        assert_eq!(misc.program.instructions(), [0x2085, 0x2052, 0x4000, 0x8040, 0x80e0, 0xa02d, 0xa0f6, 0xc043, 0xc031, 0xe083,
+                                                 0x0040, 0x00a3, 0x00c1, 0x1234]);
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        assert_eq!(PioProgram::assemble(".program a\n  set x, 1\n  set q, 1"),
+                   Err(Error::Assembly { line: 3, message: "unknown set destination \"q\"".to_string() }));
+        assert_eq!(PioProgram::assemble("jmp nowhere"),
+                   Err(Error::Assembly { line: 1, message: "undefined symbol \"nowhere\"".to_string() }));
+        assert_eq!(PioProgram::assemble(".side_set 1\nnop"),
+                   Err(Error::Assembly { line: 1, message: ".side_set outside of a .program".to_string() }));
+        assert!(matches!(PioProgram::assemble(".program a\n.side_set 1\nnop"), Err(Error::Assembly { line: 3, .. }))); // Not optional
+        assert_eq!(PioProgram::assemble("loop: jmp loop").unwrap().instructions(), [0x0000]);
+    }
+}
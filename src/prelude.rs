@@ -0,0 +1,20 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Everything most programs need, in one line:
+//
+//     use pio_pi5_rs::prelude::*;
+//
+//     fn main() -> Result<()> {
+//         let pio = Rp1PIO::new(0)?;
+//         let sm = pio.sm_claim_unused()?;
+//         ...
+//     }
+
+pub use crate::{Chip, ClkDiv, Error, ErrorKind, LoadedProgram, PioBackend, PinGroups, PioFifoJoin, PioMovStatus, PioProgram, Result,
+                Rp1PIO, SmConfig, StateMachine, TeardownPolicy};
+pub use crate::gpio::{Direction, DriveStrength, Function};
+#[cfg(feature = "embedded-hal")]
+pub use crate::hal::PioGpio;
+#[cfg(feature = "embedded-hal")]
+pub use embedded_hal::digital::{InputPin as _, OutputPin as _};
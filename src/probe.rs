@@ -0,0 +1,161 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A quick logic probe for sanity checking wiring:
+//
+//     pio_pi5_rs::probe::watch(17)?.print()?;
+//
+// It grabs a free SM, loads a tiny edge capture program and streams out every transition on the pin until the
+// Probe is dropped. The pin's function is left alone so it can watch pins that are being driven by something else.
+
+use std::time::Duration;
+
+use crate::{pio_clock_hz, Error, PioFifoJoin, PioProgram, Rp1PIO, SmConfig};
+
+// Each word pushed to the RX FIFO is (level << 31) | x, where x is a 31 bit down counter that is decremented once
+// every 2 SM cycles while waiting for the pin to change.
+const EDGE_CAPTURE: [u16; 11] = [
+    0xa023, //  0:         mov x, null
+    0x4001, //  1: report: in  pins, 1
+    0x403f, //  2:         in  x, 31        ; autopush
+    0x00c7, //  3:         jmp pin, high
+    0x00c1, //  4: low:    jmp pin, report
+    0x0044, //  5:         jmp x--, low
+    0x0004, //  6:         jmp low          ; x wrapped
+    0x00c9, //  7: high:   jmp pin, hi_cnt
+    0x0001, //  8:         jmp report
+    0x0047, //  9: hi_cnt: jmp x--, high
+    0x0007, // 10:         jmp high         ; x wrapped
+];
+const CYCLES_PER_TICK: u128 = 2;
+const COUNT_MASK: u32 = 0x7fff_ffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub level: bool,
+    pub at: Duration, // Since the probe started. The first Transition is the initial level of the pin.
+}
+
+pub struct Probe {
+    pio: Rp1PIO,
+    sm: u16,
+    pin: u16,
+    program: PioProgram,
+    offset: Option<u16>,
+    last_count: Option<u32>,
+    ticks: u64,
+}
+
+// Watches `pin` using PIO 0.
+pub fn watch(pin: u16) -> Result<Probe, Error> {
+    watch_pio(Rp1PIO::new(0)?, pin)
+}
+
+pub fn watch_pio(pio: Rp1PIO, pin: u16) -> Result<Probe, Error> {
+    let sm = pio.sm_claim_unused()?.index();
+    let mut probe = Probe { pio, sm, pin, program: PioProgram::new(&EDGE_CAPTURE, None),
+                            offset: None, last_count: None, ticks: 0 };
+    probe.offset = Some(probe.pio.add_program(&probe.program)?);
+    probe.start()?;
+    Ok(probe)
+}
+
+impl Probe {
+    fn start(&self) -> Result<(), Error> {
+        let offset = self.offset.expect("program should be loaded before starting");
+        let config = SmConfig::default()
+            .set_in_pins(self.pin as u32)?
+            .set_jmp_pin(self.pin as u32)?
+            .set_in_shift(false, true, 32)?
+            .set_fifo_join(PioFifoJoin::Rx)?
+            .set_wrap(offset as u32, offset as u32 + EDGE_CAPTURE.len() as u32 - 1)?;
+        let sm = self.pio.sm(self.sm);
+        sm.init(offset, &config)?;
+        sm.set_enabled(true)
+    }
+
+    pub fn pin(&self) -> u16 {
+        self.pin
+    }
+
+    // Blocks forever, printing each transition as it happens.
+    pub fn print(&mut self) -> Result<(), Error> {
+        let pin = self.pin;
+        for transition in self {
+            let transition = transition?;
+            println!("{:>14.9}s GPIO{pin} {}", transition.at.as_secs_f64(), if transition.level { "high" } else { "low" });
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self, word: u32) -> Transition {
+        let count = word & COUNT_MASK;
+        if let Some(last) = self.last_count {
+            // The counter wraps every 2^31 ticks (~21s at full speed), so longer gaps will be under-reported.
+            self.ticks += (last.wrapping_sub(count) & COUNT_MASK) as u64;
+        }
+        self.last_count = Some(count);
+        Transition {
+            level: word & !COUNT_MASK != 0,
+            at: Duration::from_nanos((self.ticks as u128 * CYCLES_PER_TICK * 1_000_000_000 / pio_clock_hz() as u128) as u64),
+        }
+    }
+}
+
+impl Iterator for Probe {
+    type Item = Result<Transition, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.pio.sm(self.sm).get(true).map(|word| self.decode(word)))
+    }
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        let sm = self.pio.sm(self.sm);
+        let _ = sm.set_enabled(false);
+        if let Some(offset) = self.offset {
+            let _ = self.pio.remove_program(&self.program, Some(offset));
+        }
+        let _ = sm.unclaim();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend, Peripheral}, Chip};
+
+    // GPIO17 high from 2000 to 2600 cycles.
+    #[derive(Debug)]
+    struct Pulse;
+
+    impl Peripheral for Pulse {
+        fn step(&mut self, _pins: u32, now: u64) -> (u32, u32) {
+            (((2000..2600).contains(&now) as u32) << 17, 1 << 17)
+        }
+    }
+
+    #[test]
+    fn watch() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(100_000);
+        backend.emulator().attach(Arc::new(Mutex::new(Pulse)));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let mut probe = watch_pio(pio, 17).unwrap();
+        let start = backend.emulator().cycle();
+        assert_eq!(probe.pin(), 17);
+        let transitions: Vec<Transition> = probe.by_ref().take(3).map(Result::unwrap).collect();
+        assert_eq!(transitions.iter().map(|t| t.level).collect::<Vec<_>>(), [false, true, false]);
+        let cycles = |t: &Transition| (t.at.as_nanos() * pio_clock_hz() as u128 / 1_000_000_000) as i64;
+        assert_eq!(transitions[0].at, Duration::ZERO);
+        // Each report costs a few cycles that the counter doesn't see.
+        let rise = cycles(&transitions[1]) - (2000 - start as i64);
+        assert!((-8..=2).contains(&rise), "{transitions:?} {rise}");
+        let width = cycles(&transitions[2]) - cycles(&transitions[1]);
+        assert!((592..=602).contains(&width), "{transitions:?} {width}");
+        // Nothing else happens, so the next read times out.
+        assert!(matches!(probe.next(), Some(Err(Error::TimedOut))));
+    }
+}
@@ -0,0 +1,315 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Recording every ioctl a program makes, and playing the recording back later without the hardware:
+//
+//     let backend = Recorder::create(Box::new(IoctlBackend::open("/dev/pio0")?), "trace.txt")?;
+//     let pio = Rp1PIO::with_backend(Box::new(backend), Chip::new());
+//     ... later, anywhere:
+//     let pio = Rp1PIO::with_backend(Box::new(Replayer::open("trace.txt")?), Chip::new());
+//
+// Traces are text, one ioctl per line:
+//
+//     <name> <request> <args before> <args after> <buffer> <result>
+//
+// where the args are hex dumps of the ioctl's argument struct and <buffer> is what READ_HW/WRITE_HW and the XFER_DATA
+// requests point at ("-" for none). The result is "ok:<n>", "errno:<n>", "timedout", "remoteio" or "unknown:<n>".
+//
+// Replaying checks that each ioctl matches the next one in the trace (pointers excepted, since they change from run
+// to run), then hands back the recorded results, including anything the kernel wrote into the args or buffer.
+
+use std::{ffi::c_void, fs::File, io::{BufRead, BufReader, BufWriter, Write}, path::{Path, PathBuf}, sync::Mutex};
+
+use libc::c_ulong;
+
+use crate::{lock, Error, PioBackend};
+use crate::ioctl::*;
+
+const HEADER: &str = "# pio-pi5-rs ioctl trace v1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Ok(u32),
+    Errno(i32),
+    TimedOut,
+    RemoteIO,
+    Unknown(i32),
+}
+
+impl Outcome {
+    pub(crate) fn of(result: &Result<u32, Error>) -> Outcome {
+        match result {
+            Ok(r)                    => Outcome::Ok(*r),
+            Err(Error::TimedOut)     => Outcome::TimedOut,
+            Err(Error::RemoteIOErr)  => Outcome::RemoteIO,
+            Err(Error::IOError(e))   => Outcome::Errno(e.raw_os_error().unwrap_or(libc::EIO)),
+            Err(Error::Unknown(r))   => Outcome::Unknown(*r),
+            Err(_)                   => Outcome::Errno(libc::EINVAL),
+        }
+    }
+
+    pub(crate) fn result(&self) -> Result<u32, Error> {
+        match *self {
+            Outcome::Ok(r)      => Ok(r),
+            Outcome::Errno(e)   => Err(std::io::Error::from_raw_os_error(e))?,
+            Outcome::TimedOut   => Err(Error::TimedOut),
+            Outcome::RemoteIO   => Err(Error::RemoteIOErr),
+            Outcome::Unknown(r) => Err(Error::Unknown(r)),
+        }
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Ok(r)      => write!(f, "ok:{r}"),
+            Outcome::Errno(e)   => write!(f, "errno:{e}"),
+            Outcome::TimedOut   => write!(f, "timedout"),
+            Outcome::RemoteIO   => write!(f, "remoteio"),
+            Outcome::Unknown(r) => write!(f, "unknown:{r}"),
+        }
+    }
+}
+
+impl std::str::FromStr for Outcome {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |n: &str| n.parse().map_err(|_| format!("bad result {s:?}"));
+        Ok(match s.split_once(':') {
+            Some(("ok", n))      => Outcome::Ok(number(n)?),
+            Some(("errno", n))   => Outcome::Errno(number(n)? as i32),
+            Some(("unknown", n)) => Outcome::Unknown(n.parse().map_err(|_| format!("bad result {s:?}"))?),
+            None if s == "timedout" => Outcome::TimedOut,
+            None if s == "remoteio" => Outcome::RemoteIO,
+            _ => Err(format!("bad result {s:?}"))?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    request: c_ulong,
+    before: Vec<u8>,
+    after: Vec<u8>,
+    buffer: Vec<u8>,
+    outcome: Outcome,
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "-".to_string();
+    }
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub(crate) fn unhex(s: &str) -> Result<Vec<u8>, String> {
+    if s == "-" {
+        return Ok(Vec::new());
+    }
+    if !s.len().is_multiple_of(2) {
+        Err(format!("odd length hex {s:?}"))?;
+    }
+    // By bytes, since anything could be in there (and from_str_radix() would take a "+")
+    let nibble = |c: u8| (c as char).to_digit(16).ok_or_else(|| format!("bad hex {s:?}"));
+    s.as_bytes().chunks(2).map(|pair| Ok((nibble(pair[0])? << 4 | nibble(pair[1])?) as u8)).collect()
+}
+
+impl Entry {
+    fn to_line(&self) -> String {
+        format!("{} {:#x} {} {} {} {}", request_name(self.request), self.request, hex(&self.before), hex(&self.after), hex(&self.buffer), self.outcome)
+    }
+
+    fn from_line(line: &str) -> Result<Entry, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [_name, request, before, after, buffer, outcome] = fields[..] else { Err(format!("expected 6 fields in {line:?}"))? };
+        Ok(Entry {
+            request: c_ulong::from_str_radix(request.trim_start_matches("0x"), 16).map_err(|_| format!("bad request {request:?}"))?,
+            before: unhex(before)?,
+            after: unhex(after)?,
+            buffer: unhex(buffer)?,
+            outcome: outcome.parse()?,
+        })
+    }
+}
+
+// Passes everything through to another backend, writing each ioctl to a trace as it goes.
+pub struct Recorder {
+    inner: Box<dyn PioBackend>,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+    pub fn new(inner: Box<dyn PioBackend>, mut out: Box<dyn Write + Send>) -> Result<Recorder, Error> {
+        writeln!(out, "{HEADER}")?;
+        Ok(Recorder { inner, out: Mutex::new(out) })
+    }
+
+    pub fn create(inner: Box<dyn PioBackend>, path: impl AsRef<Path>) -> Result<Recorder, Error> {
+        Recorder::new(inner, Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        Ok(lock(&self.out).flush()?)
+    }
+}
+
+impl PioBackend for Recorder {
+    unsafe fn ioctl(&self, request: c_ulong, args: *mut c_void) -> Result<u32, Error> {
+        unsafe {
+            let before = args_bytes(request, args).to_vec();
+            let buffer = args_buffer(request, args);
+            let buffer_bytes = |(data, len, _): (*mut u8, usize, bool)| std::slice::from_raw_parts(data, len).to_vec();
+            let buffer_in = buffer.filter(|&(_, _, output)| !output).map(buffer_bytes);
+            let result = self.inner.ioctl(request, args);
+            let entry = Entry {
+                request,
+                after: args_bytes(request, args).to_vec(),
+                before,
+                buffer: buffer_in.or_else(|| buffer.filter(|_| result.is_ok()).map(buffer_bytes)).unwrap_or_default(),
+                outcome: Outcome::of(&result),
+            };
+            // A trace with holes in it isn't much use, but neither is failing the ioctl because the disk is full.
+            let _ = writeln!(lock(&self.out), "{}", entry.to_line());
+            result
+        }
+    }
+
+    fn devname(&self) -> &Path {
+        self.inner.devname()
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// Plays back a trace from Recorder.
+pub struct Replayer {
+    devname: PathBuf,
+    entries: Vec<Entry>,
+    next: Mutex<usize>,
+}
+
+impl Replayer {
+    pub fn open(path: impl AsRef<Path>) -> Result<Replayer, Error> {
+        let mut replayer = Replayer::from_reader(BufReader::new(File::open(path.as_ref())?))?;
+        replayer.devname = path.as_ref().to_path_buf();
+        Ok(replayer)
+    }
+
+    pub fn from_reader(reader: impl BufRead) -> Result<Replayer, Error> {
+        let mut entries = Vec::new();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') { continue }
+            entries.push(Entry::from_line(&line).map_err(|e| Error::ParamErr { param: "trace", should_be: format!("valid (line {}: {e})", n + 1) })?);
+        }
+        Ok(Replayer { devname: PathBuf::from("replay"), entries, next: Mutex::new(0) })
+    }
+
+    // How many recorded ioctls haven't been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.entries.len() - *lock(&self.next)
+    }
+}
+
+impl PioBackend for Replayer {
+    unsafe fn ioctl(&self, request: c_ulong, args: *mut c_void) -> Result<u32, Error> {
+        let mut next = lock(&self.next);
+        let index = *next;
+        let mismatch = |expected: String| Error::ReplayMismatch { entry: index, expected, got: request_name(request).to_string() };
+        let Some(entry) = self.entries.get(index) else { Err(mismatch("the end of the trace".to_string()))? };
+        if entry.request != request {
+            Err(mismatch(request_name(entry.request).to_string()))?;
+        }
+        unsafe {
+            let bytes = args_bytes(request, args);
+            let compare = if args_buffer(request, args).is_some() { POINTER_OFFSET.min(bytes.len()) } else { bytes.len() };
+            if bytes[..compare] != entry.before[..compare.min(entry.before.len())] {
+                Err(mismatch(format!("{} with args {}", request_name(entry.request), hex(&entry.before))))?;
+            }
+            // Copy back whatever the kernel wrote, leaving pointers alone.
+            let keep_pointers = compare < bytes.len();
+            for (i, (byte, &recorded)) in bytes.iter_mut().zip(&entry.after).enumerate() {
+                if !keep_pointers || i < POINTER_OFFSET {
+                    *byte = recorded;
+                }
+            }
+            if let Some((data, len, true)) = args_buffer(request, args) {
+                let len = len.min(entry.buffer.len());
+                std::ptr::copy_nonoverlapping(entry.buffer.as_ptr(), data, len);
+            }
+        }
+        *next += 1;
+        entry.outcome.result()
+    }
+
+    fn devname(&self) -> &Path {
+        &self.devname
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockPio, Chip, PioProgram, Rp1PIO, SmConfig};
+
+    // A Write that can be read back after the Recorder is done with it.
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { lock(&self.0).write(buf) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    fn session(pio: &Rp1PIO) -> Result<(u16, u32, u32), Error> {
+        let offset = pio.add_program(&PioProgram::new(&[0x80a0, 0x6001], None))?;
+        let sm = pio.sm_claim_unused()?;
+        sm.set_config(&SmConfig::default().set_wrap(offset as u32, offset as u32 + 1)?)?;
+        sm.put(0x1234, true)?;
+        let word = sm.get(false)?;
+        let pc = sm.read_hw_state_machine()?.pc;
+        Ok((offset, word, pc))
+    }
+
+    #[test]
+    fn record_then_replay() {
+        let mock = MockPio::new();
+        mock.push_rx(0, &[0xabcd]);
+        let trace = SharedBuf::default();
+        let recorded = {
+            let recorder = Recorder::new(Box::new(mock.clone()), Box::new(trace.clone())).unwrap();
+            let pio = Rp1PIO::with_backend(Box::new(recorder), Chip::new());
+            session(&pio).unwrap()
+        };
+        assert_eq!(recorded.1, 0xabcd);
+
+        let text = lock(&trace.0).clone();
+        let replayer = Replayer::from_reader(&text[..]).unwrap();
+        let pio = Rp1PIO::with_backend(Box::new(replayer), Chip::new());
+        assert_eq!(session(&pio).unwrap(), recorded);
+    }
+
+    #[test]
+    fn replay_mismatch() {
+        let trace = format!("{HEADER}\n{}\n", Entry { request: PIO_IOC_SM_CLAIM, before: vec![1, 0], after: vec![1, 0], buffer: vec![], outcome: Outcome::Ok(0) }.to_line());
+        let pio = Rp1PIO::with_backend(Box::new(Replayer::from_reader(trace.as_bytes()).unwrap()), Chip::new());
+        assert!(matches!(pio.sm_claim(1), Err(Error::ReplayMismatch { entry: 0, .. })));
+        pio.sm_claim(0).unwrap();
+        assert!(matches!(pio.sm_claim(0), Err(Error::ReplayMismatch { entry: 1, .. })));
+    }
+
+    #[test]
+    fn bad_hex() {
+        assert_eq!(unhex("00ff7A"), Ok(vec![0, 0xff, 0x7a]));
+        assert!(unhex("€a").is_err()); // Even length, but not on a char boundary
+        assert!(unhex("+f").is_err());
+        assert!(unhex("abc").is_err());
+        let line = Entry { request: PIO_IOC_SM_CLAIM, before: vec![1, 0], after: vec![1, 0], buffer: vec![], outcome: Outcome::Ok(0) }.to_line();
+        let trace = format!("{HEADER}\n{}\n", line.replacen("0100", "€a", 1));
+        assert!(Replayer::from_reader(trace.as_bytes()).is_err());
+    }
+}
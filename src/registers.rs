@@ -0,0 +1,329 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// The PIO register map, with field names, for decoding raw register values (see proc-pio.rs for the descriptions):
+//
+//     for reg in pio.read_registers(false)? {
+//         println!("{} = {:#x?}", reg.name, reg.value);
+//         for (field, value) in reg.fields() { ... }
+//     }
+//
+// Registers repeated per SM (or per FIFO, or per instruction) are listed once, with "{}" in the name standing for
+// the index.
+
+use crate::{Error, Rp1PIO};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub msb: u32,
+    pub lsb: u32,
+    pub access: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Register {
+    pub name: &'static str,
+    pub offset: u32,
+    pub count: u32,
+    pub stride: u32,
+    pub access: &'static str,  // For registers without fields
+    pub fields: &'static [Field],
+}
+
+// A register read from the hardware. `value` is None for the ones that can't be read (or, for the FIFOs, can't be
+// read without side effects).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterValue {
+    pub name: String,
+    pub offset: u32,
+    pub value: Option<u32>,
+    pub register: &'static Register,
+}
+
+impl Field {
+    pub fn mask(&self) -> u32 {
+        (u32::MAX >> (31 - (self.msb - self.lsb))) << self.lsb
+    }
+
+    pub fn extract(&self, value: u32) -> u32 {
+        (value & self.mask()) >> self.lsb
+    }
+}
+
+impl Register {
+    // Each copy of the register, with its index substituted into the name.
+    pub fn instances(&self) -> impl Iterator<Item = (String, u32)> + '_ {
+        (0..self.count).map(|i| (self.name.replace("{}", &i.to_string()), self.offset + i * self.stride))
+    }
+
+    pub fn readable(&self) -> bool {
+        !matches!(self.access, "WO" | "WF" | "RF")
+    }
+}
+
+impl RegisterValue {
+    pub fn fields(&self) -> impl Iterator<Item = (&'static Field, u32)> + '_ {
+        self.register.fields.iter().filter_map(|field| self.value.map(|value| (field, field.extract(value))))
+    }
+}
+
+pub fn register_at(offset: u32) -> Option<(String, &'static Register)> {
+    REGISTERS.iter().find_map(|reg| reg.instances().find(|&(_, o)| o == offset).map(|(name, _)| (name, reg)))
+}
+
+impl Rp1PIO {
+    // Reads every readable register. Instruction memory is write-only, so only comes back with `all` (and then
+    // shows whatever the hardware returns for it, which may well be zeros).
+    pub fn read_registers(&self, all: bool) -> Result<Vec<RegisterValue>, Error> {
+        let mut values = Vec::new();
+        for reg in REGISTERS {
+            for (name, offset) in reg.instances() {
+                let value = if reg.readable() || all && reg.access == "WO" {
+                    let mut value = [0];
+                    self.read_hw(offset, &mut value)?;
+                    Some(value[0])
+                } else {
+                    None
+                };
+                values.push(RegisterValue { name, offset, value, register: reg });
+            }
+        }
+        Ok(values)
+    }
+}
+
+pub const REGISTERS: &[Register] = &[
+    Register { name: "CTRL", offset: 0x000, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "CLKDIV_RESTART", msb: 11, lsb: 8, access: "SC" },
+        Field { name: "SM_RESTART", msb: 7, lsb: 4, access: "SC" },
+        Field { name: "SM_ENABLE", msb: 3, lsb: 0, access: "RW" },
+    ]},
+    Register { name: "FSTAT", offset: 0x004, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "TXEMPTY", msb: 27, lsb: 24, access: "RO" },
+        Field { name: "TXFULL", msb: 19, lsb: 16, access: "RO" },
+        Field { name: "RXEMPTY", msb: 11, lsb: 8, access: "RO" },
+        Field { name: "RXFULL", msb: 3, lsb: 0, access: "RO" },
+    ]},
+    Register { name: "FDEBUG", offset: 0x008, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "TXSTALL", msb: 27, lsb: 24, access: "WC" },
+        Field { name: "TXOVER", msb: 19, lsb: 16, access: "WC" },
+        Field { name: "RXUNDER", msb: 11, lsb: 8, access: "WC" },
+        Field { name: "RXSTALL", msb: 3, lsb: 0, access: "WC" },
+    ]},
+    Register { name: "FLEVEL", offset: 0x00c, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "RX3", msb: 31, lsb: 28, access: "RO" },
+        Field { name: "TX3", msb: 27, lsb: 24, access: "RO" },
+        Field { name: "RX2", msb: 23, lsb: 20, access: "RO" },
+        Field { name: "TX2", msb: 19, lsb: 16, access: "RO" },
+        Field { name: "RX1", msb: 15, lsb: 12, access: "RO" },
+        Field { name: "TX1", msb: 11, lsb: 8, access: "RO" },
+        Field { name: "RX0", msb: 7, lsb: 4, access: "RO" },
+        Field { name: "TX0", msb: 3, lsb: 0, access: "RO" },
+    ]},
+    Register { name: "FLEVEL2", offset: 0x010, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "RX3", msb: 28, lsb: 28, access: "RO" },
+        Field { name: "TX3", msb: 24, lsb: 24, access: "RO" },
+        Field { name: "RX2", msb: 20, lsb: 20, access: "RO" },
+        Field { name: "TX2", msb: 16, lsb: 16, access: "RO" },
+        Field { name: "RX1", msb: 12, lsb: 12, access: "RO" },
+        Field { name: "TX1", msb: 8, lsb: 8, access: "RO" },
+        Field { name: "RX0", msb: 4, lsb: 4, access: "RO" },
+        Field { name: "TX0", msb: 0, lsb: 0, access: "RO" },
+    ]},
+    Register { name: "TXF{}", offset: 0x014, count: 4, stride: 0x04, access: "WF", fields: &[] },
+    Register { name: "RXF{}", offset: 0x024, count: 4, stride: 0x04, access: "RF", fields: &[] },
+    Register { name: "IRQ", offset: 0x034, count: 1, stride: 0x00, access: "WC", fields: &[] },
+    Register { name: "IRQ_FORCE", offset: 0x038, count: 1, stride: 0x00, access: "WF", fields: &[] },
+    Register { name: "INPUT_SYNC_BYPASS", offset: 0x03c, count: 1, stride: 0x00, access: "RW", fields: &[] },
+    Register { name: "DBG_PADOUT", offset: 0x040, count: 1, stride: 0x00, access: "RO", fields: &[] },
+    Register { name: "DBG_PADOE", offset: 0x044, count: 1, stride: 0x00, access: "RO", fields: &[] },
+    Register { name: "DBG_CFGINFO", offset: 0x048, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "IMEM_SIZE", msb: 21, lsb: 16, access: "RO" },
+        Field { name: "SM_COUNT", msb: 11, lsb: 8, access: "RO" },
+        Field { name: "FIFO_DEPTH", msb: 5, lsb: 0, access: "RO" },
+    ]},
+    Register { name: "INSTR_MEM{}", offset: 0x04c, count: 32, stride: 0x04, access: "WO", fields: &[] },
+    Register { name: "SM{}_CLKDIV", offset: 0x0cc, count: 4, stride: 0x20, access: "", fields: &[
+        Field { name: "INT", msb: 31, lsb: 16, access: "RW" },
+        Field { name: "FRAC", msb: 15, lsb: 8, access: "RW" },
+    ]},
+    Register { name: "SM{}_EXECCTRL", offset: 0x0d0, count: 4, stride: 0x20, access: "", fields: &[
+        Field { name: "EXEC_STALLED", msb: 31, lsb: 31, access: "RO" },
+        Field { name: "SIDE_EN", msb: 30, lsb: 30, access: "RW" },
+        Field { name: "SIDE_PINDIR", msb: 29, lsb: 29, access: "RW" },
+        Field { name: "JMP_PIN", msb: 28, lsb: 24, access: "RW" },
+        Field { name: "OUT_EN_SEL", msb: 23, lsb: 19, access: "RW" },
+        Field { name: "INLINE_OUT_EN", msb: 18, lsb: 18, access: "RW" },
+        Field { name: "OUT_STICKY", msb: 17, lsb: 17, access: "RW" },
+        Field { name: "WRAP_TOP", msb: 16, lsb: 12, access: "RW" },
+        Field { name: "WRAP_BOTTOM", msb: 11, lsb: 7, access: "RW" },
+        Field { name: "STATUS_SEL", msb: 5, lsb: 5, access: "RW" },
+        Field { name: "STATUS_N", msb: 4, lsb: 0, access: "RW" },
+    ]},
+    Register { name: "SM{}_SHIFTCTRL", offset: 0x0d4, count: 4, stride: 0x20, access: "", fields: &[
+        Field { name: "FJOIN_RX", msb: 31, lsb: 31, access: "RW" },
+        Field { name: "FJOIN_TX", msb: 30, lsb: 30, access: "RW" },
+        Field { name: "PULL_THRESH", msb: 29, lsb: 25, access: "RW" },
+        Field { name: "PUSH_THRESH", msb: 24, lsb: 20, access: "RW" },
+        Field { name: "OUT_SHIFTDIR", msb: 19, lsb: 19, access: "RW" },
+        Field { name: "IN_SHIFTDIR", msb: 18, lsb: 18, access: "RW" },
+        Field { name: "AUTOPULL", msb: 17, lsb: 17, access: "RW" },
+        Field { name: "AUTOPUSH", msb: 16, lsb: 16, access: "RW" },
+    ]},
+    Register { name: "SM{}_ADDR", offset: 0x0d8, count: 4, stride: 0x20, access: "RO", fields: &[] },
+    Register { name: "SM{}_INSTR", offset: 0x0dc, count: 4, stride: 0x20, access: "RW", fields: &[] },
+    Register { name: "SM{}_PINCTRL", offset: 0x0e0, count: 4, stride: 0x20, access: "", fields: &[
+        Field { name: "SIDESET_COUNT", msb: 31, lsb: 29, access: "RW" },
+        Field { name: "SET_COUNT", msb: 28, lsb: 26, access: "RW" },
+        Field { name: "OUT_COUNT", msb: 25, lsb: 20, access: "RW" },
+        Field { name: "IN_BASE", msb: 19, lsb: 15, access: "RW" },
+        Field { name: "SIDESET_BASE", msb: 14, lsb: 10, access: "RW" },
+        Field { name: "SET_BASE", msb: 9, lsb: 5, access: "RW" },
+        Field { name: "OUT_BASE", msb: 4, lsb: 0, access: "RW" },
+    ]},
+    Register { name: "SM{}_DMACTRL_TX", offset: 0x0e4, count: 4, stride: 0x20, access: "", fields: &[
+        Field { name: "DREQ_EN", msb: 31, lsb: 31, access: "RW" },
+        Field { name: "ACTIVE", msb: 30, lsb: 30, access: "RO" },
+        Field { name: "DWELL_TIME", msb: 11, lsb: 7, access: "RW" },
+        Field { name: "FIFO_THRESHOLD", msb: 4, lsb: 0, access: "RW" },
+    ]},
+    Register { name: "SM{}_DMACTRL_RX", offset: 0x0e8, count: 4, stride: 0x20, access: "", fields: &[
+        Field { name: "DREQ_EN", msb: 31, lsb: 31, access: "RW" },
+        Field { name: "ACTIVE", msb: 30, lsb: 30, access: "RO" },
+        Field { name: "DWELL_TIME", msb: 11, lsb: 7, access: "RW" },
+        Field { name: "FIFO_THRESHOLD", msb: 4, lsb: 0, access: "RW" },
+    ]},
+    Register { name: "INTR", offset: 0x14c, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "SM3", msb: 11, lsb: 11, access: "RO" },
+        Field { name: "SM2", msb: 10, lsb: 10, access: "RO" },
+        Field { name: "SM1", msb: 9, lsb: 9, access: "RO" },
+        Field { name: "SM0", msb: 8, lsb: 8, access: "RO" },
+        Field { name: "SM3_TXNFULL", msb: 7, lsb: 7, access: "RO" },
+        Field { name: "SM2_TXNFULL", msb: 6, lsb: 6, access: "RO" },
+        Field { name: "SM1_TXNFULL", msb: 5, lsb: 5, access: "RO" },
+        Field { name: "SM0_TXNFULL", msb: 4, lsb: 4, access: "RO" },
+        Field { name: "SM3_RXNEMPTY", msb: 3, lsb: 3, access: "RO" },
+        Field { name: "SM2_RXNEMPTY", msb: 2, lsb: 2, access: "RO" },
+        Field { name: "SM1_RXNEMPTY", msb: 1, lsb: 1, access: "RO" },
+        Field { name: "SM0_RXNEMPTY", msb: 0, lsb: 0, access: "RO" },
+    ]},
+    Register { name: "IRQ0_INTE", offset: 0x150, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "SM3", msb: 11, lsb: 11, access: "RW" },
+        Field { name: "SM2", msb: 10, lsb: 10, access: "RW" },
+        Field { name: "SM1", msb: 9, lsb: 9, access: "RW" },
+        Field { name: "SM0", msb: 8, lsb: 8, access: "RW" },
+        Field { name: "SM3_TXNFULL", msb: 7, lsb: 7, access: "RW" },
+        Field { name: "SM2_TXNFULL", msb: 6, lsb: 6, access: "RW" },
+        Field { name: "SM1_TXNFULL", msb: 5, lsb: 5, access: "RW" },
+        Field { name: "SM0_TXNFULL", msb: 4, lsb: 4, access: "RW" },
+        Field { name: "SM3_RXNEMPTY", msb: 3, lsb: 3, access: "RW" },
+        Field { name: "SM2_RXNEMPTY", msb: 2, lsb: 2, access: "RW" },
+        Field { name: "SM1_RXNEMPTY", msb: 1, lsb: 1, access: "RW" },
+        Field { name: "SM0_RXNEMPTY", msb: 0, lsb: 0, access: "RW" },
+    ]},
+    Register { name: "IRQ0_INTF", offset: 0x154, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "SM3", msb: 11, lsb: 11, access: "RW" },
+        Field { name: "SM2", msb: 10, lsb: 10, access: "RW" },
+        Field { name: "SM1", msb: 9, lsb: 9, access: "RW" },
+        Field { name: "SM0", msb: 8, lsb: 8, access: "RW" },
+        Field { name: "SM3_TXNFULL", msb: 7, lsb: 7, access: "RW" },
+        Field { name: "SM2_TXNFULL", msb: 6, lsb: 6, access: "RW" },
+        Field { name: "SM1_TXNFULL", msb: 5, lsb: 5, access: "RW" },
+        Field { name: "SM0_TXNFULL", msb: 4, lsb: 4, access: "RW" },
+        Field { name: "SM3_RXNEMPTY", msb: 3, lsb: 3, access: "RW" },
+        Field { name: "SM2_RXNEMPTY", msb: 2, lsb: 2, access: "RW" },
+        Field { name: "SM1_RXNEMPTY", msb: 1, lsb: 1, access: "RW" },
+        Field { name: "SM0_RXNEMPTY", msb: 0, lsb: 0, access: "RW" },
+    ]},
+    Register { name: "IRQ0_INTS", offset: 0x158, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "SM3", msb: 11, lsb: 11, access: "RO" },
+        Field { name: "SM2", msb: 10, lsb: 10, access: "RO" },
+        Field { name: "SM1", msb: 9, lsb: 9, access: "RO" },
+        Field { name: "SM0", msb: 8, lsb: 8, access: "RO" },
+        Field { name: "SM3_TXNFULL", msb: 7, lsb: 7, access: "RO" },
+        Field { name: "SM2_TXNFULL", msb: 6, lsb: 6, access: "RO" },
+        Field { name: "SM1_TXNFULL", msb: 5, lsb: 5, access: "RO" },
+        Field { name: "SM0_TXNFULL", msb: 4, lsb: 4, access: "RO" },
+        Field { name: "SM3_RXNEMPTY", msb: 3, lsb: 3, access: "RO" },
+        Field { name: "SM2_RXNEMPTY", msb: 2, lsb: 2, access: "RO" },
+        Field { name: "SM1_RXNEMPTY", msb: 1, lsb: 1, access: "RO" },
+        Field { name: "SM0_RXNEMPTY", msb: 0, lsb: 0, access: "RO" },
+    ]},
+    Register { name: "IRQ1_INTE", offset: 0x15c, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "SM3", msb: 11, lsb: 11, access: "RW" },
+        Field { name: "SM2", msb: 10, lsb: 10, access: "RW" },
+        Field { name: "SM1", msb: 9, lsb: 9, access: "RW" },
+        Field { name: "SM0", msb: 8, lsb: 8, access: "RW" },
+        Field { name: "SM3_TXNFULL", msb: 7, lsb: 7, access: "RW" },
+        Field { name: "SM2_TXNFULL", msb: 6, lsb: 6, access: "RW" },
+        Field { name: "SM1_TXNFULL", msb: 5, lsb: 5, access: "RW" },
+        Field { name: "SM0_TXNFULL", msb: 4, lsb: 4, access: "RW" },
+        Field { name: "SM3_RXNEMPTY", msb: 3, lsb: 3, access: "RW" },
+        Field { name: "SM2_RXNEMPTY", msb: 2, lsb: 2, access: "RW" },
+        Field { name: "SM1_RXNEMPTY", msb: 1, lsb: 1, access: "RW" },
+        Field { name: "SM0_RXNEMPTY", msb: 0, lsb: 0, access: "RW" },
+    ]},
+    Register { name: "IRQ1_INTF", offset: 0x160, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "SM3", msb: 11, lsb: 11, access: "RW" },
+        Field { name: "SM2", msb: 10, lsb: 10, access: "RW" },
+        Field { name: "SM1", msb: 9, lsb: 9, access: "RW" },
+        Field { name: "SM0", msb: 8, lsb: 8, access: "RW" },
+        Field { name: "SM3_TXNFULL", msb: 7, lsb: 7, access: "RW" },
+        Field { name: "SM2_TXNFULL", msb: 6, lsb: 6, access: "RW" },
+        Field { name: "SM1_TXNFULL", msb: 5, lsb: 5, access: "RW" },
+        Field { name: "SM0_TXNFULL", msb: 4, lsb: 4, access: "RW" },
+        Field { name: "SM3_RXNEMPTY", msb: 3, lsb: 3, access: "RW" },
+        Field { name: "SM2_RXNEMPTY", msb: 2, lsb: 2, access: "RW" },
+        Field { name: "SM1_RXNEMPTY", msb: 1, lsb: 1, access: "RW" },
+        Field { name: "SM0_RXNEMPTY", msb: 0, lsb: 0, access: "RW" },
+    ]},
+    Register { name: "IRQ1_INTS", offset: 0x164, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "SM3", msb: 11, lsb: 11, access: "RO" },
+        Field { name: "SM2", msb: 10, lsb: 10, access: "RO" },
+        Field { name: "SM1", msb: 9, lsb: 9, access: "RO" },
+        Field { name: "SM0", msb: 8, lsb: 8, access: "RO" },
+        Field { name: "SM3_TXNFULL", msb: 7, lsb: 7, access: "RO" },
+        Field { name: "SM2_TXNFULL", msb: 6, lsb: 6, access: "RO" },
+        Field { name: "SM1_TXNFULL", msb: 5, lsb: 5, access: "RO" },
+        Field { name: "SM0_TXNFULL", msb: 4, lsb: 4, access: "RO" },
+        Field { name: "SM3_RXNEMPTY", msb: 3, lsb: 3, access: "RO" },
+        Field { name: "SM2_RXNEMPTY", msb: 2, lsb: 2, access: "RO" },
+        Field { name: "SM1_RXNEMPTY", msb: 1, lsb: 1, access: "RO" },
+        Field { name: "SM0_RXNEMPTY", msb: 0, lsb: 0, access: "RO" },
+    ]},
+    Register { name: "BLOCK_ID", offset: 0x168, count: 1, stride: 0x00, access: "RO", fields: &[] },
+    Register { name: "INSTANCE_ID", offset: 0x16c, count: 1, stride: 0x00, access: "RO", fields: &[] },
+    Register { name: "RSTSEQ_AUTO", offset: 0x170, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "BUSADAPTER", msb: 0, lsb: 0, access: "RW" },
+    ]},
+    Register { name: "RSTSEQ_PARALLEL", offset: 0x174, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "BUSADAPTER", msb: 0, lsb: 0, access: "RO" },
+    ]},
+    Register { name: "RSTSEQ_CTRL", offset: 0x178, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "BUSADAPTER", msb: 0, lsb: 0, access: "RW" },
+    ]},
+    Register { name: "RSTSEQ_TRIG", offset: 0x17c, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "BUSADAPTER", msb: 0, lsb: 0, access: "SC" },
+    ]},
+    Register { name: "RSTSEQ_DONE", offset: 0x180, count: 1, stride: 0x00, access: "", fields: &[
+        Field { name: "BUSADAPTER", msb: 0, lsb: 0, access: "RO" },
+    ]},
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proc_pio::*;
+
+    #[test]
+    fn lookup_and_decode() {
+        let (name, reg) = register_at(PROC_PIO_SM2_EXECCTRL_OFFSET).unwrap();
+        assert_eq!(name, "SM2_EXECCTRL");
+        let wrap_top = reg.fields.iter().find(|f| f.name == "WRAP_TOP").unwrap();
+        assert_eq!(wrap_top.mask(), PROC_PIO_SM0_EXECCTRL_WRAP_TOP_BITS);
+        assert_eq!(wrap_top.extract(7 << PROC_PIO_SM0_EXECCTRL_WRAP_TOP_LSB | 1), 7);
+        assert_eq!(register_at(PROC_PIO_RSTSEQ_DONE_OFFSET).unwrap().0, "RSTSEQ_DONE");
+        assert_eq!(REGISTERS.iter().map(|reg| reg.count).sum::<u32>(), 97);
+    }
+}
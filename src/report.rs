@@ -0,0 +1,125 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Everything worth knowing about a PIO block (and the system around it) in one JSON document, for bug reports:
+//
+//     std::fs::write("pio-report.json", pio.diagnostic_report())?;
+//
+// A part that can't be read shows up as `{ "error": "..." }` instead of sinking the whole report, since the reports
+// that matter most come from systems where things are failing.
+
+use std::{fmt::Write, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::{instruction::{Instruction, SideSet}, pio_clock_hz, pio_clock_source, Error, Rp1PIO, SmConfig};
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\'          => { out.push('\\'); out.push(c) },
+            c if c < ' '        => { let _ = write!(out, "\\u{:04x}", c as u32); },
+            c                   => out.push(c),
+        }
+    }
+    out + "\""
+}
+
+fn json_error(error: impl std::fmt::Display) -> String {
+    format!("{{ \"error\": {} }}", json_string(&error.to_string()))
+}
+
+fn file(path: &str) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => json_string(contents.trim()),
+        Err(_)       => "null".to_string(),
+    }
+}
+
+fn seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_secs_f64()).unwrap_or(0.0)
+}
+
+// FNV-1a, so the same program gets the same fingerprint on every system.
+fn fingerprint(instructions: &[u16]) -> String {
+    let hash = instructions.iter().flat_map(|instr| instr.to_le_bytes())
+        .fold(0x811c_9dc5_u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193));
+    format!("\"{hash:08x}\"")
+}
+
+impl Rp1PIO {
+    pub fn diagnostic_report(&self) -> String {
+        let chip = self.chip();
+        let mut out = String::from("{\n");
+        let _ = writeln!(out, "  \"generated\": {:.3},", seconds(SystemTime::now()));
+        let _ = writeln!(out, "  \"crate_version\": {},", json_string(env!("CARGO_PKG_VERSION")));
+        let _ = writeln!(out, "  \"device\": {},", json_string(&self.devname().display().to_string()));
+        let _ = writeln!(out, "  \"chip\": {{ \"name\": {}, \"compatible\": {}, \"instr_count\": {}, \"sm_count\": {}, \"fifo_depth\": {} }},",
+                         json_string(&chip.name), json_string(&chip.compatible), chip.instr_count, chip.sm_count, chip.fifo_depth);
+        let _ = writeln!(out, "  \"pio_clock_hz\": {},", pio_clock_hz());
+        let _ = writeln!(out, "  \"pio_clock_source\": {},", json_string(&format!("{:?}", pio_clock_source())));
+        let _ = writeln!(out, "  \"kernel\": {{ \"release\": {}, \"version\": {} }},",
+                         file("/proc/sys/kernel/osrelease"), file("/proc/sys/kernel/version"));
+        let _ = writeln!(out, "  \"driver\": {{ \"module\": \"rp1_pio\", \"version\": {}, \"srcversion\": {} }},",
+                         file("/sys/module/rp1_pio/version"), file("/sys/module/rp1_pio/srcversion"));
+
+        let sms: Vec<String> = (0..chip.sm_count).map(|sm| self.sm_report(sm).unwrap_or_else(json_error)).collect();
+        let _ = writeln!(out, "  \"state_machines\": [\n    {}\n  ],", sms.join(",\n    "));
+
+        let programs: Vec<String> = self.loaded_programs().into_iter().map(|(offset, instructions, refs)| match instructions {
+            Ok(instructions) => format!("{{ \"offset\": {offset}, \"length\": {}, \"refs\": {refs}, \"fingerprint\": {} }}",
+                                        instructions.len(), fingerprint(&instructions)),
+            Err(length)      => format!("{{ \"offset\": {offset}, \"length\": {length}, \"refs\": null, \"fingerprint\": null }}"),
+        }).collect();
+        let _ = writeln!(out, "  \"programs\": [{}],", if programs.is_empty() { String::new() } else { format!("\n    {}\n  ", programs.join(",\n    ")) });
+
+        let _ = writeln!(out, "  \"registers\": {},", self.registers_report().unwrap_or_else(json_error));
+
+        let errors: Vec<String> = self.recent_errors().into_iter()
+            .map(|(time, error)| format!("{{ \"time\": {:.3}, \"error\": {} }}", seconds(time), json_string(&error)))
+            .collect();
+        let _ = writeln!(out, "  \"recent_errors\": [{}]", if errors.is_empty() { String::new() } else { format!("\n    {}\n  ", errors.join(",\n    ")) });
+        out + "}\n"
+    }
+
+    fn sm_report(&self, sm: u16) -> Result<String, Error> {
+        let hw = self.read_hw_state_machine(sm)?;
+        let fifo = self.read_hw_fifo(sm)?;
+        let config = SmConfig::from_hw(&hw);
+        let (bit_count, optional, pindirs) = config.get_sideset();
+        let side_set = SideSet::new(bit_count.saturating_sub(optional as u32) as u8, optional, pindirs);
+        let disassembly = Instruction::decode(hw.instr as u16, side_set).map(|instr| instr.to_string()).unwrap_or_else(|_| "??".to_string());
+        let claimed = self.sm_is_claimed(sm).map(|claimed| claimed.to_string()).unwrap_or_else(|_| "null".to_string());
+        Ok(format!("{{ \"sm\": {sm}, \"claimed\": {claimed}, \"enabled\": {}, \"pc\": {}, \"instr\": {}, \"disassembly\": {}, \
+                    \"clkdiv\": {}, \"execctrl\": {}, \"shiftctrl\": {}, \"pinctrl\": {}, \"tx_level\": {}, \"rx_level\": {} }}",
+                   hw.enabled, hw.pc, hw.instr, json_string(&disassembly), hw.clkdiv, hw.execctrl, hw.shiftctrl, hw.pinctrl,
+                   fifo.tx.level, fifo.rx.level))
+    }
+
+    fn registers_report(&self) -> Result<String, Error> {
+        let registers: Vec<String> = self.read_registers(false)?.iter().filter_map(|reg| {
+            let value = reg.value?;
+            let fields = reg.fields().map(|(field, value)| format!("{}: {value}", json_string(field.name))).collect::<Vec<_>>().join(", ");
+            Some(format!("{{ \"name\": {}, \"offset\": {}, \"value\": {value}, \"fields\": {{ {fields} }} }}", json_string(&reg.name), reg.offset))
+        }).collect();
+        Ok(format!("[\n    {}\n  ]", registers.join(",\n    ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, PioProgram, Rp1PIO};
+
+    #[test]
+    fn report_has_programs_and_errors() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend), Chip::new());
+        let _program = pio.load_program(&PioProgram::new(&[0xa042], None)).unwrap(); // nop
+        let _sm = pio.sm_claim(1).unwrap();
+        assert!(pio.sm_claim(1).is_err());
+        let report = pio.diagnostic_report();
+        assert!(report.contains("\"offset\": 31, \"length\": 1, \"refs\": 1, \"fingerprint\": \"7cdc5b3f\""), "{report}");
+        assert!(report.contains("{ \"sm\": 1, \"claimed\": true,"), "{report}");
+        assert!(report.contains("SM_CLAIM"), "{report}");
+        assert!(report.contains("\"name\": \"SM1_PINCTRL\""), "{report}");
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// A loopback smoke test: jumper two GPIOs together and
+//
+//     let report = pio.self_test(20, 21)?;
+//
+// sends words out of one SM on `tx_gpio` and reads them back with another on `rx_gpio`, then times a single bit. If
+// this passes the driver, permissions, pin muxing and clocks are all fine and any remaining problem is in your
+// program. The same GPIO can be used for both to test without a jumper.
+
+use crate::{pio_clock_hz, ClkDiv, Error, PioProgram, Rp1PIO, SmConfig, StateMachine};
+
+// 32 bit frames, UART style: a start bit, 32 data bits LSB first and a stop bit, 8 SM clocks each.
+const CYCLES_PER_BIT: f64 = 8.0;
+const BIT_RATE: f64 = 1_000_000.0;
+
+const TX_PROGRAM: [u16; 6] = [
+    0x80a0, //     pull block
+    0xe600, //     set pins, 0 [6]      ; Start bit
+    0xe03f, //     set x, 31            ; (its 8th clock)
+    0x6601, // bit:
+            //     out pins, 1 [6]
+    0x0043, //     jmp x-- bit
+    0xe701, //     set pins, 1 [7]      ; Stop bit
+];
+
+const RX_PROGRAM: [u16; 6] = [
+    0x2020, //     wait 0 pin 0         ; Start bit
+    0xea3f, //     set x, 31 [10]       ; To the middle of the first data bit
+    0x4601, // bit:
+            //     in pins, 1 [6]
+    0x0042, //     jmp x-- bit
+    0x8020, //     push block
+    0x20a0, //     wait 1 pin 0         ; Stop bit
+];
+
+// Counts how long the line is low for, in pairs of system clocks.
+const MEASURE_PROGRAM: [u16; 7] = [
+    0x20a0, //     wait 1 pin 0
+    0x2020, //     wait 0 pin 0
+    0xa02b, //     mov x, ~null
+    0x00c5, // low:
+            //     jmp pin done
+    0x0043, //     jmp x-- low
+    0x4020, // done:
+            //     in x, 32
+    0x8020, //     push block
+];
+
+const TEST_WORDS: [u32; 6] = [0x0000_0000, 0xffff_ffff, 0xa5a5_5a5a, 0x1234_5678, 0x8000_0001, 0xfedc_ba98];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelfTestReport {
+    pub words: usize,               // How many words made the round trip
+    pub bit_cycles: f64,            // System clocks per bit the TX SM was set up for
+    pub measured_bit_cycles: f64,   // What the RX SM actually saw
+}
+
+fn failed(reason: String) -> Error {
+    Error::SelfTestFailed(reason)
+}
+
+impl Rp1PIO {
+    // Needs two free SMs and room for the test programs. Everything is released again afterwards.
+    pub fn self_test(&self, tx_gpio: u16, rx_gpio: u16) -> Result<SelfTestReport, Error> {
+        self.check_gpio(tx_gpio)?;
+        self.check_gpio(rx_gpio)?;
+        let tx = self.sm_claim_unused()?;
+        let rx = match self.sm_claim_unused() {
+            Ok(rx) => rx,
+            Err(e) => { let _ = tx.unclaim(); return Err(e) },
+        };
+        let result = self.run_self_test(&tx, &rx, tx_gpio as u32, rx_gpio as u32);
+        let _ = self.sm_set_enabled_mask(1 << tx.index() | 1 << rx.index(), false);
+        let _ = tx.unclaim();
+        let _ = rx.unclaim();
+        result
+    }
+
+    fn run_self_test(&self, tx: &StateMachine, rx: &StateMachine, tx_gpio: u32, rx_gpio: u32) -> Result<SelfTestReport, Error> {
+        let div = ClkDiv::for_frequency(pio_clock_hz() as f64, BIT_RATE * CYCLES_PER_BIT)?;
+        let bit_cycles = pio_clock_hz() as f64 / div.actual_frequency(pio_clock_hz() as f64) * CYCLES_PER_BIT;
+
+        let tx_program = self.load_program(&PioProgram::new(&TX_PROGRAM, None))?;
+        let rx_program = self.load_program(&PioProgram::new(&RX_PROGRAM, None))?;
+        self.pio_gpio_init(tx_gpio as u16)?;
+        self.pio_gpio_init(rx_gpio as u16)?;
+
+        let (wrap_target, wrap) = rx_program.wrap();
+        rx.init(rx_program.offset(), &SmConfig::default()
+                .set_in_pins(rx_gpio)?
+                .set_in_shift(true, false, 32)?
+                .set_clkdiv_int_frac(div)?
+                .set_wrap(wrap_target, wrap)?)?;
+        rx.set_consecutive_pindirs(rx_gpio, 1, false)?;
+
+        let (wrap_target, wrap) = tx_program.wrap();
+        tx.init(tx_program.offset(), &SmConfig::default()
+                .set_out_pins(tx_gpio, 1)?
+                .set_set_pins(tx_gpio, 1)?
+                .set_out_shift(true, false, 32)?
+                .set_clkdiv_int_frac(div)?
+                .set_wrap(wrap_target, wrap)?)?;
+        tx.set_pins_with_mask(1 << tx_gpio, 1 << tx_gpio)?; // Idle high
+        tx.set_consecutive_pindirs(tx_gpio, 1, true)?;
+
+        self.sm_enable_sync(1 << tx.index() | 1 << rx.index())?;
+        for (i, &word) in TEST_WORDS.iter().enumerate() {
+            tx.put(word, true)?;
+            let got = match rx.get(true) {
+                Err(Error::TimedOut) => Err(failed(format!("nothing received on GPIO {rx_gpio} after word {i}. Is it connected to GPIO {tx_gpio}?")))?,
+                result => result?,
+            };
+            if got != word {
+                Err(failed(format!("sent {word:#010x} but received {got:#010x} (word {i})")))?;
+            }
+        }
+
+        // Swap the RX SM over to timing the start bit of an all ones word, at full speed.
+        rx.set_enabled(false)?;
+        drop(rx_program);
+        let measure = self.load_program(&PioProgram::new(&MEASURE_PROGRAM, None))?;
+        let (wrap_target, wrap) = measure.wrap();
+        rx.init(measure.offset(), &SmConfig::default()
+                .set_in_pins(rx_gpio)?
+                .set_jmp_pin(rx_gpio)?
+                .set_in_shift(true, false, 32)?
+                .set_wrap(wrap_target, wrap)?)?;
+        rx.set_enabled(true)?;
+        tx.put(0xffff_ffff, true)?;
+        let count = !match rx.get(true) {
+            Err(Error::TimedOut) => Err(failed(format!("couldn't time a bit on GPIO {rx_gpio}")))?,
+            result => result?,
+        };
+        // 2 clocks per loop, plus the mov before it and the jmp that exits it.
+        let measured_bit_cycles = count as f64 * 2.0 + 2.0;
+        if (measured_bit_cycles - bit_cycles).abs() > (bit_cycles * 0.05).max(4.0) {
+            Err(failed(format!("bits should be {bit_cycles:.1} clocks long but measured {measured_bit_cycles:.1}")))?;
+        }
+
+        Ok(SelfTestReport { words: TEST_WORDS.len(), bit_cycles, measured_bit_cycles })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, Rp1PIO};
+
+    #[test]
+    fn loopback_on_one_pin() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(100_000);
+        let pio = Rp1PIO::with_backend(Box::new(backend), Chip::new());
+        let report = pio.self_test(4, 4).unwrap();
+        assert_eq!(report.words, 6);
+        assert!((report.measured_bit_cycles - report.bit_cycles).abs() <= 4.0, "{report:?}");
+        assert_eq!(pio.sm_claim_mask(0b11).unwrap().len(), 2); // Released
+    }
+
+    #[test]
+    fn nothing_connected() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new())).with_timeout(100_000);
+        let pio = Rp1PIO::with_backend(Box::new(backend), Chip::new());
+        assert!(pio.self_test(4, 5).is_err());
+    }
+}
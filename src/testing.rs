@@ -0,0 +1,127 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Fixtures for tests that need real hardware. Jumper pairs of GPIOs together, list them in the environment and run
+// the ignored tests:
+//
+//     PIO_TEST_GPIO_PAIRS=20:21,22:23 cargo test -- --ignored
+//
+// where each pair is <output>:<input>. PIO_TEST_DEVICE picks the device (default /dev/pio0). A test looks like:
+//
+//     #[test]
+//     #[ignore = "needs hardware"]
+//     fn loopback() {
+//         testing::run(2, 1, |fixture| {
+//             let (tx, rx) = (&fixture.sms[0], &fixture.sms[1]);
+//             let pair = fixture.pairs[0];
+//             ...
+//         });
+//     }
+//
+// Fixtures run one at a time, even when the test harness runs tests in parallel. However the test ends (panics
+// included) its SMs are disabled and released, the pair's pins are put back to inputs, and any programs it loaded
+// are removed.
+
+use std::{path::PathBuf, sync::Mutex};
+
+use crate::{lock, Error, Rp1PIO, StateMachine, TeardownPolicy};
+
+pub const DEVICE_VAR: &str = "PIO_TEST_DEVICE";
+pub const GPIO_PAIRS_VAR: &str = "PIO_TEST_GPIO_PAIRS";
+
+static HARDWARE: Mutex<()> = Mutex::new(());
+
+// Two GPIOs wired together: whatever is driven on `out` can be read on `input`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpioPair {
+    pub out: u16,
+    pub input: u16,
+}
+
+pub struct Fixture<'a> {
+    pub pio: &'a Rp1PIO,
+    pub sms: Vec<StateMachine<'a>>,
+    pub pairs: Vec<GpioPair>,
+}
+
+fn parse_pairs(pairs: &str) -> Result<Vec<GpioPair>, Error> {
+    let bad = || Error::ParamErr { param: GPIO_PAIRS_VAR, should_be: format!("comma separated <out>:<in> GPIO pairs, not {pairs:?}") };
+    pairs.split(',').map(str::trim).filter(|pair| !pair.is_empty()).map(|pair| {
+        let (out, input) = pair.split_once(':').ok_or_else(bad)?;
+        Ok(GpioPair { out: out.trim().parse().map_err(|_| bad())?, input: input.trim().parse().map_err(|_| bad())? })
+    }).collect()
+}
+
+// The jumpered pairs from PIO_TEST_GPIO_PAIRS.
+pub fn gpio_pairs() -> Result<Vec<GpioPair>, Error> {
+    match std::env::var(GPIO_PAIRS_VAR) {
+        Ok(pairs) => parse_pairs(&pairs),
+        Err(_)    => Ok(Vec::new()),
+    }
+}
+
+pub fn device() -> PathBuf {
+    std::env::var_os(DEVICE_VAR).map(PathBuf::from).unwrap_or_else(|| PathBuf::from("/dev/pio0"))
+}
+
+// Opens the device, claims `sms` state machines and `pairs` GPIO pairs, and runs `test` with them. Panics (failing
+// the test) if the hardware or pairs aren't available.
+pub fn run<R>(sms: usize, pairs: usize, test: impl FnOnce(&Fixture) -> R) -> R {
+    try_run(sms, pairs, test).unwrap_or_else(|e| panic!("hardware test setup failed: {e}"))
+}
+
+// Like run(), but hands back setup errors instead of panicking.
+pub fn try_run<R>(sms: usize, pairs: usize, test: impl FnOnce(&Fixture) -> R) -> Result<R, Error> {
+    let _hardware = lock(&HARDWARE);
+    let available = gpio_pairs()?;
+    if available.len() < pairs {
+        Err(Error::ParamErr { param: GPIO_PAIRS_VAR, should_be: format!("set to at least {pairs} <out>:<in> pair(s), found {}", available.len()) })?;
+    }
+    let mut pio = Rp1PIO::open_path(device())?;
+    pio.set_teardown_policy(TeardownPolicy::DisableAndRemove);
+    let mut fixture = Fixture { pio: &pio, sms: Vec::new(), pairs: available[..pairs].to_vec() };
+    for _ in 0..sms {
+        fixture.sms.push(pio.sm_claim_unused()?);
+    }
+    for pair in &fixture.pairs {
+        pio.pio_gpio_init(pair.out)?;
+        pio.pio_gpio_init(pair.input)?;
+    }
+    Ok(test(&fixture))
+}
+
+impl Drop for Fixture<'_> {
+    fn drop(&mut self) {
+        let pins = self.pairs.iter().fold(0, |pins, pair| pins | 1 << pair.out | 1 << pair.input);
+        if let Some(sm) = self.sms.first() {
+            let _ = sm.set_pindirs_with_mask(0, pins);
+        }
+        let mask = self.sms.iter().fold(0, |mask, sm| mask | 1 << sm.index());
+        let _ = self.pio.sm_set_enabled_mask(mask, false);
+        for sm in self.sms.drain(..) {
+            let _ = sm.unclaim();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs() {
+        assert_eq!(parse_pairs("20:21, 22:23,").unwrap(), [GpioPair { out: 20, input: 21 }, GpioPair { out: 22, input: 23 }]);
+        assert_eq!(parse_pairs("").unwrap(), []);
+        assert!(parse_pairs("20-21").is_err());
+        assert!(parse_pairs("20:x").is_err());
+    }
+
+    #[test]
+    #[ignore = "needs hardware"]
+    fn self_test_on_hardware() {
+        run(0, 1, |fixture| {
+            let pair = fixture.pairs[0];
+            fixture.pio.self_test(pair.out, pair.input).unwrap();
+        });
+    }
+}
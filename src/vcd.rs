@@ -0,0 +1,146 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Writing Value Change Dump files, for looking at waveforms in GTKWave or PulseView:
+//
+//     let mut vcd = VcdWriter::new(File::create("out.vcd")?, "1 ns");
+//     let clk = vcd.wire("top", "clk", 1)?;
+//     vcd.change(0, clk, 0)?;
+//     vcd.change(10, clk, 1)?;
+//     vcd.finish(20)?;
+//
+// All the wires have to be declared before the first change. Times are in units of the timescale and can't go
+// backwards.
+
+use std::io::Write;
+
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Wire(usize);
+
+struct Var {
+    scope: String,
+    name: String,
+    width: u32,
+    value: Option<u64>,
+}
+
+pub struct VcdWriter<W: Write> {
+    out: W,
+    timescale: String,
+    vars: Vec<Var>,
+    time: Option<u64>,
+}
+
+// Short identifiers made out of printable ASCII, like everything else that writes VCDs does.
+fn id(mut index: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 { return id }
+        index -= 1;
+    }
+}
+
+impl<W: Write> VcdWriter<W> {
+    // `timescale` is the VCD spelling: "1 ns", "10 us", etc.
+    pub fn new(out: W, timescale: &str) -> VcdWriter<W> {
+        VcdWriter { out, timescale: timescale.to_string(), vars: Vec::new(), time: None }
+    }
+
+    pub fn wire(&mut self, scope: &str, name: &str, width: u32) -> Result<Wire, Error> {
+        if self.time.is_some() {
+            Err(Error::ParamErr { param: "wire", should_be: "declared before the first change".to_string() })?;
+        }
+        if !(1..=64).contains(&width) {
+            Err(Error::ParamErr { param: "width", should_be: "1..=64".to_string() })?;
+        }
+        self.vars.push(Var { scope: scope.to_string(), name: name.to_string(), width, value: None });
+        Ok(Wire(self.vars.len() - 1))
+    }
+
+    fn header(&mut self) -> Result<(), Error> {
+        writeln!(self.out, "$version pio-pi5-rs $end")?;
+        writeln!(self.out, "$timescale {} $end", self.timescale)?;
+        let mut scopes: Vec<&str> = Vec::new();
+        for var in &self.vars {
+            if !scopes.contains(&var.scope.as_str()) { scopes.push(&var.scope) }
+        }
+        for scope in scopes {
+            writeln!(self.out, "$scope module {scope} $end")?;
+            for (i, var) in self.vars.iter().enumerate().filter(|(_, var)| var.scope == scope) {
+                writeln!(self.out, "$var wire {} {} {} $end", var.width, id(i), var.name)?;
+            }
+            writeln!(self.out, "$upscope $end")?;
+        }
+        writeln!(self.out, "$enddefinitions $end")?;
+        Ok(())
+    }
+
+    fn advance(&mut self, time: u64) -> Result<(), Error> {
+        match self.time {
+            None                      => self.header()?,
+            Some(now) if time < now   => Err(Error::ParamErr { param: "time", should_be: format!(">= {now}") })?,
+            Some(now) if time == now  => return Ok(()),
+            Some(_)                   => {},
+        }
+        writeln!(self.out, "#{time}")?;
+        self.time = Some(time);
+        Ok(())
+    }
+
+    // Only writes anything if the value actually changed.
+    pub fn change(&mut self, time: u64, wire: Wire, value: u64) -> Result<(), Error> {
+        let var = self.vars.get(wire.0).ok_or(Error::ParamErr { param: "wire", should_be: "from this VcdWriter".to_string() })?;
+        let value = if var.width == 64 { value } else { value & ((1 << var.width) - 1) };
+        if var.value == Some(value) {
+            return Ok(());
+        }
+        let width = var.width;
+        self.advance(time)?;
+        if width == 1 { writeln!(self.out, "{}{}", value, id(wire.0))? }
+        else          { writeln!(self.out, "b{:b} {}", value, id(wire.0))? }
+        self.vars[wire.0].value = Some(value);
+        Ok(())
+    }
+
+    // Marks the end of the dump (so the last values show up as lasting until `time`) and hands back the output.
+    pub fn finish(mut self, time: u64) -> Result<W, Error> {
+        self.advance(time)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump() {
+        let mut vcd = VcdWriter::new(Vec::new(), "1 ns");
+        let clk = vcd.wire("top", "clk", 1).unwrap();
+        let bus = vcd.wire("top.sm0", "pc", 5).unwrap();
+        vcd.change(0, clk, 0).unwrap();
+        vcd.change(0, bus, 3).unwrap();
+        vcd.change(5, clk, 1).unwrap();
+        vcd.change(5, bus, 3).unwrap();
+        assert!(vcd.wire("top", "late", 1).is_err());
+        assert!(vcd.change(4, clk, 0).is_err());
+        let out = String::from_utf8(vcd.finish(10).unwrap()).unwrap();
+        assert_eq!(out, "$version pio-pi5-rs $end\n$timescale 1 ns $end\n\
+                         $scope module top $end\n$var wire 1 ! clk $end\n$upscope $end\n\
+                         $scope module top.sm0 $end\n$var wire 5 \" pc $end\n$upscope $end\n\
+                         $enddefinitions $end\n#0\n0!\nb11 \"\n#5\n1!\n#10\n");
+    }
+
+    #[test]
+    fn ids() {
+        assert_eq!(id(0), "!");
+        assert_eq!(id(93), "~");
+        assert_eq!(id(94), "!!");
+        assert_eq!(id(95), "\"!");
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright © 2025 David Caldwell <david@porkrind.org>
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Noticing when a state machine has wedged (say, at a `wait` for an edge that got lost in a glitch) and doing
+// something about it:
+//
+//     let mut watchdog = sm.watchdog(WatchdogPolicy::restart(Duration::from_secs(2)));
+//     let stop = AtomicBool::new(false);
+//     std::thread::scope(|s| {
+//         s.spawn(|| watchdog.run(&stop));
+//         ...
+//     });
+//
+// or call poll() from a loop you already have. Each poll reads the SM's registers: it counts as stuck when it's
+// enabled, stalled on the same instruction as last time and neither of its FIFOs has moved. An SM sitting at a `pull`
+// with nothing in its TX FIFO is just idle and doesn't count (see WatchdogPolicy::idle_on_empty_tx).
+
+use std::{sync::atomic::{AtomicBool, Ordering}, time::{Duration, Instant}};
+
+use crate::{proc_pio::*, Error, StateMachine};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stall {
+    pub pc: u32,
+    pub instr: u32,
+    pub stalled_for: Duration,
+}
+
+pub type StallCallback<'a> = Box<dyn FnMut(&StateMachine, &Stall) -> Result<(), Error> + Send + 'a>;
+
+pub enum StallAction<'a> {
+    Restart,  // Restart the SM and jump back to its wrap target
+    Callback(StallCallback<'a>),
+}
+
+pub struct WatchdogPolicy<'a> {
+    pub threshold: Duration,      // How long the SM has to be stuck before `action` happens
+    pub poll_interval: Duration,  // How often run() looks
+    pub idle_on_empty_tx: bool,   // A `pull` waiting on an empty TX FIFO isn't a stall
+    pub action: StallAction<'a>,
+}
+
+impl<'a> WatchdogPolicy<'a> {
+    pub fn restart(threshold: Duration) -> WatchdogPolicy<'a> {
+        WatchdogPolicy::new(threshold, StallAction::Restart)
+    }
+
+    pub fn callback(threshold: Duration, callback: impl FnMut(&StateMachine, &Stall) -> Result<(), Error> + Send + 'a) -> WatchdogPolicy<'a> {
+        WatchdogPolicy::new(threshold, StallAction::Callback(Box::new(callback)))
+    }
+
+    fn new(threshold: Duration, action: StallAction<'a>) -> WatchdogPolicy<'a> {
+        WatchdogPolicy { threshold, poll_interval: (threshold / 4).max(Duration::from_millis(1)), idle_on_empty_tx: true, action }
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> WatchdogPolicy<'a> {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn with_idle_on_empty_tx(mut self, idle: bool) -> WatchdogPolicy<'a> {
+        self.idle_on_empty_tx = idle;
+        self
+    }
+}
+
+// What's compared from one poll to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Progress {
+    pc: u32,
+    tx_level: u32,
+    rx_level: u32,
+}
+
+pub struct Watchdog<'a> {
+    sm: StateMachine<'a>,
+    policy: WatchdogPolicy<'a>,
+    last: Option<(Progress, Instant)>,
+    stalls: u64,
+}
+
+impl<'a> StateMachine<'a> {
+    pub fn watchdog(&self, policy: WatchdogPolicy<'a>) -> Watchdog<'a> {
+        Watchdog { sm: self.pio().sm(self.index()), policy, last: None, stalls: 0 }
+    }
+}
+
+const PULL_MASK : u32 = 0xe080;
+const PULL      : u32 = 0x8080;
+
+impl Watchdog<'_> {
+    // Looks at the SM once, carrying out the policy's action if it has been stuck for too long. Returns the stall it
+    // acted on, if any.
+    pub fn poll(&mut self) -> Result<Option<Stall>, Error> {
+        let hw = self.sm.read_hw_state_machine()?;
+        let fifo = self.sm.read_hw_fifo()?;
+        let now = Instant::now();
+        let progress = Progress { pc: hw.pc, tx_level: fifo.tx.level, rx_level: fifo.rx.level };
+        let idle = self.policy.idle_on_empty_tx && fifo.tx.empty && hw.instr & PULL_MASK == PULL;
+        let stalled = hw.enabled && hw.execctrl & PROC_PIO_SM0_EXECCTRL_EXEC_STALLED_BITS != 0 && !idle;
+        let since = match self.last {
+            Some((last, since)) if stalled && last == progress => since,
+            _ => { self.last = Some((progress, now)); return Ok(None) },
+        };
+        let stalled_for = now - since;
+        if stalled_for < self.policy.threshold {
+            return Ok(None);
+        }
+        let stall = Stall { pc: hw.pc, instr: hw.instr, stalled_for };
+        match &mut self.policy.action {
+            StallAction::Restart => {
+                let wrap_target = (hw.execctrl & PROC_PIO_SM0_EXECCTRL_WRAP_BOTTOM_BITS) >> PROC_PIO_SM0_EXECCTRL_WRAP_BOTTOM_LSB;
+                self.sm.restart()?;
+                self.sm.exec(wrap_target as u16, false)?; // jmp wrap_target
+            },
+            StallAction::Callback(callback) => callback(&self.sm, &stall)?,
+        }
+        self.stalls += 1;
+        self.last = None;
+        Ok(Some(stall))
+    }
+
+    // Polls every `poll_interval` until `stop` is set or something goes wrong.
+    pub fn run(&mut self, stop: &AtomicBool) -> Result<(), Error> {
+        while !stop.load(Ordering::Relaxed) {
+            self.poll()?;
+            std::thread::sleep(self.policy.poll_interval);
+        }
+        Ok(())
+    }
+
+    // How many times the policy's action has been carried out.
+    pub fn stalls(&self) -> u64 {
+        self.stalls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{emulator::{Emulator, EmulatorBackend}, Chip, PioProgram, Rp1PIO, SmConfig};
+
+    #[test]
+    fn restarts_wedged_sm() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let program = pio.load_program(&PioProgram::new(&[
+            0xe020, //     set x, 0
+            0x2085, //     wait 1 gpio 5
+        ], None)).unwrap();
+        let sm = pio.sm_claim(0).unwrap();
+        let (wrap_target, wrap) = program.wrap();
+        sm.init(program.offset(), &SmConfig::default().set_wrap(wrap_target, wrap).unwrap()).unwrap();
+        sm.set_enabled(true).unwrap();
+        backend.emulator().run(10);
+
+        let mut watchdog = sm.watchdog(WatchdogPolicy::restart(Duration::ZERO));
+        assert_eq!(watchdog.poll().unwrap(), None); // Just looking
+        let stall = watchdog.poll().unwrap().unwrap();
+        assert_eq!(stall.pc, program.offset() as u32 + 1);
+        assert_eq!(watchdog.stalls(), 1);
+        backend.emulator().run(1);
+        assert_eq!(backend.emulator().sm(0).pc as u16, program.offset());
+
+        backend.emulator().set_input(5, true);
+        backend.emulator().run(10);
+        assert_eq!(watchdog.poll().unwrap(), None);
+        backend.emulator().run(1);
+        assert_eq!(watchdog.poll().unwrap(), None);
+    }
+
+    #[test]
+    fn idle_pull_isnt_a_stall() {
+        let backend = EmulatorBackend::new(Emulator::new(&Chip::new()));
+        let pio = Rp1PIO::with_backend(Box::new(backend.clone()), Chip::new());
+        let program = pio.load_program(&PioProgram::new(&[0x80a0], None)).unwrap(); // pull block
+        let sm = pio.sm_claim(0).unwrap();
+        sm.init(program.offset(), &SmConfig::default()).unwrap();
+        sm.set_enabled(true).unwrap();
+        backend.emulator().run(10);
+
+        let mut called = 0;
+        let mut watchdog = sm.watchdog(WatchdogPolicy::callback(Duration::ZERO, |_, _| { called += 1; Ok(()) }));
+        for _ in 0..3 {
+            assert_eq!(watchdog.poll().unwrap(), None);
+        }
+        watchdog.policy.idle_on_empty_tx = false;
+        assert!(watchdog.poll().unwrap().is_some());
+        drop(watchdog);
+        assert_eq!(called, 1);
+    }
+}